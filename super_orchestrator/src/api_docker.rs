@@ -1,17 +1,33 @@
+mod build_context;
 mod container_network;
+mod container_ops;
 mod container_runner;
+mod exec;
 mod helpers;
+mod image_history;
 mod misc_structs;
+mod network_handle;
+mod oci_layout;
+mod registry;
+mod run_once;
 mod super_build_image_options;
 mod super_docker_file;
+mod super_network;
 mod tarball;
 
 pub use container_network::*;
+pub use container_ops::*;
 pub use container_runner::*;
+pub use exec::*;
 pub use helpers::*;
+pub use image_history::*;
 pub use misc_structs::*;
+pub use network_handle::*;
+pub use registry::*;
+pub use run_once::*;
 pub use super_build_image_options::*;
 pub use super_docker_file::*;
+pub use super_network::*;
 pub use tarball::*;
 
 // we reuse this here
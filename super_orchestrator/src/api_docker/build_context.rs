@@ -0,0 +1,245 @@
+use std::{
+    io::Read,
+    path::PathBuf,
+    pin::Pin,
+    sync::{Mutex, PoisonError},
+};
+
+use bytes::Bytes;
+use futures::Stream;
+use http_body_util::StreamBody;
+use hyper::body::Frame;
+use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use stacked_errors::{Result, StackableErr};
+
+use super::{tarball::FinishedTarball, BollardError};
+
+/// The size of the chunks that build contexts are streamed in
+const CHUNK_LEN: usize = 64 << 10;
+/// The number of chunks that can be read ahead of the request
+const CHUNK_CAPACITY: usize = 4;
+
+/// The body that streamed build contexts are sent with
+type ContextBody = StreamBody<Pin<Box<dyn Stream<Item = std::io::Result<Frame<Bytes>>> + Send>>>;
+
+/// Where a build context can be streamed to, only endpoints that are reachable
+/// without TLS are supported
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ContextEndpoint {
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// The `host:port` of an unencrypted http endpoint
+    Http(String),
+}
+
+impl ContextEndpoint {
+    /// Parses a docker host like "unix:///var/run/docker.sock" or
+    /// "tcp://localhost:2375"
+    pub(crate) fn from_host(host: &str) -> Option<Self> {
+        if let Some(path) = host.strip_prefix("unix://") {
+            #[cfg(unix)]
+            return Some(Self::Unix(PathBuf::from(path)));
+            #[cfg(not(unix))]
+            return {
+                let _ = path;
+                None
+            };
+        }
+        let authority = host
+            .strip_prefix("tcp://")
+            .or_else(|| host.strip_prefix("http://"))?;
+        Some(Self::Http(authority.trim_end_matches('/').to_owned()))
+    }
+}
+
+/// The build context of a [SuperDockerfile](super::SuperDockerfile) build
+pub(crate) enum BuildContext {
+    /// There is no context, because the daemon fetches a remote context itself
+    Empty,
+    /// A context that was given as bytes, which may already be compressed
+    Bytes(Bytes),
+    /// A finished tarball, which is gzip compressed while it is read if `gzip`
+    /// is set
+    Tarball {
+        tarball: FinishedTarball,
+        gzip: bool,
+    },
+}
+
+impl BuildContext {
+    /// Returns a reader over the (compressed) tarball
+    fn tarball_reader(tarball: &FinishedTarball, gzip: bool) -> Result<Box<dyn Read + Send>> {
+        let reader = tarball
+            .reader()
+            .stack_err("when opening the build context")?;
+        Ok(if gzip {
+            Box::new(flate2::read::GzEncoder::new(
+                reader,
+                flate2::Compression::default(),
+            ))
+        } else {
+            reader
+        })
+    }
+
+    /// Reads the whole (compressed) context into memory
+    pub(crate) async fn into_vec(self) -> Result<Vec<u8>> {
+        match self {
+            BuildContext::Empty => Ok(Vec::new()),
+            BuildContext::Bytes(bytes) => Ok(Vec::from(bytes)),
+            BuildContext::Tarball {
+                tarball,
+                gzip: false,
+            } => tokio::task::spawn_blocking(|| tarball.into_bytes())
+                .await
+                .stack()?
+                .stack_err("when reading the build context"),
+            BuildContext::Tarball {
+                tarball,
+                gzip: true,
+            } => tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+                let mut bytes = vec![];
+                BuildContext::tarball_reader(&tarball, true)?
+                    .read_to_end(&mut bytes)
+                    .stack()?;
+                Ok(bytes)
+            })
+            .await
+            .stack()?
+            .stack_err("when compressing the build context"),
+        }
+    }
+
+    /// Returns the `bollard::Docker` and the tarball argument that a build
+    /// with `docker_instance` is started with. If `endpoint` is known, the
+    /// tarball is streamed from the start to `endpoint` without reading it
+    /// into memory, otherwise it is passed to `bollard` as bytes. This can be
+    /// called again for each attempt of a build.
+    pub(crate) async fn request_args(
+        &self,
+        docker_instance: &bollard::Docker,
+        endpoint: Option<&ContextEndpoint>,
+    ) -> Result<(bollard::Docker, Option<Bytes>)> {
+        match (self, endpoint) {
+            (BuildContext::Empty, _) => Ok((docker_instance.clone(), None)),
+            (BuildContext::Bytes(bytes), _) => Ok((docker_instance.clone(), Some(bytes.clone()))),
+            (BuildContext::Tarball { tarball, gzip }, Some(endpoint)) => {
+                let reader = Self::tarball_reader(tarball, *gzip).stack()?;
+                let docker_instance =
+                    docker_with_body(endpoint.clone(), docker_instance, reader_body(reader))
+                        .stack()?;
+                Ok((docker_instance, None))
+            }
+            (BuildContext::Tarball { tarball, gzip }, None) => {
+                match tarball.memory() {
+                    Some(bytes) if !gzip => {
+                        return Ok((docker_instance.clone(), Some(bytes.clone())))
+                    }
+                    Some(_) => (),
+                    None => tracing::debug!(
+                        "the build context is read into memory, because it can only be streamed \
+                         to the default docker instance with a unix socket or http connection"
+                    ),
+                }
+                let mut reader = Self::tarball_reader(tarball, *gzip).stack()?;
+                let bytes = tokio::task::spawn_blocking(move || {
+                    let mut bytes = vec![];
+                    reader.read_to_end(&mut bytes).map(|_| bytes)
+                })
+                .await
+                .stack()?
+                .stack_err("when reading the build context")?;
+                Ok((docker_instance.clone(), Some(Bytes::from(bytes))))
+            }
+        }
+    }
+}
+
+/// Reads `reader` on a blocking thread and returns its chunks as a request
+/// body. At most [CHUNK_CAPACITY] chunks are read ahead, and a read error ends
+/// the body with the error.
+fn reader_body(mut reader: Box<dyn Read + Send>) -> ContextBody {
+    let (send, recv) = tokio::sync::mpsc::channel(CHUNK_CAPACITY);
+    tokio::task::spawn_blocking(move || loop {
+        let mut chunk = Vec::with_capacity(CHUNK_LEN);
+        match (&mut reader).take(CHUNK_LEN as u64).read_to_end(&mut chunk) {
+            Ok(0) => break,
+            Ok(_) => {
+                // the request was dropped
+                if send
+                    .blocking_send(Ok(Frame::data(Bytes::from(chunk))))
+                    .is_err()
+                {
+                    break
+                }
+            }
+            Err(e) => {
+                let _ = send.blocking_send(Err(e));
+                break
+            }
+        }
+    });
+    StreamBody::new(Box::pin(futures::stream::unfold(
+        recv,
+        |mut recv| async move { recv.recv().await.map(|chunk| (chunk, recv)) },
+    )))
+}
+
+/// Returns a `bollard::Docker` for a single request, which is sent to
+/// `endpoint` with `body` instead of the body that `bollard` creates. This is
+/// needed because `bollard::Docker::build_image` only takes the context as
+/// bytes. The API version and timeout are the same as for `docker_instance`.
+fn docker_with_body(
+    endpoint: ContextEndpoint,
+    docker_instance: &bollard::Docker,
+    body: ContextBody,
+) -> Result<bollard::Docker> {
+    let body = Mutex::new(Some(body));
+    bollard::Docker::connect_with_custom_transport(
+        move |request: bollard::BollardRequest| {
+            let body = body.lock().unwrap_or_else(PoisonError::into_inner).take();
+            send_with_body(endpoint.clone(), request, body)
+        },
+        // only the path and query of the requests are used
+        Some("http://localhost"),
+        docker_instance.timeout().as_secs(),
+        &docker_instance.client_version(),
+    )
+    .map_err(|e| format!("{e:?}"))
+    .stack()
+}
+
+async fn send_with_body(
+    endpoint: ContextEndpoint,
+    request: bollard::BollardRequest,
+    body: Option<ContextBody>,
+) -> std::result::Result<hyper::Response<hyper::body::Incoming>, BollardError> {
+    let body = body.ok_or_else(|| {
+        BollardError::from(std::io::Error::other(
+            "the build context was already sent with an earlier request",
+        ))
+    })?;
+    let (mut parts, _) = request.into_parts();
+    let path_and_query = parts
+        .uri
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str())
+        .to_owned();
+    match endpoint {
+        #[cfg(unix)]
+        ContextEndpoint::Unix(path) => {
+            parts.uri = hyperlocal::Uri::new(path, &path_and_query).into();
+            let client = Client::builder(TokioExecutor::new()).build(hyperlocal::UnixConnector);
+            Ok(client
+                .request(hyper::Request::from_parts(parts, body))
+                .await?)
+        }
+        ContextEndpoint::Http(authority) => {
+            parts.uri = format!("http://{authority}{path_and_query}").parse()?;
+            let client = Client::builder(TokioExecutor::new()).build_http();
+            Ok(client
+                .request(hyper::Request::from_parts(parts, body))
+                .await?)
+        }
+    }
+}
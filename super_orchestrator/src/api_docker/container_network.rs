@@ -35,6 +35,7 @@ pub struct ContainerNetwork {
 
 /// Options for adding a container
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum AddContainerOptions {
     /// Use an already specified image to create the container
     Container(SuperImage),
@@ -236,13 +237,10 @@ impl ContainerNetwork {
 
         let image = match add_opts {
             AddContainerOptions::Container(image) => image,
-            AddContainerOptions::DockerFile(docker_file) => {
-                docker_file
-                    .build_image()
-                    .await
-                    .stack_err("ContainerNetwork::add_container")?
-                    .0
-            }
+            AddContainerOptions::DockerFile(docker_file) => docker_file
+                .build_image()
+                .await
+                .stack_err("ContainerNetwork::add_container")?,
             AddContainerOptions::BollardArgs {
                 image_options,
                 tarball,
@@ -0,0 +1,668 @@
+use std::{
+    future::{ready, Future},
+    io::Read,
+    path::{Component, Path},
+    time::{Duration, Instant, SystemTime},
+};
+
+use bollard::container::{DownloadFromContainerOptions, MemoryStatsStats, Stats, StatsOptions};
+use futures::{Stream, StreamExt};
+use stacked_errors::{bail_locationless, Error, Result, StackableErr};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    select,
+    sync::oneshot,
+    task::JoinHandle,
+};
+
+use crate::{
+    api_docker::{
+        docker_socket::get_or_init_default_docker_instance, BollardError, LogOutput,
+        PortBindProtocol, SuperImage,
+    },
+    close_file, random_name, FileOptions,
+};
+
+/// How a container exited, returned by [wait_container]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContainerExit {
+    pub code: i64,
+    /// If the container was killed because it ran out of memory. This is
+    /// always false if the container was removed before it could be inspected
+    /// (e.g. if it was created with auto removal).
+    pub oom_killed: bool,
+    /// The error message the daemon gave for the exit, if any
+    pub error_msg: Option<String>,
+}
+
+impl ContainerExit {
+    /// Returns if the container exited with a zero exit code
+    pub fn successful(&self) -> bool {
+        self.code == 0
+    }
+}
+
+/// Waits for the container with `container_id` (which can also be its name)
+/// to exit, the API counterpart of
+/// [CommandRunner::wait_with_timeout](crate::CommandRunner::wait_with_timeout).
+/// Returns immediately if the container has already exited.
+///
+/// A nonzero exit code is not an error. If `timeout` is exceeded, an error for
+/// which `is_timeout()` is true is returned and the container is left
+/// running.
+#[tracing::instrument(skip_all, fields(
+    container.id = %container_id,
+))]
+pub async fn wait_container(container_id: &str, timeout: Duration) -> Result<ContainerExit> {
+    let docker = get_or_init_default_docker_instance().await.stack()?;
+    wait_container_using(&docker, container_id, timeout).await
+}
+
+/// [wait_container] with a specific docker handle
+pub(crate) async fn wait_container_using(
+    docker: &bollard::Docker,
+    container_id: &str,
+    timeout: Duration,
+) -> Result<ContainerExit> {
+    let mut wait = std::pin::pin!(docker.wait_container::<String>(container_id, None));
+    let wait_res = match tokio::time::timeout(timeout, wait.next()).await {
+        Ok(wait_res) => wait_res,
+        Err(_) => {
+            return Err(Error::timeout().add_err_locationless(format!(
+                "wait_container timeout after {timeout:?} waiting for container {container_id} to \
+                 exit"
+            )))
+        }
+    };
+    let mut exit = match wait_res {
+        Some(Ok(response)) => ContainerExit {
+            code: response.status_code,
+            error_msg: response
+                .error
+                .and_then(|error| error.message)
+                .filter(|error| !error.is_empty()),
+            ..Default::default()
+        },
+        // bollard returns nonzero exit codes as errors
+        Some(Err(BollardError::DockerContainerWaitError { error, code })) => ContainerExit {
+            code,
+            error_msg: (!error.is_empty()).then_some(error),
+            ..Default::default()
+        },
+        Some(Err(e)) => {
+            return Err(e).stack_err_with_locationless(|| {
+                format!("wait_container when waiting for container {container_id}")
+            })
+        }
+        None => bail_locationless!(
+            "wait_container wait stream for container {container_id} ended without a result"
+        ),
+    };
+
+    // the OOM flag is only available by inspection
+    if let Ok(inspect) = docker.inspect_container(container_id, None).await {
+        if let Some(state) = inspect.state {
+            exit.oom_killed = state.oom_killed.unwrap_or(false);
+            if exit.error_msg.is_none() {
+                exit.error_msg = state.error.filter(|error| !error.is_empty());
+            }
+        }
+    }
+
+    Ok(exit)
+}
+
+/// Formats the results of the latest health checks for errors
+fn format_health_log(log: &[bollard::secret::HealthcheckResult]) -> String {
+    log.iter()
+        .map(|res| {
+            format!(
+                "exit code {}: {}",
+                res.exit_code
+                    .map_or_else(|| "unknown".to_owned(), |code| code.to_string()),
+                res.output.as_deref().unwrap_or_default().trim()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Waits for the healthcheck of the container with `container_id` (which can
+/// also be its name) to report that it is healthy, polling every `poll`. See
+/// [SuperDockerfile::with_healthcheck](crate::api_docker::SuperDockerfile::with_healthcheck)
+/// for adding a healthcheck to an image.
+///
+/// Returns an error with the health check log as soon as the container
+/// becomes unhealthy or exits, and an error for which `is_timeout()` is true
+/// if `timeout` is exceeded. Unlike
+/// [ContainerNetwork::wait_healthy](crate::api_docker::ContainerNetwork::wait_healthy),
+/// a container without a healthcheck is an error.
+#[tracing::instrument(skip_all, fields(
+    container.id = %container_id,
+))]
+pub async fn wait_healthy_api(container_id: &str, timeout: Duration, poll: Duration) -> Result<()> {
+    use bollard::secret::HealthStatusEnum;
+
+    let docker = get_or_init_default_docker_instance().await.stack()?;
+    let start = Instant::now();
+    loop {
+        let state = docker
+            .inspect_container(container_id, None)
+            .await
+            .stack_err_with_locationless(|| {
+                format!("wait_healthy_api when inspecting container {container_id}")
+            })?
+            .state
+            .unwrap_or_default();
+        let health = state.health.unwrap_or_default();
+        let log = health.log.as_deref().unwrap_or_default();
+        match health.status {
+            Some(HealthStatusEnum::HEALTHY) => return Ok(()),
+            Some(HealthStatusEnum::UNHEALTHY) => bail_locationless!(
+                "wait_healthy_api -> container {container_id} is unhealthy, the latest health \
+                 checks were:\n{}",
+                format_health_log(log)
+            ),
+            Some(HealthStatusEnum::STARTING) => (),
+            None | Some(HealthStatusEnum::NONE) | Some(HealthStatusEnum::EMPTY) => {
+                bail_locationless!(
+                    "wait_healthy_api -> container {container_id} has no healthcheck defined"
+                )
+            }
+        }
+        if state.running == Some(false) {
+            bail_locationless!(
+                "wait_healthy_api -> container {container_id} is not running before becoming \
+                 healthy, the latest health checks were:\n{}",
+                format_health_log(log)
+            )
+        }
+        if start.elapsed() >= timeout {
+            return Err(Error::timeout().add_err_locationless(format!(
+                "wait_healthy_api timeout after {timeout:?} waiting for container {container_id} \
+                 to be healthy, the latest health checks were:\n{}",
+                format_health_log(log)
+            )))
+        }
+        tokio::time::sleep(poll).await;
+    }
+}
+
+/// Writes the logs of the container to `stdout` and `stderr` until the log
+/// stream ends or `stop` completes
+async fn write_logs(
+    container_id: &str,
+    follow: bool,
+    stdout: &mut (impl AsyncWrite + Unpin),
+    stderr: &mut (impl AsyncWrite + Unpin),
+    stop: impl Future<Output = ()>,
+) -> Result<()> {
+    let docker = get_or_init_default_docker_instance().await.stack()?;
+    let mut logs = std::pin::pin!(docker.logs(
+        container_id,
+        Some(bollard::container::LogsOptions {
+            follow,
+            stdout: true,
+            stderr: true,
+            tail: "all".to_string(),
+            ..Default::default()
+        })
+    ));
+    let mut stop = std::pin::pin!(stop);
+    loop {
+        let output = select! {
+            output = logs.next() => output,
+            _ = &mut stop => break,
+        };
+        let Some(output) = output else { break };
+        match output.stack()? {
+            LogOutput::StdErr { message } => stderr.write_all(&message).await.stack()?,
+            LogOutput::StdOut { message } | LogOutput::Console { message } => {
+                stdout.write_all(&message).await.stack()?
+            }
+            LogOutput::StdIn { message: _ } => (),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the stdout and stderr that the container with `container_id` has
+/// output so far. If the container was created with a TTY, all output is in
+/// stdout.
+#[tracing::instrument(skip_all, fields(
+    container.id = %container_id,
+))]
+pub async fn container_logs_to_vec(container_id: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    write_logs(
+        container_id,
+        false,
+        &mut stdout,
+        &mut stderr,
+        std::future::pending(),
+    )
+    .await
+    .stack_err_with_locationless(|| {
+        format!("container_logs_to_vec when getting the logs of container {container_id}")
+    })?;
+    Ok((stdout, stderr))
+}
+
+/// Returned by [container_logs_to_file]
+#[derive(Debug)]
+pub struct ContainerLogsHandle {
+    stop: Option<oneshot::Sender<()>>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl ContainerLogsHandle {
+    /// Returns if all logs have been written and the files are closed
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Waits for all logs to be written. If following, this is when the
+    /// container exits.
+    pub async fn wait(self) -> Result<()> {
+        let Self { stop, task } = self;
+        let res = task.await.stack().and_then(|res| res);
+        drop(stop);
+        res.stack_err_locationless("ContainerLogsHandle::wait")
+    }
+
+    /// Stops following the logs and closes the files after the logs so far
+    /// have been written. Dropping the handle also stops it, but without
+    /// waiting for the files to be closed.
+    pub async fn stop(mut self) -> Result<()> {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        (&mut self.task)
+            .await
+            .stack()
+            .and_then(|res| res)
+            .stack_err_locationless("ContainerLogsHandle::stop")
+    }
+}
+
+/// Writes the stdout and stderr of the container with `container_id` to the
+/// files, the API counterpart of the log files of the CLI
+/// [ContainerNetwork](crate::cli_docker::ContainerNetwork). If the container
+/// was created with a TTY, all output goes to `stdout`.
+///
+/// If `follow`, the logs keep being written until the container exits or
+/// [ContainerLogsHandle::stop] is called, otherwise only the logs so far are
+/// written. The files are acquired before this returns.
+#[tracing::instrument(skip_all, fields(
+    container.id = %container_id,
+))]
+pub async fn container_logs_to_file(
+    container_id: &str,
+    stdout: FileOptions,
+    stderr: FileOptions,
+    follow: bool,
+) -> Result<ContainerLogsHandle> {
+    let mut stdout_file = stdout
+        .acquire_file()
+        .await
+        .stack_err_locationless("container_logs_to_file when acquiring the stdout file")?;
+    let mut stderr_file = stderr
+        .acquire_file()
+        .await
+        .stack_err_locationless("container_logs_to_file when acquiring the stderr file")?;
+    let (stop, stop_rx) = oneshot::channel();
+    let container_id = container_id.to_owned();
+    let task = tokio::task::spawn(async move {
+        let res = write_logs(
+            &container_id,
+            follow,
+            &mut stdout_file,
+            &mut stderr_file,
+            async {
+                // a dropped sender also stops
+                let _ = stop_rx.await;
+            },
+        )
+        .await
+        .stack_err_with_locationless(|| {
+            format!("container_logs_to_file when writing the logs of container {container_id}")
+        });
+        let closed = close_file(stdout_file)
+            .await
+            .and(close_file(stderr_file).await);
+        res.and(closed.stack())
+    });
+    Ok(ContainerLogsHandle {
+        stop: Some(stop),
+        task,
+    })
+}
+
+/// A resource usage sample of a container, returned by [container_stats]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatsSample {
+    /// The CPU usage since the last sample in percent of one core, the same as
+    /// what `docker stats` shows. This is not normalized by the number of
+    /// CPUs, a container using two full cores has 200.0.
+    pub cpu_percent: f64,
+    /// The memory usage excluding the inactive file cache, the same as what
+    /// `docker stats` shows
+    pub mem_bytes: u64,
+    pub mem_limit: u64,
+    /// Summed over all networks
+    pub rx_bytes: u64,
+    /// Summed over all networks
+    pub tx_bytes: u64,
+    /// When the sample was received
+    pub ts: SystemTime,
+}
+
+impl StatsSample {
+    fn from_stats(stats: &Stats, ts: SystemTime) -> Self {
+        let cpu_delta = stats
+            .cpu_stats
+            .cpu_usage
+            .total_usage
+            .saturating_sub(stats.precpu_stats.cpu_usage.total_usage);
+        let system_delta = stats
+            .cpu_stats
+            .system_cpu_usage
+            .unwrap_or(0)
+            .saturating_sub(stats.precpu_stats.system_cpu_usage.unwrap_or(0));
+        let online_cpus = stats.cpu_stats.online_cpus.unwrap_or_else(|| {
+            stats
+                .cpu_stats
+                .cpu_usage
+                .percpu_usage
+                .as_ref()
+                .map_or(1, |percpu| percpu.len() as u64)
+        });
+        let cpu_percent = if system_delta == 0 {
+            0.0
+        } else {
+            (cpu_delta as f64 / system_delta as f64) * (online_cpus as f64) * 100.0
+        };
+
+        let inactive_file = match stats.memory_stats.stats {
+            Some(MemoryStatsStats::V1(v1)) => v1.total_inactive_file,
+            Some(MemoryStatsStats::V2(v2)) => v2.inactive_file,
+            None => 0,
+        };
+        let mem_bytes = stats
+            .memory_stats
+            .usage
+            .unwrap_or(0)
+            .saturating_sub(inactive_file);
+
+        let (rx_bytes, tx_bytes) = stats
+            .networks
+            .iter()
+            .flat_map(|networks| networks.values())
+            .chain(stats.network.iter())
+            .fold((0, 0), |(rx, tx), network| {
+                (rx + network.rx_bytes, tx + network.tx_bytes)
+            });
+
+        Self {
+            cpu_percent,
+            mem_bytes,
+            mem_limit: stats.memory_stats.limit.unwrap_or(0),
+            rx_bytes,
+            tx_bytes,
+            ts,
+        }
+    }
+}
+
+/// Streams resource usage samples of the running container with
+/// `container_id`. The daemon produces samples about once per second, this
+/// yields at most one sample per `interval`.
+///
+/// The stream ends when the container stops.
+#[tracing::instrument(skip_all, fields(
+    container.id = %container_id,
+))]
+pub async fn container_stats(
+    container_id: &str,
+    interval: Duration,
+) -> Result<impl Stream<Item = Result<StatsSample>> + Send + 'static> {
+    let docker = get_or_init_default_docker_instance().await.stack()?;
+    let container_id = container_id.to_owned();
+    let mut last: Option<Instant> = None;
+    Ok(docker
+        .stats(
+            &container_id,
+            Some(StatsOptions {
+                stream: true,
+                one_shot: false,
+            }),
+        )
+        // a stopped container may get an empty sample before the stream ends
+        .take_while(|res| {
+            ready(
+                !matches!(res, Ok(stats) if stats.pids_stats.current.is_none()
+                && stats.memory_stats.usage.is_none()),
+            )
+        })
+        .filter_map(move |res| {
+            let now = Instant::now();
+            let res = match res {
+                Ok(stats) => {
+                    if last.is_some_and(|last| now.saturating_duration_since(last) < interval) {
+                        return ready(None);
+                    }
+                    last = Some(now);
+                    Ok(StatsSample::from_stats(&stats, SystemTime::now()))
+                }
+                Err(e) => Err(e).stack_err_with_locationless(|| {
+                    format!("container_stats when getting the stats of container {container_id}")
+                }),
+            };
+            ready(Some(res))
+        }))
+}
+
+/// Gets the tarball that the daemon returns for `container_path`
+async fn download_tarball(container_id: &str, container_path: &str) -> Result<Vec<u8>> {
+    let docker = get_or_init_default_docker_instance().await.stack()?;
+    let mut stream = std::pin::pin!(docker.download_from_container(
+        container_id,
+        Some(DownloadFromContainerOptions {
+            path: container_path
+        })
+    ));
+    let mut tarball = vec![];
+    while let Some(bytes) = stream.next().await {
+        tarball.extend_from_slice(&bytes.stack()?);
+    }
+    Ok(tarball)
+}
+
+/// The API equivalent of `docker cp {container_id}:{container_path}
+/// {host_dest}`, except that `host_dest` is always a directory that the file
+/// or directory at `container_path` is placed into under its own name.
+/// `host_dest` is created if it does not exist.
+///
+/// Returns an error without writing further if an entry would be placed
+/// outside of `host_dest`.
+#[tracing::instrument(skip_all, fields(
+    container.id = %container_id,
+    container.path = %container_path,
+))]
+pub async fn download_from_container(
+    container_id: &str,
+    container_path: &str,
+    host_dest: impl AsRef<Path>,
+) -> Result<()> {
+    let host_dest = host_dest.as_ref();
+    let tarball = download_tarball(container_id, container_path)
+        .await
+        .stack_err_with_locationless(|| {
+            format!(
+                "download_from_container when downloading {container_path} from container \
+                 {container_id}"
+            )
+        })?;
+    tokio::fs::create_dir_all(host_dest)
+        .await
+        .stack_err_with_locationless(|| {
+            format!("download_from_container when creating {host_dest:?}")
+        })?;
+
+    let mut archive = tar::Archive::new(std::io::Cursor::new(tarball));
+    archive.set_preserve_permissions(true);
+    for entry in archive.entries().stack()? {
+        let mut entry = entry.stack()?;
+        let path = entry.path().stack()?.into_owned();
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            bail_locationless!(
+                "download_from_container -> entry {path:?} would escape {host_dest:?}"
+            )
+        }
+        // this also refuses to write through symlinks that point outside
+        if !entry.unpack_in(host_dest).stack_err_with_locationless(|| {
+            format!("download_from_container when unpacking {path:?} into {host_dest:?}")
+        })? {
+            bail_locationless!(
+                "download_from_container -> entry {path:?} would escape {host_dest:?}"
+            )
+        }
+    }
+    Ok(())
+}
+
+/// Returns the contents of the single regular file at `container_path`, this
+/// keeps the whole file in memory and is meant for small files
+#[tracing::instrument(skip_all, fields(
+    container.id = %container_id,
+    container.path = %container_path,
+))]
+pub async fn download_to_bytes(container_id: &str, container_path: &str) -> Result<Vec<u8>> {
+    let tarball = download_tarball(container_id, container_path)
+        .await
+        .stack_err_with_locationless(|| {
+            format!(
+                "download_to_bytes when downloading {container_path} from container {container_id}"
+            )
+        })?;
+    let mut archive = tar::Archive::new(std::io::Cursor::new(tarball));
+    let mut entries = archive.entries().stack()?;
+    let mut entry = entries
+        .next()
+        .stack_err_with_locationless(|| {
+            format!("download_to_bytes -> {container_path} resulted in an empty tarball")
+        })?
+        .stack()?;
+    if !entry.header().entry_type().is_file() {
+        bail_locationless!(
+            "download_to_bytes -> {container_path} is not a regular file, it is a {:?}",
+            entry.header().entry_type()
+        )
+    }
+    let mut content = vec![];
+    entry.read_to_end(&mut content).stack()?;
+    Ok(content)
+}
+
+/// Options for [commit_container]
+#[derive(Debug, Clone, Default)]
+pub struct CommitOptions {
+    /// The repository name of the image, a random one is used if not set
+    pub repo: Option<String>,
+    /// Uses "latest" if not set
+    pub tag: Option<String>,
+    /// If the container should be paused while committing
+    pub pause: bool,
+    /// Dockerfile instructions to apply to the committed image, e.g.
+    /// "ENV VAR=value" or "ENTRYPOINT [\"/app\"]". Docker only supports CMD,
+    /// ENTRYPOINT, ENV, EXPOSE, LABEL, ONBUILD, USER, VOLUME, and WORKDIR.
+    pub changes: Vec<String>,
+}
+
+/// The API equivalent of `docker commit`, creates an image from the current
+/// state of the container with `container_id`. The returned image can be
+/// extended further with [SuperImage::to_docker_file].
+#[tracing::instrument(skip_all, fields(
+    container.id = %container_id,
+))]
+pub async fn commit_container(container_id: &str, opts: CommitOptions) -> Result<SuperImage> {
+    let docker = get_or_init_default_docker_instance().await.stack()?;
+    let repo = opts
+        .repo
+        .unwrap_or_else(|| random_name("super_orchestrator_commit"));
+    let tag = opts.tag.unwrap_or_else(|| "latest".to_owned());
+    let commit = docker
+        .commit_container(
+            bollard::image::CommitContainerOptions {
+                container: container_id.to_owned(),
+                repo: repo.clone(),
+                tag: tag.clone(),
+                pause: opts.pause,
+                changes: (!opts.changes.is_empty()).then(|| opts.changes.join("\n")),
+                ..Default::default()
+            },
+            bollard::container::Config::<String>::default(),
+        )
+        .await
+        .stack_err_with_locationless(|| {
+            format!("commit_container when committing container {container_id}")
+        })?;
+    let image_id = match commit.id {
+        Some(id) => id,
+        // bollard does not always manage to deserialize the ID, the reference is
+        // inspected instead
+        None => docker
+            .inspect_image(&format!("{repo}:{tag}"))
+            .await
+            .stack_err_with_locationless(|| {
+                format!("commit_container when inspecting the committed image {repo}:{tag}")
+            })?
+            .id
+            .stack_err_with_locationless(|| {
+                format!("commit_container -> the committed image {repo}:{tag} has no ID")
+            })?,
+    };
+    Ok(SuperImage::new(image_id).with_reference(format!("{repo}:{tag}")))
+}
+
+/// Returns the host port that `container_port` of the running container with
+/// `container_id` is published on, this is meant for getting the port that
+/// docker chose for a
+/// [PortBind::ephemeral](crate::api_docker::PortBind::ephemeral)
+#[tracing::instrument(skip_all, fields(
+    container.id = %container_id,
+))]
+pub async fn resolve_host_port(
+    container_id: &str,
+    container_port: u16,
+    protocol: PortBindProtocol,
+) -> Result<u16> {
+    let docker = get_or_init_default_docker_instance().await.stack()?;
+    let key = format!("{container_port}/{protocol}");
+    let inspect = docker
+        .inspect_container(container_id, None)
+        .await
+        .stack_err_with_locationless(|| {
+            format!("resolve_host_port when inspecting container {container_id}")
+        })?;
+    let host_port = inspect
+        .network_settings
+        .and_then(|network_settings| network_settings.ports)
+        .and_then(|mut ports| ports.remove(&key).flatten())
+        .into_iter()
+        .flatten()
+        .find_map(|binding| binding.host_port.filter(|host_port| !host_port.is_empty()))
+        .stack_err_with_locationless(|| {
+            format!(
+                "resolve_host_port -> {key} of container {container_id} is not published, is the \
+                 container running?"
+            )
+        })?;
+    host_port.parse().stack_err_with_locationless(|| {
+        format!("resolve_host_port -> could not parse host port {host_port}")
+    })
+}
@@ -13,10 +13,11 @@ use stacked_errors::{Result, StackableErr};
 use tokio::{io::AsyncWriteExt, sync::Mutex};
 
 use crate::{
+    ansi_colors,
     api_docker::{
-        docker_socket::get_or_init_default_docker_instance, port_bindings_to_bollard_args,
-        ContainerNetwork, DockerStdin, ExtraAddContainerOptions, PortBind, SuperImage,
-        WaitContainer,
+        docker_socket::{engine_info, get_or_init_default_docker_instance},
+        port_bindings_to_bollard_args, ContainerNetwork, DockerStdin, ExtraAddContainerOptions,
+        PortBind, RetryPolicy, SuperImage, WaitContainer,
     },
     next_terminal_color,
 };
@@ -41,6 +42,9 @@ pub struct ContainerCreateOptions {
     /// When enabled, the stdout and stderr of the container is logged to
     /// stderr.
     pub log_outs: Option<bool>,
+    /// How creating and starting the container is retried on transient
+    /// connection errors
+    pub retry_policy: RetryPolicy,
 }
 
 /// A struct for the metadata regarding a running container
@@ -102,9 +106,10 @@ impl ContainerRunner {
         self.should_be_started = true;
 
         let docker = get_or_init_default_docker_instance().await.stack()?;
+        let is_podman = engine_info().await.stack()?.is_podman();
 
         let (exposed_ports, port_bindings) =
-            port_bindings_to_bollard_args(&self.container_opts.port_bindings);
+            port_bindings_to_bollard_args(&self.container_opts.port_bindings).stack()?;
 
         // [docker reference](https://docs.docker.com/reference/api/engine/version/
         // v1.48/#tag/Container/operation/ContainerCreate)
@@ -124,65 +129,83 @@ impl ContainerRunner {
         ))
         .unzip();
 
+        // podman only accepts the MAC address on the network endpoint
+        let (mac_address, endpoint_mac_address) = if is_podman {
+            if self.network_opts.mac_address.is_some() {
+                tracing::warn!(
+                    "podman detected, setting the MAC address on the network endpoint instead of \
+                     the container"
+                );
+            }
+            (None, self.network_opts.mac_address.clone())
+        } else {
+            (self.network_opts.mac_address.clone(), None)
+        };
+
         if self.debug {
             tracing::debug!("Creating container");
         }
 
-        docker
-            .create_container(
-                Some(bollard::container::CreateContainerOptions {
-                    name: self.container_opts.name.clone(),
-                    ..Default::default()
-                }),
-                bollard::container::Config {
-                    hostname: self.network_opts.hostname.clone(),
-                    user: self.container_opts.user.clone(),
-                    exposed_ports,
-                    cmd: Some(self.container_opts.cmd.clone()),
-                    image: Some(self.image.get_image_id().to_string()),
-                    volumes,
-                    env: Some(self.container_opts.env_vars.clone()),
-                    mac_address: self.network_opts.mac_address.clone(),
-                    host_config: Some(bollard::secret::HostConfig {
-                        cap_add: Some(self.container_opts.cap_adds.clone()),
-                        sysctls: Some(self.container_opts.sysctls.clone()),
-                        port_bindings,
-                        binds: volume_binds,
-                        privileged: Some(self.container_opts.priviledged),
-                        devices: Some(self.container_opts.devices.clone()),
-                        // don't flood user's containers
-                        auto_remove: Some(true),
+        let create_opts = bollard::container::CreateContainerOptions {
+            name: self.container_opts.name.clone(),
+            ..Default::default()
+        };
+        let config = bollard::container::Config {
+            hostname: self.network_opts.hostname.clone(),
+            user: self.container_opts.user.clone(),
+            exposed_ports,
+            cmd: Some(self.container_opts.cmd.clone()),
+            image: Some(self.image.get_image_id().to_string()),
+            volumes,
+            env: Some(self.container_opts.env_vars.clone()),
+            mac_address,
+            host_config: Some(bollard::secret::HostConfig {
+                cap_add: Some(self.container_opts.cap_adds.clone()),
+                sysctls: Some(self.container_opts.sysctls.clone()),
+                port_bindings,
+                binds: volume_binds,
+                privileged: Some(self.container_opts.priviledged),
+                devices: Some(self.container_opts.devices.clone()),
+                // don't flood user's containers
+                auto_remove: Some(true),
+                ..Default::default()
+            }),
+            // allows testing features
+            attach_stdin: Some(true),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            tty: Some(true),
+            open_stdin: Some(true),
+            // setup network
+            networking_config: Some(bollard::container::NetworkingConfig {
+                endpoints_config: [(network_name, EndpointSettings {
+                    ipam_config: Some(EndpointIpamConfig {
+                        ipv4_address: self
+                            .network_opts
+                            .ipv4_addr
+                            .as_ref()
+                            .map(ToString::to_string),
+                        ipv6_address: self
+                            .network_opts
+                            .ipv6_addr
+                            .as_ref()
+                            .map(ToString::to_string),
                         ..Default::default()
                     }),
-                    // allows testing features
-                    attach_stdin: Some(true),
-                    attach_stdout: Some(true),
-                    attach_stderr: Some(true),
-                    tty: Some(true),
-                    open_stdin: Some(true),
-                    // setup network
-                    networking_config: Some(bollard::container::NetworkingConfig {
-                        endpoints_config: [(network_name, EndpointSettings {
-                            ipam_config: Some(EndpointIpamConfig {
-                                ipv4_address: self
-                                    .network_opts
-                                    .ipv4_addr
-                                    .as_ref()
-                                    .map(ToString::to_string),
-                                ipv6_address: self
-                                    .network_opts
-                                    .ipv6_addr
-                                    .as_ref()
-                                    .map(ToString::to_string),
-                                ..Default::default()
-                            }),
-                            ..Default::default()
-                        })]
-                        .into_iter()
-                        .collect(),
-                    }),
+                    mac_address: endpoint_mac_address,
                     ..Default::default()
-                },
+                })]
+                .into_iter()
+                .collect(),
+            }),
+            ..Default::default()
+        };
+
+        let retry_policy = self.container_opts.retry_policy;
+        retry_policy
+            .retry(
+                "ContainerRunner::start_container when creating the container",
+                || docker.create_container(Some(create_opts.clone()), config.clone()),
             )
             .await
             .inspect(|x| {
@@ -221,8 +244,11 @@ impl ContainerRunner {
             .await
             .stack()?;
 
-        docker
-            .start_container::<String>(&self.container_opts.name, None)
+        retry_policy
+            .retry(
+                "ContainerRunner::start_container when starting the container",
+                || docker.start_container::<String>(&self.container_opts.name, None),
+            )
             .await
             .stack()?;
 
@@ -261,7 +287,7 @@ impl ContainerRunner {
             use bollard::container::LogOutput;
             use futures::stream::StreamExt;
 
-            let (prefix_out, prefix_err) = if std::io::stderr().is_terminal() {
+            let (prefix_out, prefix_err) = if ansi_colors() && std::io::stderr().is_terminal() {
                 let terminal_color = next_terminal_color();
                 (
                     owo_colors::OwoColorize::color(
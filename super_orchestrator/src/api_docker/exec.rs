@@ -0,0 +1,269 @@
+use std::{borrow::Cow, str::Utf8Error, time::Duration};
+
+use bollard::{
+    container::LogOutput,
+    exec::{CreateExecOptions, StartExecResults},
+};
+use futures::StreamExt;
+use stacked_errors::{bail_locationless, Result, StackableErr};
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{sleep, Instant},
+};
+
+use crate::api_docker::{docker_socket::get_or_init_default_docker_instance, DockerOutput};
+
+/// Options for [exec_in_container]
+#[derive(Debug, Clone, Default)]
+pub struct ExecOptions {
+    /// Extra environment variables in the form "VAR=value"
+    pub env: Vec<String>,
+    pub user: Option<String>,
+    pub workdir: Option<String>,
+    /// Note that with a TTY, stdout and stderr are combined into stdout
+    pub tty: bool,
+}
+
+/// The result of [exec_in_container]
+#[must_use]
+#[derive(Debug, Clone, Default)]
+pub struct ExecResult {
+    pub exit_code: i64,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl ExecResult {
+    /// Returns if the command exited with a zero exit code
+    pub fn successful(&self) -> bool {
+        self.exit_code == 0
+    }
+
+    /// Returns a formatted error with relevant information if the command
+    /// was not successful
+    pub fn assert_success(&self) -> Result<()> {
+        if self.successful() {
+            Ok(())
+        } else {
+            bail_locationless!(
+                "ExecResult {{ exit_code: {}, stdout: {}, stderr: {} }}.assert_success() -> \
+                 unsuccessful",
+                self.exit_code,
+                self.stdout_as_utf8_lossy(),
+                self.stderr_as_utf8_lossy()
+            )
+        }
+    }
+
+    /// Returns `str::from_utf8(&self.stdout)`
+    pub fn stdout_as_utf8(&self) -> std::result::Result<&str, Utf8Error> {
+        std::str::from_utf8(&self.stdout)
+    }
+
+    /// Returns `str::from_utf8(&self.stderr)`
+    pub fn stderr_as_utf8(&self) -> std::result::Result<&str, Utf8Error> {
+        std::str::from_utf8(&self.stderr)
+    }
+
+    /// Returns `String::from_utf8_lossy(&self.stdout)`
+    pub fn stdout_as_utf8_lossy(&self) -> Cow<str> {
+        String::from_utf8_lossy(&self.stdout)
+    }
+
+    /// Returns `String::from_utf8_lossy(&self.stderr)`
+    pub fn stderr_as_utf8_lossy(&self) -> Cow<str> {
+        String::from_utf8_lossy(&self.stderr)
+    }
+}
+
+/// A line received from [exec_in_container_streaming]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecLine {
+    Stdout(String),
+    Stderr(String),
+}
+
+/// Returned by [exec_in_container_streaming]
+#[derive(Debug)]
+pub struct ExecLines {
+    /// Receives the lines without their line endings in the order they were
+    /// output. This is closed once the command exits.
+    pub lines: mpsc::UnboundedReceiver<ExecLine>,
+    exec_id: String,
+    task: Option<JoinHandle<Result<()>>>,
+}
+
+impl ExecLines {
+    /// Waits for the command to exit and returns its exit code. Lines that
+    /// have not been received yet can still be received afterwards.
+    pub async fn wait(&mut self) -> Result<i64> {
+        if let Some(task) = self.task.take() {
+            task.await.stack()?.stack()?;
+        }
+        exit_code_of(&self.exec_id).await
+    }
+}
+
+/// Starts `cmd` in the running container and returns the output stream
+async fn start_exec(
+    container_id: &str,
+    cmd: Vec<String>,
+    opts: ExecOptions,
+) -> Result<(String, DockerOutput)> {
+    let docker = get_or_init_default_docker_instance().await.stack()?;
+    let exec_id = docker
+        .create_exec(container_id, CreateExecOptions {
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            tty: Some(opts.tty),
+            env: Some(opts.env),
+            cmd: Some(cmd),
+            user: opts.user,
+            working_dir: opts.workdir,
+            ..Default::default()
+        })
+        .await
+        .stack()?
+        .id;
+    match docker.start_exec(&exec_id, None).await.stack()? {
+        StartExecResults::Attached { output, input: _ } => Ok((exec_id, output)),
+        StartExecResults::Detached => {
+            bail_locationless!("exec {exec_id} was unexpectedly detached")
+        }
+    }
+}
+
+/// Gets the exit code of an exec whose output has ended. The daemon can still
+/// report the exec as running for a moment after that, so this inspects it
+/// until the state and the exit code of one result agree.
+async fn exit_code_of(exec_id: &str) -> Result<i64> {
+    // how long the daemon has to notice the exit
+    const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+    const INSPECT_DELAY: Duration = Duration::from_millis(50);
+
+    let docker = get_or_init_default_docker_instance().await.stack()?;
+    let deadline = Instant::now() + EXIT_TIMEOUT;
+    loop {
+        let inspect = docker.inspect_exec(exec_id).await.stack()?;
+        if inspect.running != Some(true) {
+            if let Some(exit_code) = inspect.exit_code {
+                return Ok(exit_code)
+            }
+        }
+        if Instant::now() >= deadline {
+            bail_locationless!(
+                "exec {exec_id} did not have an exit code within {EXIT_TIMEOUT:?} after its \
+                 output ended"
+            )
+        }
+        sleep(INSPECT_DELAY).await;
+    }
+}
+
+/// The API equivalent of `docker exec`, runs `cmd` in the running container
+/// with `container_id` (which can also be its name) and waits for it to exit.
+/// Note that a nonzero exit code is not an error, use
+/// [ExecResult::assert_success].
+#[tracing::instrument(skip_all, fields(
+    container.id = %container_id,
+))]
+pub async fn exec_in_container<I, S>(
+    container_id: &str,
+    cmd: I,
+    opts: ExecOptions,
+) -> Result<ExecResult>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let cmd = cmd.into_iter().map(|s| s.as_ref().to_owned()).collect();
+    let (exec_id, mut output) = start_exec(container_id, cmd, opts)
+        .await
+        .stack_err_with_locationless(|| {
+            format!("exec_in_container -> when starting exec in container {container_id}")
+        })?;
+
+    let mut res = ExecResult::default();
+    while let Some(output) = output.next().await {
+        match output.stack_err_with_locationless(|| {
+            format!("exec_in_container -> when getting the output of exec {exec_id}")
+        })? {
+            LogOutput::StdErr { message } => res.stderr.extend_from_slice(&message),
+            LogOutput::StdOut { message } | LogOutput::Console { message } => {
+                res.stdout.extend_from_slice(&message)
+            }
+            LogOutput::StdIn { message: _ } => (),
+        }
+    }
+    res.exit_code = exit_code_of(&exec_id)
+        .await
+        .stack_err_locationless("exec_in_container")?;
+
+    Ok(res)
+}
+
+/// Same as [exec_in_container], but the output is split into lines and sent
+/// as it arrives, useful for tailing long running commands
+#[tracing::instrument(skip_all, fields(
+    container.id = %container_id,
+))]
+pub async fn exec_in_container_streaming<I, S>(
+    container_id: &str,
+    cmd: I,
+    opts: ExecOptions,
+) -> Result<ExecLines>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let cmd = cmd.into_iter().map(|s| s.as_ref().to_owned()).collect();
+    let (exec_id, mut output) = start_exec(container_id, cmd, opts)
+        .await
+        .stack_err_with_locationless(|| {
+            format!("exec_in_container_streaming -> when starting exec in container {container_id}")
+        })?;
+
+    let (sender, lines) = mpsc::unbounded_channel();
+    let exec_id1 = exec_id.clone();
+    let task = tokio::task::spawn(async move {
+        let mut stdout = vec![];
+        let mut stderr = vec![];
+        // the receiver being dropped is not an error, the output is still drained
+        let send = |buf: &mut Vec<u8>, partial: bool, f: fn(String) -> ExecLine| {
+            while let Some(i) = buf.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buf.drain(..=i).collect();
+                let line = String::from_utf8_lossy(&line[..i]);
+                let _ = sender.send(f(line.trim_end_matches('\r').to_owned()));
+            }
+            if partial && !buf.is_empty() {
+                let _ = sender.send(f(String::from_utf8_lossy(buf).into_owned()));
+                buf.clear();
+            }
+        };
+        while let Some(output) = output.next().await {
+            match output.stack_err_with_locationless(|| {
+                format!("exec_in_container_streaming -> when getting the output of exec {exec_id1}")
+            })? {
+                LogOutput::StdErr { message } => {
+                    stderr.extend_from_slice(&message);
+                    send(&mut stderr, false, ExecLine::Stderr);
+                }
+                LogOutput::StdOut { message } | LogOutput::Console { message } => {
+                    stdout.extend_from_slice(&message);
+                    send(&mut stdout, false, ExecLine::Stdout);
+                }
+                LogOutput::StdIn { message: _ } => (),
+            }
+        }
+        send(&mut stdout, true, ExecLine::Stdout);
+        send(&mut stderr, true, ExecLine::Stderr);
+        Ok(())
+    });
+
+    Ok(ExecLines {
+        lines,
+        exec_id,
+        task: Some(task),
+    })
+}
@@ -2,7 +2,7 @@ use std::{path::PathBuf, pin::Pin};
 
 // reexport from bollard
 pub use bollard::{
-    container::LogOutput, errors::Error as BollardError, secret::ContainerWaitResponse,
+    container::LogOutput, errors::Error as BollardError, secret::ContainerWaitResponse, Docker,
 };
 
 /// The trait object for writing to a container's stdin
@@ -22,35 +22,535 @@ pub type WaitContainer = Pin<
 
 /// Things regarding the docker API socket
 pub mod docker_socket {
-    use std::sync::{LazyLock, OnceLock};
+    use std::{
+        collections::HashMap,
+        path::PathBuf,
+        sync::{LazyLock, PoisonError, RwLock},
+    };
 
     use stacked_errors::{Result, StackableErr};
 
-    /// This acquires a process-wide unified `bollard::Docker` handle
-    pub async fn get_or_init_default_docker_instance() -> Result<bollard::Docker> {
-        static DOCKER_SOCKET: OnceLock<bollard::Docker> = OnceLock::new();
-        static EXEC_LOCK: LazyLock<tokio::sync::Mutex<()>> = LazyLock::new(Default::default);
+    use crate::api_docker::build_context::ContextEndpoint;
+
+    /// The timeout in seconds that `bollard` uses for its default connections
+    const TIMEOUT: u64 = 120;
+
+    static DOCKER_SOCKET: RwLock<Option<bollard::Docker>> = RwLock::new(None);
+    static EXEC_LOCK: LazyLock<tokio::sync::Mutex<()>> = LazyLock::new(Default::default);
+    static DOCKER_INSTANCES: LazyLock<
+        tokio::sync::Mutex<HashMap<DockerConnection, bollard::Docker>>,
+    > = LazyLock::new(Default::default);
+    static ENGINE_INFO: RwLock<Option<EngineInfo>> = RwLock::new(None);
+    static CONTEXT_ENDPOINT: RwLock<Option<ContextEndpoint>> = RwLock::new(None);
+
+    /// Returns the default instance if it has been initialized
+    fn current_default() -> Option<bollard::Docker> {
+        DOCKER_SOCKET
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Replaces the default instance, its engine info, and the endpoint that
+    /// build contexts can be streamed to
+    fn set_default(
+        docker_socket: Option<bollard::Docker>,
+        engine_info: Option<EngineInfo>,
+        context_endpoint: Option<ContextEndpoint>,
+    ) -> Option<bollard::Docker> {
+        *ENGINE_INFO.write().unwrap_or_else(PoisonError::into_inner) = engine_info;
+        *CONTEXT_ENDPOINT
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = context_endpoint;
+        std::mem::replace(
+            &mut *DOCKER_SOCKET
+                .write()
+                .unwrap_or_else(PoisonError::into_inner),
+            docker_socket,
+        )
+    }
+
+    /// Describes how to connect to a docker daemon
+    #[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+    pub enum DockerConnection {
+        /// Like the docker CLI, uses the `DOCKER_HOST` environment variable if
+        /// it is set or else the local socket. If `DOCKER_TLS_VERIFY` is set
+        /// or the host has an "https://" scheme, TLS is used with "key.pem",
+        /// "cert.pem", and "ca.pem" from the `DOCKER_CERT_PATH` directory
+        /// (defaulting to "~/.docker"). If the default docker socket does not
+        /// exist but a rootless podman socket at
+        /// "$XDG_RUNTIME_DIR/podman/podman.sock" does, the podman socket is
+        /// used.
+        #[default]
+        Defaults,
+        /// A unix socket at the given path, e.g. "/run/user/1000/docker.sock"
+        /// for rootless docker
+        Unix(PathBuf),
+        /// An unencrypted http host, e.g. "tcp://localhost:2375"
+        Http(String),
+        /// An https host with client certificates, this requires the
+        /// `docker_tls` feature
+        Tls {
+            host: String,
+            key: PathBuf,
+            cert: PathBuf,
+            ca: PathBuf,
+        },
+    }
+
+    impl std::fmt::Display for DockerConnection {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                DockerConnection::Defaults => match std::env::var("DOCKER_HOST") {
+                    Ok(host) => write!(f, "{host} (from DOCKER_HOST)"),
+                    Err(_) => write!(f, "the default local socket"),
+                },
+                DockerConnection::Unix(path) => write!(f, "unix://{}", path.display()),
+                DockerConnection::Http(host) => write!(f, "{host}"),
+                DockerConnection::Tls { host, .. } => write!(f, "{host} (with TLS)"),
+            }
+        }
+    }
+
+    impl DockerConnection {
+        /// Resolves [DockerConnection::Defaults] to a [DockerConnection::Tls]
+        /// if the environment asks for TLS, or to the rootless podman socket
+        /// if only that exists
+        fn resolve_env(&self) -> Result<Option<Self>> {
+            if *self != DockerConnection::Defaults {
+                return Ok(None);
+            }
+            let Ok(host) = std::env::var("DOCKER_HOST") else {
+                return Ok(podman_socket_fallback().map(DockerConnection::Unix));
+            };
+            let tls_verify = std::env::var("DOCKER_TLS_VERIFY").is_ok_and(|s| !s.is_empty());
+            if !(tls_verify || host.starts_with("https://")) {
+                return Ok(None);
+            }
+            let cert_path = if let Ok(cert_path) = std::env::var("DOCKER_CERT_PATH") {
+                PathBuf::from(cert_path)
+            } else {
+                let home =
+                    std::env::var("HOME").stack_err("neither DOCKER_CERT_PATH nor HOME are set")?;
+                PathBuf::from(home).join(".docker")
+            };
+            Ok(Some(DockerConnection::Tls {
+                host,
+                key: cert_path.join("key.pem"),
+                cert: cert_path.join("cert.pem"),
+                ca: cert_path.join("ca.pem"),
+            }))
+        }
+
+        /// Returns the endpoint that build contexts can be streamed to over
+        /// this connection, which is `None` for TLS connections
+        fn context_endpoint(&self) -> Result<Option<ContextEndpoint>> {
+            if let Some(resolved) = self.resolve_env().stack()? {
+                return resolved.context_endpoint();
+            }
+            Ok(match self {
+                DockerConnection::Defaults => match std::env::var("DOCKER_HOST") {
+                    Ok(host) => ContextEndpoint::from_host(&host),
+                    #[cfg(unix)]
+                    Err(_) => Some(ContextEndpoint::Unix(PathBuf::from("/var/run/docker.sock"))),
+                    #[cfg(not(unix))]
+                    Err(_) => None,
+                },
+                #[cfg(unix)]
+                DockerConnection::Unix(path) => Some(ContextEndpoint::Unix(path.clone())),
+                #[cfg(not(unix))]
+                DockerConnection::Unix(_) => None,
+                // `bollard` also accepts hosts without a scheme
+                DockerConnection::Http(host) if !host.contains("://") => {
+                    Some(ContextEndpoint::Http(host.clone()))
+                }
+                DockerConnection::Http(host) => ContextEndpoint::from_host(host),
+                DockerConnection::Tls { .. } => None,
+            })
+        }
+
+        /// Creates a new `bollard::Docker` handle for this connection. Note
+        /// that `bollard` connects lazily, so this only fails for invalid
+        /// configurations.
+        pub fn connect(&self) -> Result<bollard::Docker> {
+            self.connect_internal()
+                .stack_err_with(|| format!("when connecting to the docker endpoint {self}"))
+        }
 
+        fn connect_internal(&self) -> Result<bollard::Docker> {
+            if let Some(resolved) = self.resolve_env().stack()? {
+                return resolved.connect_internal();
+            }
+            match self {
+                DockerConnection::Defaults => bollard::Docker::connect_with_defaults().stack(),
+                #[cfg(unix)]
+                DockerConnection::Unix(path) => bollard::Docker::connect_with_unix(
+                    path.to_str()
+                        .stack_err("failed to convert socket path to str")?,
+                    TIMEOUT,
+                    bollard::API_DEFAULT_VERSION,
+                )
+                .stack(),
+                #[cfg(not(unix))]
+                DockerConnection::Unix(_) => {
+                    Err("unix sockets are not supported on this platform").stack()
+                }
+                DockerConnection::Http(host) => {
+                    bollard::Docker::connect_with_http(host, TIMEOUT, bollard::API_DEFAULT_VERSION)
+                        .stack()
+                }
+                #[cfg(feature = "docker_tls")]
+                DockerConnection::Tls {
+                    host,
+                    key,
+                    cert,
+                    ca,
+                } => bollard::Docker::connect_with_ssl(
+                    host,
+                    key,
+                    cert,
+                    ca,
+                    TIMEOUT,
+                    bollard::API_DEFAULT_VERSION,
+                )
+                .stack(),
+                #[cfg(not(feature = "docker_tls"))]
+                DockerConnection::Tls { .. } => Err("TLS docker connections require the \
+                                                     `docker_tls` feature of `super_orchestrator`")
+                .stack(),
+            }
+        }
+    }
+
+    /// Returns the rootless podman socket if there is no default docker socket
+    #[cfg(unix)]
+    fn podman_socket_fallback() -> Option<PathBuf> {
+        if std::path::Path::new("/var/run/docker.sock").exists() {
+            return None;
+        }
+        let podman_socket = PathBuf::from(std::env::var("XDG_RUNTIME_DIR").ok()?)
+            .join("podman")
+            .join("podman.sock");
+        podman_socket.exists().then_some(podman_socket)
+    }
+
+    #[cfg(not(unix))]
+    fn podman_socket_fallback() -> Option<PathBuf> {
+        None
+    }
+
+    /// The kind of container engine serving the docker API
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ContainerEngine {
+        Docker,
+        /// Podman with its docker compatible API, some options are translated
+        /// or dropped with a warning when this is detected
+        Podman,
+    }
+
+    /// Information about the container engine serving the docker API
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct EngineInfo {
+        pub engine: ContainerEngine,
+        /// The version of the engine
+        pub version: Option<String>,
+        /// The highest API version supported by the engine
+        pub api_version: Option<String>,
+    }
+
+    impl EngineInfo {
+        /// Detects the engine from a `/version` response. Podman has "Podman"
+        /// in the names of its components or platform.
+        pub fn from_version(version: &bollard::system::Version) -> Self {
+            let is_podman = |name: &str| name.to_lowercase().contains("podman");
+            let podman = version
+                .components
+                .iter()
+                .flatten()
+                .any(|component| is_podman(&component.name))
+                || version
+                    .platform
+                    .as_ref()
+                    .is_some_and(|platform| is_podman(&platform.name));
+            Self {
+                engine: if podman {
+                    ContainerEngine::Podman
+                } else {
+                    ContainerEngine::Docker
+                },
+                version: version.version.clone(),
+                api_version: version.api_version.clone(),
+            }
+        }
+
+        pub fn is_podman(&self) -> bool {
+            self.engine == ContainerEngine::Podman
+        }
+    }
+
+    /// The minimum daemon API version that the `api_docker` functions need
+    pub const MIN_API_VERSION: (u32, u32) = (1, 41);
+
+    /// API versions that features need, these are checked by
+    /// [check_compatibility]
+    const FEATURE_API_VERSIONS: &[((u32, u32), &str)] = &[
+        ((1, 32), "building for a platform"),
+        (
+            (1, 40),
+            "BuildKit sessions for `SuperDockerfile::with_secret`",
+        ),
+        (
+            MIN_API_VERSION,
+            "the container, image, and network calls of `api_docker`",
+        ),
+    ];
+
+    /// Parses an API version like "1.41"
+    pub(crate) fn parse_api_version(api_version: &str) -> Option<(u32, u32)> {
+        let (major, minor) = api_version.trim().split_once('.')?;
+        Some((major.parse().ok()?, minor.parse().ok()?))
+    }
+
+    /// Returned by [check_compatibility] when the daemon is too old
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct IncompatibleDaemon {
+        pub engine_info: EngineInfo,
+        /// The API version that the client negotiated with the daemon
+        pub negotiated_api_version: String,
+        /// The features that the daemon does not support
+        pub unsupported: Vec<&'static str>,
+    }
+
+    impl std::fmt::Display for IncompatibleDaemon {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "the {:?} daemon with version {} has API version {} (negotiated API version {}), \
+                 but API version {}.{} or newer is required. Unsupported features: {}",
+                self.engine_info.engine,
+                self.engine_info.version.as_deref().unwrap_or("unknown"),
+                self.engine_info.api_version.as_deref().unwrap_or("unknown"),
+                self.negotiated_api_version,
+                MIN_API_VERSION.0,
+                MIN_API_VERSION.1,
+                self.unsupported.join(", ")
+            )
+        }
+    }
+
+    impl std::error::Error for IncompatibleDaemon {}
+
+    /// Negotiates the API version with the daemon behind `docker_instance` and
+    /// returns an [IncompatibleDaemon] error if it is too old. Returns the
+    /// instance that uses the negotiated API version.
+    async fn check_compatibility_for(
+        docker_instance: bollard::Docker,
+    ) -> Result<(bollard::Docker, EngineInfo)> {
+        let negotiated = docker_instance
+            .negotiate_version()
+            .await
+            // because the display impl only shows the error enum
+            .map_err(|e| format!("{e:?}"))
+            .stack_err(
+                "when negotiating the API version, the endpoint may not be a docker compatible \
+                 daemon",
+            )?;
+        let engine_info = engine_info_for(&negotiated).await.stack()?;
+        let api_version = engine_info
+            .api_version
+            .as_deref()
+            .and_then(parse_api_version)
+            .stack_err_with(|| {
+                format!(
+                    "the daemon reported an invalid API version {:?}",
+                    engine_info.api_version
+                )
+            })?;
+        let unsupported: Vec<_> = FEATURE_API_VERSIONS
+            .iter()
+            .filter(|(min, _)| api_version < *min)
+            .map(|(_, feature)| *feature)
+            .collect();
+        if unsupported.is_empty() {
+            Ok((negotiated, engine_info))
+        } else {
+            Err(IncompatibleDaemon {
+                engine_info,
+                negotiated_api_version: negotiated.client_version().to_string(),
+                unsupported,
+            })
+            .stack()
+        }
+    }
+
+    /// Checks that the daemon behind [get_or_init_default_docker_instance]
+    /// supports what the `api_docker` functions need. This is called
+    /// automatically when the default instance is first used, so that old
+    /// daemons do not result in opaque errors.
+    pub async fn check_compatibility() -> Result<EngineInfo> {
+        let docker_instance = get_or_init_default_docker_instance().await.stack()?;
+        let (_, engine_info) = check_compatibility_for(docker_instance)
+            .await
+            .stack_err("docker_socket::check_compatibility")?;
+        Ok(engine_info)
+    }
+
+    /// Connects with `connection`, checks the compatibility of the daemon, and
+    /// sets the default instance and its engine info
+    async fn connect_default(connection: DockerConnection) -> Result<bollard::Docker> {
+        let (docker_socket, context_endpoint) = tokio::task::spawn_blocking(move || {
+            Ok::<_, stacked_errors::Error>((connection.connect()?, connection.context_endpoint()?))
+        })
+        .await
+        .stack()??;
+        // the negotiated instance is the one that is kept
+        let (docker_socket, engine_info) = check_compatibility_for(docker_socket)
+            .await
+            .stack_err("when checking the compatibility of the default docker instance")?;
+        set_default(
+            Some(docker_socket.clone()),
+            Some(engine_info),
+            context_endpoint,
+        );
+        Ok(docker_socket)
+    }
+
+    /// Returns the endpoint that build contexts can be streamed to for the
+    /// default instance, if the default instance was initialized by this
+    /// module with a unix socket or an unencrypted http connection
+    pub(crate) fn default_context_endpoint() -> Option<ContextEndpoint> {
+        CONTEXT_ENDPOINT
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Queries the engine behind `docker_instance`
+    pub async fn engine_info_for(docker_instance: &bollard::Docker) -> Result<EngineInfo> {
+        let version = docker_instance
+            .version()
+            .await
+            .stack_err("when querying the docker API version to detect the container engine")?;
+        Ok(EngineInfo::from_version(&version))
+    }
+
+    /// Returns information about the engine behind
+    /// [get_or_init_default_docker_instance], this is only queried once per
+    /// process
+    pub async fn engine_info() -> Result<EngineInfo> {
+        if let Some(engine_info) = ENGINE_INFO
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+        {
+            return Ok(engine_info)
+        }
+        let docker_instance = get_or_init_default_docker_instance().await.stack()?;
+        let engine_info = engine_info_for(&docker_instance).await.stack()?;
+        *ENGINE_INFO.write().unwrap_or_else(PoisonError::into_inner) = Some(engine_info.clone());
+        Ok(engine_info)
+    }
+
+    /// Returns the engine info of `docker_instance` if it is set, or else
+    /// [engine_info]
+    pub async fn engine_info_or_default(
+        docker_instance: Option<&bollard::Docker>,
+    ) -> Result<EngineInfo> {
+        match docker_instance {
+            Some(docker_instance) => engine_info_for(docker_instance).await,
+            None => engine_info().await,
+        }
+    }
+
+    /// This acquires a process-wide unified `bollard::Docker` handle. This
+    /// uses [DockerConnection::Defaults] unless [init_with] was called first.
+    ///
+    /// On first use, the API version is negotiated and the daemon is checked
+    /// with [check_compatibility].
+    pub async fn get_or_init_default_docker_instance() -> Result<bollard::Docker> {
         // this has a fast path with a slow path that is careful to not block the
         // process
-        if let Some(docker_instance) = DOCKER_SOCKET.get() {
-            Ok(docker_instance.clone())
+        if let Some(docker_instance) = current_default() {
+            Ok(docker_instance)
         } else {
             let _exec_lock = EXEC_LOCK.lock().await;
 
-            if let Some(docker_instance) = DOCKER_SOCKET.get() {
-                Ok(docker_instance.clone())
+            if let Some(docker_instance) = current_default() {
+                Ok(docker_instance)
             } else {
-                let docker_socket = tokio::task::spawn_blocking(|| {
-                    bollard::Docker::connect_with_defaults().stack()
-                })
-                .await
-                .stack()??;
+                connect_default(DockerConnection::Defaults).await.stack()
+            }
+        }
+    }
+
+    /// Returns a clone of `docker_instance` if it is set, or else
+    /// [get_or_init_default_docker_instance]. Functions that accept an
+    /// optional handle use this.
+    pub async fn docker_or_default(
+        docker_instance: Option<&bollard::Docker>,
+    ) -> Result<bollard::Docker> {
+        match docker_instance {
+            Some(docker_instance) => Ok(docker_instance.clone()),
+            None => get_or_init_default_docker_instance().await,
+        }
+    }
 
-                let _ = DOCKER_SOCKET.set(docker_socket);
+    /// Initializes the process-wide handle returned by
+    /// [get_or_init_default_docker_instance] with `connection`. Returns an
+    /// error if the handle was already initialized.
+    pub async fn init_with(connection: DockerConnection) -> Result<()> {
+        let _exec_lock = EXEC_LOCK.lock().await;
 
-                Ok(DOCKER_SOCKET.get().unwrap().clone())
-            }
+        if current_default().is_some() {
+            return Err(format!(
+                "docker_socket::init_with -> the default docker instance was already initialized, \
+                 could not initialize it with the docker endpoint {connection}"
+            ))
+            .stack()
+        }
+        connect_default(connection).await.stack()?;
+
+        Ok(())
+    }
+
+    /// Replaces the process-wide handle returned by
+    /// [get_or_init_default_docker_instance], returning the previous handle.
+    /// The compatibility of the daemon is not checked, and its engine info is
+    /// queried again on next use. Passing `None` resets the handle so that it
+    /// is initialized again on next use.
+    ///
+    /// This is meant for tests that point the default handle at a different
+    /// daemon (or at no daemon, to check failure paths). Handles that were
+    /// already cloned out are not affected.
+    pub async fn set_default_for_tests(
+        docker_instance: Option<bollard::Docker>,
+    ) -> Option<bollard::Docker> {
+        let _exec_lock = EXEC_LOCK.lock().await;
+
+        set_default(docker_instance, None, None)
+    }
+
+    /// Acquires a process-wide `bollard::Docker` handle for `connection`,
+    /// independent of the default handle. This allows different parts of one
+    /// process to talk to different daemons.
+    pub async fn get_or_init_docker_instance_for(
+        connection: &DockerConnection,
+    ) -> Result<bollard::Docker> {
+        let mut docker_instances = DOCKER_INSTANCES.lock().await;
+
+        if let Some(docker_instance) = docker_instances.get(connection) {
+            Ok(docker_instance.clone())
+        } else {
+            let docker_socket = tokio::task::spawn_blocking({
+                let connection = connection.clone();
+                move || connection.connect()
+            })
+            .await
+            .stack()??;
+
+            docker_instances.insert(connection.clone(), docker_socket.clone());
+
+            Ok(docker_socket)
         }
     }
 }
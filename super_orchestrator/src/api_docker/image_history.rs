@@ -0,0 +1,95 @@
+use stacked_errors::{bail_locationless, Result, StackableErr};
+
+use crate::api_docker::{docker_socket::get_or_init_default_docker_instance, SuperImage};
+
+/// The number of layers listed by [SuperImage::assert_max_size]
+const NUM_LARGEST_LAYERS: usize = 5;
+
+/// A layer of an image as returned by [SuperImage::history]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LayerInfo {
+    /// The instruction that created the layer
+    pub created_by: String,
+    /// The size of the layer in bytes
+    pub size: u64,
+    pub comment: String,
+}
+
+impl SuperImage {
+    /// The API equivalent of `docker history`, returns the layers of the
+    /// image from newest to oldest
+    pub async fn history(&self) -> Result<Vec<LayerInfo>> {
+        let docker = get_or_init_default_docker_instance().await.stack()?;
+        let history = docker
+            .image_history(self.get_image_id())
+            .await
+            .stack_err_with_locationless(|| {
+                format!(
+                    "SuperImage::history when getting the history of image {}",
+                    self.get_image_id()
+                )
+            })?;
+        Ok(history
+            .into_iter()
+            .map(|item| LayerInfo {
+                created_by: item.created_by,
+                size: u64::try_from(item.size).unwrap_or(0),
+                comment: item.comment,
+            })
+            .collect())
+    }
+
+    /// Returns the size of the image in bytes
+    pub async fn total_size(&self) -> Result<u64> {
+        let docker = get_or_init_default_docker_instance().await.stack()?;
+        let size = docker
+            .inspect_image(self.get_image_id())
+            .await
+            .stack_err_with_locationless(|| {
+                format!(
+                    "SuperImage::total_size when inspecting image {}",
+                    self.get_image_id()
+                )
+            })?
+            .size;
+        match size {
+            Some(size) => Ok(u64::try_from(size).unwrap_or(0)),
+            // some engines do not report the size
+            None => Ok(self
+                .history()
+                .await
+                .stack_err_locationless("SuperImage::total_size")?
+                .iter()
+                .map(|layer| layer.size)
+                .sum()),
+        }
+    }
+
+    /// Returns an error listing the largest layers if the image is larger than
+    /// `max_bytes`
+    pub async fn assert_max_size(&self, max_bytes: u64) -> Result<()> {
+        let total_size = self
+            .total_size()
+            .await
+            .stack_err_locationless("SuperImage::assert_max_size")?;
+        if total_size <= max_bytes {
+            return Ok(())
+        }
+        let mut layers = self
+            .history()
+            .await
+            .stack_err_locationless("SuperImage::assert_max_size")?;
+        layers.sort_by_key(|layer| std::cmp::Reverse(layer.size));
+        let largest: Vec<String> = layers
+            .iter()
+            .take(NUM_LARGEST_LAYERS)
+            .map(|layer| format!("{} bytes: {}", layer.size, layer.created_by.trim()))
+            .collect();
+        bail_locationless!(
+            "SuperImage::assert_max_size -> image {} is {total_size} bytes, which is larger than \
+             the maximum of {max_bytes} bytes, the largest layers are:\n{}",
+            self.get_image_id(),
+            largest.join("\n")
+        )
+    }
+}
@@ -1,24 +1,47 @@
-use std::{collections::HashMap, net::IpAddr};
+use std::{collections::HashMap, future::Future, net::IpAddr, path::PathBuf, time::Duration};
 
-use crate::{api_docker::SuperDockerfile, cli_docker::Dockerfile};
+use stacked_errors::{Result, StackableErr};
+
+use crate::{
+    api_docker::{BollardError, SuperDockerfile},
+    cli_docker::Dockerfile,
+};
 
 /// Wrapper struct for a simple image
 #[derive(Debug, Clone)]
-pub struct SuperImage(String);
+pub struct SuperImage {
+    image_id: String,
+    reference: Option<String>,
+}
 
 impl SuperImage {
     /// Should use a prebuilt image
     pub fn new(image_id: String) -> Self {
-        Self(image_id)
+        Self {
+            image_id,
+            reference: None,
+        }
+    }
+
+    /// Sets the reference (e.g. "name:tag") that the image is tagged with
+    pub fn with_reference(mut self, reference: impl ToString) -> Self {
+        self.reference = Some(reference.to_string());
+        self
     }
 
     pub fn into_inner(self) -> String {
-        self.0
+        self.image_id
     }
 
     /// Get the ID of the image as a `&str`
     pub fn get_image_id(&self) -> &str {
-        &self.0
+        &self.image_id
+    }
+
+    /// Get the reference (e.g. "name:tag") that the image was tagged with
+    /// when it was built, committed, or pulled, if any
+    pub fn reference(&self) -> Option<&str> {
+        self.reference.as_deref()
     }
 
     /// Uses `SuperDockerfile::new(Dockerfile::name_tag(self.get_image_id()),
@@ -63,6 +86,173 @@ impl BootstrapOptions {
     }
 }
 
+/// The cargo profile used when a binary needs to be built for bootstrapping
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum BuildProfile {
+    /// The `release` profile, `cargo build -r`
+    #[default]
+    Release,
+    /// The `dev` profile, which ends up under `debug/` in the target directory
+    Debug,
+    /// A custom profile defined in a `Cargo.toml`
+    Custom(String),
+}
+
+impl BuildProfile {
+    /// The name passed to `cargo build --profile`
+    pub fn to_profile_name(&self) -> &str {
+        match self {
+            BuildProfile::Release => "release",
+            BuildProfile::Debug => "dev",
+            BuildProfile::Custom(name) => name,
+        }
+    }
+
+    /// The directory name under the target directory that artifacts end up in
+    pub fn to_dir_name(&self) -> &str {
+        match self {
+            BuildProfile::Release => "release",
+            BuildProfile::Debug => "debug",
+            BuildProfile::Custom(name) => name,
+        }
+    }
+}
+
+/// The source of a BuildKit secret for `RUN --mount=type=secret,id=<id>`
+/// instructions, see [SuperDockerfile::with_secret]. Secrets are never placed
+/// in the build tarball or the dockerfile.
+#[derive(Clone, PartialEq, Eq)]
+pub enum BuildSecret {
+    /// The secret bytes, these are written to a private temporary file for the
+    /// duration of the build
+    Bytes(Vec<u8>),
+    /// A file on the host containing the secret
+    File(PathBuf),
+    /// An environment variable of the current process containing the secret
+    Env(String),
+}
+
+// avoid printing the secret bytes
+impl std::fmt::Debug for BuildSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bytes(_) => write!(f, "Bytes(..)"),
+            Self::File(path) => f.debug_tuple("File").field(path).finish(),
+            Self::Env(var) => f.debug_tuple("Env").field(var).finish(),
+        }
+    }
+}
+
+/// How API docker operations are retried on transient connection errors, such
+/// as a daemon under load resetting the connection. Errors that the daemon
+/// responds with (e.g. a 404 for a missing image) are never retried.
+///
+/// The default does no retries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The number of retries after the first attempt
+    pub max_retries: u32,
+    /// The delay before the first retry, this doubles with every retry
+    pub initial_backoff: Duration,
+    /// The maximum delay between retries
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 0,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Retries up to `max_retries` times with the default backoff
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            ..Default::default()
+        }
+    }
+
+    /// If `err` is a connection level error that is worth retrying
+    pub fn is_transient(err: &BollardError) -> bool {
+        use std::io::ErrorKind;
+        match err {
+            BollardError::IOError { err } => matches!(
+                err.kind(),
+                ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::ConnectionRefused
+                    | ErrorKind::NotConnected
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::UnexpectedEof
+                    | ErrorKind::TimedOut
+                    | ErrorKind::Interrupted
+            ),
+            BollardError::HyperResponseError { .. }
+            | BollardError::HyperLegacyError { .. }
+            | BollardError::RequestTimeoutError => true,
+            // streams report lost connections to the daemon as messages
+            BollardError::DockerStreamError { error } => {
+                let error = error.to_lowercase();
+                error.contains("eof") || error.contains("connection reset")
+            }
+            _ => false,
+        }
+    }
+
+    /// If another attempt should be made after attempt number `attempt`
+    /// (starting from 0) failed with `err`
+    pub fn should_retry(&self, attempt: u32, err: &BollardError) -> bool {
+        (attempt < self.max_retries) && Self::is_transient(err)
+    }
+
+    /// The delay after attempt number `attempt` (starting from 0) failed
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+
+    /// Records the retry at warn level and waits for the backoff
+    pub async fn wait_before_retry(&self, attempt: u32, operation: &str, err: &BollardError) {
+        let backoff = self.backoff(attempt);
+        tracing::warn!(
+            "{operation} failed with a transient error, retrying ({}/{}) in {backoff:?}: {err:?}",
+            attempt + 1,
+            self.max_retries
+        );
+        tokio::time::sleep(backoff).await;
+    }
+
+    /// Calls `f` until it succeeds, retrying transient errors according to the
+    /// policy. `operation` is used for logging and errors.
+    pub async fn retry<T, F, Fut>(&self, operation: &str, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = std::result::Result<T, BollardError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match f().await {
+                Ok(o) => return Ok(o),
+                Err(e) if self.should_retry(attempt, &e) => {
+                    self.wait_before_retry(attempt, operation, &e).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    return Err(e).stack_err_with(|| {
+                        format!("{operation} failed after {} attempt(s)", attempt + 1)
+                    })
+                }
+            }
+        }
+    }
+}
+
 /// Define port mapping like for the argument `-p
 /// <host_ip>:<host_port>:<container_port>/<protocol>`.
 ///
@@ -73,29 +263,29 @@ impl BootstrapOptions {
 #[derive(Debug, Clone)]
 pub struct PortBind {
     container_port: u16,
+    /// The inclusive end of a port range, equal to `container_port` for a
+    /// single port
+    container_port_end: u16,
     host_port: Option<u16>,
     host_ip: Option<IpAddr>,
     protocol: PortBindProtocol,
 }
 
 /// Protocol of the container port binding
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PortBindProtocol {
     Tcp,
     Udp,
+    Sctp,
 }
 
 impl std::fmt::Display for PortBindProtocol {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            if matches!(self, Self::Tcp) {
-                "tcp"
-            } else {
-                "udp"
-            }
-        )
+        f.write_str(match self {
+            Self::Tcp => "tcp",
+            Self::Udp => "udp",
+            Self::Sctp => "sctp",
+        })
     }
 }
 
@@ -105,13 +295,47 @@ impl PortBind {
     pub fn new(port: u16) -> Self {
         Self {
             container_port: port,
+            container_port_end: port,
             host_port: Some(port),
             host_ip: None,
             protocol: PortBindProtocol::Tcp,
         }
     }
 
-    /// Sets a different `host_port` in `<host_port>:<container_port>`
+    /// Results in the port mapping `<port>`, where docker chooses a free host
+    /// port. Use [resolve_host_port](crate::api_docker::resolve_host_port) to
+    /// get the port after the container is started.
+    pub fn ephemeral(port: u16) -> Self {
+        Self {
+            container_port: port,
+            container_port_end: port,
+            host_port: Some(0),
+            host_ip: None,
+            protocol: PortBindProtocol::Tcp,
+        }
+    }
+
+    /// Results in the port mapping `<start>-<end>:<start>-<end>` of an
+    /// inclusive range. Returns an error if `start > end`.
+    pub fn range(start: u16, end: u16) -> Result<Self> {
+        if start > end {
+            return Err(format!(
+                "PortBind::range({start}, {end}) -> the start is larger than the end"
+            ))
+            .stack();
+        }
+        Ok(Self {
+            container_port: start,
+            container_port_end: end,
+            host_port: Some(start),
+            host_ip: None,
+            protocol: PortBindProtocol::Tcp,
+        })
+    }
+
+    /// Sets a different `host_port` in `<host_port>:<container_port>`, a
+    /// `host_port` of 0 is the same as [PortBind::ephemeral]. For ranges, this
+    /// is the start of the host range.
     pub fn with_host_port(mut self, host_port: u16) -> Self {
         self.host_port = Some(host_port);
         self
@@ -128,6 +352,78 @@ impl PortBind {
         self.protocol = protocol;
         self
     }
+
+    fn is_range(&self) -> bool {
+        self.container_port != self.container_port_end
+    }
+
+    /// Returns the start and the inclusive end of the host port range, or
+    /// `None` if docker should choose the host ports. The end is an error
+    /// holding the unrepresentable end if the range overflows past `u16::MAX`.
+    fn host_range(&self) -> Option<(u16, std::result::Result<u16, u32>)> {
+        let len = self.container_port_end - self.container_port;
+        match self.host_port.unwrap_or(self.container_port) {
+            0 => None,
+            host_start => Some((
+                host_start,
+                host_start
+                    .checked_add(len)
+                    .ok_or(u32::from(host_start) + u32::from(len)),
+            )),
+        }
+    }
+
+    /// Returns the `(container_port, host_port)` pairs, where the host port is
+    /// empty if docker should choose it
+    fn expand(&self) -> Result<Vec<(u16, String)>> {
+        let host_start = match self.host_range() {
+            None => None,
+            Some((host_start, Ok(_))) => Some(host_start),
+            Some((_, Err(_))) => {
+                return Err(format!(
+                    "PortBind {self} -> the host port range overflows past {}",
+                    u16::MAX
+                ))
+                .stack();
+            }
+        };
+        Ok((0..=(self.container_port_end - self.container_port))
+            .map(|i| {
+                let host_port = match host_start {
+                    Some(host_start) => (host_start + i).to_string(),
+                    None => String::new(),
+                };
+                (self.container_port + i, host_port)
+            })
+            .collect())
+    }
+}
+
+impl std::fmt::Display for PortBind {
+    /// Formats as the argument to `docker run -p`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let container = if self.is_range() {
+            format!("{}-{}", self.container_port, self.container_port_end)
+        } else {
+            self.container_port.to_string()
+        };
+        // an overflowing end is shown as is, so that the range is visibly invalid
+        let host = match self.host_range() {
+            None => String::new(),
+            Some((host_start, _)) if !self.is_range() => host_start.to_string(),
+            Some((host_start, Ok(host_end))) => format!("{host_start}-{host_end}"),
+            Some((host_start, Err(host_end))) => format!("{host_start}-{host_end}"),
+        };
+        match (self.host_ip, host.is_empty()) {
+            // IPv6 addresses need brackets to not be confused with the port separators
+            (Some(IpAddr::V6(host_ip)), _) => {
+                write!(f, "[{host_ip}]:{host}:{container}/{}", self.protocol)
+            }
+            (Some(host_ip), _) => write!(f, "{host_ip}:{host}:{container}/{}", self.protocol),
+            (None, true) => write!(f, "{container}/{}", self.protocol),
+            (None, false) => write!(f, "{host}:{container}/{}", self.protocol),
+        }
+    }
 }
 
 impl From<u16> for PortBind {
@@ -140,30 +436,47 @@ impl From<u16> for PortBind {
 #[allow(clippy::type_complexity)] // internal only
 pub(crate) fn port_bindings_to_bollard_args(
     pbs: &[PortBind],
-) -> (
+) -> Result<(
     Option<HashMap<String, HashMap<(), ()>>>,
     Option<HashMap<String, Option<Vec<bollard::secret::PortBinding>>>>,
-) {
+)> {
+    // the same single port can be bound multiple times (e.g. to different host
+    // IPs), but ranges overlapping with anything are probably a mistake
+    for (i, pb0) in pbs.iter().enumerate() {
+        for pb1 in &pbs[(i + 1)..] {
+            if (pb0.is_range() || pb1.is_range())
+                && (pb0.protocol == pb1.protocol)
+                && (pb0.container_port <= pb1.container_port_end)
+                && (pb1.container_port <= pb0.container_port_end)
+            {
+                return Err(format!(
+                    "port bindings {pb0} and {pb1} have overlapping container ports"
+                ))
+                .stack();
+            }
+        }
+    }
+
     let mut port_map =
         HashMap::<String, Option<Vec<bollard::secret::PortBinding>>>::with_capacity(pbs.len());
     let mut open_ports = HashMap::<String, HashMap<(), ()>>::with_capacity(pbs.len());
 
     for pb in pbs {
-        let key = format!("{}/{}", pb.container_port, pb.protocol);
-        let entry = port_map.entry(key.clone()).or_insert_with(|| Some(vec![]));
-        if let Some(entry) = entry {
-            // always matches, just more ergonomic
-            entry.push(bollard::secret::PortBinding {
-                host_port: pb
-                    .host_port
-                    .or(Some(pb.container_port))
-                    .as_ref()
-                    .map(ToString::to_string),
-                host_ip: pb.host_ip.as_ref().map(ToString::to_string),
-            });
-        }
-        open_ports.entry(key).or_default();
-    }
-
-    (Some(open_ports), Some(port_map))
+        for (container_port, host_port) in pb.expand()? {
+            let key = format!("{container_port}/{}", pb.protocol);
+            let entry = port_map.entry(key.clone()).or_insert_with(|| Some(vec![]));
+            if let Some(entry) = entry {
+                // always matches, just more ergonomic
+                entry.push(bollard::secret::PortBinding {
+                    // an empty host port lets docker choose
+                    host_port: Some(host_port),
+                    // the API takes the plain address, IPv6 is not bracketed here
+                    host_ip: pb.host_ip.as_ref().map(ToString::to_string),
+                });
+            }
+            open_ports.entry(key).or_default();
+        }
+    }
+
+    Ok((Some(open_ports), Some(port_map)))
 }
@@ -0,0 +1,206 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use bollard::{
+    network::{CreateNetworkOptions, InspectNetworkOptions},
+    secret::{Ipam, IpamConfig},
+};
+use stacked_errors::{bail_locationless, Result, StackableErr};
+use uuid::Uuid;
+
+use crate::api_docker::docker_socket::get_or_init_default_docker_instance;
+
+/// The label key that networks created by [create_network_api] get, with a
+/// UUID value unique to each network. Networks left behind by crashed tests
+/// can be found with a `label=super_orchestrator.uuid` filter.
+pub const UUID_LABEL: &str = "super_orchestrator.uuid";
+
+/// Options for [create_network_api]
+#[derive(Debug, Clone, Default)]
+pub struct NetworkOptions {
+    /// Restricts external access to the network
+    pub internal: bool,
+    /// A subnet in CIDR notation, e.g. "172.28.0.0/16"
+    pub subnet: Option<String>,
+    /// The gateway, which must be in `subnet`
+    pub gateway: Option<String>,
+    pub labels: HashMap<String, String>,
+}
+
+/// Parses "address/prefix" CIDR notation, requiring that no host bits are set
+fn parse_subnet(subnet: &str) -> Result<(IpAddr, u32)> {
+    let (addr, prefix) = subnet
+        .split_once('/')
+        .stack_err_locationless("expected CIDR notation like \"172.28.0.0/16\"")?;
+    let addr: IpAddr = addr
+        .parse()
+        .stack_err_locationless("invalid subnet address")?;
+    let prefix: u32 = prefix
+        .parse()
+        .stack_err_locationless("invalid prefix length")?;
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    if prefix > max_prefix {
+        bail_locationless!("prefix length {prefix} is larger than {max_prefix}")
+    }
+    if masked(addr, prefix) != addr {
+        bail_locationless!(
+            "the address has host bits set, it should be {}/{prefix}",
+            masked(addr, prefix)
+        )
+    }
+    Ok((addr, prefix))
+}
+
+/// Clears the bits of `addr` after the first `prefix` bits
+fn masked(addr: IpAddr, prefix: u32) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            IpAddr::V4((u32::from(addr) & mask).into())
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            IpAddr::V6((u128::from(addr) & mask).into())
+        }
+    }
+}
+
+/// Checks that the subnet is valid CIDR and that the gateway is in the subnet
+fn validate_ipam(subnet: Option<&str>, gateway: Option<&str>) -> Result<()> {
+    let Some(subnet) = subnet else {
+        if let Some(gateway) = gateway {
+            bail_locationless!("gateway {gateway} was set without a subnet")
+        }
+        return Ok(())
+    };
+    let (subnet_addr, prefix) =
+        parse_subnet(subnet).stack_err_with_locationless(|| format!("invalid subnet {subnet}"))?;
+    if let Some(gateway) = gateway {
+        let gateway_addr: IpAddr = gateway
+            .parse()
+            .stack_err_with_locationless(|| format!("invalid gateway address {gateway}"))?;
+        if (gateway_addr.is_ipv4() != subnet_addr.is_ipv4())
+            || (masked(gateway_addr, prefix) != subnet_addr)
+        {
+            bail_locationless!("gateway {gateway} is not in subnet {subnet}")
+        }
+    }
+    Ok(())
+}
+
+/// A docker network created or found with the API
+#[derive(Debug, Clone)]
+pub struct NetworkHandle {
+    name: String,
+    id: String,
+    uuid: Option<String>,
+}
+
+impl NetworkHandle {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The value of the [UUID_LABEL], which may not exist on networks that
+    /// were reused by [get_or_create_network_api]
+    pub fn uuid(&self) -> Option<&str> {
+        self.uuid.as_deref()
+    }
+
+    /// Removes the network, this fails if containers are still connected to it
+    pub async fn remove(self) -> Result<()> {
+        let docker = get_or_init_default_docker_instance().await.stack()?;
+        docker
+            .remove_network(&self.id)
+            .await
+            .stack_err_with_locationless(|| {
+                format!("NetworkHandle::remove when removing network {}", self.name)
+            })
+    }
+}
+
+/// The API equivalent of `docker network create`, creates a bridge network
+/// with the [UUID_LABEL]. Returns an error containing the daemon message if a
+/// network with `name` already exists, see [get_or_create_network_api] for
+/// reusing it instead.
+#[tracing::instrument(skip_all, fields(
+    network.name = %name,
+))]
+pub async fn create_network_api(name: &str, opts: NetworkOptions) -> Result<NetworkHandle> {
+    validate_ipam(opts.subnet.as_deref(), opts.gateway.as_deref()).stack_err_with_locationless(
+        || format!("create_network_api when validating the options for network {name}"),
+    )?;
+
+    let uuid = Uuid::new_v4().to_string();
+    let mut labels = opts.labels;
+    labels.insert(UUID_LABEL.to_owned(), uuid.clone());
+    let ipam = if opts.subnet.is_some() {
+        Ipam {
+            config: Some(vec![IpamConfig {
+                subnet: opts.subnet,
+                gateway: opts.gateway,
+                ..Default::default()
+            }]),
+            ..Default::default()
+        }
+    } else {
+        Ipam::default()
+    };
+
+    let docker = get_or_init_default_docker_instance().await.stack()?;
+    let response = docker
+        .create_network(CreateNetworkOptions {
+            name: name.to_owned(),
+            check_duplicate: true,
+            driver: "bridge".to_owned(),
+            internal: opts.internal,
+            ipam,
+            labels,
+            ..Default::default()
+        })
+        .await
+        .stack_err_with_locationless(|| {
+            format!("create_network_api when creating network {name}")
+        })?;
+    if !response.warning.is_empty() {
+        tracing::warn!("creating network {name}: {}", response.warning);
+    }
+
+    Ok(NetworkHandle {
+        name: name.to_owned(),
+        id: response.id,
+        uuid: Some(uuid),
+    })
+}
+
+/// Same as [create_network_api], except that an existing network with `name`
+/// is reused (without checking if it matches `opts`)
+#[tracing::instrument(skip_all, fields(
+    network.name = %name,
+))]
+pub async fn get_or_create_network_api(name: &str, opts: NetworkOptions) -> Result<NetworkHandle> {
+    let docker = get_or_init_default_docker_instance().await.stack()?;
+    // inspection also matches ID prefixes, so the name is checked
+    if let Ok(network) = docker
+        .inspect_network(name, None::<InspectNetworkOptions<String>>)
+        .await
+    {
+        if network.name.as_deref() == Some(name) {
+            return Ok(NetworkHandle {
+                name: name.to_owned(),
+                id: network.id.stack_err_with_locationless(|| {
+                    format!("get_or_create_network_api -> network {name} did not have an ID")
+                })?,
+                uuid: network
+                    .labels
+                    .and_then(|mut labels| labels.remove(UUID_LABEL)),
+            })
+        }
+    }
+    create_network_api(name, opts)
+        .await
+        .stack_err_locationless("get_or_create_network_api")
+}
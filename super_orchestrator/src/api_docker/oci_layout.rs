@@ -0,0 +1,208 @@
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::{Component, Path, PathBuf},
+};
+
+use futures::StreamExt;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use stacked_errors::{bail_locationless, Result, StackableErr};
+
+use crate::api_docker::{docker_socket::get_or_init_default_docker_instance, SuperImage};
+
+const OCI_LAYOUT: &str = "oci-layout";
+const INDEX_JSON: &str = "index.json";
+const MANIFEST_JSON: &str = "manifest.json";
+
+/// An entry of the `manifest.json` of a docker-archive tarball
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ArchiveManifest {
+    config: String,
+    #[serde(default)]
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+/// Reads the regular files of a tarball into a map from their normalized
+/// paths to their contents
+fn read_tarball_files(tarball: &[u8]) -> Result<HashMap<String, Vec<u8>>> {
+    let mut files = HashMap::new();
+    let mut archive = tar::Archive::new(std::io::Cursor::new(tarball));
+    for entry in archive.entries().stack()? {
+        let mut entry = entry.stack()?;
+        if !entry.header().entry_type().is_file() {
+            continue
+        }
+        let path = entry.path().stack()?;
+        let mut normalized = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(component) => normalized.push(component),
+                Component::CurDir => (),
+                _ => bail_locationless!("the image tarball has an entry at {path:?}"),
+            }
+        }
+        let normalized = normalized
+            .to_str()
+            .stack_err_locationless("the image tarball has a non UTF-8 path")?
+            .to_owned();
+        let mut content = vec![];
+        entry.read_to_end(&mut content).stack()?;
+        files.insert(normalized, content);
+    }
+    Ok(files)
+}
+
+/// Returns the "sha256:<hex>" digest of `content`
+fn sha256_digest(content: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(content))
+}
+
+/// Converts the files of a docker-archive tarball into the files of an OCI
+/// layout
+fn docker_archive_to_oci_layout(
+    mut files: HashMap<String, Vec<u8>>,
+) -> Result<Vec<(String, Vec<u8>)>> {
+    let manifest = files
+        .get(MANIFEST_JSON)
+        .stack_err_locationless("the image tarball has neither an OCI layout or manifest.json")?;
+    let manifest: Vec<ArchiveManifest> = serde_json::from_slice(manifest)
+        .stack_err_locationless("when parsing the manifest.json of the image tarball")?;
+    let [manifest] = <[ArchiveManifest; 1]>::try_from(manifest)
+        .map_err(|manifest| {
+            format!(
+                "expected manifest.json to have a single image, it has {}",
+                manifest.len()
+            )
+        })
+        .stack()?;
+
+    let mut layout = vec![];
+    let mut add_blob = |path: &str, layout: &mut Vec<(String, Vec<u8>)>| -> Result<_> {
+        let content = files
+            .remove(path)
+            .stack_err_with_locationless(|| format!("the image tarball is missing {path}"))?;
+        let digest = sha256_digest(&content);
+        let size = content.len();
+        layout.push((digest.replace(':', "/"), content));
+        Ok((digest, size))
+    };
+
+    let (config_digest, config_size) = add_blob(&manifest.config, &mut layout).stack()?;
+    let mut layers = vec![];
+    for layer in &manifest.layers {
+        // the layers are uncompressed, so their digests match the diff IDs in the
+        // config
+        let (digest, size) = add_blob(layer, &mut layout).stack()?;
+        layers.push(serde_json::json!({
+            "mediaType": "application/vnd.oci.image.layer.v1.tar",
+            "digest": digest,
+            "size": size,
+        }));
+    }
+    let image_manifest = serde_json::to_vec(&serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "digest": config_digest,
+            "size": config_size,
+        },
+        "layers": layers,
+    }))
+    .stack()?;
+    let manifest_digest = sha256_digest(&image_manifest);
+    let manifest_size = image_manifest.len();
+    layout.push((manifest_digest.replace(':', "/"), image_manifest));
+
+    let mut descriptor = serde_json::json!({
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "digest": manifest_digest,
+        "size": manifest_size,
+    });
+    if let Some(repo_tag) = manifest.repo_tags.and_then(|tags| tags.into_iter().next()) {
+        descriptor["annotations"] = serde_json::json!({
+            "io.containerd.image.name": repo_tag,
+            "org.opencontainers.image.ref.name": repo_tag
+                .rsplit_once(':')
+                .map_or(repo_tag.as_str(), |(_, tag)| tag),
+        });
+    }
+    let index = serde_json::to_vec(&serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.index.v1+json",
+        "manifests": [descriptor],
+    }))
+    .stack()?;
+
+    // blobs are stored under "blobs/<algorithm>/<hex>"
+    let mut res: Vec<_> = layout
+        .into_iter()
+        .map(|(path, content)| (format!("blobs/{path}"), content))
+        .collect();
+    res.push((INDEX_JSON.to_owned(), index));
+    Ok(res)
+}
+
+impl SuperImage {
+    /// Exports the image into an [OCI image
+    /// layout](https://github.com/opencontainers/image-spec/blob/main/image-layout.md)
+    /// at `dest_dir`, which is created if it does not exist. This is the
+    /// format that tools such as `skopeo` and `crane` consume.
+    ///
+    /// Newer daemons already export OCI layouts, which are used directly.
+    /// Otherwise, the docker-archive export is converted. The whole image is
+    /// kept in memory while exporting.
+    #[tracing::instrument(skip_all, fields(
+        image.id = %self.get_image_id(),
+    ))]
+    pub async fn export_oci_layout(&self, dest_dir: impl AsRef<Path>) -> Result<()> {
+        let dest_dir = dest_dir.as_ref().to_owned();
+        let image_id = self.get_image_id().to_owned();
+
+        let docker = get_or_init_default_docker_instance().await.stack()?;
+        let mut stream = std::pin::pin!(docker.export_image(&image_id));
+        let mut tarball = vec![];
+        while let Some(bytes) = stream.next().await {
+            tarball.extend_from_slice(&bytes.stack_err_with_locationless(|| {
+                format!("SuperImage::export_oci_layout when exporting image {image_id}")
+            })?);
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let files = read_tarball_files(&tarball).stack()?;
+            drop(tarball);
+            let layout = if files.contains_key(OCI_LAYOUT) && files.contains_key(INDEX_JSON) {
+                files
+                    .into_iter()
+                    .filter(|(path, _)| (path == INDEX_JSON) || path.starts_with("blobs/"))
+                    .collect()
+            } else {
+                docker_archive_to_oci_layout(files).stack()?
+            };
+
+            for (path, content) in layout {
+                let path = dest_dir.join(path);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)
+                        .stack_err_with_locationless(|| format!("when creating {parent:?}"))?;
+                }
+                std::fs::write(&path, content)
+                    .stack_err_with_locationless(|| format!("when writing {path:?}"))?;
+            }
+            std::fs::write(
+                dest_dir.join(OCI_LAYOUT),
+                br#"{"imageLayoutVersion":"1.0.0"}"#,
+            )
+            .stack()?;
+            Ok(()) as Result<_>
+        })
+        .await
+        .stack()?
+        .stack_err_with_locationless(|| {
+            format!("SuperImage::export_oci_layout when writing the layout of image {image_id}")
+        })
+    }
+}
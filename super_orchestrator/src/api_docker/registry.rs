@@ -0,0 +1,309 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use base64::Engine;
+use bollard::{
+    auth::DockerCredentials,
+    image::{CreateImageOptions, PushImageOptions},
+};
+use futures::StreamExt;
+use serde::Deserialize;
+use stacked_errors::{bail_locationless, Result, StackableErr};
+
+use crate::api_docker::{docker_socket::docker_or_default, SuperImage};
+
+/// The key that Docker Hub credentials are stored under in `config.json`
+const DOCKER_HUB_KEY: &str = "https://index.docker.io/v1/";
+
+/// Credentials for a registry
+#[derive(Clone, Default)]
+pub struct RegistryAuth {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// A token used instead of the username and password
+    pub identity_token: Option<String>,
+    pub server_address: Option<String>,
+}
+
+impl std::fmt::Debug for RegistryAuth {
+    /// The secrets are not printed
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RegistryAuth")
+            .field("username", &self.username)
+            .field("server_address", &self.server_address)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The relevant parts of a docker `config.json`
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, DockerConfigAuth>,
+    creds_store: Option<String>,
+    #[serde(default)]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct DockerConfigAuth {
+    auth: Option<String>,
+    identitytoken: Option<String>,
+}
+
+/// Returns the directory of the docker `config.json`
+fn docker_config_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("DOCKER_CONFIG") {
+        return Some(PathBuf::from(dir))
+    }
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(".docker"))
+}
+
+/// Returns if `key` from the `auths` of a `config.json` refers to
+/// `registry_host`, keys can have a scheme and path
+fn auths_key_matches(key: &str, registry_host: &str) -> bool {
+    let key = key
+        .strip_prefix("https://")
+        .or_else(|| key.strip_prefix("http://"))
+        .unwrap_or(key);
+    key.split('/').next() == Some(registry_host)
+}
+
+/// Returns the registry host of an image reference like
+/// "ghcr.io/org/image:tag", or the Docker Hub key for references like
+/// "alpine:3.21"
+fn registry_host_of(reference: &str) -> &str {
+    match reference.split_once('/') {
+        Some((first, _)) if first.contains(['.', ':']) || (first == "localhost") => first,
+        _ => DOCKER_HUB_KEY,
+    }
+}
+
+impl RegistryAuth {
+    /// Reads the credentials for `registry_host` (e.g. "ghcr.io" or
+    /// "localhost:5000") that `docker login` stored in
+    /// `$DOCKER_CONFIG/config.json` or `~/.docker/config.json`. Docker Hub
+    /// can be given as "docker.io" or "https://index.docker.io/v1/".
+    ///
+    /// Returns `Ok(None)` if there is no config or no credentials for the
+    /// registry. Credential helpers and stores (`credHelpers` and
+    /// `credsStore`) cannot be executed, so an error is returned if the
+    /// credentials for the registry are kept in one.
+    pub fn from_docker_config(registry_host: &str) -> Result<Option<RegistryAuth>> {
+        let Some(dir) = docker_config_dir() else {
+            return Ok(None)
+        };
+        let path = dir.join("config.json");
+        let config = match std::fs::read(&path) {
+            Ok(config) => config,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).stack_err_with_locationless(|| {
+                    format!("RegistryAuth::from_docker_config when reading {path:?}")
+                })
+            }
+        };
+        let config: DockerConfig =
+            serde_json::from_slice(&config).stack_err_with_locationless(|| {
+                format!("RegistryAuth::from_docker_config when parsing {path:?}")
+            })?;
+        Self::from_config(config, registry_host).stack_err_with_locationless(|| {
+            format!("RegistryAuth::from_docker_config for registry {registry_host} from {path:?}")
+        })
+    }
+
+    fn from_config(mut config: DockerConfig, registry_host: &str) -> Result<Option<RegistryAuth>> {
+        let is_docker_hub = matches!(
+            registry_host,
+            "docker.io" | "index.docker.io" | "registry-1.docker.io" | DOCKER_HUB_KEY
+        );
+        let key = config.auths.keys().find(|key| {
+            if is_docker_hub {
+                (key.as_str() == DOCKER_HUB_KEY) || auths_key_matches(key, "index.docker.io")
+            } else {
+                auths_key_matches(key, registry_host)
+            }
+        });
+        let auth = key.cloned().and_then(|key| config.auths.remove(&key));
+
+        let helper = config
+            .cred_helpers
+            .iter()
+            .find(|(key, _)| {
+                auths_key_matches(key, registry_host) || (is_docker_hub && (*key == DOCKER_HUB_KEY))
+            })
+            .map(|(_, helper)| helper.clone())
+            // the store is used for all registries that have an entry
+            .or_else(|| config.creds_store.filter(|_| auth.is_some()));
+        let Some(auth) = auth else {
+            if let Some(helper) = helper {
+                bail_locationless!(
+                    "the credentials are kept by `docker-credential-{helper}`, which cannot be \
+                     executed, pass a `RegistryAuth` explicitly"
+                )
+            }
+            return Ok(None)
+        };
+
+        if let Some(identity_token) = auth.identitytoken {
+            return Ok(Some(RegistryAuth {
+                identity_token: Some(identity_token),
+                server_address: Some(registry_host.to_owned()),
+                ..Default::default()
+            }))
+        }
+        let Some(encoded) = auth.auth.filter(|encoded| !encoded.is_empty()) else {
+            if let Some(helper) = helper {
+                bail_locationless!(
+                    "the credentials are kept by `docker-credential-{helper}`, which cannot be \
+                     executed, pass a `RegistryAuth` explicitly"
+                )
+            }
+            return Ok(None)
+        };
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .stack_err_locationless("the `auth` field is not valid base64")?;
+        let decoded =
+            String::from_utf8(decoded).stack_err_locationless("the `auth` field is not UTF-8")?;
+        let (username, password) = decoded
+            .split_once(':')
+            .stack_err_locationless("the `auth` field is not of the form \"username:password\"")?;
+        Ok(Some(RegistryAuth {
+            username: Some(username.to_owned()),
+            password: Some(password.to_owned()),
+            server_address: Some(registry_host.to_owned()),
+            ..Default::default()
+        }))
+    }
+}
+
+impl From<RegistryAuth> for DockerCredentials {
+    fn from(auth: RegistryAuth) -> Self {
+        DockerCredentials {
+            username: auth.username,
+            password: auth.password,
+            identitytoken: auth.identity_token,
+            serveraddress: auth.server_address,
+            ..Default::default()
+        }
+    }
+}
+
+/// Options for [pull_image] and [push_image]
+#[derive(Debug, Clone, Default)]
+pub struct RegistryOptions {
+    /// If not set, the credentials are read with
+    /// [RegistryAuth::from_docker_config]
+    pub auth: Option<RegistryAuth>,
+    /// Do not read credentials from the docker config if `auth` is not set
+    pub skip_docker_config: bool,
+    /// If not set, the default docker instance is used
+    pub docker_instance: Option<bollard::Docker>,
+}
+
+impl RegistryOptions {
+    /// Gets the credentials to use for `reference`
+    fn credentials(self, reference: &str) -> Result<Option<DockerCredentials>> {
+        if let Some(auth) = self.auth {
+            return Ok(Some(auth.into()))
+        }
+        if self.skip_docker_config {
+            return Ok(None)
+        }
+        Ok(
+            RegistryAuth::from_docker_config(registry_host_of(reference))
+                .stack()?
+                .map(Into::into),
+        )
+    }
+}
+
+/// Splits an image reference into the name and the tag or digest that the
+/// docker API takes, e.g. "localhost:5000/image:tag" into
+/// "localhost:5000/image" and "tag", or "image@sha256:<hex>" into "image" and
+/// "sha256:<hex>". The second part is empty if there is neither a tag nor a
+/// digest. If there are both, the digest is returned like `docker pull` uses
+/// it.
+pub fn split_reference(reference: &str) -> (&str, &str) {
+    // the digest contains a colon, so it has to be split off first
+    if let Some((name, digest)) = reference.split_once('@') {
+        return (split_reference(name).0, digest)
+    }
+    match reference.rsplit_once(':') {
+        // a colon before the last slash is part of a registry host
+        Some((name, tag)) if !tag.contains('/') => (name, tag),
+        _ => (reference, ""),
+    }
+}
+
+/// The API equivalent of `docker pull`, pulls the image at `reference` (e.g.
+/// "alpine:3.21" or "ghcr.io/org/image:tag")
+#[tracing::instrument(skip_all, fields(
+    image.reference = %reference,
+))]
+pub async fn pull_image(reference: &str, opts: RegistryOptions) -> Result<SuperImage> {
+    let docker = docker_or_default(opts.docker_instance.as_ref())
+        .await
+        .stack()?;
+    let credentials = opts
+        .credentials(reference)
+        .stack_err_with_locationless(|| format!("pull_image for {reference}"))?;
+    let (name, tag) = split_reference(reference);
+    let mut stream = std::pin::pin!(docker.create_image(
+        Some(CreateImageOptions {
+            from_image: name,
+            // an empty tag would pull all tags
+            tag: if tag.is_empty() { "latest" } else { tag },
+            ..Default::default()
+        }),
+        None,
+        credentials,
+    ));
+    while let Some(info) = stream.next().await {
+        // because the display impl only shows the error enum
+        info.map_err(|e| format!("{e:?}"))
+            .stack_err_with_locationless(|| format!("pull_image when pulling {reference}"))?;
+    }
+    let id = docker
+        .inspect_image(reference)
+        .await
+        .stack_err_with_locationless(|| format!("pull_image when inspecting {reference}"))?
+        .id
+        .stack_err_with_locationless(|| format!("pull_image -> image {reference} has no ID"))?;
+    Ok(SuperImage::new(id).with_reference(reference))
+}
+
+/// The API equivalent of `docker push`, pushes the local image tagged as
+/// `reference`. Returns an error for digest references, which cannot be
+/// pushed.
+#[tracing::instrument(skip_all, fields(
+    image.reference = %reference,
+))]
+pub async fn push_image(reference: &str, opts: RegistryOptions) -> Result<()> {
+    if reference.contains('@') {
+        bail_locationless!(
+            "push_image -> {reference} is a digest reference, only tags can be pushed"
+        );
+    }
+    let docker = docker_or_default(opts.docker_instance.as_ref())
+        .await
+        .stack()?;
+    let credentials = opts
+        .credentials(reference)
+        .stack_err_with_locationless(|| format!("push_image for {reference}"))?;
+    let (name, tag) = split_reference(reference);
+    let mut stream = std::pin::pin!(docker.push_image(
+        name,
+        Some(PushImageOptions {
+            tag: if tag.is_empty() { "latest" } else { tag },
+        }),
+        credentials,
+    ));
+    while let Some(info) = stream.next().await {
+        info.map_err(|e| format!("{e:?}"))
+            .stack_err_with_locationless(|| format!("push_image when pushing {reference}"))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,223 @@
+use std::{borrow::Cow, str::Utf8Error, time::Duration};
+
+use bollard::container::LogOutput;
+use futures::StreamExt;
+use stacked_errors::{bail_locationless, Result, StackableErr};
+
+use crate::{
+    api_docker::{
+        docker_socket::docker_or_default, port_bindings_to_bollard_args, wait_container_using,
+        PortBind, RetryPolicy, SuperImage,
+    },
+    random_name,
+};
+
+/// Options for [SuperImage::run_once], the API equivalent of `docker run --rm`
+#[derive(Debug, Clone, Default)]
+pub struct SuperRunOptions {
+    /// If not set, a random name is used
+    pub name: Option<String>,
+    /// If empty, the default command of the image is used
+    pub cmd: Vec<String>,
+    pub user: Option<String>,
+    pub env_vars: Vec<String>,
+    /// Items are `(host_path, container_path)`
+    pub volumes: Vec<(String, String)>,
+    pub port_bindings: Vec<PortBind>,
+    /// If set, the container is killed and a timeout error is returned if it
+    /// has not exited in time
+    pub timeout: Option<Duration>,
+    /// How creating and starting the container is retried on transient
+    /// connection errors
+    pub retry_policy: RetryPolicy,
+    /// If not set, the default docker instance is used
+    pub docker_instance: Option<bollard::Docker>,
+}
+
+/// The result of [SuperImage::run_once]
+#[must_use]
+#[derive(Debug, Clone, Default)]
+pub struct CompletedContainer {
+    /// The name the container had
+    pub name: String,
+    pub exit_code: i64,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl CompletedContainer {
+    /// Returns if the container exited with a zero exit code
+    pub fn successful(&self) -> bool {
+        self.exit_code == 0
+    }
+
+    /// Returns a formatted error with relevant information if the container
+    /// was not successful
+    pub fn assert_success(&self) -> Result<()> {
+        if self.successful() {
+            Ok(())
+        } else {
+            bail_locationless!(
+                "CompletedContainer {{ name: {}, exit_code: {}, stdout: {}, stderr: {} \
+                 }}.assert_success() -> unsuccessful",
+                self.name,
+                self.exit_code,
+                self.stdout_as_utf8_lossy(),
+                self.stderr_as_utf8_lossy()
+            )
+        }
+    }
+
+    /// Returns `str::from_utf8(&self.stdout)`
+    pub fn stdout_as_utf8(&self) -> std::result::Result<&str, Utf8Error> {
+        std::str::from_utf8(&self.stdout)
+    }
+
+    /// Returns `str::from_utf8(&self.stderr)`
+    pub fn stderr_as_utf8(&self) -> std::result::Result<&str, Utf8Error> {
+        std::str::from_utf8(&self.stderr)
+    }
+
+    /// Returns `String::from_utf8_lossy(&self.stdout)`
+    pub fn stdout_as_utf8_lossy(&self) -> Cow<str> {
+        String::from_utf8_lossy(&self.stdout)
+    }
+
+    /// Returns `String::from_utf8_lossy(&self.stderr)`
+    pub fn stderr_as_utf8_lossy(&self) -> Cow<str> {
+        String::from_utf8_lossy(&self.stderr)
+    }
+}
+
+impl SuperImage {
+    /// Creates a container from this image, starts it, waits for it to exit,
+    /// and returns its exit code and output. The container is removed
+    /// afterwards, even if there was an error. Note that a nonzero exit code
+    /// is not an error, use [CompletedContainer::assert_success].
+    #[tracing::instrument(skip_all, fields(
+        image.id = %self.get_image_id(),
+    ))]
+    pub async fn run_once(&self, opts: SuperRunOptions) -> Result<CompletedContainer> {
+        let docker = docker_or_default(opts.docker_instance.as_ref())
+            .await
+            .stack()?;
+        let name = opts.name.clone().unwrap_or_else(|| random_name("run_once"));
+
+        let (exposed_ports, port_bindings) =
+            port_bindings_to_bollard_args(&opts.port_bindings).stack()?;
+        let create_opts = bollard::container::CreateContainerOptions {
+            name: name.clone(),
+            ..Default::default()
+        };
+        let config = bollard::container::Config {
+            image: Some(self.get_image_id().to_string()),
+            cmd: (!opts.cmd.is_empty()).then(|| opts.cmd.clone()),
+            user: opts.user.clone(),
+            env: Some(opts.env_vars.clone()),
+            exposed_ports,
+            volumes: Some(
+                opts.volumes
+                    .iter()
+                    .map(|(_, container)| (container.to_string(), Default::default()))
+                    .collect(),
+            ),
+            host_config: Some(bollard::secret::HostConfig {
+                port_bindings,
+                binds: Some(
+                    opts.volumes
+                        .iter()
+                        .map(|(host, container)| format!("{host}:{container}"))
+                        .collect(),
+                ),
+                ..Default::default()
+            }),
+            // no tty so that stdout and stderr are separate
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            tty: Some(false),
+            ..Default::default()
+        };
+
+        opts.retry_policy
+            .retry("SuperImage::run_once when creating the container", || {
+                docker.create_container(Some(create_opts.clone()), config.clone())
+            })
+            .await
+            .stack()?;
+
+        let res = run_created_container(&docker, &name, &opts).await;
+
+        // the container is not auto removed so that the logs can be collected
+        let remove = docker
+            .remove_container(
+                &name,
+                Some(bollard::container::RemoveContainerOptions {
+                    force: true,
+                    v: true,
+                    ..Default::default()
+                }),
+            )
+            .await
+            .stack_err_with(|| format!("SuperImage::run_once when removing container {name}"));
+
+        match (res, remove) {
+            (Ok(completed), Ok(())) => Ok(completed),
+            (Ok(_), Err(e)) => Err(e),
+            (Err(e), Ok(())) => Err(e),
+            (Err(e0), Err(e1)) => Err(e0.chain_errors(e1)),
+        }
+    }
+}
+
+/// Starts the already created container and collects its results
+async fn run_created_container(
+    docker: &bollard::Docker,
+    name: &str,
+    opts: &SuperRunOptions,
+) -> Result<CompletedContainer> {
+    opts.retry_policy
+        .retry("SuperImage::run_once when starting the container", || {
+            docker.start_container::<String>(name, None)
+        })
+        .await
+        .stack()?;
+
+    let exit_code =
+        match wait_container_using(docker, name, opts.timeout.unwrap_or(Duration::MAX)).await {
+            Ok(exit) => exit.code,
+            Err(e) => {
+                if e.is_timeout() {
+                    let _ = docker.kill_container::<String>(name, None).await;
+                }
+                return Err(e).stack_err_locationless("SuperImage::run_once");
+            }
+        };
+
+    let mut completed = CompletedContainer {
+        name: name.to_owned(),
+        exit_code,
+        ..Default::default()
+    };
+    let mut logs = std::pin::pin!(docker.logs(
+        name,
+        Some(bollard::container::LogsOptions {
+            stdout: true,
+            stderr: true,
+            tail: "all".to_string(),
+            ..Default::default()
+        })
+    ));
+    while let Some(output) = logs.next().await {
+        match output.stack_err_with(|| {
+            format!("SuperImage::run_once when getting the logs of container {name}")
+        })? {
+            LogOutput::StdErr { message } => completed.stderr.extend_from_slice(&message),
+            LogOutput::StdOut { message } | LogOutput::Console { message } => {
+                completed.stdout.extend_from_slice(&message)
+            }
+            LogOutput::StdIn { message: _ } => (),
+        }
+    }
+
+    Ok(completed)
+}
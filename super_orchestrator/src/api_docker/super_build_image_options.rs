@@ -2,6 +2,7 @@ use std::collections::HashMap;
 
 // reexport from bollard
 pub use bollard::image::BuilderVersion;
+use stacked_errors::{bail_locationless, Result, StackableErr};
 
 /// Extended image build options.
 ///
@@ -72,4 +73,189 @@ impl ImageBuildOptions {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Returns a builder that validates the options when finished
+    pub fn builder() -> ImageBuildOptionsBuilder {
+        ImageBuildOptionsBuilder::default()
+    }
+
+    /// Checks for conflicting options that the daemon would otherwise reject
+    /// with cryptic messages at build time
+    pub fn validate(&self) -> Result<()> {
+        if let Some(memswap) = self.memswap {
+            if memswap != -1 {
+                let Some(memory) = self.memory else {
+                    bail_locationless!("`memswap` is set to {memswap} but `memory` is not set")
+                };
+                if u64::try_from(memswap).map_or(true, |memswap| memswap < memory) {
+                    bail_locationless!(
+                        "`memswap` ({memswap}) is smaller than `memory` ({memory}), it is the \
+                         total of memory and swap"
+                    )
+                }
+            }
+        }
+        if self.cpuquota.is_some() && self.cpuperiod.is_none() {
+            bail_locationless!("`cpuquota` is set but `cpuperiod` is not")
+        }
+        if self.squash && (self.version == BuilderVersion::BuilderBuildKit) {
+            bail_locationless!("`squash` is not supported with the BuildKit `version`")
+        }
+        if self.shmsize == Some(0) {
+            bail_locationless!("`shmsize` must be greater than 0")
+        }
+        Ok(())
+    }
+}
+
+/// Builder for [ImageBuildOptions], see [ImageBuildOptions::builder]
+#[derive(Debug, Clone, Default)]
+pub struct ImageBuildOptionsBuilder {
+    opts: ImageBuildOptions,
+}
+
+impl ImageBuildOptionsBuilder {
+    /// Finishes the options, returning an error naming the conflicting fields
+    /// if [ImageBuildOptions::validate] fails
+    pub fn build(self) -> Result<ImageBuildOptions> {
+        self.opts
+            .validate()
+            .stack_err("ImageBuildOptionsBuilder::build")?;
+        Ok(self.opts)
+    }
+
+    /// Sets `t`, the `name:tag` to apply to the image
+    pub fn t(mut self, t: impl ToString) -> Self {
+        self.opts.t = t.to_string();
+        self
+    }
+
+    /// Sets `extrahosts`
+    pub fn extrahosts(mut self, extrahosts: impl ToString) -> Self {
+        self.opts.extrahosts = Some(extrahosts.to_string());
+        self
+    }
+
+    /// Sets `q`
+    pub fn q(mut self, q: bool) -> Self {
+        self.opts.q = q;
+        self
+    }
+
+    /// Sets `nocache`
+    pub fn nocache(mut self, nocache: bool) -> Self {
+        self.opts.nocache = nocache;
+        self
+    }
+
+    /// Sets `pull`
+    pub fn pull(mut self, pull: bool) -> Self {
+        self.opts.pull = pull;
+        self
+    }
+
+    /// Sets `rm`
+    pub fn rm(mut self, rm: bool) -> Self {
+        self.opts.rm = rm;
+        self
+    }
+
+    /// Sets `forcerm`
+    pub fn forcerm(mut self, forcerm: bool) -> Self {
+        self.opts.forcerm = forcerm;
+        self
+    }
+
+    /// Sets `squash`
+    pub fn squash(mut self, squash: bool) -> Self {
+        self.opts.squash = squash;
+        self
+    }
+
+    /// Adds an image to `cachefrom`
+    pub fn cachefrom(mut self, image: impl ToString) -> Self {
+        self.opts.cachefrom.push(image.to_string());
+        self
+    }
+
+    /// Sets the `memory` limit in bytes
+    pub fn memory(mut self, memory: u64) -> Self {
+        self.opts.memory = Some(memory);
+        self
+    }
+
+    /// Sets `memswap`, the total memory limit (memory + swap) in bytes or -1 to
+    /// disable swap
+    pub fn memswap(mut self, memswap: i64) -> Self {
+        self.opts.memswap = Some(memswap);
+        self
+    }
+
+    /// Sets `cpushares`
+    pub fn cpushares(mut self, cpushares: u64) -> Self {
+        self.opts.cpushares = Some(cpushares);
+        self
+    }
+
+    /// Sets `cpusetcpus`
+    pub fn cpusetcpus(mut self, cpusetcpus: impl ToString) -> Self {
+        self.opts.cpusetcpus = cpusetcpus.to_string();
+        self
+    }
+
+    /// Sets `cpuperiod` in microseconds
+    pub fn cpuperiod(mut self, cpuperiod: u64) -> Self {
+        self.opts.cpuperiod = Some(cpuperiod);
+        self
+    }
+
+    /// Sets `cpuquota` in microseconds, this requires `cpuperiod`
+    pub fn cpuquota(mut self, cpuquota: u64) -> Self {
+        self.opts.cpuquota = Some(cpuquota);
+        self
+    }
+
+    /// Adds a build-time variable to `buildargs`
+    pub fn buildarg(mut self, key: impl ToString, value: impl ToString) -> Self {
+        self.opts
+            .buildargs
+            .insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Sets `shmsize` in bytes
+    pub fn shmsize(mut self, shmsize: u64) -> Self {
+        self.opts.shmsize = Some(shmsize);
+        self
+    }
+
+    /// Adds a label to `labels`
+    pub fn label(mut self, key: impl ToString, value: impl ToString) -> Self {
+        self.opts.labels.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Sets `networkmode`
+    pub fn networkmode(mut self, networkmode: impl ToString) -> Self {
+        self.opts.networkmode = networkmode.to_string();
+        self
+    }
+
+    /// Sets `platform`
+    pub fn platform(mut self, platform: impl ToString) -> Self {
+        self.opts.platform = platform.to_string();
+        self
+    }
+
+    /// Sets the `target` build stage
+    pub fn target(mut self, target: impl ToString) -> Self {
+        self.opts.target = target.to_string();
+        self
+    }
+
+    /// Sets the builder `version`
+    pub fn version(mut self, version: BuilderVersion) -> Self {
+        self.opts.version = version;
+        self
+    }
 }
@@ -1,20 +1,36 @@
 use std::{
-    io::{Seek, Write},
-    path::PathBuf,
+    collections::VecDeque,
+    path::{Component, Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 
-use futures::{future::try_join_all, TryStreamExt};
-use stacked_errors::{Result, StackableErr};
+use futures::{future::try_join_all, stream, StreamExt};
+use stacked_errors::{Error, Result, StackableErr};
+use tracing::Instrument;
 
 use crate::{
     api_docker::{
-        docker_socket, resolve_from_to, BootstrapOptions, ImageBuildOptions, SuperImage, Tarball,
+        build_context::BuildContext, docker_socket, pull_image, resolve_from_to, BootstrapOptions,
+        BuildProfile, BuildSecret, BuilderVersion, ImageBuildOptions, OverwritePolicy,
+        PortBindProtocol, RegistryOptions, RetryPolicy, SuperImage, TarEntryOptions, Tarball,
     },
     cli_docker::Dockerfile,
-    sh,
+    sh, CtrlCTask,
 };
 
+/// The result of [SuperDockerfile::build_image_with_output]
+#[derive(Debug, Clone)]
+pub struct BuildOutput {
+    pub image: SuperImage,
+    /// The messages of the build stream in the order they were received. This
+    /// is empty for builds with secrets, because `bollard` does not expose the
+    /// progress of BuildKit sessions.
+    pub log: String,
+    /// The build context tarball, if it was requested
+    pub context_bytes: Option<Vec<u8>>,
+}
+
 /// Describes all the details needed to create and run a reproducible container
 /// via the Docker API.
 ///
@@ -39,27 +55,67 @@ pub struct SuperDockerfile {
     /// The base definition for the dockerfile
     base: Dockerfile,
     content_extend: Vec<u8>,
+    /// `ARG` lines inserted before the first `FROM`
+    global_args: Vec<u8>,
     tarball: Tarball,
     build_path: Option<PathBuf>,
     image_name: Option<String>,
+    /// The remote context URL and dockerfile path in it set by
+    /// [SuperDockerfile::from_remote_context]
+    remote: Option<(String, String)>,
+    tar_entry_options: TarEntryOptions,
+    secrets: Vec<(String, BuildSecret)>,
+    build_timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
+    platforms: Vec<String>,
+    reproducible: bool,
+    compress_context: bool,
+    force_rebuild: bool,
+    docker_instance: Option<bollard::Docker>,
+    /// The engine info of `docker_instance`, queried once for all builds
+    engine_info: Option<docker_socket::EngineInfo>,
 
     build_opts: ImageBuildOptions,
     debug: bool,
 }
 
-/// Creates a dockerfile using the [SuperDockerfile], returnig a [std::fs::File]
-/// handle to it
-async fn create_dockerfile_returning_file_handle(sdf: &SuperDockerfile) -> Result<std::fs::File> {
-    let mut temp_file_name = std::env::temp_dir();
-    temp_file_name.push(uuid::Uuid::new_v4().to_string());
+/// The label that images built from a [SuperDockerfile] with an `image_name`
+/// get, with the name as the value
+pub const IMAGE_NAME_LABEL: &str = "super_orchestrator.image_name";
 
+/// Returns the full contents of the dockerfile defined by the [SuperDockerfile]
+fn dockerfile_contents(sdf: &SuperDockerfile) -> Result<Vec<u8>> {
     let file_contents = match &sdf.base {
         Dockerfile::NameTag(nt) => Ok(format!("FROM {nt}").into_bytes()),
         Dockerfile::Path(path) => std::fs::read(path).stack(),
         Dockerfile::Contents(content) => Ok(content.clone().into_bytes()),
     }
     .map(|mut df| {
+        if !sdf.global_args.is_empty() {
+            // after any parser directives and comments
+            let mut i = 0;
+            for line in df.split_inclusive(|b| *b == b'\n') {
+                if line.trim_ascii_start().len() >= 4
+                    && line.trim_ascii_start()[..4].eq_ignore_ascii_case(b"FROM")
+                {
+                    break
+                }
+                i += line.len();
+            }
+            let i = if i == df.len() { 0 } else { i };
+            df.splice(i..i, sdf.global_args.iter().copied());
+        }
         df.extend_from_slice(&sdf.content_extend);
+        if sdf.reproducible {
+            // a dockerfile checked out with CRLF line endings should not differ
+            let mut normalized = Vec::with_capacity(df.len());
+            for (i, b) in df.iter().enumerate() {
+                if !((*b == b'\r') && (df.get(i + 1) == Some(&b'\n'))) {
+                    normalized.push(*b);
+                }
+            }
+            df = normalized;
+        }
         df
     })
     .stack()?;
@@ -71,23 +127,141 @@ async fn create_dockerfile_returning_file_handle(sdf: &SuperDockerfile) -> Resul
         );
     }
 
-    tokio::task::spawn_blocking(move || {
-        let mut temp_file = std::fs::File::options()
-            .truncate(true)
-            .create(true)
-            .write(true)
-            .read(true)
-            .open(&temp_file_name)
-            .stack()?;
+    Ok(file_contents)
+}
 
-        temp_file.write_all(&file_contents).stack()?;
+/// Build args that docker predefines without an `ARG` instruction
+const PREDEFINED_ARGS: &[&str] = &[
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "FTP_PROXY",
+    "NO_PROXY",
+    "ALL_PROXY",
+];
+
+/// Returns the names of all args declared by `ARG` instructions
+fn declared_args(dockerfile: &[u8]) -> Vec<String> {
+    let mut args = vec![];
+    for line in String::from_utf8_lossy(dockerfile).lines() {
+        let line = line.trim_start();
+        let Some(instruction) = line.get(..4) else {
+            continue
+        };
+        if !instruction.eq_ignore_ascii_case("ARG ") {
+            continue
+        }
+        // `ARG a=1 b` declares multiple args
+        for word in line[4..].split_whitespace() {
+            let name = word.split_once('=').map_or(word, |(name, _)| name);
+            args.push(name.to_owned());
+        }
+    }
+    args
+}
 
-        temp_file.seek(std::io::SeekFrom::Start(0)).stack()?;
+/// Warns about build args that no `ARG` in the dockerfile declares, because
+/// docker silently ignores them
+fn warn_undeclared_build_args(
+    buildargs: &std::collections::HashMap<String, String>,
+    dockerfile: &[u8],
+) {
+    let declared = declared_args(dockerfile);
+    for key in buildargs.keys() {
+        if !(declared.contains(key) || PREDEFINED_ARGS.contains(&key.to_uppercase().as_str())) {
+            tracing::warn!(
+                "the build arg \"{key}\" is not declared by an `ARG` instruction in the \
+                 dockerfile, docker will ignore it"
+            );
+        }
+    }
+}
+
+/// Options for [SuperDockerfile::with_healthcheck], unset options use the
+/// docker defaults
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HealthcheckOptions {
+    /// The time between checks
+    pub interval: Option<Duration>,
+    /// The time after which a single check is considered failed
+    pub timeout: Option<Duration>,
+    /// The time during which failures are not counted
+    pub start_period: Option<Duration>,
+    /// The number of consecutive failures for the container to be unhealthy
+    pub retries: Option<u32>,
+}
+
+/// Quotes `s` as a single dockerfile word, escaping quotes, backslashes, and
+/// `$` so that variables are not expanded. Dockerfiles cannot express
+/// newlines in words, so those result in an error.
+fn quote_dockerfile_word(s: &str) -> Result<String> {
+    if s.contains(['\n', '\r']) {
+        return Err(format!(
+            "{s:?} contains a newline, which dockerfile instructions cannot express"
+        ))
+        .stack();
+    }
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        if matches!(c, '"' | '\\' | '$') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    Ok(quoted)
+}
+
+/// Returns the path relative to the build context that files going to the
+/// absolute `image_path` are placed at, which mirrors the image path
+fn context_path_of_image_path(image_path: &str) -> Result<String> {
+    let mut components = Path::new(image_path).components();
+    if components.next() != Some(Component::RootDir) {
+        return Err(format!("\"{image_path}\" is not an absolute path")).stack();
+    }
+    let mut res = vec![];
+    for component in components {
+        match component {
+            Component::Normal(component) => res.push(
+                component
+                    .to_str()
+                    .stack_err("failed to convert os_str to str")?,
+            ),
+            Component::CurDir => (),
+            _ => return Err(format!("\"{image_path}\" has a `..` segment")).stack(),
+        }
+    }
+    if res.is_empty() {
+        return Err(format!("\"{image_path}\" is the root directory")).stack();
+    }
+    Ok(res.join("/"))
+}
 
-        Ok(temp_file)
+/// Returns the `ARG name[="default"]` instruction
+fn arg_instruction(name: &str, default: Option<&str>) -> Result<String> {
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace() || matches!(c, '=' | '"')) {
+        return Err(format!("{name:?} is not a valid build arg name")).stack();
+    }
+    Ok(if let Some(default) = default {
+        format!("ARG {name}={}", quote_dockerfile_word(default).stack()?)
+    } else {
+        format!("ARG {name}")
     })
-    .await
-    .stack()?
+}
+
+/// Formats a duration like "5s", "1500ms", or "250us" for dockerfile flags,
+/// using the largest unit that represents it exactly
+fn format_go_duration(duration: Duration) -> String {
+    let nanos = duration.subsec_nanos();
+    if nanos == 0 {
+        format!("{}s", duration.as_secs())
+    } else if nanos.is_multiple_of(1_000_000) {
+        format!("{}ms", duration.as_millis())
+    } else if nanos.is_multiple_of(1_000) {
+        format!("{}us", duration.as_micros())
+    } else {
+        format!("{}ns", duration.as_nanos())
+    }
 }
 
 impl SuperDockerfile {
@@ -98,10 +272,22 @@ impl SuperDockerfile {
         Self {
             base,
             content_extend: Vec::new(),
+            global_args: Vec::new(),
             build_opts: ImageBuildOptions::default(),
             tarball: Default::default(),
             image_name,
             build_path: None,
+            remote: None,
+            tar_entry_options: Default::default(),
+            secrets: Vec::new(),
+            build_timeout: None,
+            retry_policy: Default::default(),
+            platforms: Vec::new(),
+            reproducible: false,
+            compress_context: false,
+            force_rebuild: false,
+            docker_instance: None,
+            engine_info: None,
             debug: false,
         }
     }
@@ -114,13 +300,80 @@ impl SuperDockerfile {
             base,
             image_name,
             content_extend: Vec::new(),
+            global_args: Vec::new(),
             build_opts: ImageBuildOptions::default(),
             tarball,
             build_path: None,
+            remote: None,
+            tar_entry_options: Default::default(),
+            secrets: Vec::new(),
+            build_timeout: None,
+            retry_policy: Default::default(),
+            platforms: Vec::new(),
+            reproducible: false,
+            compress_context: false,
+            force_rebuild: false,
+            docker_instance: None,
+            engine_info: None,
             debug: false,
         }
     }
 
+    /// Builds from a remote context the way `docker build <url>` does, the
+    /// daemon fetches the context so nothing has to be cloned locally. `url`
+    /// can be a git repository such as
+    /// "https://github.com/org/repo.git#branch:subdir" or a URL to a tarball,
+    /// and `dockerfile_path_in_context` is the path of the dockerfile relative
+    /// to the root of the context.
+    ///
+    /// Mixing contexts is not supported, so copying files with
+    /// [SuperDockerfile::copying_from_paths] and similar, appending dockerfile
+    /// instructions, and adding secrets result in errors. Credentials for
+    /// private repositories can be passed with build args.
+    #[tracing::instrument(skip_all)]
+    pub fn from_remote_context(
+        url: impl ToString,
+        dockerfile_path_in_context: impl ToString,
+    ) -> Self {
+        let dockerfile_path_in_context = dockerfile_path_in_context.to_string();
+        let mut this = Self::new(Dockerfile::path(&dockerfile_path_in_context), None);
+        this.remote = Some((url.to_string(), dockerfile_path_in_context));
+        this
+    }
+
+    /// Tags the image with the `image_name` and adds the [IMAGE_NAME_LABEL]
+    fn apply_image_name(&mut self) -> Result<()> {
+        let Some(image_name) = &self.image_name else {
+            return Ok(())
+        };
+        if self.build_opts.t.is_empty() {
+            self.build_opts.t.clone_from(image_name);
+        } else if self.build_opts.t != *image_name {
+            return Err(format!(
+                "the image name {image_name:?} conflicts with the `t` build option {:?}",
+                self.build_opts.t
+            ))
+            .stack();
+        }
+        self.build_opts
+            .labels
+            .insert(IMAGE_NAME_LABEL.to_owned(), image_name.clone());
+        Ok(())
+    }
+
+    /// Returns an error if this uses a remote context
+    fn ensure_local_context(&self, fn_name: &str) -> Result<()> {
+        if let Some((remote, _)) = &self.remote {
+            return Err(format!(
+                "SuperDockerfile::{fn_name} -> this was created with \
+                 `SuperDockerfile::from_remote_context({remote:?}, ..)`, and mixing the remote \
+                 context with local files is not supported"
+            ))
+            .stack();
+        }
+        Ok(())
+    }
+
     /// The build path is the last argument in a docker build command.
     ///
     /// `docker build [OPTS] <build_path>`
@@ -146,6 +399,155 @@ impl SuperDockerfile {
         self
     }
 
+    /// If `true`, the build context tarball is gzip compressed while it is
+    /// sent to the daemon, which detects and decompresses it. This is
+    /// worthwhile for contexts with large bootstrapped binaries.
+    /// [SuperDockerfile::into_bollard_args] and
+    /// [SuperDockerfile::build_image_returning_context] then return the
+    /// compressed tarball.
+    /// This is ignored for remote contexts and BuildKit builds with secrets.
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn compress_context(mut self, compress_context: bool) -> Self {
+        self.compress_context = compress_context;
+        self
+    }
+
+    /// If `true`, [SuperDockerfile::bootstrap_for_target] and
+    /// [SuperDockerfile::bootstrap_musl] always run `cargo build` for the
+    /// target, instead of reusing an existing artifact that is newer than the
+    /// current binary.
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn with_force_rebuild(mut self, force_rebuild: bool) -> Self {
+        self.force_rebuild = force_rebuild;
+        self
+    }
+
+    /// Set the docker handle that the image is built with, instead of
+    /// [get_or_init_default_docker_instance](docker_socket::get_or_init_default_docker_instance).
+    /// This is also used for getting the architecture of the daemon in
+    /// [SuperDockerfile::bootstrap_musl].
+    ///
+    /// The build context is only streamed to the default instance, with a set
+    /// instance it is read into memory for the build, see
+    /// [Tarball::with_spill_threshold].
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn with_docker_instance(mut self, docker_instance: bollard::Docker) -> Self {
+        self.docker_instance = Some(docker_instance);
+        self.engine_info = None;
+        self
+    }
+
+    /// Returns the engine info of the docker handle that builds use, which is
+    /// only queried the first time
+    async fn cached_engine_info(&mut self) -> Result<docker_socket::EngineInfo> {
+        if let Some(engine_info) = &self.engine_info {
+            return Ok(engine_info.clone())
+        }
+        let engine_info = docker_socket::engine_info_or_default(self.docker_instance.as_ref())
+            .await
+            .stack()?;
+        self.engine_info = Some(engine_info.clone());
+        Ok(engine_info)
+    }
+
+    /// Set a timeout for [SuperDockerfile::build_image], this is useful for
+    /// when a `RUN` step could hang. The timeout error includes the last few
+    /// progress lines of the build.
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn with_build_timeout(mut self, build_timeout: Duration) -> Self {
+        self.build_timeout = Some(build_timeout);
+        self
+    }
+
+    /// Set how [SuperDockerfile::build_image] retries transient connection
+    /// errors, see [RetryPolicy]
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Set the platforms to build for in the format `os[/arch[/variant]]`, e.g.
+    /// "linux/amd64" and "linux/arm64". With a single platform this is the
+    /// same as setting [ImageBuildOptions::platform], with multiple platforms
+    /// [SuperDockerfile::build_platform_images] must be used.
+    ///
+    /// Building for a platform different from the daemon's requires the
+    /// daemon to have QEMU and binfmt handlers installed if any `RUN`
+    /// instructions are used.
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn with_platforms(mut self, platforms: impl IntoIterator<Item = impl ToString>) -> Self {
+        self.platforms = platforms.into_iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    /// Set what happens when multiple files are copied to the same path in the
+    /// build tarball, see [OverwritePolicy]
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn with_overwrite_policy(mut self, overwrite_policy: OverwritePolicy) -> Self {
+        self.tarball.set_overwrite_policy(overwrite_policy);
+        self
+    }
+
+    /// If `true`, the build context is made byte-for-byte reproducible: the
+    /// tarball entries are written in sorted path order with zero for the
+    /// owner IDs and modification times and with normalized modes (see
+    /// [Tarball::with_reproducible]), and CRLF line endings in the dockerfile
+    /// are normalized. This overrides the metadata from
+    /// [SuperDockerfile::with_tar_entry_options].
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn reproducible(mut self, reproducible: bool) -> Self {
+        self.reproducible = reproducible;
+        self.tarball.set_reproducible(reproducible);
+        self
+    }
+
+    /// Set the default metadata overrides for files copied from the host with
+    /// [SuperDockerfile::copying_from_paths] (which includes the entrypoint)
+    /// and for the dockerfile itself. Per-item overrides can be given with
+    /// [SuperDockerfile::copying_from_paths_with].
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn with_tar_entry_options(mut self, tar_entry_options: TarEntryOptions) -> Self {
+        self.tar_entry_options = tar_entry_options;
+        self
+    }
+
+    /// Adds a BuildKit secret that `RUN --mount=type=secret,id=<id>`
+    /// instructions can use. Secrets are passed through a BuildKit session and
+    /// are never written to the build tarball, the dockerfile, or the image
+    /// layers.
+    ///
+    /// This requires the `buildkit` feature and a daemon with BuildKit support,
+    /// and the image must be built with [SuperDockerfile::build_image] because
+    /// [bollard::image::BuildImageOptions] cannot carry secrets.
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn with_secret(mut self, id: impl ToString, secret: BuildSecret) -> Self {
+        let id = id.to_string();
+        self.secrets.retain(|(other, _)| *other != id);
+        self.secrets.push((id, secret));
+        self
+    }
+
     // TODO: I think that we should have some automatic builder derivation that has
     // `mut self ... -> Self` and `&mut self ... -> &mut Self` variations, the
     // second one having the same name but with `*_mut`
@@ -176,6 +578,181 @@ impl SuperDockerfile {
         }
     }
 
+    /// Adds an `ENV key="value"` instruction, the value is quoted and escaped
+    /// so that it is set literally. Returns an error if the key is empty or
+    /// contains whitespace or '=', or if the value contains a newline.
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn with_env(mut self, key: impl AsRef<str>, value: impl AsRef<str>) -> Result<Self> {
+        self.with_env_mut(key, value).stack()?;
+        Ok(self)
+    }
+
+    /// The same as [SuperDockerfile::with_env]
+    pub fn with_env_mut(&mut self, key: impl AsRef<str>, value: impl AsRef<str>) -> Result<()> {
+        let key = key.as_ref();
+        if key.is_empty() || key.contains(|c: char| c.is_whitespace() || (c == '=')) {
+            return Err(format!(
+                "SuperDockerfile::with_env -> {key:?} is not a valid environment variable name"
+            ))
+            .stack();
+        }
+        let value = quote_dockerfile_word(value.as_ref()).stack_err("SuperDockerfile::with_env")?;
+        self.append_dockerfile_lines_mut([format!("ENV {key}={value}")]);
+        Ok(())
+    }
+
+    /// Adds an `ARG name[="default"]` instruction to the current build stage,
+    /// see [SuperDockerfile::with_global_arg] for args that `FROM` can use.
+    /// Values are passed with the `buildargs` of the [ImageBuildOptions],
+    /// and build args that no `ARG` instruction declares result in a warning
+    /// because docker silently ignores them.
+    ///
+    /// Returns an error if the name is invalid or the default contains a
+    /// newline.
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn with_arg(mut self, name: impl AsRef<str>, default: Option<&str>) -> Result<Self> {
+        self.with_arg_mut(name, default).stack()?;
+        Ok(self)
+    }
+
+    /// The same as [SuperDockerfile::with_arg]
+    pub fn with_arg_mut(&mut self, name: impl AsRef<str>, default: Option<&str>) -> Result<()> {
+        let line =
+            arg_instruction(name.as_ref(), default).stack_err("SuperDockerfile::with_arg")?;
+        self.append_dockerfile_lines_mut([line]);
+        Ok(())
+    }
+
+    /// The same as [SuperDockerfile::with_arg], except that the `ARG` is
+    /// placed before the first `FROM` so that it can be used in `FROM`
+    /// instructions. Note that global args must be declared again without a
+    /// default inside a stage to be used there.
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn with_global_arg(mut self, name: impl AsRef<str>, default: Option<&str>) -> Result<Self> {
+        self.with_global_arg_mut(name, default).stack()?;
+        Ok(self)
+    }
+
+    /// The same as [SuperDockerfile::with_global_arg]
+    pub fn with_global_arg_mut(
+        &mut self,
+        name: impl AsRef<str>,
+        default: Option<&str>,
+    ) -> Result<()> {
+        let line = arg_instruction(name.as_ref(), default)
+            .stack_err("SuperDockerfile::with_global_arg")?;
+        self.global_args.extend_from_slice(line.as_bytes());
+        self.global_args.push(b'\n');
+        Ok(())
+    }
+
+    /// Adds a `WORKDIR` instruction, the path is quoted and escaped. Returns an
+    /// error if the path contains a newline.
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn with_workdir(mut self, path: impl AsRef<str>) -> Result<Self> {
+        self.with_workdir_mut(path).stack()?;
+        Ok(self)
+    }
+
+    /// The same as [SuperDockerfile::with_workdir]
+    pub fn with_workdir_mut(&mut self, path: impl AsRef<str>) -> Result<()> {
+        let path =
+            quote_dockerfile_word(path.as_ref()).stack_err("SuperDockerfile::with_workdir")?;
+        self.append_dockerfile_lines_mut([format!("WORKDIR {path}")]);
+        Ok(())
+    }
+
+    /// Adds a `USER` instruction, `user` can be of the form "user[:group]" and
+    /// is quoted and escaped. Returns an error if it contains a newline.
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn with_user(mut self, user: impl AsRef<str>) -> Result<Self> {
+        self.with_user_mut(user).stack()?;
+        Ok(self)
+    }
+
+    /// The same as [SuperDockerfile::with_user]
+    pub fn with_user_mut(&mut self, user: impl AsRef<str>) -> Result<()> {
+        let user = quote_dockerfile_word(user.as_ref()).stack_err("SuperDockerfile::with_user")?;
+        self.append_dockerfile_lines_mut([format!("USER {user}")]);
+        Ok(())
+    }
+
+    /// Adds an `EXPOSE <port>/<protocol>` instruction
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn with_expose(mut self, port: u16, protocol: PortBindProtocol) -> Self {
+        self.with_expose_mut(port, protocol);
+        self
+    }
+
+    /// The same as [SuperDockerfile::with_expose]
+    pub fn with_expose_mut(&mut self, port: u16, protocol: PortBindProtocol) {
+        self.append_dockerfile_lines_mut([format!("EXPOSE {port}/{protocol}")]);
+    }
+
+    /// Adds a `LABEL "key"="value"` instruction, both are quoted and escaped.
+    /// Returns an error if either contains a newline.
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn with_label(mut self, key: impl AsRef<str>, value: impl AsRef<str>) -> Result<Self> {
+        self.with_label_mut(key, value).stack()?;
+        Ok(self)
+    }
+
+    /// The same as [SuperDockerfile::with_label]
+    pub fn with_label_mut(&mut self, key: impl AsRef<str>, value: impl AsRef<str>) -> Result<()> {
+        let key = quote_dockerfile_word(key.as_ref()).stack_err("SuperDockerfile::with_label")?;
+        let value =
+            quote_dockerfile_word(value.as_ref()).stack_err("SuperDockerfile::with_label")?;
+        self.append_dockerfile_lines_mut([format!("LABEL {key}={value}")]);
+        Ok(())
+    }
+
+    /// Adds a `HEALTHCHECK` instruction that runs `cmd` in exec form, see
+    /// [wait_healthy_api](crate::api_docker::wait_healthy_api) for waiting on
+    /// it
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn with_healthcheck(
+        self,
+        opts: HealthcheckOptions,
+        cmd: impl IntoIterator<Item = impl ToString>,
+    ) -> Result<Self> {
+        let cmd = cmd.into_iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        if cmd.is_empty() {
+            return Err("SuperDockerfile::with_healthcheck -> `cmd` is empty").stack();
+        }
+        let mut instruction = "HEALTHCHECK".to_owned();
+        for (flag, duration) in [
+            ("interval", opts.interval),
+            ("timeout", opts.timeout),
+            ("start-period", opts.start_period),
+        ] {
+            if let Some(duration) = duration {
+                instruction += &format!(" --{flag}={}", format_go_duration(duration));
+            }
+        }
+        if let Some(retries) = opts.retries {
+            instruction += &format!(" --retries={retries}");
+        }
+        instruction += " CMD ";
+        instruction += &serde_json::to_string(&cmd).stack()?;
+        Ok(self.append_dockerfile_instructions([instruction]))
+    }
+
     /// Adds a `COPY` instruction to the dockerfile, copying a file at a file
     /// path into memory. The argument receives an iterator with items as
     /// `(host_source_path, image_destination_path)`.
@@ -185,10 +762,30 @@ impl SuperDockerfile {
         image.name = ?self.image_name
     ))]
     pub async fn copying_from_paths(
-        mut self,
+        self,
         v: impl IntoIterator<Item = (impl ToString, impl ToString)>,
     ) -> Result<Self> {
+        self.copying_from_paths_with(
+            v.into_iter()
+                .map(|(from, to)| (from, to, TarEntryOptions::default())),
+        )
+        .await
+    }
+
+    /// The same as [SuperDockerfile::copying_from_paths] but with items as
+    /// `(host_source_path, image_destination_path, tar_entry_options)`. The
+    /// [TarEntryOptions] override those set by
+    /// [SuperDockerfile::with_tar_entry_options].
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub async fn copying_from_paths_with(
+        mut self,
+        v: impl IntoIterator<Item = (impl ToString, impl ToString, TarEntryOptions)>,
+    ) -> Result<Self> {
+        self.ensure_local_context("copying_from_paths").stack()?;
         let build_path = self.build_path.clone();
+        let default_options = self.tar_entry_options;
 
         if self.debug {
             tracing::debug!("Current tarball paths: {:?}", self.tarball);
@@ -197,24 +794,35 @@ impl SuperDockerfile {
         let this = Arc::new(std::sync::Mutex::new(self));
         let futs = v
             .into_iter()
-            .map(|(from, to)| {
+            .map(|(from, to, options)| {
                 let this = this.clone();
                 let (from, to) = resolve_from_to(from, to, build_path.clone());
+                let options = options.or(default_options);
+                // in the order given, the tarball entries are appended in whatever order the
+                // tasks finish
+                this.lock()
+                    .unwrap()
+                    .append_dockerfile_lines_mut([format!("COPY {from} {to}")]);
 
                 tokio::task::spawn_blocking(move || {
-                    let file = &mut std::fs::File::open(&from).stack()?;
-
-                    let mut this_ref = this.lock().unwrap();
+                    let file = &mut std::fs::File::open(&from)
+                        .stack_err_with(|| format!("when opening file \"{from}\""))?;
 
-                    this_ref.append_dockerfile_lines_mut([format!("COPY {from} {to}")]);
-                    this_ref.tarball.append_file(from, file).stack()?;
+                    this.lock()
+                        .unwrap()
+                        .tarball
+                        .append_file_with(from, file, &options)
+                        .stack()?;
 
                     Ok(()) as Result<_>
                 })
             })
             .collect::<Vec<_>>();
 
-        try_join_all(futs).await.stack()?;
+        // the inner results also need to be checked
+        for res in try_join_all(futs).await.stack()? {
+            res.stack()?;
+        }
 
         self = Arc::try_unwrap(this).unwrap().into_inner().stack()?;
 
@@ -225,33 +833,102 @@ impl SuperDockerfile {
         Ok(self)
     }
 
+    /// Adds a `COPY` instruction to the dockerfile for each directory,
+    /// recursively copying the directory at a path into memory. The argument
+    /// receives an iterator with items as `(host_source_dir,
+    /// image_destination_dir)`.
+    ///
+    /// Symlinks are handled according to
+    /// [SuperDockerfile::with_follow_symlinks].
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub async fn copying_from_dirs(
+        mut self,
+        v: impl IntoIterator<Item = (impl ToString, impl ToString)>,
+    ) -> Result<Self> {
+        self.ensure_local_context("copying_from_dirs").stack()?;
+        let build_path = self.build_path.clone();
+        let v = v
+            .into_iter()
+            .map(|(from, to)| resolve_from_to(from, to, build_path.clone()))
+            .collect::<Vec<_>>();
+
+        if self.debug {
+            tracing::debug!("Current tarball paths: {:?}", self.tarball);
+        }
+
+        self = tokio::task::spawn_blocking(move || {
+            for (from, to) in v {
+                self.tarball
+                    .append_dir_all(&from, &from)
+                    .stack_err_with(|| format!("when copying directory {from}"))?;
+                self.append_dockerfile_lines_mut([format!("COPY {from} {to}")]);
+            }
+
+            Ok(self) as Result<_>
+        })
+        .await
+        .stack()??;
+
+        if self.debug {
+            tracing::debug!("New tarball paths: {:?}", self.tarball);
+        }
+
+        Ok(self)
+    }
+
+    /// If `true` (the default), symlinks in directories copied with
+    /// [SuperDockerfile::copying_from_dirs] are dereferenced. If `false`, they
+    /// are stored as links.
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub fn with_follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.tarball.set_follow_symlinks(follow_symlinks);
+        self
+    }
+
     /// Adds a `COPY` instruction to the dockerfile, copying the contents of the
     /// arguments to memory. The items are of the form `(destination_path, mode,
     /// content)`
     ///
     /// Where mode is the unix access modes octaves 0oXXX, defaults to 777
+    ///
+    /// The destination paths must be absolute and cannot have `..` segments.
+    /// Directory entries are added to the tarball for all of their parents.
     pub async fn copying_from_contents(
         mut self,
         v: impl IntoIterator<Item = (impl ToString, Option<u32>, Vec<u8>)>,
     ) -> Result<Self> {
+        self.ensure_local_context("copying_from_contents").stack()?;
         if self.debug {
             tracing::debug!("Current tarball paths: {:?}", self.tarball);
         }
 
+        let mut items = vec![];
+        for (to, mode, content) in v {
+            let to = to.to_string();
+            let path = context_path_of_image_path(&to)
+                .stack_err("SuperDockerfile::copying_from_contents")?;
+            items.push((to, path, mode, content));
+        }
+
         let this = Arc::new(std::sync::Mutex::new(self));
-        let futs = v
+        let futs = items
             .into_iter()
-            .map(|(to, mode, content)| {
+            .map(|(to, path, mode, content)| {
                 let this = this.clone();
-                let to = to.to_string();
+                this.lock()
+                    .unwrap()
+                    .append_dockerfile_lines_mut([format!("COPY {path} {to}")]);
 
                 tokio::task::spawn_blocking(move || {
                     let mut this_ref = this.lock().unwrap();
-
-                    this_ref.append_dockerfile_lines_mut([format!("COPY {to} {to}")]);
+                    this_ref.tarball.append_parent_dirs(&path).stack()?;
                     this_ref
                         .tarball
-                        .append_file_bytes(to, mode.unwrap_or(0o777), &content)
+                        .append_file_bytes(path, mode.unwrap_or(0o777), &content)
                         .stack()?;
 
                     Ok(()) as Result<_>
@@ -259,7 +936,10 @@ impl SuperDockerfile {
             })
             .collect::<Vec<_>>();
 
-        try_join_all(futs).await.stack()?;
+        // the inner results also need to be checked
+        for res in try_join_all(futs).await.stack()? {
+            res.stack()?;
+        }
 
         self = Arc::try_unwrap(this).unwrap().into_inner().stack()?;
 
@@ -270,17 +950,71 @@ impl SuperDockerfile {
         Ok(self)
     }
 
+    /// Adds a single `COPY` instruction to the dockerfile for placing multiple
+    /// files from memory under the absolute directory `dir` in the image. The
+    /// items are of the form `(relative_path, mode, content)`, where the
+    /// relative path can have subdirectories. The mode defaults to 777 like
+    /// with [SuperDockerfile::copying_from_contents].
+    ///
+    /// Returns an error if `dir` is not absolute, is the root, or if any path
+    /// has `..` segments.
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub async fn copying_contents_into_dir(
+        mut self,
+        dir: impl AsRef<str>,
+        v: impl IntoIterator<Item = (impl ToString, Option<u32>, Vec<u8>)>,
+    ) -> Result<Self> {
+        self.ensure_local_context("copying_contents_into_dir")
+            .stack()?;
+        let dir = dir.as_ref().trim_end_matches('/');
+        let dir_path = context_path_of_image_path(dir)
+            .stack_err("SuperDockerfile::copying_contents_into_dir")?;
+
+        let mut items = vec![];
+        for (relative, mode, content) in v {
+            let relative = relative.to_string();
+            let path = context_path_of_image_path(&format!("/{relative}"))
+                .ok()
+                .filter(|_| !Path::new(&relative).has_root())
+                .stack_err_with(|| {
+                    format!(
+                        "SuperDockerfile::copying_contents_into_dir -> \"{relative}\" is not a \
+                         relative path without `..` segments"
+                    )
+                })?;
+            items.push((format!("{dir_path}/{path}"), mode, content));
+        }
+
+        self = tokio::task::spawn_blocking(move || {
+            for (path, mode, content) in items {
+                self.tarball.append_parent_dirs(&path).stack()?;
+                self.tarball
+                    .append_file_bytes(path, mode.unwrap_or(0o777), &content)
+                    .stack()?;
+            }
+            Ok(self) as Result<_>
+        })
+        .await
+        .stack()??;
+        // the trailing slashes copy the contents of the directory
+        self.append_dockerfile_lines_mut([format!("COPY {dir_path}/ {dir}/")]);
+
+        Ok(self)
+    }
+
     /// Add an `ENTRYPOINT` instruction and append its file to docker "build
     /// tarball".
     ///
-    /// The entrypoint parameter is of the format (from, to).
+    /// The entrypoint parameter is of the format (from, to). The path and
+    /// arguments are passed as they are, with no variable expansion.
     ///
     /// If you already have an entrypoint and need to just change args, use
     /// [SuperDockerfile::append_dockerfile_instructions].
     #[tracing::instrument(skip_all, fields(
         image.name = ?self.image_name
     ))]
-    #[allow(clippy::obfuscated_if_else)]
     pub async fn with_entrypoint(
         mut self,
         entrypoint: (impl ToString, impl ToString),
@@ -293,20 +1027,15 @@ impl SuperDockerfile {
             .stack()?;
         let (_, to) = resolve_from_to(entrypoint.0, entrypoint.1, self.build_path.clone());
 
-        let entrypoint_args = entrypoint_args.into_iter().collect::<Vec<_>>();
-        let entrypoint_args = (!entrypoint_args.is_empty())
-            .then(|| {
-                ", ".to_string()
-                    + &entrypoint_args
-                        .into_iter()
-                        .map(|s| format!("\"{}\"", s.to_string()))
-                        .collect::<Vec<String>>()
-                        .join(", ")
-            })
-            .unwrap_or_default();
+        // the exec form is a JSON array, which also prevents variable expansion
+        let exec_form = serde_json::to_string(
+            &std::iter::once(to)
+                .chain(entrypoint_args.into_iter().map(|s| s.to_string()))
+                .collect::<Vec<_>>(),
+        )
+        .stack_err("SuperDockerfile::with_entrypoint")?;
 
-        Ok(self
-            .append_dockerfile_instructions([format!(r#"ENTRYPOINT ["{to}"{entrypoint_args}] "#,)]))
+        Ok(self.append_dockerfile_instructions([format!("ENTRYPOINT {exec_form}")]))
     }
 
     /// Make the current running binary the image's entrypoint, will call
@@ -342,10 +1071,37 @@ impl SuperDockerfile {
             .await
     }
 
-    /// Similar to bootstrap, but if the current target is not
-    /// x86_64-unknown-linux-musl, build and use musl binary else use
-    /// current binary. This is useful because musl is typically more portable.
-    /// Note that some containers support both GNU and MUSL.
+    /// Calls [SuperDockerfile::bootstrap] and also copies `files` into the
+    /// image using [SuperDockerfile::copying_from_paths]. The items are of the
+    /// form `(host_source_path, image_destination_path)`.
+    ///
+    /// This is useful for data files that the bootstrapped binary expects at
+    /// known paths.
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub async fn bootstrap_with_files(
+        self,
+        to: impl ToString,
+        entrypoint_args: impl IntoIterator<Item = impl ToString>,
+        files: impl IntoIterator<Item = (impl ToString, impl ToString)>,
+    ) -> Result<Self> {
+        self.bootstrap(to, entrypoint_args)
+            .await
+            .stack()?
+            .copying_from_paths(files)
+            .await
+            .stack_err("SuperDockerfile::bootstrap_with_files when copying the extra files")
+    }
+
+    /// Similar to bootstrap, but if the current target is not the musl target
+    /// matching the docker daemon's architecture (e.g.
+    /// x86_64-unknown-linux-musl), build and use musl binary else use current
+    /// binary. This is useful because musl is typically more portable. Note
+    /// that some containers support both GNU and MUSL.
+    ///
+    /// This calls [SuperDockerfile::bootstrap_for_target] with `None` for the
+    /// target triple and the release profile.
     #[tracing::instrument(skip_all, fields(
         image.name = ?self.image_name
     ))]
@@ -356,14 +1112,58 @@ impl SuperDockerfile {
         bootstrap_option: BootstrapOptions,
         other_build_flags: impl IntoIterator<Item = impl ToString>,
     ) -> Result<Self> {
+        self.bootstrap_for_target(
+            to,
+            entrypoint_args,
+            None,
+            BuildProfile::Release,
+            bootstrap_option,
+            other_build_flags,
+        )
+        .await
+    }
+
+    /// Similar to bootstrap, but if the current binary was not built for
+    /// `target_triple` and `profile`, build it with `cargo build --profile
+    /// <profile> --target <target_triple>` and use that binary instead. If
+    /// `target_triple` is `None`, the musl target matching the architecture of
+    /// the docker daemon is used (e.g. aarch64-unknown-linux-musl for an arm64
+    /// daemon).
+    ///
+    /// The build is skipped if the artifact for the target already exists and
+    /// was modified after the current binary. The current binary was built
+    /// from the current sources, so an artifact built after it is assumed to
+    /// be built from them as well. Changes to `other_build_flags` are not
+    /// detected, use [SuperDockerfile::with_force_rebuild] to always build.
+    #[tracing::instrument(skip_all, fields(
+        image.name = ?self.image_name
+    ))]
+    pub async fn bootstrap_for_target(
+        self,
+        to: impl ToString,
+        entrypoint_args: impl IntoIterator<Item = impl ToString>,
+        target_triple: Option<&str>,
+        profile: BuildProfile,
+        bootstrap_option: BootstrapOptions,
+        other_build_flags: impl IntoIterator<Item = impl ToString>,
+    ) -> Result<Self> {
+        let target_triple = if let Some(target_triple) = target_triple {
+            target_triple.to_string()
+        } else {
+            musl_target_for_docker_daemon(self.docker_instance.as_ref())
+                .await
+                .stack()?
+        };
+
         let target_selection_flag = bootstrap_option.to_flag();
-        let musl_target_path = &mut vec!["target", "x86_64-unknown-linux-musl", "release"];
+        let target_path = &mut vec!["target", target_triple.as_str(), profile.to_dir_name()];
 
         if let Some(path) = bootstrap_option.to_path_str() {
-            musl_target_path.push(path);
+            target_path.push(path);
         }
 
-        let mut cur_binary_path = std::env::current_exe().stack()?;
+        let cur_exe = std::env::current_exe().stack()?;
+        let mut cur_binary_path = cur_exe.clone();
 
         let cur_binary_name = normalize_windows_exe_path_for_cargo_binary(
             cur_binary_path
@@ -375,12 +1175,12 @@ impl SuperDockerfile {
         );
         cur_binary_path.pop();
 
-        let mut is_musl = true;
+        let mut is_target = true;
 
-        let musl_path_it = musl_target_path.iter().rev();
-        for cur_path in musl_path_it {
+        let target_path_it = target_path.iter().rev();
+        for cur_path in target_path_it {
             if !cur_binary_path.ends_with(cur_path) {
-                is_musl = false;
+                is_target = false;
                 break;
             }
 
@@ -389,34 +1189,54 @@ impl SuperDockerfile {
 
         let bootstrap_path = to;
 
-        if !is_musl {
-            tracing::debug!("Current binary is not linked with musl, building to accordingly");
-
-            let build_flags = other_build_flags
-                .into_iter()
-                .map(|s| s.to_string())
-                .collect::<Vec<String>>();
-            sh([
-                "cargo build -r --target x86_64-unknown-linux-musl",
-                target_selection_flag,
-                &cur_binary_name,
-            ]
-            .into_iter()
-            .chain(build_flags.iter().map(String::as_str)))
-            .await
-            .stack()?;
+        if !is_target {
             let entrypoint = &format!(
-                "./target/x86_64-unknown-linux-musl/release{}/{cur_binary_name}",
+                "./target/{target_triple}/{}{}/{cur_binary_name}",
+                profile.to_dir_name(),
                 bootstrap_option
                     .to_path_str()
                     .map_or_else(Default::default, |path| format!("/{path}")),
             );
 
+            if !self.force_rebuild && artifact_is_up_to_date(Path::new(entrypoint), &cur_exe) {
+                tracing::info!(
+                    "Current binary is not built for {target_triple} with profile {}, using the \
+                     cached {entrypoint} because it is newer than the current binary",
+                    profile.to_profile_name()
+                );
+            } else {
+                tracing::info!(
+                    "Current binary is not built for {target_triple} with profile {}, building it \
+                     accordingly",
+                    profile.to_profile_name()
+                );
+
+                let build_flags = other_build_flags
+                    .into_iter()
+                    .map(|s| s.to_string())
+                    .collect::<Vec<String>>();
+                sh([
+                    "cargo build --profile",
+                    profile.to_profile_name(),
+                    "--target",
+                    &target_triple,
+                    target_selection_flag,
+                    &cur_binary_name,
+                ]
+                .into_iter()
+                .chain(build_flags.iter().map(String::as_str)))
+                .await
+                .stack()?;
+            }
+
             self.with_entrypoint((entrypoint, bootstrap_path), entrypoint_args)
                 .await
                 .stack()
         } else {
-            tracing::debug!("Current binary is linked with musl, using it!");
+            tracing::debug!(
+                "Current binary is built for {target_triple} with profile {}, using it!",
+                profile.to_profile_name()
+            );
             self.bootstrap(bootstrap_path, entrypoint_args)
                 .await
                 .stack()
@@ -425,33 +1245,85 @@ impl SuperDockerfile {
 
     /// Inserts the Dockerfile into the tarball and consumes `self`, returning
     /// the necessary arguments for calling [bollard::Docker::build_image].
+    ///
+    /// This does not contact the daemon, engine specific adjustments like
+    /// those for podman are made by
+    /// [SuperDockerfile::build_with_bollard_defaults].
+    ///
+    /// The tarball is read into memory even if it was spilled to a file,
+    /// [SuperDockerfile::build_image] instead streams it to the daemon.
     #[tracing::instrument(skip_all, fields(
         image.name = ?self.image_name,
     ))]
     pub async fn into_bollard_args(
-        mut self,
+        self,
     ) -> Result<(bollard::image::BuildImageOptions<String>, Vec<u8>)> {
-        const DOCKER_FILE_NAME: &str = "./super.dockerfile";
-
-        let docker_file = &mut create_dockerfile_returning_file_handle(&self)
+        let (opts, context) = self.into_build_args().await.stack()?;
+        let tarball = context
+            .into_vec()
             .await
-            .stack()?;
+            .stack_err("SuperDockerfile::into_bollard_args")?;
+        Ok((opts, tarball))
+    }
 
-        self.tarball
-            .append_file(DOCKER_FILE_NAME.to_string(), docker_file)
-            .stack()?;
+    /// The implementation of [SuperDockerfile::into_bollard_args], which keeps
+    /// the finished tarball as it is
+    async fn into_build_args(
+        mut self,
+    ) -> Result<(bollard::image::BuildImageOptions<String>, BuildContext)> {
+        const DOCKER_FILE_NAME: &str = "./super.dockerfile";
 
-        if let Some(image_name) = self.image_name {
-            let (key, val) = image_name
-                .split_once(':')
-                .map(|(a, b)| (a.to_string(), b.to_string()))
-                .unwrap_or((image_name, Default::default()));
-            self.build_opts.labels.insert(key, val);
+        if !self.secrets.is_empty() {
+            return Err(
+                "SuperDockerfile::into_bollard_args -> secrets were added with \
+                 `SuperDockerfile::with_secret`, but `bollard::image::BuildImageOptions` cannot \
+                 carry them, use `SuperDockerfile::build_image` instead",
+            )
+            .stack();
         }
 
+        match self.platforms.len() {
+            0 => (),
+            1 => self.build_opts.platform = self.platforms.remove(0),
+            _ => {
+                return Err(
+                    "SuperDockerfile::into_bollard_args -> multiple platforms were set with \
+                     `SuperDockerfile::with_platforms`, use \
+                     `SuperDockerfile::build_platform_images` instead",
+                )
+                .stack()
+            }
+        }
+
+        let (remote, dockerfile) = if let Some((remote, dockerfile)) = self.remote.take() {
+            if !self.content_extend.is_empty() {
+                return Err(format!(
+                    "SuperDockerfile::into_bollard_args -> dockerfile instructions were appended, \
+                     but the dockerfile of the remote context {remote:?} cannot be modified"
+                ))
+                .stack();
+            }
+            (remote, dockerfile)
+        } else {
+            // the dockerfile is appended from memory so that no temporary file is needed
+            let docker_file = dockerfile_contents(&self).stack()?;
+            warn_undeclared_build_args(&self.build_opts.buildargs, &docker_file);
+            self.tarball
+                .append_file_bytes_with(DOCKER_FILE_NAME, &docker_file, &self.tar_entry_options)
+                .stack()?;
+            (String::new(), DOCKER_FILE_NAME.to_string())
+        };
+
+        self.apply_image_name()
+            .stack_err("SuperDockerfile::into_bollard_args")?;
+        self.build_opts
+            .validate()
+            .stack_err("SuperDockerfile::into_bollard_args when validating the build options")?;
+
         let opts = bollard::image::BuildImageOptions {
             labels: self.build_opts.labels,
-            dockerfile: DOCKER_FILE_NAME.to_string(),
+            dockerfile,
+            remote,
             t: self.build_opts.t,
             extrahosts: self.build_opts.extrahosts,
             q: self.build_opts.q,
@@ -473,58 +1345,761 @@ impl SuperDockerfile {
             platform: self.build_opts.platform,
             target: self.build_opts.target,
             version: self.build_opts.version,
-            ..Default::default()
+            // the BuildKit `session` and `outputs` are only used by `build_with_secrets`
+            #[cfg(feature = "buildkit")]
+            session: None,
+            #[cfg(feature = "buildkit")]
+            outputs: None,
         };
 
-        let tarball = self.tarball.into_tarball().stack()?;
+        // the daemon fetches remote contexts itself
+        let context = if !opts.remote.is_empty() {
+            BuildContext::Empty
+        } else {
+            // compressed while it is sent or read
+            let tarball = self.tarball;
+            BuildContext::Tarball {
+                tarball: tokio::task::spawn_blocking(move || tarball.finish())
+                    .await
+                    .stack()?
+                    .stack_err("SuperDockerfile::into_bollard_args")?,
+                gzip: self.compress_context,
+            }
+        };
 
-        Ok((opts, tarball))
+        Ok((opts, context))
     }
 
     /// Calls [bollard::Docker::build_image] using return value of
     /// [SuperDockerfile::into_bollard_args] and the default docker instance
     /// from [bollard::Docker::connect_with_defaults].
+    ///
+    /// If the engine is podman, options that it rejects are adjusted with a
+    /// warning.
     pub async fn build_with_bollard_defaults(
         build_opts: bollard::image::BuildImageOptions<String>,
         tarball: Vec<u8>,
     ) -> Result<(SuperImage, Vec<u8>)> {
-        let docker_instance = docker_socket::get_or_init_default_docker_instance()
+        Self::build_with_bollard_defaults_using(
+            build_opts,
+            tarball,
+            None,
+            RetryPolicy::default(),
+            None,
+        )
+        .await
+        .stack()
+    }
+
+    /// The same as [SuperDockerfile::build_with_bollard_defaults], but if
+    /// `timeout` is set and the build does not complete in time, a timeout
+    /// error with the last few progress lines is returned. The build is
+    /// restarted on transient connection errors according to `retry_policy`,
+    /// and aborted on ctrl-c. If `docker_instance` is `None`,
+    /// [get_or_init_default_docker_instance](docker_socket::get_or_init_default_docker_instance)
+    /// is used.
+    ///
+    /// The tarball can be gzip compressed, and the same tarball is returned.
+    pub async fn build_with_bollard_defaults_using(
+        build_opts: bollard::image::BuildImageOptions<String>,
+        tarball: Vec<u8>,
+        timeout: Option<Duration>,
+        retry_policy: RetryPolicy,
+        docker_instance: Option<&bollard::Docker>,
+    ) -> Result<(SuperImage, Vec<u8>)> {
+        let mut log = String::new();
+        let engine_info = docker_socket::engine_info_or_default(docker_instance)
+            .await
+            .stack()?;
+        let context = BuildContext::Bytes(bytes::Bytes::from(tarball));
+        let image = Self::build_collecting_log(
+            build_opts,
+            &context,
+            timeout,
+            retry_policy,
+            docker_instance,
+            &engine_info,
+            &mut log,
+        )
+        .await
+        .stack()?;
+        Ok((image, context.into_vec().await.stack()?))
+    }
+
+    /// The implementation of
+    /// [SuperDockerfile::build_with_bollard_defaults_using], which appends
+    /// the messages of the build stream to `log`. The log is restarted if the
+    /// build is retried.
+    ///
+    /// If `docker_instance` is `None` and the default instance was connected
+    /// with a unix socket or an unencrypted http connection, a tarball
+    /// `context` is streamed to the daemon without reading it into memory.
+    async fn build_collecting_log(
+        mut build_opts: bollard::image::BuildImageOptions<String>,
+        context: &BuildContext,
+        timeout: Option<Duration>,
+        retry_policy: RetryPolicy,
+        docker_instance: Option<&bollard::Docker>,
+        engine_info: &docker_socket::EngineInfo,
+        log: &mut String,
+    ) -> Result<SuperImage> {
+        // the number of progress lines kept for the timeout error
+        const NUM_LAST_LINES: usize = 8;
+
+        if engine_info.is_podman() {
+            podman_compatible_build_opts(&mut build_opts);
+        }
+
+        let uses_default = docker_instance.is_none();
+        let docker_instance = docker_socket::docker_or_default(docker_instance)
+            .await
+            .stack()?;
+        // `bollard` only takes the context as bytes, so it can only be streamed over
+        // a connection that we know how to make
+        let endpoint = uses_default
+            .then(docker_socket::default_context_endpoint)
+            .flatten();
+
+        let ctrlc = CtrlCTask::spawn();
+        let mut last_lines = VecDeque::new();
+        let mut image_id = None;
+        let consume = async {
+            let mut attempt = 0;
+            'attempt: loop {
+                let (attempt_instance, tarball) = context
+                    .request_args(&docker_instance, endpoint.as_ref())
+                    .await
+                    .stack()?;
+                let mut stream = std::pin::pin!(attempt_instance.build_image(
+                    build_opts.clone(),
+                    None,
+                    tarball.filter(|tarball| !tarball.is_empty())
+                ));
+                while let Some(build_info) = stream.next().await {
+                    if ctrlc.is_complete() {
+                        return Err(Error::from_err(
+                            "SuperDockerfile::build_image terminating because of ctrl-c",
+                        ));
+                    }
+                    let build_info = match build_info {
+                        Ok(build_info) => build_info,
+                        Err(e) if retry_policy.should_retry(attempt, &e) => {
+                            retry_policy
+                                .wait_before_retry(attempt, "SuperDockerfile::build_image", &e)
+                                .await;
+                            attempt += 1;
+                            log.clear();
+                            continue 'attempt;
+                        }
+                        // because the display impl only shows the error enum
+                        Err(e) => {
+                            return Err(Error::from_err(format!("{e:?}")))
+                                .stack_err("when trying to build image");
+                        }
+                    };
+                    if let Some(stream) = &build_info.stream {
+                        log.push_str(stream);
+                    }
+                    if let Some(status) = &build_info.status {
+                        log.push_str(status);
+                        log.push('\n');
+                    }
+                    if let Some(line) = build_info.stream.as_deref().map(str::trim) {
+                        if !line.is_empty() {
+                            tracing::debug!("{line}");
+                            if last_lines.len() >= NUM_LAST_LINES {
+                                last_lines.pop_front();
+                            }
+                            last_lines.push_back(line.to_owned());
+                        }
+                    }
+                    if image_id.is_none() {
+                        image_id = aux_image_id(build_info.aux);
+                    }
+                }
+                return Ok(());
+            }
+        };
+
+        if let Some(timeout) = timeout {
+            match tokio::time::timeout(timeout, consume).await {
+                Ok(res) => res.stack()?,
+                Err(_) => {
+                    return Err(Error::timeout().add_err_locationless(format!(
+                        "SuperDockerfile::build_image timeout after {timeout:?}, the last \
+                         progress lines were:\n{}",
+                        last_lines.into_iter().collect::<Vec<_>>().join("\n")
+                    )));
+                }
+            }
+        } else {
+            consume.await.stack()?;
+        }
+
+        let image_id = image_id.stack_err("image built without id")?;
+        let mut image = SuperImage::new(image_id);
+        if !build_opts.t.is_empty() {
+            image = image.with_reference(build_opts.t);
+        }
+
+        Ok(image)
+    }
+
+    /// Calls [SuperDockerfile::build_with_bollard_defaults_using] using the
+    /// arguments returned from [SuperDockerfile::into_bollard_args], the
+    /// timeout from [SuperDockerfile::with_build_timeout], and the retry
+    /// policy from [SuperDockerfile::with_retry_policy].
+    ///
+    /// If secrets were added with [SuperDockerfile::with_secret], the image is
+    /// instead built through a BuildKit session, which only uses the
+    /// labels, build args, target, `pull`, `nocache`, and `shmsize` of the
+    /// build options.
+    ///
+    /// The build context is dropped after the build, use
+    /// [SuperDockerfile::build_image_returning_context] if it is needed.
+    pub async fn build_image(self) -> Result<SuperImage> {
+        let (image, _) = self
+            .build_keeping_context(&mut String::new())
+            .await
+            .stack_err("SuperDockerfile::build_image")?;
+        Ok(image)
+    }
+
+    /// The same as [SuperDockerfile::build_image], but also returns the build
+    /// context tarball. If the image was built through a BuildKit session,
+    /// the tarball has the dockerfile at "Dockerfile".
+    pub async fn build_image_returning_context(self) -> Result<(SuperImage, Vec<u8>)> {
+        let (image, context) = self
+            .build_keeping_context(&mut String::new())
+            .await
+            .stack_err("SuperDockerfile::build_image")?;
+        let context = context
+            .into_vec()
+            .await
+            .stack_err("SuperDockerfile::build_image_returning_context")?;
+        Ok((image, context))
+    }
+
+    /// The implementation of [SuperDockerfile::build_image], which appends the
+    /// messages of the build stream to `log` and returns the build context
+    /// without reading it into memory
+    async fn build_keeping_context(
+        mut self,
+        log: &mut String,
+    ) -> Result<(SuperImage, BuildContext)> {
+        if !self.secrets.is_empty() {
+            self.ensure_local_context("build_image")
+                .stack_err("secrets cannot be used with a remote context")?;
+            let (image, context) = self.build_with_secrets().await.stack()?;
+            return Ok((image, BuildContext::Bytes(bytes::Bytes::from(context))))
+        }
+
+        let build_timeout = self.build_timeout;
+        let retry_policy = self.retry_policy;
+        let docker_instance = self.docker_instance.clone();
+        let engine_info = self.cached_engine_info().await.stack()?;
+        let (build_opts, context) = self.into_build_args().await.stack()?;
+
+        let image = Self::build_collecting_log(
+            build_opts,
+            &context,
+            build_timeout,
+            retry_policy,
+            docker_instance.as_ref(),
+            &engine_info,
+            log,
+        )
+        .await
+        .stack()?;
+        Ok((image, context))
+    }
+
+    /// The same as [SuperDockerfile::build_image], but also returns the
+    /// messages of the build stream in order, and the build context tarball
+    /// if `keep_context` is set. If the build fails, the error ends with the
+    /// partial log.
+    ///
+    /// The log is empty if the image was built through a BuildKit session
+    /// (with [SuperDockerfile::with_secret]), because `bollard` does not expose
+    /// its progress messages.
+    pub async fn build_image_with_output(self, keep_context: bool) -> Result<BuildOutput> {
+        let mut log = String::new();
+        match self.build_keeping_context(&mut log).await {
+            Ok((image, context)) => {
+                let context_bytes = if keep_context {
+                    Some(
+                        context
+                            .into_vec()
+                            .await
+                            .stack_err("SuperDockerfile::build_image_with_output")?,
+                    )
+                } else {
+                    None
+                };
+                Ok(BuildOutput {
+                    image,
+                    log,
+                    context_bytes,
+                })
+            }
+            Err(e) => Err(e.add_err_locationless(format!(
+                "SuperDockerfile::build_image_with_output, the build log was:\n{log}"
+            ))),
+        }
+    }
+
+    /// Builds the image separately for each platform set with
+    /// [SuperDockerfile::with_platforms], returning the images keyed by
+    /// platform in the same order. Otherwise, this is the same as
+    /// [SuperDockerfile::build_image] (except that secrets are not supported).
+    ///
+    /// If the image has a name or `t` build option, each image is tagged with
+    /// the platform appended to the tag so that the images do not replace each
+    /// other, e.g. "app:1.0" becomes "app:1.0-linux-arm64" and "app" becomes
+    /// "app:linux-arm64".
+    pub async fn build_platform_images(mut self) -> Result<Vec<(String, SuperImage)>> {
+        let platforms = std::mem::take(&mut self.platforms);
+        if platforms.is_empty() {
+            return Err(
+                "SuperDockerfile::build_platform_images -> no platforms were set with \
+                 `SuperDockerfile::with_platforms`",
+            )
+            .stack();
+        }
+
+        let build_timeout = self.build_timeout;
+        let retry_policy = self.retry_policy;
+        let docker_instance = self.docker_instance.clone();
+        let engine_info = self
+            .cached_engine_info()
+            .await
+            .stack_err("SuperDockerfile::build_platform_images")?;
+        let (build_opts, context) = self
+            .into_build_args()
+            .await
+            .stack_err("SuperDockerfile::build_platform_images")?;
+
+        let mut images = vec![];
+        for platform in platforms {
+            let t = if build_opts.t.is_empty() {
+                String::new()
+            } else {
+                platform_reference(&build_opts.t, &platform)
+            };
+            let build_opts = bollard::image::BuildImageOptions {
+                platform: platform.clone(),
+                t,
+                ..build_opts.clone()
+            };
+            let res = Self::build_collecting_log(
+                build_opts,
+                &context,
+                build_timeout,
+                retry_policy,
+                docker_instance.as_ref(),
+                &engine_info,
+                &mut String::new(),
+            )
+            .await;
+            let image = match res {
+                Ok(image) => image,
+                // this is what running a binary for another architecture without emulation
+                // results in
+                Err(e) if format!("{e:?}").contains("exec format error") => {
+                    return Err(e).stack_err(format!(
+                        "SuperDockerfile::build_platform_images -> the docker daemon could not \
+                         run binaries for platform \"{platform}\", it probably needs QEMU and \
+                         binfmt handlers installed (e.g. with `docker run --privileged --rm \
+                         tonistiigi/binfmt --install all`)"
+                    ))
+                }
+                Err(e) => {
+                    return Err(e).stack_err(format!(
+                        "SuperDockerfile::build_platform_images when building for platform \
+                         \"{platform}\""
+                    ))
+                }
+            };
+            images.push((platform, image));
+        }
+
+        Ok(images)
+    }
+}
+
+/// Builds all of the `dockerfiles` with
+/// [SuperDockerfile::build_image_returning_context], with
+/// at most `max_parallel` builds running at the same time, and returns the
+/// results in the same order.
+///
+/// Dockerfiles with identical [Dockerfile] bases are coordinated so that the
+/// base is only fetched or built once: shared `Dockerfile::NameTag` bases are
+/// pulled beforehand if they are not present locally, and for shared
+/// `Dockerfile::Path` or `Dockerfile::Contents` bases, the first dockerfile
+/// using the base is built before the others so that they can use its cached
+/// layers.
+///
+/// All builds are attempted even if some fail, and the errors of all failed
+/// builds are returned together. The builds run in spans with the image name
+/// (or the index if there is no name) so that their logs and progress lines
+/// (at the debug level) can be told apart.
+pub async fn build_images_concurrently(
+    dockerfiles: Vec<SuperDockerfile>,
+    max_parallel: usize,
+) -> Result<Vec<(SuperImage, Vec<u8>)>> {
+    let max_parallel = max_parallel.max(1);
+
+    // indexes of dockerfiles with the same remote context and base
+    let mut groups: Vec<Vec<usize>> = vec![];
+    for (i, sdf) in dockerfiles.iter().enumerate() {
+        if let Some(group) = groups.iter_mut().find(|group| {
+            let first = &dockerfiles[group[0]];
+            (first.remote == sdf.remote) && (first.base == sdf.base)
+        }) {
+            group.push(i);
+        } else {
+            groups.push(vec![i]);
+        }
+    }
+    let mut pulls = vec![];
+    let mut leaders = vec![];
+    let mut followers = vec![];
+    for group in groups {
+        let first = &dockerfiles[group[0]];
+        match &first.base {
+            Dockerfile::NameTag(name_tag) if (group.len() > 1) && first.remote.is_none() => {
+                pulls.push((name_tag.clone(), group));
+            }
+            _ if group.len() > 1 => {
+                leaders.push(group[0]);
+                followers.extend_from_slice(&group[1..]);
+            }
+            _ => followers.extend_from_slice(&group),
+        }
+    }
+
+    let labels: Vec<String> = dockerfiles
+        .iter()
+        .enumerate()
+        .map(|(i, sdf)| sdf.image_name.clone().unwrap_or_else(|| format!("#{i}")))
+        .collect();
+    let mut dockerfiles: Vec<Option<SuperDockerfile>> = dockerfiles.into_iter().map(Some).collect();
+    let mut results: Vec<_> = (0..dockerfiles.len()).map(|_| None).collect();
+    let mut errs = vec![];
+
+    let pull_futs = pulls.into_iter().map(|(name_tag, group)| async move {
+        let docker = docker_socket::get_or_init_default_docker_instance()
             .await
             .stack()?;
+        if docker.inspect_image(&name_tag).await.is_err() {
+            pull_image(&name_tag, RegistryOptions::default())
+                .await
+                .stack_err_with(|| {
+                    format!("build_images_concurrently when pulling the shared base {name_tag}")
+                })?;
+        }
+        Ok(group) as Result<Vec<usize>>
+    });
+    for res in stream::iter(pull_futs)
+        .buffer_unordered(max_parallel)
+        .collect::<Vec<_>>()
+        .await
+    {
+        match res {
+            Ok(group) => followers.extend_from_slice(&group),
+            // the dockerfiles using the base would fail in the same way
+            Err(e) => errs.push(e),
+        }
+    }
 
-        let image_id = docker_instance
-            // need the clone here because of incompatibility with tar::Builder and bytes::BytesMut
-            .build_image(build_opts, None, Some(tarball.clone().into()))
-            /*.inspect_ok(|msg| {
-                msg.stream
-                    .as_ref()
-                    .inspect(|x| tracing::debug!("{}", x.trim()));
-            })*/
-            .try_filter_map(|x| futures::future::ready(Ok(x.aux)))
-            .try_collect::<Vec<_>>()
+    for stage in [leaders, followers] {
+        let futs = stage.into_iter().map(|i| {
+            let sdf = dockerfiles[i].take().unwrap();
+            let span = tracing::info_span!("build", image.name = %labels[i]);
+            async move { (i, sdf.build_image_returning_context().await) }.instrument(span)
+        });
+        for (i, res) in stream::iter(futs)
+            .buffer_unordered(max_parallel)
+            .collect::<Vec<_>>()
             .await
-            // because the display impl only shows the error enum
-            .map_err(|e| format!("{e:?}"))
-            .stack_err("when trying to build image")?
+        {
+            results[i] = Some(res);
+        }
+    }
+
+    let mut images = vec![];
+    for (res, label) in results.into_iter().zip(labels) {
+        match res {
+            Some(Ok(image)) => images.push(image),
+            Some(Err(e)) => errs.push(e.add_err_locationless(format!(
+                "build_images_concurrently when building image {label}"
+            ))),
+            None => (),
+        }
+    }
+    if let Some(last_err) = errs.pop() {
+        Err(errs
             .into_iter()
-            .next()
-            .and_then(|x| x.id)
-            .stack_err("image built without id")?;
+            .fold(last_err, |last_err, err| last_err.chain_errors(err)))
+    } else {
+        Ok(images)
+    }
+}
 
-        Ok((SuperImage::new(image_id), tarball))
+impl SuperDockerfile {
+    #[cfg(not(feature = "buildkit"))]
+    async fn build_with_secrets(self) -> Result<(SuperImage, Vec<u8>)> {
+        Err(
+            "secrets were added with `SuperDockerfile::with_secret`, but building with secrets \
+             requires the `buildkit` feature of `super_orchestrator`",
+        )
+        .stack()
     }
 
-    /// Calls [SuperDockerfile::build_with_bollard_defaults] using the arguments
-    /// returned from [SuperDockerfile::into_bollard_args].
-    pub async fn build_image(self) -> Result<(SuperImage, Vec<u8>)> {
-        let (build_opts, tarball) = self.into_bollard_args().await.stack()?;
+    #[cfg(feature = "buildkit")]
+    async fn build_with_secrets(mut self) -> Result<(SuperImage, Vec<u8>)> {
+        use bollard::grpc::{
+            build::{ImageBuildFrontendOptions, ImageBuildLoadInput, SecretSource},
+            driver::{moby::Moby, Build},
+        };
+
+        // the dockerfile frontend looks for this name
+        const DOCKER_FILE_NAME: &str = "Dockerfile";
 
-        Self::build_with_bollard_defaults(build_opts, tarball)
+        let docker_instance = docker_socket::docker_or_default(self.docker_instance.as_ref())
             .await
-            .stack_err("SuperDockerfile::build_image")
+            .stack()?;
+        check_buildkit_support(&docker_instance).await.stack()?;
+
+        let docker_file = dockerfile_contents(&self).stack()?;
+        warn_undeclared_build_args(&self.build_opts.buildargs, &docker_file);
+        self.tarball
+            .append_file_bytes_with(DOCKER_FILE_NAME, &docker_file, &self.tar_entry_options)
+            .stack()?;
+        let tarball = bytes::Bytes::from(std::mem::take(&mut self.tarball).into_tarball().stack()?);
+
+        self.apply_image_name().stack()?;
+        let reference = (!self.build_opts.t.is_empty()).then(|| self.build_opts.t.clone());
+        // the image is exported under a name that we can inspect for the id
+        let name = if self.build_opts.t.is_empty() {
+            format!("super_orchestrator_{}", uuid::Uuid::new_v4())
+        } else {
+            self.build_opts.t.clone()
+        };
+
+        let mut frontend_opts = ImageBuildFrontendOptions::builder()
+            .pull(self.build_opts.pull)
+            .nocache(self.build_opts.nocache);
+        for (key, val) in &self.build_opts.labels {
+            frontend_opts = frontend_opts.label(key, val);
+        }
+        for (key, val) in &self.build_opts.buildargs {
+            frontend_opts = frontend_opts.buildarg(key, val);
+        }
+        if !self.build_opts.target.is_empty() {
+            frontend_opts = frontend_opts.target(&self.build_opts.target);
+        }
+        if let Some(shmsize) = self.build_opts.shmsize {
+            frontend_opts = frontend_opts.shmsize(shmsize);
+        }
+
+        // secrets given as bytes go to temporary files that only exist for the
+        // duration of the build
+        let mut temp_files = vec![];
+        let res = async {
+            for (id, secret) in &self.secrets {
+                let source = match secret {
+                    BuildSecret::Bytes(bytes) => {
+                        let mut path = std::env::temp_dir();
+                        path.push(uuid::Uuid::new_v4().to_string());
+                        temp_files.push(path.clone());
+                        write_secret_file(path.clone(), bytes.clone())
+                            .await
+                            .stack_err_with(|| format!("when writing secret \"{id}\""))?;
+                        SecretSource::File(path)
+                    }
+                    BuildSecret::File(path) => SecretSource::File(path.clone()),
+                    BuildSecret::Env(var) => SecretSource::Env(var.clone()),
+                };
+                frontend_opts = frontend_opts.set_secret(id, &source);
+            }
+
+            let build = Moby::new(&docker_instance).docker_build(
+                &name,
+                frontend_opts.build(),
+                ImageBuildLoadInput::Upload(tarball.clone()),
+                None,
+            );
+            let res = if let Some(timeout) = self.build_timeout {
+                tokio::time::timeout(timeout, build).await.map_err(|_| {
+                    Error::timeout().add_err_locationless(format!(
+                        "SuperDockerfile::build_image timeout after {timeout:?}"
+                    ))
+                })?
+            } else {
+                build.await
+            };
+            res
+                // because the display impl only shows the error enum
+                .map_err(|e| format!("{e:?}"))
+                .stack_err("when trying to build image with BuildKit")?;
+
+            docker_instance
+                .inspect_image(&name)
+                .await
+                .stack_err_with(|| format!("when inspecting built image \"{name}\""))?
+                .id
+                .stack_err("image built without id")
+        }
+        .await;
+
+        for path in temp_files {
+            if let Err(e) = std::fs::remove_file(&path) {
+                tracing::warn!("failed to remove temporary secret file {path:?}: {e}");
+            }
+        }
+
+        let mut image = SuperImage::new(res.stack()?);
+        if let Some(reference) = reference {
+            image = image.with_reference(reference);
+        }
+        Ok((image, Vec::from(tarball)))
     }
 }
 
+/// Returns if the cross compiled `artifact` exists and was modified after
+/// `current_exe`
+fn artifact_is_up_to_date(artifact: &Path, current_exe: &Path) -> bool {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    match (modified(artifact), modified(current_exe)) {
+        (Some(artifact), Some(current_exe)) => artifact >= current_exe,
+        _ => false,
+    }
+}
+
+/// Returns the ID of the built image from the `aux` of a build message
+#[cfg(not(feature = "buildkit"))]
+fn aux_image_id(aux: Option<bollard::models::ImageId>) -> Option<String> {
+    aux.and_then(|x| x.id)
+}
+
+/// Returns the ID of the built image from the `aux` of a build message, the
+/// `aux` of BuildKit progress messages has no ID
+#[cfg(feature = "buildkit")]
+fn aux_image_id(aux: Option<bollard::models::BuildInfoAux>) -> Option<String> {
+    match aux {
+        Some(bollard::models::BuildInfoAux::Default(x)) => x.id,
+        _ => None,
+    }
+}
+
+/// Returns an error if the docker daemon does not support BuildKit sessions
+#[cfg(feature = "buildkit")]
+async fn check_buildkit_support(docker_instance: &bollard::Docker) -> Result<()> {
+    // the "/grpc" endpoint needed for sessions was added in API version 1.40
+    const MIN_BUILDKIT_API_VERSION: (u32, u32) = (1, 40);
+
+    if docker_socket::engine_info_for(docker_instance)
+        .await
+        .stack()?
+        .is_podman()
+    {
+        return Err(
+            "the docker API is served by podman, which does not support the BuildKit sessions \
+             needed for build secrets",
+        )
+        .stack();
+    }
+
+    let api_version = docker_instance
+        .version()
+        .await
+        .stack_err("when getting the docker daemon version to check for BuildKit support")?
+        .api_version
+        .stack_err("docker daemon version did not include an API version")?;
+    match docker_socket::parse_api_version(&api_version) {
+        Some(version) if version >= MIN_BUILDKIT_API_VERSION => Ok(()),
+        _ => Err(format!(
+            "the docker daemon has API version {api_version} which does not support the BuildKit \
+             sessions needed for build secrets, API version {}.{} or newer is required",
+            MIN_BUILDKIT_API_VERSION.0, MIN_BUILDKIT_API_VERSION.1
+        ))
+        .stack(),
+    }
+}
+
+/// Writes a secret to a new file that only the current user can read
+#[cfg(feature = "buildkit")]
+async fn write_secret_file(path: PathBuf, content: Vec<u8>) -> Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let mut options = std::fs::File::options();
+        options.create_new(true).write(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&path).stack()?;
+        std::io::Write::write_all(&mut file, &content).stack()
+    })
+    .await
+    .stack()?
+}
+
+/// Adjusts build options that podman's docker compatible API rejects
+fn podman_compatible_build_opts(build_opts: &mut bollard::image::BuildImageOptions<String>) {
+    // podman does not serve BuildKit sessions
+    if build_opts.version == BuilderVersion::BuilderBuildKit {
+        tracing::warn!(
+            "podman detected, building with `BuilderVersion::BuilderV1` instead of BuildKit"
+        );
+        build_opts.version = BuilderVersion::BuilderV1;
+    }
+}
+
+/// Returns `reference` with `platform` appended to its tag, or with `platform`
+/// as its tag if it has none
+fn platform_reference(reference: &str, platform: &str) -> String {
+    let platform = platform.replace('/', "-");
+    // a registry port is before the last '/'
+    let name_start = reference.rfind('/').map_or(0, |i| i + 1);
+    if reference[name_start..].contains(':') {
+        format!("{reference}-{platform}")
+    } else {
+        format!("{reference}:{platform}")
+    }
+}
+
+/// Gets the musl target triple matching the architecture of the docker daemon
+async fn musl_target_for_docker_daemon(
+    docker_instance: Option<&bollard::Docker>,
+) -> Result<String> {
+    let docker_instance = docker_socket::docker_or_default(docker_instance)
+        .await
+        .stack()?;
+    let arch = docker_instance
+        .version()
+        .await
+        .stack_err("when getting the docker daemon version for its architecture")?
+        .arch
+        .stack_err("docker daemon version did not include an architecture")?;
+    // docker uses the Go architecture names
+    let target_triple = match arch.as_str() {
+        "amd64" | "x86_64" => "x86_64-unknown-linux-musl",
+        "arm64" | "aarch64" => "aarch64-unknown-linux-musl",
+        "arm" => "armv7-unknown-linux-musleabihf",
+        "386" => "i686-unknown-linux-musl",
+        "riscv64" => "riscv64gc-unknown-linux-musl",
+        "ppc64le" => "powerpc64le-unknown-linux-musl",
+        "s390x" => "s390x-unknown-linux-musl",
+        _ => {
+            return Err(format!(
+                "no known musl target for docker daemon architecture \"{arch}\", pass a target \
+                 triple explicitly"
+            ))
+            .stack()
+        }
+    };
+    Ok(target_triple.to_string())
+}
+
 /// When dealing with windows binaries, the file will have the .exe extension
 /// while the rust binary won't. If we don't remove the .exe, the command will
 /// be like `cargo build --bin ${bin_name}.exe
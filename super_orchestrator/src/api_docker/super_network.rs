@@ -0,0 +1,921 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
+    mem,
+    time::Duration,
+};
+
+use bollard::container::LogOutput;
+use futures::StreamExt;
+use stacked_errors::{bail_locationless, Error, Result, StackableErr};
+use tokio::{
+    fs::File,
+    io::AsyncWriteExt,
+    task::JoinHandle,
+    time::{sleep, Instant},
+};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::{
+    api_docker::{
+        docker_socket::get_or_init_default_docker_instance, port_bindings_to_bollard_args,
+        wait_container, CompletedContainer, PortBind, RetryPolicy, SuperImage,
+    },
+    cli_docker::{container_error_messages, record_tail},
+    close_file, CtrlCTask, FileOptions,
+};
+
+/// A container definition for [SuperNetwork], the API equivalent of
+/// [Container](crate::cli_docker::Container)
+#[derive(Debug, Clone)]
+pub struct SuperContainer {
+    /// The name used for referring to the container in the network, this is
+    /// also the hostname and network alias of the container
+    pub name: String,
+    pub image: SuperImage,
+    /// If empty, the default command of the image is used
+    pub cmd: Vec<String>,
+    pub user: Option<String>,
+    pub env_vars: Vec<String>,
+    /// Items are `(host_path, container_path)`
+    pub volumes: Vec<(String, String)>,
+    pub port_bindings: Vec<PortBind>,
+    /// If the container exiting with an unsuccessful exit code should not be
+    /// considered a failure by [SuperNetwork::wait_with_timeout]
+    pub allow_unsuccessful: bool,
+    /// If stdout and stderr should be written to log files
+    pub log: bool,
+    /// Overrides the default "{log_dir}/{name}_stdout.log" if `log` is set
+    pub stdout_log: Option<FileOptions>,
+    /// Overrides the default "{log_dir}/{name}_stderr.log" if `log` is set
+    pub stderr_log: Option<FileOptions>,
+    /// How creating and starting the container is retried on transient
+    /// connection errors
+    pub retry_policy: RetryPolicy,
+}
+
+impl SuperContainer {
+    /// Creates the information needed to describe a `SuperContainer` running
+    /// `image`
+    pub fn new(name: impl AsRef<str>, image: SuperImage) -> Self {
+        Self {
+            name: name.as_ref().to_owned(),
+            image,
+            cmd: vec![],
+            user: None,
+            env_vars: vec![],
+            volumes: vec![],
+            port_bindings: vec![],
+            allow_unsuccessful: false,
+            log: false,
+            stdout_log: None,
+            stderr_log: None,
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    /// Sets the command, replacing the default command of the image
+    pub fn cmd<I, S>(mut self, cmd: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.cmd = cmd.into_iter().map(|s| s.as_ref().to_owned()).collect();
+        self
+    }
+
+    /// Adds environment variables
+    pub fn env_vars<I, K, V>(mut self, env_vars: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.env_vars.extend(
+            env_vars
+                .into_iter()
+                .map(|(k, v)| format!("{}={}", k.as_ref(), v.as_ref())),
+        );
+        self
+    }
+
+    /// Adds a volume
+    pub fn volume(mut self, local: impl AsRef<str>, container: impl AsRef<str>) -> Self {
+        self.volumes
+            .push((local.as_ref().to_owned(), container.as_ref().to_owned()));
+        self
+    }
+
+    /// Adds port bindings
+    pub fn port_bindings(mut self, port_bindings: impl IntoIterator<Item = PortBind>) -> Self {
+        self.port_bindings.extend(port_bindings);
+        self
+    }
+
+    /// Sets `allow_unsuccessful`
+    pub fn allow_unsuccessful(mut self, allow_unsuccessful: bool) -> Self {
+        self.allow_unsuccessful = allow_unsuccessful;
+        self
+    }
+
+    /// Sets `log`
+    pub fn log(mut self, log: bool) -> Self {
+        self.log = log;
+        self
+    }
+}
+
+#[derive(Debug)]
+struct ActiveContainer {
+    docker_name: String,
+    wait: JoinHandle<Result<i64>>,
+    logs: JoinHandle<Result<(Vec<u8>, Vec<u8>)>>,
+}
+
+#[derive(Debug, Default)]
+enum RunState {
+    #[default]
+    PreActive,
+    Active(ActiveContainer),
+    PostActive {
+        result: Result<CompletedContainer>,
+        terminated: bool,
+    },
+}
+
+#[derive(Debug)]
+struct ContainerState {
+    container: SuperContainer,
+    run_state: RunState,
+}
+
+impl ContainerState {
+    fn is_active(&self) -> bool {
+        matches!(self.run_state, RunState::Active(_))
+    }
+
+    /// Removes the container if it is active and collects its results.
+    /// Returns if there was an error or an unsuccessful exit.
+    async fn deactivate(&mut self, docker: &bollard::Docker) -> bool {
+        let RunState::Active(mut active) = mem::take(&mut self.run_state) else {
+            return false;
+        };
+        let terminated = !active.wait.is_finished();
+        if terminated {
+            active.wait.abort();
+            // the log stream only ends once the container stops, if this fails the
+            // container probably stopped already
+            let _ = docker
+                .kill_container::<String>(&active.docker_name, None)
+                .await;
+        }
+        // the logs are drained before the container is removed, so that the end of its
+        // output is not lost
+        let logs = match tokio::time::timeout(Duration::from_secs(10), &mut active.logs).await {
+            Ok(logs) => logs.stack().and_then(|logs| logs),
+            Err(_) => {
+                active.logs.abort();
+                Err(Error::timeout().add_err_locationless(
+                    "SuperNetwork -> timeout when collecting the logs of a stopped container",
+                ))
+            }
+        };
+        let remove = docker
+            .remove_container(
+                &active.docker_name,
+                Some(bollard::container::RemoveContainerOptions {
+                    force: true,
+                    v: true,
+                    ..Default::default()
+                }),
+            )
+            .await
+            .stack_err_with_locationless(|| {
+                format!(
+                    "SuperNetwork -> when removing container \"{}\"",
+                    active.docker_name
+                )
+            });
+        let exit_code = if terminated {
+            // the exit code of a forcibly removed container is not meaningful
+            Ok(-1)
+        } else {
+            active.wait.await.stack().and_then(|exit_code| exit_code)
+        };
+        let result = remove.and(exit_code).and_then(|exit_code| {
+            let (stdout, stderr) = logs?;
+            Ok(CompletedContainer {
+                name: active.docker_name,
+                exit_code,
+                stdout,
+                stderr,
+            })
+        });
+        let failed = match result {
+            Ok(ref completed) => !(terminated || completed.successful()),
+            Err(_) => true,
+        };
+        self.run_state = RunState::PostActive { result, terminated };
+        failed
+    }
+}
+
+/// A controlled network of containers using the Docker API, the API
+/// equivalent of [ContainerNetwork](crate::cli_docker::ContainerNetwork).
+///
+/// Every `SuperNetwork` generates a new UUID for enabling multiple
+/// `SuperNetwork`s from the same base to run concurrently. If created with
+/// [SuperNetwork::new_with_uuid], the UUID is suffixed to the network name and
+/// the container names, containers can still reach each other by their
+/// unsuffixed names because those are added as network aliases.
+///
+/// [SuperNetwork::terminate_all] should be called before dropping, the API
+/// can not be used in `Drop` and containers would be left running.
+#[derive(Debug)]
+pub struct SuperNetwork {
+    uuid: Uuid,
+    network_name: String,
+    suffix_names: bool,
+    network_active: bool,
+    set: BTreeMap<String, ContainerState>,
+    log_dir: String,
+    /// If extra debug output should be enabled
+    pub debug: bool,
+}
+
+impl Drop for SuperNetwork {
+    fn drop(&mut self) {
+        if self.set.values().any(|state| state.is_active()) && (!std::thread::panicking()) {
+            warn!(
+                "A `SuperNetwork` was dropped without all active containers being properly \
+                 terminated"
+            );
+        }
+    }
+}
+
+impl SuperNetwork {
+    /// Creates a new `SuperNetwork`. `network_name` sets the name of the
+    /// docker network that containers will be attached to, and `log_dir` is
+    /// where ".log" log files will be written.
+    ///
+    /// The docker network is only actually created the first time a container
+    /// is run.
+    pub fn new(network_name: impl AsRef<str>, log_dir: impl AsRef<str>) -> Self {
+        Self {
+            uuid: Uuid::new_v4(),
+            network_name: network_name.as_ref().to_owned(),
+            suffix_names: false,
+            network_active: false,
+            set: BTreeMap::new(),
+            log_dir: log_dir.as_ref().to_owned(),
+            debug: false,
+        }
+    }
+
+    /// Same as [SuperNetwork::new], but it adds a UUID suffix to the
+    /// `network_name` and the docker names of the containers
+    pub fn new_with_uuid(network_name: impl AsRef<str>, log_dir: impl AsRef<str>) -> Self {
+        let mut sn = Self::new(network_name, log_dir);
+        sn.network_name = format!("{}_{}", sn.network_name, sn.uuid);
+        sn.suffix_names = true;
+        sn
+    }
+
+    /// Returns the common UUID
+    pub fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    /// Returns the common UUID as a string
+    pub fn uuid_as_string(&self) -> String {
+        self.uuid.to_string()
+    }
+
+    /// Returns the network name
+    pub fn network_name(&self) -> &str {
+        &self.network_name
+    }
+
+    /// Returns the name that docker uses for the container with `name`
+    pub fn docker_name(&self, name: &str) -> String {
+        if self.suffix_names {
+            format!("{name}_{}", self.uuid)
+        } else {
+            name.to_owned()
+        }
+    }
+
+    /// Adds the container to the inactive set
+    pub fn add_container(&mut self, container: SuperContainer) -> Result<&mut Self> {
+        match self.set.entry(container.name.clone()) {
+            Entry::Vacant(v) => {
+                v.insert(ContainerState {
+                    container,
+                    run_state: RunState::PreActive,
+                });
+            }
+            Entry::Occupied(_) => {
+                bail_locationless!(
+                    "SuperNetwork::add_container -> two containers were supplied with the same \
+                     name \"{}\"",
+                    container.name
+                )
+            }
+        }
+        Ok(self)
+    }
+
+    /// Removes the container with `name` from the network, force terminating it
+    /// if it is currently active. Returns `Ok(None)` if the container was never
+    /// activated or was terminated. Returns an error if `name` could not be
+    /// found.
+    pub async fn remove_container(&mut self, name: &str) -> Result<Option<CompletedContainer>> {
+        self.terminate([name]).await;
+        if let Some(state) = self.set.remove(name) {
+            match state.run_state {
+                RunState::PostActive {
+                    result: Ok(completed),
+                    terminated: false,
+                } => Ok(Some(completed)),
+                _ => Ok(None),
+            }
+        } else {
+            bail_locationless!(
+                "SuperNetwork::remove_container -> could not find name \"{name}\" in the network"
+            )
+        }
+    }
+
+    /// Returns the names of active containers
+    pub fn active_names(&self) -> Vec<String> {
+        self.set
+            .iter()
+            .filter(|(_, state)| state.is_active())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Returns the names of inactive containers
+    pub fn inactive_names(&self) -> Vec<String> {
+        self.set
+            .iter()
+            .filter(|(_, state)| !state.is_active())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Force removes any active containers found with the given names
+    pub async fn terminate<I, S>(&mut self, names: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let Ok(docker) = get_or_init_default_docker_instance().await else {
+            return
+        };
+        for name in names {
+            if let Some(state) = self.set.get_mut(name.as_ref()) {
+                let _ = state.deactivate(&docker).await;
+            }
+        }
+    }
+
+    /// Force removes all active containers, but does not remove the docker
+    /// network
+    pub async fn terminate_containers(&mut self) {
+        let Ok(docker) = get_or_init_default_docker_instance().await else {
+            return
+        };
+        for state in self.set.values_mut() {
+            let _ = state.deactivate(&docker).await;
+        }
+    }
+
+    /// Removes the docker network
+    async fn terminate_network(&mut self) {
+        if self.network_active {
+            if let Ok(docker) = get_or_init_default_docker_instance().await {
+                let _ = docker.remove_network(&self.network_name).await;
+            }
+            self.network_active = false;
+        }
+    }
+
+    /// Force removes all active containers and removes the network. The
+    /// `SuperNetwork` can always be safely dropped if this is the last
+    /// function called on it. The network is recreated if any containers are
+    /// run again.
+    pub async fn terminate_all(&mut self) {
+        self.terminate_containers().await;
+        self.terminate_network().await;
+    }
+
+    /// Runs only the given `names`. If an error happens in the middle of
+    /// creating and starting the containers, any of the `names` that had been
+    /// created are removed before the function returns.
+    pub async fn run<I, S>(&mut self, names: I) -> Result<()>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        // avoid polymorphizing such a large function
+        self.run_internal(
+            &names
+                .into_iter()
+                .map(|s| s.as_ref().to_owned())
+                .collect::<Vec<String>>(),
+        )
+        .await
+    }
+
+    async fn run_internal(&mut self, names: &[String]) -> Result<()> {
+        if self.debug {
+            debug!("SuperNetwork::run with UUID {}", self.uuid_as_string());
+        }
+        let mut set = BTreeSet::new();
+        for name in names {
+            if set.contains(name) {
+                bail_locationless!(
+                    "SuperNetwork::run -> two containers were supplied with the same name \
+                     \"{name}\""
+                )
+            }
+            if let Some(state) = self.set.get(name) {
+                if state.is_active() {
+                    bail_locationless!(
+                        "SuperNetwork::run -> name \"{name}\" is already an active container"
+                    )
+                }
+            } else {
+                bail_locationless!(
+                    "SuperNetwork::run -> argument name \"{name}\" is not contained in the network"
+                )
+            }
+            set.insert(name.to_string());
+        }
+
+        // acquire the log files before anything is created
+        let mut log_files = vec![];
+        for name in names {
+            let container = &self.set[name].container;
+            if container.log {
+                let stdout_log = container.stdout_log.clone().unwrap_or_else(|| {
+                    FileOptions::write2(&self.log_dir, format!("{name}_stdout.log"))
+                });
+                let stderr_log = container.stderr_log.clone().unwrap_or_else(|| {
+                    FileOptions::write2(&self.log_dir, format!("{name}_stderr.log"))
+                });
+                log_files.push((
+                    Some(
+                        stdout_log
+                            .acquire_file()
+                            .await
+                            .stack_err_with_locationless(|| {
+                                format!(
+                                    "SuperNetwork::run -> could not acquire stdout log for \
+                                     \"{name}\""
+                                )
+                            })?,
+                    ),
+                    Some(
+                        stderr_log
+                            .acquire_file()
+                            .await
+                            .stack_err_with_locationless(|| {
+                                format!(
+                                    "SuperNetwork::run -> could not acquire stderr log for \
+                                     \"{name}\""
+                                )
+                            })?,
+                    ),
+                ));
+            } else {
+                log_files.push((None, None));
+            }
+        }
+
+        let docker = get_or_init_default_docker_instance().await.stack()?;
+
+        if !self.network_active {
+            docker
+                .create_network(bollard::network::CreateNetworkOptions {
+                    name: self.network_name.clone(),
+                    driver: "bridge".to_string(),
+                    ..Default::default()
+                })
+                .await
+                .stack_err_locationless("SuperNetwork::run -> failed to create network")?;
+            self.network_active = true;
+        }
+
+        // create everything first so that problems are found before anything runs
+        let mut created = vec![];
+        for name in names {
+            let docker_name = self.docker_name(name);
+            let container = &self.set[name].container;
+            let res = self
+                .create_container(&docker, container, &docker_name)
+                .await
+                .stack_err_with_locationless(|| {
+                    format!("SuperNetwork::run when creating the container for name \"{name}\"")
+                });
+            if let Err(e) = res {
+                remove_containers(&docker, &created).await;
+                return Err(e)
+            }
+            created.push(docker_name);
+        }
+
+        if self.debug {
+            debug!("starting");
+        }
+
+        for ((name, docker_name), (stdout_file, stderr_file)) in
+            names.iter().zip(created.iter()).zip(log_files)
+        {
+            let state = self.set.get_mut(name).unwrap();
+            let res = state
+                .container
+                .retry_policy
+                .retry("SuperNetwork::run when starting the container", || {
+                    docker.start_container::<String>(docker_name, None)
+                })
+                .await
+                .stack_err_with_locationless(|| {
+                    format!("SuperNetwork::run when starting the container for name \"{name}\"")
+                });
+            if let Err(e) = res {
+                for name in names {
+                    let _ = self.set.get_mut(name).unwrap().deactivate(&docker).await;
+                }
+                remove_containers(&docker, &created).await;
+                return Err(e)
+            }
+            state.run_state = RunState::Active(ActiveContainer {
+                docker_name: docker_name.clone(),
+                wait: tokio::task::spawn({
+                    let docker_name = docker_name.clone();
+                    async move {
+                        wait_container(&docker_name, Duration::MAX)
+                            .await
+                            .map(|exit| exit.code)
+                    }
+                }),
+                logs: tokio::task::spawn(record_logs(
+                    docker.clone(),
+                    docker_name.clone(),
+                    stdout_file,
+                    stderr_file,
+                )),
+            });
+        }
+
+        if self.debug {
+            debug!("started");
+        }
+
+        Ok(())
+    }
+
+    async fn create_container(
+        &self,
+        docker: &bollard::Docker,
+        container: &SuperContainer,
+        docker_name: &str,
+    ) -> Result<()> {
+        let (exposed_ports, port_bindings) =
+            port_bindings_to_bollard_args(&container.port_bindings).stack()?;
+        let create_opts = bollard::container::CreateContainerOptions {
+            name: docker_name.to_owned(),
+            ..Default::default()
+        };
+        let config = bollard::container::Config {
+            image: Some(container.image.get_image_id().to_string()),
+            hostname: Some(container.name.clone()),
+            cmd: (!container.cmd.is_empty()).then(|| container.cmd.clone()),
+            user: container.user.clone(),
+            env: Some(container.env_vars.clone()),
+            exposed_ports,
+            volumes: Some(
+                container
+                    .volumes
+                    .iter()
+                    .map(|(_, container)| (container.to_string(), Default::default()))
+                    .collect(),
+            ),
+            host_config: Some(bollard::secret::HostConfig {
+                port_bindings,
+                binds: Some(
+                    container
+                        .volumes
+                        .iter()
+                        .map(|(host, container)| format!("{host}:{container}"))
+                        .collect(),
+                ),
+                network_mode: Some(self.network_name.clone()),
+                ..Default::default()
+            }),
+            networking_config: Some(bollard::container::NetworkingConfig {
+                endpoints_config: [(
+                    self.network_name.clone(),
+                    bollard::secret::EndpointSettings {
+                        aliases: Some(vec![container.name.clone()]),
+                        ..Default::default()
+                    },
+                )]
+                .into(),
+            }),
+            // no tty so that stdout and stderr are separate
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            tty: Some(false),
+            ..Default::default()
+        };
+
+        container
+            .retry_policy
+            .retry("SuperNetwork::run when creating the container", || {
+                docker.create_container(Some(create_opts.clone()), config.clone())
+            })
+            .await
+            .stack()?;
+        Ok(())
+    }
+
+    /// [SuperNetwork::run] on all inactive containers in the network. Note
+    /// that terminated containers are recreated.
+    pub async fn run_all(&mut self) -> Result<()> {
+        let names = self.inactive_names();
+        self.run(&names)
+            .await
+            .stack_err_locationless("SuperNetwork::run_all")
+    }
+
+    /// Looks through the results and includes the last "Error:" or
+    /// " panicked at " parts. Checks stderr first and falls back to
+    /// stdout. Omits stacks that have "ProbablyNotRootCauseError". Only the
+    /// ends of the outputs are looked through, so that huge outputs are not
+    /// converted as a whole.
+    fn error_compilation(&mut self) -> Result<()> {
+        let mut res = Error::empty();
+        for (name, state) in self.set.iter() {
+            if let RunState::PostActive {
+                ref result,
+                terminated,
+            } = state.run_state
+            {
+                match result {
+                    Ok(completed) => {
+                        if terminated || completed.successful() {
+                            continue
+                        }
+                        // check stderr
+                        let stderr = record_tail(&completed.stderr);
+                        let mut messages = container_error_messages(name, "stderr", &stderr);
+
+                        // check stdout only if stderr had nothing
+                        if messages.is_empty() {
+                            let stdout = record_tail(&completed.stdout);
+                            messages = container_error_messages(name, "stdout", &stdout);
+                        }
+
+                        if messages.is_empty() {
+                            messages.push(format!(
+                                "Error: Container \"{name}\" was unsuccessful but does not seem \
+                                 to have an error or panic message\n"
+                            ));
+                        }
+                        for message in messages {
+                            res = res.add_err_locationless(message);
+                        }
+                    }
+                    Err(e) => {
+                        res = res.add_err_locationless(format!(
+                            "Error: The internal handling of Container \"{name}\" produced this \
+                             error:\n {e:?}"
+                        ));
+                    }
+                }
+            }
+        }
+        Err(res)
+    }
+
+    /// Waits for the containers with `names` to all complete, or returns if
+    /// `duration` timeout is exceeded.
+    ///
+    /// If `terminate_on_failure`, then if there is a timeout or any
+    /// container from `names` has an error, then the whole network will be
+    /// terminated.
+    ///
+    /// If any container stops with an unsuccessful exit code (not just the
+    /// `names` but any container in the network), this returns an error or
+    /// terminates everything if `terminate_on_failure`. This can be changed by
+    /// setting the `allow_unsuccessful` flag on the desired `SuperContainer`s.
+    ///
+    /// Note that if a CTRL+C/sigterm signal is sent, then
+    /// [terminate_all](SuperNetwork::terminate_all) will be triggered
+    /// internally, to prevent containers from continuing to run in the
+    /// background.
+    ///
+    /// If called with `Duration::ZERO`, this will always complete successfully
+    /// if all containers were terminated before this call.
+    pub async fn wait_with_timeout<I, S>(
+        &mut self,
+        names: I,
+        terminate_on_failure: bool,
+        duration: Duration,
+    ) -> Result<()>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        // avoid polymorphizing
+        self.wait_with_timeout_internal(
+            names
+                .into_iter()
+                .map(|s| s.as_ref().to_owned())
+                .collect::<Vec<String>>(),
+            terminate_on_failure,
+            duration,
+        )
+        .await
+    }
+
+    async fn wait_with_timeout_internal(
+        &mut self,
+        mut names: Vec<String>,
+        terminate_on_failure: bool,
+        duration: Duration,
+    ) -> Result<()> {
+        for name in names.iter() {
+            if let Some(state) = self.set.get(name) {
+                if !state.is_active() {
+                    bail_locationless!(
+                        "SuperNetwork::wait_with_timeout -> name \"{name}\" is already inactive"
+                    );
+                }
+            } else {
+                bail_locationless!(
+                    "SuperNetwork::wait_with_timeout -> name \"{name}\" not found in the network"
+                );
+            }
+        }
+
+        let docker = get_or_init_default_docker_instance().await.stack()?;
+
+        // the loop needs to loop over all active names, but we return when these are
+        // all done
+        let mut target_names: BTreeSet<String> = names.iter().cloned().collect();
+        for active_name in self.active_names() {
+            if !target_names.contains(&active_name) {
+                names.push(active_name);
+            }
+        }
+
+        let start = Instant::now();
+        let ctrlc = CtrlCTask::spawn();
+        let mut skip_fail = true;
+        let mut i = 0;
+        loop {
+            if ctrlc.is_complete() {
+                self.terminate_all().await;
+                bail_locationless!(
+                    "SuperNetwork::wait_with_timeout terminating because of `CTRLC_ISSUED`",
+                )
+            }
+            if target_names.is_empty() {
+                break;
+            }
+            if i >= names.len() {
+                i = 0;
+                let elapsed = Instant::now().saturating_duration_since(start);
+                if elapsed > duration {
+                    if skip_fail {
+                        // give one extra round, this is strong enough for the `Duration::ZERO`
+                        // guarantee
+                        skip_fail = false;
+                    } else {
+                        if terminate_on_failure {
+                            // we put in some extra delay so that the log writers have some
+                            // extra time to finish
+                            sleep(Duration::from_millis(300)).await;
+                            self.terminate_all().await;
+                        }
+                        return Err(Error::timeout().add_err_locationless(format!(
+                            "SuperNetwork::wait_with_timeout timeout waiting for container names \
+                             {target_names:?} to complete"
+                        )));
+                    }
+                } else {
+                    sleep(Duration::from_millis(256)).await;
+                }
+                continue
+            }
+
+            let name = &names[i];
+            let state = self.set.get_mut(name).unwrap();
+            match state.run_state {
+                RunState::Active(ref active) if active.wait.is_finished() => {
+                    let err = state.deactivate(&docker).await;
+                    if terminate_on_failure && err && (!state.container.allow_unsuccessful) {
+                        // give some time for other containers to react, they will be sending
+                        // ProbablyNotRootCause errors and other things
+                        sleep(Duration::from_millis(300)).await;
+                        self.terminate_all().await;
+                        return self.error_compilation().stack_err_locationless(
+                            "SuperNetwork::wait_with_timeout error compilation (check logs for \
+                             more):\n",
+                        );
+                    }
+                    let name = names.remove(i);
+                    target_names.remove(&name);
+                }
+                RunState::Active(_) => {
+                    i += 1;
+                }
+                _ => {
+                    // terminated by something else in the meantime
+                    let name = names.remove(i);
+                    target_names.remove(&name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs [SuperNetwork::wait_with_timeout] on all active containers.
+    pub async fn wait_with_timeout_all(
+        &mut self,
+        terminate_on_failure: bool,
+        duration: Duration,
+    ) -> Result<()> {
+        self.wait_with_timeout(self.active_names(), terminate_on_failure, duration)
+            .await
+    }
+}
+
+/// Removes the containers, ignoring errors
+async fn remove_containers(docker: &bollard::Docker, docker_names: &[String]) {
+    for docker_name in docker_names {
+        let _ = docker
+            .remove_container(
+                docker_name,
+                Some(bollard::container::RemoveContainerOptions {
+                    force: true,
+                    v: true,
+                    ..Default::default()
+                }),
+            )
+            .await;
+    }
+}
+
+/// Follows the logs of the container until it is removed, writing them to the
+/// files and returning them
+async fn record_logs(
+    docker: bollard::Docker,
+    docker_name: String,
+    mut stdout_file: Option<File>,
+    mut stderr_file: Option<File>,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    let mut logs = std::pin::pin!(docker.logs(
+        &docker_name,
+        Some(bollard::container::LogsOptions {
+            follow: true,
+            stdout: true,
+            stderr: true,
+            tail: "all".to_string(),
+            ..Default::default()
+        })
+    ));
+    while let Some(output) = logs.next().await {
+        let (message, file, record) = match output {
+            Ok(LogOutput::StdErr { message }) => (message, stderr_file.as_mut(), &mut stderr),
+            Ok(LogOutput::StdOut { message }) | Ok(LogOutput::Console { message }) => {
+                (message, stdout_file.as_mut(), &mut stdout)
+            }
+            Ok(LogOutput::StdIn { message: _ }) => continue,
+            Err(e) => {
+                // this can happen when the container is removed while following
+                debug!("SuperNetwork -> log stream of container \"{docker_name}\" ended: {e:?}");
+                break
+            }
+        };
+        if let Some(file) = file {
+            file.write_all(&message)
+                .await
+                .stack_err_with_locationless(|| {
+                    format!("SuperNetwork -> when writing logs of container \"{docker_name}\"")
+                })?;
+        }
+        record.extend_from_slice(&message);
+    }
+    for file in [stdout_file, stderr_file].into_iter().flatten() {
+        close_file(file).await.stack()?;
+    }
+    Ok((stdout, stderr))
+}
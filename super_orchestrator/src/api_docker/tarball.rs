@@ -1,20 +1,208 @@
-use std::collections::HashSet;
+use std::{
+    collections::HashSet,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
 
 use stacked_errors::{Result, StackableErr};
 
+/// What a [Tarball] should do when an entry is appended at a path that is
+/// already in the archive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Return an error
+    Error,
+    /// Remove the earlier entry from the archive and keep the new one
+    #[default]
+    ReplaceLast,
+    /// Keep the earlier entry and discard the new one
+    KeepFirst,
+}
+
+/// Overrides for the metadata of an entry added from a host file. Fields left
+/// as `None` use the metadata of the host file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TarEntryOptions {
+    /// The unix access mode, e.g. `0o755`
+    pub mode: Option<u32>,
+    pub uid: Option<u64>,
+    pub gid: Option<u64>,
+    /// Modification time in seconds since the unix epoch
+    pub mtime: Option<u64>,
+}
+
+impl TarEntryOptions {
+    /// Uses `Self::default()`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fields that are `None` in `self` are replaced with the fields from
+    /// `defaults`
+    pub fn or(self, defaults: Self) -> Self {
+        Self {
+            mode: self.mode.or(defaults.mode),
+            uid: self.uid.or(defaults.uid),
+            gid: self.gid.or(defaults.gid),
+            mtime: self.mtime.or(defaults.mtime),
+        }
+    }
+}
+
+/// The default for [Tarball::with_spill_threshold]
+pub const DEFAULT_SPILL_THRESHOLD: u64 = 128 << 20;
+
+/// A reader that can also seek
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// The storage of a [Tarball], which moves from memory to a temporary file
+/// once it grows past the spill threshold. The file is removed on drop.
+struct SpillBuffer {
+    memory: Vec<u8>,
+    file: Option<(std::fs::File, PathBuf)>,
+    spill_threshold: Option<u64>,
+}
+
+impl SpillBuffer {
+    fn new(memory: Vec<u8>, spill_threshold: Option<u64>) -> Self {
+        Self {
+            memory,
+            file: None,
+            spill_threshold,
+        }
+    }
+
+    /// Returns a reader over everything written so far
+    fn reader(&mut self) -> std::io::Result<Box<dyn ReadSeek + '_>> {
+        if let Some((file, _)) = &mut self.file {
+            file.rewind()?;
+            Ok(Box::new(std::io::BufReader::new(file)))
+        } else {
+            Ok(Box::new(std::io::Cursor::new(self.memory.as_slice())))
+        }
+    }
+}
+
+impl Write for SpillBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.file.is_none()
+            && self
+                .spill_threshold
+                .is_some_and(|threshold| (self.memory.len() + buf.len()) as u64 > threshold)
+        {
+            let path = std::env::temp_dir().join(format!(
+                "super_orchestrator_tarball_{}.tar",
+                uuid::Uuid::new_v4()
+            ));
+            let file = std::fs::File::options()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)?;
+            tracing::debug!("Tarball spilling to {path:?}");
+            // set before writing so that the file is removed even on errors
+            self.file = Some((file, path));
+            let (file, _) = self.file.as_mut().unwrap();
+            file.write_all(&self.memory)?;
+            self.memory = Vec::new();
+        }
+        if let Some((file, _)) = &mut self.file {
+            file.write(buf)
+        } else {
+            self.memory.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if let Some((file, _)) = &mut self.file {
+            file.flush()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for SpillBuffer {
+    fn drop(&mut self) {
+        if let Some((_, path)) = self.file.take() {
+            if let Err(e) = std::fs::remove_file(&path) {
+                tracing::warn!("failed to remove temporary tarball file {path:?}: {e}");
+            }
+        }
+    }
+}
+
+/// A finished [Tarball] that can be read from the start any number of times,
+/// e.g. once for each attempt of a build, without reading a spilled tarball
+/// into memory. The spilled file is removed on drop.
+pub(crate) struct FinishedTarball {
+    memory: bytes::Bytes,
+    spill: SpillBuffer,
+}
+
+impl FinishedTarball {
+    fn new(mut spill: SpillBuffer) -> Self {
+        Self {
+            memory: bytes::Bytes::from(std::mem::take(&mut spill.memory)),
+            spill,
+        }
+    }
+
+    /// Returns if the tarball is in a temporary file instead of in memory
+    pub(crate) fn is_spilled(&self) -> bool {
+        self.spill.file.is_some()
+    }
+
+    /// Returns the tarball if it is in memory
+    pub(crate) fn memory(&self) -> Option<&bytes::Bytes> {
+        (!self.is_spilled()).then_some(&self.memory)
+    }
+
+    /// Returns a reader from the start of the tarball, a spilled tarball is
+    /// read through a new handle of its file
+    pub(crate) fn reader(&self) -> std::io::Result<Box<dyn Read + Send>> {
+        if let Some((_, path)) = &self.spill.file {
+            Ok(Box::new(std::io::BufReader::new(std::fs::File::open(
+                path,
+            )?)))
+        } else {
+            Ok(Box::new(std::io::Cursor::new(self.memory.clone())))
+        }
+    }
+
+    /// Reads the whole tarball into memory
+    pub(crate) fn into_bytes(self) -> std::io::Result<Vec<u8>> {
+        if self.is_spilled() {
+            let mut bytes = vec![];
+            self.reader()?.read_to_end(&mut bytes)?;
+            Ok(bytes)
+        } else {
+            Ok(Vec::from(self.memory))
+        }
+    }
+}
+
 /// A tarball for directly placing files in a container at definition time
 pub struct Tarball {
-    tar: tar::Builder<Vec<u8>>,
-    // TODO this was entirely for debug
+    tar: tar::Builder<SpillBuffer>,
+    // used for duplicate detection and debugging
     paths: HashSet<String>,
+    overwrite_policy: OverwritePolicy,
+    follow_symlinks: bool,
+    reproducible: bool,
 }
 
 impl Default for Tarball {
     /// An empty tarball
     fn default() -> Self {
         Self {
-            tar: tar::Builder::new(Vec::new()),
+            tar: tar::Builder::new(SpillBuffer::new(Vec::new(), Some(DEFAULT_SPILL_THRESHOLD))),
             paths: Default::default(),
+            overwrite_policy: Default::default(),
+            follow_symlinks: true,
+            reproducible: false,
         }
     }
 }
@@ -34,32 +222,264 @@ impl std::fmt::Debug for Tarball {
     }
 }
 
+/// Normalizes a path the same way `tar` does when writing it to a header, so
+/// that e.g. "./a/b", "/a/b", and "a/b" are detected as the same entry
+fn normalize_entry_path(path: &str) -> String {
+    Path::new(path)
+        .components()
+        .filter(|component| {
+            !matches!(
+                component,
+                Component::CurDir | Component::RootDir | Component::Prefix(_)
+            )
+        })
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Rewrites the tarball in `source` to `dest` with its entries sorted by path,
+/// with zeroed ownership and modification times, and with modes normalized to
+/// `0o755` for directories and executable files and `0o644` for other files.
+/// Only the headers are kept in memory, the data of each entry is copied from
+/// its position in `source`.
+fn reproducible_tarball(source: &mut SpillBuffer, dest: &mut SpillBuffer) -> Result<()> {
+    let mut entries = vec![];
+    let mut archive = tar::Archive::new(source.reader().stack()?);
+    for entry in archive.entries_with_seek().stack()? {
+        let entry = entry.stack()?;
+        let path = entry.path().stack()?.into_owned();
+        let entry_type = entry.header().entry_type();
+        let mode = entry.header().mode().stack()?;
+        let link_name = entry.link_name().stack()?.map(|link| link.into_owned());
+        let position = entry.raw_file_position();
+        let size = entry.size();
+        let sort_key = normalize_entry_path(&path.to_string_lossy());
+        entries.push((sort_key, path, entry_type, mode, link_name, position, size));
+    }
+    drop(archive);
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut reader = source.reader().stack()?;
+    let mut tar = tar::Builder::new(dest);
+    for (_, path, entry_type, mode, link_name, position, size) in entries {
+        let header = &mut tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(if entry_type.is_symlink() {
+            0o777
+        } else if entry_type.is_dir() || ((mode & 0o111) != 0) {
+            0o755
+        } else {
+            0o644
+        });
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        if let Some(link_name) = link_name {
+            header.set_size(0);
+            tar.append_link(header, path, link_name).stack()?;
+        } else {
+            header.set_size(size);
+            reader.seek(SeekFrom::Start(position)).stack()?;
+            tar.append_data(header, path, (&mut reader).take(size))
+                .stack()?;
+        }
+    }
+    tar.into_inner().stack()?;
+    Ok(())
+}
+
 impl Tarball {
     /// Uses the bytes of an existing tarball, also parsing and checking that
     /// the paths are valid UTF-8
     pub fn new(tarball: Vec<u8>) -> Result<Self> {
-        // rebuild paths (useful for debugging)
+        // rebuild paths for duplicate detection
         let mut archive = tar::Archive::new(std::io::Cursor::new(&tarball));
         let mut paths = HashSet::new();
         for entry in archive.entries().stack()? {
-            paths.insert(
+            paths.insert(normalize_entry_path(
                 entry
                     .stack()?
                     .path()
                     .stack()?
                     .as_os_str()
                     .to_str()
-                    .stack_err("failed to convert os_str to str")?
-                    .to_string(),
-            );
+                    .stack_err("failed to convert os_str to str")?,
+            ));
         }
 
         Ok(Self {
-            tar: tar::Builder::new(tarball),
+            tar: tar::Builder::new(SpillBuffer::new(tarball, Some(DEFAULT_SPILL_THRESHOLD))),
             paths,
+            overwrite_policy: Default::default(),
+            follow_symlinks: true,
+            reproducible: false,
         })
     }
 
+    /// Sets the [OverwritePolicy] used when appending to a path that is
+    /// already in the tarball
+    pub fn with_overwrite_policy(mut self, overwrite_policy: OverwritePolicy) -> Self {
+        self.overwrite_policy = overwrite_policy;
+        self
+    }
+
+    /// Sets the [OverwritePolicy] used when appending to a path that is
+    /// already in the tarball
+    pub fn set_overwrite_policy(&mut self, overwrite_policy: OverwritePolicy) {
+        self.overwrite_policy = overwrite_policy;
+    }
+
+    /// If `true` (the default), symlinks encountered by
+    /// [Tarball::append_dir_all] are dereferenced and the contents they point
+    /// to are archived. If `false`, they are stored as links.
+    pub fn with_follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.set_follow_symlinks(follow_symlinks);
+        self
+    }
+
+    /// If `true` (the default), symlinks encountered by
+    /// [Tarball::append_dir_all] are dereferenced and the contents they point
+    /// to are archived. If `false`, they are stored as links.
+    pub fn set_follow_symlinks(&mut self, follow_symlinks: bool) {
+        self.follow_symlinks = follow_symlinks;
+        self.tar.follow_symlinks(follow_symlinks);
+    }
+
+    /// If `true`, [Tarball::into_tarball] rewrites the archive with the
+    /// entries sorted by path, zero for the owner IDs and modification times,
+    /// and normalized modes, so that the same files always result in the same
+    /// bytes regardless of the host metadata and the order they were appended
+    /// in. The default is `false`.
+    pub fn with_reproducible(mut self, reproducible: bool) -> Self {
+        self.set_reproducible(reproducible);
+        self
+    }
+
+    /// See [Tarball::with_reproducible]
+    pub fn set_reproducible(&mut self, reproducible: bool) {
+        self.reproducible = reproducible;
+    }
+
+    /// Once the tarball grows past `spill_threshold` bytes, it is moved from
+    /// memory to a temporary file (which is removed when the tarball is
+    /// dropped) so that large contexts do not stay in memory while they are
+    /// assembled. `None` keeps the tarball in memory. The default is
+    /// [DEFAULT_SPILL_THRESHOLD].
+    ///
+    /// [SuperDockerfile::build_image](crate::api_docker::SuperDockerfile::build_image)
+    /// streams a spilled tarball from its file when building with the default
+    /// docker instance over a unix socket or an unencrypted http connection,
+    /// otherwise the tarball is read into memory for the build.
+    pub fn with_spill_threshold(mut self, spill_threshold: Option<u64>) -> Self {
+        self.set_spill_threshold(spill_threshold);
+        self
+    }
+
+    /// See [Tarball::with_spill_threshold]
+    pub fn set_spill_threshold(&mut self, spill_threshold: Option<u64>) {
+        self.tar.get_mut().spill_threshold = spill_threshold;
+    }
+
+    /// Returns if the tarball has been moved to a temporary file, see
+    /// [Tarball::with_spill_threshold]
+    pub fn is_spilled(&self) -> bool {
+        self.tar.get_ref().file.is_some()
+    }
+
+    /// Returns if the tarball already has an entry at `path`
+    pub fn contains_path(&self, path: &str) -> bool {
+        self.paths.contains(&normalize_entry_path(path))
+    }
+
+    /// Consults the overwrite policy, returns `false` if the new entry should
+    /// be skipped
+    fn prepare_entry(&mut self, path: &str) -> Result<bool> {
+        let path = normalize_entry_path(path);
+        if !self.paths.contains(&path) {
+            self.paths.insert(path);
+            return Ok(true);
+        }
+        match self.overwrite_policy {
+            OverwritePolicy::Error => Err(format!(
+                "Tarball already has an entry at path \"{path}\" and the overwrite policy is \
+                 `OverwritePolicy::Error`"
+            ))
+            .stack(),
+            OverwritePolicy::ReplaceLast => {
+                self.remove_entry(&path).stack()?;
+                Ok(true)
+            }
+            OverwritePolicy::KeepFirst => {
+                tracing::debug!("Tarball keeping first entry at path \"{path}\"");
+                Ok(false)
+            }
+        }
+    }
+
+    /// Rebuilds the archive without any entries at the normalized `path`
+    fn remove_entry(&mut self, path: &str) -> Result<()> {
+        // entries are complete in the underlying buffer, only the end of archive
+        // blocks are missing which is fine for reading
+        let spill_threshold = self.tar.get_ref().spill_threshold;
+        let old = std::mem::replace(
+            &mut self.tar,
+            tar::Builder::new(SpillBuffer::new(Vec::new(), spill_threshold)),
+        );
+        self.tar.follow_symlinks(self.follow_symlinks);
+        let mut old = old.into_inner().stack()?;
+        let mut archive = tar::Archive::new(old.reader().stack()?);
+        for entry in archive.entries().stack()? {
+            let mut entry = entry.stack()?;
+            let entry_path = entry.path().stack()?.into_owned();
+            if normalize_entry_path(&entry_path.to_string_lossy()) == path {
+                continue;
+            }
+            let mut header = entry.header().clone();
+            if let Some(link_name) = entry.link_name().stack()? {
+                let link_name = link_name.into_owned();
+                self.tar
+                    .append_link(&mut header, entry_path, link_name)
+                    .stack()?;
+            } else {
+                self.tar
+                    .append_data(&mut header, entry_path, &mut entry)
+                    .stack()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends directory entries with a mode of `0o755` for all parent
+    /// directories of `path` that are not in the tarball yet. Like with
+    /// [Tarball::append_dir_all], these do not go through the
+    /// [OverwritePolicy].
+    pub fn append_parent_dirs(&mut self, path: &str) -> Result<()> {
+        let path = normalize_entry_path(path);
+        let Some((parents, _)) = path.rsplit_once('/') else {
+            return Ok(())
+        };
+        let mut parent = String::new();
+        for component in parents.split('/') {
+            if !parent.is_empty() {
+                parent.push('/');
+            }
+            parent.push_str(component);
+            if self.paths.contains(&parent) {
+                continue
+            }
+            self.paths.insert(parent.clone());
+            let header = &mut tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_size(0);
+            header.set_mode(0o755);
+            self.tar
+                .append_data(header, &parent, std::io::empty())
+                .stack_err("Tarball::append_parent_dirs")?;
+        }
+        Ok(())
+    }
+
     /// Append a file that will go to the given `path`, with `mode` and the
     /// bytes of the `content` of the file
     pub fn append_file_bytes(
@@ -68,26 +488,174 @@ impl Tarball {
         mode: u32,
         content: &[u8],
     ) -> Result<()> {
+        let path = path.to_string();
+        if !self.prepare_entry(&path).stack()? {
+            return Ok(());
+        }
         let header = &mut tar::Header::new_gnu();
         header.set_size(content.len() as _);
         header.set_mode(mode);
         header.set_cksum();
+        self.tar.append_data(header, path, content).stack()
+    }
+
+    /// Append a file that will go to the given `path` with the bytes of the
+    /// `content` of the file. Fields of `options` that are `None` default to a
+    /// mode of `0o644` and zero for the others.
+    pub fn append_file_bytes_with(
+        &mut self,
+        path: impl ToString,
+        content: &[u8],
+        options: &TarEntryOptions,
+    ) -> Result<()> {
+        let path = path.to_string();
+        if !self.prepare_entry(&path).stack()? {
+            return Ok(());
+        }
+        let header = &mut tar::Header::new_gnu();
+        header.set_size(content.len() as _);
+        header.set_mode(options.mode.unwrap_or(0o644));
+        header.set_uid(options.uid.unwrap_or(0));
+        header.set_gid(options.gid.unwrap_or(0));
+        header.set_mtime(options.mtime.unwrap_or(0));
+        header.set_cksum();
         self.tar
-            .append_data(header, path.to_string(), content)
-            .stack()
+            .append_data(header, path, content)
+            .stack_err("Tarball::append_file_bytes_with")
     }
 
     /// Uses a `std::fs::File` and its metadata
     pub fn append_file(&mut self, path: impl ToString, file: &mut std::fs::File) -> Result<()> {
         let path = path.to_string();
-        self.paths.insert(path.clone());
+        if !self.prepare_entry(&path).stack()? {
+            return Ok(());
+        }
         self.tar
             .append_file(path, file)
             .stack_err("Tarball::append_file")
     }
 
-    /// Get the bytes of a tarball
+    /// Uses a `std::fs::File` and its metadata, with overrides from `options`
+    pub fn append_file_with(
+        &mut self,
+        path: impl ToString,
+        file: &mut std::fs::File,
+        options: &TarEntryOptions,
+    ) -> Result<()> {
+        let path = path.to_string();
+        if !self.prepare_entry(&path).stack()? {
+            return Ok(());
+        }
+        let metadata = file.metadata().stack_err("Tarball::append_file_with")?;
+        let header = &mut tar::Header::new_gnu();
+        header.set_metadata(&metadata);
+        if let Some(mode) = options.mode {
+            header.set_mode(mode);
+        }
+        if let Some(uid) = options.uid {
+            header.set_uid(uid);
+        }
+        if let Some(gid) = options.gid {
+            header.set_gid(gid);
+        }
+        if let Some(mtime) = options.mtime {
+            header.set_mtime(mtime);
+        }
+        self.tar
+            .append_data(header, path, file)
+            .stack_err("Tarball::append_file_with")
+    }
+
+    /// Recursively appends the directory at `host_dir` and all of its contents
+    /// under `prefix` in the tarball, similar to
+    /// [tar::Builder::append_dir_all]. Symlinks are handled according to
+    /// [Tarball::with_follow_symlinks], and files go through the
+    /// [OverwritePolicy] like with the other `append_*` functions.
+    pub fn append_dir_all(
+        &mut self,
+        prefix: impl ToString,
+        host_dir: impl AsRef<Path>,
+    ) -> Result<()> {
+        let prefix = normalize_entry_path(&prefix.to_string());
+        let host_dir = host_dir.as_ref();
+        if !host_dir.is_dir() {
+            return Err(format!(
+                "Tarball::append_dir_all -> \"{}\" is not a directory",
+                host_dir.display()
+            ))
+            .stack();
+        }
+
+        let mut stack: Vec<(PathBuf, String)> = vec![(host_dir.to_owned(), prefix)];
+        while let Some((src, name)) = stack.pop() {
+            let metadata = if self.follow_symlinks {
+                std::fs::metadata(&src)
+            } else {
+                std::fs::symlink_metadata(&src)
+            }
+            .stack_err_with(|| format!("Tarball::append_dir_all -> when reading {src:?}"))?;
+
+            if metadata.is_dir() {
+                // an empty name is the root when the prefix is empty, duplicate directory
+                // entries are harmless so they do not go through the overwrite policy
+                if !name.is_empty() && !self.paths.contains(&name) {
+                    self.paths.insert(name.clone());
+                    self.tar.append_dir(&name, &src).stack()?;
+                }
+                let mut children = std::fs::read_dir(&src)
+                    .stack_err_with(|| {
+                        format!("Tarball::append_dir_all -> when reading directory {src:?}")
+                    })?
+                    .map(|entry| entry.map(|entry| entry.path()))
+                    .collect::<std::io::Result<Vec<_>>>()
+                    .stack()?;
+                // reverse so that entries are popped in sorted order
+                children.sort_unstable_by(|a, b| b.cmp(a));
+                for child in children {
+                    let file_name = child
+                        .file_name()
+                        .stack()?
+                        .to_str()
+                        .stack_err("failed to convert os_str to str")?
+                        .to_owned();
+                    let child_name = if name.is_empty() {
+                        file_name
+                    } else {
+                        format!("{name}/{file_name}")
+                    };
+                    stack.push((child, child_name));
+                }
+            } else if self.prepare_entry(&name).stack()? {
+                self.tar
+                    .append_path_with_name(&src, &name)
+                    .stack_err_with(|| {
+                        format!("Tarball::append_dir_all -> when appending {src:?}")
+                    })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the bytes of a tarball, this reads the tarball into memory if it
+    /// was spilled to a file
     pub fn into_tarball(self) -> Result<Vec<u8>> {
-        self.tar.into_inner().stack()
+        self.finish()
+            .stack_err("Tarball::into_tarball")?
+            .into_bytes()
+            .stack_err("Tarball::into_tarball")
+    }
+
+    /// Finishes the archive without reading it into memory, the rewrite of a
+    /// reproducible tarball goes through a new spill buffer
+    pub(crate) fn finish(self) -> Result<FinishedTarball> {
+        let spill_threshold = self.tar.get_ref().spill_threshold;
+        let mut tarball = self.tar.into_inner().stack()?;
+        if self.reproducible {
+            let mut rewritten = SpillBuffer::new(Vec::new(), spill_threshold);
+            reproducible_tarball(&mut tarball, &mut rewritten)
+                .stack_err("when rewriting the tarball to be reproducible")?;
+            tarball = rewritten;
+        }
+        Ok(FinishedTarball::new(tarball))
     }
 }
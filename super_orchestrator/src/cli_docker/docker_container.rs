@@ -621,8 +621,12 @@ impl Container {
         stderr_log: Option<&FileOptions>,
     ) -> Result<CommandRunner> {
         let name = &self.name;
+        // if orchestration is cancelled, the attach process should not leak regardless
+        // of the crate wide default
         let mut command = apply_debug(
-            Command::new("docker start --attach").arg(container_id),
+            Command::new("docker start --attach")
+                .arg(container_id)
+                .kill_on_drop(true),
             name,
             self.debug,
         );
@@ -4,11 +4,16 @@ use stacked_errors::{bail, stacked_get, Result, StackableErr};
 use tokio::time::sleep;
 use tracing::{info, warn};
 
-use crate::{sh, wait_for_ok, Command, CtrlCTask};
+use crate::{sh, wait_for_ok, Command, CtrlCTask, FileOptions};
 
 const STD_DELAY: Duration = Duration::from_millis(300);
 const IP_RETRIES: u64 = 10;
 
+/// The number of bytes at the end of each output of a container that the
+/// error compilation of the docker networks looks through, so that it works
+/// with huge logs
+const ERROR_COMPILATION_TAIL: u64 = 64 * 1024;
+
 /// Uses `docker inspect` to find the IP address of the container. There is a
 /// delay between a container starting and an IP address being assigned, which
 /// is why this has a retry mechanism.
@@ -152,3 +157,80 @@ where
     }
     Ok(())
 }
+
+/// Returns the end of the `record` of an output of a container
+pub(crate) fn record_tail(record: &[u8]) -> String {
+    let start = record.len().saturating_sub(ERROR_COMPILATION_TAIL as usize);
+    String::from_utf8_lossy(&record[start..]).into_owned()
+}
+
+/// Returns the end of an output of a container, from the log file if there is
+/// one or else from the `record`
+pub(crate) async fn output_tail(log: Option<&FileOptions>, record: &[u8]) -> String {
+    if let Some(log) = log {
+        if let Ok((tail, _)) = FileOptions::read_tail(&log.path, ERROR_COMPILATION_TAIL).await {
+            return String::from_utf8_lossy(&tail).into_owned()
+        }
+    }
+    record_tail(record)
+}
+
+/// Returns the first "Error:" and " panicked at " parts in `output` (the
+/// "stderr" or "stdout" `stream` of the container `name`), formatted for adding
+/// to an error. Parts that include "ProbablyNotRootCauseError" are omitted.
+/// This is shared by the error compilation of the docker networks.
+pub(crate) fn container_error_messages(name: &str, stream: &str, output: &str) -> Vec<String> {
+    fn contains<'a>(
+        output: &'a str,
+        marker: &str,
+        ignore: &str,
+        find_thread: bool,
+    ) -> Option<&'a str> {
+        // the problem is that some library error types included in the middle of a
+        // error stack have "Error:", I have decided to truncate to the end of the
+        // result if it goes over 10000 bytes
+        const MAX_LEN: usize = 10000;
+
+        let mut output = output;
+        let mut good = false;
+        if let Some(start) = output.find(marker) {
+            if find_thread {
+                // find the "thread" before the "panicked at "
+                if let Some(i) = output[..start].rfind("thread") {
+                    good = true;
+                    output = &output[i..];
+                }
+            } else {
+                good = true;
+                output = &output[start..];
+            }
+        }
+        let mut start = output.len().saturating_sub(MAX_LEN);
+        while !output.is_char_boundary(start) {
+            start += 1;
+        }
+        output = &output[start..];
+        if output.contains(ignore) {
+            good = false
+        }
+        if good {
+            Some(output)
+        } else {
+            None
+        }
+    }
+
+    let not_root_cause = "ProbablyNotRootCauseError";
+    let mut messages = vec![];
+    if let Some(part) = contains(output, "Error:", not_root_cause, false) {
+        messages.push(format!(
+            "Error from container \"{name}\" {stream}:\n{part}\n"
+        ));
+    }
+    if let Some(part) = contains(output, " panicked at ", not_root_cause, true) {
+        messages.push(format!(
+            "Panic message from container \"{name}\" {stream}:\n{part}\n"
+        ));
+    }
+    messages
+}
@@ -11,7 +11,7 @@ use tracing::{debug, warn};
 use uuid::Uuid;
 
 use crate::{
-    cli_docker::{wait_get_ip_addr, Container, Dockerfile},
+    cli_docker::{container_error_messages, output_tail, wait_get_ip_addr, Container, Dockerfile},
     Command, CommandResult, CommandRunner, CtrlCTask, FileOptions,
 };
 
@@ -58,9 +58,16 @@ impl Drop for ContainerState {
 }
 
 impl ContainerState {
-    // returns if there was an error from a `CommandRunner`.
+    // returns if there was an error from a `CommandRunner`. If `graceful` is set,
+    // the runner is terminated with `terminate_graceful` before the container
+    // is removed.
     #[must_use]
-    pub async fn terminate(&mut self) -> bool {
+    pub async fn terminate(&mut self, graceful: Option<Duration>) -> bool {
+        // the attached `docker start` proxies the signal to the container
+        let graceful_res = match (graceful, &mut self.run_state) {
+            (Some(grace), RunState::Active(runner)) => Some(runner.terminate_graceful(grace).await),
+            _ => None,
+        };
         if let Some(id) = self.active_container_id.take() {
             let _ = Command::new("docker rm -f")
                 .arg(id)
@@ -70,7 +77,10 @@ impl ContainerState {
         let state = mem::take(&mut self.run_state);
         match state {
             RunState::PreActive => false,
-            RunState::Active(mut runner) => match runner.terminate().await {
+            RunState::Active(mut runner) => match match graceful_res {
+                Some(res) => res,
+                None => runner.terminate().await,
+            } {
                 Ok(()) => {
                     if let Some(comres) = runner.take_command_result() {
                         let err = !comres.successful();
@@ -145,6 +155,13 @@ pub struct ContainerNetwork {
     pub debug_create: bool,
     /// If extra debug output should be enabled
     pub debug_extra: bool,
+    /// If set, [ContainerNetwork::terminate],
+    /// [ContainerNetwork::terminate_containers], and
+    /// [ContainerNetwork::terminate_all] use
+    /// [CommandRunner::terminate_graceful] with this grace period on the
+    /// runners attached to the containers, which lets the containers handle
+    /// SIGTERM before they are removed
+    pub graceful_termination: Option<Duration>,
     already_tried_drop: bool,
 }
 
@@ -191,7 +208,8 @@ impl ContainerNetwork {
     /// be attached to, `dockerfile_write_dir` is the directory in
     /// which ".tmp.dockerfile" files can be written if `Dockerfile::Contents`
     /// is used (unless the `dockerfile_write_file`s are explicitly set),
-    /// and `log_dir` is where ".log" log files will be written.
+    /// and `log_dir` is where ".log" log files will be written. Both
+    /// directories are created when running if they do not exist.
     ///
     /// The docker network is only actually created the first time a container
     /// is run.
@@ -226,6 +244,7 @@ impl ContainerNetwork {
             debug_build: false,
             debug_create: false,
             debug_extra: false,
+            graceful_termination: None,
             already_tried_drop: false,
         }
     }
@@ -399,7 +418,7 @@ impl ContainerNetwork {
         for name in names {
             let name = name.as_ref();
             if let Some(state) = self.set.get_mut(name) {
-                let _ = state.terminate().await;
+                let _ = state.terminate(self.graceful_termination).await;
             }
         }
     }
@@ -408,7 +427,7 @@ impl ContainerNetwork {
     /// network
     pub async fn terminate_containers(&mut self) {
         for state in self.set.values_mut() {
-            let _ = state.terminate().await;
+            let _ = state.terminate(self.graceful_termination).await;
         }
     }
 
@@ -490,7 +509,8 @@ impl ContainerNetwork {
         let log_file = FileOptions::write2(
             &self.log_dir,
             format!("container_network_{}.log", self.network_name()),
-        );
+        )
+        .create_parents(true);
         log_file
             .preacquire()
             .await
@@ -514,6 +534,7 @@ impl ContainerNetwork {
                             })?;
                     } else if let Some(dir) = &self.dockerfile_write_dir {
                         let path = FileOptions::write2(dir, format!("{name}.tmp.dockerfile"))
+                            .create_parents(true)
                             .preacquire()
                             .await
                             .stack_err_locationless(
@@ -635,7 +656,7 @@ impl ContainerNetwork {
                 Err(e) => {
                     // need to fix all the containers in the intermediate state
                     for name in &names[..i] {
-                        let _ = self.set.get_mut(name).unwrap().terminate().await;
+                        let _ = self.set.get_mut(name).unwrap().terminate(None).await;
                     }
                     e.stack_err_with_locationless(|| {
                         format!(
@@ -652,19 +673,8 @@ impl ContainerNetwork {
 
         // start containers
         for name in names {
+            let (stdout_log, stderr_log) = self.log_files(name);
             let state = self.set.get_mut(name).unwrap();
-            let (stdout_log, stderr_log) = if state.container.log {
-                (
-                    Some(state.container.stdout_log.clone().unwrap_or_else(|| {
-                        FileOptions::write2(&self.log_dir, format!("{name}_stdout.log"))
-                    })),
-                    Some(state.container.stderr_log.clone().unwrap_or_else(|| {
-                        FileOptions::write2(&self.log_dir, format!("{name}_stderr.log"))
-                    })),
-                )
-            } else {
-                (None, None)
-            };
             match state
                 .container()
                 .start(
@@ -681,7 +691,7 @@ impl ContainerNetwork {
                 }
                 Err(e) => {
                     for name in names.iter() {
-                        let _ = self.set.get_mut(name).unwrap().terminate().await;
+                        let _ = self.set.get_mut(name).unwrap().terminate(None).await;
                     }
                     return Err(e);
                 }
@@ -708,50 +718,31 @@ impl ContainerNetwork {
             .stack_err_locationless("ContainerNetwork::run_all")
     }
 
-    /// Looks through the results and includes the last "Error:" or
-    /// " panicked at " parts. Checks stderr first and falls back to
-    /// stdout. Omits stacks that have "ProbablyNotRootCauseError".
-    fn error_compilation(&mut self) -> Result<()> {
-        fn contains<'a>(
-            stderr: &'a str,
-            marker: &str,
-            ignore: &str,
-            find_thread: bool,
-        ) -> Option<&'a str> {
-            // the problem is that some library error types included in the middle of a
-            // error stack have "Error:", I have decided to truncate to the end of the
-            // result if it goes over 10000 characters
-            let mut stderr = stderr;
-            let mut good = false;
-            if let Some(start) = stderr.find(marker) {
-                if find_thread {
-                    // find the "thread" before the "panicked at "
-                    if let Some(i) = stderr[..start].rfind("thread") {
-                        good = true;
-                        stderr = &stderr[i..];
-                    }
-                } else {
-                    good = true;
-                    stderr = &stderr[start..];
-                }
-            }
-            let len = stderr.len();
-            if len > 10000 {
-                stderr = &stderr[(len - 10000)..];
-            }
-            if stderr.contains(ignore) {
-                good = false
-            }
-            if good {
-                Some(stderr)
-            } else {
-                None
-            }
+    /// Returns the stdout and stderr log files of the container with `name`,
+    /// if it is logged
+    fn log_files(&self, name: &str) -> (Option<FileOptions>, Option<FileOptions>) {
+        let container = &self.set[name].container;
+        if !container.log {
+            return (None, None)
         }
+        (
+            Some(container.stdout_log.clone().unwrap_or_else(|| {
+                FileOptions::write2(&self.log_dir, format!("{name}_stdout.log"))
+                    .create_parents(true)
+            })),
+            Some(container.stderr_log.clone().unwrap_or_else(|| {
+                FileOptions::write2(&self.log_dir, format!("{name}_stderr.log"))
+                    .create_parents(true)
+            })),
+        )
+    }
 
-        let not_root_cause = "ProbablyNotRootCauseError";
-        let error_marker = "Error:";
-        let panicked_at = " panicked at ";
+    /// Looks through the results and includes the last "Error:" or
+    /// " panicked at " parts. Checks stderr first and falls back to
+    /// stdout. Omits stacks that have "ProbablyNotRootCauseError". Only the
+    /// ends of the outputs are looked through, which are read from the log
+    /// files if the containers are logged.
+    async fn error_compilation(&mut self) -> Result<()> {
         let mut res = Error::empty();
         for (name, state) in self.set.iter() {
             // TODO not sure if we should have a generation counter to track different sets
@@ -761,59 +752,27 @@ impl ContainerNetwork {
                 match result {
                     Ok(comres) => {
                         if !comres.successful() {
-                            let mut encountered = false;
+                            let (stdout_log, stderr_log) = self.log_files(name);
 
                             // check stderr
-                            let stderr = comres.stderr_as_utf8_lossy();
-
-                            if let Some(stderr) =
-                                contains(&stderr, error_marker, not_root_cause, false)
-                            {
-                                encountered = true;
-                                res = res.add_err_locationless(format!(
-                                    "Error from container \"{name}\" stderr:\n{stderr}\n"
-                                ));
-                            }
-
-                            if let Some(stderr) =
-                                contains(&stderr, panicked_at, not_root_cause, true)
-                            {
-                                encountered = true;
-                                res = res.add_err_locationless(format!(
-                                    "Panic message from container \"{name}\" stderr:\n{stderr}\n"
-                                ));
-                            }
+                            let stderr = output_tail(stderr_log.as_ref(), &comres.stderr).await;
+                            let mut messages = container_error_messages(name, "stderr", &stderr);
 
                             // check stdout only if stderr had nothing
-                            if !encountered {
-                                let stdout = comres.stdout_as_utf8_lossy();
-
-                                if let Some(stdout) =
-                                    contains(&stdout, error_marker, not_root_cause, false)
-                                {
-                                    encountered = true;
-                                    res = res.add_err_locationless(format!(
-                                        "Error from container \"{name}\" stdout:\n{stdout}\n"
-                                    ));
-                                }
-
-                                if let Some(stdout) =
-                                    contains(&stdout, panicked_at, not_root_cause, true)
-                                {
-                                    encountered = true;
-                                    res = res.add_err_locationless(format!(
-                                        "Panic message from container \"{name}\" \
-                                         stdout:\n{stdout}\n"
-                                    ));
-                                }
+                            if messages.is_empty() {
+                                let stdout = output_tail(stdout_log.as_ref(), &comres.stdout).await;
+                                messages = container_error_messages(name, "stdout", &stdout);
                             }
 
-                            if (!encountered) && (!comres.successful_or_terminated()) {
-                                res = res.add_err_locationless(format!(
+                            if messages.is_empty() && (!comres.successful_or_terminated()) {
+                                messages.push(format!(
                                     "Error: Container \"{name}\" was unsuccessful but does not \
                                      seem to have an error or panic message\n"
                                 ));
                             }
+                            for message in messages {
+                                res = res.add_err_locationless(message);
+                            }
                         }
                     }
                     Err(e) => {
@@ -971,7 +930,7 @@ impl ContainerNetwork {
                             // ProbablyNotRootCause errors and other things
                             sleep(Duration::from_millis(300)).await;
                             self.terminate_all().await;
-                            return self.error_compilation().stack_err_locationless(
+                            return self.error_compilation().await.stack_err_locationless(
                                 "ContainerNetwork::wait_with_timeout error compilation (check \
                                  logs for more):\n",
                             );
@@ -989,6 +948,7 @@ impl ContainerNetwork {
                             }
                             return self
                                 .error_compilation()
+                                .await
                                 .stack_err_locationless(
                                     "ContainerNetwork::wait_with_timeout encountered OS-level \
                                      `CommandRunner` error",
@@ -1051,6 +1011,29 @@ impl ContainerNetwork {
         Ok(ip)
     }
 
+    /// Returns the PID of the `docker start --attach` process attached to the
+    /// active container `name`. Note that this is not the PID of the process
+    /// inside the container, but `docker start --attach` forwards the signals
+    /// it receives to the container (see [CommandRunner::send_signal]).
+    pub fn runner_pid(&self, name: &str) -> Result<u32> {
+        let state = self.set.get(name).stack_err_with_locationless(|| {
+            format!(
+                "ContainerNetwork::runner_pid(name: {name}) -> could not find name in container \
+                 network"
+            )
+        })?;
+        if let RunState::Active(ref runner) = state.run_state {
+            runner.pid().stack_err_with_locationless(|| {
+                format!("ContainerNetwork::runner_pid(name: {name}) -> the runner has no PID")
+            })
+        } else {
+            bail_locationless!(
+                "ContainerNetwork::runner_pid(name: {name}) -> found container, but it was not \
+                 active"
+            )
+        }
+    }
+
     /// Sets whether the `Container::build` commands should produce debug output
     pub fn debug_build(&mut self, debug_build: bool) -> &mut Self {
         self.debug_build = debug_build;
@@ -3,36 +3,326 @@ use std::{
     borrow::{Borrow, Cow},
     ffi::{OsStr, OsString},
     fmt::{Debug, Display},
+    future::Future,
     path::{Path, PathBuf},
     process::{ExitStatus, Stdio},
     str::Utf8Error,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
 };
 
 use serde::{Deserialize, Serialize};
-use stacked_errors::{bail_locationless, DisplayStr, Result, StackableErr};
-use tokio::io::AsyncWriteExt;
+use stacked_errors::{bail_locationless, DisplayStr, Error, Result, StackableErr};
+use tokio::{io::AsyncWriteExt, time::sleep};
+use tracing::{warn, Level};
 
-use crate::{command_runner, CommandRunner, FileOptions};
+use crate::{
+    command_runner, file_options::last_lines_start, spawn_detached, CommandPipeline, CommandRunner,
+    DetachedChild, FileOptions,
+};
 
 const DEFAULT_READ_LOOP_TIMEOUT: Duration = Duration::from_millis(300);
+const DEFAULT_ERROR_EXCERPT_LINES: usize = 20;
+/// The maximum size of each output excerpt in `assert_success` errors
+const ERROR_EXCERPT_MAX_BYTES: usize = 4 * 1024;
+
+fn default_error_excerpt_lines() -> usize {
+    DEFAULT_ERROR_EXCERPT_LINES
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// The `kill_on_drop` used by `Command`s that do not set it
+static DEFAULT_KILL_ON_DROP: AtomicBool = AtomicBool::new(true);
+
+/// Sets the crate wide default of [Command::kill_on_drop] for commands that
+/// do not set it, this is initially `true`
+pub fn set_default_kill_on_drop(kill_on_drop: bool) {
+    DEFAULT_KILL_ON_DROP.store(kill_on_drop, Ordering::Relaxed);
+}
+
+/// Returns the crate wide default of [Command::kill_on_drop]
+pub fn default_kill_on_drop() -> bool {
+    DEFAULT_KILL_ON_DROP.load(Ordering::Relaxed)
+}
+
+fn default_debug_level() -> Level {
+    Level::INFO
+}
+
+/// (De)serializes a `tracing::Level` as its name
+mod serde_level {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use tracing::Level;
+
+    pub fn serialize<S: Serializer>(level: &Level, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(level.as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Level, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// A callback for the lines of a standard stream of a running [Command], see
+/// [Command::on_stdout_line]. Clones of a `Command` share the callback.
+pub type LineCallback = Arc<Mutex<dyn FnMut(&str) + Send>>;
+
+/// What happens when a line channel of a [CommandRunner] is full, see
+/// [Command::line_channels]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LineOverflow {
+    /// Reading from the stream waits until there is space in the channel. The
+    /// command itself can block on writing if the pipe to it fills up.
+    Block,
+    /// The oldest line in the channel is dropped to make room
+    DropOldest,
+}
+
+/// What the stdout and stderr records keep once they reach the
+/// `record_limit`, see [Command::max_capture]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CapturePolicy {
+    /// The oldest bytes are dropped, so the record keeps the end of the output
+    #[default]
+    TruncateHead,
+    /// New bytes are dropped, so the record keeps the start of the output
+    TruncateTail,
+    /// The whole record is dropped and nothing more is recorded
+    Discard,
+}
+
+/// Decides if an unsuccessful attempt of a [Command] is retried, see
+/// [Command::retry_if]
+pub type RetryPredicate = Arc<dyn Fn(&CommandResult) -> bool + Send + Sync>;
+
+/// The delays between the attempts of a [Command], see
+/// [Command::retry_backoff]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RetryBackoff {
+    /// The delay before the first retry
+    pub initial: Duration,
+    /// The delay is multiplied by this after every retry
+    pub factor: f64,
+    /// The maximum delay between attempts
+    pub max: Duration,
+}
+
+impl Default for RetryBackoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            factor: 2.0,
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryBackoff {
+    /// The delay after attempt number `attempt` (starting from 0) failed
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = self
+            .factor
+            .max(0.0)
+            .powi(i32::try_from(attempt).unwrap_or(i32::MAX));
+        // `mul_f64` panics on overflow
+        Duration::try_from_secs_f64(self.initial.as_secs_f64() * factor)
+            .unwrap_or(Duration::MAX)
+            .min(self.max)
+    }
+}
+
+/// A standard output stream of a [Command]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl Display for OutputStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputStream::Stdout => f.write_str("stdout"),
+            OutputStream::Stderr => f.write_str("stderr"),
+        }
+    }
+}
+
+/// A chunk of output in the combined record, see [Command::record_combined]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputChunk {
+    /// The stream the chunk was read from
+    pub stream: OutputStream,
+    /// When the chunk was read
+    pub time: SystemTime,
+    pub bytes: Vec<u8>,
+}
+
+/// A resource that can be limited with [Command::rlimit]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RlimitResource {
+    /// `RLIMIT_NOFILE`, one more than the highest file descriptor that can be
+    /// opened
+    NoFile,
+    /// `RLIMIT_AS`, the size of the virtual memory in bytes
+    AddressSpace,
+    /// `RLIMIT_CORE`, the size of core dumps in bytes
+    Core,
+    /// `RLIMIT_CPU`, the CPU time in seconds
+    Cpu,
+    /// `RLIMIT_DATA`, the size of the data segment in bytes
+    Data,
+    /// `RLIMIT_FSIZE`, the size of files that can be written in bytes
+    FileSize,
+    /// `RLIMIT_STACK`, the size of the stack in bytes
+    Stack,
+}
+
+/// Returns the shell operator that appears unescaped outside of quotes in `s`,
+/// if any
+fn find_shell_operator(s: &str) -> Option<&'static str> {
+    let mut quote = None;
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => (),
+            (_, '\\') => {
+                chars.next();
+            }
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => (),
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '|') => return Some("|"),
+            (None, '>') => return Some(">"),
+            (None, '&') if chars.peek() == Some(&'&') => return Some("&&"),
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Splits `s` into words like a POSIX shell, but without any expansion, see
+/// [Command::new] for the grammar. Returns what is wrong if `s` cannot be
+/// split.
+pub fn split_program_with_args(s: &str) -> std::result::Result<Vec<String>, &'static str> {
+    let mut words = vec![];
+    // `None` between words, so that quoted empty strings still make a word
+    let mut word: Option<String> = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("a single quote is not closed"),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => word.push(c),
+                            Some('\n') => (),
+                            // other backslashes are literal in double quotes
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err("a double quote is not closed"),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err("a double quote is not closed"),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                // a line continuation
+                Some('\n') => (),
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err("it ends with a backslash that does not escape anything"),
+            },
+            c if c.is_whitespace() => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
 
 /// An OS Command, this is `tokio::process::Command` wrapped in a bunch of
 /// helping functionality.
+///
+/// # Clone
+///
+/// A partially configured `Command` can be used as a template for several
+/// commands that differ by a few arguments, e.g. `template.clone().arg(name)`
+/// in a loop. Clones share the line callbacks (see [LineCallback]), and the
+/// log `FileOptions` are cloned with the same path. Clones that run at the same
+/// time should get their own log paths or use logs that are opened in append
+/// mode (`FileOptions::new(path, ReadOrWrite::write(true, true))`), otherwise
+/// each run truncates the log that the others are writing to.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Command {
     /// The program to run.
     pub program: OsString,
     /// All the arguments that will be passed to the program
     pub args: Vec<OsString>,
+    /// Set by [Command::new] if it was given shell syntax that would not be
+    /// interpreted, running the command returns this as an error
+    #[serde(default)]
+    pub shell_syntax_error: Option<String>,
     /// If set, the environment variable map is cleared (before the `envs` are
     /// applied)
     pub env_clear: bool,
     /// Environment variable mappings
     pub envs: Vec<(OsString, OsString)>,
     /// Working directory for process. `acquire_dir_path` is used on this in the
-    /// functions that run the `Commanmd`.
+    /// functions that run the `Command`, and the command recorded in the
+    /// `CommandResult` has the canonicalized directory.
     pub cwd: Option<PathBuf>,
+    /// The user ID the process runs as (Unix only), see [Command::uid]
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// The group ID the process runs as (Unix only)
+    #[serde(default)]
+    pub gid: Option<u32>,
+    /// The name of a user whose ID and primary group ID are used where `uid`
+    /// and `gid` are not set, see [Command::user]
+    #[serde(default)]
+    pub user: Option<String>,
+    /// The niceness the process runs with, see [Command::nice]
+    #[serde(default)]
+    pub nice: Option<i32>,
+    /// The resource limits of the process as `(resource, soft, hard)`, see
+    /// [Command::rlimit]
+    #[serde(default)]
+    pub rlimits: Vec<(RlimitResource, u64, u64)>,
+    /// If set, `rlimits` are ignored with a warning where they are not
+    /// supported instead of returning an error
+    #[serde(default)]
+    pub ignore_unsupported_rlimits: bool,
+    /// If set, the process uses the standard streams of this process
+    /// directly, see [Command::inherit_stdio]
+    #[serde(default)]
+    pub inherit_stdio: bool,
+    /// If set, the stdout and stderr are treated as binary data that is not
+    /// processed as lines, see [Command::binary]
+    #[serde(default)]
+    pub binary: bool,
     /// Set to true by default, this enables recording of the `stdout` which can
     /// be accessed from `stdout_record` in the runner or `stdout` in the
     /// command result later
@@ -45,19 +335,56 @@ pub struct Command {
     pub stdout_log: Option<FileOptions>,
     /// If set, the command will copy the `stderr` to the file
     pub stderr_log: Option<FileOptions>,
-    /// Forward stdout to the current process stdout
+    /// If set, the stdout and stderr are also recorded together in the order
+    /// they arrive, see [Command::record_combined]
+    #[serde(default)]
+    pub record_combined: bool,
+    /// If set, the command will copy both the `stdout` and `stderr` to the
+    /// file in the order they arrive
+    #[serde(default)]
+    pub combined_log: Option<FileOptions>,
+    /// Forward stdout lines as `tracing` events, or to the current process
+    /// stdout, see [Command::debug_level]
     pub stdout_debug: bool,
-    /// Forward stderr to the current process stderr
+    /// Forward stderr lines as `tracing` events, or to the current process
+    /// stderr
     pub stderr_debug: bool,
+    /// The level of the `tracing` events from debugging, see
+    /// [Command::debug_level]
+    #[serde(with = "serde_level", default = "default_debug_level")]
+    pub debug_level: Level,
+    /// Debug output is written to the stdout and stderr of this process even
+    /// if there is a `tracing` subscriber
+    pub raw_debug: bool,
     /// If the default stdout debug line prefix should be overridden
     pub stdout_debug_line_prefix: Option<String>,
     /// If the default stderr debug line prefix should be overridden
     pub stderr_debug_line_prefix: Option<String>,
+    /// A label used in the default debug line prefixes instead of the command
+    /// name and process ID, see [Command::terminal_prefix]
+    #[serde(default)]
+    pub terminal_prefix: Option<String>,
+    /// Called with each line of the stdout, see [Command::on_stdout_line]
+    #[serde(skip)]
+    pub stdout_line_callback: Option<LineCallback>,
+    /// Called with each line of the stderr, see [Command::on_stderr_line]
+    #[serde(skip)]
+    pub stderr_line_callback: Option<LineCallback>,
+    /// The capacity and overflow policy of the line channels, see
+    /// [Command::line_channels]
+    pub line_channels: Option<(usize, LineOverflow)>,
+    /// If the most recent lines are kept for [CommandRunner::wait_for_line],
+    /// see [Command::watch_lines]
+    #[serde(default)]
+    pub watch_lines: bool,
     /// Sets a limit on the number of bytes recorded by the stdout and stderr
-    /// records separately, after which the records become circular buffers.
-    /// This limits the potential memory used by a long running command. `None`
-    /// means there is no limit.
+    /// records separately, after which the records become circular buffers
+    /// (or what `capture_policy` says). This limits the potential memory used
+    /// by a long running command. `None` means there is no limit.
     pub record_limit: Option<u64>,
+    /// What happens to the records when they reach the `record_limit`
+    #[serde(default)]
+    pub capture_policy: CapturePolicy,
     /// Sets a limit on the size of log files. Each time the limit is reached,
     /// the file is truncated.
     pub log_limit: Option<u64>,
@@ -67,11 +394,35 @@ pub struct Command {
     /// memory can increase without bound for cases that should be limited. This
     /// defaults to 300 ms.
     pub read_loop_timeout: Duration,
-    /// If `false`, then killing the command on drop is enabled. NOTE: this
-    /// being true or false should not be relied upon in normal program
-    /// operation, `CommandRunner`s should be properly finished so that the
-    /// child process is cleaned up properly.
-    pub forget_on_drop: bool,
+    /// If set, [Command::run_to_completion] and
+    /// [Command::run_with_input_to_completion] terminate the command and
+    /// return a timeout error if it has not completed in time
+    pub timeout: Option<Duration>,
+    /// The number of retries after the first attempt, see
+    /// [Command::retries]
+    #[serde(default)]
+    pub retries: u32,
+    /// The delays between attempts
+    #[serde(default)]
+    pub retry_backoff: RetryBackoff,
+    /// If set, only unsuccessful attempts for which this returns true are
+    /// retried
+    #[serde(skip)]
+    pub retry_if: Option<RetryPredicate>,
+    /// The number of trailing stderr and stdout lines that
+    /// [CommandResult::assert_success] errors include, see
+    /// [Command::error_excerpt_lines]. This defaults to 20.
+    #[serde(default = "default_error_excerpt_lines")]
+    pub error_excerpt_lines: usize,
+    /// Set to true by default, this makes terminating the command also kill
+    /// the processes it started, see [Command::kill_descendants]
+    #[serde(default = "default_true")]
+    pub kill_descendants: bool,
+    /// If the process is killed when its `CommandRunner` is dropped before
+    /// being finished, see [Command::kill_on_drop]. If this is `None`,
+    /// [default_kill_on_drop] is used.
+    #[serde(default)]
+    pub kill_on_drop: Option<bool>,
 }
 
 impl Default for Command {
@@ -79,21 +430,46 @@ impl Default for Command {
         Self {
             program: Default::default(),
             args: Default::default(),
+            shell_syntax_error: None,
             env_clear: Default::default(),
             envs: Default::default(),
             cwd: Default::default(),
+            uid: None,
+            gid: None,
+            user: None,
+            nice: None,
+            rlimits: vec![],
+            ignore_unsupported_rlimits: false,
+            inherit_stdio: false,
+            binary: false,
             stderr_recording: true,
             stdout_recording: true,
             stdout_log: Default::default(),
             stderr_log: Default::default(),
+            record_combined: false,
+            combined_log: None,
             stdout_debug: Default::default(),
             stderr_debug: Default::default(),
+            debug_level: default_debug_level(),
+            raw_debug: false,
             stdout_debug_line_prefix: None,
             stderr_debug_line_prefix: None,
+            terminal_prefix: None,
+            stdout_line_callback: None,
+            stderr_line_callback: None,
+            line_channels: None,
+            watch_lines: false,
             record_limit: Default::default(),
+            capture_policy: Default::default(),
             log_limit: Default::default(),
             read_loop_timeout: DEFAULT_READ_LOOP_TIMEOUT,
-            forget_on_drop: Default::default(),
+            timeout: None,
+            retries: 0,
+            retry_backoff: Default::default(),
+            retry_if: None,
+            error_excerpt_lines: DEFAULT_ERROR_EXCERPT_LINES,
+            kill_descendants: true,
+            kill_on_drop: None,
         }
     }
 }
@@ -113,6 +489,27 @@ impl Debug for Command {
         if let Some(cwd) = &self.cwd {
             f.write_fmt(format_args!(" cwd: {cwd:?},"))?;
         }
+        if let Some(uid) = self.uid {
+            f.write_fmt(format_args!(" uid: {uid},"))?;
+        }
+        if let Some(gid) = self.gid {
+            f.write_fmt(format_args!(" gid: {gid},"))?;
+        }
+        if let Some(user) = &self.user {
+            f.write_fmt(format_args!(" user: {user:?},"))?;
+        }
+        if let Some(nice) = self.nice {
+            f.write_fmt(format_args!(" nice: {nice},"))?;
+        }
+        if !self.rlimits.is_empty() {
+            f.write_fmt(format_args!(" rlimits: {:?},", self.rlimits))?;
+        }
+        if self.inherit_stdio {
+            f.write_fmt(format_args!(" inherit_stdio: true,"))?;
+        }
+        if self.binary {
+            f.write_fmt(format_args!(" binary: true,"))?;
+        }
         // potential accident cases
         if !(self.stdout_recording && self.stderr_recording) {
             f.write_fmt(format_args!(
@@ -126,20 +523,66 @@ impl Debug for Command {
         if let Some(log) = self.stderr_log.as_ref().map(|x| &x.path) {
             f.write_fmt(format_args!(" stderr_log: {log:?},"))?;
         }
+        if self.record_combined {
+            f.write_fmt(format_args!(" record_combined: true,"))?;
+        }
+        if self.watch_lines {
+            f.write_fmt(format_args!(" watch_lines: true,"))?;
+        }
+        if let Some(log) = self.combined_log.as_ref().map(|x| &x.path) {
+            f.write_fmt(format_args!(" combined_log: {log:?},"))?;
+        }
         if self.stdout_debug || self.stderr_debug {
             f.write_fmt(format_args!(
                 " debug: ({}, {}),",
                 self.stdout_debug, self.stderr_debug
             ))?;
+            if self.debug_level != Level::INFO {
+                f.write_fmt(format_args!(" debug_level: {},", self.debug_level))?;
+            }
+            if self.raw_debug {
+                f.write_fmt(format_args!(" raw_debug: true,"))?;
+            }
+            if let Some(label) = &self.terminal_prefix {
+                f.write_fmt(format_args!(" terminal_prefix: {label:?},"))?;
+            }
+        }
+        if self.stdout_line_callback.is_some() || self.stderr_line_callback.is_some() {
+            f.write_fmt(format_args!(
+                " line_callbacks: ({}, {}),",
+                self.stdout_line_callback.is_some(),
+                self.stderr_line_callback.is_some()
+            ))?;
+        }
+        if let Some((capacity, overflow)) = self.line_channels {
+            f.write_fmt(format_args!(" line_channels: ({capacity}, {overflow:?}),"))?;
         }
         if let Some(limit) = self.record_limit {
             f.write_fmt(format_args!(" record_limit: {limit},"))?;
         }
+        if self.capture_policy != CapturePolicy::TruncateHead {
+            f.write_fmt(format_args!(" capture_policy: {:?},", self.capture_policy))?;
+        }
         if let Some(limit) = self.log_limit {
             f.write_fmt(format_args!(" log_limit: {limit},"))?;
         }
-        if self.forget_on_drop {
-            f.write_fmt(format_args!(" forget_on_drop: true,"))?;
+        if let Some(timeout) = self.timeout {
+            f.write_fmt(format_args!(" timeout: {timeout:?},"))?;
+        }
+        if self.retries != 0 {
+            f.write_fmt(format_args!(
+                " retries: {}, retry_backoff: {:?},",
+                self.retries, self.retry_backoff
+            ))?;
+        }
+        if self.error_excerpt_lines != DEFAULT_ERROR_EXCERPT_LINES {
+            f.write_fmt(format_args!(
+                " error_excerpt_lines: {},",
+                self.error_excerpt_lines
+            ))?;
+        }
+        if let Some(kill_on_drop) = self.kill_on_drop {
+            f.write_fmt(format_args!(" kill_on_drop: {kill_on_drop},"))?;
         }
         f.write_fmt(format_args!("}}",))
     }
@@ -159,26 +602,93 @@ impl Command {
     }
 
     /// Creates a `Command` that only sets the `program` and `args` and leaves
-    /// other things as their default values. `program_with_args` is separated
-    /// by whitespace, the first part becomes the progam, and the the others
-    /// are inserted as args.
+    /// other things as their default values. `program_with_args` is split
+    /// into words like a POSIX shell would, the first word becomes the
+    /// program, and the others are inserted as args. The words are separated
+    /// by unquoted whitespace, and
     ///
-    /// In case an argument has spaces, it should be put into `args` as an
-    /// unbroken `&str`. In case the command name has spaces, `self.command`
-    /// can be changed directly.
+    /// - `'...'` is taken literally, it cannot contain a `'`
+    /// - in `"..."`, a backslash escapes `"` and `\`, a backslash before a
+    ///   newline removes both, and other backslashes are literal
+    /// - outside of quotes, a backslash makes the next character literal, and a
+    ///   backslash before a newline removes both
+    /// - quoted and unquoted parts next to each other make one word, e.g.
+    ///   `--label="a b"` becomes `--label=a b`, and `""` is an empty argument
+    ///
+    /// There is no expansion of any kind (`$VAR`, `~`, `*`, etc. are passed
+    /// as they are). The [split_program_with_args] function has the same
+    /// splitting. If a quote is not closed or `program_with_args` ends with a
+    /// lone backslash, running the command returns an error.
+    ///
+    /// Because of the escapes, Windows paths with backslashes need to be in
+    /// single quotes or be used with [Command::new_raw]. Arguments from
+    /// elsewhere are best passed with [Command::arg], which needs no quoting.
+    ///
+    /// There is no shell interpretation, if `program_with_args` has a `|`,
+    /// `&&`, or `>` outside of quotes, running the command returns an error
+    /// pointing to [Command::shell].
     pub fn new(program_with_args: impl AsRef<str>) -> Self {
-        let mut program = String::new();
-        let mut args: Vec<OsString> = vec![];
-        for (i, part) in program_with_args.as_ref().split_whitespace().enumerate() {
-            if i == 0 {
-                part.clone_into(&mut program)
-            } else {
-                args.push(part.into());
+        let program_with_args = program_with_args.as_ref();
+        match split_program_with_args(program_with_args) {
+            Ok(words) => Self::from_words(program_with_args, words),
+            Err(e) => {
+                let mut command = Self::new_raw(program_with_args);
+                command.shell_syntax_error = Some(format!(
+                    "`Command::new` was given {program_with_args:?} which could not be split into \
+                     arguments because {e}"
+                ));
+                command
             }
         }
+    }
+
+    /// The same as [Command::new] except that `program_with_args` is only
+    /// separated by whitespace, quotes and backslashes are kept as they are.
+    /// This is how [Command::new] split its input before it handled quotes.
+    pub fn new_raw(program_with_args: impl AsRef<str>) -> Self {
+        let program_with_args = program_with_args.as_ref();
+        Self::from_words(
+            program_with_args,
+            program_with_args
+                .split_whitespace()
+                .map(str::to_owned)
+                .collect(),
+        )
+    }
+
+    fn from_words(program_with_args: &str, words: Vec<String>) -> Self {
+        let mut words = words.into_iter();
+        let program = words.next().unwrap_or_default();
+        let args = words.map(OsString::from).collect();
+        let shell_syntax_error = find_shell_operator(program_with_args).map(|operator| {
+            format!(
+                "`Command::new` was given {program_with_args:?} which has the shell operator \
+                 `{operator}`, but there is no shell to interpret it. Use `Command::shell` to run \
+                 it with a shell, or pass the argument with `Command::arg`"
+            )
+        });
         Self {
             program: program.into(),
             args,
+            shell_syntax_error,
+            ..Default::default()
+        }
+    }
+
+    /// Creates a `Command` that runs `script` with the system shell, `sh -c`
+    /// (or `cmd /C` on Windows), so that pipes, globs, `&&`, and redirection
+    /// work. Prefer [Command::new] or [Command::new_os_str] with explicit
+    /// arguments when a shell is not needed, since quoting is then not a
+    /// concern.
+    pub fn shell(script: impl AsRef<str>) -> Self {
+        let (program, flag) = if cfg!(windows) {
+            ("cmd", "/C")
+        } else {
+            ("sh", "-c")
+        };
+        Self {
+            program: program.into(),
+            args: vec![flag.into(), script.as_ref().into()],
             ..Default::default()
         }
     }
@@ -200,12 +710,83 @@ impl Command {
         self
     }
 
-    /// Sets `self.cwd`
+    /// Sets `self.cwd`, the working directory that the program is run in. The
+    /// directory is checked when the command is run, returning an error naming
+    /// it if it does not exist.
     pub fn cwd(mut self, cwd: impl AsRef<Path>) -> Self {
         self.cwd = Some(cwd.as_ref().to_owned());
         self
     }
 
+    /// Sets `uid`, the user ID that the process runs as. This needs the
+    /// privileges to change the user (e.g. running as root), otherwise running
+    /// the command returns an error.
+    ///
+    /// Only the IDs change, the environment is inherited (or set with
+    /// `env_clear` and `envs`) as usual, so `HOME` and `USER` are still those
+    /// of this process unless they are set explicitly. The working directory
+    /// is entered after the IDs change, so the `cwd` (or the current directory
+    /// if it is not set) must be accessible to the user.
+    #[cfg(unix)]
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Sets `gid`, the group ID that the process runs as, see [Command::uid]
+    #[cfg(unix)]
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Sets `user`. When the command is run, the user is looked up and its
+    /// user ID and primary group ID are used for `uid` and `gid` unless they
+    /// are set explicitly, returning an error if there is no such user. See
+    /// [Command::uid] for the interaction with the environment and `cwd`.
+    #[cfg(all(unix, feature = "nix_support"))]
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    /// Sets `nice`, the niceness that the process runs with, from -20 (the
+    /// highest priority) to 19 (the lowest priority), e.g. so that a load
+    /// generator does not starve the rest of a test. On Unix this is applied
+    /// with `setpriority` before the program is executed, and lowering the
+    /// niceness below that of this process requires privileges, otherwise
+    /// running the command returns an error.
+    ///
+    /// On Windows, this is mapped to the closest priority class, from
+    /// `IDLE_PRIORITY_CLASS` for 15 and above to `HIGH_PRIORITY_CLASS` for -15
+    /// and below (the realtime class is never used).
+    #[cfg(any(windows, all(unix, feature = "nix_support")))]
+    pub fn nice(mut self, nice: i32) -> Self {
+        self.nice = Some(nice);
+        self
+    }
+
+    /// Limits the `resource` of the process to the `soft` limit, which the
+    /// process can raise up to the `hard` limit. `u64::MAX` is no limit. The
+    /// limits are applied with `setrlimit` before the program is executed,
+    /// and raising a hard limit above that of this process requires
+    /// privileges, otherwise running the command returns an error.
+    ///
+    /// This is only supported on Unix with the "nix_support" feature,
+    /// elsewhere running the command returns an error unless
+    /// [Command::ignore_unsupported_rlimits] is set.
+    pub fn rlimit(mut self, resource: RlimitResource, soft: u64, hard: u64) -> Self {
+        self.rlimits.push((resource, soft, hard));
+        self
+    }
+
+    /// Sets `ignore_unsupported_rlimits`, if set the [Command::rlimit]s are
+    /// ignored with a warning on platforms that do not support them
+    pub fn ignore_unsupported_rlimits(mut self, ignore_unsupported_rlimits: bool) -> Self {
+        self.ignore_unsupported_rlimits = ignore_unsupported_rlimits;
+        self
+    }
+
     /// Set if environment variables should be cleared
     pub fn env_clear(mut self, env_clear: bool) -> Self {
         self.env_clear = env_clear;
@@ -234,13 +815,34 @@ impl Command {
     }
 
     /// Sets `stdout_debug` and `stderr_debug` for passing command standard
-    /// streams to the standard streams of this process.
+    /// streams to `tracing` or the standard streams of this process, see
+    /// [Command::debug_level].
     pub fn debug(mut self, std_stream_debug: bool) -> Self {
         self.stdout_debug = std_stream_debug;
         self.stderr_debug = std_stream_debug;
         self
     }
 
+    /// Sets `debug_level`. If debugging is enabled and a `tracing` subscriber
+    /// is active when the command is run, the command line, each line of the
+    /// debugged streams, and the exit status are emitted as events at this
+    /// level (INFO by default) under the "super_orchestrator::command"
+    /// target. Otherwise, the lines are written to the stdout and stderr of
+    /// this process like before. The line events have a `command` field with
+    /// the line prefix, which is colored if the stdout is a terminal, and a
+    /// `stream` field.
+    pub fn debug_level(mut self, debug_level: Level) -> Self {
+        self.debug_level = debug_level;
+        self
+    }
+
+    /// Sets `raw_debug`, which makes debug output always be written directly
+    /// to the stdout and stderr of this process instead of `tracing`
+    pub fn raw_debug(mut self, raw_debug: bool) -> Self {
+        self.raw_debug = raw_debug;
+        self
+    }
+
     /// Sets `stdout_recording`
     pub fn stdout_recording(mut self, stdout_recording: bool) -> Self {
         self.stdout_recording = stdout_recording;
@@ -253,6 +855,35 @@ impl Command {
         self
     }
 
+    /// Sets `inherit_stdio`. If set, the process uses the stdin, stdout, and
+    /// stderr of this process directly, which is needed for interactive
+    /// programs. Nothing is captured, so the recording, debug, callback, and
+    /// line channel settings have no effect and the `stdout` and `stderr` of
+    /// the `CommandResult` are empty (see [CommandResult::stdio_inherited]),
+    /// but the exit status and timing are still recorded. The stdin given to
+    /// [Command::run_with_stdin] is not used, and
+    /// [Command::run_with_input_to_completion] cannot be used. Running the
+    /// command returns an error if `stdout_log` or `stderr_log` is set.
+    pub fn inherit_stdio(mut self, inherit_stdio: bool) -> Self {
+        self.inherit_stdio = inherit_stdio;
+        self
+    }
+
+    /// Sets `binary`, for commands that output binary data such as tar streams.
+    /// The records and logs always keep the exact bytes, this additionally
+    /// turns off all processing of the output as lines, so that no time or
+    /// memory is spent on it. Running the command returns an error if
+    /// `stdout_debug`, `stderr_debug`, line callbacks, `line_channels`, or
+    /// `watch_lines` are set. The `Debug`
+    /// output of the `CommandResult` and the errors from
+    /// [CommandResult::assert_success] show the number of bytes of the output
+    /// instead of the output. Use [CommandResult::stdout_bytes] and
+    /// [CommandResult::stderr_bytes] to get the output.
+    pub fn binary(mut self, binary: bool) -> Self {
+        self.binary = binary;
+        self
+    }
+
     /// Sets `stdout_recording` and `stderr_recording`
     pub fn recording(mut self, recording: bool) -> Self {
         self.stdout_recording = recording;
@@ -297,12 +928,94 @@ impl Command {
         self
     }
 
+    /// Sets `record_combined`. If set, the output of both streams is also
+    /// recorded in the order it arrives, with each chunk tagged with its
+    /// stream and the time it was read, see [CommandResult::combined_chunks].
+    /// This is for finding out the relative order of the stdout and stderr,
+    /// the separate records are unchanged. The `record_limit` applies
+    /// separately to the combined record, which keeps the end of the output.
+    ///
+    /// Note that the order is only as precise as the reading of the two pipes,
+    /// output written at nearly the same time can still arrive out of order.
+    pub fn record_combined(mut self, record_combined: bool) -> Self {
+        self.record_combined = record_combined;
+        self
+    }
+
+    /// Sets `combined_log` for copying both the stdout and stderr to a file in
+    /// the order they arrive, like `> file 2>&1` in a shell. The `log_limit`
+    /// also applies to this file.
+    pub fn combined_log<F: Borrow<FileOptions>>(mut self, combined_log: Option<F>) -> Self {
+        self.combined_log = combined_log.map(|f| f.borrow().clone());
+        self
+    }
+
+    /// Sets `stdout_line_callback`, which is called with each complete line of
+    /// the stdout as it is read (without the "\n" or "\r\n" terminator, and
+    /// with invalid UTF-8 replaced). A final line without a terminator is
+    /// passed when the stream ends. This works alongside recording, logging,
+    /// and debug forwarding.
+    ///
+    /// The callback is called from the task that copies the stream, so it
+    /// should not block. If it panics, it is not called again and the panic
+    /// is returned as an error when the runner is waited on.
+    pub fn on_stdout_line(mut self, callback: impl FnMut(&str) + Send + 'static) -> Self {
+        self.stdout_line_callback = Some(Arc::new(Mutex::new(callback)));
+        self
+    }
+
+    /// Sets `stderr_line_callback`, see [Command::on_stdout_line]
+    pub fn on_stderr_line(mut self, callback: impl FnMut(&str) + Send + 'static) -> Self {
+        self.stderr_line_callback = Some(Arc::new(Mutex::new(callback)));
+        self
+    }
+
+    /// Sets `line_channels`, which makes the [CommandRunner] send the lines of
+    /// the stdout and stderr to channels that can be taken with
+    /// [CommandRunner::stdout_lines] and [CommandRunner::stderr_lines]. The
+    /// lines are like those of [Command::on_stdout_line], and the channels
+    /// close when the streams end.
+    ///
+    /// Each channel holds up to `capacity` lines (at least 1) and `overflow`
+    /// decides what happens to further lines while it is full. With
+    /// [LineOverflow::DropOldest], one more line can be in transit. Channels
+    /// that have not been taken are dropped when the runner is waited on or
+    /// terminated.
+    pub fn line_channels(mut self, capacity: usize, overflow: LineOverflow) -> Self {
+        self.line_channels = Some((capacity, overflow));
+        self
+    }
+
+    /// Sets `watch_lines`. If set, the most recent lines of the stdout and
+    /// stderr are kept for [CommandRunner::wait_for_line], up to a MiB per
+    /// stream or the `record_limit` if it is smaller. With
+    /// [CapturePolicy::Discard], all but the newest line are dropped once the
+    /// limit is reached. The streams are read even if nothing else is set for
+    /// them.
+    pub fn watch_lines(mut self, watch_lines: bool) -> Self {
+        self.watch_lines = watch_lines;
+        self
+    }
+
     /// Sets `record_limit` for limiting stdout and stderr record byte lengths
     pub fn record_limit(mut self, record_limit: Option<u64>) -> Self {
         self.record_limit = record_limit;
         self
     }
 
+    /// Bounds the in-memory stdout and stderr records (and thus the
+    /// `CommandResult`) to `max_bytes` each, with `policy` deciding which
+    /// bytes are kept. This sets `record_limit` and `capture_policy`. Log files
+    /// are not affected by this, only by `log_limit`.
+    ///
+    /// The number of bytes that were dropped is recorded in
+    /// `CommandResult::stdout_dropped_bytes` and `stderr_dropped_bytes`.
+    pub fn max_capture(mut self, max_bytes: u64, policy: CapturePolicy) -> Self {
+        self.record_limit = Some(max_bytes);
+        self.capture_policy = policy;
+        self
+    }
+
     /// Sets `log_limit` for limiting stdout and stderr log file byte lengths
     pub fn log_limit(mut self, log_limit: Option<u64>) -> Self {
         self.log_limit = log_limit;
@@ -322,9 +1035,98 @@ impl Command {
         self
     }
 
-    /// Sets `forget_on_drop`
+    /// Sets `timeout`, see [CommandRunner::wait_with_output_timeout] for what
+    /// happens when it is exceeded
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets `retries`. If nonzero, [Command::run_to_completion] and
+    /// [Command::run_with_input_to_completion] run the command again (waiting
+    /// according to the `retry_backoff` in between) if it could not be
+    /// spawned, timed out, or was unsuccessful, up to `retries` more times.
+    ///
+    /// Note that when `retries` is nonzero, an unsuccessful final attempt is
+    /// returned as an error with the `assert_success` errors of every
+    /// attempt, rather than as an unsuccessful `CommandResult`. Line
+    /// callbacks are shared between the attempts, and logs are reopened with
+    /// their `FileOptions` for every attempt.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Sets `retry_backoff`. The delay before the first retry is `initial`,
+    /// and it is multiplied by `factor` after every retry up to `max`. This
+    /// defaults to 1 second doubling up to 30 seconds.
+    pub fn retry_backoff(mut self, initial: Duration, factor: f64, max: Duration) -> Self {
+        self.retry_backoff = RetryBackoff {
+            initial,
+            factor,
+            max,
+        };
+        self
+    }
+
+    /// Sets `retry_if`, only the unsuccessful attempts for which `f` returns
+    /// true are retried (e.g. to only retry a specific exit code). Attempts
+    /// that fail with an error (e.g. a spawn failure or timeout) are always
+    /// retried.
+    pub fn retry_if<F: Fn(&CommandResult) -> bool + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.retry_if = Some(Arc::new(f));
+        self
+    }
+
+    /// Sets `error_excerpt_lines`. When the `CommandResult` is not
+    /// successful, the error from `assert_success` includes the exit status
+    /// and up to this many of the last lines of the stderr and stdout (each
+    /// excerpt is also capped at 4 KiB), instead of the whole output. Setting
+    /// this to 0 disables the excerpts.
+    pub fn error_excerpt_lines(mut self, error_excerpt_lines: usize) -> Self {
+        self.error_excerpt_lines = error_excerpt_lines;
+        self
+    }
+
+    /// Sets `kill_descendants`, which is true by default. On Unix, the command
+    /// is spawned in its own process group, and [CommandRunner::terminate]
+    /// and [CommandRunner::terminate_graceful] signal the whole group so that
+    /// background processes started by the command (e.g. the children of an
+    /// entrypoint script) do not outlive it. On Windows, `taskkill /T` is used
+    /// to kill the process tree, which is found through the parent process
+    /// IDs, so descendants whose parent has already exited are not found.
+    ///
+    /// Note that because of the separate process group, a "Ctrl-C" in the
+    /// terminal does not reach the command directly, and killing on drop
+    /// (see [Command::kill_on_drop]) only kills the direct child. If this is
+    /// false, only the direct child is signaled.
+    pub fn kill_descendants(mut self, kill_descendants: bool) -> Self {
+        self.kill_descendants = kill_descendants;
+        self
+    }
+
+    /// Sets `kill_on_drop`. If set, the process is killed when its
+    /// `CommandRunner` is dropped without being finished, e.g. when a future
+    /// holding the runner is cancelled by a timeout or an aborted task.
+    /// Otherwise the process keeps running with nothing waiting on it. If this
+    /// is not called, [default_kill_on_drop] is used, which can be changed
+    /// with [set_default_kill_on_drop].
+    ///
+    /// This only applies to dropping, after [CommandRunner::terminate] or any
+    /// of the waiting functions have finished the runner there is no process
+    /// left to kill. Like for dropping a `tokio::process::Child`, only the
+    /// direct child is killed, not the processes it started (see
+    /// [Command::kill_descendants]). Runners should still be finished
+    /// properly where possible.
+    pub fn kill_on_drop(mut self, kill_on_drop: bool) -> Self {
+        self.kill_on_drop = Some(kill_on_drop);
+        self
+    }
+
+    /// The opposite of [Command::kill_on_drop], sets `kill_on_drop` to
+    /// `!forget_on_drop`
     pub fn forget_on_drop(mut self, forget_on_drop: bool) -> Self {
-        self.forget_on_drop = forget_on_drop;
+        self.kill_on_drop = Some(!forget_on_drop);
         self
     }
 
@@ -342,6 +1144,23 @@ impl Command {
         self
     }
 
+    /// Labels the debug output of the command, each forwarded line starts with
+    /// `[label]` (followed by `E|` for stderr lines) instead of the command
+    /// name and process ID, colored the same way as the default prefix. This
+    /// only changes the output from [Command::debug], the logs are not
+    /// prefixed. Use [Command::stdout_debug_line_prefix] and
+    /// [Command::stderr_debug_line_prefix] for completely custom prefixes.
+    ///
+    /// With a label, forwarded lines are written whole, so that the lines of
+    /// concurrent commands stay readable even if the child writes in odd
+    /// chunks. An incomplete line is written when it is completed or when the
+    /// stream ends. Without one, the output is forwarded as it is read, so
+    /// that e.g. progress output using `\r` is shown.
+    pub fn terminal_prefix(mut self, label: impl Into<String>) -> Self {
+        self.terminal_prefix = Some(label.into());
+        self
+    }
+
     /// Gets the program and args interspersed with spaces
     pub(crate) fn get_unified_command(&self) -> String {
         let mut command = self.program.to_string_lossy().into_owned();
@@ -362,35 +1181,148 @@ impl Command {
         command_runner(self, stdin_cfg).await
     }
 
+    /// Creates a [CommandPipeline] that pipes the stdout of `self` into the
+    /// stdin of `next`, like `self | next` in a shell
+    pub fn pipe_to(self, next: Command) -> CommandPipeline {
+        CommandPipeline::new(vec![self, next])
+    }
+
     /// Calls [Command::run_with_stdin] with `Stdio::null()`
     pub async fn run(self) -> Result<CommandRunner> {
         self.run_with_stdin(Stdio::null()).await
     }
 
+    /// Starts the command detached from this process, so that it can outlive
+    /// it (e.g. a helper daemon used across several test runs). There is no
+    /// `CommandRunner` and nothing will terminate the process, use the
+    /// returned PID to manage it.
+    ///
+    /// The stdin is null, and the stdout and stderr go directly to the
+    /// `stdout_log` and `stderr_log` files if they are set (`log_limit` is not
+    /// applied) or are discarded otherwise. The recording, debug, callback,
+    /// line channel, timeout, and retry settings are not used. On Unix, the
+    /// process is started in a new session with `setsid` (or only in its own
+    /// process group without the "nix_support" feature), and on Windows, it is
+    /// started with `DETACHED_PROCESS`.
+    pub async fn spawn_detached(self) -> Result<DetachedChild> {
+        spawn_detached(self).await
+    }
+
     /// Calls [Command::run] and waits for it to complete, returning the command
-    /// result
+    /// result. If `timeout` is set, [CommandRunner::wait_with_output_timeout]
+    /// is used. If `retries` is set, the command is retried, see
+    /// [Command::retries].
     pub async fn run_to_completion(self) -> Result<CommandResult> {
-        self.run()
-            .await
-            .stack_err_locationless("Command::run_to_completion")?
-            .wait_with_output()
-            .await
+        self.run_with_retries("Command::run_to_completion", |command| {
+            command.run_once_to_completion()
+        })
+        .await
     }
 
     /// Same as [Command::run_to_completion] except it pipes `input` to the
     /// process stdin
     pub async fn run_with_input_to_completion(self, input: &[u8]) -> Result<CommandResult> {
+        self.run_with_retries("Command::run_with_input_to_completion", |command| {
+            command.run_once_with_input_to_completion(input)
+        })
+        .await
+    }
+
+    /// Calls `attempt` with clones of `self` according to `retries`
+    async fn run_with_retries<F, Fut>(self, operation: &str, attempt: F) -> Result<CommandResult>
+    where
+        F: Fn(Command) -> Fut,
+        Fut: Future<Output = Result<CommandResult>>,
+    {
+        if self.retries == 0 {
+            return attempt(self).await
+        }
+        let mut errors: Option<Error> = None;
+        for i in 0..=self.retries {
+            let (retry, e) = match attempt(self.clone()).await {
+                Ok(result) => {
+                    if result.successful() {
+                        return Ok(result)
+                    }
+                    let retry = self.retry_if.as_ref().is_none_or(|f| f(&result));
+                    (retry, result.assert_success().unwrap_err())
+                }
+                Err(e) => (true, e),
+            };
+            let e = e.add_err_locationless(format!("attempt {} of {}", i + 1, self.retries + 1));
+            errors = Some(match errors {
+                Some(errors) => errors.chain_errors(e),
+                None => e,
+            });
+            if (!retry) || (i == self.retries) {
+                break
+            }
+            let backoff = self.retry_backoff.backoff(i);
+            warn!(
+                "{operation} attempt {}/{} of {} failed, retrying in {backoff:?}",
+                i + 1,
+                self.retries + 1,
+                self.get_unified_command()
+            );
+            sleep(backoff).await;
+        }
+        Err(errors.unwrap()).stack_err_with_locationless(|| {
+            format!(
+                "{operation} -> all attempts of {} were unsuccessful",
+                self.get_unified_command()
+            )
+        })
+    }
+
+    /// One attempt of [Command::run_to_completion]
+    async fn run_once_to_completion(self) -> Result<CommandResult> {
+        let timeout = self.timeout;
+        let runner = self
+            .run()
+            .await
+            .stack_err_locationless("Command::run_to_completion")?;
+        if let Some(timeout) = timeout {
+            runner.wait_with_output_timeout(timeout).await
+        } else {
+            runner.wait_with_output().await
+        }
+    }
+
+    /// One attempt of [Command::run_with_input_to_completion]
+    async fn run_once_with_input_to_completion(self, input: &[u8]) -> Result<CommandResult> {
+        if self.inherit_stdio {
+            bail_locationless!(
+                "{self:?}.run_with_input_to_completion() -> cannot pipe input to a command with \
+                 `inherit_stdio`"
+            )
+        }
+        let timeout = self.timeout;
         let mut runner = self
             .run_with_stdin(Stdio::piped())
             .await
             .stack_err_locationless("Command::run_with_input_to_completion")?;
         let mut stdin = runner.child_process.as_mut().unwrap().stdin.take().unwrap();
-        stdin.write_all(input).await.stack_err_locationless(
+        // the input is written while waiting, so that the timeout also applies to a
+        // command that does not read its stdin
+        let write = async move {
+            let res = stdin.write_all(input).await;
+            // needs to close to actually finish
+            drop(stdin);
+            res
+        };
+        let wait = async move {
+            if let Some(timeout) = timeout {
+                runner.wait_with_output_timeout(timeout).await
+            } else {
+                runner.wait_with_output().await
+            }
+        };
+        let (write_res, res) = tokio::join!(write, wait);
+        let res = res?;
+        write_res.stack_err_locationless(
             "Command::run_with_input_to_completion -> failed to write_all to process stdin",
         )?;
-        // needs to close to actually finish
-        drop(stdin);
-        runner.wait_with_output().await
+        Ok(res)
     }
 }
 
@@ -403,14 +1335,126 @@ pub struct CommandResult {
     pub status: Option<ExitStatus>,
     pub stdout: Vec<u8>,
     pub stderr: Vec<u8>,
+    /// Set if the command was stopped with
+    /// [CommandRunner::terminate_graceful] and exited within the grace period
+    /// instead of being killed
+    pub terminated_gracefully: bool,
+    /// The number of stdout bytes that were not kept because of the
+    /// `record_limit`, see [Command::max_capture]
+    pub stdout_dropped_bytes: u64,
+    /// The number of stderr bytes that were not kept because of the
+    /// `record_limit`
+    pub stderr_dropped_bytes: u64,
+    /// When the command was spawned (this and `finished_at` are only `None`
+    /// for a `CommandResult` that was not created by a `CommandRunner`)
+    pub started_at: Option<SystemTime>,
+    /// When the exit (or termination) of the command was observed
+    pub finished_at: Option<SystemTime>,
+    /// How long the command ran, measured with a monotonic clock
+    pub duration: Duration,
+    /// The output of both streams in the order it arrived if
+    /// [Command::record_combined] was set
+    pub combined_chunks: Vec<OutputChunk>,
+}
+
+/// Notes in `assert_success` errors that output is missing
+fn truncation_note(stdout_dropped_bytes: u64, stderr_dropped_bytes: u64) -> String {
+    let mut note = String::new();
+    for (stream, dropped) in [
+        ("stdout", stdout_dropped_bytes),
+        ("stderr", stderr_dropped_bytes),
+    ] {
+        if dropped != 0 {
+            note += &format!(
+                " (note: {dropped} bytes of the {stream} were dropped because of the record limit)"
+            );
+        }
+    }
+    note
+}
+
+/// Formats the last `lines` lines of `output` for `assert_success` errors
+fn output_excerpt(stream: &str, output: &[u8], lines: usize) -> String {
+    if (lines == 0) || output.is_empty() {
+        return String::new();
+    }
+    let trimmed = output.strip_suffix(b"\n").unwrap_or(output);
+    let mut excerpt = &trimmed[last_lines_start(trimmed, lines).unwrap_or(0)..];
+    let mut capped = "";
+    if excerpt.len() > ERROR_EXCERPT_MAX_BYTES {
+        excerpt = &excerpt[(excerpt.len() - ERROR_EXCERPT_MAX_BYTES)..];
+        capped = ", capped";
+    }
+    let excerpt = String::from_utf8_lossy(excerpt);
+    let lines = match excerpt.lines().count() {
+        1 => "line".to_owned(),
+        count => format!("{count} lines"),
+    };
+    format!("\nthe last {lines} of the {stream}{capped}:\n{excerpt}")
+}
+
+/// The error message of `assert_success`, `result` is the debug output of the
+/// result that is used as the start
+fn assert_success_message(
+    result: &str,
+    command: &Command,
+    status: Option<&ExitStatus>,
+    stdout: &[u8],
+    stderr: &[u8],
+    stdout_dropped_bytes: u64,
+    stderr_dropped_bytes: u64,
+) -> String {
+    let reason = if let Some(status) = status {
+        format!("unsuccessful, {status}")
+    } else {
+        "termination was called before completion".to_owned()
+    };
+    let mut note = truncation_note(stdout_dropped_bytes, stderr_dropped_bytes);
+    if command.inherit_stdio {
+        note += " (the standard streams were inherited, so there is no output to show)";
+    }
+    // the `Debug` output of `result` has the lengths of binary outputs
+    let lines = if command.binary {
+        0
+    } else {
+        command.error_excerpt_lines
+    };
+    format!(
+        "{result}.assert_success() -> {reason}{note}{}{}",
+        output_excerpt("stderr", stderr, lines),
+        output_excerpt("stdout", stdout, lines)
+    )
 }
 
 impl Debug for CommandResult {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_fmt(format_args!(
-            "CommandResult {{\ncommand: {:?},\nstatus: {:?},\n",
-            self.command, self.status
+            "CommandResult {{\ncommand: {:?},\nstatus: {:?},\nduration: {:?},\n",
+            self.command, self.status, self.duration
         ))?;
+        if self.terminated_gracefully {
+            f.write_fmt(format_args!("terminated_gracefully: true,\n"))?;
+        }
+        if self.stdout_dropped_bytes != 0 {
+            f.write_fmt(format_args!(
+                "stdout_dropped_bytes: {},\n",
+                self.stdout_dropped_bytes
+            ))?;
+        }
+        if self.stderr_dropped_bytes != 0 {
+            f.write_fmt(format_args!(
+                "stderr_dropped_bytes: {},\n",
+                self.stderr_dropped_bytes
+            ))?;
+        }
+        if self.command.binary {
+            f.write_fmt(format_args!(
+                "stdout: {} bytes,\nstderr: {} bytes,\n",
+                self.stdout.len(),
+                self.stderr.len()
+            ))?;
+            return f.write_fmt(format_args!("}}"))
+        }
         // move the commas out of the way of the stdout and stderr
         let stdout = self.stdout_as_utf8_lossy();
         if !stdout.is_empty() {
@@ -438,9 +1482,37 @@ impl CommandResult {
             status: self.status,
             stdout: self.stdout,
             stderr: self.stderr,
+            terminated_gracefully: self.terminated_gracefully,
+            stdout_dropped_bytes: self.stdout_dropped_bytes,
+            stderr_dropped_bytes: self.stderr_dropped_bytes,
+            started_at: self.started_at,
+            finished_at: self.finished_at,
+            duration: self.duration,
+            combined_chunks: self.combined_chunks,
         }
     }
 
+    /// Returns the stdout and stderr merged in the order they arrived, if
+    /// [Command::record_combined] was set
+    pub fn combined(&self) -> Vec<u8> {
+        self.combined_chunks
+            .iter()
+            .flat_map(|chunk| chunk.bytes.iter().copied())
+            .collect()
+    }
+
+    /// Returns `String::from_utf8_lossy` of [CommandResult::combined]
+    pub fn combined_as_utf8_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.combined()).into_owned()
+    }
+
+    /// Returns if the command used the standard streams of this process (see
+    /// [Command::inherit_stdio]), in which case `stdout` and `stderr` are
+    /// empty because nothing was captured
+    pub fn stdio_inherited(&self) -> bool {
+        self.command.inherit_stdio
+    }
+
     /// Returns if the command completed (not terminated early) with a
     /// successful return status
     pub fn successful(&self) -> bool {
@@ -464,17 +1536,42 @@ impl CommandResult {
     /// Returns a formatted error with relevant information if the command was
     /// not successful
     pub fn assert_success(&self) -> Result<()> {
-        if let Some(status) = self.status.as_ref() {
-            if status.success() {
-                Ok(())
-            } else {
-                bail_locationless!("{self:#?}.assert_success() -> unsuccessful")
-            }
+        if self.successful() {
+            return Ok(())
+        }
+        // the excerpts take the place of the whole output
+        let result = if self.command.error_excerpt_lines == 0 {
+            format!("{self:#?}")
         } else {
-            bail_locationless!(
-                "{self:#?}.assert_success() -> termination was called before completion"
+            format!(
+                "CommandResult {{\ncommand: {:?},\nstatus: {:?},\nduration: {:?},\n}}",
+                self.command, self.status, self.duration
             )
-        }
+        };
+        bail_locationless!(
+            "{}",
+            assert_success_message(
+                &result,
+                &self.command,
+                self.status.as_ref(),
+                &self.stdout,
+                &self.stderr,
+                self.stdout_dropped_bytes,
+                self.stderr_dropped_bytes
+            )
+        )
+    }
+
+    /// Returns the exact bytes of the stdout, this is the same as
+    /// `&self.stdout`
+    pub fn stdout_bytes(&self) -> &[u8] {
+        &self.stdout
+    }
+
+    /// Returns the exact bytes of the stderr, this is the same as
+    /// `&self.stderr`
+    pub fn stderr_bytes(&self) -> &[u8] {
+        &self.stderr
     }
 
     /// Returns `str::from_utf8(&self.stdout)`
@@ -507,6 +1604,13 @@ pub struct CommandResultNoDebug {
     pub status: Option<ExitStatus>,
     pub stdout: Vec<u8>,
     pub stderr: Vec<u8>,
+    pub terminated_gracefully: bool,
+    pub stdout_dropped_bytes: u64,
+    pub stderr_dropped_bytes: u64,
+    pub started_at: Option<SystemTime>,
+    pub finished_at: Option<SystemTime>,
+    pub duration: Duration,
+    pub combined_chunks: Vec<OutputChunk>,
 }
 
 impl Debug for CommandResultNoDebug {
@@ -514,6 +1618,10 @@ impl Debug for CommandResultNoDebug {
         f.debug_struct("CommandResult")
             .field("command", &self.command)
             .field("status", &self.status)
+            .field("duration", &self.duration)
+            .field("terminated_gracefully", &self.terminated_gracefully)
+            .field("stdout_dropped_bytes", &self.stdout_dropped_bytes)
+            .field("stderr_dropped_bytes", &self.stderr_dropped_bytes)
             .finish()
     }
 }
@@ -531,9 +1639,37 @@ impl CommandResultNoDebug {
             status: self.status,
             stdout: self.stdout,
             stderr: self.stderr,
+            terminated_gracefully: self.terminated_gracefully,
+            stdout_dropped_bytes: self.stdout_dropped_bytes,
+            stderr_dropped_bytes: self.stderr_dropped_bytes,
+            started_at: self.started_at,
+            finished_at: self.finished_at,
+            duration: self.duration,
+            combined_chunks: self.combined_chunks,
         }
     }
 
+    /// Returns the stdout and stderr merged in the order they arrived, if
+    /// [Command::record_combined] was set
+    pub fn combined(&self) -> Vec<u8> {
+        self.combined_chunks
+            .iter()
+            .flat_map(|chunk| chunk.bytes.iter().copied())
+            .collect()
+    }
+
+    /// Returns `String::from_utf8_lossy` of [CommandResult::combined]
+    pub fn combined_as_utf8_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.combined()).into_owned()
+    }
+
+    /// Returns if the command used the standard streams of this process (see
+    /// [Command::inherit_stdio]), in which case `stdout` and `stderr` are
+    /// empty because nothing was captured
+    pub fn stdio_inherited(&self) -> bool {
+        self.command.inherit_stdio
+    }
+
     /// Returns if the command completed (not terminated early) with a
     /// successful return status
     pub fn successful(&self) -> bool {
@@ -557,17 +1693,21 @@ impl CommandResultNoDebug {
     /// Returns a formatted error with relevant information if the command was
     /// not successful
     pub fn assert_success(&self) -> Result<()> {
-        if let Some(status) = self.status.as_ref() {
-            if status.success() {
-                Ok(())
-            } else {
-                bail_locationless!("{self:#?}.assert_success() -> unsuccessful")
-            }
-        } else {
-            bail_locationless!(
-                "{self:#?}.assert_success() -> termination was called before completion"
-            )
+        if self.successful() {
+            return Ok(())
         }
+        bail_locationless!(
+            "{}",
+            assert_success_message(
+                &format!("{self:#?}"),
+                &self.command,
+                self.status.as_ref(),
+                &self.stdout,
+                &self.stderr,
+                self.stdout_dropped_bytes,
+                self.stderr_dropped_bytes
+            )
+        )
     }
 
     /// Returns `str::from_utf8(&self.stdout)`
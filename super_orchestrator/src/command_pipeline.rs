@@ -0,0 +1,182 @@
+use std::{process::Stdio, time::Duration};
+
+use stacked_errors::{bail_locationless, Result, StackableErr};
+use tokio::time::timeout;
+
+use crate::{Command, CommandResult, CommandRunner};
+
+const DEFAULT_UPSTREAM_GRACE: Duration = Duration::from_secs(1);
+
+/// A pipeline of `Command`s where the stdout of each stage is piped into the
+/// stdin of the next stage, like `docker save image | gzip` in a shell. This is
+/// created with [Command::pipe_to].
+///
+/// The stdout of every stage but the last goes into the pipe, so the stdout
+/// recording, debug, log, callback, and `line_channels` settings of those
+/// stages are not used. Neither are their `record_combined` and `combined_log`
+/// settings, which would need the stdout as well. The `timeout`s of the stages
+/// are not used either.
+#[derive(Debug, Clone)]
+pub struct CommandPipeline {
+    /// The stages in order
+    pub stages: Vec<Command>,
+    /// After a stage has exited, how long the stage before it gets to exit by
+    /// itself before it is stopped with [CommandRunner::terminate_graceful]
+    /// (with the same grace period). This defaults to 1 second.
+    pub upstream_grace: Duration,
+}
+
+impl CommandPipeline {
+    /// Creates a pipeline out of the `stages`
+    pub fn new(stages: Vec<Command>) -> Self {
+        Self {
+            stages,
+            upstream_grace: DEFAULT_UPSTREAM_GRACE,
+        }
+    }
+
+    /// Adds `next` as the last stage
+    pub fn pipe_to(mut self, next: Command) -> Self {
+        self.stages.push(next);
+        self
+    }
+
+    /// Sets `upstream_grace`
+    pub fn upstream_grace(mut self, upstream_grace: Duration) -> Self {
+        self.upstream_grace = upstream_grace;
+        self
+    }
+
+    /// Spawns all the stages with real pipes between them, waits for all of
+    /// them, and returns their results. Like for [Command::run_to_completion],
+    /// unsuccessful stages are not an error, see
+    /// [PipelineResult::assert_success].
+    ///
+    /// If a stage exits early (e.g. `head -n 1`), the stage before it usually
+    /// exits from a broken pipe (which makes it unsuccessful, like with
+    /// `pipefail`), otherwise it is stopped after `upstream_grace`.
+    pub async fn run_to_completion(self) -> Result<PipelineResult> {
+        if self.stages.is_empty() {
+            bail_locationless!("CommandPipeline::run_to_completion -> the pipeline has no stages")
+        }
+        let num_stages = self.stages.len();
+        let mut runners: Vec<CommandRunner> = vec![];
+        let mut stdin = Some(Stdio::null());
+        for (i, mut stage) in self.stages.into_iter().enumerate() {
+            let last = (i + 1) == num_stages;
+            if !last {
+                // leaves the `ChildStdout` in the runner
+                stage.stdout_recording = false;
+                stage.stdout_debug = false;
+                stage.stdout_log = None;
+                stage.stdout_line_callback = None;
+                stage.line_channels = None;
+                stage.record_combined = false;
+                stage.combined_log = None;
+            }
+            let res = async {
+                let mut runner = stage.run_with_stdin(stdin.take().unwrap()).await.stack()?;
+                if !last {
+                    let stdout = runner
+                        .child_process
+                        .as_mut()
+                        .and_then(|child_process| child_process.stdout.take())
+                        .stack_err_locationless("the stdout of the stage was not piped")?;
+                    match stdout.try_into() {
+                        Ok(stdout) => stdin = Some(stdout),
+                        Err(e) => {
+                            let _ = runner.terminate().await;
+                            return Err(e).stack_err_locationless(
+                                "could not convert the stdout of the stage into a `Stdio`",
+                            )
+                        }
+                    }
+                }
+                Ok(runner)
+            }
+            .await;
+            match res {
+                Ok(runner) => runners.push(runner),
+                Err(e) => {
+                    for mut runner in runners {
+                        let _ = runner.terminate().await;
+                    }
+                    return Err(e).stack_err_with_locationless(|| {
+                        format!("CommandPipeline::run_to_completion when spawning stage {i}")
+                    })
+                }
+            }
+        }
+
+        // wait from the last stage, so that each stage is waited on after the stage
+        // reading its stdout has exited
+        let mut results = vec![];
+        while let Some(mut runner) = runners.pop() {
+            let i = runners.len();
+            let res = if results.is_empty() {
+                runner.wait_with_output().await
+            } else {
+                let exited = if let Some(child_process) = runner.child_process.as_mut() {
+                    timeout(self.upstream_grace, child_process.wait())
+                        .await
+                        .is_ok()
+                } else {
+                    true
+                };
+                if exited {
+                    runner.wait_with_output().await
+                } else {
+                    runner
+                        .terminate_graceful(self.upstream_grace)
+                        .await
+                        .map(|()| runner.take_command_result().unwrap())
+                }
+            };
+            match res {
+                Ok(result) => results.push(result),
+                Err(e) => {
+                    for mut runner in runners {
+                        let _ = runner.terminate().await;
+                    }
+                    return Err(e).stack_err_with_locationless(|| {
+                        format!("CommandPipeline::run_to_completion when waiting on stage {i}")
+                    })
+                }
+            }
+        }
+        results.reverse();
+        Ok(PipelineResult { results })
+    }
+}
+
+/// The result of a [CommandPipeline]
+#[must_use]
+#[derive(Debug, Clone)]
+pub struct PipelineResult {
+    /// The results of the stages in order
+    pub results: Vec<CommandResult>,
+}
+
+impl PipelineResult {
+    /// Returns if all of the stages were successful, like with `pipefail`
+    pub fn successful(&self) -> bool {
+        self.results.iter().all(CommandResult::successful)
+    }
+
+    /// Returns the error of the last unsuccessful stage (which is the stage
+    /// that `pipefail` takes the exit status from) if there is one
+    pub fn assert_success(&self) -> Result<()> {
+        for (i, result) in self.results.iter().enumerate().rev() {
+            result.assert_success().stack_err_with_locationless(|| {
+                format!("PipelineResult::assert_success -> stage {i} was unsuccessful")
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Returns the result of the last stage, which has the output of the
+    /// pipeline
+    pub fn last(&self) -> &CommandResult {
+        self.results.last().unwrap()
+    }
+}
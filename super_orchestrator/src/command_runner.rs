@@ -1,18 +1,31 @@
 use core::fmt;
-use std::{collections::VecDeque, fmt::Debug, process::Stdio, sync::Arc, time::Duration};
+use std::{
+    collections::VecDeque,
+    fmt::{Debug, Display},
+    panic::{catch_unwind, AssertUnwindSafe},
+    process::Stdio,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
-use stacked_errors::{bail_locationless, Error, Result, StackableErr};
+use stacked_errors::{bail_locationless, Error, Result, StackableErr, StackedErrorDowncast};
 use tokio::{
     fs::File,
     io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader},
     process::{self, Child},
-    sync::Mutex,
+    sync::{mpsc, watch, Mutex, Notify},
     task::{self, JoinHandle},
     time::{sleep, timeout},
 };
-use tracing::warn;
+use tracing::{instrument::WithSubscriber, warn, Level};
 
-use crate::{acquire_dir_path, next_terminal_color, Command, CommandResult};
+use crate::{
+    acquire_dir_path, ansi_colors, default_kill_on_drop, next_terminal_color, CapturePolicy,
+    Command, CommandResult, LineCallback, LineOverflow, OutputChunk, OutputStream, RlimitResource,
+};
 
 // note that most things should use `_locationless`, especially if they are
 // expected to be able to error under normal `Command` running circumstances,
@@ -23,75 +36,599 @@ use crate::{acquire_dir_path, next_terminal_color, Command, CommandResult};
 // an `Arc<AtomicBool>` or something to communicate, and change one of the
 // `FileOptions` to not truncate?.
 
+/// The target of the events from [Command::debug_level]
+const COMMAND_TARGET: &str = "super_orchestrator::command";
+
+/// `tracing::event!` under [COMMAND_TARGET] with a level that is not known at
+/// compile time
+macro_rules! command_event {
+    ($level:expr, $($arg:tt)+) => {
+        let level = $level;
+        if level == Level::ERROR {
+            tracing::event!(target: COMMAND_TARGET, Level::ERROR, $($arg)+)
+        } else if level == Level::WARN {
+            tracing::event!(target: COMMAND_TARGET, Level::WARN, $($arg)+)
+        } else if level == Level::INFO {
+            tracing::event!(target: COMMAND_TARGET, Level::INFO, $($arg)+)
+        } else if level == Level::DEBUG {
+            tracing::event!(target: COMMAND_TARGET, Level::DEBUG, $($arg)+)
+        } else {
+            tracing::event!(target: COMMAND_TARGET, Level::TRACE, $($arg)+)
+        }
+    };
+}
+
+/// For emitting the lines of a stream as `tracing` events
+struct LineTrace {
+    level: Level,
+    prefix: String,
+    stream: &'static str,
+}
+
+/// The lines queued for a [LineOverflow::DropOldest] channel
+struct DropOldestQueue {
+    lines: std::sync::Mutex<VecDeque<String>>,
+    capacity: usize,
+    notify: Notify,
+    closed: AtomicBool,
+}
+
+impl DropOldestQueue {
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<String>> {
+        self.lines
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+/// Moves lines from the queue into the channel until the queue is closed and
+/// empty, or the receiver is dropped
+async fn forward_lines(queue: Arc<DropOldestQueue>, tx: mpsc::Sender<String>) {
+    // a line is only taken from the queue once the channel has room for it, so
+    // that lines waiting for room can still be dropped
+    while let Ok(permit) = tx.reserve().await {
+        loop {
+            // pushes happen before closing, so an empty queue after this is final
+            let closed = queue.closed.load(Ordering::Acquire);
+            let line = queue.lock().pop_front();
+            if let Some(line) = line {
+                permit.send(line);
+                break
+            } else if closed {
+                return
+            } else {
+                queue.notify.notified().await;
+            }
+        }
+    }
+}
+
+/// The sending half of a line channel
+enum LineSender {
+    Block(mpsc::Sender<String>),
+    DropOldest(Arc<DropOldestQueue>),
+}
+
+impl LineSender {
+    /// Returns `false` if the receiver has been dropped
+    async fn send(&self, line: String) -> bool {
+        match self {
+            LineSender::Block(tx) => tx.send(line).await.is_ok(),
+            LineSender::DropOldest(queue) => {
+                let mut lines = queue.lock();
+                if lines.len() >= queue.capacity {
+                    lines.pop_front();
+                }
+                lines.push_back(line);
+                drop(lines);
+                queue.notify.notify_one();
+                true
+            }
+        }
+    }
+}
+
+impl Drop for LineSender {
+    fn drop(&mut self) {
+        if let LineSender::DropOldest(queue) = self {
+            queue.closed.store(true, Ordering::Release);
+            queue.notify.notify_one();
+        }
+    }
+}
+
+/// Creates a line channel, see [Command::line_channels]
+fn line_channel(capacity: usize, overflow: LineOverflow) -> (LineSender, mpsc::Receiver<String>) {
+    let capacity = capacity.max(1);
+    match overflow {
+        LineOverflow::Block => {
+            let (tx, rx) = mpsc::channel(capacity);
+            (LineSender::Block(tx), rx)
+        }
+        LineOverflow::DropOldest => {
+            let (tx, rx) = mpsc::channel(1);
+            let queue = Arc::new(DropOldestQueue {
+                lines: std::sync::Mutex::new(VecDeque::new()),
+                capacity,
+                notify: Notify::new(),
+                closed: AtomicBool::new(false),
+            });
+            task::spawn(forward_lines(Arc::clone(&queue), tx));
+            (LineSender::DropOldest(queue), rx)
+        }
+    }
+}
+
+/// How many bytes of the most recent lines of a stream are kept for
+/// [CommandRunner::wait_for_line] at most, a smaller `record_limit` also
+/// applies
+const LINE_WATCH_HISTORY: usize = 1 << 20;
+/// When only [CommandRunner::wait_for_line] needs the lines, longer lines are
+/// cut to this many bytes
+const LINE_WATCH_MAX_LINE: usize = 64 * 1024;
+/// The number of lines in the error when [CommandRunner::wait_for_line] times
+/// out
+const LINE_WATCH_EXCERPT: usize = 10;
+/// Lines forwarded by `stdout_debug` and `stderr_debug` that are longer than
+/// this many bytes are broken up
+const FORWARD_MAX_LINE: usize = 64 * 1024;
+
+/// The most recent lines of a stream, for [CommandRunner::wait_for_line]
+struct LineWatch {
+    lines: VecDeque<String>,
+    // the number of bytes in `lines`
+    len: usize,
+    // the index of the front of `lines` among all the lines of the stream
+    first: u64,
+    // the maximum of `len`, other than for the newest line
+    limit: usize,
+    // if all but the newest line are dropped when reaching the `limit`, like the
+    // records with `CapturePolicy::Discard`
+    discard: bool,
+}
+
+impl LineWatch {
+    fn new(record_limit: Option<u64>, capture_policy: CapturePolicy) -> Self {
+        let limit = record_limit.map_or(LINE_WATCH_HISTORY, |limit| {
+            usize::try_from(limit).map_or(LINE_WATCH_HISTORY, |limit| limit.min(LINE_WATCH_HISTORY))
+        });
+        Self {
+            lines: VecDeque::new(),
+            len: 0,
+            first: 0,
+            limit,
+            discard: capture_policy == CapturePolicy::Discard,
+        }
+    }
+
+    fn push(&mut self, line: &str) {
+        self.len = self.len.saturating_add(line.len());
+        self.lines.push_back(line.to_owned());
+        if self.len <= self.limit {
+            return
+        }
+        while (self.lines.len() > 1) && (self.discard || (self.len > self.limit)) {
+            let line = self.lines.pop_front().unwrap();
+            self.len -= line.len();
+            self.first += 1;
+        }
+    }
+}
+
+/// The receiving end of a [LineWatch]
+struct LineWatcher {
+    receiver: watch::Receiver<LineWatch>,
+    // the index of the line after the last match
+    next: u64,
+}
+
+/// Splits complete lines out of the bytes read from a stream for a
+/// [LineCallback], line channel, and [LineWatch]
+struct LineSplitter {
+    callback: Option<LineCallback>,
+    sender: Option<LineSender>,
+    trace: Option<LineTrace>,
+    watch: Option<watch::Sender<LineWatch>>,
+    partial: Vec<u8>,
+    // if the rest of a line that was cut is being skipped
+    skipping: bool,
+    // the message of a panic in the callback
+    panic: Option<String>,
+}
+
+impl LineSplitter {
+    fn new(
+        callback: Option<LineCallback>,
+        sender: Option<LineSender>,
+        trace: Option<LineTrace>,
+        watch: Option<watch::Sender<LineWatch>>,
+    ) -> Self {
+        Self {
+            callback,
+            sender,
+            trace,
+            watch,
+            partial: vec![],
+            skipping: false,
+            panic: None,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.callback.is_some()
+            || self.sender.is_some()
+            || self.trace.is_some()
+            || self.watch.is_some()
+    }
+
+    /// Handles every line completed by `bytes`
+    async fn push(&mut self, mut bytes: &[u8]) {
+        if !self.is_active() {
+            return;
+        }
+        if self.skipping {
+            if let Some(end) = bytes.iter().position(|b| *b == b'\n') {
+                bytes = &bytes[(end + 1)..];
+                self.skipping = false;
+            } else {
+                return;
+            }
+        }
+        let mut partial = std::mem::take(&mut self.partial);
+        partial.extend_from_slice(bytes);
+        let mut start = 0;
+        while let Some(end) = partial[start..].iter().position(|b| *b == b'\n') {
+            let end = start + end;
+            self.line(&partial[start..end]).await;
+            start = end + 1;
+        }
+        if self.is_active() {
+            partial.drain(..start);
+            if (partial.len() > LINE_WATCH_MAX_LINE)
+                && self.callback.is_none()
+                && self.sender.is_none()
+                && self.trace.is_none()
+            {
+                // the watch does not need whole lines, so that the partial line cannot use
+                // unbounded memory if a stream has no newlines
+                partial.truncate(LINE_WATCH_MAX_LINE);
+                self.line(&partial).await;
+                partial.clear();
+                self.skipping = true;
+            }
+            self.partial = partial;
+        }
+    }
+
+    /// Handles the last line if it had no terminator
+    async fn finish(&mut self) {
+        if !self.partial.is_empty() {
+            let line = std::mem::take(&mut self.partial);
+            self.line(&line).await;
+        }
+    }
+
+    async fn line(&mut self, line: &[u8]) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let line = String::from_utf8_lossy(line);
+        if let Some(trace) = self.trace.as_ref() {
+            command_event!(
+                trace.level,
+                command = %trace.prefix,
+                stream = trace.stream,
+                "{line}"
+            );
+        }
+        if let Some(callback) = self.callback.as_ref() {
+            let res = catch_unwind(AssertUnwindSafe(|| {
+                let mut callback = callback
+                    .lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner);
+                (*callback)(&line)
+            }));
+            if let Err(payload) = res {
+                let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+                    (*msg).to_owned()
+                } else if let Some(msg) = payload.downcast_ref::<String>() {
+                    msg.clone()
+                } else {
+                    "unknown panic payload".to_owned()
+                };
+                // the callback is not called again
+                self.callback = None;
+                self.panic = Some(msg);
+            }
+        }
+        if let Some(watch) = self.watch.as_ref() {
+            watch.send_modify(|watch| watch.push(&line));
+        }
+        if let Some(sender) = self.sender.as_ref() {
+            if !sender.send(line.into_owned()).await {
+                self.sender = None;
+            }
+        }
+    }
+}
+
+/// Where the output of both streams goes for [Command::record_combined] and
+/// [Command::combined_log], shared by the recorders
+struct CombinedSink {
+    recording: bool,
+    chunks: VecDeque<OutputChunk>,
+    // the number of bytes in `chunks`
+    len: usize,
+    record_limit: Option<u64>,
+    log: Option<File>,
+    // the number of bytes written to `log`
+    log_len: u64,
+    log_limit: Option<u64>,
+}
+
+impl CombinedSink {
+    async fn push(&mut self, stream: OutputStream, bytes: &[u8]) -> Result<()> {
+        if self.recording {
+            self.chunks.push_back(OutputChunk {
+                stream,
+                time: SystemTime::now(),
+                bytes: bytes.to_vec(),
+            });
+            self.len = self.len.saturating_add(bytes.len());
+            if let Some(limit) = self.record_limit {
+                // keep the end of the output
+                let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+                while self.len > limit {
+                    let excess = self.len - limit;
+                    let front = self.chunks.front_mut().unwrap();
+                    if front.bytes.len() <= excess {
+                        self.len -= front.bytes.len();
+                        self.chunks.pop_front();
+                    } else {
+                        front.bytes.drain(..excess);
+                        self.len -= excess;
+                    }
+                }
+            }
+        }
+        if let Some(ref mut log) = self.log {
+            let len = u64::try_from(bytes.len()).unwrap();
+            self.log_len = self.log_len.saturating_add(len);
+            let start = match self.log_limit {
+                Some(limit) if self.log_len > limit => {
+                    // truncated like the separate logs
+                    log.set_len(0)
+                        .await
+                        .stack_err_locationless("failed to truncate the combined log")?;
+                    log.seek(std::io::SeekFrom::Start(0))
+                        .await
+                        .stack_err_locationless("failed to seek in the combined log")?;
+                    let start = len.saturating_sub(limit);
+                    self.log_len = len - start;
+                    usize::try_from(start).unwrap()
+                }
+                _ => 0,
+            };
+            log.write_all(&bytes[start..])
+                .await
+                .stack_err_locationless("failed to write to the combined log")?;
+            // the two streams write to this, so flush to keep the order
+            log.flush()
+                .await
+                .stack_err_locationless("failed to flush the combined log")?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the complete lines at the start of `pending` to `std_forward` with
+/// `prefix` before each, and removes them from `pending`. Lines are written
+/// whole so that the output of other commands never ends up in the middle of a
+/// line. If `finish`, the remaining incomplete line is written with a newline.
+/// Lines longer than [FORWARD_MAX_LINE] are broken up.
+async fn forward_complete_lines<W: AsyncWrite + Unpin>(
+    std_forward: &mut W,
+    prefix: &str,
+    pending: &mut Vec<u8>,
+    finish: bool,
+) {
+    let mut out = Vec::new();
+    let mut start = 0;
+    let push_line = |out: &mut Vec<u8>, line: &[u8]| {
+        // the prefix and line need to be written together, otherwise stdout running
+        // into stderr is too common. `write_vectored` is useless for this.
+        out.extend_from_slice(prefix.as_bytes());
+        // a newline cannot be part of a multibyte codepoint, so only the incomplete
+        // line can have a cut up codepoint. Invalid sequences become replacement
+        // characters according to the "substitution of maximal subparts" strategy.
+        out.extend_from_slice(String::from_utf8_lossy(line).as_bytes());
+    };
+    while let Some(i) = pending[start..].iter().position(|b| *b == b'\n') {
+        push_line(&mut out, &pending[start..=(start + i)]);
+        start += i + 1;
+    }
+    let rest = &pending[start..];
+    if finish && !rest.is_empty() {
+        push_line(&mut out, rest);
+        out.push(b'\n');
+        start = pending.len();
+    } else if rest.len() > FORWARD_MAX_LINE {
+        // keep a cut up codepoint at the end for the next part
+        let mut cut = rest.len();
+        if let Some(chunk) = bstr::ByteSlice::utf8_chunks(rest).last() {
+            if chunk.incomplete() {
+                cut -= chunk.invalid().len();
+            }
+        }
+        push_line(&mut out, &rest[..cut]);
+        out.push(b'\n');
+        start += cut;
+    }
+    pending.drain(..start);
+    if !out.is_empty() {
+        std_forward
+            .write_all(&out)
+            .await
+            .expect("`super_orchestrator::Command` stdout or stderr forwarding failed on write");
+        std_forward.flush().await.unwrap();
+    }
+}
+
+/// Writes `pending` to `std_forward` as it is, with `prefix` inserted at the
+/// start of each line, and removes it from `pending`. This keeps output such as
+/// progress bars that use `\r` working. Only a cut up codepoint at the end is
+/// left in `pending` for the next read. If `finish`, everything is written and
+/// an incomplete last line gets a newline.
+async fn forward_raw<W: AsyncWrite + Unpin>(
+    std_forward: &mut W,
+    prefix: &str,
+    pending: &mut Vec<u8>,
+    at_line_start: &mut bool,
+    finish: bool,
+) {
+    let mut end = pending.len();
+    if !finish {
+        if let Some(chunk) = bstr::ByteSlice::utf8_chunks(pending.as_slice()).last() {
+            if chunk.incomplete() {
+                end -= chunk.invalid().len();
+            }
+        }
+    }
+    let mut out = Vec::new();
+    // `lines_with_terminator` avoids the issue with `lines` where a string with the
+    // final sequence being a newline has no difference without it
+    for line in bstr::ByteSlice::lines_with_terminator(&pending[..end]) {
+        if *at_line_start {
+            out.extend_from_slice(prefix.as_bytes());
+        }
+        out.extend_from_slice(String::from_utf8_lossy(line).as_bytes());
+        *at_line_start = line.last() == Some(&b'\n');
+    }
+    if finish && !*at_line_start {
+        out.push(b'\n');
+        *at_line_start = true;
+    }
+    pending.drain(..end);
+    if !out.is_empty() {
+        std_forward
+            .write_all(&out)
+            .await
+            .expect("`super_orchestrator::Command` stdout or stderr forwarding failed on write");
+        std_forward.flush().await.unwrap();
+    }
+}
+
 /// Used as the engine in the stdout and stderr recording tasks. `unwrap`s only
-/// are used in here because it is spawned as a separate task.
+/// are used in here because it is spawned as a separate task. Returns an
+/// error if the line callback panicked.
 #[allow(clippy::too_many_arguments)]
 async fn recorder<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
     read_loop_timeout: Duration,
     mut std_read: BufReader<R>,
     mut std_record: Option<Arc<Mutex<VecDeque<u8>>>>,
     record_limit: Option<u64>,
+    capture_policy: CapturePolicy,
+    // counts the bytes not kept in the record
+    std_dropped: Arc<AtomicU64>,
     mut std_log: Option<File>,
     log_limit: Option<u64>,
-    // write point and prefix
-    mut std_forward: Option<(W, String)>,
-) {
+    // write point, prefix, and if only whole lines are written
+    mut std_forward: Option<(W, String, bool)>,
+    line_callback: Option<LineCallback>,
+    line_sender: Option<LineSender>,
+    line_trace: Option<LineTrace>,
+    line_watch: Option<watch::Sender<LineWatch>>,
+    combined: Option<(Arc<Mutex<CombinedSink>>, OutputStream)>,
+) -> Result<()> {
     const FORWARDING_FAILED: &str =
         "`super_orchestrator::Command` stdout or stderr recording failed on write";
     // for tracking how much has been written to the file
     let mut log_len = 0u64;
-    // if the previous read had a newline on the end (for forwarding to stdout)
-    let mut previous_newline = false;
-    // if no bytes have been written (for forwarding to stdout)
-    let mut empty = true;
-    let mut line_buf = Vec::new();
-    // when a utf8 codepoint is cut up across reads, we need to store it here
-    let mut cut_up: Option<Vec<u8>> = None;
+    // the incomplete last line when forwarding whole lines to stdout or stderr, or
+    // else only a cut up codepoint
+    let mut forward_pending = Vec::new();
+    // if the next forwarded byte starts a line and needs the prefix
+    let mut forward_line_start = true;
+    let mut line_splitter = LineSplitter::new(line_callback, line_sender, line_trace, line_watch);
+    // if the record has been discarded for `CapturePolicy::Discard`
+    let mut discarded = false;
     // 8 KB, like BufReader
     let mut buf = [0u8; 8 * 1024];
     loop {
         match timeout(read_loop_timeout, std_read.read(&mut buf)).await {
             Ok(Ok(bytes_read)) => {
                 if bytes_read == 0 {
-                    // if there has been nonempty output with no ending newline insert one upon
-                    // completion
-                    if (!empty) && (!previous_newline) {
-                        if let Some((ref mut std_forward, _)) = std_forward {
-                            if cut_up.is_some() {
-                                // the outside precondition is always met in case of an incomplete
-                                std_forward
-                                    .write_all("\u{fffd}\n".as_bytes())
-                                    .await
-                                    .expect(FORWARDING_FAILED);
-                            } else {
-                                std_forward.write_all(b"\n").await.expect(FORWARDING_FAILED);
-                            }
-                            std_forward.flush().await.unwrap();
+                    line_splitter.finish().await;
+                    // closes the channel
+                    line_splitter.sender = None;
+                    // if there has been output with no ending newline, it is forwarded with one
+                    if let Some((ref mut std_forward, ref prefix, whole_lines)) = std_forward {
+                        if whole_lines {
+                            forward_complete_lines(std_forward, prefix, &mut forward_pending, true)
+                                .await;
+                        } else {
+                            forward_raw(
+                                std_forward,
+                                prefix,
+                                &mut forward_pending,
+                                &mut forward_line_start,
+                                true,
+                            )
+                            .await;
                         }
                     }
                     break;
                 }
-                let mut bytes = &buf[..bytes_read];
+                let bytes = &buf[..bytes_read];
+                line_splitter.push(bytes).await;
+                if let Some((ref sink, stream)) = combined {
+                    sink.lock().await.push(stream, bytes).await?;
+                }
                 // copying to record
                 if let Some(ref mut arc) = std_record {
                     let mut deque = arc.lock().await;
                     if let Some(limit) = record_limit {
                         let limit = usize::try_from(limit).unwrap();
-                        if deque.len().saturating_add(bytes.len()) > limit {
+                        let mut dropped = 0;
+                        if discarded {
+                            dropped = bytes.len();
+                        } else if deque.len().saturating_add(bytes.len()) > limit {
                             // we would overflow the limit if all the `bytes` were inserted
-                            if bytes.len() >= limit {
-                                // the deque needs to be entirely replaced with the end of `bytes`
-                                deque.clear();
-                                deque.extend(bytes[bytes.len().wrapping_sub(limit)..].iter());
-                            } else {
-                                let start =
-                                    deque.len().wrapping_sub(limit).wrapping_add(bytes.len());
-                                deque.drain(..start);
-                                deque.extend(bytes.iter());
+                            match capture_policy {
+                                CapturePolicy::TruncateHead => {
+                                    dropped = deque.len().wrapping_add(bytes.len()) - limit;
+                                    if bytes.len() >= limit {
+                                        // the deque needs to be entirely replaced with the end
+                                        // of `bytes`
+                                        deque.clear();
+                                        deque.extend(
+                                            bytes[bytes.len().wrapping_sub(limit)..].iter(),
+                                        );
+                                    } else {
+                                        let start = deque
+                                            .len()
+                                            .wrapping_sub(limit)
+                                            .wrapping_add(bytes.len());
+                                        deque.drain(..start);
+                                        deque.extend(bytes.iter());
+                                    }
+                                }
+                                CapturePolicy::TruncateTail => {
+                                    let kept = limit.saturating_sub(deque.len());
+                                    dropped = bytes.len() - kept;
+                                    deque.extend(bytes[..kept].iter());
+                                }
+                                CapturePolicy::Discard => {
+                                    dropped = deque.len().wrapping_add(bytes.len());
+                                    deque.clear();
+                                    discarded = true;
+                                }
                             }
                         } else {
                             deque.extend(bytes);
                         }
+                        if dropped != 0 {
+                            std_dropped
+                                .fetch_add(u64::try_from(dropped).unwrap(), Ordering::Relaxed);
+                        }
                     } else {
                         deque.extend(bytes);
                     }
@@ -123,73 +660,25 @@ async fn recorder<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
                     }
                 }
                 // copying to std stream
-                if let Some((ref mut std_forward, ref prefix)) = std_forward {
-                    let mut tmp = Vec::new();
-                    if let Some(cut_up) = cut_up.take() {
-                        // prepend the possibly cut up bytes, this should be very rare
-                        tmp.extend_from_slice(&cut_up);
-                        tmp.extend_from_slice(bytes);
-                        // use this instead of the original backing to `bytes`
-                        bytes = &tmp;
-                    }
-                    // `utf8_chunks` is incredibly useful, since the `incomplete` function will only
-                    // check on the last chunk
-                    for utf8_chunk in bstr::ByteSlice::utf8_chunks(bytes) {
-                        // `utf8_chunk` can have a valid part followed by an invalid part
-                        let valid = utf8_chunk.valid();
-                        if !valid.is_empty() {
-                            // `lines_with_terminator` avoids the issue with `lines` where a string
-                            // with the final sequence being a newline has no difference without it
-                            for line in bstr::ByteSlice::lines_with_terminator(valid.as_bytes()) {
-                                // Need to write the terminal prefix together with the line,
-                                // otherwise stdout running into stderr
-                                // is too common. `write_vectored` is useless for this.
-
-                                // if there has been no writing yet, or the last writing had a
-                                // newline, then insert the terminal prefix
-                                if empty || previous_newline {
-                                    line_buf.extend_from_slice(prefix.as_bytes());
-                                }
-                                previous_newline = line.last() == Some(&b'\n');
-                                line_buf.extend_from_slice(line);
-                                std_forward
-                                    .write_all(&line_buf)
-                                    .await
-                                    .expect(FORWARDING_FAILED);
-                                line_buf.clear();
-                                empty = false;
-                            }
-                        }
-                        let invalid = utf8_chunk.invalid();
-                        if !invalid.is_empty() {
-                            // need to have this again
-                            if empty || previous_newline {
-                                line_buf.extend_from_slice(prefix.as_bytes());
-                            }
-                            if utf8_chunk.incomplete() {
-                                // the next read pass or ending will pick this up
-                                cut_up = Some(invalid.to_vec());
-                            } else {
-                                // insert replacement character, this will happen according to the
-                                // "substitution of maximal subparts" strategy described in `bstr`
-                                line_buf.extend_from_slice("\u{fffd}".as_bytes());
-                            }
-                            if !line_buf.is_empty() {
-                                std_forward
-                                    .write_all(&line_buf)
-                                    .await
-                                    .expect(FORWARDING_FAILED);
-                                line_buf.clear();
-                            }
-                            previous_newline = false;
-                            empty = false;
-                        }
+                if let Some((ref mut std_forward, ref prefix, whole_lines)) = std_forward {
+                    forward_pending.extend_from_slice(bytes);
+                    if whole_lines {
+                        forward_complete_lines(std_forward, prefix, &mut forward_pending, false)
+                            .await;
+                    } else {
+                        forward_raw(
+                            std_forward,
+                            prefix,
+                            &mut forward_pending,
+                            &mut forward_line_start,
+                            false,
+                        )
+                        .await;
                     }
                     // if set excessively large by some single line, shrink
-                    if line_buf.capacity() > (8 * 1024) {
-                        line_buf.shrink_to_fit();
+                    if forward_pending.capacity() > (8 * 1024) {
+                        forward_pending.shrink_to_fit();
                     }
-                    std_forward.flush().await.unwrap();
                 }
             }
             Ok(Err(e)) => {
@@ -201,6 +690,10 @@ async fn recorder<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
             Err(_) => (),
         }
     }
+    if let Some(msg) = line_splitter.panic {
+        bail_locationless!("a line callback of the `Command` panicked: {msg}")
+    }
+    Ok(())
 }
 
 /// Detached `Commands` are represented by this struct.
@@ -229,7 +722,7 @@ pub struct CommandRunner {
     /// `stderr_debug`, and `stderr_log.is_some()` should be all false if
     /// you want `ChildStderr`.
     pub child_process: Option<Child>,
-    handles: Vec<tokio::task::JoinHandle<()>>,
+    handles: Vec<tokio::task::JoinHandle<Result<()>>>,
 
     // TODO I'm not sure if this can/should be a `std::sync::mutex` considering the parallel async
     // tasks, clippy sends warnings in basic_commands.rs (not sure if they are spurious).
@@ -243,6 +736,19 @@ pub struct CommandRunner {
     /// Note: the lock should only be held long enough to make needed
     /// `VecDeque` operations.
     pub stderr_record: Arc<Mutex<VecDeque<u8>>>,
+    stdout_dropped: Arc<AtomicU64>,
+    stderr_dropped: Arc<AtomicU64>,
+    stdout_lines: Option<mpsc::Receiver<String>>,
+    stderr_lines: Option<mpsc::Receiver<String>>,
+    stdout_watch: Option<LineWatcher>,
+    stderr_watch: Option<LineWatcher>,
+    // when the process was spawned
+    started: Option<(SystemTime, Instant)>,
+    // the level and prefix for `tracing` debug events
+    trace: Option<(Level, String)>,
+    // the ID of the process group (or tree on Windows) to kill when terminating
+    process_group: Option<u32>,
+    combined: Option<Arc<Mutex<CombinedSink>>>,
     result: Option<CommandResult>,
 }
 
@@ -278,10 +784,12 @@ impl Drop for CommandRunner {
     }
 }
 
-pub(crate) async fn command_runner<C: Into<Stdio>>(
-    this: Command,
-    stdin_cfg: C,
-) -> Result<CommandRunner> {
+/// Creates the OS command for `this`, applying everything but the standard
+/// streams. `function` is used in errors.
+async fn os_command(this: &mut Command, function: &str) -> Result<process::Command> {
+    if let Some(ref e) = this.shell_syntax_error {
+        bail_locationless!("{this:?}.{function}() -> {e}")
+    }
     let mut cmd = process::Command::new(&this.program);
     if this.env_clear {
         // must happen before the `envs` call
@@ -291,9 +799,99 @@ pub(crate) async fn command_runner<C: Into<Stdio>>(
         let cwd = acquire_dir_path(cwd)
             .await
             .stack_err_with_locationless(|| {
-                format!("{this:?}.run() -> failed to acquire current working directory")
+                format!("{this:?}.{function}() -> failed to acquire current working directory")
             })?;
-        cmd.current_dir(cwd);
+        cmd.current_dir(&cwd);
+        // the `CommandResult` records the directory that was actually used
+        this.cwd = Some(cwd);
+    }
+    if let Some(ref user) = this.user {
+        let (uid, gid) = lookup_user(user).stack_err_with_locationless(|| {
+            format!("{this:?}.{function}() -> failed to find the user")
+        })?;
+        // the `CommandResult` records the IDs that were actually used
+        this.uid = Some(this.uid.unwrap_or(uid));
+        this.gid = Some(this.gid.unwrap_or(gid));
+    }
+    #[cfg(unix)]
+    {
+        if let Some(uid) = this.uid {
+            cmd.uid(uid);
+        }
+        if let Some(gid) = this.gid {
+            cmd.gid(gid);
+        }
+    }
+    #[cfg(not(unix))]
+    if this.uid.is_some() || this.gid.is_some() {
+        bail_locationless!("{this:?}.{function}() -> `uid` and `gid` are only supported on Unix")
+    }
+    if let Some(nice) = this.nice {
+        set_nice(&mut cmd, nice)
+            .stack_err_with_locationless(|| format!("{this:?}.{function}()"))?;
+    }
+    if !this.rlimits.is_empty() {
+        if let Err(e) = set_rlimits(&mut cmd, &this.rlimits) {
+            if this.ignore_unsupported_rlimits {
+                warn!("{this:?}.{function}() -> the `rlimits` are ignored: {e:?}");
+            } else {
+                return Err(e).stack_err_with_locationless(|| format!("{this:?}.{function}()"))
+            }
+        }
+    }
+    cmd.args(&this.args)
+        .envs(this.envs.iter().map(|x| (&x.0, &x.1)));
+    Ok(cmd)
+}
+
+/// The error context for when `this` failed to spawn
+fn spawn_error(this: &Command, function: &str) -> String {
+    if this.uid.is_some() || this.gid.is_some() {
+        format!(
+            "{this:?}.{function}() -> failed to spawn child process, note that changing the user \
+             or group requires privileges, and the working directory must be accessible to the \
+             user"
+        )
+    } else if this.nice.is_some() {
+        format!(
+            "{this:?}.{function}() -> failed to spawn child process, note that lowering the \
+             niceness below that of this process requires privileges"
+        )
+    } else if !this.rlimits.is_empty() {
+        format!(
+            "{this:?}.{function}() -> failed to spawn child process, note that raising a hard \
+             resource limit above that of this process requires privileges"
+        )
+    } else {
+        format!("{this:?}.{function}() -> failed to spawn child process")
+    }
+}
+
+pub(crate) async fn command_runner<C: Into<Stdio>>(
+    mut this: Command,
+    stdin_cfg: C,
+) -> Result<CommandRunner> {
+    let mut cmd = os_command(&mut this, "run").await?;
+    if this.inherit_stdio
+        && (this.stdout_log.is_some() || this.stderr_log.is_some() || this.combined_log.is_some())
+    {
+        bail_locationless!(
+            "{this:?}.run() -> `stdout_log`, `stderr_log`, and `combined_log` cannot be used with \
+             `inherit_stdio`, the output does not pass through this process"
+        )
+    }
+    if this.binary
+        && (this.stdout_debug
+            || this.stderr_debug
+            || this.stdout_line_callback.is_some()
+            || this.stderr_line_callback.is_some()
+            || this.line_channels.is_some()
+            || this.watch_lines)
+    {
+        bail_locationless!(
+            "{this:?}.run() -> `stdout_debug`, `stderr_debug`, line callbacks, `line_channels`, \
+             and `watch_lines` cannot be used with `binary`, the output is not processed as lines"
+        )
     }
     // do as much as possible before spawning the process
     let stdout_log = if let Some(ref options) = this.stdout_log {
@@ -306,6 +904,24 @@ pub(crate) async fn command_runner<C: Into<Stdio>>(
     } else {
         None
     };
+    let combined = if this.record_combined || this.combined_log.is_some() {
+        let log = if let Some(ref options) = this.combined_log {
+            Some(options.acquire_file().await?)
+        } else {
+            None
+        };
+        Some(Arc::new(Mutex::new(CombinedSink {
+            recording: this.record_combined,
+            chunks: VecDeque::new(),
+            len: 0,
+            record_limit: this.record_limit,
+            log,
+            log_len: 0,
+            log_limit: this.log_limit,
+        })))
+    } else {
+        None
+    };
     let stdout_record = Arc::new(Mutex::new(VecDeque::new()));
     let stdout_record_clone = if this.stdout_recording && (this.record_limit != Some(0)) {
         Some(Arc::clone(&stdout_record))
@@ -319,98 +935,617 @@ pub(crate) async fn command_runner<C: Into<Stdio>>(
         None
     };
     let record_limit = this.record_limit;
+    let capture_policy = this.capture_policy;
+    let watch_lines = this.watch_lines;
+    let stdout_dropped = Arc::new(AtomicU64::new(0));
+    let stderr_dropped = Arc::new(AtomicU64::new(0));
     let log_limit = this.log_limit;
     let program_name = this.program.to_string_lossy();
     let read_loop_timeout = this.read_loop_timeout;
-    let mut handles: Vec<JoinHandle<()>> = vec![];
-    cmd.args(&this.args)
-        .envs(this.envs.iter().map(|x| (&x.0, &x.1)))
-        .kill_on_drop(!this.forget_on_drop);
+    let mut handles: Vec<JoinHandle<Result<()>>> = vec![];
+    let (stdout_line_sender, stdout_lines, stderr_line_sender, stderr_lines) =
+        if let Some((capacity, overflow)) = this.line_channels {
+            let (stdout_tx, stdout_rx) = line_channel(capacity, overflow);
+            let (stderr_tx, stderr_rx) = line_channel(capacity, overflow);
+            (
+                Some(stdout_tx),
+                Some(stdout_rx),
+                Some(stderr_tx),
+                Some(stderr_rx),
+            )
+        } else {
+            (None, None, None, None)
+        };
+    cmd.kill_on_drop(this.kill_on_drop.unwrap_or_else(default_kill_on_drop));
+    #[cfg(unix)]
+    if this.kill_descendants {
+        // the group ID will be the PID of the child
+        cmd.process_group(0);
+    }
+    if this.inherit_stdio {
+        cmd.stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit());
+    } else {
+        cmd.stdin(stdin_cfg)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+    }
     let mut child = cmd
-        .stdin(stdin_cfg)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
         .spawn()
-        .stack_err_with_locationless(|| {
-            format!("{this:?}.run() -> failed to spawn child process")
-        })?;
+        .stack_err_with_locationless(|| spawn_error(&this, "run"))?;
+    let started = (SystemTime::now(), Instant::now());
     let child_id = child.id().unwrap();
     let terminal_color = if this.stdout_debug || this.stderr_debug {
         next_terminal_color()
     } else {
         owo_colors::AnsiColors::Default
     };
-    let stdout_forward = if this.stdout_debug {
+    // debug output goes to `tracing` if there is a subscriber
+    let trace_level = if (this.stdout_debug || this.stderr_debug)
+        && (!this.raw_debug)
+        && tracing::dispatcher::get_default(|dispatch| {
+            !dispatch.is::<tracing::subscriber::NoSubscriber>()
+        }) {
+        Some(this.debug_level)
+    } else {
+        None
+    };
+    let trace_prefix = |prefix: &Option<String>| {
+        if let Some(prefix) = prefix {
+            prefix.clone()
+        } else {
+            let prefix = if let Some(label) = &this.terminal_prefix {
+                format!("[{label}]")
+            } else {
+                format!("{program_name} {child_id}")
+            };
+            if ansi_colors() {
+                owo_colors::OwoColorize::color(&prefix, terminal_color).to_string()
+            } else {
+                prefix
+            }
+        }
+    };
+    let (stdout_trace, stderr_trace) = if let Some(level) = trace_level {
+        let prefix = trace_prefix(&None);
+        command_event!(
+            level,
+            command = %prefix,
+            "running {}",
+            this.get_unified_command()
+        );
+        (
+            this.stdout_debug.then(|| LineTrace {
+                level,
+                prefix: trace_prefix(&this.stdout_debug_line_prefix),
+                stream: "stdout",
+            }),
+            this.stderr_debug.then(|| LineTrace {
+                level,
+                prefix: trace_prefix(&this.stderr_debug_line_prefix),
+                stream: "stderr",
+            }),
+        )
+    } else {
+        (None, None)
+    };
+    let stdout_forward = if this.stdout_debug && trace_level.is_none() {
         let stdout = tokio::io::stdout();
         // TODO tokio does not support `IsTerminal` yet
         let prefix = if let Some(prefix) = &this.stdout_debug_line_prefix {
             prefix.clone()
         } else {
             owo_colors::OwoColorize::color(
-                &format!("{program_name} {child_id}  | "),
+                &if let Some(label) = &this.terminal_prefix {
+                    format!("[{label}]  | ")
+                } else {
+                    format!("{program_name} {child_id}  | ")
+                },
                 terminal_color,
             )
             .to_string()
         };
-        Some((stdout, prefix))
+        Some((stdout, prefix, this.terminal_prefix.is_some()))
     } else {
         None
     };
-    let stderr_forward = if this.stderr_debug {
+    let stderr_forward = if this.stderr_debug && trace_level.is_none() {
         let stderr = tokio::io::stderr();
         let prefix = if let Some(prefix) = &this.stderr_debug_line_prefix {
             prefix.clone()
         } else {
             owo_colors::OwoColorize::color(
-                &format!("{program_name} {child_id} E| "),
+                &if let Some(label) = &this.terminal_prefix {
+                    format!("[{label}] E| ")
+                } else {
+                    format!("{program_name} {child_id} E| ")
+                },
                 terminal_color,
             )
             .to_string()
         };
-        Some((stderr, prefix))
+        Some((stderr, prefix, this.terminal_prefix.is_some()))
     } else {
         None
     };
     // dropping the stdout and stderr handles actually results in an error, we keep
     // all the stuff anyway in `child_process` if there is not any kind of recording
-    if this.stdout_recording || this.stdout_debug || this.stdout_log.is_some() {
+    let stdout_watch = if (!this.inherit_stdio)
+        && (this.stdout_recording
+            || this.stdout_debug
+            || this.stdout_log.is_some()
+            || this.stdout_line_callback.is_some()
+            || this.line_channels.is_some()
+            || this.watch_lines
+            || combined.is_some())
+    {
         let stdout = child.stdout.take().unwrap();
         let stdout_read = BufReader::new(stdout);
-        handles.push(task::spawn(recorder(
-            read_loop_timeout,
-            stdout_read,
-            stdout_record_clone,
-            record_limit,
-            stdout_log,
-            log_limit,
-            stdout_forward,
-        )));
-    }
-    if this.stderr_recording || this.stderr_debug || this.stderr_log.is_some() {
+        let (line_watch, receiver) = watch::channel(LineWatch::new(record_limit, capture_policy));
+        handles.push(task::spawn(
+            recorder(
+                read_loop_timeout,
+                stdout_read,
+                stdout_record_clone,
+                record_limit,
+                capture_policy,
+                Arc::clone(&stdout_dropped),
+                stdout_log,
+                log_limit,
+                stdout_forward,
+                this.stdout_line_callback.clone(),
+                stdout_line_sender,
+                stdout_trace,
+                watch_lines.then_some(line_watch),
+                combined
+                    .as_ref()
+                    .map(|sink| (Arc::clone(sink), OutputStream::Stdout)),
+            )
+            .with_current_subscriber(),
+        ));
+        watch_lines.then_some(LineWatcher { receiver, next: 0 })
+    } else {
+        None
+    };
+    let stderr_watch = if (!this.inherit_stdio)
+        && (this.stderr_recording
+            || this.stderr_debug
+            || this.stderr_log.is_some()
+            || this.stderr_line_callback.is_some()
+            || this.line_channels.is_some()
+            || this.watch_lines
+            || combined.is_some())
+    {
         let stderr = child.stderr.take().unwrap();
         let stderr_read = BufReader::new(stderr);
-        handles.push(task::spawn(recorder(
-            read_loop_timeout,
-            stderr_read,
-            stderr_record_clone,
-            record_limit,
-            stderr_log,
-            log_limit,
-            stderr_forward,
-        )));
-    }
+        let (line_watch, receiver) = watch::channel(LineWatch::new(record_limit, capture_policy));
+        handles.push(task::spawn(
+            recorder(
+                read_loop_timeout,
+                stderr_read,
+                stderr_record_clone,
+                record_limit,
+                capture_policy,
+                Arc::clone(&stderr_dropped),
+                stderr_log,
+                log_limit,
+                stderr_forward,
+                this.stderr_line_callback.clone(),
+                stderr_line_sender,
+                stderr_trace,
+                watch_lines.then_some(line_watch),
+                combined
+                    .as_ref()
+                    .map(|sink| (Arc::clone(sink), OutputStream::Stderr)),
+            )
+            .with_current_subscriber(),
+        ));
+        watch_lines.then_some(LineWatcher { receiver, next: 0 })
+    } else {
+        None
+    };
+    let trace = trace_level.map(|level| (level, trace_prefix(&None)));
+    let process_group = this.kill_descendants.then_some(child_id);
     Ok(CommandRunner {
         command: Some(this),
         child_process: Some(child),
         handles,
         stdout_record,
         stderr_record,
+        stdout_dropped,
+        stderr_dropped,
+        stdout_lines,
+        stderr_lines,
+        stdout_watch,
+        stderr_watch,
+        started: Some(started),
+        trace,
+        process_group,
+        combined,
         result: None,
     })
 }
 
-/// Note: there are `send_unix_signal` and `send_unix_sigterm` function that can
-/// be enabled by the "nix_support" feature
+/// A process started with [Command::spawn_detached]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DetachedChild {
+    pub pid: u32,
+}
+
+impl DetachedChild {
+    /// Returns if the process is still running, see [is_running]
+    pub async fn is_running(&self) -> Result<bool> {
+        is_running(self.pid).await
+    }
+}
+
+pub(crate) async fn spawn_detached(mut this: Command) -> Result<DetachedChild> {
+    let mut cmd = os_command(&mut this, "spawn_detached").await?;
+    let stdout = if let Some(ref options) = this.stdout_log {
+        Stdio::from(options.acquire_file().await?.into_std().await)
+    } else {
+        Stdio::null()
+    };
+    let stderr = if let Some(ref options) = this.stderr_log {
+        Stdio::from(options.acquire_file().await?.into_std().await)
+    } else {
+        Stdio::null()
+    };
+    // a new session has no controlling terminal and its process group does not
+    // get the signals meant for the group of this process, such as from a
+    // "Ctrl-C" or the terminal closing
+    #[cfg(all(unix, feature = "nix_support"))]
+    set_new_session(&mut cmd);
+    // without `setsid`, a separate process group at least avoids the signals
+    #[cfg(all(unix, not(feature = "nix_support")))]
+    cmd.process_group(0);
+    #[cfg(windows)]
+    {
+        const CREATE_NEW_PROCESS_GROUP: u32 = 0x200;
+        const DETACHED_PROCESS: u32 = 0x8;
+        // this replaces the flags from `os_command`
+        cmd.creation_flags(
+            DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP | this.nice.map_or(0, priority_class),
+        );
+    }
+    let mut child = cmd
+        .kill_on_drop(false)
+        .stdin(Stdio::null())
+        .stdout(stdout)
+        .stderr(stderr)
+        .spawn()
+        .stack_err_with_locationless(|| spawn_error(&this, "spawn_detached"))?;
+    let pid = child.id().stack_err_with_locationless(|| {
+        format!("{this:?}.spawn_detached() -> the process has no ID")
+    })?;
+    // waiting reaps the process if it exits while this process is still
+    // running, otherwise it would stay a zombie that `is_running` has to detect
+    tokio::spawn(async move {
+        let _ = child.wait().await;
+    });
+    Ok(DetachedChild { pid })
+}
+
+/// Makes the process the leader of a new session
+#[cfg(all(unix, feature = "nix_support"))]
+fn set_new_session(cmd: &mut process::Command) {
+    // Safety: `setsid` is async-signal-safe and nothing is allocated
+    unsafe {
+        cmd.pre_exec(|| {
+            if nix::libc::setsid() == -1 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        });
+    }
+}
+
+/// Returns if the process with `pid` has exited but has not been reaped by its
+/// parent yet
+#[cfg(target_os = "linux")]
+async fn is_zombie(pid: u32) -> bool {
+    match tokio::fs::read_to_string(format!("/proc/{pid}/stat")).await {
+        // the state follows the file name, which is in parentheses and can
+        // itself contain parentheses
+        Ok(stat) => stat
+            .rsplit_once(')')
+            .is_some_and(|(_, rest)| rest.trim_start().starts_with('Z')),
+        Err(_) => false,
+    }
+}
+
+/// Returns if a process with `pid` is running, e.g. one started by
+/// [Command::spawn_detached]. Note that process IDs can be reused after a
+/// process exits. On Linux, a zombie process that has exited but has not been
+/// reaped by its parent is not running.
+#[cfg(all(unix, feature = "nix_support"))]
+pub async fn is_running(pid: u32) -> Result<bool> {
+    // a zombie can still be signaled
+    #[cfg(target_os = "linux")]
+    if is_zombie(pid).await {
+        return Ok(false)
+    }
+    // a null signal only checks if the process exists
+    match nix::sys::signal::kill(
+        nix::unistd::Pid::from_raw(i32::try_from(pid).stack_err("is_running -> PID overflow")?),
+        None,
+    ) {
+        Ok(()) | Err(nix::errno::Errno::EPERM) => Ok(true),
+        Err(nix::errno::Errno::ESRCH) => Ok(false),
+        Err(e) => Err(e).stack_err("is_running -> `nix::sys::signal::kill` failed"),
+    }
+}
+
+/// Returns if a process with `pid` is running, e.g. one started by
+/// [Command::spawn_detached]. Note that process IDs can be reused after a
+/// process exits. On Linux, a zombie process that has exited but has not been
+/// reaped by its parent is not running.
+#[cfg(all(unix, not(feature = "nix_support")))]
+pub async fn is_running(pid: u32) -> Result<bool> {
+    #[cfg(target_os = "linux")]
+    if is_zombie(pid).await {
+        return Ok(false)
+    }
+    // `kill -0` only checks if the process exists and can be signaled, which
+    // includes zombies
+    let comres = Command::new("kill -0")
+        .arg(pid.to_string())
+        .run_to_completion()
+        .await
+        .stack_err_locationless("is_running")?;
+    Ok(comres.successful() || comres.stderr_as_utf8_lossy().contains("not permitted"))
+}
+
+/// Returns if a process with `pid` is running, e.g. one started by
+/// [Command::spawn_detached]. Note that process IDs can be reused after a
+/// process exits.
+#[cfg(windows)]
+pub async fn is_running(pid: u32) -> Result<bool> {
+    let comres = Command::new("tasklist /NH /FI")
+        .arg(format!("PID eq {pid}"))
+        .run_to_completion()
+        .await
+        .stack_err_locationless("is_running")?;
+    comres
+        .assert_success()
+        .stack_err_locationless("is_running")?;
+    let pid = pid.to_string();
+    Ok(comres
+        .stdout_as_utf8_lossy()
+        .split_whitespace()
+        .any(|word| word == pid))
+}
+
+/// Makes the process run with the niceness `nice`
+#[cfg(all(unix, feature = "nix_support"))]
+fn set_nice(cmd: &mut process::Command, nice: i32) -> Result<()> {
+    // Safety: `setpriority` is async-signal-safe and nothing is allocated
+    unsafe {
+        cmd.pre_exec(move || {
+            if nix::libc::setpriority(nix::libc::PRIO_PROCESS, 0, nice) == -1 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, not(feature = "nix_support")))]
+fn set_nice(_cmd: &mut process::Command, _nice: i32) -> Result<()> {
+    bail_locationless!("`nice` needs the \"nix_support\" feature on Unix")
+}
+
+/// Makes the process run with the resource `limits`
+#[cfg(all(unix, feature = "nix_support"))]
+fn set_rlimits(cmd: &mut process::Command, limits: &[(RlimitResource, u64, u64)]) -> Result<()> {
+    use nix::libc;
+
+    let rlim = |limit: u64| {
+        if limit == u64::MAX {
+            libc::RLIM_INFINITY
+        } else {
+            limit
+        }
+    };
+    // allocated here, because the closure should not allocate
+    let limits: Vec<(RlimitResource, libc::rlimit)> = limits
+        .iter()
+        .map(|(resource, soft, hard)| {
+            (*resource, libc::rlimit {
+                rlim_cur: rlim(*soft),
+                rlim_max: rlim(*hard),
+            })
+        })
+        .collect();
+    // Safety: `setrlimit` is async-signal-safe and nothing is allocated
+    unsafe {
+        cmd.pre_exec(move || {
+            for (resource, limit) in &limits {
+                let resource = match resource {
+                    RlimitResource::NoFile => libc::RLIMIT_NOFILE,
+                    RlimitResource::AddressSpace => libc::RLIMIT_AS,
+                    RlimitResource::Core => libc::RLIMIT_CORE,
+                    RlimitResource::Cpu => libc::RLIMIT_CPU,
+                    RlimitResource::Data => libc::RLIMIT_DATA,
+                    RlimitResource::FileSize => libc::RLIMIT_FSIZE,
+                    RlimitResource::Stack => libc::RLIMIT_STACK,
+                };
+                if libc::setrlimit(resource, limit) == -1 {
+                    return Err(std::io::Error::last_os_error())
+                }
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(all(unix, feature = "nix_support")))]
+fn set_rlimits(_cmd: &mut process::Command, _limits: &[(RlimitResource, u64, u64)]) -> Result<()> {
+    bail_locationless!("`rlimits` are only supported on Unix with the \"nix_support\" feature")
+}
+
+/// Returns the priority class closest to the niceness `nice`
+#[cfg(windows)]
+fn priority_class(nice: i32) -> u32 {
+    const IDLE_PRIORITY_CLASS: u32 = 0x40;
+    const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x4000;
+    const NORMAL_PRIORITY_CLASS: u32 = 0x20;
+    const ABOVE_NORMAL_PRIORITY_CLASS: u32 = 0x8000;
+    const HIGH_PRIORITY_CLASS: u32 = 0x80;
+    match nice {
+        15.. => IDLE_PRIORITY_CLASS,
+        5..=14 => BELOW_NORMAL_PRIORITY_CLASS,
+        -4..=4 => NORMAL_PRIORITY_CLASS,
+        -14..=-5 => ABOVE_NORMAL_PRIORITY_CLASS,
+        _ => HIGH_PRIORITY_CLASS,
+    }
+}
+
+#[cfg(windows)]
+fn set_nice(cmd: &mut process::Command, nice: i32) -> Result<()> {
+    // this does not need privileges because the realtime class is not used
+    cmd.creation_flags(priority_class(nice));
+    Ok(())
+}
+
+/// Returns the user ID and primary group ID of the user named `name`
+#[cfg(all(unix, feature = "nix_support"))]
+fn lookup_user(name: &str) -> Result<(u32, u32)> {
+    let user = nix::unistd::User::from_name(name)
+        .stack_err("`nix::unistd::User::from_name` failed")?
+        .stack_err_with(|| format!("there is no user named {name:?}"))?;
+    Ok((user.uid.as_raw(), user.gid.as_raw()))
+}
+
+#[cfg(not(all(unix, feature = "nix_support")))]
+fn lookup_user(name: &str) -> Result<(u32, u32)> {
+    bail_locationless!("looking up the user {name:?} needs Unix and the \"nix_support\" feature")
+}
+
+/// Asks the process with `pid` (and the rest of its process group if `group`
+/// is set) to exit, used by [CommandRunner::terminate_graceful]
+#[cfg(all(unix, feature = "nix_support"))]
+async fn request_exit(pid: u32, group: bool) -> Result<()> {
+    let pid = nix::unistd::Pid::from_raw(i32::try_from(pid).stack_err("PID overflow")?);
+    if group {
+        nix::sys::signal::killpg(pid, nix::sys::signal::Signal::SIGTERM)
+            .stack_err("`nix::sys::signal::killpg` failed")
+    } else {
+        nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGTERM)
+            .stack_err("`nix::sys::signal::kill` failed")
+    }
+}
+
+#[cfg(all(unix, not(feature = "nix_support")))]
+async fn request_exit(pid: u32, group: bool) -> Result<()> {
+    // a negative PID refers to the process group
+    let pid = if group {
+        format!("-{pid}")
+    } else {
+        pid.to_string()
+    };
+    Command::new("kill -s TERM --")
+        .arg(pid)
+        .run_to_completion()
+        .await
+        .stack()?
+        .assert_success()
+}
+
+#[cfg(windows)]
+async fn request_exit(pid: u32, group: bool) -> Result<()> {
+    // without `/F`, this asks the process to close
+    Command::new("taskkill")
+        .args(if group { vec!["/T"] } else { vec![] })
+        .arg("/PID")
+        .arg(pid.to_string())
+        .run_to_completion()
+        .await
+        .stack()?
+        .assert_success()
+}
+
+/// Kills the remaining processes in the process group with ID `pgid`, used by
+/// the termination functions if [Command::kill_descendants] is set. The group
+/// no longer existing is not an error.
+#[cfg(all(unix, feature = "nix_support"))]
+async fn kill_process_group(pgid: u32) -> Result<()> {
+    match nix::sys::signal::killpg(
+        nix::unistd::Pid::from_raw(i32::try_from(pgid).stack_err("PID overflow")?),
+        nix::sys::signal::Signal::SIGKILL,
+    ) {
+        Ok(()) | Err(nix::errno::Errno::ESRCH) => Ok(()),
+        Err(e) => Err(e).stack_err("`nix::sys::signal::killpg` failed"),
+    }
+}
+
+// these use `tokio::process` directly, because the termination functions of a
+// `Command` cannot be used here
+
+#[cfg(all(unix, not(feature = "nix_support")))]
+async fn kill_process_group(pgid: u32) -> Result<()> {
+    // this is unsuccessful if the group no longer exists
+    let _ = process::Command::new("kill")
+        .args(["-s", "KILL", "--", &format!("-{pgid}")])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .stack_err("failed to run `kill`")?;
+    Ok(())
+}
+
+#[cfg(windows)]
+async fn kill_process_group(pid: u32) -> Result<()> {
+    // this needs to happen before the process itself is killed, because the tree
+    // is found through the parent process IDs. This is unsuccessful if the
+    // process no longer exists.
+    let _ = process::Command::new("taskkill")
+        .args(["/F", "/T", "/PID", &pid.to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .stack_err("failed to run `taskkill`")?;
+    Ok(())
+}
+
+/// The error that [CommandRunner::wait_with_output_timeout] (and so
+/// [Command::run_to_completion] with [Command::timeout]) adds to its timeout
+/// error, which carries the partial `CommandResult` with the output that was
+/// recorded until the command was terminated
+#[derive(Debug, Clone)]
+pub struct CommandTimeout {
+    /// The timeout that was exceeded
+    pub duration: Duration,
+    pub result: CommandResult,
+}
+
+impl CommandTimeout {
+    /// Returns the `CommandTimeout` in `err`, if it has one
+    pub fn from_error(err: &Error) -> Option<&CommandTimeout> {
+        err.iter().find_map(|item| item.downcast_ref())
+    }
+}
+
+impl Display for CommandTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?}\n -> CommandRunner::wait_with_output_timeout timed out after {:?}, the command \
+             was terminated",
+            self.result, self.duration
+        )
+    }
+}
+
+/// Note: there are `send_signal`, `send_unix_signal`, and `send_unix_sigterm`
+/// functions that can be enabled by the "nix_support" feature
 impl CommandRunner {
     /// Attempts to force the command to exit, but does not wait for the request
     /// to take effect. This does not set `self.result`.
@@ -430,21 +1565,44 @@ impl CommandRunner {
     /// error if the process exited by itself, only if a termination function
     /// that removes the handle has been called).
     ///
-    /// `self.result` is set, and `self.result.status` is set to `None`.
+    /// `self.result` is set, and `self.result.status` is set to `None`. If
+    /// [Command::kill_descendants] is set, the processes started by the
+    /// command are also killed.
     pub async fn terminate(&mut self) -> Result<()> {
         if let Some(child_process) = self.child_process.as_mut() {
+            if let Some(pgid) = self.process_group {
+                if let Err(e) = kill_process_group(pgid).await {
+                    warn!(
+                        "CommandRunner::terminate -> could not kill the processes started by the \
+                         command: {e:?}"
+                    );
+                }
+            }
             child_process.kill().await.stack_err(
                 "CommandRunner::terminate -> running `kill` on the child process failed",
             )?;
             drop(self.child_process.take().unwrap());
+            self.drop_line_channels();
+            let (started_at, finished_at, duration) = self.timing();
             let stdout = self.stdout_record.lock().await.iter().cloned().collect();
             let stderr = self.stderr_record.lock().await.iter().cloned().collect();
+            let combined_chunks = self.combined_chunks().await;
             self.result = Some(CommandResult {
                 command: self.command.take().unwrap(),
                 status: None,
                 stdout,
                 stderr,
+                terminated_gracefully: false,
+                stdout_dropped_bytes: self.stdout_dropped.load(Ordering::Relaxed),
+                stderr_dropped_bytes: self.stderr_dropped.load(Ordering::Relaxed),
+                combined_chunks,
+                started_at,
+                finished_at,
+                duration,
             });
+            if let Some((level, prefix)) = self.trace.as_ref() {
+                command_event!(*level, command = %prefix, "terminated after {duration:?}");
+            }
             Ok(())
         } else {
             bail_locationless!(
@@ -453,6 +1611,60 @@ impl CommandRunner {
         }
     }
 
+    /// Asks the command to exit and waits up to `grace` for it to do so, before
+    /// forcing it to exit like [CommandRunner::terminate]. On Unix, SIGTERM is
+    /// sent (with `kill` if the "nix_support" feature is not enabled), and on
+    /// Windows, `taskkill` without `/F` is used.
+    ///
+    /// `self.result` is set. If the command exited within the grace period,
+    /// `self.result.status` is its exit status and
+    /// `self.result.terminated_gracefully` is set, otherwise the status is
+    /// `None`. Returns an error if some termination method has already been
+    /// called.
+    ///
+    /// If [Command::kill_descendants] is set, the whole process group is asked
+    /// to exit, and any processes started by the command that remain after it
+    /// exits are killed.
+    pub async fn terminate_graceful(&mut self, grace: Duration) -> Result<()> {
+        self.drop_line_channels();
+        let child_process = self.child_process.as_mut().stack_err_locationless(
+            "CommandRunner::terminate_graceful -> a termination method has already been called",
+        )?;
+        let exited = match child_process.try_wait() {
+            Ok(status) => status.is_some(),
+            Err(e) => {
+                return Err(Error::from_err_locationless(e)).stack_err_locationless(
+                    "CommandRunner::terminate_graceful failed at `try_wait`",
+                )
+            }
+        };
+        if !exited {
+            if let Some(pid) = child_process.id() {
+                if let Err(e) = request_exit(pid, self.process_group.is_some()).await {
+                    warn!(
+                        "CommandRunner::terminate_graceful -> could not ask the process to exit, \
+                         killing it: {e:?}"
+                    );
+                } else if timeout(grace, child_process.wait()).await.is_ok() {
+                    self.kill_remaining_descendants().await;
+                    self.wait_with_output_internal().await?;
+                    if let Some(result) = self.result.as_mut() {
+                        result.terminated_gracefully = true;
+                    }
+                    return Ok(())
+                }
+            }
+        }
+        if exited {
+            self.kill_remaining_descendants().await;
+            self.wait_with_output_internal().await
+        } else {
+            self.terminate()
+                .await
+                .stack_err_locationless("CommandRunner::terminate_graceful")
+        }
+    }
+
     /// Returns the `pid` of the child process. Returns `None` if the command
     /// has been terminated or the internal `id` call returned `None`.
     pub fn pid(&self) -> Option<u32> {
@@ -464,6 +1676,167 @@ impl CommandRunner {
         None
     }
 
+    /// Takes the channel of stdout lines if [Command::line_channels] was set.
+    /// Returns `None` if it was not set or the channel was already taken.
+    pub fn stdout_lines(&mut self) -> Option<mpsc::Receiver<String>> {
+        self.stdout_lines.take()
+    }
+
+    /// Takes the channel of stderr lines, see [CommandRunner::stdout_lines]
+    pub fn stderr_lines(&mut self) -> Option<mpsc::Receiver<String>> {
+        self.stderr_lines.take()
+    }
+
+    /// Waits for a line of the `stream` that contains `pattern` and returns
+    /// the line, e.g. for waiting until a server prints "listening on". See
+    /// [CommandRunner::wait_for_line_matching] for the details.
+    pub async fn wait_for_line(
+        &mut self,
+        stream: OutputStream,
+        pattern: &str,
+        timeout: Duration,
+    ) -> Result<String> {
+        self.wait_for_line_matching(stream, |line| line.contains(pattern), timeout)
+            .await
+            .stack_err_with_locationless(|| {
+                format!("CommandRunner::wait_for_line for {stream} lines containing {pattern:?}")
+            })
+    }
+
+    /// Waits for a line of the `stream` that `predicate` returns `true` for and
+    /// returns the line (without the line terminator). Other kinds of matching
+    /// like regexes can be done in the `predicate`.
+    ///
+    /// The lines are only kept if [Command::watch_lines] was set. Lines
+    /// printed before this is called are also searched (up to the last MiB of
+    /// them, or the `record_limit` if it is smaller), and lines are only
+    /// searched once, so that a later call finds lines after the previous
+    /// match.
+    ///
+    /// If `timeout` passes first, a timeout error with the last lines of the
+    /// `stream` is returned. If the `stream` ends first, an error is returned
+    /// right away, and if the process has exited the runner is finished and
+    /// the error has the `CommandResult`, which can also be taken with
+    /// [CommandRunner::take_command_result].
+    pub async fn wait_for_line_matching<F: FnMut(&str) -> bool>(
+        &mut self,
+        stream: OutputStream,
+        mut predicate: F,
+        timeout: Duration,
+    ) -> Result<String> {
+        let watcher = match stream {
+            OutputStream::Stdout => self.stdout_watch.as_mut(),
+            OutputStream::Stderr => self.stderr_watch.as_mut(),
+        }
+        .stack_err_with_locationless(|| {
+            format!(
+                "CommandRunner::wait_for_line_matching -> the {stream} lines of the command are \
+                 not kept, set `Command::watch_lines`"
+            )
+        })?;
+        let deadline = tokio::time::Instant::now().checked_add(timeout);
+        let mut ended = false;
+        loop {
+            {
+                let watch = watcher.receiver.borrow_and_update();
+                let skip = usize::try_from(watcher.next.saturating_sub(watch.first)).unwrap();
+                for (i, line) in watch.lines.iter().enumerate().skip(skip) {
+                    if predicate(line) {
+                        watcher.next = watch.first + u64::try_from(i).unwrap() + 1;
+                        return Ok(line.clone())
+                    }
+                }
+                watcher.next = watch.first + u64::try_from(watch.lines.len()).unwrap();
+            }
+            if ended {
+                break
+            }
+            let changed = watcher.receiver.changed();
+            let res = if let Some(deadline) = deadline {
+                tokio::time::timeout_at(deadline, changed).await
+            } else {
+                Ok(changed.await)
+            };
+            match res {
+                Ok(Ok(())) => (),
+                // the recorder has finished, the last lines are searched before breaking
+                Ok(Err(_)) => ended = true,
+                Err(_) => {
+                    let watch = watcher.receiver.borrow();
+                    let excerpt: Vec<&str> = watch
+                        .lines
+                        .iter()
+                        .skip(watch.lines.len().saturating_sub(LINE_WATCH_EXCERPT))
+                        .map(String::as_str)
+                        .collect();
+                    return Err(Error::timeout().add_err_locationless(format!(
+                        "CommandRunner::wait_for_line_matching timed out after {timeout:?}, the \
+                         last lines of the {stream} were:\n{}",
+                        excerpt.join("\n")
+                    )))
+                }
+            }
+        }
+
+        // the stream usually ends because the process exited, which may not have been
+        // reaped yet
+        let mut exited = false;
+        if let Some(child_process) = self.child_process.as_mut() {
+            for _ in 0..10 {
+                if matches!(child_process.try_wait(), Ok(Some(_))) {
+                    exited = true;
+                    break
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        }
+        if exited {
+            self.wait_with_output_internal()
+                .await
+                .stack_err_locationless("CommandRunner::wait_for_line_matching")?;
+            bail_locationless!(
+                "{:?}\n -> CommandRunner::wait_for_line_matching -> the command exited without a \
+                 matching line in its {stream}",
+                self.result.as_ref().unwrap()
+            )
+        } else {
+            bail_locationless!(
+                "{self:?}.wait_for_line_matching() -> the {stream} ended without a matching line"
+            )
+        }
+    }
+
+    /// Sends a Unix `Signal` to the process, e.g. `SIGHUP` to make a daemon
+    /// reload its configuration. Unlike [CommandRunner::send_unix_signal],
+    /// this checks that the process has not exited first and returns an error
+    /// if it has (or if a termination method has already been called).
+    #[cfg(feature = "nix_support")]
+    pub fn send_signal(&mut self, signal: nix::sys::signal::Signal) -> Result<()> {
+        let child_process = self
+            .child_process
+            .as_mut()
+            .stack_err_with_locationless(|| {
+                format!(
+                    "CommandRunner::send_signal({signal}) -> a termination method has already \
+                     been called"
+                )
+            })?;
+        match child_process.try_wait() {
+            Ok(None) => (),
+            Ok(Some(status)) => bail_locationless!(
+                "CommandRunner::send_signal({signal}) -> the process has already exited with \
+                 {status}"
+            ),
+            Err(e) => {
+                return Err(Error::from_err_locationless(e)).stack_err_with_locationless(|| {
+                    format!("CommandRunner::send_signal({signal}) failed at `try_wait`")
+                })
+            }
+        }
+        self.send_unix_signal(signal)
+            .stack_err_with_locationless(|| format!("CommandRunner::send_signal({signal})"))
+    }
+
     /// Sends a Unix `Signal` to the process.
     #[cfg(feature = "nix_support")]
     pub fn send_unix_signal(&self, unix_signal: nix::sys::signal::Signal) -> Result<()> {
@@ -493,7 +1866,48 @@ impl CommandRunner {
     // the exit status from `try_wait`, so keep the `_with_output` functions in case
     // we want a plain `wait` function
 
+    /// Returns the start time, finish time, and duration for a `CommandResult`
+    /// finishing now
+    fn timing(&self) -> (Option<SystemTime>, Option<SystemTime>, Duration) {
+        if let Some((started_at, instant)) = self.started {
+            (Some(started_at), Some(SystemTime::now()), instant.elapsed())
+        } else {
+            (None, None, Duration::ZERO)
+        }
+    }
+
+    /// Kills what remains of the process group after the command has exited,
+    /// otherwise background processes holding on to the standard streams would
+    /// stall the recorders
+    async fn kill_remaining_descendants(&mut self) {
+        if let Some(pgid) = self.process_group {
+            if let Err(e) = kill_process_group(pgid).await {
+                warn!(
+                    "CommandRunner::terminate_graceful -> could not kill the processes started by \
+                     the command: {e:?}"
+                );
+            }
+        }
+    }
+
+    /// Returns a copy of the combined record
+    async fn combined_chunks(&self) -> Vec<OutputChunk> {
+        if let Some(ref combined) = self.combined {
+            combined.lock().await.chunks.iter().cloned().collect()
+        } else {
+            vec![]
+        }
+    }
+
+    /// Drops the line channels that have not been taken, so that a full
+    /// [LineOverflow::Block] channel cannot hold up the command
+    fn drop_line_channels(&mut self) {
+        self.stdout_lines = None;
+        self.stderr_lines = None;
+    }
+
     async fn wait_with_output_internal(&mut self) -> Result<()> {
+        self.drop_line_channels();
         let output = self
             .child_process
             .take()
@@ -505,19 +1919,39 @@ impl CommandRunner {
             .stack_err_with_locationless(|| {
                 format!("{self:?}.wait_with_output() -> failed when waiting on child process")
             })?;
+        let (started_at, finished_at, duration) = self.timing();
         while let Some(handle) = self.handles.pop() {
-            handle.await.stack_err_with_locationless(|| {
-                format!("{self:?}.wait_with_output() -> `Command` task panicked")
-            })?;
+            handle
+                .await
+                .stack_err_with_locationless(|| {
+                    format!("{self:?}.wait_with_output() -> `Command` task panicked")
+                })?
+                .stack_err_with_locationless(|| format!("{self:?}.wait_with_output()"))?;
         }
         let stdout = self.stdout_record.lock().await.iter().copied().collect();
         let stderr = self.stderr_record.lock().await.iter().copied().collect();
+        let combined_chunks = self.combined_chunks().await;
         self.result = Some(CommandResult {
             command: self.command.take().unwrap(),
             status: Some(output.status),
             stdout,
             stderr,
+            terminated_gracefully: false,
+            stdout_dropped_bytes: self.stdout_dropped.load(Ordering::Relaxed),
+            stderr_dropped_bytes: self.stderr_dropped.load(Ordering::Relaxed),
+            combined_chunks,
+            started_at,
+            finished_at,
+            duration,
         });
+        if let Some((level, prefix)) = self.trace.as_ref() {
+            command_event!(
+                *level,
+                command = %prefix,
+                "exited with {} after {duration:?}",
+                output.status
+            );
+        }
         Ok(())
     }
 
@@ -539,6 +1973,7 @@ impl CommandRunner {
     ///
     /// Note: use `Duration::ZERO` if you want a single attempt
     pub async fn wait_with_timeout(&mut self, duration: Duration) -> Result<()> {
+        self.drop_line_channels();
         // backoff control
         let mut interval = Duration::from_millis(1);
         let mut elapsed = Duration::ZERO;
@@ -578,6 +2013,45 @@ impl CommandRunner {
         Ok(())
     }
 
+    /// The same as [CommandRunner::wait_with_output], except that if the
+    /// command does not complete after `duration`, it is terminated and a
+    /// timeout error is returned. The error has a [CommandTimeout] with the
+    /// partial `CommandResult`, use [CommandTimeout::from_error] to get it.
+    pub async fn wait_with_output_timeout(mut self, duration: Duration) -> Result<CommandResult> {
+        // how long the recording tasks get to finish after termination, they can be
+        // held up if a grandchild process keeps the standard streams open
+        const COLLECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+        self.drop_line_channels();
+
+        let child_process = self.child_process.as_mut().stack_err_locationless(
+            "CommandRunner::wait_with_output_timeout -> some termination method has already been \
+             called",
+        )?;
+        if timeout(duration, child_process.wait()).await.is_ok() {
+            self.wait_with_output_internal().await?;
+            return Ok(self.result.take().unwrap())
+        }
+
+        self.terminate()
+            .await
+            .stack_err_locationless("CommandRunner::wait_with_output_timeout")?;
+        let handles = std::mem::take(&mut self.handles);
+        let _ = timeout(COLLECT_TIMEOUT, async {
+            for handle in handles {
+                let _ = handle.await;
+            }
+        })
+        .await;
+        let mut result = self.result.take().unwrap();
+        result.stdout = self.stdout_record.lock().await.iter().copied().collect();
+        result.stderr = self.stderr_record.lock().await.iter().copied().collect();
+        result.stdout_dropped_bytes = self.stdout_dropped.load(Ordering::Relaxed);
+        result.stderr_dropped_bytes = self.stderr_dropped.load(Ordering::Relaxed);
+        result.combined_chunks = self.combined_chunks().await;
+        Err(Error::timeout().add_err_locationless(CommandTimeout { duration, result }))
+    }
+
     /// After [CommandRunner::wait_with_timeout] is successful, this will return
     /// a reference to the `CommandResult`
     pub fn get_command_result(&mut self) -> Option<&CommandResult> {
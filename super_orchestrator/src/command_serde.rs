@@ -0,0 +1,203 @@
+//! `Serialize` and `Deserialize` for `CommandResult`, enabled by the
+//! "command_serde" feature
+
+use std::{
+    borrow::Cow,
+    path::Path,
+    process::ExitStatus,
+    time::{Duration, SystemTime},
+};
+
+use base64::Engine;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use stacked_errors::{Result, StackableErr};
+use tokio::io::AsyncWriteExt;
+
+use crate::{close_file, Command, CommandResult, FileOptions, OutputChunk, OutputStream};
+
+impl CommandResult {
+    /// Writes `self` as pretty JSON to the file from `file_options`, see the
+    /// `Serialize` impl of `CommandResult`
+    pub async fn to_json_file(&self, file_options: FileOptions) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self)
+            .stack_err_locationless("CommandResult::to_json_file when serializing")?;
+        let mut file = file_options
+            .acquire_file()
+            .await
+            .stack_err_locationless("CommandResult::to_json_file")?;
+        file.write_all(&json)
+            .await
+            .stack_err_locationless("CommandResult::to_json_file")?;
+        close_file(file).await.stack_err_locationless(
+            "CommandResult::to_json_file -> unexpected error when closing file",
+        )?;
+        Ok(())
+    }
+
+    /// Reads a `CommandResult` from a JSON file at `file_path` that was written
+    /// by [CommandResult::to_json_file]
+    pub async fn from_json_file(file_path: impl AsRef<Path>) -> Result<Self> {
+        let file_path = file_path.as_ref();
+        let json = FileOptions::read_to_vec(file_path)
+            .await
+            .stack_err_locationless("CommandResult::from_json_file")?;
+        serde_json::from_slice(&json).stack_err_with_locationless(|| {
+            format!("CommandResult::from_json_file when deserializing {file_path:?}")
+        })
+    }
+}
+
+/// The serialized form of an `ExitStatus`, `raw` is the platform specific
+/// value that the status is recreated from
+#[derive(Serialize, Deserialize)]
+struct SerdeExitStatus {
+    code: Option<i32>,
+    raw: i64,
+}
+
+impl From<ExitStatus> for SerdeExitStatus {
+    fn from(status: ExitStatus) -> Self {
+        #[cfg(unix)]
+        let raw = i64::from(std::os::unix::process::ExitStatusExt::into_raw(status));
+        #[cfg(windows)]
+        let raw = i64::from(status.code().map_or(0, |code| code as u32));
+        Self {
+            code: status.code(),
+            raw,
+        }
+    }
+}
+
+impl TryFrom<SerdeExitStatus> for ExitStatus {
+    type Error = std::num::TryFromIntError;
+
+    fn try_from(status: SerdeExitStatus) -> std::result::Result<Self, Self::Error> {
+        #[cfg(unix)]
+        let status = std::os::unix::process::ExitStatusExt::from_raw(i32::try_from(status.raw)?);
+        #[cfg(windows)]
+        let status = std::os::windows::process::ExitStatusExt::from_raw(u32::try_from(status.raw)?);
+        Ok(status)
+    }
+}
+
+/// The serialized form of stdout or stderr bytes. `base64` has the exact bytes
+/// and `lossy` is for human readers, deserialization only falls back to
+/// `lossy` if there is no `base64`.
+#[derive(Serialize, Deserialize)]
+struct SerdeOutput<'a> {
+    #[serde(default)]
+    base64: Option<String>,
+    #[serde(default)]
+    lossy: Option<Cow<'a, str>>,
+}
+
+impl<'a> SerdeOutput<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            base64: Some(base64::engine::general_purpose::STANDARD.encode(bytes)),
+            lossy: Some(String::from_utf8_lossy(bytes)),
+        }
+    }
+
+    fn into_bytes(self) -> std::result::Result<Vec<u8>, base64::DecodeError> {
+        if let Some(base64) = self.base64 {
+            base64::engine::general_purpose::STANDARD.decode(base64)
+        } else {
+            Ok(self.lossy.unwrap_or_default().into_owned().into_bytes())
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SerdeOutputChunk<'a> {
+    stream: OutputStream,
+    time: SystemTime,
+    bytes: SerdeOutput<'a>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SerdeCommandResult<'a> {
+    command: Cow<'a, Command>,
+    status: Option<SerdeExitStatus>,
+    stdout: SerdeOutput<'a>,
+    stderr: SerdeOutput<'a>,
+    #[serde(default)]
+    terminated_gracefully: bool,
+    #[serde(default)]
+    stdout_dropped_bytes: u64,
+    #[serde(default)]
+    stderr_dropped_bytes: u64,
+    #[serde(default)]
+    started_at: Option<SystemTime>,
+    #[serde(default)]
+    finished_at: Option<SystemTime>,
+    #[serde(default)]
+    duration: Duration,
+    #[serde(default)]
+    combined_chunks: Vec<SerdeOutputChunk<'a>>,
+}
+
+/// The stdout and stderr are serialized as objects with both a `base64` field
+/// with the exact bytes and a `lossy` UTF-8 string. Callbacks of the `Command`
+/// are not serialized.
+impl Serialize for CommandResult {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        SerdeCommandResult {
+            command: Cow::Borrowed(&self.command),
+            status: self.status.map(SerdeExitStatus::from),
+            stdout: SerdeOutput::new(&self.stdout),
+            stderr: SerdeOutput::new(&self.stderr),
+            terminated_gracefully: self.terminated_gracefully,
+            stdout_dropped_bytes: self.stdout_dropped_bytes,
+            stderr_dropped_bytes: self.stderr_dropped_bytes,
+            started_at: self.started_at,
+            finished_at: self.finished_at,
+            duration: self.duration,
+            combined_chunks: self
+                .combined_chunks
+                .iter()
+                .map(|chunk| SerdeOutputChunk {
+                    stream: chunk.stream,
+                    time: chunk.time,
+                    bytes: SerdeOutput::new(&chunk.bytes),
+                })
+                .collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CommandResult {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        use serde::de::Error;
+        let res = SerdeCommandResult::deserialize(deserializer)?;
+        Ok(CommandResult {
+            command: res.command.into_owned(),
+            status: res
+                .status
+                .map(ExitStatus::try_from)
+                .transpose()
+                .map_err(D::Error::custom)?,
+            stdout: res.stdout.into_bytes().map_err(D::Error::custom)?,
+            stderr: res.stderr.into_bytes().map_err(D::Error::custom)?,
+            terminated_gracefully: res.terminated_gracefully,
+            stdout_dropped_bytes: res.stdout_dropped_bytes,
+            stderr_dropped_bytes: res.stderr_dropped_bytes,
+            started_at: res.started_at,
+            finished_at: res.finished_at,
+            duration: res.duration,
+            combined_chunks: res
+                .combined_chunks
+                .into_iter()
+                .map(|chunk| {
+                    Ok(OutputChunk {
+                        stream: chunk.stream,
+                        time: chunk.time,
+                        bytes: chunk.bytes.into_bytes()?,
+                    })
+                })
+                .collect::<std::result::Result<_, base64::DecodeError>>()
+                .map_err(D::Error::custom)?,
+        })
+    }
+}
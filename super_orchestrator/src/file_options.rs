@@ -1,11 +1,19 @@
-use std::path::{Path, PathBuf};
+use std::{
+    any::type_name,
+    mem,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use serde::{Deserialize, Serialize};
-use stacked_errors::{Result, StackableErr};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+#[cfg(feature = "sha256")]
+use sha2::{Digest, Sha256};
+use stacked_errors::{bail_locationless, Error, Result, StackableErr};
 use tokio::{
-    fs::{File, OpenOptions},
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
 };
+use tracing::warn;
 
 use crate::{acquire_dir_path, acquire_file_path, close_file};
 
@@ -15,8 +23,32 @@ pub struct WriteOptions {
     pub create: bool,
     /// append rather than truncate
     pub append: bool,
+    /// write to a temporary file and rename it over the destination, see
+    /// [FileOptions::write_all]. This and `append` are mutually exclusive.
+    pub atomic: bool,
+    /// creates missing parent directories of the file
+    pub create_parents: bool,
+    /// the Unix permissions (e.g. `0o600`) of the file, which are set unless
+    /// appending. When appending, this is only used if the file is created
+    /// (subject to the umask). This is ignored with a warning on other
+    /// platforms.
+    pub mode: Option<u32>,
 }
 
+/// Temporary files of atomic writes that have not been modified for this long
+/// are from crashed writers and get removed
+const STALE_TEMP_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// The number of bytes of lines that a [LineWriterHandle] buffers before
+/// writing them
+const LINE_WRITER_CAPACITY: usize = 64 * 1024;
+
+/// The size of the buffer that files are copied and hashed with
+const COPY_BUF_LEN: usize = 64 * 1024;
+
+/// The size of the blocks that [FileOptions::tail_lines] reads backwards with
+const TAIL_BLOCK_LEN: u64 = 64 * 1024;
+
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum ReadOrWrite {
     Read,
@@ -32,10 +64,29 @@ impl ReadOrWrite {
     /// Write mode, with options to `create` if the file should be created if it
     /// does not exist, and `append` to append to the file instead of overwrite.
     pub fn write(create: bool, append: bool) -> Self {
-        Self::Write(WriteOptions { create, append })
+        Self::Write(WriteOptions {
+            create,
+            append,
+            atomic: false,
+            create_parents: false,
+            mode: None,
+        })
     }
 }
 
+/// Options for [FileOptions::copy_with], the default copies only the contents
+/// like [FileOptions::copy]
+#[derive(
+    Debug, Default, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub struct CopyOptions {
+    /// copies the Unix permissions (e.g. the execute bit) of the source, this
+    /// does nothing on other platforms
+    pub preserve_mode: bool,
+    /// copies the modification time of the source
+    pub preserve_mtime: bool,
+}
+
 /// A wrapper combining capabilities from `tokio::fs::{OpenOptions, File}` with
 /// a lot of opinionated defaults and `close_file`.
 #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
@@ -93,6 +144,9 @@ impl FileOptions {
             options: ReadOrWrite::Write(WriteOptions {
                 create: true,
                 append: false,
+                atomic: false,
+                create_parents: false,
+                mode: None,
             }),
         }
     }
@@ -107,18 +161,81 @@ impl FileOptions {
             options: ReadOrWrite::Write(WriteOptions {
                 create: true,
                 append: false,
+                atomic: false,
+                create_parents: false,
+                mode: None,
+            }),
+        }
+    }
+
+    /// `FileOptions` for writing to `file_path` atomically (see
+    /// [FileOptions::write_all]). Sets `create` to true and `append` to false.
+    pub fn write_atomic(file_path: impl AsRef<Path>) -> Self {
+        Self {
+            path: file_path.as_ref().to_owned(),
+            options: ReadOrWrite::Write(WriteOptions {
+                create: true,
+                append: false,
+                atomic: true,
+                create_parents: false,
+                mode: None,
             }),
         }
     }
 
+    /// Sets if missing parent directories are created when writing (see
+    /// [WriteOptions::create_parents]), this has no effect when reading
+    pub fn create_parents(mut self, create_parents: bool) -> Self {
+        if let ReadOrWrite::Write(write_options) = &mut self.options {
+            write_options.create_parents = create_parents;
+        }
+        self
+    }
+
+    /// `FileOptions` for writing an executable script to `file_path` with mode
+    /// `0o755`. Sets `create` to true and `append` to false.
+    pub fn write_executable(file_path: impl AsRef<Path>) -> Self {
+        Self::write(file_path).mode(0o755)
+    }
+
+    /// `FileOptions` for writing a secret like a private key to `file_path`,
+    /// which only the owner can read and write with mode `0o600`. Sets
+    /// `create` to true and `append` to false.
+    pub fn write_secret(file_path: impl AsRef<Path>) -> Self {
+        Self::write(file_path).mode(0o600)
+    }
+
+    /// Sets the mode of the file when writing (see [WriteOptions::mode]), this
+    /// has no effect when reading
+    pub fn mode(mut self, mode: u32) -> Self {
+        if let ReadOrWrite::Write(write_options) = &mut self.options {
+            write_options.mode = Some(mode);
+        }
+        self
+    }
+
     /// Checks only for existence of the directory and file (allowing the file
     /// to not exist if `create` is not true). Returns the combined path if
-    /// `!create`, else returns the directory.
+    /// `!create`, else returns the directory. If `create_parents` is set for
+    /// writing, missing directories are created first.
     pub async fn preacquire(&self) -> Result<PathBuf> {
         let dir = self
             .path
             .parent()
             .stack_err_locationless("FileOptions::preacquire() -> empty path")?;
+        if let ReadOrWrite::Write(WriteOptions {
+            create_parents: true,
+            ..
+        }) = self.options
+        {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)
+                    .await
+                    .stack_err_with_locationless(|| {
+                        format!("{self:?}.preacquire() could not create the parent directories")
+                    })?;
+            }
+        }
         let mut path = acquire_dir_path(dir)
             .await
             .stack_err_with_locationless(|| {
@@ -149,8 +266,16 @@ impl FileOptions {
 
     /// Acquires a `File`, first running [preacquire](FileOptions::preacquire)
     /// on `self` and then opening a file according to the `ReadOrWrite`
-    /// options.
+    /// options. Options with `atomic` set are rejected, since the temporary
+    /// file can only be renamed over the destination once all of it has been
+    /// written, use [FileOptions::write_all] for them.
     pub async fn acquire_file(&self) -> Result<File> {
+        if let ReadOrWrite::Write(WriteOptions { atomic: true, .. }) = self.options {
+            bail_locationless!(
+                "{self:?}.acquire_file() -> atomic writes can only be done with \
+                 `FileOptions::write_all`"
+            )
+        }
         let path = self
             .preacquire()
             .await
@@ -161,29 +286,141 @@ impl FileOptions {
                 .open(path)
                 .await
                 .stack_err_with_locationless(|| format!("{self:?}.acquire_file()"))?,
-            ReadOrWrite::Write(WriteOptions { create, append }) => {
-                if create {
-                    OpenOptions::new()
-                        .write(true)
-                        .create(true)
-                        .truncate(!append)
-                        .append(append)
-                        .open(path)
-                        .await
-                        .stack_err_with_locationless(|| format!("{self:?}.acquire_file()"))?
-                } else {
-                    OpenOptions::new()
-                        .write(true)
-                        .truncate(!append)
-                        .append(append)
-                        .open(path)
+            ReadOrWrite::Write(WriteOptions {
+                create,
+                append,
+                mode,
+                ..
+            }) => {
+                let mut options = OpenOptions::new();
+                options
+                    .write(true)
+                    .create(create)
+                    .truncate(!append)
+                    .append(append);
+                #[cfg(unix)]
+                if let Some(mode) = mode {
+                    options.mode(mode);
+                }
+                let file = options
+                    .open(path)
+                    .await
+                    .stack_err_with_locationless(|| format!("{self:?}.acquire_file()"))?;
+                // the mode of an existing file is only changed if it is overwritten
+                if let (Some(mode), false) = (mode, append) {
+                    set_mode(&file, mode)
                         .await
-                        .stack_err_with_locationless(|| format!("{self:?}.acquire_file()"))?
+                        .stack_err_with_locationless(|| {
+                            format!("{self:?}.acquire_file() -> could not set the mode")
+                        })?;
                 }
+                file
             }
         })
     }
 
+    /// Writes `v` to the file according to the `ReadOrWrite` options, which
+    /// need to be for writing.
+    ///
+    /// If `atomic` is set, `v` is written to a `.<file name>.tmp-<uuid>` file
+    /// in the same directory which is synced and then renamed over the
+    /// destination, so that the destination has either the old or the new
+    /// contents even if the process is killed in the middle. On Unix, the
+    /// directory is synced afterwards so that the rename survives a crash of
+    /// the system. The permissions
+    /// of an existing destination are kept unless `mode` is set. Temporary
+    /// files that crashed writers left behind are removed once they have not
+    /// been modified for 10 minutes. `atomic` cannot be used together with
+    /// `append`.
+    pub async fn write_all(&self, v: impl AsRef<[u8]>) -> Result<()> {
+        let WriteOptions {
+            atomic,
+            append,
+            mode,
+            ..
+        } = match self.options {
+            ReadOrWrite::Read => {
+                bail_locationless!("{self:?}.write_all() -> the options are not for writing")
+            }
+            ReadOrWrite::Write(write_options) => write_options,
+        };
+        if !atomic {
+            let mut file = self
+                .acquire_file()
+                .await
+                .stack_err_locationless("FileOptions::write_all()")?;
+            file.write_all(v.as_ref())
+                .await
+                .stack_err_with_locationless(|| format!("{self:?}.write_all()"))?;
+            return close_file(file).await.stack_err_with_locationless(|| {
+                format!("{self:?}.write_all() -> unexpected error when closing file")
+            })
+        }
+        if append {
+            bail_locationless!(
+                "{self:?}.write_all() -> `atomic` and `append` are mutually exclusive"
+            )
+        }
+        let path = self
+            .preacquire()
+            .await
+            .stack_err_locationless("FileOptions::write_all()")?;
+        let dir = path
+            .parent()
+            .stack_err_with_locationless(|| format!("{self:?}.write_all() -> no parent"))?;
+        let file_name = path
+            .file_name()
+            .stack_err_with_locationless(|| format!("{self:?}.write_all() -> no file name"))?
+            .to_string_lossy();
+        let prefix = format!(".{file_name}.tmp-");
+        remove_stale_temp_files(dir, &prefix).await;
+        let temp_path = dir.join(format!("{prefix}{}", uuid::Uuid::new_v4()));
+        let res = async {
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            if let Some(mode) = mode {
+                options.mode(mode);
+            }
+            let mut file = options
+                .open(&temp_path)
+                .await
+                .stack_err_locationless("could not create the temporary file")?;
+            if let Some(mode) = mode {
+                set_mode(&file, mode)
+                    .await
+                    .stack_err_locationless("could not set the mode")?;
+            } else if let Ok(metadata) = fs::metadata(&path).await {
+                file.set_permissions(metadata.permissions())
+                    .await
+                    .stack_err_locationless("could not copy the permissions of the destination")?;
+            }
+            file.write_all(v.as_ref())
+                .await
+                .stack_err_locationless("could not write the temporary file")?;
+            close_file(file)
+                .await
+                .stack_err_locationless("could not close the temporary file")?;
+            fs::rename(&temp_path, &path).await.stack_err_locationless(
+                "could not rename the temporary file over the destination",
+            )?;
+            // the rename is an entry of the directory, which needs its own sync
+            #[cfg(unix)]
+            File::open(dir)
+                .await
+                .stack_err_locationless("could not open the directory")?
+                .sync_all()
+                .await
+                .stack_err_locationless("could not sync the directory")?;
+            Ok::<(), Error>(())
+        }
+        .await;
+        if res.is_err() {
+            let _ = fs::remove_file(&temp_path).await;
+        }
+        res.stack_err_with_locationless(|| format!("{self:?}.write_all() with {temp_path:?}"))
+    }
+
     /// Reads a file at `file_path` to a string, returning an error if acquiring
     /// the file fails or if the data is not UTF-8
     pub async fn read_to_string(file_path: impl AsRef<Path>) -> Result<String> {
@@ -253,6 +490,22 @@ impl FileOptions {
         Ok(())
     }
 
+    /// Writes `s` to `file_name` in `directory` atomically (see
+    /// [FileOptions::write_all]), returning an error if there is some
+    /// filesystem error
+    pub async fn write2_atomic_str(
+        directory: impl AsRef<Path>,
+        file_name: impl AsRef<Path>,
+        s: &str,
+    ) -> Result<()> {
+        let mut path = directory.as_ref().to_owned();
+        path.push(file_name.as_ref());
+        Self::write_atomic(path)
+            .write_all(s.as_bytes())
+            .await
+            .stack_err_locationless("FileOptions::write2_atomic_str")
+    }
+
     /// Reads a file at `file_path` to a `Vec<u8>`, returning an error if
     /// acquiring the file fails
     pub async fn read_to_vec(file_path: impl AsRef<Path>) -> Result<Vec<u8>> {
@@ -284,6 +537,153 @@ impl FileOptions {
         Ok(v)
     }
 
+    /// Reads at most `max_bytes` from the start of the file at `file_path`,
+    /// returning the bytes and if the file was truncated because it is larger.
+    /// Use this instead of [FileOptions::read_to_vec] for files that can grow
+    /// without bound like logs.
+    pub async fn read_to_vec_limited(
+        file_path: impl AsRef<Path>,
+        max_bytes: u64,
+    ) -> Result<(Vec<u8>, bool)> {
+        let file = Self::read(file_path)
+            .acquire_file()
+            .await
+            .stack_err_locationless("FileOptions::read_to_vec_limited")?;
+        // one more byte tells if there is more
+        let mut v = vec![];
+        file.take(max_bytes.saturating_add(1))
+            .read_to_end(&mut v)
+            .await
+            .stack_err_locationless("FileOptions::read_to_vec_limited")?;
+        let truncated = (v.len() as u64) > max_bytes;
+        if truncated {
+            v.pop();
+        }
+        Ok((v, truncated))
+    }
+
+    /// Like [FileOptions::read_to_vec_limited] but returns a string, returning
+    /// an error if the data is not UTF-8. A character that is cut by the
+    /// truncation is removed.
+    pub async fn read_to_string_limited(
+        file_path: impl AsRef<Path>,
+        max_bytes: u64,
+    ) -> Result<(String, bool)> {
+        let (mut v, truncated) = Self::read_to_vec_limited(file_path, max_bytes)
+            .await
+            .stack_err_locationless("FileOptions::read_to_string_limited")?;
+        if truncated {
+            if let Err(e) = std::str::from_utf8(&v) {
+                // an incomplete character at the end
+                if e.error_len().is_none() {
+                    v.truncate(e.valid_up_to());
+                }
+            }
+        }
+        let s = String::from_utf8(v).stack_err_locationless(
+            "FileOptions::read_to_string_limited -> the data is not UTF-8",
+        )?;
+        Ok((s, truncated))
+    }
+
+    /// Reads at most `max_bytes` from the end of the file at `file_path`,
+    /// returning the bytes and if the start of the file was cut off. When cut
+    /// off, the bytes start at the next line boundary so that there is no
+    /// partial first line, unless the bytes have no newline at all. This is
+    /// for getting the last messages of a log that can be arbitrarily large.
+    pub async fn read_tail(file_path: impl AsRef<Path>, max_bytes: u64) -> Result<(Vec<u8>, bool)> {
+        let mut file = Self::read(file_path)
+            .acquire_file()
+            .await
+            .stack_err_locationless("FileOptions::read_tail")?;
+        let len = file
+            .metadata()
+            .await
+            .stack_err_locationless("FileOptions::read_tail")?
+            .len();
+        if len <= max_bytes {
+            let mut v = vec![];
+            file.take(max_bytes)
+                .read_to_end(&mut v)
+                .await
+                .stack_err_locationless("FileOptions::read_tail")?;
+            return Ok((v, false))
+        }
+        // the byte before the tail tells if the tail starts at a line boundary
+        file.seek(SeekFrom::Start(len - max_bytes - 1))
+            .await
+            .stack_err_locationless("FileOptions::read_tail")?;
+        let mut v = vec![];
+        file.take(max_bytes + 1)
+            .read_to_end(&mut v)
+            .await
+            .stack_err_locationless("FileOptions::read_tail")?;
+        let start = match v.iter().position(|b| *b == b'\n') {
+            Some(i) => i + 1,
+            None => 1.min(v.len()),
+        };
+        v.drain(..start);
+        Ok((v, true))
+    }
+
+    /// Returns the last `n` lines of the file at `file_path`, without the line
+    /// endings (either "\n" or "\r\n"). The file is read backwards in blocks
+    /// from the end until there are enough lines, so only the lines that are
+    /// returned are read into memory. The last line does not need a trailing
+    /// newline, and lines that are not UTF-8 are converted lossily.
+    pub async fn tail_lines(file_path: impl AsRef<Path>, n: usize) -> Result<Vec<String>> {
+        let file_path = file_path.as_ref();
+        let mut file = Self::read(file_path)
+            .acquire_file()
+            .await
+            .stack_err_locationless("FileOptions::tail_lines")?;
+        let len = file
+            .metadata()
+            .await
+            .stack_err_locationless("FileOptions::tail_lines")?
+            .len();
+        if n == 0 {
+            return Ok(vec![])
+        }
+        // the blocks from the end backwards
+        let mut blocks: Vec<Vec<u8>> = vec![];
+        let mut newlines = 0;
+        let mut pos = len;
+        while pos > 0 {
+            let block_len = pos.min(TAIL_BLOCK_LEN);
+            pos -= block_len;
+            file.seek(SeekFrom::Start(pos))
+                .await
+                .stack_err_locationless("FileOptions::tail_lines")?;
+            let mut block = vec![0; block_len as usize];
+            file.read_exact(&mut block)
+                .await
+                .stack_err_with_locationless(|| {
+                    format!("FileOptions::tail_lines -> could not read {file_path:?}")
+                })?;
+            newlines += block.iter().filter(|b| **b == b'\n').count();
+            blocks.push(block);
+            // a trailing newline does not start another line
+            let trailing = usize::from(blocks[0].last() == Some(&b'\n'));
+            if newlines >= n + trailing {
+                break
+            }
+        }
+        let tail: Vec<u8> = blocks.into_iter().rev().flatten().collect();
+        let tail = tail.strip_suffix(b"\n").unwrap_or(&tail);
+        if tail.is_empty() && (len == 0) {
+            return Ok(vec![])
+        }
+        let tail = &tail[last_lines_start(tail, n).unwrap_or(0)..];
+        Ok(tail
+            .split(|b| *b == b'\n')
+            .map(|line| {
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+                String::from_utf8_lossy(line).into_owned()
+            })
+            .collect())
+    }
+
     /// Writes `v` to a file at `file_path`, returning an error if acquiring the
     /// file fails or if there is some filesystem error. Uses the
     /// [FileOptions::write] defaults.
@@ -322,40 +722,634 @@ impl FileOptions {
         Ok(())
     }
 
-    /// Copies bytes from the source to destination files. Does not do any
-    /// permissions copying unlike `tokio::fs::copy`.
-    pub async fn copy(
-        src_file_path: impl AsRef<Path>,
-        dst_file_path: impl AsRef<Path>,
+    /// Writes `value` as pretty JSON to a file at `file_path`, returning an
+    /// error if serialization fails or if there is some filesystem error. Uses
+    /// the [FileOptions::write] defaults, see [FileOptions::write_json_with]
+    /// for compact JSON or other options.
+    pub async fn write_json<T: ?Sized + Serialize>(
+        file_path: impl AsRef<Path>,
+        value: &T,
     ) -> Result<()> {
-        let src_file_path = src_file_path.as_ref();
-        let dst_file_path = dst_file_path.as_ref();
-        let src = Self::read(src_file_path)
-            .acquire_file()
+        Self::write(file_path)
+            .write_json_with(value, true)
+            .await
+            .stack_err_locationless("FileOptions::write_json")
+    }
+
+    /// Writes `value` as pretty JSON to `file_name` in `directory`. Uses the
+    /// [FileOptions::write2] defaults.
+    pub async fn write2_json<T: ?Sized + Serialize>(
+        directory: impl AsRef<Path>,
+        file_name: impl AsRef<Path>,
+        value: &T,
+    ) -> Result<()> {
+        Self::write2(directory, file_name)
+            .write_json_with(value, true)
+            .await
+            .stack_err_locationless("FileOptions::write2_json")
+    }
+
+    /// Writes `value` as JSON with these options (see
+    /// [FileOptions::write_all]), which is pretty if `pretty` or else compact
+    pub async fn write_json_with<T: ?Sized + Serialize>(
+        &self,
+        value: &T,
+        pretty: bool,
+    ) -> Result<()> {
+        let res = if pretty {
+            serde_json::to_vec_pretty(value)
+        } else {
+            serde_json::to_vec(value)
+        };
+        let v = res.stack_err_with_locationless(|| {
+            format!(
+                "{self:?}.write_json_with::<{}>() -> could not serialize",
+                type_name::<T>()
+            )
+        })?;
+        self.write_all(v).await.stack_err_with_locationless(|| {
+            format!("{self:?}.write_json_with::<{}>()", type_name::<T>())
+        })
+    }
+
+    /// Reads JSON from a file at `file_path` and deserializes it, returning an
+    /// error if acquiring the file fails or with the line and column where
+    /// deserialization failed
+    pub async fn read_json<T: DeserializeOwned>(file_path: impl AsRef<Path>) -> Result<T> {
+        let file_path = file_path.as_ref();
+        let v = Self::read_to_vec(file_path)
             .await
             .stack_err_with_locationless(|| {
-                format!(
-                    "FileOptions::copy(src_file_path: {src_file_path:?}, dst_file_path: \
-                     {dst_file_path:?}) when opening source"
-                )
+                format!("FileOptions::read_json::<{}>()", type_name::<T>())
             })?;
-        let mut dst = Self::write(dst_file_path)
-            .acquire_file()
+        serde_json::from_slice(&v).stack_err_with_locationless(|| {
+            format!(
+                "FileOptions::read_json::<{}>() -> could not deserialize {file_path:?}",
+                type_name::<T>()
+            )
+        })
+    }
+
+    /// Reads JSON from `file_name` in `directory` and deserializes it, see
+    /// [FileOptions::read_json]
+    pub async fn read2_json<T: DeserializeOwned>(
+        directory: impl AsRef<Path>,
+        file_name: impl AsRef<Path>,
+    ) -> Result<T> {
+        let mut path = directory.as_ref().to_owned();
+        path.push(file_name.as_ref());
+        Self::read_json(path)
+            .await
+            .stack_err_locationless("FileOptions::read2_json")
+    }
+
+    /// Writes `value` as pretty TOML to a file at `file_path`, returning an
+    /// error if serialization fails or if there is some filesystem error. Uses
+    /// the [FileOptions::write] defaults, see [FileOptions::write_toml_with]
+    /// for other options.
+    #[cfg(feature = "toml")]
+    pub async fn write_toml<T: ?Sized + Serialize>(
+        file_path: impl AsRef<Path>,
+        value: &T,
+    ) -> Result<()> {
+        Self::write(file_path)
+            .write_toml_with(value, true)
+            .await
+            .stack_err_locationless("FileOptions::write_toml")
+    }
+
+    /// Writes `value` as pretty TOML to `file_name` in `directory`. Uses the
+    /// [FileOptions::write2] defaults.
+    #[cfg(feature = "toml")]
+    pub async fn write2_toml<T: ?Sized + Serialize>(
+        directory: impl AsRef<Path>,
+        file_name: impl AsRef<Path>,
+        value: &T,
+    ) -> Result<()> {
+        Self::write2(directory, file_name)
+            .write_toml_with(value, true)
+            .await
+            .stack_err_locationless("FileOptions::write2_toml")
+    }
+
+    /// Writes `value` as TOML with these options (see
+    /// [FileOptions::write_all]), which is pretty if `pretty` (arrays are split
+    /// over multiple lines) or else compact
+    #[cfg(feature = "toml")]
+    pub async fn write_toml_with<T: ?Sized + Serialize>(
+        &self,
+        value: &T,
+        pretty: bool,
+    ) -> Result<()> {
+        let res = if pretty {
+            toml::to_string_pretty(value)
+        } else {
+            toml::to_string(value)
+        };
+        let s = res.stack_err_with_locationless(|| {
+            format!(
+                "{self:?}.write_toml_with::<{}>() -> could not serialize",
+                type_name::<T>()
+            )
+        })?;
+        self.write_all(s).await.stack_err_with_locationless(|| {
+            format!("{self:?}.write_toml_with::<{}>()", type_name::<T>())
+        })
+    }
+
+    /// Reads TOML from a file at `file_path` and deserializes it, returning an
+    /// error if acquiring the file fails or with the line and column where
+    /// deserialization failed
+    #[cfg(feature = "toml")]
+    pub async fn read_toml<T: DeserializeOwned>(file_path: impl AsRef<Path>) -> Result<T> {
+        let file_path = file_path.as_ref();
+        let s = Self::read_to_string(file_path)
             .await
             .stack_err_with_locationless(|| {
-                format!(
-                    "FileOptions::copy(src_file_path: {src_file_path:?}, dst_file_path: \
-                     {dst_file_path:?}) when opening destination"
-                )
+                format!("FileOptions::read_toml::<{}>()", type_name::<T>())
             })?;
-        tokio::io::copy_buf(&mut BufReader::new(src), &mut dst)
+        toml::from_str(&s).stack_err_with_locationless(|| {
+            format!(
+                "FileOptions::read_toml::<{}>() -> could not deserialize {file_path:?}",
+                type_name::<T>()
+            )
+        })
+    }
+
+    /// Reads TOML from `file_name` in `directory` and deserializes it, see
+    /// [FileOptions::read_toml]
+    #[cfg(feature = "toml")]
+    pub async fn read2_toml<T: DeserializeOwned>(
+        directory: impl AsRef<Path>,
+        file_name: impl AsRef<Path>,
+    ) -> Result<T> {
+        let mut path = directory.as_ref().to_owned();
+        path.push(file_name.as_ref());
+        Self::read_toml(path)
+            .await
+            .stack_err_locationless("FileOptions::read2_toml")
+    }
+
+    /// Writes `value` as YAML to a file at `file_path`, returning an error if
+    /// serialization fails or if there is some filesystem error. Uses the
+    /// [FileOptions::write] defaults, see [FileOptions::write_yaml_with] for
+    /// other options.
+    #[cfg(feature = "yaml")]
+    pub async fn write_yaml<T: ?Sized + Serialize>(
+        file_path: impl AsRef<Path>,
+        value: &T,
+    ) -> Result<()> {
+        Self::write(file_path)
+            .write_yaml_with(value)
+            .await
+            .stack_err_locationless("FileOptions::write_yaml")
+    }
+
+    /// Writes `value` as YAML to `file_name` in `directory`. Uses the
+    /// [FileOptions::write2] defaults.
+    #[cfg(feature = "yaml")]
+    pub async fn write2_yaml<T: ?Sized + Serialize>(
+        directory: impl AsRef<Path>,
+        file_name: impl AsRef<Path>,
+        value: &T,
+    ) -> Result<()> {
+        Self::write2(directory, file_name)
+            .write_yaml_with(value)
+            .await
+            .stack_err_locationless("FileOptions::write2_yaml")
+    }
+
+    /// Writes `value` as YAML with these options (see
+    /// [FileOptions::write_all])
+    #[cfg(feature = "yaml")]
+    pub async fn write_yaml_with<T: ?Sized + Serialize>(&self, value: &T) -> Result<()> {
+        let s = serde_norway::to_string(value).stack_err_with_locationless(|| {
+            format!(
+                "{self:?}.write_yaml_with::<{}>() -> could not serialize",
+                type_name::<T>()
+            )
+        })?;
+        self.write_all(s).await.stack_err_with_locationless(|| {
+            format!("{self:?}.write_yaml_with::<{}>()", type_name::<T>())
+        })
+    }
+
+    /// Reads YAML from a file at `file_path` and deserializes it, returning an
+    /// error if acquiring the file fails or with the line and column where
+    /// deserialization failed
+    #[cfg(feature = "yaml")]
+    pub async fn read_yaml<T: DeserializeOwned>(file_path: impl AsRef<Path>) -> Result<T> {
+        let file_path = file_path.as_ref();
+        let v = Self::read_to_vec(file_path)
             .await
             .stack_err_with_locationless(|| {
-                format!(
-                    "FileOptions::copy(src_file_path: {src_file_path:?}, dst_file_path: \
-                     {dst_file_path:?}) when copying"
-                )
+                format!("FileOptions::read_yaml::<{}>()", type_name::<T>())
+            })?;
+        serde_norway::from_slice(&v).stack_err_with_locationless(|| {
+            format!(
+                "FileOptions::read_yaml::<{}>() -> could not deserialize {file_path:?}",
+                type_name::<T>()
+            )
+        })
+    }
+
+    /// Reads YAML from `file_name` in `directory` and deserializes it, see
+    /// [FileOptions::read_yaml]
+    #[cfg(feature = "yaml")]
+    pub async fn read2_yaml<T: DeserializeOwned>(
+        directory: impl AsRef<Path>,
+        file_name: impl AsRef<Path>,
+    ) -> Result<T> {
+        let mut path = directory.as_ref().to_owned();
+        path.push(file_name.as_ref());
+        Self::read_yaml(path)
+            .await
+            .stack_err_locationless("FileOptions::read2_yaml")
+    }
+
+    /// Appends `line` and a newline to the file at `file_path`, creating the
+    /// file if it does not exist, then flushes and closes the file. The line
+    /// is written in a single write call, so lines that different tasks or
+    /// processes append at the same time do not interleave (for lines smaller
+    /// than a few MiB). `line` should not have a newline itself. Use
+    /// [FileOptions::line_writer] for appending many lines in a loop.
+    pub async fn append_line(file_path: impl AsRef<Path>, line: &str) -> Result<()> {
+        let mut file = Self::new(file_path, ReadOrWrite::write(true, true))
+            .acquire_file()
+            .await
+            .stack_err_locationless("FileOptions::append_line")?;
+        let mut v = Vec::with_capacity(line.len() + 1);
+        v.extend_from_slice(line.as_bytes());
+        v.push(b'\n');
+        file.write_all(&v)
+            .await
+            .stack_err_locationless("FileOptions::append_line")?;
+        close_file(file).await.stack_err_locationless(
+            "FileOptions::append_line -> unexpected error when closing file",
+        )?;
+        Ok(())
+    }
+
+    /// Opens the file at `file_path` for appending lines with a
+    /// [LineWriterHandle], creating the file if it does not exist
+    pub async fn line_writer(file_path: impl AsRef<Path>) -> Result<LineWriterHandle> {
+        let options = Self::new(file_path, ReadOrWrite::write(true, true));
+        let file = options
+            .acquire_file()
+            .await
+            .stack_err_locationless("FileOptions::line_writer")?;
+        Ok(LineWriterHandle {
+            file: Some(file),
+            path: options.path,
+            buf: vec![],
+        })
+    }
+
+    /// Copies bytes from the source to destination files. Does not do any
+    /// permissions copying unlike `tokio::fs::copy`, see
+    /// [FileOptions::copy_with] for that.
+    pub async fn copy(
+        src_file_path: impl AsRef<Path>,
+        dst_file_path: impl AsRef<Path>,
+    ) -> Result<()> {
+        Self::copy_with(src_file_path, dst_file_path, CopyOptions::default()).await
+    }
+
+    /// Like [FileOptions::copy], but with `options` for also copying the
+    /// permissions or modification time of the source, e.g. so that a copied
+    /// entrypoint script stays executable
+    pub async fn copy_with(
+        src_file_path: impl AsRef<Path>,
+        dst_file_path: impl AsRef<Path>,
+        options: CopyOptions,
+    ) -> Result<()> {
+        copy_chunks(
+            "copy_with",
+            src_file_path.as_ref(),
+            dst_file_path.as_ref(),
+            options,
+            |_| (),
+        )
+        .await
+    }
+
+    /// Like [FileOptions::copy_with], but also returns the SHA-256 digest of
+    /// the bytes that were copied, which can be compared with
+    /// [FileOptions::verify_sha256] on the other end of a transfer
+    #[cfg(feature = "sha256")]
+    pub async fn copy_with_sha256(
+        src_file_path: impl AsRef<Path>,
+        dst_file_path: impl AsRef<Path>,
+        options: CopyOptions,
+    ) -> Result<[u8; 32]> {
+        let mut hasher = Sha256::new();
+        copy_chunks(
+            "copy_with_sha256",
+            src_file_path.as_ref(),
+            dst_file_path.as_ref(),
+            options,
+            |chunk| hasher.update(chunk),
+        )
+        .await?;
+        Ok(hasher.finalize().into())
+    }
+
+    /// Returns the SHA-256 digest of the file at `file_path`, which is read
+    /// with a fixed size buffer so that large files are not read into memory
+    #[cfg(feature = "sha256")]
+    pub async fn sha256(file_path: impl AsRef<Path>) -> Result<[u8; 32]> {
+        let file_path = file_path.as_ref();
+        let mut file = Self::read(file_path)
+            .acquire_file()
+            .await
+            .stack_err_locationless("FileOptions::sha256")?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; COPY_BUF_LEN];
+        loop {
+            let n = file.read(&mut buf).await.stack_err_with_locationless(|| {
+                format!("FileOptions::sha256 -> could not read {file_path:?}")
             })?;
+            if n == 0 {
+                break
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    /// Returns an error with both digests in hexadecimal if the SHA-256 digest
+    /// of the file at `file_path` is not `expected`
+    #[cfg(feature = "sha256")]
+    pub async fn verify_sha256(file_path: impl AsRef<Path>, expected: &[u8; 32]) -> Result<()> {
+        let file_path = file_path.as_ref();
+        let digest = Self::sha256(file_path)
+            .await
+            .stack_err_locationless("FileOptions::verify_sha256")?;
+        if &digest != expected {
+            bail_locationless!(
+                "FileOptions::verify_sha256 -> the SHA-256 digest of {file_path:?} is {} but {} \
+                 was expected",
+                hex(&digest),
+                hex(expected)
+            )
+        }
+        Ok(())
+    }
+}
+
+/// Copies the source to the destination file in chunks, passing each chunk to
+/// `on_chunk`, and then copies the metadata according to `options`
+async fn copy_chunks(
+    fn_name: &str,
+    src_file_path: &Path,
+    dst_file_path: &Path,
+    options: CopyOptions,
+    mut on_chunk: impl FnMut(&[u8]),
+) -> Result<()> {
+    let fn_name = || {
+        format!(
+            "FileOptions::{fn_name}(src_file_path: {src_file_path:?}, dst_file_path: \
+             {dst_file_path:?})"
+        )
+    };
+    let mut src = FileOptions::read(src_file_path)
+        .acquire_file()
+        .await
+        .stack_err_with_locationless(|| format!("{} when opening source", fn_name()))?;
+    let mut dst = FileOptions::write(dst_file_path)
+        .acquire_file()
+        .await
+        .stack_err_with_locationless(|| format!("{} when opening destination", fn_name()))?;
+    let mut buf = vec![0; COPY_BUF_LEN];
+    loop {
+        let n = src
+            .read(&mut buf)
+            .await
+            .stack_err_with_locationless(|| format!("{} when reading", fn_name()))?;
+        if n == 0 {
+            break
+        }
+        on_chunk(&buf[..n]);
+        dst.write_all(&buf[..n])
+            .await
+            .stack_err_with_locationless(|| format!("{} when writing", fn_name()))?;
+    }
+    dst.flush()
+        .await
+        .stack_err_with_locationless(|| format!("{} when flushing", fn_name()))?;
+    if options.preserve_mode || options.preserve_mtime {
+        let metadata = src.metadata().await;
+        copy_metadata(metadata, dst, options)
+            .await
+            .stack_err_with_locationless(|| format!("{} when copying {options:?}", fn_name()))?;
+    }
+    Ok(())
+}
+
+/// Sets the permissions and modification time of `dst` from the `metadata` of
+/// the source according to `options`
+async fn copy_metadata(
+    metadata: std::io::Result<std::fs::Metadata>,
+    dst: File,
+    options: CopyOptions,
+) -> Result<()> {
+    let metadata = metadata.stack_err_locationless("could not get the metadata of the source")?;
+    #[cfg(unix)]
+    if options.preserve_mode {
+        use std::os::unix::fs::PermissionsExt;
+        set_mode(&dst, metadata.permissions().mode() & 0o7777)
+            .await
+            .stack_err_locationless("could not set the mode")?;
+    }
+    if options.preserve_mtime {
+        let mtime = metadata
+            .modified()
+            .stack_err_locationless("could not get the modification time of the source")?;
+        // this waits for the writes to finish, which would otherwise update the time
+        let dst = dst.into_std().await;
+        tokio::task::spawn_blocking(move || dst.set_modified(mtime))
+            .await
+            .map_err(Error::from_err_locationless)
+            .and_then(|res| res.stack_err_locationless("could not set the modification time"))?;
+    }
+    Ok(())
+}
+
+/// Returns where the last `lines` lines of `bytes` start, or `None` if `bytes`
+/// has fewer lines. `bytes` should not have a trailing newline.
+pub(crate) fn last_lines_start(bytes: &[u8], lines: usize) -> Option<usize> {
+    if lines == 0 {
+        return Some(bytes.len())
+    }
+    bytes
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, b)| **b == b'\n')
+        .nth(lines - 1)
+        .map(|(i, _)| i + 1)
+}
+
+/// Returns `bytes` as lowercase hexadecimal
+#[cfg(feature = "sha256")]
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Keeps a file open for appending lines in a hot loop, see
+/// [FileOptions::line_writer]. Lines are buffered and written in batches of
+/// whole lines, so like with [FileOptions::append_line] the lines of
+/// different writers do not interleave. Call [LineWriterHandle::flush] to write
+/// the buffered lines, and [LineWriterHandle::close] at the end. Lines that are
+/// still buffered when this is dropped are written on a best-effort basis by a
+/// blocking task in the background (so they may appear a little later), with
+/// a warning if that fails.
+#[derive(Debug)]
+pub struct LineWriterHandle {
+    /// only `None` after the file was closed
+    file: Option<File>,
+    path: PathBuf,
+    buf: Vec<u8>,
+}
+
+impl LineWriterHandle {
+    /// Returns the path of the file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Buffers `line` and a newline, writing the buffered lines if they are
+    /// over 64 KiB. `line` should not have a newline itself.
+    pub async fn write_line(&mut self, line: &str) -> Result<()> {
+        if self.buf.len() + line.len() >= LINE_WRITER_CAPACITY {
+            self.write_buf().await?;
+        }
+        self.buf.extend_from_slice(line.as_bytes());
+        self.buf.push(b'\n');
+        if self.buf.len() >= LINE_WRITER_CAPACITY {
+            self.write_buf().await?;
+        }
         Ok(())
     }
+
+    /// Writes the buffered lines and flushes the file
+    pub async fn flush(&mut self) -> Result<()> {
+        self.write_buf()
+            .await
+            .stack_err_with_locationless(|| format!("LineWriterHandle::flush for {:?}", self.path))
+    }
+
+    /// Writes the buffered lines and closes the file with [close_file]
+    pub async fn close(mut self) -> Result<()> {
+        self.write_buf().await?;
+        let Some(file) = self.file.take() else {
+            return Ok(())
+        };
+        close_file(file)
+            .await
+            .stack_err_with_locationless(|| format!("LineWriterHandle::close for {:?}", self.path))
+    }
+
+    /// Writes the buffered lines and waits for the write to finish, so that the
+    /// file can be converted back in `drop`
+    async fn write_buf(&mut self) -> Result<()> {
+        let Some(file) = self.file.as_mut() else {
+            return Ok(())
+        };
+        if self.buf.is_empty() {
+            return Ok(())
+        }
+        let res = file.write_all(&self.buf).await;
+        let res = match res {
+            Ok(()) => file.flush().await,
+            Err(e) => Err(e),
+        };
+        self.buf.clear();
+        res.stack_err_with_locationless(|| {
+            format!(
+                "LineWriterHandle -> could not write lines to {:?}",
+                self.path
+            )
+        })
+    }
+}
+
+impl Drop for LineWriterHandle {
+    fn drop(&mut self) {
+        if self.buf.is_empty() {
+            return
+        }
+        let Some(file) = self.file.take() else { return };
+        let buf = mem::take(&mut self.buf);
+        let path = mem::take(&mut self.path);
+        let write = move || {
+            let res = match file.try_into_std() {
+                Ok(mut file) => std::io::Write::write_all(&mut file, &buf),
+                Err(_) => Err(std::io::Error::other(
+                    "an operation on the file was still in progress",
+                )),
+            };
+            if let Err(e) = res {
+                warn!(
+                    "LineWriterHandle was dropped and could not write {} bytes of buffered lines \
+                     to {path:?}: {e}",
+                    buf.len(),
+                );
+            }
+        };
+        // the write blocks, which must not happen on a runtime thread
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(write);
+            }
+            Err(_) => write(),
+        }
+    }
+}
+
+/// Sets the permissions of `file` to exactly `mode`, regardless of the umask
+#[cfg(unix)]
+async fn set_mode(file: &File, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(std::fs::Permissions::from_mode(mode))
+        .await
+}
+
+#[cfg(not(unix))]
+async fn set_mode(_file: &File, mode: u32) -> std::io::Result<()> {
+    warn!("the file mode {mode:#o} is ignored on this platform");
+    Ok(())
+}
+
+/// Removes the files in `dir` that start with `prefix` and have not been
+/// modified for `STALE_TEMP_AGE`. Failures are only warned about, since they do
+/// not prevent the write.
+async fn remove_stale_temp_files(dir: &Path, prefix: &str) {
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("could not look for stale temporary files in {dir:?}: {e}");
+            return
+        }
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if !entry.file_name().to_string_lossy().starts_with(prefix) {
+            continue
+        }
+        let stale = entry
+            .metadata()
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age >= STALE_TEMP_AGE);
+        if stale {
+            if let Err(e) = fs::remove_file(entry.path()).await {
+                warn!(
+                    "could not remove the stale temporary file {:?}: {e}",
+                    entry.path()
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,206 @@
+use std::{
+    future::Future,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use futures_core::Stream;
+use stacked_errors::{Error, Result, StackableErr};
+use tokio::time::{sleep, Instant, Sleep};
+
+/// The default interval that files are polled with by [wait_for_file] and
+/// [FileWatcher]
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A change to a file that a [FileWatcher] observed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileEvent {
+    /// The file did not exist and now does
+    Created,
+    /// The length or modification time of the file changed
+    Modified,
+    /// The file existed and now does not
+    Removed,
+}
+
+/// What is compared between polls
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileState {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+/// Returns the state of the file at `path`, or `None` if it does not exist
+fn file_state(metadata: std::io::Result<std::fs::Metadata>) -> std::io::Result<Option<FileState>> {
+    match metadata {
+        Ok(metadata) => Ok(Some(FileState {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Waits for a file (or directory) at `path` to exist, e.g. a "ready" marker
+/// that a container writes to a shared volume. Returns immediately if it
+/// already exists, or a timeout error if it does not exist within `timeout`.
+/// This polls every [DEFAULT_POLL_INTERVAL], which works across volume drivers
+/// that do not propagate filesystem notifications.
+///
+/// This is cancel safe.
+pub async fn wait_for_file(path: impl AsRef<Path>, timeout: Duration) -> Result<()> {
+    wait_for_file_with_interval(path, timeout, DEFAULT_POLL_INTERVAL).await
+}
+
+/// The same as [wait_for_file], but polls every `interval`
+pub async fn wait_for_file_with_interval(
+    path: impl AsRef<Path>,
+    timeout: Duration,
+    interval: Duration,
+) -> Result<()> {
+    let path = path.as_ref();
+    let deadline = Instant::now().checked_add(timeout);
+    loop {
+        let exists = tokio::fs::try_exists(path)
+            .await
+            .stack_err_with_locationless(|| format!("wait_for_file(path: {path:?})"))?;
+        if exists {
+            return Ok(())
+        }
+        let now = Instant::now();
+        if deadline.is_some_and(|deadline| now >= deadline) {
+            return Err(Error::timeout().add_err_locationless(format!(
+                "wait_for_file(path: {path:?}, timeout: {timeout:?}) timed out"
+            )))
+        }
+        let delay = match deadline {
+            Some(deadline) => interval.min(deadline - now),
+            None => interval,
+        };
+        sleep(delay).await;
+    }
+}
+
+/// Returns a [FileWatcher] for the file at `path`, which reports the changes
+/// after this call
+pub fn watch_file(path: impl AsRef<Path>) -> FileWatcher {
+    let path = path.as_ref().to_owned();
+    // if this fails, the first poll returns the error
+    let (last, initial_err) = match file_state(std::fs::metadata(&path)) {
+        Ok(last) => (last, None),
+        Err(e) => (None, Some(e)),
+    };
+    FileWatcher {
+        path,
+        interval: DEFAULT_POLL_INTERVAL,
+        last,
+        initial_err,
+        metadata: None,
+        sleep: None,
+    }
+}
+
+/// The future of a metadata query of a [FileWatcher]
+type MetadataFuture = Pin<Box<dyn Future<Output = std::io::Result<std::fs::Metadata>> + Send>>;
+
+/// Watches a file for changes by polling its length and modification time, see
+/// [watch_file]. Polling is used because filesystem notifications do not
+/// propagate reliably across some volume drivers. Changes that happen within
+/// one interval are reported as one event, and a change that keeps both the
+/// length and the modification time (which can have a coarse resolution)
+/// the same is not noticed.
+///
+/// This is a [Stream] of events that never ends, an error (e.g. because of
+/// missing permissions) is yielded as an item and the file is polled again
+/// after the interval. [FileWatcher::next_event] can be used without a
+/// `Stream` extension trait.
+pub struct FileWatcher {
+    path: PathBuf,
+    interval: Duration,
+    last: Option<FileState>,
+    initial_err: Option<std::io::Error>,
+    metadata: Option<MetadataFuture>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl std::fmt::Debug for FileWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileWatcher")
+            .field("path", &self.path)
+            .field("interval", &self.interval)
+            .field("last", &self.last)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FileWatcher {
+    /// Sets the interval that the file is polled with, this defaults to
+    /// [DEFAULT_POLL_INTERVAL]
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Returns the path of the watched file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Waits for the next change to the file. Use [tokio::time::timeout] or
+    /// `select!` to stop waiting.
+    ///
+    /// This is cancel safe, a change is only consumed when it is returned.
+    pub async fn next_event(&mut self) -> Result<FileEvent> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx))
+            .await
+            .expect("`FileWatcher` streams never end")
+    }
+}
+
+impl Stream for FileWatcher {
+    type Item = Result<FileEvent>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some(e) = this.initial_err.take() {
+            return Poll::Ready(Some(Err(e).stack_err_with_locationless(|| {
+                format!("watch_file(path: {:?})", this.path)
+            })))
+        }
+        loop {
+            if let Some(sleep) = &mut this.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+            let metadata = this
+                .metadata
+                .get_or_insert_with(|| Box::pin(tokio::fs::metadata(this.path.clone())));
+            let metadata = ready!(metadata.as_mut().poll(cx));
+            this.metadata = None;
+            let state = match file_state(metadata) {
+                Ok(state) => state,
+                Err(e) => {
+                    this.sleep = Some(Box::pin(sleep(this.interval)));
+                    return Poll::Ready(Some(Err(e).stack_err_with_locationless(|| {
+                        format!("FileWatcher::poll_next for {:?}", this.path)
+                    })))
+                }
+            };
+            let event = match (this.last, state) {
+                (None, Some(_)) => Some(FileEvent::Created),
+                (Some(_), None) => Some(FileEvent::Removed),
+                (Some(last), Some(state)) if last != state => Some(FileEvent::Modified),
+                _ => None,
+            };
+            if let Some(event) = event {
+                this.last = state;
+                return Poll::Ready(Some(Ok(event)))
+            }
+            this.sleep = Some(Box::pin(sleep(this.interval)));
+        }
+    }
+}
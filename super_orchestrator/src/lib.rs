@@ -1,10 +1,15 @@
 //! See README.md for more
 
 mod command;
+mod command_pipeline;
 mod command_runner;
+#[cfg(feature = "command_serde")]
+mod command_serde;
 mod file_options;
+mod file_watcher;
 mod misc;
 mod parsing;
+mod temp_workspace;
 
 // TODO there is a bunch of rigor in `cli_docker` that has not yet been brought
 // to `api_docker`, for instance there are no limiters on container output
@@ -25,10 +30,13 @@ pub mod api_docker;
 pub mod cli_docker;
 mod paths;
 pub use command::*;
+pub use command_pipeline::*;
 pub use command_runner::*;
 /// Communication with `NetMessenger`
 pub mod net_message;
 pub use file_options::*;
+pub use file_watcher::*;
 pub use misc::*;
 pub use parsing::*;
 pub use paths::*;
+pub use temp_workspace::*;
@@ -9,7 +9,8 @@ use std::{
     time::Duration,
 };
 
-pub(crate) use color_cycle::next_terminal_color;
+pub use color_cycle::set_ansi_colors;
+pub(crate) use color_cycle::{ansi_colors, next_terminal_color};
 use stacked_errors::{bail_locationless, Result, StackableErr, TimeoutError};
 use tokio::{
     fs::{read_dir, remove_file, File},
@@ -18,7 +19,11 @@ use tokio::{
 };
 use tracing::warn;
 
-use crate::{acquire_dir_path, Command};
+use crate::{
+    acquire_dir_path,
+    net_message::{tag_hash, TYPE_ID_LEN},
+    Command,
+};
 
 /// A convenience wrapper around the functionality of [tokio::signal::ctrl_c]
 pub struct CtrlCTask {
@@ -70,19 +75,12 @@ pub fn random_name(name: impl std::fmt::Display) -> String {
 
 /// Takes the hash of the type name of `T` and returns it. Has the
 /// potential to change between compiler versions.
-pub fn type_hash<T: ?Sized>() -> [u8; 16] {
+pub fn type_hash<T: ?Sized>() -> [u8; TYPE_ID_LEN] {
     // we can't make this `const` currently because of `type_name`, however it
     // should compile down to the result in practice, at least on release mode
 
     // TODO `type_name` should be const soon
-    use sha3::{Digest, Sha3_256};
-    let name = type_name::<T>();
-    let mut hasher = Sha3_256::new();
-    hasher.update(name.as_bytes());
-    let tmp: [u8; 32] = hasher.finalize().into();
-    let mut res = [0u8; 16];
-    res.copy_from_slice(&tmp[0..16]);
-    res
+    tag_hash(type_name::<T>())
 }
 
 /// Equivalent to calling
@@ -98,28 +96,36 @@ where
     I: IntoIterator<Item = S>,
     S: AsRef<str>,
 {
-    let mut command = None;
-    for (i, part) in program_with_args.into_iter().enumerate() {
-        if i == 0 {
-            command = Some(Command::new(part.as_ref()));
-        } else {
-            command = Some(command.unwrap().arg(part.as_ref()));
-        }
-    }
-    let comres = command
-        .stack_err_locationless("super_orchestrator::sh was called with an empty iterator")?
-        .debug(true)
-        .run_to_completion()
-        .await?;
-    comres.assert_success()?;
-    comres
-        .stdout_as_utf8()
-        .map(|s| s.to_owned())
-        .stack_err_locationless("super_orchestrator::sh -> `Command` output was not UTF-8")
+    sh_impl("super_orchestrator::sh", program_with_args, true, None).await
 }
 
 /// [sh] but without debug mode
 pub async fn sh_no_debug<I, S>(program_with_args: I) -> Result<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    sh_impl("sh_no_debug", program_with_args, false, None).await
+}
+
+/// [sh] but the command is terminated and a timeout error with the partial
+/// output is returned if it does not complete within `timeout`, see
+/// [Command::timeout]
+pub async fn sh_with_timeout<I, S>(program_with_args: I, timeout: Duration) -> Result<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    sh_impl("sh_with_timeout", program_with_args, true, Some(timeout)).await
+}
+
+/// The implementation of the `sh` functions, `fn_name` is used in errors
+async fn sh_impl<I, S>(
+    fn_name: &str,
+    program_with_args: I,
+    debug: bool,
+    timeout: Option<Duration>,
+) -> Result<String>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<str>,
@@ -132,15 +138,18 @@ where
             command = Some(command.unwrap().arg(part.as_ref()));
         }
     }
-    let comres = command
-        .stack_err_locationless("sh_no_debug was called with an empty iterator")?
-        .run_to_completion()
-        .await?;
+    let mut command = command
+        .stack_err_with_locationless(|| format!("{fn_name} was called with an empty iterator"))?
+        .debug(debug);
+    if let Some(timeout) = timeout {
+        command = command.timeout(timeout);
+    }
+    let comres = command.run_to_completion().await?;
     comres.assert_success()?;
     comres
         .stdout_as_utf8()
         .map(|s| s.to_owned())
-        .stack_err_locationless("sh_no_debug -> `Command` output was not UTF-8")
+        .stack_err_with_locationless(|| format!("{fn_name} -> `Command` output was not UTF-8"))
 }
 
 /// Repeatedly polls `f` until it returns an `Ok` which is returned, or
@@ -406,7 +415,10 @@ where
 }
 
 mod color_cycle {
-    use std::sync::atomic::AtomicUsize;
+    use std::sync::{
+        atomic::{AtomicU8, AtomicUsize, Ordering},
+        OnceLock,
+    };
 
     use owo_colors::{AnsiColors, AnsiColors::*};
 
@@ -423,8 +435,30 @@ mod color_cycle {
 
     static COLOR_NUM: AtomicUsize = AtomicUsize::new(0);
 
+    // 0 if unset, 1 if disabled, 2 if enabled
+    static ANSI_COLORS: AtomicU8 = AtomicU8::new(0);
+
     pub(crate) fn next_terminal_color() -> AnsiColors {
-        let inx = COLOR_NUM.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let inx = COLOR_NUM.fetch_add(1, Ordering::Relaxed);
         COLOR_CYCLE[inx % COLOR_CYCLE.len()]
     }
+
+    /// Sets if the prefixes of `Command` and container output are colored
+    /// with ANSI escape codes. This should match the `with_ansi` setting of
+    /// the `tracing` subscriber the output goes to. If this is never called,
+    /// colors are enabled unless the `NO_COLOR` environment variable is set
+    /// to a nonempty value, which is the default of `tracing_subscriber::fmt`.
+    pub fn set_ansi_colors(enabled: bool) {
+        ANSI_COLORS.store(if enabled { 2 } else { 1 }, Ordering::Relaxed);
+    }
+
+    /// Returns if prefixes should be colored, see [set_ansi_colors]
+    pub(crate) fn ansi_colors() -> bool {
+        static NO_COLOR: OnceLock<bool> = OnceLock::new();
+        match ANSI_COLORS.load(Ordering::Relaxed) {
+            0 => !*NO_COLOR
+                .get_or_init(|| std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty())),
+            x => x == 2,
+        }
+    }
 }
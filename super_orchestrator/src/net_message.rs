@@ -1,12 +1,39 @@
-use std::{any::type_name, cmp::max, net::SocketAddr, time::Duration};
+mod datagram;
+mod request;
+mod server;
+mod stream;
+#[cfg(feature = "net_tls")]
+mod tls;
 
+use std::{
+    any::type_name,
+    cmp::{max, min},
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Display},
+    future::{poll_fn, Future},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+pub use datagram::*;
+pub use request::*;
 use serde::{de::DeserializeOwned, Serialize};
-use stacked_errors::{bail, Error, Result, StackableErr};
+pub use server::*;
+use stacked_errors::{bail, bail_locationless, Error, Result, StackableErr};
+pub use stream::*;
+#[cfg(feature = "net_tls")]
+pub use tls::*;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf},
     net::{lookup_host, TcpListener, TcpStream},
     select,
-    time::sleep,
+    sync::Mutex,
+    task::AbortHandle,
+    time::{sleep, timeout_at, Instant},
 };
 
 use crate::{type_hash, wait_for_ok};
@@ -53,57 +80,1237 @@ pub async fn wait_for_ok_tcp_stream_connect(
 // a DSL keyword it succinctly logically moves a tuple of things to the next
 // program in parallel.
 
+/// The length of the type ID at the start of each message, see [type_hash]
+pub const TYPE_ID_LEN: usize = 16;
+/// The version of the `NetMessenger` protocol. This is exchanged in a handshake
+/// when connecting, so that binaries with incompatible versions fail loudly
+/// instead of misreading messages.
+pub const PROTOCOL_VERSION: u8 = 4;
+/// Sent before the version in the handshake
+const HANDSHAKE_MAGIC: &[u8; 4] = b"SONM";
+/// The version of `super_orchestrator` that is sent in the handshake
+const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// The maximum length of [NetMessengerOptions::schema]
+pub const MAX_SCHEMA_LEN: usize = 1 << 20;
+/// The default of [NetMessengerOptions::connect_timeout]
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// The frame type byte of a message, which is followed by the type ID, the
+/// length, and the body
+pub const FRAME_MESSAGE: u8 = 0;
+/// The frame type byte of a keepalive ping, which has nothing else, see
+/// [NetMessenger::enable_keepalive]
+pub const FRAME_PING: u8 = 1;
+/// The frame type byte of a request, see [NetMessenger::request]. This and
+/// the other frames for requests have a little endian `u64` correlation ID
+/// after the frame type byte, and are otherwise like messages.
+pub const FRAME_REQUEST: u8 = 2;
+/// The frame type byte of the response to a request
+pub const FRAME_RESPONSE: u8 = 3;
+/// The frame type byte of a [RemoteError] in response to a request, the type
+/// ID of these frames is all zeros
+pub const FRAME_ERROR: u8 = 4;
+/// The frame type byte that [NetMessenger::close] sends before closing the
+/// connection, which has nothing else
+pub const FRAME_CLOSE: u8 = 5;
+/// Set in the frame type byte of frames whose body is compressed, see
+/// [NetMessengerOptions::enable_compression]
+pub const FRAME_COMPRESSED: u8 = 0x80;
+/// The default of [NetMessenger::max_message_size]
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 << 20;
+/// The default of [NetMessenger::stream_chunk_size]
+pub const DEFAULT_STREAM_CHUNK_SIZE: usize = 1 << 20;
+/// The buffer of a `NetMessenger` grows by at least this much at a time while
+/// receiving a large message
+const BODY_CHUNK: usize = 64 << 10;
+/// After a message, the buffers are shrunk back to this capacity if they are
+/// larger, unless [NetMessenger::with_initial_capacity] was used
+const RETAINED_BUFFER: usize = 1 << 20;
+/// Bodies smaller than this are not compressed
+#[cfg(feature = "net_compression")]
+const COMPRESSION_THRESHOLD: usize = 256;
+
+/// Returns the ID that [NetMessenger::send_untyped] sends for `tag`, which is
+/// the first [TYPE_ID_LEN] bytes of the SHA3-256 hash of the tag. For the
+/// typed functions the tag is `std::any::type_name`, see [type_hash].
+pub fn tag_hash(tag: &str) -> [u8; TYPE_ID_LEN] {
+    use sha3::{Digest, Sha3_256};
+    let hash: [u8; 32] = Sha3_256::digest(tag.as_bytes()).into();
+    let mut res = [0u8; TYPE_ID_LEN];
+    res.copy_from_slice(&hash[0..TYPE_ID_LEN]);
+    res
+}
+
+/// How the body of a message is encoded, see [NetMessenger::codec]. Both
+/// sides need to use the same codec.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// `postcard`, which is compact but only practical for Rust peers
+    #[default]
+    Postcard,
+    /// `serde_json`, for peers in other languages. Together with
+    /// [NetMessenger::send_untyped] and [NetMessenger::recv_untyped], the
+    /// protocol is simple to implement elsewhere. After connecting, both sides
+    /// send a handshake and read the one of the other side. The handshake is
+    /// `b"SONM"`, the [PROTOCOL_VERSION] byte, the length of the crate version
+    /// as a little endian `u16` and the crate version (which foreign peers
+    /// leave empty), then a 0 byte, or a 1 byte followed by the length of the
+    /// schema as a little endian `u32` and the schema (see
+    /// [NetMessengerOptions::schema]), and then the [Compression] byte which
+    /// is 0 for no compression. Each message is the [FRAME_MESSAGE]
+    /// byte, the [tag_hash] of the tag, the length of the body as a little
+    /// endian `u64`, and the JSON body. [FRAME_PING] bytes can come between
+    /// messages if the other side has keepalive enabled, and a [FRAME_CLOSE]
+    /// byte is sent before a graceful close. A minimal Python client:
+    ///
+    /// ```python
+    /// import hashlib, json, socket, struct
+    ///
+    /// def recv_exact(sock, n):
+    ///     buf = b""
+    ///     while len(buf) < n:
+    ///         chunk = sock.recv(n - len(buf))
+    ///         if not chunk:
+    ///             raise EOFError()
+    ///         buf += chunk
+    ///     return buf
+    ///
+    /// def tag_hash(tag):
+    ///     return hashlib.sha3_256(tag.encode()).digest()[:16]
+    ///
+    /// def send(sock, tag, msg):
+    ///     body = json.dumps(msg).encode()
+    ///     sock.sendall(bytes([0]) + tag_hash(tag) + struct.pack("<Q", len(body)) + body)
+    ///
+    /// def recv(sock, tag):
+    ///     # skips keepalive pings
+    ///     while recv_exact(sock, 1) != bytes([0]):
+    ///         pass
+    ///     if recv_exact(sock, 16) != tag_hash(tag):
+    ///         raise ValueError("unexpected tag")
+    ///     (length,) = struct.unpack("<Q", recv_exact(sock, 8))
+    ///     return json.loads(recv_exact(sock, length))
+    ///
+    /// def handshake(sock):
+    ///     # an empty crate version, no schema, and no compression
+    ///     sock.sendall(b"SONM" + bytes([4]) + struct.pack("<H", 0) + bytes([0, 0]))
+    ///     if recv_exact(sock, 5) != b"SONM" + bytes([4]):
+    ///         raise ValueError("unexpected handshake")
+    ///     (length,) = struct.unpack("<H", recv_exact(sock, 2))
+    ///     recv_exact(sock, length)
+    ///     if recv_exact(sock, 2) != bytes([0, 0]):
+    ///         raise ValueError("the other side has a schema or compression")
+    ///
+    /// sock = socket.create_connection(("container1", 26000))
+    /// handshake(sock)
+    /// send(sock, "greeting", {"text": "hello"})
+    /// print(recv(sock, "greeting"))
+    /// ```
+    Json,
+}
+
+/// How the bodies of frames are compressed, see
+/// [NetMessengerOptions::enable_compression]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// No compression
+    #[default]
+    None,
+    /// `zstd` with a compression level from 1 to 22, where 3 is the default of
+    /// `zstd`. Levels outside of `zstd::compression_level_range()` are clamped
+    /// to it by [NetMessengerOptions::enable_compression]. This needs the
+    /// "net_compression" feature.
+    #[cfg(feature = "net_compression")]
+    Zstd(i32),
+}
+
+impl Compression {
+    /// The byte that is sent in the handshake
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            #[cfg(feature = "net_compression")]
+            Compression::Zstd(_) => 1,
+        }
+    }
+}
+
+/// Returns the name of the compression with `id` for errors
+fn compression_name(id: u8) -> &'static str {
+    match id {
+        0 => "no compression",
+        1 => "zstd compression",
+        _ => "an unknown compression",
+    }
+}
+
+/// Returns `body` compressed with `compression`, or `None` if it should be
+/// sent uncompressed because it is small or would not get smaller
+#[cfg_attr(not(feature = "net_compression"), allow(unused_variables))]
+fn compress(compression: Compression, body: &[u8]) -> Option<Vec<u8>> {
+    match compression {
+        Compression::None => None,
+        #[cfg(feature = "net_compression")]
+        Compression::Zstd(level) => {
+            if body.len() < COMPRESSION_THRESHOLD {
+                return None
+            }
+            zstd::bulk::compress(body, level)
+                .ok()
+                .filter(|compressed| compressed.len() < body.len())
+        }
+    }
+}
+
+/// Decompresses `body`, returning an error if it is larger than
+/// `max_message_size` when decompressed
+#[cfg_attr(not(feature = "net_compression"), allow(unused_variables))]
+fn decompress(compression: Compression, body: &[u8], max_message_size: usize) -> Result<Vec<u8>> {
+    match compression {
+        Compression::None => bail_locationless!("compression was not negotiated"),
+        #[cfg(feature = "net_compression")]
+        Compression::Zstd(_) => {
+            use std::io::Read;
+            let mut res = vec![];
+            zstd::stream::read::Decoder::new(body)
+                .stack_err_locationless("failed to start decompressing with `zstd`")?
+                // the limit is checked as the data is decompressed, so that a small body cannot
+                // make this side run out of memory
+                .take((max_message_size as u64).saturating_add(1))
+                .read_to_end(&mut res)
+                .stack_err_locationless("failed to decompress with `zstd`")?;
+            if res.len() > max_message_size {
+                bail_locationless!(
+                    "the decompressed message is larger than the `max_message_size` of \
+                     {max_message_size} bytes"
+                )
+            }
+            Ok(res)
+        }
+    }
+}
+
+/// The parts of a message on the wire, for errors
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    /// The frame type and type ID
+    Header,
+    /// The length of the body
+    Length,
+    Body,
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Header => "header",
+            Phase::Length => "length",
+            Phase::Body => "body",
+        })
+    }
+}
+
+/// The connection of a `NetMessenger`
+#[derive(Debug)]
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(feature = "net_tls")]
+    Tls(Box<tokio_rustls::TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "net_tls")]
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "net_tls")]
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "net_tls")]
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "net_tls")]
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Like `read_exact`, but `filled` is kept up to date so that the progress is
+/// known if the future is cancelled
+async fn read_tracked<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    buf: &mut [u8],
+    filled: &mut usize,
+) -> std::io::Result<()> {
+    while *filled < buf.len() {
+        match stream.read(&mut buf[*filled..]).await? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => *filled += n,
+        }
+    }
+    Ok(())
+}
+
+/// Like `write_all`, but `written` is kept up to date so that the progress is
+/// known if the future is cancelled
+async fn write_tracked<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    buf: &[u8],
+    written: &mut usize,
+) -> std::io::Result<()> {
+    while *written < buf.len() {
+        match stream.write(&buf[*written..]).await? {
+            0 => return Err(std::io::ErrorKind::WriteZero.into()),
+            n => *written += n,
+        }
+    }
+    Ok(())
+}
+
+/// Returns `None` if the `deadline` was reached first
+async fn with_deadline<F: Future>(deadline: Option<Instant>, f: F) -> Option<F::Output> {
+    if let Some(deadline) = deadline {
+        timeout_at(deadline, f).await.ok()
+    } else {
+        Some(f.await)
+    }
+}
+
+/// Why reading or writing part of a message stopped
+enum Stop {
+    Io(io::Error),
+    Timeout,
+    /// Nothing was read or written for the keepalive grace period
+    Dead,
+    /// A previous send was cancelled in the middle of a frame
+    Cancelled,
+}
+
+/// Returns the earlier of `deadline` and the end of a `grace` period that
+/// starts now
+fn next_deadline(deadline: Option<Instant>, grace: Option<Duration>) -> Option<Instant> {
+    match (
+        deadline,
+        grace.and_then(|grace| Instant::now().checked_add(grace)),
+    ) {
+        (Some(deadline), Some(grace)) => Some(min(deadline, grace)),
+        (deadline, grace) => deadline.or(grace),
+    }
+}
+
+fn deadline_reached(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Returns why something stopped without progress
+fn stop_reason(deadline: Option<Instant>) -> Stop {
+    if deadline_reached(deadline) {
+        Stop::Timeout
+    } else {
+        Stop::Dead
+    }
+}
+
+/// `read_tracked` until `deadline`, and if there is a `grace` period, also
+/// until nothing has arrived for that long
+async fn read_live<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    buf: &mut [u8],
+    filled: &mut usize,
+    deadline: Option<Instant>,
+    grace: Option<Duration>,
+) -> std::result::Result<(), Stop> {
+    loop {
+        let before = *filled;
+        let next = next_deadline(deadline, grace);
+        match with_deadline(next, read_tracked(stream, buf, filled)).await {
+            Some(res) => return res.map_err(Stop::Io),
+            None => {
+                if (*filled == before) || deadline_reached(deadline) {
+                    return Err(stop_reason(deadline))
+                }
+            }
+        }
+    }
+}
+
+/// `write_tracked` until `deadline`, and if there is a `grace` period, also
+/// until nothing could be written for that long
+async fn write_live<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    buf: &[u8],
+    written: &mut usize,
+    deadline: Option<Instant>,
+    grace: Option<Duration>,
+) -> std::result::Result<(), Stop> {
+    loop {
+        let before = *written;
+        let next = next_deadline(deadline, grace);
+        match with_deadline(next, write_tracked(stream, buf, written)).await {
+            Some(res) => return res.map_err(Stop::Io),
+            None => {
+                if (*written == before) || deadline_reached(deadline) {
+                    return Err(stop_reason(deadline))
+                }
+            }
+        }
+    }
+}
+
+/// Returns if frames of `frame_type` have a correlation ID after the frame
+/// type byte
+fn has_correlation(frame_type: u8) -> bool {
+    matches!(frame_type, FRAME_REQUEST | FRAME_RESPONSE | FRAME_ERROR)
+}
+
+/// The header of a frame other than a ping
+#[derive(Debug, Clone, Copy)]
+struct FrameHeader {
+    /// Without the [FRAME_COMPRESSED] flag
+    frame_type: u8,
+    /// Zero for frames without a correlation ID
+    correlation: u64,
+    id: [u8; TYPE_ID_LEN],
+    compressed: bool,
+}
+
+impl FrameHeader {
+    fn message(id: [u8; TYPE_ID_LEN]) -> Self {
+        Self {
+            frame_type: FRAME_MESSAGE,
+            correlation: 0,
+            id,
+            compressed: false,
+        }
+    }
+
+    /// Returns the bytes on the wire
+    fn to_bytes(self) -> Vec<u8> {
+        let flag = if self.compressed { FRAME_COMPRESSED } else { 0 };
+        let mut bytes = vec![self.frame_type | flag];
+        if has_correlation(self.frame_type) {
+            bytes.extend_from_slice(&self.correlation.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.id);
+        bytes
+    }
+}
+
+/// How far the frame that is being received has been read, this is kept in
+/// the messenger so that a cancelled receive can be continued
+#[derive(Debug, Default)]
+struct ReadProgress {
+    header_bytes: [u8; 1 + 8 + TYPE_ID_LEN],
+    header_filled: usize,
+    /// Set when the header is complete until the body is read
+    header: Option<FrameHeader>,
+    len_bytes: [u8; 8],
+    len_filled: usize,
+    /// Set when the length is read
+    body_len: Option<usize>,
+    body_filled: usize,
+}
+
+/// Serializes `msg` into `buf` with `codec`, returning an error if it is
+/// larger than `max_message_size`
+fn encode<T: ?Sized + Serialize>(
+    codec: Codec,
+    max_message_size: usize,
+    buf: &mut Vec<u8>,
+    msg: &T,
+    fn_name: &str,
+) -> Result<()> {
+    if codec == Codec::Json {
+        buf.clear();
+        serde_json::to_writer(&mut *buf, msg)
+            .stack_err_locationless("failed to serialize message")?;
+    } else {
+        loop {
+            buf.clear();
+            buf.resize(buf.capacity(), 0);
+            match postcard::to_slice(msg, buf) {
+                Ok(used) => {
+                    let len = used.len();
+                    buf.truncate(len);
+                }
+                Err(postcard::Error::SerializeBufferFull) => {
+                    if buf.capacity() >= max_message_size {
+                        bail_locationless!(
+                            "{fn_name} -> the serialized message is larger than the \
+                             `max_message_size` of {max_message_size} bytes"
+                        )
+                    }
+                    // double the capacity
+                    let current_cap = max(buf.capacity(), 1);
+                    // reserve is based on `self.len() + additional` instead of
+                    // `self.capacity() + additional`
+                    let double = current_cap.wrapping_shl(1);
+                    buf.reserve(double);
+                    continue;
+                }
+                Err(e) => {
+                    return Err(Error::from_err(e))
+                        .stack_err_locationless("failed to serialize message")?
+                }
+            }
+            break;
+        }
+    }
+    if buf.len() > max_message_size {
+        bail_locationless!(
+            "{fn_name} -> the serialized message is {} bytes, which is larger than the \
+             `max_message_size` of {max_message_size} bytes",
+            buf.len()
+        )
+    }
+    Ok(())
+}
+
+/// Deserializes `body` with `codec`
+fn decode<T: DeserializeOwned>(codec: Codec, body: &[u8], fn_name: &str) -> Result<T> {
+    match codec {
+        Codec::Postcard => postcard::from_bytes(body).stack_err_with(|| {
+            format!("{fn_name} -> failed to deserialize message with `postcard`")
+        }),
+        Codec::Json => serde_json::from_slice(body).stack_err_with(|| {
+            format!("{fn_name} -> failed to deserialize message with `serde_json`")
+        }),
+    }
+}
+
+/// Why a messenger is poisoned after a `send` was cancelled in the middle of a
+/// frame
+const SEND_CANCELLED: &str = "a send was cancelled in the middle of a frame";
+
+/// The writing half of a connection, which is shared with the keepalive task
+#[derive(Debug)]
+struct FrameWriter {
+    stream: WriteHalf<Stream>,
+    // how much of the header of the current frame was written, this is only
+    // left nonzero if writing the frame stopped (e.g. a `send` future was dropped)
+    // and nothing else can be written after that
+    header_written: usize,
+}
+
+/// Writes a whole frame under the lock of `writer`, so that frames from
+/// different tasks are not interleaved. On failure this returns the phase
+/// where it stopped and if part of the frame was written.
+async fn write_frame(
+    writer: &Mutex<FrameWriter>,
+    header: &[u8],
+    body: &[u8],
+    deadline: Option<Instant>,
+    grace: Option<Duration>,
+) -> std::result::Result<(), (Phase, bool, Stop)> {
+    // the keepalive task only holds the lock for a ping, unless the other side is
+    // not reading
+    let Some(mut writer) = with_deadline(next_deadline(deadline, grace), writer.lock()).await
+    else {
+        return Err((Phase::Header, false, stop_reason(deadline)))
+    };
+    let writer = &mut *writer;
+    if writer.header_written != 0 {
+        return Err((Phase::Header, true, Stop::Cancelled))
+    }
+    if let Err(stop) = write_live(
+        &mut writer.stream,
+        header,
+        &mut writer.header_written,
+        deadline,
+        grace,
+    )
+    .await
+    {
+        return Err((Phase::Header, writer.header_written != 0, stop))
+    }
+    let len = (body.len() as u64).to_le_bytes();
+    let mut written = 0;
+    if let Err(stop) = write_live(&mut writer.stream, &len, &mut written, deadline, grace).await {
+        return Err((Phase::Length, true, stop))
+    }
+    let mut written = 0;
+    if let Err(stop) = write_live(&mut writer.stream, body, &mut written, deadline, grace).await {
+        return Err((Phase::Body, true, stop))
+    }
+    match with_deadline(next_deadline(deadline, grace), writer.stream.flush()).await {
+        Some(Ok(())) => {
+            writer.header_written = 0;
+            Ok(())
+        }
+        Some(Err(e)) => Err((Phase::Body, true, Stop::Io(e))),
+        None => Err((Phase::Body, true, stop_reason(deadline))),
+    }
+}
+
+/// Returns the error for `stop` in `phase`
+fn stop_error(fn_name: &str, phase: Phase, stop: Stop, grace: Option<Duration>) -> Error {
+    match stop {
+        Stop::Io(e) => Error::from_err_locationless(e).add_err_locationless(format!(
+            "{fn_name} -> failed in the {phase} phase of the message"
+        )),
+        Stop::Timeout => Error::timeout().add_err_locationless(format!(
+            "{fn_name} -> timed out in the {phase} phase of the message"
+        )),
+        Stop::Dead => Error::from_err_locationless(format!(
+            "{fn_name} -> the other side is considered dead, there was no progress in the {phase} \
+             phase of the message for the keepalive grace period of {grace:?}"
+        )),
+        Stop::Cancelled => Error::from_err_locationless(format!(
+            "{fn_name} -> a previous send was cancelled in the middle of a frame"
+        )),
+    }
+}
+
+/// The background task of [NetMessenger::enable_keepalive], which is stopped
+/// when the messenger is dropped
+#[derive(Debug)]
+struct Keepalive {
+    grace: Duration,
+    task: AbortHandle,
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Binds to `host` and accepts a single connection, returns a timeout error if
+/// `timeout` is reached first
+async fn accept_single(host: &str, timeout: Duration) -> Result<TcpStream> {
+    let socket_addr = lookup_host(host)
+        .await
+        .stack()?
+        .next()
+        .stack_err("no socket addresses from lookup_host(host)")?;
+    let listener = TcpListener::bind(socket_addr).await.stack()?;
+    // we use the cancel safety of `tokio::net::TcpListener::accept
+    select! {
+        tmp = listener.accept() => {
+            let (stream, _) = tmp.stack()?;
+            Ok(stream)
+        }
+        _ = sleep(timeout) => {
+            Err(Error::timeout())
+        }
+    }
+}
+
+/// Looks up `host` and connects to it, with retries
+async fn connect_single(num_retries: u64, delay: Duration, host: &str) -> Result<TcpStream> {
+    let socket_addrs = wait_for_ok_lookup_host(num_retries, delay, host)
+        .await
+        .stack()?;
+    let socket_addr = *socket_addrs
+        .first()
+        .stack_err("wait_for_ok_lookup_host was ok but returned no socket addresses")?;
+    wait_for_ok_tcp_stream_connect(num_retries, delay, socket_addr)
+        .await
+        .stack()
+}
+
+/// The error that [NetMessenger::recv] and the other receiving functions
+/// return after the other side closed the connection with
+/// [NetMessenger::close], see [NetMessenger::is_closed_by_peer]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClosedByPeer;
+
+impl Display for ClosedByPeer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the other side closed the connection with `NetMessenger::close`")
+    }
+}
+
+impl std::error::Error for ClosedByPeer {}
+
+/// The capacities of the buffers of a [NetMessenger], see
+/// [NetMessenger::buffer_stats]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BufferStats {
+    /// The current capacity of the buffer that messages are serialized into
+    pub send_capacity: usize,
+    /// The current capacity of the buffer that messages are received into
+    pub recv_capacity: usize,
+    /// The largest capacity that the sending buffer has had
+    pub send_high_water_mark: usize,
+    /// The largest capacity that the receiving buffer has had
+    pub recv_high_water_mark: usize,
+}
+
+/// Runs a part of the handshake with `deadline`
+async fn handshake_io<T, F: Future<Output = io::Result<T>>>(
+    deadline: Option<Instant>,
+    f: F,
+) -> Result<T> {
+    match with_deadline(deadline, f).await {
+        Some(Ok(t)) => Ok(t),
+        Some(Err(e)) => Err(Error::from_err_locationless(e))
+            .stack_err_locationless("failed to exchange the handshake"),
+        None => {
+            Err(Error::timeout().add_err_locationless("timed out while exchanging the handshake"))
+        }
+    }
+}
+
+/// Reads the crate version, schema, and compression byte of the handshake
+/// after the version byte
+async fn read_handshake_rest<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> io::Result<(String, Option<String>, u8)> {
+    fn invalid(msg: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+    }
+    let len = reader.read_u16_le().await?;
+    let mut crate_version = vec![0u8; usize::from(len)];
+    reader.read_exact(&mut crate_version).await?;
+    let crate_version =
+        String::from_utf8(crate_version).map_err(|_| invalid("the crate version is not UTF-8"))?;
+    let schema = match reader.read_u8().await? {
+        0 => None,
+        1 => {
+            let len = reader.read_u32_le().await?;
+            let len = usize::try_from(len)
+                .ok()
+                .filter(|len| *len <= MAX_SCHEMA_LEN)
+                .ok_or_else(|| invalid("the schema is longer than `MAX_SCHEMA_LEN`"))?;
+            let mut schema = vec![0u8; len];
+            reader.read_exact(&mut schema).await?;
+            Some(String::from_utf8(schema).map_err(|_| invalid("the schema is not UTF-8"))?)
+        }
+        _ => return Err(invalid("invalid schema flag")),
+    };
+    let compression = reader.read_u8().await?;
+    Ok((crate_version, schema, compression))
+}
+
+/// Options for connecting a [NetMessenger], which are checked against the
+/// options of the other side in the handshake. [NetMessenger::listen] and
+/// [NetMessenger::connect] use the default options.
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use super_orchestrator::net_message::NetMessengerOptions;
+///
+/// # async fn ex() -> stacked_errors::Result<()> {
+/// let mut nm = NetMessengerOptions::new()
+///     .schema("jobs v2")
+///     .connect(300, Duration::from_millis(100), "coordinator:26000")
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct NetMessengerOptions {
+    schema: Option<String>,
+    compression: Compression,
+    connect_timeout: Option<Duration>,
+}
+
+impl NetMessengerOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a schema string that is exchanged in the handshake, such as a
+    /// version or a hash of the definitions of the message types. The
+    /// connection fails in the handshake if the other side has a different
+    /// schema or none, which catches incompatible binaries before any message
+    /// is misread. The type checks of the messages still apply.
+    pub fn schema(mut self, schema: impl Into<String>) -> Self {
+        self.schema = Some(schema.into());
+        self
+    }
+
+    /// Enables compressing the bodies of frames with `compression`, which
+    /// is worth it for large messages when the network is slower than
+    /// compressing, e.g. between hosts. The other side needs to use the same
+    /// kind of compression (the level can differ), otherwise the handshake
+    /// fails. Small bodies and bodies that do not get smaller are sent
+    /// uncompressed. The `max_message_size` applies to both the compressed
+    /// and the decompressed size.
+    pub fn enable_compression(mut self, compression: Compression) -> Self {
+        self.compression = match compression {
+            Compression::None => Compression::None,
+            #[cfg(feature = "net_compression")]
+            Compression::Zstd(level) => {
+                let range = zstd::compression_level_range();
+                Compression::Zstd(level.clamp(*range.start(), *range.end()))
+            }
+        };
+        self
+    }
+
+    /// Sets how long connecting has for the handshake once the TCP connection
+    /// is established, after which a timeout error is returned. This keeps a
+    /// connection to something that accepts but never answers (e.g. a wrong
+    /// port or a stuck peer) from hanging forever. The retries of the TCP
+    /// connection are governed by the `num_retries` and `delay` of the connect
+    /// functions instead. This defaults to [DEFAULT_CONNECT_TIMEOUT], the
+    /// handshake when listening has the `timeout` of the listen function.
+    /// With [NetMessengerServer::options], each accepted connection has this
+    /// long for its handshake.
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Returns the deadline of the handshake when connecting
+    fn connect_deadline(&self) -> Option<Instant> {
+        Instant::now().checked_add(self.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT))
+    }
+
+    /// Like [NetMessenger::listen] with these options
+    pub async fn listen(&self, host: &str, timeout: Duration) -> Result<NetMessenger> {
+        let deadline = Instant::now().checked_add(timeout);
+        let stream = accept_single(host, timeout)
+            .await
+            .stack_err_locationless("NetMessenger::listen")?;
+        NetMessenger::handshake(Stream::Tcp(stream), deadline, self)
+            .await
+            .stack_err_locationless("NetMessenger::listen")
+    }
+
+    /// Like [NetMessenger::connect] with these options
+    pub async fn connect(
+        &self,
+        num_retries: u64,
+        delay: Duration,
+        host: &str,
+    ) -> Result<NetMessenger> {
+        let stream = connect_single(num_retries, delay, host)
+            .await
+            .stack_err_locationless("NetMessenger::connect")?;
+        NetMessenger::handshake(Stream::Tcp(stream), self.connect_deadline(), self)
+            .await
+            .stack_err_locationless("NetMessenger::connect")
+    }
+
+    /// Returns what this side sends in the handshake
+    fn handshake_bytes(&self) -> Result<Vec<u8>> {
+        let mut res = HANDSHAKE_MAGIC.to_vec();
+        res.push(PROTOCOL_VERSION);
+        res.extend_from_slice(&(CRATE_VERSION.len() as u16).to_le_bytes());
+        res.extend_from_slice(CRATE_VERSION.as_bytes());
+        match &self.schema {
+            Some(schema) => {
+                if schema.len() > MAX_SCHEMA_LEN {
+                    bail_locationless!(
+                        "the schema is {} bytes long, which is more than `MAX_SCHEMA_LEN`",
+                        schema.len()
+                    )
+                }
+                res.push(1);
+                res.extend_from_slice(&(schema.len() as u32).to_le_bytes());
+                res.extend_from_slice(schema.as_bytes());
+            }
+            None => res.push(0),
+        }
+        res.push(self.compression.id());
+        Ok(res)
+    }
+}
+
 /// This is mainly intended for sending serializeable structs within
 /// self-contained container networks
 #[derive(Debug)]
 pub struct NetMessenger {
-    stream: TcpStream,
-    // buffer whose capacity is kept around
+    reader: ReadHalf<Stream>,
+    // shared with the keepalive task, which writes pings between messages
+    writer: Arc<Mutex<FrameWriter>>,
+    // buffers whose capacity is kept around, the receiving one holds a partial
+    // body if a receive was cancelled
     buf: Vec<u8>,
+    recv_buf: Vec<u8>,
+    // the capacity that the buffers are shrunk back to
+    retained_capacity: usize,
+    send_high_water_mark: usize,
+    recv_high_water_mark: usize,
+    progress: ReadProgress,
+    // the reason if an operation stopped in the middle of a message, after which
+    // the framing of the stream is unknown
+    poisoned: Option<String>,
+    max_message_size: usize,
+    stream_chunk_size: usize,
+    codec: Codec,
+    compression: Compression,
+    keepalive: Option<Keepalive>,
+    // the correlation ID of the next request
+    next_request: u64,
+    // requests that are waiting for a response
+    in_flight: BTreeSet<u64>,
+    // responses that arrived while waiting for another response
+    responses: BTreeMap<u64, (FrameHeader, Vec<u8>)>,
+    // if the close frame of the other side was received
+    closed_by_peer: bool,
 }
 
 impl NetMessenger {
     /// Binds to and listens on `socket_addr`, and accepts a single connection
     /// to message with. Cancels the bind and returns a timeout error if
-    /// `timeout` is reached first.
+    /// `timeout` is reached first (including the handshake). See
+    /// [NetMessengerOptions] for connecting with a schema.
     pub async fn listen(host: &str, timeout: Duration) -> Result<Self> {
-        let socket_addr = lookup_host(host)
-            .await
-            .stack()?
-            .next()
-            .stack_err("NetMessenger::listen -> no socket addresses from lookup_host(host)")?;
-        let listener = TcpListener::bind(socket_addr).await.stack()?;
-        // we use the cancel safety of `tokio::net::TcpListener::accept
-        select! {
-            tmp = listener.accept() => {
-                let (stream, _) = tmp.stack()?;
-                Ok(Self {stream, buf: vec![]})
-            }
-            _ = sleep(timeout) => {
-                Err(Error::timeout())
-            }
-        }
+        NetMessengerOptions::new().listen(host, timeout).await
     }
 
     /// Connects to another `NetMessenger` that is being started with
-    /// `listen`.
+    /// `listen`. Returns an error if the other side uses a different
+    /// [PROTOCOL_VERSION] or version of `super_orchestrator`, or a different
+    /// [NetMessengerOptions::schema].
     pub async fn connect(num_retries: u64, delay: Duration, host: &str) -> Result<Self> {
-        let socket_addrs = wait_for_ok_lookup_host(num_retries, delay, host)
+        NetMessengerOptions::new()
+            .connect(num_retries, delay, host)
             .await
-            .stack()?;
-        let socket_addr = *socket_addrs.first().stack_err(
-            "NetMessenger::connect -> wait_for_ok_lookup_host was ok but returned no socket \
-             addresses",
-        )?;
-        let stream = wait_for_ok_tcp_stream_connect(num_retries, delay, socket_addr)
-            .await
-            .stack()?;
+    }
+
+    /// Both sides send the magic bytes, their versions, and their schema, then
+    /// check what the other side sent
+    async fn handshake(
+        mut stream: Stream,
+        deadline: Option<Instant>,
+        options: &NetMessengerOptions,
+    ) -> Result<Self> {
+        let ours = options.handshake_bytes()?;
+        handshake_io(deadline, async {
+            stream.write_all(&ours).await?;
+            stream.flush().await
+        })
+        .await?;
+        // the rest is only read if the versions match, since older versions do not
+        // send it
+        let mut theirs = [0u8; 5];
+        handshake_io(deadline, stream.read_exact(&mut theirs)).await?;
+        if theirs[..4] != HANDSHAKE_MAGIC[..] {
+            bail_locationless!(
+                "the other side sent {theirs:?} as the handshake, it is not a `NetMessenger` or \
+                 is from a version of `super_orchestrator` without handshakes"
+            )
+        }
+        if theirs[4] != PROTOCOL_VERSION {
+            bail_locationless!(
+                "the other side uses version {} of the `NetMessenger` protocol, but this side \
+                 uses version {PROTOCOL_VERSION}",
+                theirs[4]
+            )
+        }
+        let (crate_version, schema, compression) =
+            handshake_io(deadline, read_handshake_rest(&mut stream)).await?;
+        // foreign peers send an empty crate version
+        if !crate_version.is_empty() && crate_version != CRATE_VERSION {
+            bail_locationless!(
+                "the other side uses version {crate_version} of `super_orchestrator`, but this \
+                 side uses version {CRATE_VERSION}"
+            )
+        }
+        if schema != options.schema {
+            bail_locationless!(
+                "the other side has the schema {schema:?}, but this side has the schema {:?}",
+                options.schema
+            )
+        }
+        if compression != options.compression.id() {
+            bail_locationless!(
+                "the other side uses {}, but this side uses {}",
+                compression_name(compression),
+                compression_name(options.compression.id())
+            )
+        }
+        let (reader, writer) = split(stream);
         Ok(Self {
-            stream,
+            reader,
+            writer: Arc::new(Mutex::new(FrameWriter {
+                stream: writer,
+                header_written: 0,
+            })),
             buf: vec![],
+            recv_buf: vec![],
+            retained_capacity: RETAINED_BUFFER,
+            send_high_water_mark: 0,
+            recv_high_water_mark: 0,
+            progress: ReadProgress::default(),
+            poisoned: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            stream_chunk_size: DEFAULT_STREAM_CHUNK_SIZE,
+            codec: Codec::default(),
+            compression: options.compression,
+            keepalive: None,
+            next_request: 0,
+            in_flight: BTreeSet::new(),
+            responses: BTreeMap::new(),
+            closed_by_peer: false,
         })
     }
 
+    /// Sets the [Codec] of the message bodies, this defaults to
+    /// [Codec::Postcard]
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Sets the maximum size of the serialized messages that can be sent and
+    /// received, this defaults to [DEFAULT_MAX_MESSAGE_SIZE] (64 MiB). The
+    /// size that the other side advertises is checked before any allocation,
+    /// so that a corrupt or malicious peer cannot make this side run out of
+    /// memory.
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Allocates `capacity` bytes for both the sending and the receiving
+    /// buffer up front, so that messages up to about this size do not cause
+    /// reallocations. The buffers are also shrunk back to this capacity
+    /// (instead of the default of 1 MiB) after larger messages, so a small
+    /// `capacity` keeps the memory of an idle messenger low.
+    pub fn with_initial_capacity(mut self, capacity: usize) -> Self {
+        self.retained_capacity = capacity;
+        self.buf.reserve(capacity);
+        self.recv_buf.reserve(capacity);
+        self.update_high_water_marks();
+        self
+    }
+
+    /// Shrinks both buffers to at most `capacity` bytes now, e.g. after a
+    /// phase of large messages. A partially received message from a
+    /// cancelled `recv` is kept, so the receiving buffer may stay larger.
+    pub fn shrink_to(&mut self, capacity: usize) {
+        self.buf.clear();
+        self.buf.shrink_to(capacity);
+        // only the part of a body that was received so far needs to be kept
+        let keep = if self.progress.body_len.is_some() {
+            self.progress.body_filled
+        } else {
+            0
+        };
+        self.recv_buf.truncate(keep);
+        self.recv_buf.shrink_to(capacity);
+    }
+
+    /// Returns the current capacities of the buffers and the largest
+    /// capacities they have had, for tuning
+    /// [NetMessenger::with_initial_capacity]
+    pub fn buffer_stats(&self) -> BufferStats {
+        BufferStats {
+            send_capacity: self.buf.capacity(),
+            recv_capacity: self.recv_buf.capacity(),
+            send_high_water_mark: self.send_high_water_mark,
+            recv_high_water_mark: self.recv_high_water_mark,
+        }
+    }
+
+    /// Enables heartbeats for detecting a dead peer, e.g. a container that was
+    /// killed or frozen without the connection being closed. A background
+    /// task sends a ping to the other side every `interval`, and `send` and
+    /// `recv` return an error saying that the other side is considered dead
+    /// if nothing could be written or read for `grace`. The pings of the other
+    /// side count as progress for `recv`. The messenger is poisoned after the
+    /// error (see [NetMessenger::is_poisoned]).
+    ///
+    /// Note: Both sides need to enable this with an `interval` that is well
+    /// below the `grace` of the other side, otherwise an idle but alive peer
+    /// is considered dead. `send` also considers the other side dead if it
+    /// does not read for `grace` while the buffers in between are full.
+    pub fn enable_keepalive(mut self, interval: Duration, grace: Duration) -> Self {
+        let writer = Arc::clone(&self.writer);
+        let task = tokio::spawn(async move {
+            loop {
+                sleep(interval).await;
+                // whole messages are written under the lock, so pings only come between them
+                let mut writer = writer.lock().await;
+                // a ping cannot be written after a partial frame
+                if (writer.header_written != 0)
+                    || writer.stream.write_all(&[FRAME_PING]).await.is_err()
+                    || writer.stream.flush().await.is_err()
+                {
+                    break
+                }
+            }
+        });
+        self.keepalive = Some(Keepalive {
+            grace,
+            task: task.abort_handle(),
+        });
+        self
+    }
+
+    /// Shrinks the buffer after unusually large messages, so that long lived
+    /// messengers do not keep the memory
+    fn shrink_buf(&mut self) {
+        self.update_high_water_marks();
+        if self.buf.capacity() > self.retained_capacity {
+            self.buf.clear();
+            self.buf.shrink_to(self.retained_capacity);
+        }
+    }
+
+    /// Like `shrink_buf` for the receiving buffer, which is only called after
+    /// a whole frame was received
+    fn shrink_recv_buf(&mut self) {
+        self.update_high_water_marks();
+        if self.recv_buf.capacity() > self.retained_capacity {
+            self.recv_buf.clear();
+            self.recv_buf.shrink_to(self.retained_capacity);
+        }
+    }
+
+    fn update_high_water_marks(&mut self) {
+        self.send_high_water_mark = max(self.send_high_water_mark, self.buf.capacity());
+        self.recv_high_water_mark = max(self.recv_high_water_mark, self.recv_buf.capacity());
+    }
+
+    /// Returns if a `send` stopped in the middle of a message (e.g. from a
+    /// timeout) or a `recv` failed in the middle of a message, after which the
+    /// messenger cannot be used anymore because the framing of the stream is
+    /// lost. A new connection is needed in this case.
+    pub fn is_poisoned(&self) -> bool {
+        self.poison_reason().is_some()
+    }
+
+    /// Returns why the messenger is poisoned, including a `send` that was
+    /// cancelled in the middle of a frame and has not been noticed yet
+    fn poison_reason(&self) -> Option<&str> {
+        if let Some(reason) = &self.poisoned {
+            Some(reason)
+        } else if self
+            .writer
+            .try_lock()
+            .is_ok_and(|writer| writer.header_written != 0)
+        {
+            Some(SEND_CANCELLED)
+        } else {
+            None
+        }
+    }
+
+    /// Returns if the other side closed the connection gracefully with
+    /// [NetMessenger::close], as opposed to the connection ending because the
+    /// other side was terminated or the messenger was dropped. This is set
+    /// when a receiving function reaches the close, which then returns a
+    /// [ClosedByPeer] error.
+    pub fn is_closed_by_peer(&self) -> bool {
+        self.closed_by_peer
+    }
+
+    /// Closes the connection gracefully, so that the other side gets a
+    /// [ClosedByPeer] error from `recv` after it received the messages that
+    /// were sent before. This sends a [FRAME_CLOSE] byte and shuts down the
+    /// writing side of the connection.
+    ///
+    /// It is not an error if the other side already closed the connection
+    /// with `close`, so both sides can close in any order. If the messenger
+    /// is poisoned, this returns an error and the connection is dropped
+    /// without a close frame.
+    pub async fn close(mut self) -> Result<()> {
+        let fn_name = "NetMessenger::close()";
+        self.check_poisoned(fn_name)?;
+        let grace = self.grace();
+        // no pings after the close frame
+        drop(self.keepalive.take());
+        let mut writer = self.writer.lock().await;
+        let writer = &mut *writer;
+        let mut written = 0;
+        let res = async {
+            if writer.header_written != 0 {
+                return Err(Stop::Cancelled)
+            }
+            write_live(
+                &mut writer.stream,
+                &[FRAME_CLOSE],
+                &mut written,
+                None,
+                grace,
+            )
+            .await?;
+            writer.stream.flush().await.map_err(Stop::Io)?;
+            writer.stream.shutdown().await.map_err(Stop::Io)
+        }
+        .await;
+        match res {
+            Ok(()) => Ok(()),
+            Err(Stop::Io(e))
+                if self.closed_by_peer
+                    && matches!(
+                        e.kind(),
+                        io::ErrorKind::BrokenPipe
+                            | io::ErrorKind::ConnectionReset
+                            | io::ErrorKind::NotConnected
+                    ) =>
+            {
+                Ok(())
+            }
+            Err(stop) => Err(stop_error(fn_name, Phase::Header, stop, grace)),
+        }
+    }
+
+    /// Returns the error for a function that needs a connection which the
+    /// other side closed
+    fn closed_by_peer_error(fn_name: &str) -> Error {
+        Error::from_err_locationless(ClosedByPeer)
+            .add_err_locationless(format!("{fn_name} -> the connection was closed"))
+    }
+
+    fn check_poisoned(&self, fn_name: &str) -> Result<()> {
+        if let Some(reason) = self.poison_reason() {
+            bail_locationless!(
+                "{fn_name} -> the `NetMessenger` is poisoned because {reason}, the framing of the \
+                 stream is lost and a new connection is needed"
+            )
+        }
+        Ok(())
+    }
+
+    /// Returns the error for when an operation stopped in `phase`, `partial` is
+    /// if part of the message was already read or written
+    fn stopped(&mut self, fn_name: &str, phase: Phase, partial: bool, stop: Stop) -> Error {
+        if matches!(stop, Stop::Dead) {
+            self.poisoned = Some(format!("{fn_name} considered the other side dead"));
+        } else if matches!(stop, Stop::Cancelled) {
+            self.poisoned = Some(SEND_CANCELLED.to_owned());
+        } else if partial || !matches!(phase, Phase::Header) {
+            self.poisoned = Some(format!(
+                "{fn_name} stopped in the middle of the {phase} of a message"
+            ));
+        }
+        stop_error(fn_name, phase, stop, self.grace())
+    }
+
+    /// Like `stopped` for receiving, where a timeout can be continued from
+    fn read_stopped(&mut self, fn_name: &str, phase: Phase, partial: bool, stop: Stop) -> Error {
+        if matches!(stop, Stop::Timeout) {
+            stop_error(fn_name, phase, stop, self.grace())
+        } else {
+            self.stopped(fn_name, phase, partial, stop)
+        }
+    }
+
     /// Sends `msg` to the connected party, waiting for a corresponding `recv`
     /// call.
     ///
@@ -117,85 +1324,462 @@ impl NetMessenger {
     /// binaries compiled by different compiler versions (but at least it is a
     /// false positive).
     pub async fn send<T: ?Sized + Serialize>(&mut self, msg: &T) -> Result<()> {
-        loop {
-            self.buf.clear();
-            self.buf.resize(self.buf.capacity(), 0);
-            match postcard::to_slice(msg, &mut self.buf) {
-                Ok(_) => (),
-                Err(postcard::Error::SerializeBufferFull) => {
-                    // double the capacity
-                    // TODO we need to add limits, maybe a settable option on the `NetMessage`
-                    // struct
-                    let current_cap = max(self.buf.capacity(), 1);
-                    // reserve is based on `self.len() + additional` instead of
-                    // `self.capacity() + additional`
-                    let double = current_cap.wrapping_shl(1);
-                    self.buf.reserve(double);
-                    continue;
-                }
-                Err(e) => {
-                    return Err(Error::from_err(e))
-                        .stack_err_locationless("failed to serialize message")?
-                }
-            }
-            break;
+        let fn_name = format!("NetMessenger::send::<{}>()", type_name::<T>());
+        self.send_internal(msg, FrameHeader::message(type_hash::<T>()), &fn_name, None)
+            .await
+    }
+
+    /// Like [NetMessenger::send], but returns a timeout error if the message
+    /// has not been written within `timeout` (e.g. because the other side is
+    /// hanging and the buffers are full). The error says which phase of the
+    /// message timed out. If part of the message was already written, the
+    /// messenger is poisoned (see [NetMessenger::is_poisoned]).
+    pub async fn send_timeout<T: ?Sized + Serialize>(
+        &mut self,
+        msg: &T,
+        timeout: Duration,
+    ) -> Result<()> {
+        let fn_name = format!("NetMessenger::send_timeout::<{}>()", type_name::<T>());
+        let deadline = Instant::now().checked_add(timeout);
+        self.send_internal(
+            msg,
+            FrameHeader::message(type_hash::<T>()),
+            &fn_name,
+            deadline,
+        )
+        .await
+    }
+
+    /// Like [NetMessenger::send], but the message is identified by `tag`
+    /// instead of the type, for peers that cannot compute the [type_hash] of
+    /// Rust types (see [Codec::Json]). The other side needs to use
+    /// [NetMessenger::recv_untyped] with the same `tag`.
+    pub async fn send_untyped<T: ?Sized + Serialize>(&mut self, tag: &str, msg: &T) -> Result<()> {
+        let fn_name = format!("NetMessenger::send_untyped(tag: {tag:?})");
+        self.send_internal(msg, FrameHeader::message(tag_hash(tag)), &fn_name, None)
+            .await
+    }
+
+    /// Serializes `msg` into the buffer with the codec
+    fn serialize<T: ?Sized + Serialize>(&mut self, msg: &T, fn_name: &str) -> Result<()> {
+        let res = encode(
+            self.codec,
+            self.max_message_size,
+            &mut self.buf,
+            msg,
+            fn_name,
+        );
+        if res.is_err() {
+            self.shrink_buf();
         }
-        // TODO handle timeouts
-        let id = type_hash::<T>();
-        if let Err(e) = self.stream.write_all(&id).await {
-            return Err(Error::probably_not_root_cause()
+        res
+    }
+
+    /// Returns the keepalive grace period if there is one
+    fn grace(&self) -> Option<Duration> {
+        self.keepalive.as_ref().map(|keepalive| keepalive.grace)
+    }
+
+    /// Sends `msg` in a frame with `header`
+    async fn send_internal<T: ?Sized + Serialize>(
+        &mut self,
+        msg: &T,
+        header: FrameHeader,
+        fn_name: &str,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        self.check_poisoned(fn_name)?;
+        self.send_message(msg, header, fn_name, deadline).await
+    }
+
+    /// `send_internal` without checking if the messenger is poisoned
+    async fn send_message<T: ?Sized + Serialize>(
+        &mut self,
+        msg: &T,
+        header: FrameHeader,
+        fn_name: &str,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        if self.closed_by_peer {
+            return Err(Self::closed_by_peer_error(fn_name))
+        }
+        self.serialize(msg, fn_name)?;
+        let res = self.write_buf(header, fn_name, deadline).await;
+        self.shrink_buf();
+        res
+    }
+
+    /// Sends what is in `buf` as the body of a frame with `header`, the caller
+    /// shrinks `buf` afterwards
+    async fn write_buf(
+        &mut self,
+        header: FrameHeader,
+        fn_name: &str,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        let compressed = compress(self.compression, &self.buf);
+        let header = FrameHeader {
+            compressed: compressed.is_some(),
+            ..header
+        };
+        let writer = Arc::clone(&self.writer);
+        let res = write_frame(
+            &writer,
+            &header.to_bytes(),
+            compressed.as_deref().unwrap_or(&self.buf),
+            deadline,
+            self.grace(),
+        )
+        .await;
+        match res {
+            Ok(()) => Ok(()),
+            Err((Phase::Header, false, Stop::Io(e))) => Err(Error::probably_not_root_cause()
                 .add_err_locationless(format!(
-                    "NetMessenger::send::<{}>::() could not write_all, this may be because the \
-                     other side was abruptly terminated",
-                    type_name::<T>()
+                    "{fn_name} could not write_all, this may be because the other side was \
+                     abruptly terminated"
                 ))
-                .add_err_locationless(e));
+                .add_err_locationless(e)),
+            // later errors are probably real network errors
+            Err((phase, partial, stop)) => Err(self.stopped(fn_name, phase, partial, stop)),
         }
-        // later errors are probably real network errors
-        self.stream
-            .write_u64_le(u64::try_from(self.buf.len()).stack()?)
-            .await
-            .stack()?;
-        self.stream.write_all(&self.buf).await.stack()?;
-        self.stream.flush().await.stack()?;
-        Ok(())
     }
 
     /// Waits for the connected party to `send` something with the same `T`.
     ///
+    /// This is cancel safe, e.g. it can be used in a `tokio::select!` with a
+    /// shutdown signal. If the future is dropped in the middle of a message,
+    /// the part that was read is kept and the next receiving call continues
+    /// with the rest of the message. The same applies to the other receiving
+    /// functions except [NetMessenger::recv_stream], but not to the sending
+    /// functions, where a `send` that is cancelled after writing part of a
+    /// message poisons the messenger. Nothing is written after the partial
+    /// message, including keepalive pings.
+    ///
     /// Note: If you don't directly assign the output to a binding with a
     /// specified type, you should always use the turbofish to specify `T`,
     /// because it is otherwise possible to get an unexpected type because
     /// of `Deref` coercion.
     pub async fn recv<T: DeserializeOwned>(&mut self) -> Result<T> {
-        // TODO handle timeouts
-        let expected_id = type_hash::<T>();
-        let mut actual_id = [0u8; 16];
-        if let Err(e) = self.stream.read_exact(&mut actual_id).await {
-            return Err(Error::probably_not_root_cause()
-                .add_err_locationless(format!(
-                    "NetMessenger::recv::<{}>::() could not read_exact, this may be because the \
-                     other side was abruptly terminated",
-                    type_name::<T>()
-                ))
-                .add_err_locationless(e));
-        }
+        let fn_name = format!("NetMessenger::recv::<{}>()", type_name::<T>());
+        self.recv_internal(type_hash::<T>(), &fn_name, None).await
+    }
+
+    /// Like [NetMessenger::recv], but returns a timeout error if a message has
+    /// not been received within `timeout`. The error says which phase of the
+    /// message timed out. Like a cancelled `recv`, the next receiving call
+    /// continues with the rest of the message.
+    pub async fn recv_timeout<T: DeserializeOwned>(&mut self, timeout: Duration) -> Result<T> {
+        let fn_name = format!("NetMessenger::recv_timeout::<{}>()", type_name::<T>());
+        self.recv_internal(type_hash::<T>(), &fn_name, Some(timeout))
+            .await
+    }
+
+    /// Waits for the connected party to send a message with `tag`, see
+    /// [NetMessenger::send_untyped]
+    pub async fn recv_untyped<T: DeserializeOwned>(&mut self, tag: &str) -> Result<T> {
+        let fn_name = format!("NetMessenger::recv_untyped(tag: {tag:?})");
+        self.recv_internal(tag_hash(tag), &fn_name, None).await
+    }
+
+    async fn recv_internal<T: DeserializeOwned>(
+        &mut self,
+        expected_id: [u8; TYPE_ID_LEN],
+        fn_name: &str,
+        timeout: Option<Duration>,
+    ) -> Result<T> {
+        self.check_poisoned(fn_name)?;
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        self.recv_message(expected_id, fn_name, deadline).await
+    }
+
+    /// `recv_internal` without checking if the messenger is poisoned
+    async fn recv_message<T: DeserializeOwned>(
+        &mut self,
+        expected_id: [u8; TYPE_ID_LEN],
+        fn_name: &str,
+        deadline: Option<Instant>,
+    ) -> Result<T> {
+        let Some(header) = self.read_header(fn_name, deadline).await? else {
+            return Err(self.closed_error(fn_name))
+        };
         // later errors are probably real network errors
-        if expected_id != actual_id {
-            bail!(
-                "NetMessenger::recv() -> incoming type did not match expected type ({})",
-                type_name::<T>()
+        if header.frame_type != FRAME_MESSAGE {
+            self.poisoned = Some(format!("{fn_name} received a frame that is not a message"));
+            bail_locationless!(
+                "{fn_name} -> received a frame of type {} instead of a message, requests need to \
+                 be answered with `NetMessenger::serve`",
+                header.frame_type
             )
         }
-        let data_len = usize::try_from(self.stream.read_u64_le().await.stack()?).stack()?;
-        if data_len > self.buf.len() {
-            self.buf.resize_with(data_len, || 0);
+        if expected_id != header.id {
+            self.poisoned = Some(format!(
+                "{fn_name} received a message of an unexpected type"
+            ));
+            bail!("{fn_name} -> incoming type did not match expected type")
+        }
+        let len = self.read_body(fn_name, header, deadline).await?;
+        let res = decode(self.codec, &self.recv_buf[..len], fn_name);
+        self.shrink_recv_buf();
+        res
+    }
+
+    /// Returns the error for when `read_header` returned `None`
+    fn closed_error(&self, fn_name: &str) -> Error {
+        if self.closed_by_peer {
+            Self::closed_by_peer_error(fn_name)
+        } else {
+            Error::probably_not_root_cause()
+                .add_err_locationless(closed_message(fn_name))
+                .add_err_locationless("the connection was closed")
+        }
+    }
+
+    /// Reads the header of the next frame, skipping keepalive pings. Returns
+    /// `None` if the other side closed the connection before the frame, in
+    /// which case `closed_by_peer` is set if it was closed with a close frame.
+    async fn read_header(
+        &mut self,
+        fn_name: &str,
+        deadline: Option<Instant>,
+    ) -> Result<Option<FrameHeader>> {
+        if self.closed_by_peer {
+            return Ok(None)
+        }
+        // the header of a frame whose body was not completely read yet
+        if let Some(header) = self.progress.header {
+            return Ok(Some(header))
         }
-        self.stream
-            .read_exact(&mut self.buf[0..data_len])
+        let grace = self.grace();
+        let reader = &mut self.reader;
+        let progress = &mut self.progress;
+        let res = async {
+            loop {
+                read_live(
+                    reader,
+                    &mut progress.header_bytes[..1],
+                    &mut progress.header_filled,
+                    deadline,
+                    grace,
+                )
+                .await?;
+                if progress.header_bytes[0] != FRAME_PING {
+                    break
+                }
+                progress.header_filled = 0;
+            }
+            let len = match progress.header_bytes[0] & !FRAME_COMPRESSED {
+                FRAME_MESSAGE => 1 + TYPE_ID_LEN,
+                frame_type if has_correlation(frame_type) => 1 + 8 + TYPE_ID_LEN,
+                _ => return Ok(()),
+            };
+            read_live(
+                reader,
+                &mut progress.header_bytes[..len],
+                &mut progress.header_filled,
+                deadline,
+                grace,
+            )
             .await
-            .stack()?;
-        postcard::from_bytes(&self.buf[0..data_len])
-            .stack_err("NetMessenger::recv() -> failed to deserialize message")
+        }
+        .await;
+        let filled = self.progress.header_filled;
+        match res {
+            Ok(()) => (),
+            Err(Stop::Io(e)) if filled == 0 => {
+                if e.kind() == io::ErrorKind::UnexpectedEof {
+                    return Ok(None)
+                }
+                return Err(Error::probably_not_root_cause()
+                    .add_err_locationless(closed_message(fn_name))
+                    .add_err_locationless(e));
+            }
+            Err(stop) => return Err(self.read_stopped(fn_name, Phase::Header, filled != 0, stop)),
+        }
+        self.progress.header_filled = 0;
+        let header = self.progress.header_bytes;
+        let frame_type = header[0] & !FRAME_COMPRESSED;
+        let compressed = (header[0] & FRAME_COMPRESSED) != 0;
+        if compressed && (self.compression == Compression::None) {
+            self.poisoned = Some(format!("{fn_name} received an unexpected compressed frame"));
+            bail_locationless!(
+                "{fn_name} -> received a compressed frame, but compression was not negotiated"
+            )
+        }
+        let (correlation, id) = match frame_type {
+            FRAME_CLOSE => {
+                self.closed_by_peer = true;
+                return Ok(None)
+            }
+            FRAME_MESSAGE => (0, &header[1..(1 + TYPE_ID_LEN)]),
+            frame_type if has_correlation(frame_type) => (
+                u64::from_le_bytes(header[1..9].try_into().unwrap()),
+                &header[9..],
+            ),
+            _ => {
+                self.poisoned = Some(format!("{fn_name} received an unknown frame type"));
+                bail_locationless!(
+                    "{fn_name} -> received the unknown frame type {frame_type}, the other side \
+                     may be from an incompatible version"
+                )
+            }
+        };
+        let header = FrameHeader {
+            frame_type,
+            correlation,
+            id: id.try_into().unwrap(),
+            compressed,
+        };
+        self.progress.header = Some(header);
+        Ok(Some(header))
     }
+
+    /// Reads the length and body of the frame with `header` into the receive
+    /// buffer, and returns the length of the (decompressed) body. Like
+    /// `read_header`, this continues where a cancelled call stopped.
+    async fn read_body(
+        &mut self,
+        fn_name: &str,
+        header: FrameHeader,
+        deadline: Option<Instant>,
+    ) -> Result<usize> {
+        let grace = self.grace();
+        let data_len = match self.progress.body_len {
+            Some(data_len) => data_len,
+            None => {
+                let res = read_live(
+                    &mut self.reader,
+                    &mut self.progress.len_bytes,
+                    &mut self.progress.len_filled,
+                    deadline,
+                    grace,
+                )
+                .await;
+                if let Err(stop) = res {
+                    return Err(self.read_stopped(fn_name, Phase::Length, true, stop))
+                }
+                let data_len = u64::from_le_bytes(self.progress.len_bytes);
+                let data_len = match usize::try_from(data_len) {
+                    Ok(data_len) if data_len <= self.max_message_size => data_len,
+                    _ => {
+                        self.poisoned =
+                            Some(format!("{fn_name} received a message that was too large"));
+                        bail_locationless!(
+                            "{fn_name} -> the other side advertised a message of {data_len} \
+                             bytes, which is larger than the `max_message_size` of {} bytes",
+                            self.max_message_size
+                        )
+                    }
+                };
+                self.progress.len_filled = 0;
+                self.progress.body_len = Some(data_len);
+                data_len
+            }
+        };
+        // the buffer grows as the data arrives, so that an advertised size alone does
+        // not allocate
+        while self.progress.body_filled < data_len {
+            let filled = self.progress.body_filled;
+            let target = min(data_len, max(filled.saturating_mul(2), BODY_CHUNK));
+            if self.recv_buf.len() < target {
+                self.recv_buf.resize(target, 0);
+            }
+            let res = read_live(
+                &mut self.reader,
+                &mut self.recv_buf[..target],
+                &mut self.progress.body_filled,
+                deadline,
+                grace,
+            )
+            .await;
+            if let Err(stop) = res {
+                return Err(self.read_stopped(fn_name, Phase::Body, true, stop))
+            }
+        }
+        self.progress = ReadProgress::default();
+        if !header.compressed {
+            return Ok(data_len)
+        }
+        // the whole frame was read, so a failure here does not poison
+        let body = decompress(
+            self.compression,
+            &self.recv_buf[..data_len],
+            self.max_message_size,
+        )
+        .stack_err_with_locationless(|| format!("{fn_name} -> could not decompress the message"))?;
+        let len = body.len();
+        self.recv_buf = body;
+        Ok(len)
+    }
+}
+
+/// Sends `msg` to all of `messengers` concurrently with
+/// [NetMessenger::send_timeout], e.g. for a coordinator that tells all of its
+/// workers to start the next phase. Every messenger is attempted even if some
+/// of them fail, and if any failed the error lists which of them (by index)
+/// and why.
+///
+/// The order of the messages on each connection is the same as with a `send`,
+/// so that anything sent before the broadcast is received before it.
+pub async fn broadcast<T: ?Sized + Serialize>(
+    messengers: &mut [NetMessenger],
+    msg: &T,
+    timeout: Duration,
+) -> Result<()> {
+    let num = messengers.len();
+    let mut sends: Vec<_> = messengers
+        .iter_mut()
+        .map(|messenger| Box::pin(messenger.send_timeout::<T>(msg, timeout)))
+        .collect();
+    let mut results: Vec<Option<Result<()>>> = (0..num).map(|_| None).collect();
+    // each messenger has at most one send in flight, so they can be polled together
+    poll_fn(|cx: &mut Context| {
+        let mut pending = false;
+        for (send, res) in sends.iter_mut().zip(results.iter_mut()) {
+            if res.is_none() {
+                match send.as_mut().poll(cx) {
+                    Poll::Ready(tmp) => *res = Some(tmp),
+                    Poll::Pending => pending = true,
+                }
+            }
+        }
+        if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    })
+    .await;
+    let results: Vec<Result<()>> = results.into_iter().map(Option::unwrap).collect();
+
+    let failed: Vec<usize> = results
+        .iter()
+        .enumerate()
+        .filter_map(|(i, res)| res.is_err().then_some(i))
+        .collect();
+    if failed.is_empty() {
+        return Ok(())
+    }
+    let mut errors: Option<Error> = None;
+    for (i, res) in results.into_iter().enumerate() {
+        if let Err(e) = res {
+            let e = e.add_err_locationless(format!("messenger {i}"));
+            errors = Some(match errors {
+                Some(errors) => errors.chain_errors(e),
+                None => e,
+            });
+        }
+    }
+    Err(errors.unwrap().add_err_locationless(format!(
+        "broadcast -> failed to send to {} of {num} messengers, which are at the indexes \
+         {failed:?}",
+        failed.len()
+    )))
+}
+
+/// The message for when the other side closed the connection between frames
+fn closed_message(fn_name: &str) -> String {
+    format!(
+        "{fn_name} could not read_exact, this may be because the other side was abruptly \
+         terminated"
+    )
 }
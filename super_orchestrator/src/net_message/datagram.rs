@@ -0,0 +1,173 @@
+use std::{any::type_name, net::SocketAddr, time::Duration};
+
+use serde::{de::DeserializeOwned, Serialize};
+use stacked_errors::{bail_locationless, Error, Result, StackableErr};
+use tokio::{
+    net::{lookup_host, ToSocketAddrs, UdpSocket},
+    time::Instant,
+};
+
+use super::{decode, encode, with_deadline, Codec, TYPE_ID_LEN};
+use crate::type_hash;
+
+/// The default of [NetDatagram::mtu], which is what fits in an Ethernet frame
+/// with the IPv4 and UDP headers
+pub const DEFAULT_MTU: usize = 1472;
+/// The largest payload of a UDP datagram
+const MAX_DATAGRAM_LEN: usize = 65507;
+
+/// Fire-and-forget messages over UDP with the same typed encoding as
+/// [NetMessenger](super::NetMessenger), e.g. for measuring latencies between
+/// containers. Each datagram is the type ID followed by the body encoded
+/// with the [Codec]. There is no connection or handshake, so datagrams can
+/// be lost, duplicated, or arrive out of order, and both sides need to use
+/// the same codec.
+#[derive(Debug)]
+pub struct NetDatagram {
+    socket: UdpSocket,
+    codec: Codec,
+    mtu: usize,
+    buf: Vec<u8>,
+    datagram: Vec<u8>,
+}
+
+impl NetDatagram {
+    /// Binds a UDP socket to `host`, use port 0 to let the OS choose the port
+    /// (see [NetDatagram::local_addr])
+    pub async fn bind(host: &str) -> Result<Self> {
+        let socket_addr = lookup_host(host)
+            .await
+            .stack_err_locationless("NetDatagram::bind")?
+            .next()
+            .stack_err_locationless(
+                "NetDatagram::bind -> no socket addresses from lookup_host(host)",
+            )?;
+        let socket = UdpSocket::bind(socket_addr)
+            .await
+            .stack_err_with_locationless(|| {
+                format!("NetDatagram::bind -> could not bind to {socket_addr}")
+            })?;
+        Ok(Self {
+            socket,
+            codec: Codec::default(),
+            mtu: DEFAULT_MTU,
+            buf: vec![],
+            datagram: vec![],
+        })
+    }
+
+    /// Sets the [Codec] of the message bodies, this defaults to
+    /// [Codec::Postcard]
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Sets the maximum size of the datagrams including the type ID, this
+    /// defaults to [DEFAULT_MTU]. Larger datagrams can be fragmented or
+    /// dropped by the network, so `send_to` returns an error for them instead.
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    /// Returns the address that the socket is bound to, which is useful when
+    /// binding to port 0
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket
+            .local_addr()
+            .stack_err_locationless("NetDatagram::local_addr")
+    }
+
+    /// Sends `msg` in a datagram to `addr`. Returns an error without sending
+    /// anything if the datagram would be larger than the [NetDatagram::mtu].
+    ///
+    /// Note: You should always use the turbofish to specify `T`, see
+    /// [NetMessenger::send](super::NetMessenger::send).
+    pub async fn send_to<T: ?Sized + Serialize>(
+        &mut self,
+        msg: &T,
+        addr: impl ToSocketAddrs,
+    ) -> Result<()> {
+        let fn_name = format!("NetDatagram::send_to::<{}>()", type_name::<T>());
+        encode(self.codec, usize::MAX, &mut self.buf, msg, &fn_name)?;
+        let len = TYPE_ID_LEN + self.buf.len();
+        if len > self.mtu {
+            bail_locationless!(
+                "{fn_name} -> the datagram is {len} bytes with the type ID, which is larger than \
+                 the MTU of {} bytes",
+                self.mtu
+            )
+        }
+        self.datagram.clear();
+        self.datagram.extend_from_slice(&type_hash::<T>());
+        self.datagram.extend_from_slice(&self.buf);
+        self.buf.clear();
+        let sent = self
+            .socket
+            .send_to(&self.datagram, addr)
+            .await
+            .stack_err_locationless(fn_name.clone())?;
+        if sent != len {
+            bail_locationless!("{fn_name} -> only {sent} of {len} bytes were sent")
+        }
+        Ok(())
+    }
+
+    /// Waits for a datagram with a message of type `T`, and returns it with
+    /// the address it came from. Returns an error for a datagram of another
+    /// type or one that cannot be decoded, after which the next datagram can
+    /// be received.
+    ///
+    /// This is cancel safe.
+    pub async fn recv_from<T: DeserializeOwned>(&mut self) -> Result<(T, SocketAddr)> {
+        let fn_name = format!("NetDatagram::recv_from::<{}>()", type_name::<T>());
+        self.recv_internal(type_hash::<T>(), &fn_name, None).await
+    }
+
+    /// Like [NetDatagram::recv_from], but returns a timeout error if no
+    /// datagram arrived within `timeout`, e.g. because it was lost
+    pub async fn recv_from_timeout<T: DeserializeOwned>(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<(T, SocketAddr)> {
+        let fn_name = format!("NetDatagram::recv_from_timeout::<{}>()", type_name::<T>());
+        let deadline = Instant::now().checked_add(timeout);
+        self.recv_internal(type_hash::<T>(), &fn_name, deadline)
+            .await
+    }
+
+    async fn recv_internal<T: DeserializeOwned>(
+        &mut self,
+        expected_id: [u8; TYPE_ID_LEN],
+        fn_name: &str,
+        deadline: Option<Instant>,
+    ) -> Result<(T, SocketAddr)> {
+        // datagrams that are larger than the buffer would be truncated
+        self.datagram.resize(MAX_DATAGRAM_LEN, 0);
+        let (len, addr) = match with_deadline(deadline, self.socket.recv_from(&mut self.datagram))
+            .await
+        {
+            Some(res) => res.stack_err_locationless(fn_name.to_owned())?,
+            None => {
+                return Err(Error::timeout()
+                    .add_err_locationless(format!("{fn_name} -> timed out waiting for a datagram")))
+            }
+        };
+        let datagram = &self.datagram[..len];
+        if datagram.len() < TYPE_ID_LEN {
+            bail_locationless!(
+                "{fn_name} -> received a datagram of {len} bytes from {addr}, which is too short \
+                 for the type ID"
+            )
+        }
+        if datagram[..TYPE_ID_LEN] != expected_id {
+            bail_locationless!(
+                "{fn_name} -> the type of the datagram from {addr} did not match the expected type"
+            )
+        }
+        let msg = decode(self.codec, &datagram[TYPE_ID_LEN..], fn_name)
+            .stack_err_with_locationless(|| format!("{fn_name} -> datagram from {addr}"))?;
+        Ok((msg, addr))
+    }
+}
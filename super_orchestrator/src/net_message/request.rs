@@ -0,0 +1,311 @@
+use std::{
+    any::type_name,
+    fmt::{self, Display},
+    future::Future,
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use stacked_errors::{bail_locationless, Error, Result, StackableErr};
+use tokio::{task::JoinSet, time::Instant};
+
+use super::{
+    compress, decode, encode, stop_error, write_frame, Codec, FrameHeader, NetMessenger,
+    FRAME_ERROR, FRAME_REQUEST, FRAME_RESPONSE, TYPE_ID_LEN,
+};
+use crate::type_hash;
+
+/// Identifies a request in flight, see [NetMessenger::send_request]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RequestId(pub u64);
+
+/// What kind of [RemoteError] the serving side sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RemoteErrorKind {
+    /// The type of the request did not match the type that the serving side
+    /// handles
+    TypeMismatch,
+    /// The request could not be deserialized
+    BadRequest,
+    /// The handler returned an error
+    Handler,
+    /// The response could not be serialized or was too large
+    BadResponse,
+}
+
+/// An error that the serving side of [NetMessenger::serve] sent in response
+/// to a request. The connection stays usable after these.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteError {
+    pub kind: RemoteErrorKind,
+    pub message: String,
+}
+
+impl Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "RemoteError {{ kind: {:?}, message: {} }}",
+            self.kind, self.message
+        )
+    }
+}
+
+impl NetMessenger {
+    /// Sends `req` to the other side which is in [NetMessenger::serve], and
+    /// waits for the response. Returns a timeout error if the response has
+    /// not arrived within `timeout`, a late response is discarded when it
+    /// arrives. Errors that the other side sends back (e.g. from the handler
+    /// or a mismatched type) are returned as errors with a [RemoteError]
+    /// message, and do not affect the connection.
+    ///
+    /// Note: You should always use the turbofish to specify `Req` and `Resp`,
+    /// both are checked like with `send` and `recv`.
+    pub async fn request<Req: ?Sized + Serialize, Resp: DeserializeOwned>(
+        &mut self,
+        req: &Req,
+        timeout: Duration,
+    ) -> Result<Resp> {
+        let fn_name = format!(
+            "NetMessenger::request::<{}, {}>()",
+            type_name::<Req>(),
+            type_name::<Resp>()
+        );
+        let deadline = Instant::now().checked_add(timeout);
+        let id = self.send_request_internal(req, &fn_name, deadline).await?;
+        let res = self.recv_response_internal(id, &fn_name, deadline).await;
+        // a late response is discarded instead of kept around
+        self.in_flight.remove(&id.0);
+        res
+    }
+
+    /// Sends a request like [NetMessenger::request] without waiting for the
+    /// response. Any number of requests can be in flight at once, and their
+    /// responses can be received in any order with
+    /// [NetMessenger::recv_response].
+    pub async fn send_request<Req: ?Sized + Serialize>(&mut self, req: &Req) -> Result<RequestId> {
+        let fn_name = format!("NetMessenger::send_request::<{}>()", type_name::<Req>());
+        self.send_request_internal(req, &fn_name, None).await
+    }
+
+    /// Waits for the response to the request with `id` from
+    /// [NetMessenger::send_request]. Responses to other requests that arrive
+    /// first are kept for their `recv_response` calls. If this times out, the
+    /// request stays in flight and this can be called again.
+    pub async fn recv_response<Resp: DeserializeOwned>(
+        &mut self,
+        id: RequestId,
+        timeout: Duration,
+    ) -> Result<Resp> {
+        let fn_name = format!("NetMessenger::recv_response::<{}>()", type_name::<Resp>());
+        let deadline = Instant::now().checked_add(timeout);
+        self.recv_response_internal(id, &fn_name, deadline).await
+    }
+
+    async fn send_request_internal<Req: ?Sized + Serialize>(
+        &mut self,
+        req: &Req,
+        fn_name: &str,
+        deadline: Option<Instant>,
+    ) -> Result<RequestId> {
+        let correlation = self.next_request;
+        self.next_request = self.next_request.wrapping_add(1);
+        let header = FrameHeader {
+            frame_type: FRAME_REQUEST,
+            correlation,
+            id: type_hash::<Req>(),
+            compressed: false,
+        };
+        self.send_internal(req, header, fn_name, deadline).await?;
+        self.in_flight.insert(correlation);
+        Ok(RequestId(correlation))
+    }
+
+    async fn recv_response_internal<Resp: DeserializeOwned>(
+        &mut self,
+        id: RequestId,
+        fn_name: &str,
+        deadline: Option<Instant>,
+    ) -> Result<Resp> {
+        self.check_poisoned(fn_name)?;
+        loop {
+            if let Some((header, body)) = self.responses.remove(&id.0) {
+                self.in_flight.remove(&id.0);
+                return decode_response(self.codec, header, &body, fn_name)
+            }
+            if !self.in_flight.contains(&id.0) {
+                bail_locationless!("{fn_name} -> there is no request in flight with {id:?}")
+            }
+            let Some(header) = self.read_header(fn_name, deadline).await? else {
+                return Err(self.closed_error(fn_name)).stack_err_locationless(format!(
+                    "{fn_name} -> the other side closed the connection before responding"
+                ))
+            };
+            if !matches!(header.frame_type, FRAME_RESPONSE | FRAME_ERROR) {
+                self.poisoned = Some(format!("{fn_name} received a frame that is not a response"));
+                bail_locationless!(
+                    "{fn_name} -> received a frame of type {} while waiting for a response",
+                    header.frame_type
+                )
+            }
+            let len = self.read_body(fn_name, header, deadline).await?;
+            if header.correlation == id.0 {
+                self.in_flight.remove(&id.0);
+                let res = decode_response(self.codec, header, &self.recv_buf[..len], fn_name);
+                self.shrink_recv_buf();
+                return res
+            }
+            // responses to requests that are not in flight anymore are discarded
+            if self.in_flight.contains(&header.correlation) {
+                self.responses
+                    .insert(header.correlation, (header, self.recv_buf[..len].to_vec()));
+            }
+            self.shrink_recv_buf();
+        }
+    }
+
+    /// Answers the requests of the other side (see [NetMessenger::request])
+    /// with `handler` until the other side closes the connection (with
+    /// [NetMessenger::close] or otherwise). The
+    /// handlers of different requests run concurrently, and their responses
+    /// are sent when they finish. Requests of the wrong type and errors from
+    /// `handler` are sent back as [RemoteError]s.
+    ///
+    /// Note: You should always use the turbofish to specify `Req` and `Resp`
+    /// or use an explicit type on the argument of the closure.
+    pub async fn serve<Req, Resp, F, Fut>(&mut self, handler: F) -> Result<()>
+    where
+        Req: DeserializeOwned + Send + 'static,
+        Resp: Serialize + Send + 'static,
+        F: Fn(Req) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Resp>> + Send + 'static,
+    {
+        let fn_name = format!(
+            "NetMessenger::serve::<{}, {}>()",
+            type_name::<Req>(),
+            type_name::<Resp>()
+        );
+        self.check_poisoned(&fn_name)?;
+        let handler = Arc::new(handler);
+        // the handlers write their own responses
+        let mut handlers: JoinSet<Result<()>> = JoinSet::new();
+        loop {
+            while let Some(res) = handlers.try_join_next() {
+                self.check_handler(res, &fn_name)?;
+            }
+            let Some(header) = self.read_header(&fn_name, None).await? else {
+                break
+            };
+            if header.frame_type != FRAME_REQUEST {
+                self.poisoned = Some(format!("{fn_name} received a frame that is not a request"));
+                bail_locationless!(
+                    "{fn_name} -> received a frame of type {} instead of a request",
+                    header.frame_type
+                )
+            }
+            let len = self.read_body(&fn_name, header, None).await?;
+            let req = if header.id != type_hash::<Req>() {
+                Err(RemoteError {
+                    kind: RemoteErrorKind::TypeMismatch,
+                    message: format!("the request type did not match `{}`", type_name::<Req>()),
+                })
+            } else {
+                decode::<Req>(self.codec, &self.recv_buf[..len], &fn_name).map_err(|e| {
+                    RemoteError {
+                        kind: RemoteErrorKind::BadRequest,
+                        message: format!("{e:?}"),
+                    }
+                })
+            };
+            self.shrink_recv_buf();
+
+            let handler = Arc::clone(&handler);
+            let writer = Arc::clone(&self.writer);
+            let codec = self.codec;
+            let compression = self.compression;
+            let max_message_size = self.max_message_size;
+            let grace = self.grace();
+            let fn_name = fn_name.clone();
+            handlers.spawn(async move {
+                let res = match req {
+                    Ok(req) => handler(req).await.map_err(|e| RemoteError {
+                        kind: RemoteErrorKind::Handler,
+                        message: format!("{e:?}"),
+                    }),
+                    Err(e) => Err(e),
+                };
+                let mut body = vec![];
+                let res = res.and_then(|resp| {
+                    encode(codec, max_message_size, &mut body, &resp, &fn_name).map_err(|e| {
+                        RemoteError {
+                            kind: RemoteErrorKind::BadResponse,
+                            message: format!("{e:?}"),
+                        }
+                    })
+                });
+                let (frame_type, id) = match res {
+                    Ok(()) => (FRAME_RESPONSE, type_hash::<Resp>()),
+                    Err(e) => {
+                        encode(codec, usize::MAX, &mut body, &e, &fn_name).stack()?;
+                        (FRAME_ERROR, [0; TYPE_ID_LEN])
+                    }
+                };
+                let compressed = compress(compression, &body);
+                let header = FrameHeader {
+                    frame_type,
+                    correlation: header.correlation,
+                    id,
+                    compressed: compressed.is_some(),
+                };
+                let body = compressed.as_deref().unwrap_or(&body);
+                write_frame(&writer, &header.to_bytes(), body, None, grace)
+                    .await
+                    .map_err(|(phase, _, stop)| stop_error(&fn_name, phase, stop, grace))
+            });
+        }
+        // the other side may still read the responses after it stopped writing, if it
+        // closed the connection completely the responses that could not be written
+        // are reported
+        while let Some(res) = handlers.join_next().await {
+            self.check_handler(res, &fn_name)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the result of a handler task of `serve`, which fails if it
+    /// panicked or the response could not be written
+    fn check_handler(
+        &mut self,
+        res: std::result::Result<Result<()>, tokio::task::JoinError>,
+        fn_name: &str,
+    ) -> Result<()> {
+        let res = match res {
+            Ok(res) => res,
+            Err(e) => Err(Error::from_err_locationless(e)),
+        };
+        if let Err(e) = res {
+            self.poisoned = Some(format!("{fn_name} could not write a response"));
+            return Err(e).stack_err_locationless(format!("{fn_name} -> could not send a response"))
+        }
+        Ok(())
+    }
+}
+
+/// Returns the response or [RemoteError] in a frame
+fn decode_response<Resp: DeserializeOwned>(
+    codec: Codec,
+    header: FrameHeader,
+    body: &[u8],
+    fn_name: &str,
+) -> Result<Resp> {
+    if header.frame_type == FRAME_ERROR {
+        let remote: RemoteError = decode(codec, body, fn_name)?;
+        return Err(Error::from_err_locationless(remote))
+            .stack_err_locationless(format!("{fn_name} -> the other side returned an error"))
+    }
+    if header.id != type_hash::<Resp>() {
+        bail_locationless!("{fn_name} -> the response type did not match the expected type")
+    }
+    decode(codec, body, fn_name)
+}
@@ -0,0 +1,149 @@
+use std::{collections::VecDeque, net::SocketAddr, time::Duration};
+
+use stacked_errors::{bail_locationless, Error, Result, StackableErr};
+use tokio::{
+    net::{lookup_host, TcpListener},
+    select,
+    task::JoinSet,
+    time::Instant,
+};
+
+use super::{with_deadline, NetMessenger, NetMessengerOptions, Stream};
+
+/// Accepts any number of `NetMessenger` connections on one address, e.g. for
+/// a coordinator container that talks to several workers which each use
+/// [NetMessenger::connect].
+///
+/// The accepting functions are cancel safe, connections that were accepted
+/// or are in the middle of the handshake when an `accept` future is dropped
+/// are kept and returned by later calls.
+#[derive(Debug)]
+pub struct NetMessengerServer {
+    listener: TcpListener,
+    options: NetMessengerOptions,
+    // handshakes in progress
+    handshakes: JoinSet<(Result<NetMessenger>, SocketAddr)>,
+    // finished handshakes that have not been returned yet
+    accepted: VecDeque<(NetMessenger, SocketAddr)>,
+}
+
+impl NetMessengerServer {
+    /// Binds to and listens on `host`
+    pub async fn bind(host: &str) -> Result<Self> {
+        let socket_addr = lookup_host(host)
+            .await
+            .stack_err_locationless("NetMessengerServer::bind")?
+            .next()
+            .stack_err_locationless(
+                "NetMessengerServer::bind -> no socket addresses from lookup_host(host)",
+            )?;
+        let listener = TcpListener::bind(socket_addr)
+            .await
+            .stack_err_with_locationless(|| {
+                format!("NetMessengerServer::bind -> could not bind to {socket_addr}")
+            })?;
+        Ok(Self {
+            listener,
+            options: NetMessengerOptions::new(),
+            handshakes: JoinSet::new(),
+            accepted: VecDeque::new(),
+        })
+    }
+
+    /// Sets the options that the handshakes of the accepted connections use
+    pub fn options(mut self, options: NetMessengerOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Returns the address that the server is bound to, which is useful when
+    /// binding to port 0
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener
+            .local_addr()
+            .stack_err_locationless("NetMessengerServer::local_addr")
+    }
+
+    /// Accepts the next connection and returns its messenger and the address
+    /// of the other side. Returns a timeout error if `timeout` is reached
+    /// first, and an error if `CTRLC_ISSUED`. A connection that fails the
+    /// handshake is also an error, after which `accept` can be called again.
+    pub async fn accept(&mut self, timeout: Duration) -> Result<(NetMessenger, SocketAddr)> {
+        let deadline = Instant::now().checked_add(timeout);
+        if self.accepted.is_empty() {
+            self.accept_until(deadline)
+                .await
+                .stack_err_locationless("NetMessengerServer::accept")?;
+        }
+        Ok(self.accepted.pop_front().unwrap())
+    }
+
+    /// Accepts `n` connections like [NetMessengerServer::accept], where
+    /// `timeout` is for all of them. If there is an error, the connections
+    /// that were already accepted are returned by the next calls.
+    pub async fn accept_n(
+        &mut self,
+        n: usize,
+        timeout: Duration,
+    ) -> Result<Vec<(NetMessenger, SocketAddr)>> {
+        let deadline = Instant::now().checked_add(timeout);
+        // the messengers stay in `accepted` until there are enough of them, so that
+        // nothing is lost if this is cancelled
+        while self.accepted.len() < n {
+            let accepted = self.accepted.len();
+            self.accept_until(deadline)
+                .await
+                .stack_err_with_locationless(|| {
+                    format!(
+                        "NetMessengerServer::accept_n -> accepted {accepted} of {n} connections"
+                    )
+                })?;
+        }
+        Ok(self.accepted.drain(..n).collect())
+    }
+
+    /// Adds one more messenger to `accepted`
+    async fn accept_until(&mut self, deadline: Option<Instant>) -> Result<()> {
+        match with_deadline(deadline, self.accept_next()).await {
+            Some(res) => res,
+            None => Err(Error::timeout()),
+        }
+    }
+
+    /// Each handshake has the connect timeout of the options from when its
+    /// connection was accepted, so that a handshake that outlives one call can
+    /// still finish in a later one
+    async fn accept_next(&mut self) -> Result<()> {
+        loop {
+            // `TcpListener::accept`, `JoinSet::join_next`, and `ctrl_c` are all cancel safe
+            select! {
+                tmp = self.listener.accept() => {
+                    let (stream, addr) = tmp.stack_err_locationless("could not accept")?;
+                    let options = self.options.clone();
+                    let deadline = options.connect_deadline();
+                    self.handshakes.spawn(async move {
+                        let res =
+                            NetMessenger::handshake(Stream::Tcp(stream), deadline, &options).await;
+                        (res, addr)
+                    });
+                }
+                Some(tmp) = self.handshakes.join_next() => {
+                    match tmp.stack_err_locationless("handshake task panicked")? {
+                        (Ok(messenger), addr) => {
+                            self.accepted.push_back((messenger, addr));
+                            return Ok(())
+                        }
+                        (Err(e), addr) => {
+                            return Err(e).stack_err_with_locationless(|| {
+                                format!("the connection from {addr} failed the handshake")
+                            })
+                        }
+                    }
+                }
+                _ = tokio::signal::ctrl_c() => {
+                    bail_locationless!("terminating because of `CTRLC_ISSUED`")
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use std::cmp::min;
+
+use crc32fast::Hasher;
+use serde::{Deserialize, Serialize};
+use stacked_errors::{bail_locationless, Result, StackableErr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{tag_hash, FrameHeader, NetMessenger, FRAME_MESSAGE};
+use crate::{close_file, FileOptions, ReadOrWrite};
+
+/// The tag of the message that starts a stream, see
+/// [NetMessenger::send_stream]. The stream messages have fixed tags instead of
+/// type names, so that they do not change between compiler versions.
+pub const STREAM_START_TAG: &str = "super_orchestrator::net_message::stream::StreamStart";
+/// The tag of the answer of [NetMessenger::recv_stream] to the start
+pub const STREAM_ACCEPT_TAG: &str = "super_orchestrator::net_message::stream::StreamAccept";
+/// The tag of the frames with the chunks of a stream, whose bodies are the
+/// raw bytes instead of being encoded with the codec
+pub const STREAM_CHUNK_TAG: &str = "super_orchestrator::net_message::StreamChunk";
+/// The tag of the message with the checksum after the last chunk
+pub const STREAM_END_TAG: &str = "super_orchestrator::net_message::stream::StreamEnd";
+
+/// Sent first by `send_stream`, with the chunk size it proposes
+#[derive(Debug, Serialize, Deserialize)]
+struct StreamStart {
+    len: u64,
+    chunk_size: u64,
+}
+
+/// The answer of `recv_stream` with the chunk size that is used
+#[derive(Debug, Serialize, Deserialize)]
+struct StreamAccept {
+    chunk_size: u64,
+}
+
+/// Sent after the last chunk, with the CRC32 of all the bytes
+#[derive(Debug, Serialize, Deserialize)]
+struct StreamEnd {
+    checksum: u32,
+}
+
+impl NetMessenger {
+    /// Sets the size of the chunks that [NetMessenger::send_stream] splits
+    /// streams into, this defaults to
+    /// [DEFAULT_STREAM_CHUNK_SIZE](super::DEFAULT_STREAM_CHUNK_SIZE) (1 MiB).
+    /// Both sides use the smaller of their chunk sizes, which is also limited
+    /// by the `max_message_size` of both sides.
+    pub fn stream_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.stream_chunk_size = chunk_size;
+        self
+    }
+
+    /// Sends `len` bytes from `reader` to the other side which is in
+    /// [NetMessenger::recv_stream], without holding more than one chunk in
+    /// memory. This is for payloads that are too large for a single message,
+    /// e.g. a database snapshot. The bytes are sent in chunks (see
+    /// [NetMessenger::stream_chunk_size]) followed by a checksum that the
+    /// other side verifies. `progress` is called with the number of bytes
+    /// sent so far and `len` after each chunk.
+    ///
+    /// Returns an error if `reader` ends before `len` bytes. This is not
+    /// cancel safe, if the future is dropped or there is an error in the
+    /// middle of the stream, the messenger is poisoned because the other side
+    /// is left in the middle of the stream.
+    pub async fn send_stream<R: AsyncRead + Unpin>(
+        &mut self,
+        mut reader: R,
+        len: u64,
+        progress: Option<&mut (dyn FnMut(u64, u64) + Send)>,
+    ) -> Result<()> {
+        let fn_name = "NetMessenger::send_stream()";
+        self.check_poisoned(fn_name)?;
+        // this stays if the future is dropped before the end of the stream
+        self.poisoned = Some(format!("{fn_name} stopped in the middle of a stream"));
+        let res = self
+            .send_stream_internal(&mut reader, len, progress, fn_name)
+            .await;
+        self.shrink_buf();
+        if res.is_ok() {
+            self.poisoned = None;
+        }
+        res
+    }
+
+    async fn send_stream_internal<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+        len: u64,
+        mut progress: Option<&mut (dyn FnMut(u64, u64) + Send)>,
+        fn_name: &str,
+    ) -> Result<()> {
+        let proposed = min(self.stream_chunk_size, self.max_message_size).max(1);
+        let start = StreamStart {
+            len,
+            chunk_size: proposed as u64,
+        };
+        let header = FrameHeader::message(tag_hash(STREAM_START_TAG));
+        self.send_message(&start, header, fn_name, None).await?;
+        let accept: StreamAccept = self
+            .recv_message(tag_hash(STREAM_ACCEPT_TAG), fn_name, None)
+            .await?;
+        let chunk_size = match usize::try_from(accept.chunk_size) {
+            Ok(chunk_size) if (1..=proposed).contains(&chunk_size) => chunk_size,
+            _ => bail_locationless!(
+                "{fn_name} -> the other side answered with a chunk size of {}, but this side \
+                 proposed {proposed}",
+                accept.chunk_size
+            ),
+        };
+
+        let chunk_header = FrameHeader::message(tag_hash(STREAM_CHUNK_TAG));
+        let mut hasher = Hasher::new();
+        let mut sent = 0u64;
+        while sent < len {
+            let n = min(chunk_size as u64, len - sent) as usize;
+            self.buf.clear();
+            self.buf.resize(n, 0);
+            reader
+                .read_exact(&mut self.buf)
+                .await
+                .stack_err_with_locationless(|| {
+                    format!("{fn_name} -> could not read the stream after {sent} of {len} bytes")
+                })?;
+            hasher.update(&self.buf);
+            self.write_buf(chunk_header, fn_name, None).await?;
+            sent += n as u64;
+            if let Some(progress) = progress.as_mut() {
+                progress(sent, len);
+            }
+        }
+        let end = StreamEnd {
+            checksum: hasher.finalize(),
+        };
+        let header = FrameHeader::message(tag_hash(STREAM_END_TAG));
+        self.send_message(&end, header, fn_name, None).await
+    }
+
+    /// Receives what the other side sends with [NetMessenger::send_stream]
+    /// and writes it to `writer`, returning the number of bytes. `progress`
+    /// is called with the number of bytes received so far and the total after
+    /// each chunk.
+    ///
+    /// Returns an error if the checksum at the end does not match, in which
+    /// case the messenger can still be used but `writer` has received bad
+    /// data. This is only cancel safe until the start of the stream is
+    /// received, if the future is dropped or there is another error in the
+    /// middle of the stream, the messenger is poisoned.
+    pub async fn recv_stream<W: AsyncWrite + Unpin>(
+        &mut self,
+        mut writer: W,
+        progress: Option<&mut (dyn FnMut(u64, u64) + Send)>,
+    ) -> Result<u64> {
+        let fn_name = "NetMessenger::recv_stream()";
+        self.check_poisoned(fn_name)?;
+        let res = self
+            .recv_stream_internal(&mut writer, progress, fn_name)
+            .await;
+        self.shrink_recv_buf();
+        if res.is_ok() {
+            self.poisoned = None;
+        }
+        res
+    }
+
+    async fn recv_stream_internal<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        mut progress: Option<&mut (dyn FnMut(u64, u64) + Send)>,
+        fn_name: &str,
+    ) -> Result<u64> {
+        // receiving the start is cancel safe like other messages
+        let start: StreamStart = self
+            .recv_message(tag_hash(STREAM_START_TAG), fn_name, None)
+            .await?;
+        // this stays if the future is dropped before the end of the stream
+        self.poisoned = Some(format!("{fn_name} stopped in the middle of a stream"));
+        if start.chunk_size == 0 {
+            bail_locationless!("{fn_name} -> the other side proposed a chunk size of 0")
+        }
+        let len = start.len;
+        let ours = min(self.stream_chunk_size, self.max_message_size).max(1);
+        let chunk_size = min(ours as u64, start.chunk_size);
+        let header = FrameHeader::message(tag_hash(STREAM_ACCEPT_TAG));
+        self.send_message(&StreamAccept { chunk_size }, header, fn_name, None)
+            .await?;
+
+        let chunk_id = tag_hash(STREAM_CHUNK_TAG);
+        let mut hasher = Hasher::new();
+        let mut received = 0u64;
+        while received < len {
+            let Some(header) = self.read_header(fn_name, None).await? else {
+                return Err(self.closed_error(fn_name)).stack_err_with_locationless(|| {
+                    format!(
+                        "{fn_name} -> the connection was closed after {received} of {len} bytes \
+                         of the stream"
+                    )
+                })
+            };
+            if (header.frame_type != FRAME_MESSAGE) || (header.id != chunk_id) {
+                bail_locationless!(
+                    "{fn_name} -> received a frame that is not a chunk after {received} of {len} \
+                     bytes of the stream"
+                )
+            }
+            let n = self.read_body(fn_name, header, None).await?;
+            if (n as u64) > min(chunk_size, len - received) {
+                bail_locationless!(
+                    "{fn_name} -> received a chunk of {n} bytes, which is larger than the chunk \
+                     size of {chunk_size} or the rest of the stream"
+                )
+            }
+            hasher.update(&self.recv_buf[..n]);
+            writer
+                .write_all(&self.recv_buf[..n])
+                .await
+                .stack_err_with_locationless(|| {
+                    format!(
+                        "{fn_name} -> could not write the stream after {received} of {len} bytes"
+                    )
+                })?;
+            received += n as u64;
+            if let Some(progress) = progress.as_mut() {
+                progress(received, len);
+            }
+        }
+        writer
+            .flush()
+            .await
+            .stack_err_locationless(format!("{fn_name} -> could not flush the writer"))?;
+        let end: StreamEnd = self
+            .recv_message(tag_hash(STREAM_END_TAG), fn_name, None)
+            .await?;
+        if end.checksum != hasher.finalize() {
+            // the whole stream was read, so the framing is intact
+            self.poisoned = None;
+            bail_locationless!(
+                "{fn_name} -> the checksum of the {len} bytes of the stream did not match the \
+                 checksum that the other side sent"
+            )
+        }
+        Ok(len)
+    }
+
+    /// Sends the file of `file` with [NetMessenger::send_stream], `file` needs
+    /// to be for reading
+    pub async fn send_file(&mut self, file: FileOptions) -> Result<()> {
+        if file.options != ReadOrWrite::Read {
+            bail_locationless!("NetMessenger::send_file -> {file:?} is not for reading")
+        }
+        let f = file
+            .acquire_file()
+            .await
+            .stack_err_locationless("NetMessenger::send_file")?;
+        let len = f
+            .metadata()
+            .await
+            .stack_err_with_locationless(|| format!("NetMessenger::send_file -> {file:?}"))?
+            .len();
+        self.send_stream(f, len, None)
+            .await
+            .stack_err_with_locationless(|| format!("NetMessenger::send_file -> {file:?}"))
+    }
+
+    /// Receives a file that the other side sends with
+    /// [NetMessenger::send_file] or `send_stream`, and writes it to `file`
+    /// which needs to be for writing. Returns the number of bytes.
+    pub async fn recv_to_file(&mut self, file: FileOptions) -> Result<u64> {
+        if file.options == ReadOrWrite::Read {
+            bail_locationless!("NetMessenger::recv_to_file -> {file:?} is not for writing")
+        }
+        let mut f = file
+            .acquire_file()
+            .await
+            .stack_err_locationless("NetMessenger::recv_to_file")?;
+        let len = self
+            .recv_stream(&mut f, None)
+            .await
+            .stack_err_with_locationless(|| format!("NetMessenger::recv_to_file -> {file:?}"))?;
+        close_file(f)
+            .await
+            .stack_err_with_locationless(|| format!("NetMessenger::recv_to_file -> {file:?}"))?;
+        Ok(len)
+    }
+}
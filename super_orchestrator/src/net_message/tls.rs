@@ -0,0 +1,215 @@
+use std::{sync::Arc, time::Duration};
+
+use stacked_errors::{bail_locationless, Error, Result, StackableErr};
+use tokio::time::Instant;
+use tokio_rustls::{
+    rustls::{
+        crypto::{ring::default_provider, CryptoProvider},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+        server::WebPkiClientVerifier,
+        ClientConfig, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor, TlsConnector,
+};
+
+use super::{
+    accept_single, connect_single, with_deadline, NetMessenger, NetMessengerOptions, Stream,
+};
+
+/// The certificates for [NetMessenger::connect_tls] and
+/// [NetMessenger::listen_tls]. Both sides always authenticate each other
+/// (mutual TLS), so both sides need a certificate that the other side trusts.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    client: Arc<ClientConfig>,
+    server: Arc<ServerConfig>,
+    server_name: Option<String>,
+}
+
+impl TlsConfig {
+    /// Uses the PEM encoded certificate chain `cert_pem` and private key
+    /// `key_pem` for this side, and trusts the other side if its certificate
+    /// is signed by one of the certificates in `ca_pem`. The PEM files can be
+    /// loaded with [FileOptions::read_to_vec](crate::FileOptions::read_to_vec).
+    pub fn mutual(cert_pem: &[u8], key_pem: &[u8], ca_pem: &[u8]) -> Result<Self> {
+        let certs = CertificateDer::pem_slice_iter(cert_pem)
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| format!("{e:?}"))
+            .stack_err_locationless("TlsConfig::mutual -> could not parse `cert_pem`")?;
+        if certs.is_empty() {
+            bail_locationless!("TlsConfig::mutual -> there are no certificates in `cert_pem`")
+        }
+        let key = PrivateKeyDer::from_pem_slice(key_pem)
+            .map_err(|e| format!("{e:?}"))
+            .stack_err_locationless("TlsConfig::mutual -> could not parse `key_pem`")?;
+        let mut roots = RootCertStore::empty();
+        for ca in CertificateDer::pem_slice_iter(ca_pem) {
+            let ca = ca
+                .map_err(|e| format!("{e:?}"))
+                .stack_err_locationless("TlsConfig::mutual -> could not parse `ca_pem`")?;
+            roots
+                .add(ca)
+                .stack_err_locationless("TlsConfig::mutual -> invalid certificate in `ca_pem`")?;
+        }
+        if roots.is_empty() {
+            bail_locationless!("TlsConfig::mutual -> there are no certificates in `ca_pem`")
+        }
+        let roots = Arc::new(roots);
+        let provider: Arc<CryptoProvider> = Arc::new(default_provider());
+
+        let client = ClientConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .stack_err_locationless("TlsConfig::mutual")?
+            .with_root_certificates(Arc::clone(&roots))
+            .with_client_auth_cert(certs.clone(), key.clone_key())
+            .stack_err_locationless("TlsConfig::mutual -> invalid certificate or key")?;
+        let verifier = WebPkiClientVerifier::builder_with_provider(roots, Arc::clone(&provider))
+            .build()
+            .stack_err_locationless("TlsConfig::mutual")?;
+        let server = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .stack_err_locationless("TlsConfig::mutual")?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)
+            .stack_err_locationless("TlsConfig::mutual -> invalid certificate or key")?;
+        Ok(Self {
+            client: Arc::new(client),
+            server: Arc::new(server),
+            server_name: None,
+        })
+    }
+
+    /// Uses a self-signed certificate (e.g. from
+    /// [TlsConfig::generate_self_signed]) on both sides, which only trust each
+    /// other if they have the same certificate. This is the simplest way to
+    /// encrypt a container network, where the certificate and key can be
+    /// given to all the containers.
+    pub fn pinned(cert_pem: &[u8], key_pem: &[u8]) -> Result<Self> {
+        Self::mutual(cert_pem, key_pem, cert_pem).stack_err_locationless("TlsConfig::pinned")
+    }
+
+    /// Generates a self-signed certificate for `subject_alt_names` (the host
+    /// names or IP addresses that the listening side is connected to with),
+    /// and returns the PEM encoded certificate and private key for
+    /// [TlsConfig::pinned]
+    pub fn generate_self_signed(subject_alt_names: &[&str]) -> Result<(String, String)> {
+        let names: Vec<String> = subject_alt_names.iter().map(|s| s.to_string()).collect();
+        let certified = rcgen::generate_simple_self_signed(names)
+            .stack_err_locationless("TlsConfig::generate_self_signed")?;
+        Ok((certified.cert.pem(), certified.key_pair.serialize_pem()))
+    }
+
+    /// Sets the name that the certificate of the listening side is checked
+    /// against. By default this is the host given to
+    /// [NetMessenger::connect_tls] without the port.
+    pub fn server_name(mut self, server_name: impl Into<String>) -> Self {
+        self.server_name = Some(server_name.into());
+        self
+    }
+}
+
+/// Returns `host` without the port and IPv6 brackets
+fn host_without_port(host: &str) -> &str {
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => name,
+        _ => host,
+    };
+    name.trim_start_matches('[').trim_end_matches(']')
+}
+
+impl NetMessenger {
+    /// Like [NetMessenger::listen], but the connection uses TLS with `tls`.
+    /// The other side needs to use [NetMessenger::connect_tls]. TLS handshake
+    /// failures (e.g. from a certificate that is not trusted) are reported as
+    /// such, separately from TCP errors and timeouts.
+    pub async fn listen_tls(host: &str, timeout: Duration, tls: &TlsConfig) -> Result<Self> {
+        NetMessengerOptions::new()
+            .listen_tls(host, timeout, tls)
+            .await
+    }
+
+    /// Like [NetMessenger::connect], but the connection uses TLS with `tls`.
+    /// The other side needs to use [NetMessenger::listen_tls].
+    ///
+    /// Note: With TLS 1.3, if the other side does not trust the certificate of
+    /// this side, this is only noticed while exchanging the `NetMessenger`
+    /// handshake after the TLS handshake.
+    pub async fn connect_tls(
+        num_retries: u64,
+        delay: Duration,
+        host: &str,
+        tls: &TlsConfig,
+    ) -> Result<Self> {
+        NetMessengerOptions::new()
+            .connect_tls(num_retries, delay, host, tls)
+            .await
+    }
+}
+
+impl NetMessengerOptions {
+    /// Like [NetMessenger::listen_tls] with these options
+    pub async fn listen_tls(
+        &self,
+        host: &str,
+        timeout: Duration,
+        tls: &TlsConfig,
+    ) -> Result<NetMessenger> {
+        let deadline = Instant::now().checked_add(timeout);
+        let stream = accept_single(host, timeout)
+            .await
+            .stack_err_locationless("NetMessenger::listen_tls")?;
+        let acceptor = TlsAcceptor::from(Arc::clone(&tls.server));
+        let stream = match with_deadline(deadline, acceptor.accept(stream)).await {
+            Some(Ok(stream)) => stream,
+            Some(Err(e)) => {
+                return Err(Error::from_err_locationless(e))
+                    .stack_err_locationless("NetMessenger::listen_tls -> the TLS handshake failed")
+            }
+            None => {
+                return Err(Error::timeout().add_err_locationless(
+                    "NetMessenger::listen_tls -> timed out in the TLS handshake",
+                ))
+            }
+        };
+        NetMessenger::handshake(Stream::Tls(Box::new(stream.into())), deadline, self)
+            .await
+            .stack_err_locationless("NetMessenger::listen_tls")
+    }
+
+    /// Like [NetMessenger::connect_tls] with these options
+    pub async fn connect_tls(
+        &self,
+        num_retries: u64,
+        delay: Duration,
+        host: &str,
+        tls: &TlsConfig,
+    ) -> Result<NetMessenger> {
+        let server_name = tls
+            .server_name
+            .clone()
+            .unwrap_or_else(|| host_without_port(host).to_owned());
+        let server_name = ServerName::try_from(server_name)
+            .stack_err_locationless("NetMessenger::connect_tls -> invalid server name")?;
+        let stream = connect_single(num_retries, delay, host)
+            .await
+            .stack_err_locationless("NetMessenger::connect_tls")?;
+        // the connect timeout covers both handshakes
+        let deadline = self.connect_deadline();
+        let connector = TlsConnector::from(Arc::clone(&tls.client));
+        let stream = match with_deadline(deadline, connector.connect(server_name, stream)).await {
+            Some(Ok(stream)) => stream,
+            Some(Err(e)) => {
+                return Err(Error::from_err_locationless(e))
+                    .stack_err_locationless("NetMessenger::connect_tls -> the TLS handshake failed")
+            }
+            None => {
+                return Err(Error::timeout().add_err_locationless(
+                    "NetMessenger::connect_tls -> timed out in the TLS handshake",
+                ))
+            }
+        };
+        NetMessenger::handshake(Stream::Tls(Box::new(stream.into())), deadline, self)
+            .await
+            .stack_err_locationless("NetMessenger::connect_tls")
+    }
+}
@@ -0,0 +1,111 @@
+use std::path::{Path, PathBuf};
+
+use stacked_errors::{Result, StackableErr};
+use tracing::warn;
+
+use crate::{random_name, FileOptions};
+
+/// A temporary directory that is removed when it is no longer needed, e.g. a
+/// scratch directory of a test. The directory is removed by
+/// [TempWorkspace::close], or on a best-effort basis when this is dropped. It
+/// is kept if [TempWorkspace::keep] was called or if it is dropped while the
+/// thread is panicking (see [TempWorkspace::keep_on_panic]), so that it can
+/// be inspected after a failure.
+#[derive(Debug)]
+pub struct TempWorkspace {
+    path: PathBuf,
+    keep: bool,
+    keep_on_panic: bool,
+    closed: bool,
+}
+
+impl TempWorkspace {
+    /// Creates a new directory in the system temporary directory, with a name
+    /// starting with `prefix` followed by a random suffix
+    pub async fn new(prefix: &str) -> Result<Self> {
+        Self::new_in(std::env::temp_dir(), prefix)
+            .await
+            .stack_err_locationless("TempWorkspace::new")
+    }
+
+    /// Like [TempWorkspace::new], but creates the directory in `directory`
+    /// (which must exist), e.g. "./logs" so that the workspace is next to the
+    /// other outputs
+    pub async fn new_in(directory: impl AsRef<Path>, prefix: &str) -> Result<Self> {
+        let path = directory.as_ref().join(random_name(prefix));
+        tokio::fs::create_dir(&path)
+            .await
+            .stack_err_with_locationless(|| {
+                format!("TempWorkspace::new_in -> could not create the directory {path:?}")
+            })?;
+        Ok(Self {
+            path,
+            keep: false,
+            keep_on_panic: true,
+            closed: false,
+        })
+    }
+
+    /// Returns the path of the directory
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns `FileOptions` for writing to `file_name` in the directory (with
+    /// the [FileOptions::write2] defaults and `create_parents`). Set `options`
+    /// to [ReadOrWrite::Read](crate::ReadOrWrite::Read) for reading.
+    pub fn file(&self, file_name: impl AsRef<Path>) -> FileOptions {
+        FileOptions::write2(&self.path, file_name).create_parents(true)
+    }
+
+    /// Disarms the removal of the directory, so that it is kept after this is
+    /// closed or dropped
+    pub fn keep(&mut self) {
+        self.keep = true;
+    }
+
+    /// Sets if the directory is kept when this is dropped while the thread is
+    /// panicking, this defaults to true
+    pub fn keep_on_panic(mut self, keep_on_panic: bool) -> Self {
+        self.keep_on_panic = keep_on_panic;
+        self
+    }
+
+    /// Removes the directory and everything in it, unless
+    /// [TempWorkspace::keep] was called
+    pub async fn close(mut self) -> Result<()> {
+        self.closed = true;
+        if self.keep {
+            return Ok(())
+        }
+        tokio::fs::remove_dir_all(&self.path)
+            .await
+            .stack_err_with_locationless(|| {
+                format!(
+                    "TempWorkspace::close -> could not remove the directory {:?}",
+                    self.path
+                )
+            })
+    }
+}
+
+impl Drop for TempWorkspace {
+    fn drop(&mut self) {
+        if self.closed || self.keep {
+            return
+        }
+        if self.keep_on_panic && std::thread::panicking() {
+            warn!(
+                "TempWorkspace was dropped while panicking, keeping {:?}",
+                self.path
+            );
+            return
+        }
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            warn!(
+                "TempWorkspace was dropped and could not remove {:?}: {e}",
+                self.path
+            );
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! Checks that `SuperDockerfile::bootstrap_for_target` reuses an up to date
+//! artifact instead of running `cargo build`, this does not need docker to be
+//! running. Run from the repo root.
+
+use std::io::Read;
+
+use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::api_docker::{BootstrapOptions, BuildProfile, Dockerfile, SuperDockerfile};
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+// a target that `cargo build` would fail for
+const TARGET: &str = "super_orchestrator_nonexistent-unknown-none";
+
+async fn bootstrap(force_rebuild: bool) -> Result<SuperDockerfile> {
+    SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .with_force_rebuild(force_rebuild)
+        .bootstrap_for_target(
+            "/entrypoint",
+            ["--flag"],
+            Some(TARGET),
+            BuildProfile::Release,
+            BootstrapOptions::Bin,
+            Vec::<String>::new(),
+        )
+        .await
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt().with_env_filter("debug").init();
+
+    let dir = format!("./target/{TARGET}/release");
+    let artifact = format!("{dir}/bootstrap_cache");
+    std::fs::create_dir_all(&dir).stack()?;
+    // written after the current binary was built
+    std::fs::write(&artifact, "cached artifact").stack()?;
+
+    let (_, tarball) = bootstrap(false)
+        .await
+        .stack()?
+        .into_bollard_args()
+        .await
+        .stack()?;
+    let mut archive = tar::Archive::new(std::io::Cursor::new(tarball));
+    let mut found = false;
+    for entry in archive.entries().stack()? {
+        let mut entry = entry.stack()?;
+        if entry.path().stack()?.ends_with("bootstrap_cache") {
+            let mut content = String::new();
+            entry.read_to_string(&mut content).stack()?;
+            ensure_eq!(content, "cached artifact");
+            found = true;
+        }
+    }
+    ensure!(found);
+
+    // the build is attempted, which fails for the nonexistent target
+    ensure!(bootstrap(true).await.is_err());
+
+    // older artifacts are rebuilt
+    std::fs::File::options()
+        .write(true)
+        .open(&artifact)
+        .stack()?
+        .set_modified(std::time::UNIX_EPOCH)
+        .stack()?;
+    ensure!(bootstrap(false).await.is_err());
+
+    std::fs::remove_dir_all(format!("./target/{TARGET}")).stack()?;
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+//! Checks that build args declared with `SuperDockerfile::with_arg` and
+//! `with_global_arg` influence the built image
+
+use std::str::FromStr;
+
+use stacked_errors::{ensure_eq, Result, StackableErr};
+use super_orchestrator::api_docker::{
+    Dockerfile, ImageBuildOptions, SuperDockerfile, SuperImage, SuperRunOptions,
+};
+use tracing_subscriber::EnvFilter;
+
+async fn build(buildargs: &[(&str, &str)]) -> Result<SuperImage> {
+    let mut builder = ImageBuildOptions::builder();
+    for (key, value) in buildargs {
+        builder = builder.buildarg(key, value);
+    }
+    let image = SuperDockerfile::new(Dockerfile::name_tag("alpine:${ALPINE_VERSION}"), None)
+        .with_global_arg("ALPINE_VERSION", Some("3.21"))?
+        .with_arg("GREETING", Some("hello"))?
+        .append_dockerfile_instructions(["RUN echo $GREETING > /greeting"])
+        .with_build_opts(builder.build().stack()?)
+        .build_image()
+        .await
+        .stack()?;
+    Ok(image)
+}
+
+async fn greeting(image: &SuperImage) -> Result<String> {
+    let completed = image
+        .run_once(SuperRunOptions {
+            cmd: vec!["cat".to_owned(), "/greeting".to_owned()],
+            ..Default::default()
+        })
+        .await
+        .stack()?;
+    completed.assert_success().stack()?;
+    Ok(completed.stdout_as_utf8().stack()?.to_owned())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    // the default is used
+    let image = build(&[]).await.stack()?;
+    let res = greeting(&image).await.stack()?;
+    ensure_eq!(res, "hello\n");
+
+    // the value overrides the default, the undeclared arg only warns
+    let image = build(&[("GREETING", "world"), ("UNDECLARED", "x")])
+        .await
+        .stack()?;
+    let res = greeting(&image).await.stack()?;
+    ensure_eq!(res, "world\n");
+
+    Ok(())
+}
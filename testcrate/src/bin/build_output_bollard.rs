@@ -0,0 +1,62 @@
+//! Checks the log returned by `SuperDockerfile::build_image_with_output` for
+//! successful and failed builds
+
+use std::str::FromStr;
+
+use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::api_docker::{Dockerfile, SuperDockerfile, SuperRunOptions};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let output = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .append_dockerfile_instructions(["RUN echo build_output_marker > /marker"])
+        .build_image_with_output(true)
+        .await
+        .stack()?;
+    ensure!(output
+        .log
+        .contains("RUN echo build_output_marker > /marker"));
+    ensure!(output.context_bytes.is_some());
+    let completed = output
+        .image
+        .run_once(SuperRunOptions {
+            cmd: vec!["cat".to_owned(), "/marker".to_owned()],
+            ..Default::default()
+        })
+        .await
+        .stack()?;
+    ensure_eq!(completed.stdout_as_utf8().stack()?, "build_output_marker\n");
+
+    let output = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .append_dockerfile_instructions(["RUN echo build_output_marker > /marker"])
+        .build_image_with_output(false)
+        .await
+        .stack()?;
+    ensure!(output.context_bytes.is_none());
+
+    // the output before the failure is in the error
+    let err = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .append_dockerfile_instructions(["RUN echo before_the_failure && exit 3"])
+        .build_image_with_output(false)
+        .await
+        .err()
+        .stack_err("the build should have failed")?;
+    let err = format!("{err:?}");
+    ensure!(
+        err.contains("the build log was"),
+        format!("unexpected error: {err}")
+    );
+    ensure!(
+        err.contains("before_the_failure"),
+        format!("unexpected error: {err}")
+    );
+
+    Ok(())
+}
@@ -1,10 +1,21 @@
-use std::{io::Write, iter, time::Duration};
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    iter,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use clap::Parser;
 use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
-use super_orchestrator::{remove_files_in_dir, Command, FileOptions};
+use super_orchestrator::{
+    remove_files_in_dir, sh_with_timeout, split_program_with_args, CapturePolicy, Command,
+    CommandResult, CommandTimeout, FileOptions, LineOverflow, OutputStream, ReadOrWrite,
+    RlimitResource,
+};
 use tokio::time::sleep;
-use tracing::info;
+use tracing::{info, instrument::WithSubscriber, Level};
+use tracing_subscriber::{layer::Context, prelude::*, Layer};
 
 // this program calls itself to get stdout and stderr examples
 #[derive(Parser, Debug)]
@@ -18,6 +29,37 @@ struct Args {
     to_stderr: String,
     #[arg(long)]
     nonutf8: bool,
+    #[arg(long)]
+    terminal_prefix: bool,
+}
+
+type EventFields = BTreeMap<String, String>;
+
+/// Collects the level and fields of the events from `Command` debugging
+#[derive(Clone, Default)]
+struct CommandEvents(Arc<Mutex<Vec<(Level, EventFields)>>>);
+
+impl<S: tracing::Subscriber> Layer<S> for CommandEvents {
+    fn on_event(&self, event: &tracing::Event<'_>, _: Context<'_, S>) {
+        struct Fields(BTreeMap<String, String>);
+        impl tracing::field::Visit for Fields {
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                self.0.insert(field.name().to_owned(), format!("{value:?}"));
+            }
+
+            fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+                self.0.insert(field.name().to_owned(), value.to_owned());
+            }
+        }
+        if event.metadata().target() == "super_orchestrator::command" {
+            let mut fields = Fields(BTreeMap::new());
+            event.record(&mut fields);
+            self.0
+                .lock()
+                .unwrap()
+                .push((*event.metadata().level(), fields.0));
+        }
+    }
 }
 
 #[tokio::main]
@@ -74,6 +116,45 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
+    #[cfg(unix)]
+    if args.terminal_prefix {
+        // two labeled commands writing partial lines at interleaved times
+        let labeled = |label: &str, delay: &str| {
+            Command::new("sh -c")
+                .arg(format!(
+                    "sleep {delay}; printf 'a'; sleep 0.2; printf 'b\\nc'; sleep 0.2; printf \
+                     'd\\n'; sleep 0.2; printf 'e'"
+                ))
+                .debug(true)
+                .raw_debug(true)
+                .terminal_prefix(label)
+                .stdout_log(Some(FileOptions::write(format!("./logs/{label}.log"))))
+                .run_to_completion()
+        };
+        let (one, two) = tokio::join!(labeled("one", "0"), labeled("two", "0.1"));
+        one.stack()?.assert_success().stack()?;
+        two.stack()?.assert_success().stack()?;
+        // the logs are not prefixed
+        for label in ["one", "two"] {
+            let log = FileOptions::read_to_string(format!("./logs/{label}.log"))
+                .await
+                .stack()?;
+            ensure_eq!(log, "ab\ncd\ne");
+        }
+        // without a label, the output is forwarded as it is read
+        Command::new("sh -c")
+            .arg("printf '1\\r2\\n3'")
+            .debug(true)
+            .raw_debug(true)
+            .stdout_debug_line_prefix(Some("raw| ".to_owned()))
+            .run_to_completion()
+            .await
+            .stack()?
+            .assert_success()
+            .stack()?;
+        return Ok(());
+    }
+
     remove_files_in_dir("./logs/", &["stdout.log", "stderr.log"])
         .await
         .stack()?;
@@ -136,6 +217,162 @@ async fn main() -> Result<()> {
     ensure!(file.len() <= 10 * 1024);
     ensure!(!file.chars().any(|c| c != 'e'));
 
+    // the capture policy decides which bytes are kept, and the dropped bytes are
+    // counted
+    if !cfg!(windows) {
+        for (policy, stdout) in [
+            (CapturePolicy::TruncateHead, "ghij"),
+            (CapturePolicy::TruncateTail, "0123"),
+            (CapturePolicy::Discard, ""),
+        ] {
+            let comres = Command::new("sh")
+                .args([
+                    "-c",
+                    "printf 0123456789; sleep 0.1; printf abcdefghij; exit 1",
+                ])
+                .max_capture(4, policy)
+                .run_to_completion()
+                .await
+                .stack()?;
+            ensure_eq!(comres.stdout_as_utf8().stack()?, stdout);
+            ensure_eq!(comres.stdout_dropped_bytes, 20 - stdout.len() as u64);
+            ensure_eq!(comres.stderr_dropped_bytes, 0);
+            let e = comres.assert_success().unwrap_err();
+            ensure!(format!("{e:?}").contains("bytes of the stdout were dropped"));
+        }
+    }
+
+    // the start and finish times are recorded
+    if !cfg!(windows) {
+        let comres = Command::new("sleep 0.3")
+            .run_to_completion()
+            .await
+            .stack()?;
+        comres.assert_success().stack()?;
+        ensure!(comres.duration >= Duration::from_millis(300));
+        let started_at = comres.started_at.stack()?;
+        let finished_at = comres.finished_at.stack()?;
+        ensure!(finished_at.duration_since(started_at).stack()? >= Duration::from_millis(300));
+        ensure!(format!("{comres:?}").contains("duration: "));
+    }
+
+    // `assert_success` errors have excerpts of the output
+    if !cfg!(windows) {
+        let command = Command::new("sh").args([
+            "-c",
+            "i=1; while [ $i -le 30 ]; do echo out$i; echo err$i >&2; i=$((i+1)); done; exit 2",
+        ]);
+        let comres = command.clone().run_to_completion().await.stack()?;
+        let e = format!("{:?}", comres.assert_success().unwrap_err());
+        ensure!(e.contains("exit status: 2"));
+        ensure!(e.contains("the last 20 lines of the stderr:"));
+        ensure!(e.contains("err30") && e.contains("out11") && e.contains("out30"));
+        ensure!(!e.contains("out10"));
+        let e = format!("{:?}", comres.no_debug().assert_success().unwrap_err());
+        ensure!(e.contains("out11") && !e.contains("out10"));
+
+        let comres = command
+            .error_excerpt_lines(0)
+            .run_to_completion()
+            .await
+            .stack()?;
+        let e = format!("{:?}", comres.assert_success().unwrap_err());
+        ensure!(e.contains("out10") && !e.contains("the last"));
+    }
+
+    // arguments with spaces in argv mode and shell mode
+    if !cfg!(windows) {
+        let comres = Command::new("printf [%s]")
+            .arg("a b")
+            .run_to_completion()
+            .await
+            .stack()?;
+        comres.assert_success().stack()?;
+        ensure_eq!(comres.stdout_as_utf8().stack()?, "[a b]");
+        let comres = Command::shell("printf '[%s]' 'a b' | tr a-z A-Z && echo")
+            .run_to_completion()
+            .await
+            .stack()?;
+        comres.assert_success().stack()?;
+        ensure_eq!(comres.stdout_as_utf8().stack()?, "[A B]\n");
+
+        // shell syntax is rejected by the whitespace splitting constructor
+        let e = Command::new("echo a | cat")
+            .run_to_completion()
+            .await
+            .unwrap_err();
+        ensure!(format!("{e:?}").contains("Command::shell"));
+        ensure!(Command::new("ls ./ > out.txt").run().await.is_err());
+        ensure!(Command::new("true && true").run().await.is_err());
+        // but not if it is quoted or passed as an argument
+        Command::new("echo 'a|b'")
+            .run_to_completion()
+            .await
+            .stack()?
+            .assert_success()
+            .stack()?;
+        Command::new("echo")
+            .arg("|")
+            .run_to_completion()
+            .await
+            .stack()?
+            .assert_success()
+            .stack()?;
+    }
+
+    // the splitting of `Command::new`, `None` is for inputs that cannot be split
+    let cases: &[(&str, Option<&[&str]>)] = &[
+        ("", Some(&[])),
+        ("  a \t b\n", Some(&["a", "b"])),
+        (
+            r#"docker run --label "a b" img"#,
+            Some(&["docker", "run", "--label", "a b", "img"]),
+        ),
+        (r#"a --label="x y"z"#, Some(&["a", "--label=x yz"])),
+        (r#"a "" '' b"#, Some(&["a", "", "", "b"])),
+        (r#"a ""''"#, Some(&["a", ""])),
+        (
+            r#"a "it's" 'say "hi"'"#,
+            Some(&["a", "it's", r#"say "hi""#]),
+        ),
+        (r#"a "\"\\\$x""#, Some(&["a", r#""\\$x"#])),
+        (r"a 'b\c' \'d\ e", Some(&["a", r"b\c", "'d e"])),
+        (r"a 'b\'", Some(&["a", r"b\"])),
+        ("a \\\n b \"c\\\nd\"", Some(&["a", "b", "cd"])),
+        ("a $HOME ~ *", Some(&["a", "$HOME", "~", "*"])),
+        (r"a b\", None),
+        (r#"a "b"#, None),
+        (r#"a "b\""#, None),
+        ("a 'b", None),
+    ];
+    for (input, expected) in cases {
+        let command = Command::new(input);
+        if let Some(expected) = *expected {
+            ensure_eq!(split_program_with_args(input).stack()?, expected);
+            ensure!(command.shell_syntax_error.is_none());
+            ensure_eq!(
+                command.program,
+                expected.first().copied().unwrap_or_default()
+            );
+            ensure_eq!(command.args, expected.get(1..).unwrap_or_default());
+        } else {
+            ensure!(
+                split_program_with_args(input).is_err(),
+                format!("input: {input:?}")
+            );
+            ensure!(command.shell_syntax_error.is_some());
+        }
+    }
+    // escaped or quoted operators are not shell syntax
+    ensure!(Command::new(r"a \| b").shell_syntax_error.is_none());
+    ensure!(Command::new(r#"a "\"|" b"#).shell_syntax_error.is_none());
+    ensure!(Command::new(r#"a "\\"|b"#).shell_syntax_error.is_some());
+    // the raw constructor only splits on whitespace
+    let command = Command::new_raw(r#"a "b c" d\ e"#);
+    ensure_eq!(command.program, "a");
+    ensure_eq!(command.args, [r#""b"#, r#"c""#, r"d\", "e"]);
+    ensure!(command.shell_syntax_error.is_none());
+
     // disable recording entirely if we don't need it
     let comres = Command::new("cargo r --bin commands --quiet -- --print")
         .arg("--to-stdout")
@@ -162,7 +399,826 @@ async fn main() -> Result<()> {
     ensure!(comres.stderr_as_utf8().is_err());
     dbg!(comres.stdout_as_utf8_lossy());
     dbg!(comres.stderr_as_utf8_lossy());
-    dbg!(comres).assert_success().stack()?;
+    let comres = dbg!(comres);
+    comres.assert_success().stack()?;
+
+    // the JSON form round trips the exact bytes
+    comres
+        .to_json_file(FileOptions::write("./logs/command_result.json"))
+        .await
+        .stack()?;
+    let json = FileOptions::read_to_string("./logs/command_result.json")
+        .await
+        .stack()?;
+    ensure!(json.contains("\"lossy\""));
+    let deserialized = CommandResult::from_json_file("./logs/command_result.json")
+        .await
+        .stack()?;
+    ensure!(deserialized.stdout == comres.stdout);
+    ensure!(deserialized.stderr == comres.stderr);
+    ensure!(deserialized.status == comres.status);
+    ensure!(deserialized.duration == comres.duration);
+    ensure!(deserialized.started_at == comres.started_at);
+    ensure!(deserialized.command.program == comres.command.program);
+    ensure!(deserialized.command.args == comres.command.args);
+
+    // failed attempts are retried, and the errors of every attempt are kept
+    #[cfg(unix)]
+    {
+        let _ = std::fs::remove_file("./logs/retry_count");
+        let comres = Command::shell(
+            "n=$(cat ./logs/retry_count 2>/dev/null || echo 0); n=$((n + 1)); echo $n > \
+             ./logs/retry_count; [ $n -ge 3 ]",
+        )
+        .retries(3)
+        .retry_backoff(Duration::from_millis(10), 2.0, Duration::from_millis(50))
+        .run_to_completion()
+        .await
+        .stack()?;
+        comres.assert_success().stack()?;
+        ensure_eq!(
+            FileOptions::read_to_string("./logs/retry_count")
+                .await
+                .stack()?
+                .trim(),
+            "3"
+        );
+
+        let e = Command::shell("echo failing >&2; exit 1")
+            .retries(2)
+            .retry_backoff(Duration::ZERO, 2.0, Duration::ZERO)
+            .run_to_completion()
+            .await
+            .unwrap_err();
+        let e = format!("{e:?}");
+        ensure!(e.contains("attempt 1 of 3") && e.contains("attempt 3 of 3"));
+        ensure!(e.contains("failing"));
+
+        // only the attempts `retry_if` accepts are retried
+        let e = Command::shell("exit 3")
+            .retries(5)
+            .retry_backoff(Duration::ZERO, 2.0, Duration::ZERO)
+            .retry_if(|comres| comres.status.and_then(|status| status.code()) == Some(2))
+            .run_to_completion()
+            .await
+            .unwrap_err();
+        let e = format!("{e:?}");
+        ensure!(e.contains("attempt 1 of 6") && !e.contains("attempt 2 of 6"));
+
+        // spawn failures are retried
+        let e = Command::new("nonexistent_program_for_retries")
+            .retries(1)
+            .retry_backoff(Duration::ZERO, 2.0, Duration::ZERO)
+            .run_to_completion()
+            .await
+            .unwrap_err();
+        ensure!(format!("{e:?}").contains("attempt 2 of 2"));
+    }
+
+    // the working directory is checked when running, and the canonicalized
+    // directory is recorded
+    if !cfg!(windows) {
+        let comres = Command::new("pwd")
+            .cwd("./testcrate/src")
+            .run_to_completion()
+            .await
+            .stack()?;
+        comres.assert_success().stack()?;
+        let cwd = std::fs::canonicalize("./testcrate/src").stack()?;
+        ensure_eq!(
+            comres.stdout_as_utf8().stack()?.trim(),
+            cwd.to_str().stack()?
+        );
+        ensure!(comres.command.cwd.as_ref() == Some(&cwd));
+    }
+    // running as another user needs privileges, the environment is inherited, and
+    // the working directory is entered as the user
+    #[cfg(unix)]
+    {
+        let uid = Command::new("id -u").run_to_completion().await.stack()?;
+        if uid.stdout_as_utf8().stack()?.trim() == "0" {
+            let comres = Command::shell("id -u; id -g; echo $HOME; pwd")
+                .uid(65534)
+                .gid(65534)
+                .env("HOME", "/tmp")
+                .cwd("/tmp")
+                .run_to_completion()
+                .await
+                .stack()?;
+            comres.assert_success().stack()?;
+            ensure_eq!(
+                comres.stdout_as_utf8().stack()?,
+                "65534\n65534\n/tmp\n/tmp\n"
+            );
+
+            std::fs::create_dir_all("./logs/private_cwd").stack()?;
+            std::fs::set_permissions(
+                "./logs/private_cwd",
+                std::os::unix::fs::PermissionsExt::from_mode(0o700),
+            )
+            .stack()?;
+            let err = Command::new("true")
+                .uid(65534)
+                .gid(65534)
+                .cwd("./logs/private_cwd")
+                .run_to_completion()
+                .await
+                .err()
+                .stack_err("the working directory should not be accessible")?;
+            ensure!(format!("{err:?}").contains("must be accessible"));
+        } else {
+            let err = Command::new("true")
+                .uid(0)
+                .run_to_completion()
+                .await
+                .err()
+                .stack_err("changing the user should need privileges")?;
+            ensure!(format!("{err:?}").contains("requires privileges"));
+        }
+    }
+
+    // the niceness applies to the process, and lowering it needs privileges
+    #[cfg(target_os = "linux")]
+    {
+        // the 19th field of `/proc/[pid]/stat`, after the parenthesized name
+        fn niceness(stat: &str) -> Result<i32> {
+            let (_, fields) = stat.rsplit_once(')').stack()?;
+            fields.split_whitespace().nth(16).stack()?.parse().stack()
+        }
+
+        let own = niceness(&std::fs::read_to_string("/proc/self/stat").stack()?).stack()?;
+        let nice = (own + 5).min(19);
+        let comres = Command::new("cat /proc/self/stat")
+            .nice(nice)
+            .run_to_completion()
+            .await
+            .stack()?;
+        comres.assert_success().stack()?;
+        ensure_eq!(niceness(comres.stdout_as_utf8().stack()?).stack()?, nice);
+        ensure!(format!("{:?}", comres.command).contains(&format!("nice: {nice},")));
+
+        let uid = Command::new("id -u").run_to_completion().await.stack()?;
+        let res = Command::new("cat /proc/self/stat")
+            .nice(own - 1)
+            .run_to_completion()
+            .await;
+        if uid.stdout_as_utf8().stack()?.trim() == "0" {
+            let comres = res.stack()?;
+            ensure_eq!(niceness(comres.stdout_as_utf8().stack()?).stack()?, own - 1);
+        } else {
+            let err = res
+                .err()
+                .stack_err("lowering the niceness should need privileges")?;
+            ensure!(format!("{err:?}").contains("requires privileges"));
+        }
+    }
+
+    // resource limits apply to the process, a tiny file descriptor limit makes
+    // opening files fail
+    #[cfg(target_os = "linux")]
+    {
+        let comres = Command::shell("ulimit -Sn; ulimit -Hn")
+            .rlimit(RlimitResource::NoFile, 16, 32)
+            .run_to_completion()
+            .await
+            .stack()?;
+        comres.assert_success().stack()?;
+        ensure_eq!(comres.stdout_as_utf8().stack()?, "16\n32\n");
+        ensure!(format!("{:?}", comres.command).contains("rlimits: [(NoFile, 16, 32)]"));
+
+        let open_files = "exec 3</dev/null 4</dev/null 5</dev/null 6</dev/null 7</dev/null";
+        Command::shell(open_files)
+            .rlimit(RlimitResource::NoFile, 16, 16)
+            .run_to_completion()
+            .await
+            .stack()?
+            .assert_success()
+            .stack()?;
+        let comres = Command::shell(open_files)
+            .rlimit(RlimitResource::NoFile, 5, 5)
+            .run_to_completion()
+            .await
+            .stack()?;
+        ensure!(!comres.successful());
+        ensure!(!comres.stderr.is_empty());
+    }
+
+    let err = Command::new("ls")
+        .cwd("./testcrate/nonexistent_cwd")
+        .run_to_completion()
+        .await
+        .err()
+        .stack_err("running in a nonexistent directory should fail")?;
+    ensure!(format!("{err:?}").contains("nonexistent_cwd"));
+
+    // a timeout terminates the command and the error has the partial output
+    if !cfg!(windows) {
+        let start = std::time::Instant::now();
+        let err = Command::new("sh")
+            .args(["-c", "echo partial_output && exec sleep 10"])
+            .record_combined(true)
+            .timeout(Duration::from_millis(500))
+            .run_to_completion()
+            .await
+            .err()
+            .stack_err("the command should have timed out")?;
+        ensure!(start.elapsed() < Duration::from_secs(5));
+        ensure!(err.is_timeout());
+        let command_timeout = CommandTimeout::from_error(&err).stack()?;
+        ensure_eq!(command_timeout.duration, Duration::from_millis(500));
+        ensure_eq!(
+            command_timeout.result.stdout_as_utf8_lossy(),
+            "partial_output\n"
+        );
+        ensure_eq!(
+            command_timeout.result.combined_as_utf8_lossy(),
+            "partial_output\n"
+        );
+        let err = format!("{err:?}");
+        ensure!(
+            err.contains("partial_output"),
+            format!("unexpected error: {err}")
+        );
+        ensure!(
+            err.contains("timed out"),
+            format!("unexpected error: {err}")
+        );
+
+        // the timeout also applies while writing input that is not read
+        let start = std::time::Instant::now();
+        let err = Command::new("sleep")
+            .arg("10")
+            .timeout(Duration::from_millis(500))
+            .run_with_input_to_completion(&vec![0u8; 16 << 20])
+            .await
+            .err()
+            .stack_err("the command should have timed out")?;
+        ensure!(start.elapsed() < Duration::from_secs(5));
+        ensure!(err.is_timeout());
+
+        let err = sh_with_timeout(["sleep 10"], Duration::from_millis(100))
+            .await
+            .err()
+            .stack_err("sh_with_timeout should have timed out")?;
+        ensure!(format!("{err:?}").contains("timed out"));
+        ensure_eq!(
+            sh_with_timeout(["echo in_time"], Duration::from_secs(10))
+                .await
+                .stack()?,
+            "in_time\n"
+        );
+    }
+
+    // graceful termination lets the command handle SIGTERM, and falls back to
+    // killing
+    if !cfg!(windows) {
+        let mut runner = Command::new("sh")
+            .args([
+                "-c",
+                "trap 'echo got_sigterm; exit 0' TERM; echo ready; while true; do sleep 0.1; done",
+            ])
+            .run()
+            .await
+            .stack()?;
+        while runner.stdout_record.lock().await.is_empty() {
+            sleep(Duration::from_millis(10)).await;
+        }
+        runner
+            .terminate_graceful(Duration::from_secs(5))
+            .await
+            .stack()?;
+        let comres = runner.take_command_result().stack()?;
+        ensure!(comres.terminated_gracefully);
+        ensure!(comres.stdout_as_utf8().stack()?.contains("got_sigterm"));
+
+        let mut runner = Command::new("sh")
+            .args([
+                "-c",
+                "trap '' TERM; echo ready; while true; do sleep 0.1; done",
+            ])
+            .run()
+            .await
+            .stack()?;
+        while runner.stdout_record.lock().await.is_empty() {
+            sleep(Duration::from_millis(10)).await;
+        }
+        runner
+            .terminate_graceful(Duration::from_millis(300))
+            .await
+            .stack()?;
+        let comres = runner.take_command_result().stack()?;
+        ensure!(!comres.terminated_gracefully);
+        ensure!(comres.status.is_none());
+    }
+
+    // inherited standard streams are not captured, but the status and timing are
+    // still recorded
+    #[cfg(target_os = "linux")]
+    {
+        let comres = Command::shell(
+            "link=$(readlink /proc/$$/fd/1); echo $link > ./logs/inherited_stdout; exit 3",
+        )
+        .inherit_stdio(true)
+        .run_to_completion()
+        .await
+        .stack()?;
+        ensure!(comres.stdio_inherited());
+        ensure!(comres.stdout.is_empty() && comres.stderr.is_empty());
+        ensure_eq!(comres.status.and_then(|status| status.code()), Some(3));
+        ensure!(comres.finished_at.is_some());
+        ensure!(format!("{:?}", comres.assert_success().unwrap_err()).contains("inherited"));
+        ensure_eq!(
+            FileOptions::read_to_string("./logs/inherited_stdout")
+                .await
+                .stack()?
+                .trim(),
+            std::fs::read_link("/proc/self/fd/1")
+                .stack()?
+                .to_str()
+                .stack()?
+        );
+
+        let err = Command::new("true")
+            .inherit_stdio(true)
+            .stdout_log(Some(FileOptions::write("./logs/inherited.log")))
+            .run_to_completion()
+            .await
+            .err()
+            .stack_err("logs should be rejected with `inherit_stdio`")?;
+        ensure!(format!("{err:?}").contains("cannot be used with `inherit_stdio`"));
+    }
+
+    // the combined output keeps the order that the two streams were written in
+    #[cfg(unix)]
+    {
+        let comres = Command::shell("echo a; sleep 0.1; echo b >&2; sleep 0.1; echo c")
+            .record_combined(true)
+            .combined_log(Some(FileOptions::write("./logs/combined.log")))
+            .run_to_completion()
+            .await
+            .stack()?;
+        comres.assert_success().stack()?;
+        ensure_eq!(comres.combined_as_utf8_lossy(), "a\nb\nc\n");
+        ensure_eq!(
+            comres
+                .combined_chunks
+                .iter()
+                .map(|chunk| chunk.stream)
+                .collect::<Vec<_>>(),
+            [
+                OutputStream::Stdout,
+                OutputStream::Stderr,
+                OutputStream::Stdout
+            ]
+        );
+        ensure!(comres
+            .combined_chunks
+            .windows(2)
+            .all(|chunks| chunks[0].time <= chunks[1].time));
+        // the separate records are unchanged
+        ensure_eq!(comres.stdout_as_utf8().stack()?, "a\nc\n");
+        ensure_eq!(comres.stderr_as_utf8().stack()?, "b\n");
+        ensure_eq!(
+            FileOptions::read_to_string("./logs/combined.log")
+                .await
+                .stack()?,
+            "a\nb\nc\n"
+        );
+        let roundtrip: CommandResult =
+            serde_json::from_str(&serde_json::to_string(&comres).stack()?).stack()?;
+        ensure_eq!(roundtrip.combined_chunks, comres.combined_chunks);
+    }
+
+    // waiting for a line, with or without a log
+    #[cfg(unix)]
+    {
+        for log in [false, true] {
+            let mut command = Command::shell(
+                "echo starting; sleep 0.2; echo 'listening on 8080'; exec sleep 100",
+            )
+            .watch_lines(true);
+            if log {
+                command = command.stdout_log(Some(FileOptions::write("./logs/wait_for_line.log")));
+            }
+            let mut runner = command.run().await.stack()?;
+            let line = runner
+                .wait_for_line(OutputStream::Stdout, "listening", Duration::from_secs(10))
+                .await
+                .stack()?;
+            ensure_eq!(line, "listening on 8080");
+            // a line is only matched once, the error has the last lines
+            let e = runner
+                .wait_for_line(
+                    OutputStream::Stdout,
+                    "listening",
+                    Duration::from_millis(100),
+                )
+                .await
+                .unwrap_err();
+            let e = format!("{e:?}");
+            ensure!(e.contains("timed out") && e.contains("listening on 8080"));
+            runner.terminate().await.stack()?;
+        }
+
+        // lines from before waiting and custom matching
+        let mut runner = Command::shell("echo 'ready 1' >&2; exec sleep 100")
+            .watch_lines(true)
+            .run()
+            .await
+            .stack()?;
+        sleep(Duration::from_millis(200)).await;
+        let line = runner
+            .wait_for_line_matching(
+                OutputStream::Stderr,
+                |line| line.starts_with("ready"),
+                Duration::from_secs(10),
+            )
+            .await
+            .stack()?;
+        ensure_eq!(line, "ready 1");
+        runner.terminate().await.stack()?;
+
+        // the kept lines follow the `record_limit` and `capture_policy`
+        for (policy, first) in [
+            (CapturePolicy::TruncateHead, "bb"),
+            (CapturePolicy::Discard, "cc"),
+        ] {
+            let mut runner = Command::shell("echo aa; echo bb; echo cc; exec sleep 100")
+                .watch_lines(true)
+                .max_capture(4, policy)
+                .run()
+                .await
+                .stack()?;
+            sleep(Duration::from_millis(200)).await;
+            let line = runner
+                .wait_for_line_matching(OutputStream::Stdout, |_| true, Duration::from_secs(10))
+                .await
+                .stack()?;
+            ensure_eq!(line, first);
+            runner.terminate().await.stack()?;
+        }
+
+        // the lines are only kept if `watch_lines` is set
+        let mut runner = Command::shell("echo a").run().await.stack()?;
+        let e = runner
+            .wait_for_line(OutputStream::Stdout, "a", Duration::from_secs(10))
+            .await
+            .unwrap_err();
+        ensure!(format!("{e:?}").contains("set `Command::watch_lines`"));
+        runner
+            .wait_with_output()
+            .await
+            .stack()?
+            .assert_success()
+            .stack()?;
+
+        // exiting first fails right away with the result
+        let mut runner = Command::shell("echo a; exit 2")
+            .watch_lines(true)
+            .run()
+            .await
+            .stack()?;
+        let e = runner
+            .wait_for_line(OutputStream::Stdout, "never", Duration::from_secs(100))
+            .await
+            .unwrap_err();
+        ensure!(format!("{e:?}").contains("exited without a matching line"));
+        let comres = runner.take_command_result().stack()?;
+        ensure_eq!(comres.status.and_then(|status| status.code()), Some(2));
+        ensure_eq!(comres.stdout_as_utf8().stack()?, "a\n");
+    }
+
+    // clones of a configured command can run concurrently with a shared log
+    #[cfg(unix)]
+    {
+        remove_files_in_dir("./logs/", &["template.log"])
+            .await
+            .stack()?;
+        let template =
+            Command::new("sh -c")
+                .env("PREFIX", "item")
+                .stdout_log(Some(FileOptions::new(
+                    "./logs/template.log",
+                    ReadOrWrite::write(true, true),
+                )));
+        let runners = (0..4).map(|i| {
+            template
+                .clone()
+                .arg(format!("sleep 0.1; echo ${{PREFIX}}{i}"))
+                .run_to_completion()
+        });
+        for (i, comres) in futures::future::join_all(runners)
+            .await
+            .into_iter()
+            .enumerate()
+        {
+            let comres = comres.stack()?;
+            comres.assert_success().stack()?;
+            ensure_eq!(comres.stdout_as_utf8().stack()?, format!("item{i}\n"));
+        }
+        ensure_eq!(template.args.len(), 1);
+        let log = FileOptions::read_to_string("./logs/template.log")
+            .await
+            .stack()?;
+        let mut lines: Vec<&str> = log.lines().collect();
+        lines.sort_unstable();
+        ensure_eq!(lines, ["item0", "item1", "item2", "item3"]);
+    }
+
+    // binary output goes through the records and logs unchanged
+    #[cfg(unix)]
+    {
+        // xorshift, so that every byte value appears including invalid UTF-8
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let input: Vec<u8> = iter::repeat_with(|| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state.to_le_bytes()
+        })
+        .take(1 << 17)
+        .flatten()
+        .collect();
+        let comres = Command::new("cat")
+            .binary(true)
+            .stdout_log(Some(FileOptions::write("./logs/binary.log")))
+            .record_combined(true)
+            .run_with_input_to_completion(&input)
+            .await
+            .stack()?;
+        comres.assert_success().stack()?;
+        ensure!(comres.stdout_bytes() == input);
+        ensure!(comres.stderr_bytes().is_empty());
+        ensure!(comres.combined() == input);
+        let log = std::fs::read("./logs/binary.log").stack()?;
+        ensure!(log == input);
+        ensure!(format!("{comres:?}").contains(&format!("stdout: {} bytes,", input.len())));
+
+        // line processing cannot be used
+        let res = Command::new("cat")
+            .binary(true)
+            .debug(true)
+            .run_with_input_to_completion(&input)
+            .await;
+        ensure!(format!("{:?}", res.unwrap_err()).contains("cannot be used with `binary`"));
+        let res = Command::new("cat /dev/null")
+            .binary(true)
+            .watch_lines(true)
+            .run()
+            .await;
+        ensure!(format!("{:?}", res.unwrap_err()).contains("cannot be used with `binary`"));
+    }
+
+    // a cancelled runner kills its process on drop unless `kill_on_drop` is false
+    #[cfg(target_os = "linux")]
+    {
+        // if the process no longer exists or is a zombie
+        fn is_gone(pid: u32) -> bool {
+            match std::fs::read_to_string(format!("/proc/{pid}/stat")) {
+                Ok(stat) => stat.contains(") Z "),
+                Err(_) => true,
+            }
+        }
+
+        for kill_on_drop in [true, false] {
+            let pid = Arc::new(Mutex::new(None));
+            let pid1 = Arc::clone(&pid);
+            let res = tokio::time::timeout(Duration::from_millis(300), async move {
+                let runner = Command::new("sleep 100")
+                    .kill_on_drop(kill_on_drop)
+                    .run()
+                    .await
+                    .stack()?;
+                *pid1.lock().unwrap() = runner.pid();
+                runner.wait_with_output().await
+            })
+            .await;
+            ensure!(res.is_err());
+            let pid = pid.lock().unwrap().stack()?;
+            if kill_on_drop {
+                let mut i = 0;
+                while !is_gone(pid) {
+                    ensure!(i < 100, "the process was not killed on drop");
+                    i += 1;
+                    sleep(Duration::from_millis(10)).await;
+                }
+            } else {
+                ensure!(!is_gone(pid), "the process should still be running");
+                Command::new("kill -s KILL")
+                    .arg(pid.to_string())
+                    .run_to_completion()
+                    .await
+                    .stack()?
+                    .assert_success()
+                    .stack()?;
+            }
+        }
+    }
+
+    // detached commands run independently and write directly to their logs
+    #[cfg(unix)]
+    {
+        let detached = Command::shell("echo started; exec sleep 100")
+            .stdout_log(Some(FileOptions::write("./logs/detached.log")))
+            .spawn_detached()
+            .await
+            .stack()?;
+        let mut i = 0;
+        while !FileOptions::read_to_string("./logs/detached.log")
+            .await
+            .stack()?
+            .contains("started")
+        {
+            ensure!(i < 500, "the detached command did not write to its log");
+            i += 1;
+            sleep(Duration::from_millis(10)).await;
+        }
+        ensure!(detached.is_running().await.stack()?);
+        Command::new("kill -s KILL")
+            .arg(detached.pid.to_string())
+            .run_to_completion()
+            .await
+            .stack()?
+            .assert_success()
+            .stack()?;
+        let mut i = 0;
+        while detached.is_running().await.stack()? {
+            ensure!(i < 500, "the detached command is still running");
+            i += 1;
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    // terminating also kills the background processes started by the command
+    #[cfg(target_os = "linux")]
+    {
+        // if the process no longer exists or is a zombie
+        async fn is_gone(pid: &str) -> bool {
+            for _ in 0..100 {
+                match std::fs::read_to_string(format!("/proc/{pid}/stat")) {
+                    Ok(stat) if !stat.contains(") Z ") => (),
+                    _ => return true,
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+            false
+        }
+
+        for graceful in [false, true] {
+            let mut runner = Command::shell("sleep 100 & echo $!; sleep 100")
+                .run()
+                .await
+                .stack()?;
+            while !runner.stdout_record.lock().await.contains(&b'\n') {
+                sleep(Duration::from_millis(10)).await;
+            }
+            let background =
+                String::from_utf8(runner.stdout_record.lock().await.iter().copied().collect())
+                    .stack()?
+                    .trim()
+                    .to_owned();
+            ensure!(!is_gone(&background).await);
+            if graceful {
+                runner
+                    .terminate_graceful(Duration::from_secs(5))
+                    .await
+                    .stack()?;
+            } else {
+                runner.terminate().await.stack()?;
+            }
+            ensure!(is_gone(&background).await);
+        }
+
+        // only the direct child is killed without `kill_descendants`
+        let mut runner = Command::shell("sleep 100 & echo $!; sleep 100")
+            .kill_descendants(false)
+            .run()
+            .await
+            .stack()?;
+        while !runner.stdout_record.lock().await.contains(&b'\n') {
+            sleep(Duration::from_millis(10)).await;
+        }
+        let background =
+            String::from_utf8(runner.stdout_record.lock().await.iter().copied().collect())
+                .stack()?
+                .trim()
+                .to_owned();
+        runner.terminate().await.stack()?;
+        sleep(Duration::from_millis(100)).await;
+        ensure!(std::fs::metadata(format!("/proc/{background}")).is_ok());
+        Command::new("kill -s KILL")
+            .arg(&background)
+            .run_to_completion()
+            .await
+            .stack()?
+            .assert_success()
+            .stack()?;
+    }
+
+    // line callbacks get complete lines, with CRLF and a final partial line
+    if !cfg!(windows) {
+        let stdout_lines = Arc::new(Mutex::new(vec![]));
+        let stderr_lines = Arc::new(Mutex::new(vec![]));
+        let stdout_lines_clone = stdout_lines.clone();
+        let stderr_lines_clone = stderr_lines.clone();
+        let comres = Command::new("sh")
+            .args([
+                "-c",
+                "printf 'a\\r\\nb'; sleep 0.1; printf 'c\\n\\nd'; printf 'e\\n' >&2",
+            ])
+            .on_stdout_line(move |line| stdout_lines_clone.lock().unwrap().push(line.to_owned()))
+            .on_stderr_line(move |line| stderr_lines_clone.lock().unwrap().push(line.to_owned()))
+            .run_to_completion()
+            .await
+            .stack()?;
+        comres.assert_success().stack()?;
+        ensure_eq!(comres.stdout_as_utf8().stack()?, "a\r\nbc\n\nd");
+        let stdout_lines = stdout_lines.lock().unwrap().clone();
+        let stderr_lines = stderr_lines.lock().unwrap().clone();
+        ensure_eq!(stdout_lines, ["a", "bc", "", "d"]);
+        ensure_eq!(stderr_lines, ["e"]);
+
+        // a panicking callback is surfaced at wait time
+        let res = Command::new("sh")
+            .args(["-c", "echo a; echo b"])
+            .on_stdout_line(|line| {
+                if line == "a" {
+                    panic!("callback panic")
+                }
+            })
+            .run_to_completion()
+            .await;
+        let e = res.err().stack()?;
+        ensure!(format!("{e:?}").contains("callback panic"));
+    }
+
+    // line channels deliver lines in order and close when the streams end
+    if !cfg!(windows) {
+        let expected: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+        let mut runner = Command::new("sh")
+            .args([
+                "-c",
+                "i=0; while [ $i -lt 100 ]; do echo $i; i=$((i+1)); done; echo e >&2",
+            ])
+            .line_channels(4, LineOverflow::Block)
+            .run()
+            .await
+            .stack()?;
+        let mut stdout_lines = runner.stdout_lines().stack()?;
+        let mut stderr_lines = runner.stderr_lines().stack()?;
+        ensure!(runner.stdout_lines().is_none());
+        let mut lines = vec![];
+        while let Some(line) = stdout_lines.recv().await {
+            lines.push(line);
+        }
+        ensure_eq!(lines, expected);
+        ensure!(stderr_lines.recv().await.as_deref() == Some("e"));
+        ensure!(stderr_lines.recv().await.is_none());
+        runner
+            .wait_with_output()
+            .await
+            .stack()?
+            .assert_success()
+            .stack()?;
+
+        // a slow consumer only gets the newest lines
+        let mut runner = Command::new("sh")
+            .args([
+                "-c",
+                "i=0; while [ $i -lt 100 ]; do echo $i; i=$((i+1)); done",
+            ])
+            .line_channels(4, LineOverflow::DropOldest)
+            .run()
+            .await
+            .stack()?;
+        let mut stdout_lines = runner.stdout_lines().stack()?;
+        runner
+            .wait_with_output()
+            .await
+            .stack()?
+            .assert_success()
+            .stack()?;
+        let mut lines = vec![];
+        while let Some(line) = stdout_lines.recv().await {
+            lines.push(line);
+        }
+        ensure!((1..=5).contains(&lines.len()));
+        ensure!(expected.ends_with(&lines[1..]));
+        ensure_eq!(lines.last().map(String::as_str), Some("99"));
+
+        // untaken blocking channels do not hold up the command
+        Command::new("sh")
+            .args([
+                "-c",
+                "i=0; while [ $i -lt 100 ]; do echo $i; i=$((i+1)); done",
+            ])
+            .line_channels(1, LineOverflow::Block)
+            .run_to_completion()
+            .await
+            .stack()?
+            .assert_success()
+            .stack()?;
+    }
 
     // check command debug
     let command = Command::new("ls")
@@ -191,6 +1247,94 @@ async fn main() -> Result<()> {
         .assert_success()
         .stack()?;
 
+    // labeled debug output is written in whole lines, other debug output as it is
+    // read
+    #[cfg(unix)]
+    {
+        let comres = Command::new("cargo r --bin commands --quiet -- --terminal-prefix")
+            .run_to_completion()
+            .await
+            .stack()?;
+        comres.assert_success().stack()?;
+        // remove the colors
+        let mut stdout = String::new();
+        let mut escape = false;
+        for c in comres.stdout_as_utf8().stack()?.chars() {
+            if c == '\u{1b}' {
+                escape = true;
+            } else if escape {
+                escape = c != 'm';
+            } else {
+                stdout.push(c);
+            }
+        }
+        for label in ["one", "two"] {
+            let prefix = format!("[{label}]  | ");
+            let lines: Vec<&str> = stdout
+                .lines()
+                .filter_map(|line| line.strip_prefix(&prefix))
+                .collect();
+            ensure_eq!(lines, ["ab", "cd", "e"]);
+        }
+        ensure!(stdout.ends_with("raw| 1\r2\nraw| 3\n"));
+        ensure_eq!(stdout.lines().count(), 8);
+    }
+
+    // with a subscriber, debug output is emitted as events at the debug level
+    #[cfg(unix)]
+    {
+        let events = CommandEvents::default();
+        let subscriber = tracing_subscriber::registry().with(events.clone());
+        Command::new("sh -c")
+            .arg("echo out; echo err >&2")
+            .debug(true)
+            .stderr_debug_line_prefix(Some("custom |".to_owned()))
+            .debug_level(Level::DEBUG)
+            .run_to_completion()
+            .with_subscriber(subscriber)
+            .await
+            .stack()?
+            .assert_success()
+            .stack()?;
+        let events = events.0.lock().unwrap().clone();
+        ensure!(events.iter().all(|(level, _)| *level == Level::DEBUG));
+        let messages: Vec<&str> = events
+            .iter()
+            .map(|(_, fields)| fields["message"].as_str())
+            .collect();
+        ensure!(messages.first().unwrap().starts_with("running "));
+        ensure!(messages
+            .last()
+            .unwrap()
+            .starts_with("exited with exit status: 0 after "));
+        let line = |stream: &str| {
+            events
+                .iter()
+                .find(|(_, fields)| fields.get("stream").map(String::as_str) == Some(stream))
+                .map(|(_, fields)| (fields["command"].clone(), fields["message"].clone()))
+        };
+        ensure!(line("stdout").unwrap().0.contains("sh "));
+        ensure_eq!(line("stdout").unwrap().1, "out");
+        ensure_eq!(
+            line("stderr"),
+            Some(("custom |".to_owned(), "err".to_owned()))
+        );
+
+        // `raw_debug` keeps writing to the standard streams
+        let events = CommandEvents::default();
+        let subscriber = tracing_subscriber::registry().with(events.clone());
+        Command::new("echo raw")
+            .debug(true)
+            .raw_debug(true)
+            .run_to_completion()
+            .with_subscriber(subscriber)
+            .await
+            .stack()?
+            .assert_success()
+            .stack()?;
+        ensure!(events.0.lock().unwrap().is_empty());
+    }
+
     info!("test completed successfully");
 
     Ok(())
@@ -0,0 +1,87 @@
+//! Checks `commit_container` and building on top of the committed image
+
+use std::{str::FromStr, time::Duration};
+
+use stacked_errors::{ensure_eq, Result, StackableErr};
+use super_orchestrator::{
+    api_docker::{
+        commit_container, download_to_bytes, CommitOptions, Dockerfile, SuperContainer,
+        SuperDockerfile, SuperImage, SuperNetwork, SuperRunOptions,
+    },
+    wait_for_ok,
+};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+
+async fn commit(container_id: &str) -> Result<SuperImage> {
+    wait_for_ok(100, Duration::from_millis(100), || {
+        download_to_bytes(container_id, "/migrated")
+    })
+    .await
+    .stack()?;
+    commit_container(container_id, CommitOptions {
+        tag: Some("test".to_owned()),
+        pause: true,
+        changes: vec!["ENV MIGRATED=yes".to_owned()],
+        ..Default::default()
+    })
+    .await
+    .stack()
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let image = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .build_image()
+        .await
+        .stack()?;
+
+    let mut sn = SuperNetwork::new_with_uuid("test_commit", "./logs");
+    sn.add_container(SuperContainer::new("migrations", image).cmd([
+        "sh",
+        "-c",
+        "echo schema > /migrated && sleep 300",
+    ]))
+    .stack()?;
+    sn.run_all().await.stack()?;
+    let res = commit(&sn.docker_name("migrations")).await;
+    sn.terminate_all().await;
+    let committed = res.stack()?;
+
+    let completed = committed
+        .run_once(SuperRunOptions {
+            cmd: vec![
+                "sh".to_owned(),
+                "-c".to_owned(),
+                "cat /migrated && echo $MIGRATED".to_owned(),
+            ],
+            ..Default::default()
+        })
+        .await
+        .stack()?;
+    completed.assert_success().stack()?;
+    ensure_eq!(completed.stdout_as_utf8().stack()?, "schema\nyes\n");
+
+    // further layers can be added
+    let extended = committed
+        .to_docker_file()
+        .append_dockerfile_instructions(["RUN echo extended >> /migrated"])
+        .build_image()
+        .await
+        .stack()?;
+    let completed = extended
+        .run_once(SuperRunOptions {
+            cmd: vec!["cat".to_owned(), "/migrated".to_owned()],
+            ..Default::default()
+        })
+        .await
+        .stack()?;
+    ensure_eq!(completed.stdout_as_utf8().stack()?, "schema\nextended\n");
+
+    Ok(())
+}
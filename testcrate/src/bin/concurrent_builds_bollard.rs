@@ -0,0 +1,78 @@
+//! Checks `build_images_concurrently`
+
+use std::str::FromStr;
+
+use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::api_docker::{
+    build_images_concurrently, Dockerfile, SuperDockerfile, SuperImage, SuperRunOptions,
+};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+const SHARED_CONTENTS: &str = "FROM alpine:3.21\nRUN echo shared > /shared";
+
+fn name_tag_based(i: usize) -> SuperDockerfile {
+    SuperDockerfile::new(
+        Dockerfile::name_tag(BASE_CONTAINER),
+        Some(format!("concurrent_builds_{i}")),
+    )
+    .append_dockerfile_instructions([format!("RUN echo {i} > /index")])
+}
+
+fn contents_based(i: usize) -> SuperDockerfile {
+    SuperDockerfile::new(Dockerfile::contents(SHARED_CONTENTS), None)
+        .append_dockerfile_instructions([format!("RUN echo {i} > /index")])
+}
+
+async fn cat(image: &SuperImage, path: &str) -> Result<String> {
+    let completed = image
+        .run_once(SuperRunOptions {
+            cmd: vec!["cat".to_owned(), path.to_owned()],
+            ..Default::default()
+        })
+        .await
+        .stack()?;
+    completed.assert_success().stack()?;
+    Ok(completed.stdout_as_utf8().stack()?.to_owned())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let dockerfiles = vec![
+        name_tag_based(0),
+        contents_based(1),
+        name_tag_based(2),
+        contents_based(3),
+        name_tag_based(4),
+        contents_based(5),
+    ];
+    let images = build_images_concurrently(dockerfiles, 3).await.stack()?;
+    ensure_eq!(images.len(), 6);
+    // the results are in order
+    for (i, (image, _)) in images.iter().enumerate() {
+        let index = cat(image, "/index").await.stack()?;
+        ensure_eq!(index, format!("{i}\n"));
+        if (i % 2) == 1 {
+            let shared = cat(image, "/shared").await.stack()?;
+            ensure_eq!(shared, "shared\n");
+        }
+    }
+
+    // all failures are reported
+    let dockerfiles = vec![
+        name_tag_based(0).append_dockerfile_instructions(["RUN exit 1"]),
+        name_tag_based(1),
+        contents_based(2).append_dockerfile_instructions(["RUN exit 2"]),
+    ];
+    let err = build_images_concurrently(dockerfiles, 2).await.unwrap_err();
+    let err = format!("{err:?}");
+    ensure!(err.contains("when building image concurrent_builds_0"));
+    ensure!(err.contains("when building image #2"));
+    ensure!(!err.contains("when building image concurrent_builds_1"));
+
+    Ok(())
+}
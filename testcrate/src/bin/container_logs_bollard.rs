@@ -0,0 +1,110 @@
+//! Checks `container_logs_to_file` and `container_logs_to_vec`
+
+use std::{str::FromStr, time::Duration};
+
+use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::{
+    api_docker::{
+        container_logs_to_file, container_logs_to_vec, Dockerfile, SuperContainer, SuperDockerfile,
+        SuperNetwork,
+    },
+    FileOptions,
+};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+const LOGS: &str = "./logs";
+
+async fn run_logs(sn: &SuperNetwork) -> Result<()> {
+    let printing = sn.docker_name("printing");
+    let handle = container_logs_to_file(
+        &printing,
+        FileOptions::write2(LOGS, "container_logs_stdout.log"),
+        FileOptions::write2(LOGS, "container_logs_stderr.log"),
+        true,
+    )
+    .await
+    .stack()?;
+    // stops when the container exits
+    tokio::time::timeout(Duration::from_secs(60), handle.wait())
+        .await
+        .stack()?
+        .stack()?;
+    ensure_eq!(
+        FileOptions::read2_to_string(LOGS, "container_logs_stdout.log")
+            .await
+            .stack()?,
+        "out\nlater\n"
+    );
+    ensure_eq!(
+        FileOptions::read2_to_string(LOGS, "container_logs_stderr.log")
+            .await
+            .stack()?,
+        "err\n"
+    );
+
+    let (stdout, stderr) = container_logs_to_vec(&printing).await.stack()?;
+    ensure_eq!(stdout, b"out\nlater\n");
+    ensure_eq!(stderr, b"err\n");
+
+    // stopping a follow of a container that keeps running
+    let handle = container_logs_to_file(
+        &sn.docker_name("sleeping"),
+        FileOptions::write2(LOGS, "container_logs_sleeping_stdout.log"),
+        FileOptions::write2(LOGS, "container_logs_sleeping_stderr.log"),
+        true,
+    )
+    .await
+    .stack()?;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    ensure!(!handle.is_finished());
+    tokio::time::timeout(Duration::from_secs(10), handle.stop())
+        .await
+        .stack()?
+        .stack()?;
+    ensure_eq!(
+        FileOptions::read2_to_string(LOGS, "container_logs_sleeping_stdout.log")
+            .await
+            .stack()?,
+        "sleeping\n"
+    );
+
+    ensure!(container_logs_to_vec("nonexistent_container_name")
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let image = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .build_image()
+        .await
+        .stack()?;
+
+    let mut sn = SuperNetwork::new_with_uuid("test_container_logs", LOGS);
+    sn.add_container(SuperContainer::new("printing", image.clone()).cmd([
+        "sh",
+        "-c",
+        "echo out; echo err >&2; sleep 2; echo later",
+    ]))
+    .stack()?;
+    sn.add_container(SuperContainer::new("sleeping", image).cmd([
+        "sh",
+        "-c",
+        "echo sleeping; sleep 300",
+    ]))
+    .stack()?;
+    sn.run_all().await.stack()?;
+
+    let res = run_logs(&sn).await;
+    sn.terminate_all().await;
+    res.stack()?;
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+//! Checks `container_stats` on a container that is busy for a few seconds
+
+use std::{str::FromStr, time::Duration};
+
+use futures::StreamExt;
+use stacked_errors::{ensure, Result, StackableErr};
+use super_orchestrator::api_docker::{
+    container_stats, Dockerfile, SuperContainer, SuperDockerfile, SuperNetwork,
+};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+
+async fn run_stats(sn: &SuperNetwork) -> Result<()> {
+    let stats = container_stats(&sn.docker_name("busy"), Duration::from_millis(500))
+        .await
+        .stack()?;
+    // the stream ends by itself when the container stops
+    let samples = tokio::time::timeout(Duration::from_secs(60), stats.collect::<Vec<_>>())
+        .await
+        .stack()?
+        .into_iter()
+        .collect::<Result<Vec<_>>>()
+        .stack()?;
+    tracing::info!("{samples:#?}");
+    ensure!(samples.len() >= 2);
+    ensure!(samples.iter().all(|sample| sample.mem_limit > 0));
+    ensure!(samples.iter().any(|sample| sample.cpu_percent > 10.0));
+    ensure!(samples.windows(2).all(|w| w[0].ts < w[1].ts));
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let image = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .build_image()
+        .await
+        .stack()?;
+
+    let mut sn = SuperNetwork::new_with_uuid("test_container_stats", "./logs");
+    sn.add_container(SuperContainer::new("busy", image).cmd([
+        "sh",
+        "-c",
+        "timeout 5 sh -c 'while true; do :; done'; sleep 1",
+    ]))
+    .stack()?;
+    sn.run_all().await.stack()?;
+
+    let res = run_stats(&sn).await;
+    sn.terminate_all().await;
+    res.stack()?;
+
+    Ok(())
+}
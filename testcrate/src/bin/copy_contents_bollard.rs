@@ -0,0 +1,67 @@
+//! Checks `SuperDockerfile::copying_from_contents` and
+//! `copying_contents_into_dir` by reading the files from the built image
+
+use std::str::FromStr;
+
+use stacked_errors::{ensure_eq, Result, StackableErr};
+use super_orchestrator::api_docker::{Dockerfile, SuperDockerfile, SuperImage, SuperRunOptions};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+
+async fn run(image: &SuperImage, cmd: &[&str]) -> Result<String> {
+    let completed = image
+        .run_once(SuperRunOptions {
+            cmd: cmd.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        })
+        .await
+        .stack()?;
+    completed.assert_success().stack()?;
+    Ok(completed.stdout_as_utf8().stack()?.to_owned())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let image = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .copying_from_contents([("/etc/myapp/conf.d/app.toml", None, b"app\n".to_vec())])
+        .await
+        .stack()?
+        .copying_contents_into_dir("/etc/myapp", [
+            ("a.toml", Some(0o644), b"a\n".to_vec()),
+            ("b.toml", None, b"b\n".to_vec()),
+            ("nested/c.toml", None, b"c\n".to_vec()),
+        ])
+        .await
+        .stack()?
+        .build_image()
+        .await
+        .stack()?;
+
+    let res = run(&image, &["cat", "/etc/myapp/conf.d/app.toml"])
+        .await
+        .stack()?;
+    ensure_eq!(res, "app\n");
+    let res = run(&image, &[
+        "cat",
+        "/etc/myapp/a.toml",
+        "/etc/myapp/b.toml",
+        "/etc/myapp/nested/c.toml",
+    ])
+    .await
+    .stack()?;
+    ensure_eq!(res, "a\nb\nc\n");
+    let res = run(&image, &["stat", "-c", "%a", "/etc/myapp/a.toml"])
+        .await
+        .stack()?;
+    ensure_eq!(res, "644\n");
+    // the directory has nothing from the rest of the context
+    let res = run(&image, &["ls", "/etc/myapp"]).await.stack()?;
+    ensure_eq!(res, "a.toml\nb.toml\nconf.d\nnested\n");
+
+    Ok(())
+}
@@ -0,0 +1,87 @@
+//! Checks that `docker_socket` reports endpoints that are too old or not
+//! docker daemons clearly, using fake HTTP endpoints. This does not need
+//! docker to be running.
+
+use stacked_errors::{ensure, Result, StackableErr};
+use super_orchestrator::api_docker::docker_socket::{self, DockerConnection};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// Serves every request with the same response, returns the host
+async fn fake_endpoint(status: &'static str, body: &'static str) -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.stack()?;
+    let addr = listener.local_addr().stack()?;
+    tokio::task::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = vec![];
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: \
+                 {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+    Ok(format!("tcp://{addr}"))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt().with_env_filter("debug").init();
+
+    // an old daemon
+    let host = fake_endpoint(
+        "200 OK",
+        r#"{"Version":"17.06.0-ce","ApiVersion":"1.30","MinAPIVersion":"1.12"}"#,
+    )
+    .await
+    .stack()?;
+    let err = docker_socket::init_with(DockerConnection::Http(host))
+        .await
+        .unwrap_err();
+    let err = format!("{err:?}");
+    ensure!(err.contains("17.06.0-ce"));
+    ensure!(err.contains("negotiated API version 1.30"));
+    ensure!(err.contains("building for a platform"));
+    ensure!(err.contains("BuildKit sessions"));
+
+    // something that is not a docker daemon, the failed initialization can be
+    // retried
+    let host = fake_endpoint("404 Not Found", "page not found")
+        .await
+        .stack()?;
+    let err = docker_socket::init_with(DockerConnection::Http(host))
+        .await
+        .unwrap_err();
+    ensure!(format!("{err:?}").contains("may not be a docker compatible daemon"));
+
+    // a new enough daemon that is older than the client, the default instance
+    // uses the negotiated API version
+    let host = fake_endpoint(
+        "200 OK",
+        r#"{"Version":"24.0.9","ApiVersion":"1.43","MinAPIVersion":"1.12"}"#,
+    )
+    .await
+    .stack()?;
+    docker_socket::init_with(DockerConnection::Http(host))
+        .await
+        .stack()?;
+    let engine_info = docker_socket::check_compatibility().await.stack()?;
+    ensure!(engine_info.api_version.as_deref() == Some("1.43"));
+    ensure!(!engine_info.is_podman());
+    let docker_instance = docker_socket::get_or_init_default_docker_instance()
+        .await
+        .stack()?;
+    ensure!(docker_instance.client_version().to_string() == "1.43");
+
+    Ok(())
+}
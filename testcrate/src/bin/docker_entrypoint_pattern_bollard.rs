@@ -16,8 +16,9 @@ use stacked_errors::{bail, ensure_eq, Result, StackableErr};
 use super_orchestrator::{
     acquire_dir_path,
     api_docker::{
-        AddContainerOptions, BootstrapOptions, ContainerCreateOptions, ContainerNetwork,
-        Dockerfile, NetworkCreateOptions, OutputDirConfig, SuperDockerfile, Tarball,
+        AddContainerOptions, BootstrapOptions, BuildProfile, ContainerCreateOptions,
+        ContainerNetwork, Dockerfile, NetworkCreateOptions, OutputDirConfig, SuperDockerfile,
+        Tarball,
     },
     net_message::NetMessenger,
     FileOptions,
@@ -222,7 +223,8 @@ async fn container_runner(args: &Args) -> Result<()> {
     ];
 
     // uses `container2_dockerfile`, allowing for self-contained complicated systems
-    // in a single file
+    // in a single file. This one also uses a debug build of the binary, which is
+    // much faster to compile.
     cn.add_container(
         AddContainerOptions::DockerFile(
             SuperDockerfile::new_with_tar(
@@ -230,9 +232,11 @@ async fn container_runner(args: &Args) -> Result<()> {
                 None,
                 tarball,
             )
-            .bootstrap_musl(
+            .bootstrap_for_target(
                 "/entrypoint",
                 ["--entry-name", "container2"],
+                None,
+                BuildProfile::Debug,
                 BootstrapOptions::Bin,
                 ["--features", "bollard"],
             )
@@ -0,0 +1,90 @@
+//! Checks that per-call docker handles and
+//! `docker_socket::set_default_for_tests` are used instead of the default
+//! docker instance. The "daemons" are TCP listeners that only count the
+//! connections made to them, so this does not need docker.
+
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::api_docker::{
+    docker_socket::{self, DockerConnection},
+    pull_image, Docker, Dockerfile, RegistryOptions, SuperDockerfile, SuperImage, SuperRunOptions,
+};
+use tokio::net::TcpListener;
+use tracing_subscriber::EnvFilter;
+
+/// Binds a listener that closes every connection, returns a docker handle
+/// for it and the number of connections made
+async fn fake_daemon() -> Result<(Docker, Arc<AtomicUsize>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.stack()?;
+    let port = listener.local_addr().stack()?.port();
+    let connections = Arc::new(AtomicUsize::new(0));
+    tokio::spawn({
+        let connections = connections.clone();
+        async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.fetch_add(1, Ordering::SeqCst);
+                drop(stream);
+            }
+        }
+    });
+    let docker = DockerConnection::Http(format!("tcp://127.0.0.1:{port}"))
+        .connect()
+        .stack()?;
+    Ok((docker, connections))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let (default_docker, default_connections) = fake_daemon().await.stack()?;
+    let (other_docker, other_connections) = fake_daemon().await.stack()?;
+
+    let previous = docker_socket::set_default_for_tests(Some(default_docker)).await;
+    ensure!(previous.is_none());
+    let image = SuperImage::new("super_orchestrator_nonexistent".to_owned());
+
+    // without an override, the default set for tests is used
+    ensure!(image.run_once(SuperRunOptions::default()).await.is_err());
+    ensure!(default_connections.load(Ordering::SeqCst) > 0);
+    ensure_eq!(other_connections.load(Ordering::SeqCst), 0);
+
+    let default_before = default_connections.load(Ordering::SeqCst);
+    let res = image
+        .run_once(SuperRunOptions {
+            docker_instance: Some(other_docker.clone()),
+            ..Default::default()
+        })
+        .await;
+    ensure!(res.is_err());
+    let res = pull_image("alpine:3.21", RegistryOptions {
+        skip_docker_config: true,
+        docker_instance: Some(other_docker.clone()),
+        ..Default::default()
+    })
+    .await;
+    ensure!(res.is_err());
+    let res = SuperDockerfile::new(Dockerfile::name_tag("alpine:3.21"), None)
+        .with_docker_instance(other_docker)
+        .build_image()
+        .await;
+    ensure!(res.is_err());
+    ensure!(other_connections.load(Ordering::SeqCst) >= 3);
+    ensure_eq!(default_connections.load(Ordering::SeqCst), default_before);
+
+    // resetting returns the handle that was set
+    let previous = docker_socket::set_default_for_tests(None).await;
+    ensure!(previous.is_some());
+    ensure!(docker_socket::set_default_for_tests(None).await.is_none());
+
+    Ok(())
+}
@@ -0,0 +1,113 @@
+//! Checks the rendering of the typed dockerfile instruction helpers of
+//! `SuperDockerfile`, this does not need docker to be running.
+
+use std::io::Read;
+
+use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::api_docker::{Dockerfile, PortBindProtocol, SuperDockerfile};
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+
+/// Returns the dockerfile that `sdf` results in
+async fn render(sdf: SuperDockerfile) -> Result<String> {
+    let (_, tarball) = sdf.into_bollard_args().await.stack()?;
+    let mut archive = tar::Archive::new(std::io::Cursor::new(tarball));
+    for entry in archive.entries().stack()? {
+        let mut entry = entry.stack()?;
+        if entry.path().stack()?.to_str() == Some("super.dockerfile") {
+            let mut content = String::new();
+            entry.read_to_string(&mut content).stack()?;
+            return Ok(content)
+        }
+    }
+    Err("no dockerfile in the tarball").stack()
+}
+
+fn base() -> SuperDockerfile {
+    SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let sdf = base()
+        .with_env("PLAIN", "value")?
+        .with_env("SPACES", "a b  c")?
+        .with_env("QUOTES", r#"say "hi" \o/"#)?
+        .with_env("VARS", "$HOME ${PATH}")?
+        .with_env("UNICODE", "héllo wörld ✓")?
+        .with_env("EMPTY", "")?
+        .with_workdir("/app dir")?
+        .with_user("1000:1000")?
+        .with_expose(8080, PortBindProtocol::Tcp)
+        .with_expose(53, PortBindProtocol::Udp)
+        .with_label("org.example.description", r#"a "quoted" label"#)?;
+    let rendered = render(sdf).await.stack()?;
+    ensure_eq!(
+        rendered,
+        format!(
+            r#"FROM {BASE_CONTAINER}
+ENV PLAIN="value"
+ENV SPACES="a b  c"
+ENV QUOTES="say \"hi\" \\o/"
+ENV VARS="\$HOME \${{PATH}}"
+ENV UNICODE="héllo wörld ✓"
+ENV EMPTY=""
+WORKDIR "/app dir"
+USER "1000:1000"
+EXPOSE 8080/tcp
+EXPOSE 53/udp
+LABEL "org.example.description"="a \"quoted\" label""#
+        )
+    );
+
+    // the `_mut` variants append the same
+    let mut sdf = base();
+    sdf.with_env_mut("KEY", "a b").stack()?;
+    sdf.with_workdir_mut("/app").stack()?;
+    sdf.with_user_mut("user").stack()?;
+    sdf.with_expose_mut(80, PortBindProtocol::Sctp);
+    sdf.with_label_mut("key", "value").stack()?;
+    let rendered = render(sdf).await.stack()?;
+    ensure_eq!(
+        rendered,
+        format!(
+            "FROM {BASE_CONTAINER}\nENV KEY=\"a b\"\nWORKDIR \"/app\"\nUSER \"user\"\nEXPOSE \
+             80/sctp\nLABEL \"key\"=\"value\""
+        )
+    );
+
+    // stage args follow the `FROM`, global args precede it
+    let sdf = base()
+        .with_arg("STAGE", Some("a b"))?
+        .with_arg("NO_DEFAULT", None)?
+        .with_global_arg("GLOBAL", Some("1"))?;
+    let rendered = render(sdf).await.stack()?;
+    ensure_eq!(
+        rendered,
+        format!("ARG GLOBAL=\"1\"\nFROM {BASE_CONTAINER}\nARG STAGE=\"a b\"\nARG NO_DEFAULT")
+    );
+    // parser directives and comments stay at the top
+    let mut sdf = SuperDockerfile::new(
+        Dockerfile::contents("# syntax=docker/dockerfile:1\n# comment\nfrom alpine:3.21\n"),
+        None,
+    );
+    sdf.with_global_arg_mut("VERSION", None).stack()?;
+    let rendered = render(sdf).await.stack()?;
+    ensure_eq!(
+        rendered,
+        "# syntax=docker/dockerfile:1\n# comment\nARG VERSION\nfrom alpine:3.21\n"
+    );
+
+    // things that cannot be expressed
+    ensure!(base().with_env("KEY", "multi\nline").is_err());
+    ensure!(base().with_env("KEY WITH SPACE", "value").is_err());
+    ensure!(base().with_env("KEY=", "value").is_err());
+    ensure!(base().with_env("", "value").is_err());
+    ensure!(base().with_workdir("/a\nb").is_err());
+    ensure!(base().with_label("key", "a\r\nb").is_err());
+    ensure!(base().with_arg("A B", None).is_err());
+    ensure!(base().with_arg("A=", None).is_err());
+    ensure!(base().with_global_arg("A", Some("a\nb")).is_err());
+
+    Ok(())
+}
@@ -0,0 +1,94 @@
+//! Checks `download_from_container` and `download_to_bytes`
+
+use std::{str::FromStr, time::Duration};
+
+use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::{
+    api_docker::{
+        download_from_container, download_to_bytes, Dockerfile, SuperContainer, SuperDockerfile,
+        SuperNetwork,
+    },
+    wait_for_ok, FileOptions,
+};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+const DEST: &str = "./logs/download_bollard";
+
+async fn run_downloads(container_id: &str) -> Result<()> {
+    wait_for_ok(100, Duration::from_millis(100), || {
+        download_to_bytes(container_id, "/ready")
+    })
+    .await
+    .stack()?;
+
+    ensure_eq!(
+        download_to_bytes(container_id, "/data/a.txt")
+            .await
+            .stack()?,
+        b"a\n"
+    );
+    // directories are not single files
+    ensure!(download_to_bytes(container_id, "/data").await.is_err());
+    ensure!(download_to_bytes(container_id, "/nonexistent")
+        .await
+        .is_err());
+
+    let _ = std::fs::remove_dir_all(DEST);
+    download_from_container(container_id, "/data", DEST)
+        .await
+        .stack()?;
+    ensure_eq!(
+        FileOptions::read_to_string(format!("{DEST}/data/a.txt"))
+            .await
+            .stack()?,
+        "a\n"
+    );
+    ensure_eq!(
+        FileOptions::read_to_string(format!("{DEST}/data/sub/b.txt"))
+            .await
+            .stack()?,
+        "b\n"
+    );
+
+    download_from_container(container_id, "/data/a.txt", format!("{DEST}/single"))
+        .await
+        .stack()?;
+    ensure_eq!(
+        FileOptions::read_to_string(format!("{DEST}/single/a.txt"))
+            .await
+            .stack()?,
+        "a\n"
+    );
+
+    std::fs::remove_dir_all(DEST).stack()?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let image = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .build_image()
+        .await
+        .stack()?;
+
+    let mut sn = SuperNetwork::new_with_uuid("test_download", "./logs");
+    sn.add_container(SuperContainer::new("files", image).cmd([
+        "sh",
+        "-c",
+        "mkdir -p /data/sub && echo a > /data/a.txt && echo b > /data/sub/b.txt && touch /ready \
+         && sleep 300",
+    ]))
+    .stack()?;
+    sn.run_all().await.stack()?;
+
+    let res = run_downloads(&sn.docker_name("files")).await;
+    sn.terminate_all().await;
+    res.stack()?;
+
+    Ok(())
+}
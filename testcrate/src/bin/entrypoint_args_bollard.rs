@@ -0,0 +1,59 @@
+//! Checks that `SuperDockerfile::with_entrypoint` passes arguments to a
+//! bootstrapped binary exactly as they were given. When run with
+//! `--echo-args`, this binary prints its arguments as JSON instead.
+
+use std::str::FromStr;
+
+use stacked_errors::{ensure_eq, Result, StackableErr};
+use super_orchestrator::api_docker::{
+    BootstrapOptions, Dockerfile, SuperDockerfile, SuperRunOptions,
+};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+const ECHO_ARGS: &str = "--echo-args";
+
+const ARGS: &[&str] = &[
+    "plain",
+    "with spaces",
+    r#"say "hi""#,
+    r#"{"json": ["blob", 1]}"#,
+    r"back\slash",
+    "multi\nline",
+    "$HOME ${PATH}",
+    "héllo wörld ✓",
+    "",
+];
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some(ECHO_ARGS) {
+        println!("{}", serde_json::to_string(&args[1..]).stack()?);
+        return Ok(())
+    }
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let image = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .bootstrap_musl(
+            "/entrypoint",
+            std::iter::once(ECHO_ARGS).chain(ARGS.iter().copied()),
+            BootstrapOptions::Bin,
+            ["--features", "bollard"],
+        )
+        .await
+        .stack()?
+        .build_image()
+        .await
+        .stack()?;
+
+    let completed = image.run_once(SuperRunOptions::default()).await.stack()?;
+    completed.assert_success().stack()?;
+    let echoed: Vec<String> = serde_json::from_slice(&completed.stdout).stack()?;
+    ensure_eq!(echoed, ARGS);
+
+    Ok(())
+}
@@ -0,0 +1,73 @@
+//! Checks `PortBind::ephemeral` and `resolve_host_port` with two containers
+//! that publish the same container port
+
+use std::{str::FromStr, time::Duration};
+
+use stacked_errors::{ensure, ensure_ne, Result, StackableErr};
+use super_orchestrator::{
+    api_docker::{
+        resolve_host_port, Dockerfile, PortBind, PortBindProtocol, SuperContainer, SuperDockerfile,
+        SuperNetwork,
+    },
+    wait_for_ok,
+};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+
+async fn run_ports(sn: &SuperNetwork) -> Result<()> {
+    let port0 = resolve_host_port(&sn.docker_name("server0"), 8080, PortBindProtocol::Tcp)
+        .await
+        .stack()?;
+    let port1 = resolve_host_port(&sn.docker_name("server1"), 8080, PortBindProtocol::Tcp)
+        .await
+        .stack()?;
+    ensure_ne!(port0, port1);
+    for port in [port0, port1] {
+        ensure!(port != 0);
+        wait_for_ok(100, Duration::from_millis(100), || async move {
+            tokio::net::TcpStream::connect(("127.0.0.1", port))
+                .await
+                .stack()
+        })
+        .await
+        .stack()?;
+    }
+    // not published
+    ensure!(
+        resolve_host_port(&sn.docker_name("server0"), 8081, PortBindProtocol::Tcp)
+            .await
+            .is_err()
+    );
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let image = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .build_image()
+        .await
+        .stack()?;
+
+    let mut sn = SuperNetwork::new_with_uuid("test_ephemeral_ports", "./logs");
+    for name in ["server0", "server1"] {
+        sn.add_container(
+            SuperContainer::new(name, image.clone())
+                .cmd(["httpd", "-f", "-p", "8080"])
+                .port_bindings([PortBind::ephemeral(8080)]),
+        )
+        .stack()?;
+    }
+    sn.run_all().await.stack()?;
+
+    let res = run_ports(&sn).await;
+    sn.terminate_all().await;
+    res.stack()?;
+
+    Ok(())
+}
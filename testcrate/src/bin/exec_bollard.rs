@@ -0,0 +1,84 @@
+//! Checks `exec_in_container`, the API equivalent of `docker exec`
+
+use std::str::FromStr;
+
+use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::api_docker::{
+    exec_in_container, exec_in_container_streaming, Dockerfile, ExecLine, ExecOptions,
+    SuperContainer, SuperDockerfile, SuperNetwork,
+};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+
+async fn run_execs(container_id: &str) -> Result<()> {
+    let res = exec_in_container(
+        container_id,
+        ["sh", "-c", "echo out; echo err 1>&2; exit 3"],
+        ExecOptions::default(),
+    )
+    .await
+    .stack()?;
+    ensure_eq!(res.exit_code, 3);
+    ensure!(res.assert_success().is_err());
+    ensure_eq!(res.stdout_as_utf8().stack()?, "out\n");
+    ensure_eq!(res.stderr_as_utf8().stack()?, "err\n");
+
+    let res = exec_in_container(
+        container_id,
+        ["sh", "-c", "echo $VAR; pwd; id -u"],
+        ExecOptions {
+            env: vec!["VAR=value".to_owned()],
+            user: Some("1000".to_owned()),
+            workdir: Some("/tmp".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await
+    .stack()?;
+    res.assert_success().stack()?;
+    ensure_eq!(res.stdout_as_utf8().stack()?, "value\n/tmp\n1000\n");
+
+    let mut lines = exec_in_container_streaming(
+        container_id,
+        ["sh", "-c", "echo a; sleep 1; echo b >&2; sleep 1; printf c"],
+        ExecOptions::default(),
+    )
+    .await
+    .stack()?;
+    ensure_eq!(lines.wait().await.stack()?, 0);
+    let mut received = vec![];
+    while let Some(line) = lines.lines.recv().await {
+        received.push(line);
+    }
+    ensure_eq!(received, vec![
+        ExecLine::Stdout("a".to_owned()),
+        ExecLine::Stderr("b".to_owned()),
+        ExecLine::Stdout("c".to_owned()),
+    ]);
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let image = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .build_image()
+        .await
+        .stack()?;
+
+    let mut sn = SuperNetwork::new_with_uuid("test_exec", "./logs");
+    sn.add_container(SuperContainer::new("target", image).cmd(["sleep", "300"]))
+        .stack()?;
+    sn.run_all().await.stack()?;
+
+    let res = run_execs(&sn.docker_name("target")).await;
+    sn.terminate_all().await;
+    res.stack()?;
+
+    Ok(())
+}
@@ -1,18 +1,938 @@
-use std::path::PathBuf;
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
 
-use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
-use super_orchestrator::{close_file, remove_files_in_dir, FileOptions, ReadOrWrite};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use stacked_errors::{bail, ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::{
+    close_file, remove_files_in_dir, wait_for_file, wait_for_file_with_interval, watch_file,
+    Command, CopyOptions, FileEvent, FileOptions, ReadOrWrite, TempWorkspace, WriteOptions,
+};
 use tokio::{
     fs::{File, OpenOptions},
     io::{AsyncReadExt, AsyncWriteExt},
+    time::{sleep, Instant},
 };
 use tracing::{debug, info};
 
+const ATOMIC_LEN: usize = 64 << 20;
+
+/// Writes to "./logs/atomic.log" atomically until it is killed
+async fn atomic_writer() -> Result<()> {
+    let contents = vec![b'b'; ATOMIC_LEN];
+    loop {
+        FileOptions::write_atomic("./logs/atomic.log")
+            .write_all(&contents)
+            .await
+            .stack()?;
+    }
+}
+
+/// Returns the names of the temporary files of "./logs/atomic.log"
+async fn atomic_temp_files() -> Result<Vec<String>> {
+    let mut names = vec![];
+    let mut entries = tokio::fs::read_dir("./logs").await.stack()?;
+    while let Some(entry) = entries.next_entry().await.stack()? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(".atomic.log.tmp-") {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+async fn create_parents() -> Result<()> {
+    let _ = tokio::fs::remove_dir_all("./logs/parents").await;
+    let path = "./logs/parents/a/b/example.log";
+    ensure!(FileOptions::write_str(path, "test").await.is_err());
+    // reading never creates directories
+    ensure!(FileOptions::read(path)
+        .create_parents(true)
+        .acquire_file()
+        .await
+        .is_err());
+    ensure!(!tokio::fs::try_exists("./logs/parents").await.stack()?);
+
+    FileOptions::write(path)
+        .create_parents(true)
+        .write_all("test")
+        .await
+        .stack()?;
+    ensure_eq!(FileOptions::read_to_string(path).await.stack()?, "test");
+    // existing directories are fine, and this works with atomic writes
+    FileOptions::write_atomic(path)
+        .create_parents(true)
+        .write_all("atomic")
+        .await
+        .stack()?;
+    ensure_eq!(FileOptions::read_to_string(path).await.stack()?, "atomic");
+    tokio::fs::remove_dir_all("./logs/parents").await.stack()?;
+    Ok(())
+}
+
+/// Returns the permission bits of the file at `path`
+async fn file_mode(path: &str) -> Result<u32> {
+    let metadata = tokio::fs::metadata(path).await.stack()?;
+    Ok(metadata.permissions().mode() & 0o777)
+}
+
+async fn modes() -> Result<()> {
+    let path = "./logs/mode.log";
+    let _ = tokio::fs::remove_file(path).await;
+    FileOptions::write_executable(path)
+        .write_all("#!/bin/sh")
+        .await
+        .stack()?;
+    ensure_eq!(file_mode(path).await.stack()?, 0o755);
+
+    // the mode of an existing file is changed when it is overwritten, regardless of
+    // the umask
+    FileOptions::write_str(path, "test").await.stack()?;
+    ensure_eq!(file_mode(path).await.stack()?, 0o755);
+    FileOptions::write_secret(path)
+        .write_all("secret")
+        .await
+        .stack()?;
+    ensure_eq!(file_mode(path).await.stack()?, 0o600);
+    FileOptions::write(path)
+        .mode(0o777)
+        .write_all("test")
+        .await
+        .stack()?;
+    ensure_eq!(file_mode(path).await.stack()?, 0o777);
+
+    // but not when appending
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o644))
+        .await
+        .stack()?;
+    FileOptions::new(path, ReadOrWrite::write(false, true))
+        .mode(0o700)
+        .write_all(" appended")
+        .await
+        .stack()?;
+    ensure_eq!(file_mode(path).await.stack()?, 0o644);
+    ensure_eq!(
+        FileOptions::read_to_string(path).await.stack()?,
+        "test appended"
+    );
+
+    // atomic writes use the mode instead of the permissions of the destination
+    FileOptions::write_atomic(path)
+        .mode(0o600)
+        .write_all("atomic")
+        .await
+        .stack()?;
+    ensure_eq!(file_mode(path).await.stack()?, 0o600);
+    FileOptions::write_atomic(path)
+        .write_all("atomic 2")
+        .await
+        .stack()?;
+    ensure_eq!(file_mode(path).await.stack()?, 0o600);
+    ensure_eq!(FileOptions::read_to_string(path).await.stack()?, "atomic 2");
+
+    // reading ignores the mode
+    ensure_eq!(
+        FileOptions::read(path).mode(0o755).options,
+        ReadOrWrite::Read
+    );
+    tokio::fs::remove_file(path).await.stack()?;
+    Ok(())
+}
+
+async fn limited_reads() -> Result<()> {
+    let path = "./logs/limited.log";
+    FileOptions::write_str(path, "line 1\nline 2\nline 3\n")
+        .await
+        .stack()?;
+
+    let (v, truncated) = FileOptions::read_to_vec_limited(path, 100).await.stack()?;
+    ensure_eq!(v, b"line 1\nline 2\nline 3\n");
+    ensure!(!truncated);
+    let (v, truncated) = FileOptions::read_to_vec_limited(path, 21).await.stack()?;
+    ensure_eq!(v.len(), 21);
+    ensure!(!truncated);
+    let (v, truncated) = FileOptions::read_to_vec_limited(path, 8).await.stack()?;
+    ensure_eq!(v, b"line 1\nl");
+    ensure!(truncated);
+
+    // the tail starts at the next line boundary
+    let (v, truncated) = FileOptions::read_tail(path, 100).await.stack()?;
+    ensure_eq!(v, b"line 1\nline 2\nline 3\n");
+    ensure!(!truncated);
+    let (v, truncated) = FileOptions::read_tail(path, 10).await.stack()?;
+    ensure_eq!(v, b"line 3\n");
+    ensure!(truncated);
+    let (v, truncated) = FileOptions::read_tail(path, 14).await.stack()?;
+    ensure_eq!(v, b"line 2\nline 3\n");
+    ensure!(truncated);
+    let (v, _) = FileOptions::read_tail(path, 0).await.stack()?;
+    ensure!(v.is_empty());
+
+    // a single line is kept if there is no newline
+    FileOptions::write_str(path, "abcdefgh").await.stack()?;
+    let (v, truncated) = FileOptions::read_tail(path, 3).await.stack()?;
+    ensure_eq!(v, b"fgh");
+    ensure!(truncated);
+
+    // a character cut by the limit is removed, but invalid UTF-8 is an error
+    FileOptions::write_str(path, "ab\u{e9}").await.stack()?;
+    let (s, truncated) = FileOptions::read_to_string_limited(path, 3).await.stack()?;
+    ensure_eq!(s, "ab");
+    ensure!(truncated);
+    let (s, truncated) = FileOptions::read_to_string_limited(path, 4).await.stack()?;
+    ensure_eq!(s, "ab\u{e9}");
+    ensure!(!truncated);
+    FileOptions::write(path)
+        .write_all(b"a\xffbc")
+        .await
+        .stack()?;
+    ensure!(FileOptions::read_to_string_limited(path, 3).await.is_err());
+
+    // large files are not read completely
+    let file = FileOptions::write(path).acquire_file().await.stack()?;
+    file.set_len(1 << 40).await.stack()?;
+    close_file(file).await.stack()?;
+    let (v, truncated) = FileOptions::read_to_vec_limited(path, 1000).await.stack()?;
+    ensure_eq!(v.len(), 1000);
+    ensure!(truncated);
+    let (v, truncated) = FileOptions::read_tail(path, 1000).await.stack()?;
+    ensure_eq!(v.len(), 1000);
+    ensure!(truncated);
+    tokio::fs::remove_file(path).await.stack()?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Config {
+    name: String,
+    port: u16,
+    peers: Vec<String>,
+}
+
+impl Config {
+    fn example() -> Self {
+        Self {
+            name: "node".to_owned(),
+            port: 8080,
+            peers: vec!["a".to_owned(), "b".to_owned()],
+        }
+    }
+}
+
+async fn json() -> Result<()> {
+    let config = Config::example();
+    FileOptions::write_json("./logs/config.json", &config)
+        .await
+        .stack()?;
+    ensure_eq!(
+        FileOptions::read_json::<Config>("./logs/config.json")
+            .await
+            .stack()?,
+        config.clone()
+    );
+    // pretty by default
+    ensure!(FileOptions::read_to_string("./logs/config.json")
+        .await
+        .stack()?
+        .contains("\n  \"port\": 8080"));
+
+    FileOptions::write_atomic("./logs/config.json")
+        .write_json_with(&config, false)
+        .await
+        .stack()?;
+    ensure_eq!(
+        FileOptions::read_to_string("./logs/config.json")
+            .await
+            .stack()?,
+        r#"{"name":"node","port":8080,"peers":["a","b"]}"#
+    );
+    FileOptions::write2_json("./logs", "config.json", &config)
+        .await
+        .stack()?;
+    ensure_eq!(
+        FileOptions::read2_json::<Config>("./logs", "config.json")
+            .await
+            .stack()?,
+        config.clone()
+    );
+
+    // the errors have the path and where deserialization failed
+    FileOptions::write_str(
+        "./logs/config.json",
+        "{\n  \"name\": \"node\",\n  \"port\": true\n}",
+    )
+    .await
+    .stack()?;
+    let e = FileOptions::read_json::<Config>("./logs/config.json")
+        .await
+        .unwrap_err();
+    let e = format!("{e:?}");
+    ensure!(e.contains("config.json"));
+    ensure!(e.contains("line 3 column 14"));
+    ensure!(FileOptions::read_json::<Config>("./logs/nonexistent.json")
+        .await
+        .is_err());
+    tokio::fs::remove_file("./logs/config.json").await.stack()?;
+    Ok(())
+}
+
+async fn toml_and_yaml() -> Result<()> {
+    let config = Config::example();
+    FileOptions::write_toml("./logs/config.toml", &config)
+        .await
+        .stack()?;
+    ensure_eq!(
+        FileOptions::read_toml::<Config>("./logs/config.toml")
+            .await
+            .stack()?,
+        config.clone()
+    );
+    FileOptions::write("./logs/config.toml")
+        .write_toml_with(&config, false)
+        .await
+        .stack()?;
+    ensure_eq!(
+        FileOptions::read2_toml::<Config>("./logs", "config.toml")
+            .await
+            .stack()?,
+        config.clone()
+    );
+    FileOptions::write_str("./logs/config.toml", "name = \"node\"\nport = true\n")
+        .await
+        .stack()?;
+    let e = format!(
+        "{:?}",
+        FileOptions::read_toml::<Config>("./logs/config.toml")
+            .await
+            .unwrap_err()
+    );
+    ensure!(e.contains("config.toml"));
+    ensure!(e.contains("line 2"));
+    tokio::fs::remove_file("./logs/config.toml").await.stack()?;
+
+    FileOptions::write2_yaml("./logs", "config.yaml", &config)
+        .await
+        .stack()?;
+    ensure_eq!(
+        FileOptions::read_yaml::<Config>("./logs/config.yaml")
+            .await
+            .stack()?,
+        config.clone()
+    );
+    ensure_eq!(
+        FileOptions::read2_yaml::<Config>("./logs", "config.yaml")
+            .await
+            .stack()?,
+        config
+    );
+    FileOptions::write_str("./logs/config.yaml", "name: node\nport: true\n")
+        .await
+        .stack()?;
+    let e = format!(
+        "{:?}",
+        FileOptions::read_yaml::<Config>("./logs/config.yaml")
+            .await
+            .unwrap_err()
+    );
+    ensure!(e.contains("config.yaml"));
+    ensure!(e.contains("line 2"));
+    tokio::fs::remove_file("./logs/config.yaml").await.stack()?;
+    Ok(())
+}
+
+async fn append_lines() -> Result<()> {
+    let path = "./logs/journal.log";
+    let _ = tokio::fs::remove_file(path).await;
+    FileOptions::append_line(path, "first").await.stack()?;
+    FileOptions::append_line(path, "second").await.stack()?;
+    ensure_eq!(
+        FileOptions::read_to_string(path).await.stack()?,
+        "first\nsecond\n"
+    );
+    tokio::fs::remove_file(path).await.stack()?;
+
+    // concurrent appenders and line writers do not interleave within lines
+    let mut tasks = vec![];
+    for task in 0..8u8 {
+        tasks.push(tokio::spawn(async move {
+            let line = String::from_utf8(vec![b'a' + task; 1000]).unwrap();
+            if task < 4 {
+                for _ in 0..50 {
+                    FileOptions::append_line(path, &line).await.stack()?;
+                }
+            } else {
+                let mut writer = FileOptions::line_writer(path).await.stack()?;
+                for i in 0..500 {
+                    writer.write_line(&line).await.stack()?;
+                    if i == 250 {
+                        writer.flush().await.stack()?;
+                    }
+                }
+                writer.close().await.stack()?;
+            }
+            Result::<()>::Ok(())
+        }));
+    }
+    for task in tasks {
+        task.await.stack()?.stack()?;
+    }
+    let s = FileOptions::read_to_string(path).await.stack()?;
+    let mut counts = [0usize; 8];
+    for line in s.lines() {
+        ensure_eq!(line.len(), 1000);
+        let c = line.as_bytes()[0];
+        ensure!(line.bytes().all(|b| b == c));
+        counts[usize::from(c - b'a')] += 1;
+    }
+    ensure_eq!(counts, [50, 50, 50, 50, 500, 500, 500, 500]);
+
+    // lines are buffered until they are flushed
+    let mut writer = FileOptions::line_writer(path).await.stack()?;
+    ensure_eq!(writer.path(), std::path::Path::new(path));
+    writer.write_line("buffered").await.stack()?;
+    ensure!(!FileOptions::read_to_string(path)
+        .await
+        .stack()?
+        .ends_with("buffered\n"));
+    writer.flush().await.stack()?;
+    ensure!(FileOptions::read_to_string(path)
+        .await
+        .stack()?
+        .ends_with("buffered\n"));
+    writer.close().await.stack()?;
+
+    // buffered lines are written in the background when the writer is dropped
+    let mut writer = FileOptions::line_writer(path).await.stack()?;
+    writer.write_line("dropped").await.stack()?;
+    drop(writer);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !FileOptions::read_to_string(path)
+        .await
+        .stack()?
+        .ends_with("buffered\ndropped\n")
+    {
+        ensure!(Instant::now() < deadline);
+        sleep(Duration::from_millis(10)).await;
+    }
+    tokio::fs::remove_file(path).await.stack()?;
+    Ok(())
+}
+
+/// Parses a SHA-256 digest in hexadecimal
+fn digest(hex: &str) -> [u8; 32] {
+    let mut digest = [0; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[(2 * i)..(2 * i + 2)], 16).unwrap();
+    }
+    digest
+}
+
+async fn checksums() -> Result<()> {
+    let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    let empty = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    FileOptions::write_str("./logs/checksum.log", "abc")
+        .await
+        .stack()?;
+    ensure_eq!(
+        FileOptions::sha256("./logs/checksum.log").await.stack()?,
+        digest(abc)
+    );
+    FileOptions::verify_sha256("./logs/checksum.log", &digest(abc))
+        .await
+        .stack()?;
+    let e = FileOptions::verify_sha256("./logs/checksum.log", &digest(empty))
+        .await
+        .unwrap_err();
+    let e = format!("{e:?}");
+    ensure!(e.contains(abc) && e.contains(empty));
+    ensure!(FileOptions::sha256("./logs/nonexistent.log").await.is_err());
+
+    FileOptions::write_str("./logs/checksum.log", "")
+        .await
+        .stack()?;
+    ensure_eq!(
+        FileOptions::sha256("./logs/checksum.log").await.stack()?,
+        digest(empty)
+    );
+
+    // larger than the buffer
+    let contents: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+    FileOptions::write_bytes("./logs/checksum.log", &contents)
+        .await
+        .stack()?;
+    let copied = FileOptions::copy_with_sha256(
+        "./logs/checksum.log",
+        "./logs/checksum_copy.log",
+        CopyOptions::default(),
+    )
+    .await
+    .stack()?;
+    ensure_eq!(
+        FileOptions::read_to_vec("./logs/checksum_copy.log")
+            .await
+            .stack()?,
+        contents
+    );
+    ensure_eq!(
+        FileOptions::sha256("./logs/checksum.log").await.stack()?,
+        copied
+    );
+    FileOptions::verify_sha256("./logs/checksum_copy.log", &copied)
+        .await
+        .stack()?;
+    tokio::fs::remove_file("./logs/checksum.log")
+        .await
+        .stack()?;
+    tokio::fs::remove_file("./logs/checksum_copy.log")
+        .await
+        .stack()?;
+    Ok(())
+}
+
+/// The last `n` lines of `contents` in the simplest way, to compare with
+/// `FileOptions::tail_lines`
+fn naive_tail_lines(contents: &[u8], n: usize) -> Vec<String> {
+    if contents.is_empty() {
+        return vec![]
+    }
+    let contents = contents.strip_suffix(b"\n").unwrap_or(contents);
+    let lines: Vec<String> = contents
+        .split(|b| *b == b'\n')
+        .map(|line| String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(line)).into_owned())
+        .collect();
+    lines[lines.len().saturating_sub(n)..].to_vec()
+}
+
+/// Writes `contents` and checks the last `n` lines
+async fn check_tail(path: &str, contents: &[u8], n: usize, expected: &[&str]) -> Result<()> {
+    FileOptions::write_bytes(path, contents).await.stack()?;
+    let lines = FileOptions::tail_lines(path, n).await.stack()?;
+    let expected: Vec<String> = expected.iter().map(|s| s.to_string()).collect();
+    ensure_eq!(lines, expected);
+    Ok(())
+}
+
+async fn tail_lines() -> Result<()> {
+    let path = "./logs/tail.log";
+    check_tail(path, b"", 3, &[]).await.stack()?;
+    check_tail(path, b"a\nb\nc\n", 0, &[]).await.stack()?;
+    check_tail(path, b"a\nb\nc\n", 2, &["b", "c"])
+        .await
+        .stack()?;
+    check_tail(path, b"a\nb\nc\n", 3, &["a", "b", "c"])
+        .await
+        .stack()?;
+    check_tail(path, b"a\nb\nc\n", 10, &["a", "b", "c"])
+        .await
+        .stack()?;
+    // missing trailing newline
+    check_tail(path, b"a\nb\nc", 2, &["b", "c"]).await.stack()?;
+    check_tail(path, b"abc", 1, &["abc"]).await.stack()?;
+    // empty lines and line endings
+    check_tail(path, b"\n", 2, &[""]).await.stack()?;
+    check_tail(path, b"\n\n\n", 2, &["", ""]).await.stack()?;
+    check_tail(path, b"a\n\nb\n\n", 2, &["b", ""])
+        .await
+        .stack()?;
+    check_tail(path, b"a\r\nb\r\n", 5, &["a", "b"])
+        .await
+        .stack()?;
+    // non-UTF-8 lines are lossy
+    check_tail(path, b"a\n\xffb\n", 1, &["\u{fffd}b"])
+        .await
+        .stack()?;
+
+    // lines across block boundaries, lines longer than a block, and newlines at the
+    // ends of blocks
+    let block = 64 * 1024;
+    let mut cases: Vec<Vec<u8>> = vec![];
+    let mut lines = vec![];
+    for i in 0..500 {
+        lines.extend_from_slice(format!("line {i} {}\n", "x".repeat(i * 7)).as_bytes());
+    }
+    cases.push(lines.clone());
+    lines.pop();
+    cases.push(lines);
+    let mut long = vec![b'y'; 3 * block + 5];
+    long.extend_from_slice(b"\nshort\n");
+    long.extend(vec![b'z'; block]);
+    cases.push(long);
+    for newline_at in [block - 1, block, block + 1] {
+        let mut v = vec![b'w'; 2 * block];
+        v[newline_at] = b'\n';
+        let len = v.len();
+        v[len - newline_at - 1] = b'\n';
+        cases.push(v);
+    }
+    for contents in cases {
+        FileOptions::write_bytes(path, &contents).await.stack()?;
+        for n in [1, 2, 3, 10, 100, 499, 500, 501, 1000] {
+            let lines = FileOptions::tail_lines(path, n).await.stack()?;
+            ensure_eq!(lines, naive_tail_lines(&contents, n));
+        }
+    }
+    // only the end of a large file is read
+    let file = FileOptions::write(path).acquire_file().await.stack()?;
+    file.set_len(1 << 40).await.stack()?;
+    close_file(file).await.stack()?;
+    let mut file = FileOptions::new(path, ReadOrWrite::write(false, true))
+        .acquire_file()
+        .await
+        .stack()?;
+    file.write_all(b"\nsecond to last\nlast").await.stack()?;
+    close_file(file).await.stack()?;
+    ensure_eq!(FileOptions::tail_lines(path, 2).await.stack()?, vec![
+        "second to last".to_owned(),
+        "last".to_owned()
+    ]);
+
+    ensure!(FileOptions::tail_lines("./logs/nonexistent.log", 1)
+        .await
+        .is_err());
+    tokio::fs::remove_file(path).await.stack()?;
+    Ok(())
+}
+
+async fn copies() -> Result<()> {
+    let src = "./logs/entrypoint.sh";
+    let dst = "./logs/entrypoint_copy.sh";
+    let _ = tokio::fs::remove_file(dst).await;
+    FileOptions::write_executable(src)
+        .write_all("#!/bin/sh\necho hello\n")
+        .await
+        .stack()?;
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(src)
+        .stack()?
+        .set_modified(mtime)
+        .stack()?;
+    let modified = |path: &'static str| async move {
+        tokio::fs::metadata(path).await.stack()?.modified().stack()
+    };
+
+    // the default only copies the contents
+    FileOptions::copy(src, dst).await.stack()?;
+    ensure_eq!(
+        FileOptions::read_to_string(dst).await.stack()?,
+        "#!/bin/sh\necho hello\n"
+    );
+    ensure!(file_mode(dst).await.stack()? & 0o111 == 0);
+    ensure!(modified(dst).await.stack()? != mtime);
+
+    tokio::fs::remove_file(dst).await.stack()?;
+    FileOptions::copy_with(src, dst, CopyOptions {
+        preserve_mode: true,
+        preserve_mtime: false,
+    })
+    .await
+    .stack()?;
+    ensure_eq!(file_mode(dst).await.stack()?, 0o755);
+    ensure!(modified(dst).await.stack()? != mtime);
+
+    tokio::fs::remove_file(dst).await.stack()?;
+    FileOptions::copy_with(src, dst, CopyOptions {
+        preserve_mode: false,
+        preserve_mtime: true,
+    })
+    .await
+    .stack()?;
+    ensure!(file_mode(dst).await.stack()? & 0o111 == 0);
+    ensure_eq!(modified(dst).await.stack()?, mtime);
+
+    // an existing destination gets the mode and time of the source
+    FileOptions::write_secret(dst)
+        .write_all("old")
+        .await
+        .stack()?;
+    FileOptions::copy_with(src, dst, CopyOptions {
+        preserve_mode: true,
+        preserve_mtime: true,
+    })
+    .await
+    .stack()?;
+    ensure_eq!(file_mode(dst).await.stack()?, 0o755);
+    ensure_eq!(modified(dst).await.stack()?, mtime);
+    ensure_eq!(
+        FileOptions::read_to_string(dst).await.stack()?,
+        "#!/bin/sh\necho hello\n"
+    );
+    tokio::fs::remove_file(src).await.stack()?;
+    tokio::fs::remove_file(dst).await.stack()?;
+    Ok(())
+}
+
+async fn temp_workspaces() -> Result<()> {
+    let exists = |path: PathBuf| async move { tokio::fs::try_exists(path).await.stack() };
+
+    let workspace = TempWorkspace::new("file_options").await.stack()?;
+    let path = workspace.path().to_owned();
+    ensure!(path.starts_with(std::env::temp_dir()));
+    ensure!(path
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .starts_with("file_options-"));
+    workspace.file("a/b.txt").write_all("b").await.stack()?;
+    ensure_eq!(
+        FileOptions::read_to_string(path.join("a/b.txt"))
+            .await
+            .stack()?,
+        "b"
+    );
+    workspace.close().await.stack()?;
+    ensure!(!exists(path).await.stack()?);
+
+    // dropping removes the directory as well
+    let workspace = TempWorkspace::new_in("./logs", "workspace").await.stack()?;
+    let path = workspace.path().to_owned();
+    ensure!(path.starts_with("./logs"));
+    workspace.file("c.txt").write_all("c").await.stack()?;
+    drop(workspace);
+    ensure!(!exists(path).await.stack()?);
+
+    // unless it is kept
+    let mut workspace = TempWorkspace::new_in("./logs", "workspace").await.stack()?;
+    let path = workspace.path().to_owned();
+    workspace.keep();
+    workspace.close().await.stack()?;
+    ensure!(exists(path.clone()).await.stack()?);
+    tokio::fs::remove_dir_all(path).await.stack()?;
+
+    // or dropped while panicking
+    for keep_on_panic in [true, false] {
+        let res = tokio::spawn(async move {
+            let workspace = TempWorkspace::new_in("./logs", "workspace")
+                .await
+                .unwrap()
+                .keep_on_panic(keep_on_panic);
+            workspace.file("d.txt").write_all("d").await.unwrap();
+            FileOptions::write_str(
+                "./logs/workspace_path.txt",
+                &workspace.path().to_string_lossy(),
+            )
+            .await
+            .unwrap();
+            panic!("test failure");
+        })
+        .await;
+        ensure!(res.unwrap_err().is_panic());
+        let path = PathBuf::from(
+            FileOptions::read_to_string("./logs/workspace_path.txt")
+                .await
+                .stack()?,
+        );
+        ensure_eq!(exists(path.join("d.txt")).await.stack()?, keep_on_panic);
+        if keep_on_panic {
+            tokio::fs::remove_dir_all(path).await.stack()?;
+        }
+    }
+    tokio::fs::remove_file("./logs/workspace_path.txt")
+        .await
+        .stack()?;
+    Ok(())
+}
+
+async fn file_watchers() -> Result<()> {
+    let workspace = TempWorkspace::new_in("./logs", "watch").await.stack()?;
+    let ready = workspace.path().join("ready");
+
+    // times out while the marker does not exist
+    let start = Instant::now();
+    let e = wait_for_file(&ready, Duration::from_millis(300))
+        .await
+        .unwrap_err();
+    ensure!(e.is_timeout());
+    ensure!(start.elapsed() >= Duration::from_millis(300));
+
+    // cancelling a wait leaves nothing behind
+    ensure!(tokio::time::timeout(
+        Duration::from_millis(50),
+        wait_for_file(&ready, Duration::from_secs(10))
+    )
+    .await
+    .is_err());
+
+    // resolves once the marker is written by someone else
+    let writer = {
+        let ready = ready.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(300)).await;
+            FileOptions::write_str(ready, "ok").await
+        })
+    };
+    let start = Instant::now();
+    wait_for_file(&ready, Duration::from_secs(10))
+        .await
+        .stack()?;
+    ensure!(start.elapsed() >= Duration::from_millis(250));
+    writer.await.stack()?.stack()?;
+
+    // and immediately if it already exists
+    let start = Instant::now();
+    wait_for_file(&ready, Duration::ZERO).await.stack()?;
+    ensure!(start.elapsed() < Duration::from_millis(100));
+
+    // a long interval is cut short by the timeout
+    let start = Instant::now();
+    let e = wait_for_file_with_interval(
+        workspace.path().join("never"),
+        Duration::from_millis(200),
+        Duration::from_secs(10),
+    )
+    .await
+    .unwrap_err();
+    ensure!(e.is_timeout());
+    ensure!(start.elapsed() < Duration::from_secs(5));
+
+    let marker = workspace.path().join("marker");
+    let mut watcher = watch_file(&marker).interval(Duration::from_millis(10));
+    ensure_eq!(watcher.path(), marker);
+
+    // nothing happened yet, and cancelling does not lose the next event
+    ensure!(
+        tokio::time::timeout(Duration::from_millis(100), watcher.next_event())
+            .await
+            .is_err()
+    );
+    FileOptions::write_str(&marker, "a").await.stack()?;
+    ensure_eq!(watcher.next_event().await.stack()?, FileEvent::Created);
+    FileOptions::append_line(&marker, "bc").await.stack()?;
+    ensure_eq!(watcher.next_event().await.stack()?, FileEvent::Modified);
+    tokio::fs::remove_file(&marker).await.stack()?;
+    ensure_eq!(watcher.next_event().await.stack()?, FileEvent::Removed);
+    ensure!(
+        tokio::time::timeout(Duration::from_millis(100), watcher.next_event())
+            .await
+            .is_err()
+    );
+
+    // changes before the watcher was created are not reported
+    FileOptions::write_str(&marker, "a").await.stack()?;
+    let mut watcher = watch_file(&marker).interval(Duration::from_millis(10));
+    ensure!(
+        tokio::time::timeout(Duration::from_millis(100), watcher.next_event())
+            .await
+            .is_err()
+    );
+
+    // the watcher is a stream
+    tokio::fs::remove_file(&marker).await.stack()?;
+    ensure_eq!(watcher.next().await.stack()?.stack()?, FileEvent::Removed);
+
+    // the error of the initial query is returned first
+    let mut watcher = watch_file(ready.join("below_a_file"));
+    let e = watcher.next_event().await.unwrap_err();
+    ensure!(format!("{e:?}").contains("watch_file"));
+
+    workspace.close().await.stack()?;
+    Ok(())
+}
+
+async fn atomic_writes() -> Result<()> {
+    for name in atomic_temp_files().await.stack()? {
+        tokio::fs::remove_file(format!("./logs/{name}"))
+            .await
+            .stack()?;
+    }
+    FileOptions::write2_atomic_str("./logs", "atomic.log", "old")
+        .await
+        .stack()?;
+    ensure_eq!(
+        FileOptions::read_to_string("./logs/atomic.log")
+            .await
+            .stack()?,
+        "old"
+    );
+
+    // kill a writer in the middle of writing
+    let mut writer = Command::new_os_str(std::env::current_exe().stack()?)
+        .arg("--atomic-writer")
+        .run()
+        .await
+        .stack()?;
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while atomic_temp_files().await.stack()?.is_empty() {
+        if Instant::now() > deadline {
+            bail!("the writer did not start writing");
+        }
+        sleep(Duration::from_millis(1)).await;
+    }
+    writer.terminate().await.stack()?;
+    // the destination has the old or the new contents, but nothing in between
+    let contents = FileOptions::read_to_vec("./logs/atomic.log")
+        .await
+        .stack()?;
+    ensure!(
+        (contents == b"old")
+            || ((contents.len() == ATOMIC_LEN) && contents.iter().all(|b| *b == b'b'))
+    );
+
+    // recent temporary files are kept since they may be from a running writer,
+    // but old ones are removed by the next atomic write
+    let left = atomic_temp_files().await.stack()?;
+    debug!("temporary files left by the killed writer: {left:?}");
+    FileOptions::write_str("./logs/.atomic.log.tmp-fresh", "")
+        .await
+        .stack()?;
+    for name in &left {
+        let file = std::fs::File::options()
+            .write(true)
+            .open(format!("./logs/{name}"))
+            .stack()?;
+        file.set_modified(SystemTime::now() - Duration::from_secs(3600))
+            .stack()?;
+    }
+    FileOptions::write_atomic("./logs/atomic.log")
+        .write_all("new")
+        .await
+        .stack()?;
+    ensure_eq!(
+        FileOptions::read_to_string("./logs/atomic.log")
+            .await
+            .stack()?,
+        "new"
+    );
+    let fresh = ".atomic.log.tmp-fresh".to_owned();
+    ensure_eq!(atomic_temp_files().await.stack()?, vec![fresh]);
+    tokio::fs::remove_file("./logs/.atomic.log.tmp-fresh")
+        .await
+        .stack()?;
+
+    // atomic writes cannot append or go through a `File`
+    let mut options = FileOptions::write_atomic("./logs/atomic.log");
+    ensure!(options.acquire_file().await.is_err());
+    options.options = ReadOrWrite::Write(WriteOptions {
+        create: true,
+        append: true,
+        atomic: true,
+        create_parents: false,
+        mode: None,
+    });
+    let e = options.write_all("x").await.unwrap_err();
+    ensure!(format!("{e:?}").contains("mutually exclusive"));
+    ensure_eq!(
+        FileOptions::read_to_string("./logs/atomic.log")
+            .await
+            .stack()?,
+        "new"
+    );
+    Ok(())
+}
+
 #[tokio::main]
 #[rustfmt::skip]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt().with_env_filter("debug").init();
 
+    if std::env::args().nth(1).as_deref() == Some("--atomic-writer") {
+        return atomic_writer().await
+    }
+
     remove_files_in_dir("./logs/", &["example.log"])
         .await
         .stack()?;
@@ -71,7 +991,7 @@ async fn main() -> Result<()> {
         e.to_string().contains(r#"
     FileOptions::write_str
     FileOptions::acquire_file()
-    FileOptions { path: "./nonexistent/example.log", options: Write(WriteOptions { create: true, append: false }) }.preacquire() could not acquire directory
+    FileOptions { path: "./nonexistent/example.log", options: Write(WriteOptions { create: true, append: false, atomic: false, create_parents: false, mode: None }) }.preacquire() could not acquire directory
     acquire_dir_path(dir_path: "./nonexistent")"#)
     );
 
@@ -118,6 +1038,19 @@ async fn main() -> Result<()> {
         "test part 2"
     );
 
+    atomic_writes().await.stack()?;
+    create_parents().await.stack()?;
+    modes().await.stack()?;
+    limited_reads().await.stack()?;
+    json().await.stack()?;
+    toml_and_yaml().await.stack()?;
+    append_lines().await.stack()?;
+    checksums().await.stack()?;
+    tail_lines().await.stack()?;
+    copies().await.stack()?;
+    temp_workspaces().await.stack()?;
+    file_watchers().await.stack()?;
+
     info!("test completed successfully");
 
     Ok(())
@@ -0,0 +1,100 @@
+//! Checks `SuperDockerfile::with_healthcheck` and `wait_healthy_api`
+
+use std::{str::FromStr, time::Duration};
+
+use stacked_errors::{ensure, Result, StackableErr};
+use super_orchestrator::api_docker::{
+    wait_healthy_api, Dockerfile, HealthcheckOptions, SuperContainer, SuperDockerfile, SuperNetwork,
+};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+const TIMEOUT: Duration = Duration::from_secs(30);
+const POLL: Duration = Duration::from_millis(200);
+
+async fn run_healthchecks(sn: &SuperNetwork) -> Result<()> {
+    wait_healthy_api(&sn.docker_name("healthy"), TIMEOUT, POLL)
+        .await
+        .stack()?;
+
+    let err = wait_healthy_api(&sn.docker_name("unhealthy"), TIMEOUT, POLL)
+        .await
+        .unwrap_err();
+    let err = format!("{err:?}");
+    ensure!(err.contains("is unhealthy"));
+    // the health log is included
+    ensure!(err.contains("not ready"));
+
+    let err = wait_healthy_api(&sn.docker_name("no_healthcheck"), TIMEOUT, POLL)
+        .await
+        .unwrap_err();
+    ensure!(format!("{err:?}").contains("has no healthcheck defined"));
+
+    // still starting
+    let err = wait_healthy_api(&sn.docker_name("slow"), Duration::from_millis(500), POLL)
+        .await
+        .unwrap_err();
+    ensure!(err.is_timeout());
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let image = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .build_image()
+        .await
+        .stack()?;
+    let healthcheck_image = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .with_healthcheck(
+            HealthcheckOptions {
+                interval: Some(Duration::from_secs(1)),
+                retries: Some(1),
+                ..Default::default()
+            },
+            ["sh", "-c", "test -f /ready || (echo not ready; exit 1)"],
+        )
+        .stack()?
+        .build_image()
+        .await
+        .stack()?;
+    let slow_image = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .with_healthcheck(
+            HealthcheckOptions {
+                interval: Some(Duration::from_secs(60)),
+                ..Default::default()
+            },
+            ["true"],
+        )
+        .stack()?
+        .build_image()
+        .await
+        .stack()?;
+
+    let mut sn = SuperNetwork::new_with_uuid("test_healthcheck", "./logs");
+    sn.add_container(
+        SuperContainer::new("healthy", healthcheck_image.clone()).cmd([
+            "sh",
+            "-c",
+            "touch /ready && sleep 300",
+        ]),
+    )
+    .stack()?;
+    sn.add_container(SuperContainer::new("unhealthy", healthcheck_image).cmd(["sleep", "300"]))
+        .stack()?;
+    sn.add_container(SuperContainer::new("no_healthcheck", image).cmd(["sleep", "300"]))
+        .stack()?;
+    sn.add_container(SuperContainer::new("slow", slow_image).cmd(["sleep", "300"]))
+        .stack()?;
+    sn.run_all().await.stack()?;
+
+    let res = run_healthchecks(&sn).await;
+    sn.terminate_all().await;
+    res.stack()?;
+
+    Ok(())
+}
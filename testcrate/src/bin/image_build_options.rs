@@ -0,0 +1,68 @@
+//! Checks the validation of `ImageBuildOptions`, this does not need docker to
+//! be running.
+
+use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::api_docker::{
+    BuilderVersion, Dockerfile, ImageBuildOptions, ImageBuildOptionsBuilder, SuperDockerfile,
+};
+
+/// Returns the debug string of the error from building the options
+fn build_err(builder: ImageBuildOptionsBuilder) -> String {
+    format!("{:?}", builder.build().unwrap_err())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let opts = ImageBuildOptions::builder()
+        .memory(1 << 30)
+        .memswap(2 << 30)
+        .cpuperiod(100_000)
+        .cpuquota(50_000)
+        .nocache(true)
+        .buildarg("KEY", "value")
+        .label("key", "value")
+        .build()
+        .stack()?;
+    ensure_eq!(opts.memory, Some(1 << 30));
+    ensure!(opts.nocache);
+    ensure_eq!(opts.buildargs.get("KEY").map(String::as_str), Some("value"));
+    ensure!(ImageBuildOptions::builder()
+        .memory(1 << 30)
+        .memswap(-1)
+        .build()
+        .is_ok());
+
+    // the errors name the conflicting fields
+    ensure!(build_err(
+        ImageBuildOptions::builder()
+            .memory(2 << 30)
+            .memswap(1 << 30)
+    )
+    .contains("`memswap` (1073741824) is smaller than `memory` (2147483648)"));
+    ensure!(
+        build_err(ImageBuildOptions::builder().memswap(1 << 30)).contains("`memory` is not set")
+    );
+    ensure!(build_err(ImageBuildOptions::builder().cpuquota(50_000))
+        .contains("`cpuquota` is set but `cpuperiod` is not"));
+    ensure!(build_err(
+        ImageBuildOptions::builder()
+            .squash(true)
+            .version(BuilderVersion::BuilderBuildKit)
+    )
+    .contains("`squash`"));
+    ensure!(build_err(ImageBuildOptions::builder().shmsize(0)).contains("`shmsize`"));
+
+    // raw options are validated when building
+    ensure!(
+        SuperDockerfile::new(Dockerfile::name_tag("alpine:3.21"), None)
+            .with_build_opts(ImageBuildOptions {
+                cpuquota: Some(50_000),
+                ..Default::default()
+            })
+            .into_bollard_args()
+            .await
+            .is_err()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,40 @@
+//! Checks that the `image_name` of a `SuperDockerfile` tags the built image
+
+use std::str::FromStr;
+
+use stacked_errors::{ensure_eq, Result, StackableErr};
+use super_orchestrator::api_docker::{Dockerfile, SuperDockerfile, SuperImage, SuperRunOptions};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+const IMAGE_NAME: &str = "super_orchestrator_image_name_test:latest";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let image = SuperDockerfile::new(
+        Dockerfile::name_tag(BASE_CONTAINER),
+        Some(IMAGE_NAME.to_owned()),
+    )
+    .append_dockerfile_instructions(["RUN echo named > /named"])
+    .build_image()
+    .await
+    .stack()?;
+    ensure_eq!(image.reference(), Some(IMAGE_NAME));
+
+    // the image can be used by its name
+    let completed = SuperImage::new(IMAGE_NAME.to_owned())
+        .run_once(SuperRunOptions {
+            cmd: vec!["cat".to_owned(), "/named".to_owned()],
+            ..Default::default()
+        })
+        .await
+        .stack()?;
+    completed.assert_success().stack()?;
+    ensure_eq!(completed.stdout_as_utf8().stack()?, "named\n");
+
+    Ok(())
+}
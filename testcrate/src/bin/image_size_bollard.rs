@@ -0,0 +1,56 @@
+//! Checks `SuperImage::history`, `total_size`, and the failure message of
+//! `assert_max_size` with an image that has a deliberately large layer
+
+use std::str::FromStr;
+
+use stacked_errors::{ensure, Result, StackableErr};
+use super_orchestrator::api_docker::{Dockerfile, SuperDockerfile};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+const LARGE_LAYER_SIZE: u64 = 20_000_000;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let image = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .append_dockerfile_instructions([format!(
+            "RUN head -c {LARGE_LAYER_SIZE} /dev/urandom > /large_layer"
+        )])
+        .build_image()
+        .await
+        .stack()?;
+
+    let history = image.history().await.stack()?;
+    // the newest layer is first
+    let large_layer = history.first().stack()?;
+    ensure!(
+        large_layer.created_by.contains("/large_layer"),
+        format!("unexpected history: {history:?}")
+    );
+    ensure!(large_layer.size >= LARGE_LAYER_SIZE);
+
+    let total_size = image.total_size().await.stack()?;
+    ensure!(total_size >= LARGE_LAYER_SIZE);
+    image.assert_max_size(total_size).await.stack()?;
+
+    let err = image
+        .assert_max_size(LARGE_LAYER_SIZE / 2)
+        .await
+        .err()
+        .stack_err("the image should have been too large")?;
+    let err = format!("{err:?}");
+    ensure!(
+        err.contains("the largest layers are"),
+        format!("unexpected error: {err}")
+    );
+    ensure!(
+        err.contains("/large_layer"),
+        format!("unexpected error: {err}")
+    );
+
+    Ok(())
+}
@@ -0,0 +1,267 @@
+//! Checks that a large `Tarball` build context spills to a temporary file
+//! instead of staying in memory, and that `SuperDockerfile::build_image`
+//! streams it to the daemon. This does not need docker to be running, the
+//! daemon is faked. Linux only because of the peak memory measurement.
+
+use std::{
+    collections::HashSet,
+    io::{Read, Write},
+    path::PathBuf,
+};
+
+use stacked_errors::{bail_locationless, ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::{
+    api_docker::{
+        docker_socket::{self, DockerConnection},
+        SuperDockerfile, Tarball,
+    },
+    cli_docker::Dockerfile,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+const LARGE_FILE: &str = "./logs/large_context.bin";
+const RECEIVED_FILE: &str = "./logs/large_context_received.tar";
+const IMAGE_ID: &str = "sha256:1111111111111111111111111111111111111111111111111111111111111111";
+const LARGE_SIZE: u64 = 300 << 20;
+const SPILL_THRESHOLD: u64 = 16 << 20;
+// far below the size of the context
+const MAX_PEAK_MEMORY: u64 = 64 << 20;
+
+/// Returns the peak resident memory of this process in bytes
+fn peak_memory() -> Result<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").stack()?;
+    let line = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .stack()?;
+    let kib: u64 = line.trim().trim_end_matches("kB").trim().parse().stack()?;
+    Ok(kib * 1024)
+}
+
+/// Resets the peak resident memory to the current resident memory
+fn reset_peak_memory() -> Result<()> {
+    std::fs::write("/proc/self/clear_refs", "5").stack()
+}
+
+/// Serves a fake daemon connection, the body of a build request is written to
+/// [RECEIVED_FILE] without keeping it in memory
+async fn serve_fake_daemon(stream: TcpStream) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await.stack()?;
+    let mut chunked = false;
+    let mut content_len = 0u64;
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.stack()?;
+        let line = line.trim_end().to_ascii_lowercase();
+        if line.is_empty() {
+            break
+        }
+        if line == "transfer-encoding: chunked" {
+            chunked = true;
+        } else if let Some(len) = line.strip_prefix("content-length:") {
+            content_len = len.trim().parse().stack()?;
+        }
+    }
+
+    let body = if request_line.contains("/version") {
+        r#"{"Version":"24.0.9","ApiVersion":"1.43","MinAPIVersion":"1.12"}"#.to_owned()
+    } else if request_line.contains("/build") {
+        let mut file = tokio::fs::File::create(RECEIVED_FILE).await.stack()?;
+        if chunked {
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.stack()?;
+                let len = u64::from_str_radix(line.trim(), 16).stack()?;
+                if len > 0 {
+                    tokio::io::copy(&mut (&mut stream).take(len), &mut file)
+                        .await
+                        .stack()?;
+                }
+                // the end of the chunk, or of the trailers
+                let mut line = String::new();
+                stream.read_line(&mut line).await.stack()?;
+                if len == 0 {
+                    break
+                }
+            }
+        } else {
+            tokio::io::copy(&mut (&mut stream).take(content_len), &mut file)
+                .await
+                .stack()?;
+        }
+        file.flush().await.stack()?;
+        format!(
+            "{{\"stream\":\"Step 1/1 : FROM alpine\"}}\n{{\"aux\":{{\"ID\":\"{IMAGE_ID}\"}}}}\n"
+        )
+    } else {
+        bail_locationless!("unexpected request {request_line:?}")
+    };
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: \
+         close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await.stack()?;
+    stream.shutdown().await.stack()
+}
+
+/// Starts a fake daemon, returns its host
+async fn fake_daemon() -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.stack()?;
+    let addr = listener.local_addr().stack()?;
+    tokio::task::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::task::spawn(async move {
+                if let Err(e) = serve_fake_daemon(stream).await {
+                    tracing::error!("{e:?}");
+                }
+            });
+        }
+    });
+    Ok(format!("tcp://{addr}"))
+}
+
+/// Returns the paths of the entries of the received build context
+fn received_paths(compressed: bool) -> Result<Vec<String>> {
+    let file = std::io::BufReader::new(std::fs::File::open(RECEIVED_FILE).stack()?);
+    let reader: Box<dyn Read> = if compressed {
+        Box::new(flate2::read::GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    let mut archive = tar::Archive::new(reader);
+    let mut paths = vec![];
+    for entry in archive.entries().stack()? {
+        paths.push(
+            entry
+                .stack()?
+                .path()
+                .stack()?
+                .to_string_lossy()
+                .into_owned(),
+        );
+    }
+    Ok(paths)
+}
+
+/// Builds an image with a large context against the fake daemon, checking
+/// that the context is streamed instead of read into memory
+async fn build_large_context(compressed: bool) -> Result<()> {
+    let mut tarball = Tarball::default().with_spill_threshold(Some(SPILL_THRESHOLD));
+    tarball
+        .append_file("large.bin", &mut std::fs::File::open(LARGE_FILE).stack()?)
+        .stack()?;
+    ensure!(tarball.is_spilled());
+
+    reset_peak_memory().stack()?;
+    let image = SuperDockerfile::new_with_tar(Dockerfile::name_tag("alpine"), None, tarball)
+        .compress_context(compressed)
+        .build_image()
+        .await
+        .stack()?;
+    let peak = peak_memory().stack()?;
+    ensure!(
+        peak < MAX_PEAK_MEMORY,
+        format!(
+            "peak memory of {peak} bytes while building with a {LARGE_SIZE} byte context \
+             (compressed: {compressed})"
+        )
+    );
+    ensure_eq!(image.get_image_id(), IMAGE_ID);
+
+    let paths = received_paths(compressed).stack()?;
+    ensure!(
+        paths.iter().any(|path| path == "large.bin"),
+        format!("{paths:?}")
+    );
+    ensure!(
+        paths.iter().any(|path| path.ends_with("super.dockerfile")),
+        format!("{paths:?}")
+    );
+    std::fs::remove_file(RECEIVED_FILE).stack()?;
+    Ok(())
+}
+
+/// Returns the temporary files of spilled tarballs
+fn spill_files() -> Result<HashSet<PathBuf>> {
+    let mut res = HashSet::new();
+    for entry in std::fs::read_dir(std::env::temp_dir()).stack()? {
+        let path = entry.stack()?.path();
+        if path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("super_orchestrator_tarball_"))
+        {
+            res.insert(path);
+        }
+    }
+    Ok(res)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // small spilled tarballs read back the same
+    let mut tarball = Tarball::default().with_spill_threshold(Some(0));
+    tarball.append_file_bytes("a.txt", 0o644, b"a").stack()?;
+    ensure!(tarball.is_spilled());
+    let bytes = tarball.into_tarball().stack()?;
+    let mut archive = tar::Archive::new(bytes.as_slice());
+    let mut entry = archive.entries().stack()?.next().stack()?.stack()?;
+    let mut content = String::new();
+    entry.read_to_string(&mut content).stack()?;
+    ensure_eq!(content, "a");
+
+    // not a sparse file, because `tar` only archives the data of those
+    let mut file = std::io::BufWriter::new(std::fs::File::create(LARGE_FILE).stack()?);
+    let chunk: Vec<u8> = (0..(1 << 20)).map(|i| i as u8).collect();
+    for _ in 0..(LARGE_SIZE / chunk.len() as u64) {
+        file.write_all(&chunk).stack()?;
+    }
+    file.flush().stack()?;
+    drop(file);
+
+    let before = spill_files().stack()?;
+    let mut tarball = Tarball::default().with_spill_threshold(Some(SPILL_THRESHOLD));
+    tarball
+        .append_file("large.bin", &mut std::fs::File::open(LARGE_FILE).stack()?)
+        .stack()?;
+    ensure!(tarball.is_spilled());
+    tarball
+        .append_file_bytes("small.txt", 0o644, b"1")
+        .stack()?;
+    // replacing an entry rewrites the whole archive
+    tarball
+        .append_file_bytes("small.txt", 0o644, b"2")
+        .stack()?;
+    ensure!(tarball.is_spilled());
+    ensure!(tarball.contains_path("large.bin"));
+    let spilled = spill_files().stack()?;
+    ensure_eq!(spilled.difference(&before).count(), 1);
+
+    let peak = peak_memory().stack()?;
+    ensure!(
+        peak < MAX_PEAK_MEMORY,
+        format!("peak memory of {peak} bytes while assembling a {LARGE_SIZE} byte context")
+    );
+
+    // the temporary file is removed
+    drop(tarball);
+    ensure_eq!(spill_files().stack()?, before);
+
+    let host = fake_daemon().await.stack()?;
+    docker_socket::init_with(DockerConnection::Http(host))
+        .await
+        .stack()?;
+    build_large_context(false).await.stack()?;
+    build_large_context(true).await.stack()?;
+    ensure_eq!(spill_files().stack()?, before);
+
+    std::fs::remove_file(LARGE_FILE).stack()?;
+
+    Ok(())
+}
@@ -0,0 +1,127 @@
+//! A fan-in with `NetMessengerServer`, where three worker processes send
+//! their results to one coordinator. `cargo r --bin net_fan_in` runs the
+//! coordinator, which starts the workers as copies of this binary. In a
+//! container network the workers would be the entrypoints of other
+//! containers connecting to e.g. "coordinator:26000".
+
+use std::time::Duration;
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use stacked_errors::{ensure_eq, Result, StackableErr};
+use super_orchestrator::{
+    net_message::{NetMessenger, NetMessengerServer},
+    Command,
+};
+use tracing::info;
+
+const NUM_WORKERS: u64 = 3;
+const TIMEOUT: Duration = Duration::from_secs(30);
+const STD_TRIES: u64 = 300;
+const STD_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Parser, Debug)]
+#[command(about)]
+struct Args {
+    /// Runs as the worker with this index instead of the coordinator
+    #[arg(long)]
+    worker: Option<u64>,
+    /// The address of the coordinator for workers
+    #[arg(long)]
+    coordinator: Option<String>,
+}
+
+/// What the coordinator sends to each worker
+#[derive(Debug, Serialize, Deserialize)]
+struct Task {
+    start: u64,
+    end: u64,
+}
+
+/// What each worker sends back
+#[derive(Debug, Serialize, Deserialize)]
+struct WorkResult {
+    worker: u64,
+    sum: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .init();
+    let args = Args::parse();
+
+    if let Some(worker) = args.worker {
+        let coordinator = args.coordinator.stack_err("workers need `--coordinator`")?;
+        worker_runner(worker, &coordinator).await
+    } else {
+        coordinator_runner().await
+    }
+}
+
+async fn coordinator_runner() -> Result<()> {
+    let mut server = NetMessengerServer::bind("127.0.0.1:0").await.stack()?;
+    let addr = server.local_addr().stack()?.to_string();
+
+    let exe = std::env::current_exe().stack()?;
+    let mut workers = vec![];
+    for i in 0..NUM_WORKERS {
+        workers.push(
+            Command::new_os_str(&exe)
+                .args(["--worker", &i.to_string(), "--coordinator", &addr])
+                .debug(true)
+                .run()
+                .await
+                .stack()?,
+        );
+    }
+
+    // the workers connect in any order, so the tasks are handed out by what the
+    // workers say they are
+    let mut messengers = server
+        .accept_n(NUM_WORKERS as usize, TIMEOUT)
+        .await
+        .stack()?;
+    for (nm, addr) in &mut messengers {
+        let worker: u64 = nm.recv().await.stack()?;
+        info!("worker {worker} connected from {addr}");
+        nm.send::<Task>(&Task {
+            start: worker * 100,
+            end: (worker + 1) * 100,
+        })
+        .await
+        .stack()?;
+    }
+    let mut total = 0;
+    for (nm, _) in &mut messengers {
+        let res: WorkResult = nm.recv().await.stack()?;
+        info!("worker {} sent {}", res.worker, res.sum);
+        total += res.sum;
+    }
+    ensure_eq!(total, (0..(NUM_WORKERS * 100)).sum::<u64>());
+
+    for worker in workers {
+        worker
+            .wait_with_output_timeout(TIMEOUT)
+            .await
+            .stack()?
+            .assert_success()
+            .stack()?;
+    }
+    info!("all {NUM_WORKERS} workers finished with a total of {total}");
+    Ok(())
+}
+
+async fn worker_runner(worker: u64, coordinator: &str) -> Result<()> {
+    let mut nm = NetMessenger::connect(STD_TRIES, STD_DELAY, coordinator)
+        .await
+        .stack()?;
+    nm.send::<u64>(&worker).await.stack()?;
+    let task: Task = nm.recv().await.stack()?;
+    let sum = (task.start..task.end).sum();
+    nm.send::<WorkResult>(&WorkResult { worker, sum })
+        .await
+        .stack()?;
+    Ok(())
+}
@@ -0,0 +1,1364 @@
+//! Checks `NetMessenger` over localhost
+
+use std::{io::SeekFrom, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use stacked_errors::{bail, ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::{
+    close_file,
+    net_message::{
+        broadcast, tag_hash, Codec, Compression, NetDatagram, NetMessenger, NetMessengerOptions,
+        NetMessengerServer, TlsConfig, FRAME_COMPRESSED, FRAME_MESSAGE, FRAME_PING,
+        PROTOCOL_VERSION, STREAM_CHUNK_TAG, STREAM_END_TAG, STREAM_START_TAG,
+    },
+    Command, FileOptions,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    select,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a foreign peer sends and expects for an `Example`
+const JSON_FIXTURE: &str = r#"{"name":"fixture","values":[1,2,3],"nested":null}"#;
+
+/// A minimal Python peer, it receives an `Example` and replies with it after
+/// appending a value
+const PYTHON_CLIENT: &str = r#"
+import hashlib, json, socket, struct, sys, time
+
+def recv_exact(sock, n):
+    buf = b""
+    while len(buf) < n:
+        chunk = sock.recv(n - len(buf))
+        if not chunk:
+            raise EOFError()
+        buf += chunk
+    return buf
+
+def tag_hash(tag):
+    return hashlib.sha3_256(tag.encode()).digest()[:16]
+
+def send(sock, tag, msg):
+    body = json.dumps(msg).encode()
+    sock.sendall(bytes([0]) + tag_hash(tag) + struct.pack("<Q", len(body)) + body)
+
+def recv(sock, tag):
+    # skips keepalive pings
+    while recv_exact(sock, 1) != bytes([0]):
+        pass
+    if recv_exact(sock, 16) != tag_hash(tag):
+        raise ValueError("unexpected tag")
+    (length,) = struct.unpack("<Q", recv_exact(sock, 8))
+    return json.loads(recv_exact(sock, length))
+
+# the other side may not be listening yet
+for i in range(100):
+    try:
+        sock = socket.create_connection((sys.argv[1], int(sys.argv[2])))
+        break
+    except ConnectionRefusedError:
+        time.sleep(0.05)
+else:
+    raise ConnectionRefusedError()
+# an empty crate version, no schema, and no compression
+sock.sendall(b"SONM" + bytes([4]) + struct.pack("<H", 0) + bytes([0, 0]))
+if recv_exact(sock, 5) != b"SONM" + bytes([4]):
+    raise ValueError("unexpected handshake")
+(length,) = struct.unpack("<H", recv_exact(sock, 2))
+recv_exact(sock, length)
+if recv_exact(sock, 2) != bytes([0, 0]):
+    raise ValueError("the other side has a schema or compression")
+example = recv(sock, "example")
+example["values"].append(4)
+send(sock, "reply", example)
+"#;
+
+/// Returns a localhost address with a free port
+async fn free_addr() -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.stack()?;
+    Ok(listener.local_addr().stack()?.to_string())
+}
+
+/// Returns a connected pair of messengers
+async fn messenger_pair() -> Result<(NetMessenger, NetMessenger)> {
+    let addr = free_addr().await.stack()?;
+    let listen = tokio::spawn({
+        let addr = addr.clone();
+        async move { NetMessenger::listen(&addr, TIMEOUT).await }
+    });
+    let connected = NetMessenger::connect(10, Duration::from_millis(100), &addr)
+        .await
+        .stack()?;
+    let listened = listen.await.stack()?.stack()?;
+    Ok((listened, connected))
+}
+
+/// Returns the handshake of a foreign peer without a schema
+fn raw_handshake(version: u8, crate_version: &str, compression: u8) -> Vec<u8> {
+    let mut res = b"SONM".to_vec();
+    res.push(version);
+    res.extend_from_slice(&(crate_version.len() as u16).to_le_bytes());
+    res.extend_from_slice(crate_version.as_bytes());
+    res.push(0);
+    res.push(compression);
+    res
+}
+
+/// Returns a messenger connected to a raw stream that sends `version` in the
+/// handshake, for sending broken messages
+async fn messenger_and_raw(version: u8) -> Result<(Result<NetMessenger>, TcpStream)> {
+    messenger_and_raw_handshake(NetMessengerOptions::new(), &raw_handshake(version, "", 0)).await
+}
+
+/// Returns a messenger with `options` connected to a raw stream that sends
+/// `handshake`
+async fn messenger_and_raw_handshake(
+    options: NetMessengerOptions,
+    handshake: &[u8],
+) -> Result<(Result<NetMessenger>, TcpStream)> {
+    let addr = free_addr().await.stack()?;
+    let listen = tokio::spawn({
+        let addr = addr.clone();
+        async move { options.listen(&addr, TIMEOUT).await }
+    });
+    let mut i = 0;
+    let mut raw = loop {
+        match TcpStream::connect(&addr).await {
+            Ok(raw) => break raw,
+            Err(e) => {
+                ensure!(i < 100, format!("could not connect: {e:?}"));
+                i += 1;
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        }
+    };
+    raw.write_all(handshake).await.stack()?;
+    let mut theirs = [0u8; 5];
+    raw.read_exact(&mut theirs).await.stack()?;
+    ensure_eq!(&theirs[..4], b"SONM");
+    ensure_eq!(theirs[4], PROTOCOL_VERSION);
+    let len = raw.read_u16_le().await.stack()?;
+    let mut crate_version = vec![0u8; usize::from(len)];
+    raw.read_exact(&mut crate_version).await.stack()?;
+    ensure!(!crate_version.is_empty());
+    // the schema flag and compression
+    ensure_eq!(raw.read_u8().await.stack()?, 0);
+    raw.read_u8().await.stack()?;
+    Ok((listen.await.stack()?, raw))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Example {
+    name: String,
+    values: Vec<u64>,
+    nested: Option<Box<Example>>,
+}
+
+async fn round_trip() -> Result<()> {
+    let (mut a, mut b) = messenger_pair().await.stack()?;
+    let example = Example {
+        name: "outer".to_owned(),
+        values: vec![0, 1, u64::MAX],
+        nested: Some(Box::new(Example {
+            name: "inner".to_owned(),
+            values: vec![],
+            nested: None,
+        })),
+    };
+    // both directions, and several messages in a row
+    for i in 0..3u64 {
+        a.send::<Example>(&example).await.stack()?;
+        a.send::<u64>(&i).await.stack()?;
+        ensure_eq!(b.recv::<Example>().await.stack()?, example);
+        ensure_eq!(b.recv::<u64>().await.stack()?, i);
+        b.send::<String>(&format!("reply {i}")).await.stack()?;
+        ensure_eq!(a.recv::<String>().await.stack()?, format!("reply {i}"));
+    }
+
+    // the type is checked
+    a.send::<u64>(&0).await.stack()?;
+    let e = b.recv::<String>().await.unwrap_err();
+    ensure!(format!("{e:?}").contains("did not match expected type"));
+
+    // incompatible versions fail the handshake
+    let (res, _raw) = messenger_and_raw(PROTOCOL_VERSION.wrapping_add(1))
+        .await
+        .stack()?;
+    let e = res.unwrap_err();
+    ensure!(format!("{e:?}").contains("version"));
+    Ok(())
+}
+
+/// Returns the results of `listen` and `connect` to each other with the
+/// schemas `a` and `b`
+async fn schema_pair(
+    a: Option<&str>,
+    b: Option<&str>,
+) -> Result<(Result<NetMessenger>, Result<NetMessenger>)> {
+    let options = |schema: Option<&str>| match schema {
+        Some(schema) => NetMessengerOptions::new().schema(schema),
+        None => NetMessengerOptions::new(),
+    };
+    let addr = free_addr().await.stack()?;
+    let listen = tokio::spawn({
+        let addr = addr.clone();
+        let options = options(a);
+        async move { options.listen(&addr, TIMEOUT).await }
+    });
+    let connected = options(b)
+        .connect(10, Duration::from_millis(100), &addr)
+        .await;
+    Ok((listen.await.stack()?, connected))
+}
+
+async fn handshake() -> Result<()> {
+    // the same schema
+    let (a, b) = schema_pair(Some("v1"), Some("v1")).await.stack()?;
+    let (mut a, mut b) = (a.stack()?, b.stack()?);
+    a.send::<u64>(&1).await.stack()?;
+    ensure_eq!(b.recv::<u64>().await.stack()?, 1);
+
+    // a different schema fails on both sides, and the error has both schemas
+    let (a, b) = schema_pair(Some("v1"), Some("v2")).await.stack()?;
+    for e in [a.unwrap_err(), b.unwrap_err()] {
+        let e = format!("{e:?}");
+        ensure!(e.contains("v1") && e.contains("v2"), e);
+    }
+
+    // a schema on only one side also fails
+    let (a, b) = schema_pair(None, Some("v1")).await.stack()?;
+    ensure!(format!("{:?}", a.unwrap_err()).contains("the other side has the schema Some"));
+    ensure!(format!("{:?}", b.unwrap_err()).contains("the other side has the schema None"));
+
+    // a different crate version fails, but foreign peers without one do not
+    let (res, _raw) = messenger_and_raw_handshake(
+        NetMessengerOptions::new(),
+        &raw_handshake(PROTOCOL_VERSION, "0.0.0", 0),
+    )
+    .await
+    .stack()?;
+    let e = format!("{:?}", res.unwrap_err());
+    ensure!(
+        e.contains("version 0.0.0 of `super_orchestrator`, but this side uses version"),
+        e
+    );
+    let (res, _raw) = messenger_and_raw(PROTOCOL_VERSION).await.stack()?;
+    res.stack()?;
+
+    // connecting to something that accepts but never answers times out
+    let listener = TcpListener::bind("127.0.0.1:0").await.stack()?;
+    let addr = listener.local_addr().stack()?.to_string();
+    let e = NetMessengerOptions::new()
+        .connect_timeout(Duration::from_millis(200))
+        .connect(10, Duration::from_millis(100), &addr)
+        .await
+        .unwrap_err();
+    ensure!(e.is_timeout(), format!("{e:?}"));
+    drop(listener);
+
+    // the server checks the schema of each connection
+    let mut server = NetMessengerServer::bind("127.0.0.1:0")
+        .await
+        .stack()?
+        .options(NetMessengerOptions::new().schema("v1"));
+    let addr = server.local_addr().stack()?.to_string();
+    for (schema, ok) in [("v2", false), ("v1", true)] {
+        let addr = addr.clone();
+        let connect = tokio::spawn(async move {
+            NetMessengerOptions::new()
+                .schema(schema)
+                .connect(10, Duration::from_millis(100), &addr)
+                .await
+        });
+        let accepted = server.accept(TIMEOUT).await;
+        let connected = connect.await.stack()?;
+        ensure_eq!(accepted.is_ok(), ok);
+        ensure_eq!(connected.is_ok(), ok);
+    }
+    Ok(())
+}
+
+async fn timeouts() -> Result<()> {
+    // a timeout before anything of a message arrived leaves the messenger usable
+    let (mut a, mut b) = messenger_pair().await.stack()?;
+    let e = a
+        .recv_timeout::<String>(Duration::from_millis(100))
+        .await
+        .unwrap_err();
+    ensure!(format!("{e:?}").contains("timed out in the header phase"));
+    ensure!(!a.is_poisoned());
+    b.send_timeout::<String>(&"hello".to_owned(), TIMEOUT)
+        .await
+        .stack()?;
+    ensure!(a.recv_timeout::<String>(TIMEOUT).await.stack()? == "hello");
+
+    // a timeout in the middle of a message is continued from
+    let (a, mut raw) = messenger_and_raw(PROTOCOL_VERSION).await.stack()?;
+    let mut a = a.stack()?;
+    raw.write_u8(FRAME_MESSAGE).await.stack()?;
+    raw.write_all(&super_orchestrator::type_hash::<String>())
+        .await
+        .stack()?;
+    raw.write_all(&[6, 0, 0]).await.stack()?;
+    let e = a
+        .recv_timeout::<String>(Duration::from_millis(100))
+        .await
+        .unwrap_err();
+    ensure!(format!("{e:?}").contains("timed out in the length phase"));
+    ensure!(!a.is_poisoned());
+    raw.write_all(&[0, 0, 0, 0, 0, 5, b'h']).await.stack()?;
+    let e = a
+        .recv_timeout::<String>(Duration::from_millis(100))
+        .await
+        .unwrap_err();
+    ensure!(format!("{e:?}").contains("timed out in the body phase"));
+    raw.write_all(b"ello").await.stack()?;
+    ensure_eq!(a.recv::<String>().await.stack()?, "hello");
+
+    // a receive that fails in the middle of a message poisons the messenger
+    raw.write_u8(FRAME_MESSAGE).await.stack()?;
+    drop(raw);
+    let e = a.recv::<String>().await.unwrap_err();
+    ensure!(format!("{e:?}").contains("header phase"));
+    ensure!(a.is_poisoned());
+
+    // the other side does not read a message that is larger than the buffers
+    let (mut a, _b) = messenger_pair().await.stack()?;
+    let large = vec![0u8; 32 << 20];
+    let e = a
+        .send_timeout::<Vec<u8>>(&large, Duration::from_millis(300))
+        .await
+        .unwrap_err();
+    ensure!(format!("{e:?}").contains("timed out in the body phase"));
+    ensure!(a.is_poisoned());
+    Ok(())
+}
+
+async fn cancel_safety() -> Result<()> {
+    let (a, mut raw) = messenger_and_raw(PROTOCOL_VERSION).await.stack()?;
+    let mut a = a.stack()?;
+    let (shutdown_tx, mut shutdown_rx) = tokio::sync::mpsc::channel::<()>(1);
+
+    // the frame arrives in pieces, and the `recv` is cancelled by a shutdown signal
+    // after each piece
+    let mut frame = vec![FRAME_MESSAGE];
+    frame.extend_from_slice(&super_orchestrator::type_hash::<String>());
+    frame.extend_from_slice(&(6u64).to_le_bytes());
+    frame.extend_from_slice(&[5, b'h', b'e', b'l', b'l', b'o']);
+    let pieces = [
+        &frame[..1],
+        &frame[1..9],
+        &frame[9..20],
+        &frame[20..30],
+        &frame[30..],
+    ];
+    for (i, piece) in pieces.into_iter().enumerate() {
+        // only the last piece completes the message
+        let last = i == pieces.len() - 1;
+        raw.write_all(piece).await.stack()?;
+        raw.flush().await.stack()?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown_tx.send(()).await.stack()?;
+        // `recv` first reads what is available
+        select! {
+            biased;
+            res = a.recv::<String>() => {
+                ensure!(last, format!("received early: {res:?}"));
+                let msg = res.stack()?;
+                ensure_eq!(msg, "hello");
+            }
+            _ = shutdown_rx.recv() => {
+                ensure!(!last, "cancelled instead of receiving");
+            }
+        }
+    }
+    ensure!(!a.is_poisoned());
+
+    // the next message is received normally
+    raw.write_all(&frame).await.stack()?;
+    ensure_eq!(a.recv::<String>().await.stack()?, "hello");
+
+    // with a real peer and a large message that is cancelled many times
+    let (mut a, mut b) = messenger_pair().await.stack()?;
+    let large: Vec<u8> = (0..(4 << 20)).map(|i: u32| i as u8).collect();
+    let send = tokio::spawn({
+        let large = large.clone();
+        async move {
+            b.send::<Vec<u8>>(&large).await.stack()?;
+            b.send::<u64>(&1).await.stack()?;
+            Ok::<_, stacked_errors::Error>(b)
+        }
+    });
+    let mut cancelled = 0;
+    let received = loop {
+        select! {
+            res = a.recv::<Vec<u8>>() => break res.stack()?,
+            _ = tokio::task::yield_now() => cancelled += 1,
+        }
+    };
+    ensure!(cancelled > 0);
+    ensure!(received == large);
+    ensure_eq!(a.recv::<u64>().await.stack()?, 1);
+    send.await.stack()?.stack()?;
+    Ok(())
+}
+
+async fn max_message_size() -> Result<()> {
+    // large messages within the limit work
+    let (a, mut b) = messenger_pair().await.stack()?;
+    let mut a = a.max_message_size(16 << 20);
+    let large: Vec<u8> = (0..(10 << 20)).map(|i: u32| i as u8).collect();
+    let send = tokio::spawn(async move {
+        b.send::<Vec<u8>>(&large).await.stack()?;
+        Ok::<_, stacked_errors::Error>((b, large))
+    });
+    let received = a.recv::<Vec<u8>>().await.stack()?;
+    let (mut b, large) = send.await.stack()?.stack()?;
+    ensure!(received == large);
+
+    // the sending side checks before writing anything
+    let mut b_small = {
+        b.send::<u64>(&1).await.stack()?;
+        ensure_eq!(a.recv::<u64>().await.stack()?, 1);
+        b.max_message_size(1000)
+    };
+    let e = b_small.send::<Vec<u8>>(&vec![0u8; 2000]).await.unwrap_err();
+    ensure!(format!("{e:?}").contains("larger than the `max_message_size` of 1000 bytes"));
+    ensure!(!b_small.is_poisoned());
+    b_small.send::<Vec<u8>>(&vec![0u8; 900]).await.stack()?;
+    ensure_eq!(a.recv::<Vec<u8>>().await.stack()?.len(), 900);
+
+    // the receiving side checks before allocating
+    let mut a = a.max_message_size(1000);
+    b_small
+        .max_message_size(1 << 20)
+        .send::<Vec<u8>>(&vec![0u8; 2000])
+        .await
+        .stack()?;
+    let e = a.recv::<Vec<u8>>().await.unwrap_err();
+    ensure!(format!("{e:?}").contains("advertised a message of 2002 bytes"));
+    ensure!(a.is_poisoned());
+
+    let (a, mut raw) = messenger_and_raw(PROTOCOL_VERSION).await.stack()?;
+    let mut a = a.stack()?;
+    raw.write_u8(FRAME_MESSAGE).await.stack()?;
+    raw.write_all(&super_orchestrator::type_hash::<Vec<u8>>())
+        .await
+        .stack()?;
+    raw.write_u64_le(u64::MAX).await.stack()?;
+    let e = a.recv::<Vec<u8>>().await.unwrap_err();
+    ensure!(format!("{e:?}").contains(&format!("advertised a message of {} bytes", u64::MAX)));
+    Ok(())
+}
+
+async fn buffers() -> Result<()> {
+    let (a, mut raw) = messenger_and_raw(PROTOCOL_VERSION).await.stack()?;
+    let mut a = a.stack()?.with_initial_capacity(4096);
+    let stats = a.buffer_stats();
+    ensure!(stats.send_capacity >= 4096);
+    ensure!(stats.recv_capacity >= 4096);
+
+    // the small frame after a large one only has its own length
+    let large = vec![7u8; 100_000];
+    a.send::<Vec<u8>>(&large).await.stack()?;
+    a.send::<u64>(&1).await.stack()?;
+    let mut lens = vec![];
+    for _ in 0..2 {
+        ensure_eq!(raw.read_u8().await.stack()?, FRAME_MESSAGE);
+        let mut id = [0u8; 16];
+        raw.read_exact(&mut id).await.stack()?;
+        let len = usize::try_from(raw.read_u64_le().await.stack()?).stack()?;
+        let mut body = vec![0u8; len];
+        raw.read_exact(&mut body).await.stack()?;
+        lens.push(len);
+    }
+    // postcard uses a varint for the length of the `Vec` and for the `u64`
+    ensure_eq!(lens, vec![100_003, 1]);
+
+    raw.write_u8(FRAME_MESSAGE).await.stack()?;
+    raw.write_all(&super_orchestrator::type_hash::<Vec<u8>>())
+        .await
+        .stack()?;
+    raw.write_u64_le(100_003).await.stack()?;
+    raw.write_all(&[0xa0, 0x8d, 0x06]).await.stack()?;
+    raw.write_all(&large).await.stack()?;
+    ensure!(a.recv::<Vec<u8>>().await.stack()? == large);
+
+    // the buffers are shrunk back to the initial capacity, but the high-water
+    // marks stay
+    let stats = a.buffer_stats();
+    ensure!(stats.send_high_water_mark >= 100_003);
+    ensure!(stats.recv_high_water_mark >= 100_003);
+    ensure!(stats.send_capacity < 100_003);
+    ensure!(stats.recv_capacity < 100_003);
+
+    a.shrink_to(0);
+    let stats = a.buffer_stats();
+    ensure_eq!(stats.send_capacity, 0);
+    ensure_eq!(stats.recv_capacity, 0);
+    ensure!(stats.send_high_water_mark >= 100_003);
+
+    // the messenger still works with empty buffers
+    a.send::<u64>(&2).await.stack()?;
+    let mut frame = [0u8; 26];
+    raw.read_exact(&mut frame).await.stack()?;
+    ensure_eq!(frame[17..].to_vec(), vec![1, 0, 0, 0, 0, 0, 0, 0, 2]);
+    Ok(())
+}
+
+/// Writes a frame like `NetMessenger::send_untyped` would
+async fn write_raw_frame(raw: &mut TcpStream, tag: &str, body: &[u8]) -> Result<()> {
+    raw.write_u8(FRAME_MESSAGE).await.stack()?;
+    raw.write_all(&tag_hash(tag)).await.stack()?;
+    raw.write_u64_le(body.len() as u64).await.stack()?;
+    raw.write_all(body).await.stack()?;
+    Ok(())
+}
+
+async fn streams() -> Result<()> {
+    // the smaller chunk size of both sides is used
+    let (a, b) = messenger_pair().await.stack()?;
+    let mut a = a.stream_chunk_size(64 << 10);
+    let mut b = b.stream_chunk_size(16 << 10);
+    let data = incompressible(1 << 20);
+    let send = tokio::spawn({
+        let data = data.clone();
+        async move {
+            let mut sent = vec![];
+            let mut progress = |done, total| sent.push((done, total));
+            a.send_stream(&data[..], data.len() as u64, Some(&mut progress))
+                .await
+                .stack()?;
+            Ok::<_, stacked_errors::Error>((a, sent))
+        }
+    });
+    let mut received = vec![];
+    let mut calls = vec![];
+    let mut progress = |done, total| calls.push((done, total));
+    let len = b
+        .recv_stream(&mut received, Some(&mut progress))
+        .await
+        .stack()?;
+    let (mut a, sent) = send.await.stack()?.stack()?;
+    ensure_eq!(len, 1 << 20);
+    ensure!(received == data);
+    ensure_eq!(calls.len(), 64);
+    ensure_eq!(calls.last().copied(), Some((1 << 20, 1 << 20)));
+    ensure_eq!(sent, calls);
+    // normal messages work afterwards
+    a.send::<u64>(&1).await.stack()?;
+    ensure_eq!(b.recv::<u64>().await.stack()?, 1);
+
+    // cancelling before the stream starts does not poison
+    ensure!(tokio::time::timeout(
+        Duration::from_millis(100),
+        b.recv_stream(&mut Vec::new(), None)
+    )
+    .await
+    .is_err());
+    ensure!(!b.is_poisoned());
+    let send = tokio::spawn(async move {
+        a.send_stream(&[1u8, 2, 3][..], 3, None).await.stack()?;
+        Ok::<_, stacked_errors::Error>(a)
+    });
+    let mut received = vec![];
+    ensure_eq!(b.recv_stream(&mut received, None).await.stack()?, 3);
+    ensure_eq!(received, [1, 2, 3]);
+    let mut a = send.await.stack()?.stack()?;
+
+    // a reader that ends early leaves both sides poisoned
+    let recv = tokio::spawn(async move {
+        let res = b.recv_stream(&mut Vec::new(), None).await;
+        (res, b.is_poisoned())
+    });
+    let e = a.send_stream(&data[..100], 200, None).await.unwrap_err();
+    ensure!(format!("{e:?}").contains("could not read the stream after 0 of 200 bytes"));
+    ensure!(a.is_poisoned());
+    drop(a);
+    let (res, poisoned) = tokio::time::timeout(TIMEOUT, recv).await.stack()?.stack()?;
+    ensure!(res.is_err() && poisoned);
+
+    // a bad checksum is an error, but the rest of the stream was read
+    let (a, mut raw) = messenger_and_raw(PROTOCOL_VERSION).await.stack()?;
+    let mut a = a.stack()?;
+    write_raw_frame(&mut raw, STREAM_START_TAG, &[3, 10])
+        .await
+        .stack()?;
+    write_raw_frame(&mut raw, STREAM_CHUNK_TAG, &[1, 2, 3])
+        .await
+        .stack()?;
+    write_raw_frame(&mut raw, STREAM_END_TAG, &[0])
+        .await
+        .stack()?;
+    write_raw_frame(&mut raw, "after", &[7]).await.stack()?;
+    let mut received = vec![];
+    let e = a.recv_stream(&mut received, None).await.unwrap_err();
+    ensure!(format!("{e:?}").contains("did not match the checksum"));
+    ensure!(!a.is_poisoned());
+    ensure_eq!(received, vec![1, 2, 3]);
+    ensure_eq!(a.recv_untyped::<u8>("after").await.stack()?, 7);
+    // the answer has the proposed chunk size, which is smaller than the default
+    let mut accept = [0u8; 1 + 16 + 8 + 1];
+    raw.read_exact(&mut accept).await.stack()?;
+    ensure_eq!(accept[17], 1);
+    ensure_eq!(accept[25], 10);
+
+    // a large sparse file
+    let src = "./logs/net_messenger_stream_src.bin";
+    let dst = "./logs/net_messenger_stream_dst.bin";
+    let len: u64 = 300 << 20;
+    let mut file = FileOptions::write(src).acquire_file().await.stack()?;
+    file.set_len(len).await.stack()?;
+    file.write_all(b"start").await.stack()?;
+    file.seek(SeekFrom::Start(len - 3)).await.stack()?;
+    file.write_all(b"end").await.stack()?;
+    close_file(file).await.stack()?;
+
+    let (mut a, mut b) = messenger_pair().await.stack()?;
+    let send = tokio::spawn(async move { a.send_file(FileOptions::read(src)).await });
+    ensure_eq!(b.recv_to_file(FileOptions::write(dst)).await.stack()?, len);
+    send.await.stack()?.stack()?;
+    let mut file = FileOptions::read(dst).acquire_file().await.stack()?;
+    ensure_eq!(file.metadata().await.stack()?.len(), len);
+    let mut start = [0u8; 5];
+    file.read_exact(&mut start).await.stack()?;
+    ensure_eq!(&start, b"start");
+    let mut end = [0u8; 3];
+    file.seek(SeekFrom::Start(len - 3)).await.stack()?;
+    file.read_exact(&mut end).await.stack()?;
+    ensure_eq!(&end, b"end");
+    tokio::fs::remove_file(src).await.stack()?;
+    tokio::fs::remove_file(dst).await.stack()?;
+
+    // the file options need to be for reading and writing
+    let (mut a, mut b) = messenger_pair().await.stack()?;
+    ensure!(a.send_file(FileOptions::write(dst)).await.is_err());
+    ensure!(b.recv_to_file(FileOptions::read(src)).await.is_err());
+    ensure!(!a.is_poisoned() && !b.is_poisoned());
+    Ok(())
+}
+
+/// Returns bytes that do not compress
+fn incompressible(len: usize) -> Vec<u8> {
+    (0..len.div_ceil(16))
+        .flat_map(|i| tag_hash(&i.to_string()))
+        .take(len)
+        .collect()
+}
+
+async fn compression() -> Result<()> {
+    // levels that `zstd` does not support are clamped
+    let options = NetMessengerOptions::new().enable_compression(Compression::Zstd(1000));
+    ensure!(format!("{options:?}").contains("Zstd(22)"));
+
+    let zstd = || NetMessengerOptions::new().enable_compression(Compression::Zstd(3));
+    let addr = free_addr().await.stack()?;
+    let listen = tokio::spawn({
+        let addr = addr.clone();
+        async move { zstd().listen(&addr, TIMEOUT).await }
+    });
+    let mut b = NetMessengerOptions::new()
+        .enable_compression(Compression::Zstd(1))
+        .connect(10, Duration::from_millis(100), &addr)
+        .await
+        .stack()?;
+    let mut a = listen.await.stack()?.stack()?;
+
+    // highly compressible, incompressible, and tiny messages in both directions
+    let compressible = vec![7u8; 8 << 20];
+    let random = incompressible(1 << 20);
+    for msg in [&compressible, &random, &vec![1u8]] {
+        a.send::<Vec<u8>>(msg).await.stack()?;
+        ensure!(&b.recv::<Vec<u8>>().await.stack()? == msg);
+        b.send::<Vec<u8>>(msg).await.stack()?;
+        ensure!(&a.recv::<Vec<u8>>().await.stack()? == msg);
+    }
+
+    // and with requests
+    let server = tokio::spawn(async move {
+        b.serve(|len: usize| async move { Ok(vec![3u8; len]) })
+            .await
+    });
+    let res = a
+        .request::<usize, Vec<u8>>(&(4 << 20), TIMEOUT)
+        .await
+        .stack()?;
+    ensure!(res == vec![3u8; 4 << 20]);
+    a.close().await.stack()?;
+    server.await.stack()?.stack()?;
+
+    // what is on the wire
+    let (a, mut raw) = messenger_and_raw_handshake(zstd(), &raw_handshake(PROTOCOL_VERSION, "", 1))
+        .await
+        .stack()?;
+    let mut a = a.stack()?;
+    for (msg, compressed) in [
+        (compressible.clone(), true),
+        (random.clone(), false),
+        (vec![7u8; 10], false),
+    ] {
+        a.send::<Vec<u8>>(&msg).await.stack()?;
+        let flag = if compressed { FRAME_COMPRESSED } else { 0 };
+        ensure_eq!(raw.read_u8().await.stack()?, FRAME_MESSAGE | flag);
+        let mut id = [0u8; 16];
+        raw.read_exact(&mut id).await.stack()?;
+        let len = usize::try_from(raw.read_u64_le().await.stack()?).stack()?;
+        // the length is of the compressed body
+        let smaller = len < msg.len();
+        ensure_eq!(smaller, compressed);
+        let mut body = vec![0u8; len];
+        raw.read_exact(&mut body).await.stack()?;
+    }
+
+    // a body that cannot be decompressed is an error, but the frame was read whole
+    raw.write_u8(FRAME_MESSAGE | FRAME_COMPRESSED)
+        .await
+        .stack()?;
+    raw.write_all(&super_orchestrator::type_hash::<String>())
+        .await
+        .stack()?;
+    raw.write_u64_le(3).await.stack()?;
+    raw.write_all(b"bad").await.stack()?;
+    let e = a.recv::<String>().await.unwrap_err();
+    ensure!(format!("{e:?}").contains("could not decompress"));
+    ensure!(!a.is_poisoned());
+
+    // the decompressed size is limited by `max_message_size`
+    let addr = free_addr().await.stack()?;
+    let listen = tokio::spawn({
+        let addr = addr.clone();
+        async move { zstd().listen(&addr, TIMEOUT).await }
+    });
+    let mut b = zstd()
+        .connect(10, Duration::from_millis(100), &addr)
+        .await
+        .stack()?;
+    let mut a = listen.await.stack()?.stack()?.max_message_size(1 << 20);
+    b.send::<Vec<u8>>(&vec![0u8; 4 << 20]).await.stack()?;
+    let e = a.recv::<Vec<u8>>().await.unwrap_err();
+    ensure!(format!("{e:?}").contains("larger than the `max_message_size`"));
+    b.send::<u64>(&1).await.stack()?;
+    ensure_eq!(a.recv::<u64>().await.stack()?, 1);
+
+    // a peer without compression is rejected in the handshake
+    let addr = free_addr().await.stack()?;
+    let listen = tokio::spawn({
+        let addr = addr.clone();
+        async move { zstd().listen(&addr, TIMEOUT).await }
+    });
+    let b = NetMessenger::connect(10, Duration::from_millis(100), &addr).await;
+    let a = listen.await.stack()?;
+    for e in [a.unwrap_err(), b.unwrap_err()] {
+        let e = format!("{e:?}");
+        ensure!(
+            e.contains("zstd compression") && e.contains("no compression"),
+            e
+        );
+    }
+
+    // and compressed frames from it are not misread
+    let (a, mut raw) = messenger_and_raw(PROTOCOL_VERSION).await.stack()?;
+    let mut a = a.stack()?;
+    raw.write_u8(FRAME_MESSAGE | FRAME_COMPRESSED)
+        .await
+        .stack()?;
+    raw.write_all(&super_orchestrator::type_hash::<String>())
+        .await
+        .stack()?;
+    let e = a.recv::<String>().await.unwrap_err();
+    ensure!(format!("{e:?}").contains("compression was not negotiated"));
+    Ok(())
+}
+
+async fn json_interop() -> Result<()> {
+    let fixture = Example {
+        name: "fixture".to_owned(),
+        values: vec![1, 2, 3],
+        nested: None,
+    };
+
+    // from a foreign peer
+    let (a, mut raw) = messenger_and_raw(PROTOCOL_VERSION).await.stack()?;
+    let mut a = a.stack()?.codec(Codec::Json);
+    raw.write_u8(FRAME_MESSAGE).await.stack()?;
+    raw.write_all(&tag_hash("example")).await.stack()?;
+    raw.write_u64_le(JSON_FIXTURE.len() as u64).await.stack()?;
+    raw.write_all(JSON_FIXTURE.as_bytes()).await.stack()?;
+    ensure_eq!(a.recv_untyped::<Example>("example").await.stack()?, fixture);
+
+    // to a foreign peer
+    a.send_untyped::<Example>("example", &fixture)
+        .await
+        .stack()?;
+    ensure_eq!(raw.read_u8().await.stack()?, FRAME_MESSAGE);
+    let mut id = [0u8; 16];
+    raw.read_exact(&mut id).await.stack()?;
+    ensure_eq!(id, tag_hash("example"));
+    let len = raw.read_u64_le().await.stack()?;
+    let mut body = vec![0u8; usize::try_from(len).stack()?];
+    raw.read_exact(&mut body).await.stack()?;
+    ensure_eq!(body, JSON_FIXTURE.as_bytes());
+
+    // the tag is checked
+    raw.write_u8(FRAME_MESSAGE).await.stack()?;
+    raw.write_all(&tag_hash("other")).await.stack()?;
+    let e = a.recv_untyped::<Example>("example").await.unwrap_err();
+    ensure!(format!("{e:?}").contains("did not match"));
+
+    // with a real Python peer if there is one
+    if Command::new("python3 --version")
+        .run_to_completion()
+        .await
+        .is_ok_and(|comres| comres.successful())
+    {
+        let addr = free_addr().await.stack()?;
+        let (host, port) = addr.rsplit_once(':').stack()?;
+        let client = Command::new("python3 -c")
+            .arg(PYTHON_CLIENT)
+            .args([host, port])
+            .run()
+            .await
+            .stack()?;
+        let mut b = NetMessenger::listen(&addr, TIMEOUT)
+            .await
+            .stack()?
+            .codec(Codec::Json);
+        b.send_untyped::<Example>("example", &fixture)
+            .await
+            .stack()?;
+        let reply = b.recv_untyped::<Example>("reply").await.stack()?;
+        ensure_eq!(reply.values, [1, 2, 3, 4]);
+        client
+            .wait_with_output()
+            .await
+            .stack()?
+            .assert_success()
+            .stack()?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Query {
+    n: u64,
+    delay_ms: u64,
+}
+
+async fn requests() -> Result<()> {
+    let (mut a, mut b) = messenger_pair().await.stack()?;
+    let server = tokio::spawn(async move {
+        b.serve(|query: Query| async move {
+            tokio::time::sleep(Duration::from_millis(query.delay_ms)).await;
+            if query.n == 13 {
+                bail!("unlucky number")
+            }
+            Ok(query.n * 2)
+        })
+        .await
+    });
+
+    let query = |n| Query { n, delay_ms: 0 };
+    ensure_eq!(
+        a.request::<Query, u64>(&query(3), TIMEOUT).await.stack()?,
+        6
+    );
+
+    // errors are sent back and the connection stays usable
+    let e = a
+        .request::<Query, u64>(&query(13), TIMEOUT)
+        .await
+        .unwrap_err();
+    let e = format!("{e:?}");
+    ensure!(e.contains("Handler") && e.contains("unlucky number"));
+    let e = a
+        .request::<String, u64>(&"wrong".to_owned(), TIMEOUT)
+        .await
+        .unwrap_err();
+    ensure!(format!("{e:?}").contains("TypeMismatch"));
+    let e = a
+        .request::<Query, String>(&query(1), TIMEOUT)
+        .await
+        .unwrap_err();
+    ensure!(format!("{e:?}").contains("response type did not match"));
+    ensure!(!a.is_poisoned());
+
+    // concurrent requests, where the later one is answered first
+    let slow = a
+        .send_request::<Query>(&Query {
+            n: 1,
+            delay_ms: 300,
+        })
+        .await
+        .stack()?;
+    let fast = a.send_request::<Query>(&query(2)).await.stack()?;
+    ensure_eq!(a.recv_response::<u64>(slow, TIMEOUT).await.stack()?, 2);
+    ensure_eq!(a.recv_response::<u64>(fast, TIMEOUT).await.stack()?, 4);
+    ensure!(a.recv_response::<u64>(fast, TIMEOUT).await.is_err());
+
+    // a late response after a timeout is discarded
+    let e = a
+        .request::<Query, u64>(
+            &Query {
+                n: 4,
+                delay_ms: 300,
+            },
+            Duration::from_millis(50),
+        )
+        .await
+        .unwrap_err();
+    ensure!(format!("{e:?}").contains("timed out in the header phase"));
+    ensure!(!a.is_poisoned());
+    ensure_eq!(
+        a.request::<Query, u64>(&query(5), TIMEOUT).await.stack()?,
+        10
+    );
+
+    // `serve` ends when the other side closes the connection, the late response
+    // is written and discarded first
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    ensure_eq!(
+        a.request::<Query, u64>(&query(6), TIMEOUT).await.stack()?,
+        12
+    );
+    drop(a);
+    server.await.stack()?.stack()?;
+    Ok(())
+}
+
+async fn keepalive() -> Result<()> {
+    // idle messengers with keepalive stay usable, the pings are skipped
+    let (a, b) = messenger_pair().await.stack()?;
+    let mut a = a.enable_keepalive(Duration::from_millis(20), Duration::from_millis(500));
+    let mut b = b.enable_keepalive(Duration::from_millis(20), Duration::from_millis(500));
+    tokio::time::sleep(Duration::from_millis(700)).await;
+    a.send::<String>(&"after idling".to_owned()).await.stack()?;
+    ensure_eq!(b.recv::<String>().await.stack()?, "after idling");
+    // a `recv` that waits longer than the grace period for a message
+    let send = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        b.send::<u64>(&5).await.stack()?;
+        Ok::<_, stacked_errors::Error>(b)
+    });
+    ensure_eq!(a.recv::<u64>().await.stack()?, 5);
+    let _b = send.await.stack()?.stack()?;
+
+    // a peer that stopped responding is considered dead
+    let (a, _raw) = messenger_and_raw(PROTOCOL_VERSION).await.stack()?;
+    let mut a = a
+        .stack()?
+        .enable_keepalive(Duration::from_millis(20), Duration::from_millis(300));
+    let start = std::time::Instant::now();
+    let e = a.recv::<String>().await.unwrap_err();
+    ensure!(format!("{e:?}").contains("the other side is considered dead"));
+    ensure!(start.elapsed() < TIMEOUT);
+    ensure!(a.is_poisoned());
+
+    // also when sending
+    let (a, _raw) = messenger_and_raw(PROTOCOL_VERSION).await.stack()?;
+    let mut a = a
+        .stack()?
+        .enable_keepalive(Duration::from_millis(20), Duration::from_millis(300));
+    let e = a.send::<Vec<u8>>(&vec![0u8; 32 << 20]).await.unwrap_err();
+    ensure!(format!("{e:?}").contains("the other side is considered dead"));
+    ensure!(a.is_poisoned());
+
+    // a send cancelled in the middle of a frame poisons, and no pings are
+    // written into the partial frame
+    let (a, mut raw) = messenger_and_raw(PROTOCOL_VERSION).await.stack()?;
+    let mut a = a
+        .stack()?
+        .enable_keepalive(Duration::from_millis(20), Duration::from_secs(100));
+    let large = vec![0u8; 32 << 20];
+    ensure!(
+        tokio::time::timeout(Duration::from_millis(100), a.send::<Vec<u8>>(&large))
+            .await
+            .is_err()
+    );
+    ensure!(a.is_poisoned());
+    let e = a.send::<u64>(&0).await.unwrap_err();
+    ensure!(format!("{e:?}").contains("cancelled in the middle of a frame"));
+    let mut received = vec![];
+    let mut buf = vec![0u8; 1 << 16];
+    while let Ok(n) = tokio::time::timeout(Duration::from_millis(300), raw.read(&mut buf)).await {
+        let n = n.stack()?;
+        ensure!(n != 0);
+        received.extend_from_slice(&buf[..n]);
+    }
+    // pings from before the send, then the frame type, type ID, and length before
+    // the body
+    let start = received.iter().position(|b| *b != FRAME_PING).stack()? + 25;
+    ensure!((received.len() > start) && (received.len() < large.len()));
+    // the body is the `postcard` length of the `Vec` followed by the zeros
+    ensure!(received[(start + 4)..].iter().all(|b| *b == 0));
+
+    // unknown frame types are not misread as messages
+    let (a, mut raw) = messenger_and_raw(PROTOCOL_VERSION).await.stack()?;
+    let mut a = a.stack()?;
+    raw.write_u8(7).await.stack()?;
+    let e = a.recv::<String>().await.unwrap_err();
+    ensure!(format!("{e:?}").contains("unknown frame type 7"));
+    Ok(())
+}
+
+async fn close() -> Result<()> {
+    // the messages before the close are received, then every `recv` fails
+    let (mut a, mut b) = messenger_pair().await.stack()?;
+    a.send::<String>(&"last".to_owned()).await.stack()?;
+    a.close().await.stack()?;
+    ensure_eq!(b.recv::<String>().await.stack()?, "last");
+    ensure!(!b.is_closed_by_peer());
+    for _ in 0..2 {
+        let e = b.recv::<String>().await.unwrap_err();
+        ensure!(format!("{e:?}").contains("closed the connection with `NetMessenger::close`"));
+        ensure!(b.is_closed_by_peer());
+        ensure!(!b.is_poisoned());
+    }
+    ensure!(b.send::<u64>(&0).await.is_err());
+    // closing after the other side closed is fine
+    b.close().await.stack()?;
+
+    // a dropped messenger is not a graceful close
+    let (a, mut b) = messenger_pair().await.stack()?;
+    drop(a);
+    let e = b.recv::<String>().await.unwrap_err();
+    ensure!(!format!("{e:?}").contains("`NetMessenger::close`"));
+    ensure!(!b.is_closed_by_peer());
+
+    // a pending `recv` returns when the other side closes
+    let (a, mut b) = messenger_pair().await.stack()?;
+    let recv = tokio::spawn(async move {
+        let res = b.recv::<String>().await;
+        (res, b.is_closed_by_peer())
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    a.close().await.stack()?;
+    let (res, closed_by_peer) = tokio::time::timeout(TIMEOUT, recv).await.stack()?.stack()?;
+    ensure!(res.is_err() && closed_by_peer);
+
+    // and so does a pending `recv_response`, with keepalive on both sides
+    let (a, b) = messenger_pair().await.stack()?;
+    let mut a = a.enable_keepalive(Duration::from_millis(20), Duration::from_millis(500));
+    let b = b.enable_keepalive(Duration::from_millis(20), Duration::from_millis(500));
+    let id = a.send_request::<u64>(&1).await.stack()?;
+    b.close().await.stack()?;
+    let e = a.recv_response::<u64>(id, TIMEOUT).await.unwrap_err();
+    ensure!(format!("{e:?}").contains("closed the connection with `NetMessenger::close`"));
+    a.close().await.stack()?;
+
+    // `serve` ends when the other side closes
+    let (mut a, mut b) = messenger_pair().await.stack()?;
+    let server = tokio::spawn(async move { b.serve(|n: u64| async move { Ok(n + 1) }).await });
+    ensure_eq!(a.request::<u64, u64>(&1, TIMEOUT).await.stack()?, 2);
+    a.close().await.stack()?;
+    tokio::time::timeout(TIMEOUT, server)
+        .await
+        .stack()?
+        .stack()?
+        .stack()?;
+    Ok(())
+}
+
+async fn broadcasts() -> Result<()> {
+    let mut ours = vec![];
+    let mut theirs = vec![];
+    for _ in 0..4 {
+        let (a, b) = messenger_pair().await.stack()?;
+        ours.push(a);
+        theirs.push(b);
+    }
+    // the messages before the broadcast are received first
+    for (i, a) in ours.iter_mut().enumerate() {
+        a.send::<u64>(&(i as u64)).await.stack()?;
+    }
+    broadcast::<String>(&mut ours, &"phase 2".to_owned(), TIMEOUT)
+        .await
+        .stack()?;
+    for (i, b) in theirs.iter_mut().enumerate() {
+        ensure_eq!(b.recv::<u64>().await.stack()?, i as u64);
+        ensure_eq!(b.recv::<String>().await.stack()?, "phase 2");
+    }
+
+    // the other messengers are still sent to if some fail
+    for i in [3, 1] {
+        theirs.remove(i).close().await.stack()?;
+        ensure!(ours[i].recv::<u64>().await.is_err());
+    }
+    let e = broadcast::<String>(&mut ours, &"phase 3".to_owned(), TIMEOUT)
+        .await
+        .unwrap_err();
+    let e = format!("{e:?}");
+    ensure!(e.contains("failed to send to 2 of 4 messengers, which are at the indexes [1, 3]"));
+    ensure!(e.contains("messenger 1") && e.contains("messenger 3"));
+    for b in &mut theirs {
+        ensure_eq!(b.recv::<String>().await.stack()?, "phase 3");
+    }
+
+    // a peer that does not read does not hold up the others, the payload has to be
+    // larger than the socket buffers to stall the peer, and the timeout is generous
+    // so that the reader finishes even on a loaded machine
+    let mut ours = vec![ours.remove(0), ours.remove(1)];
+    let large = vec![0u8; 16 << 20];
+    let mut stuck = theirs.pop().unwrap();
+    let mut reader = theirs.pop().unwrap();
+    let read = tokio::spawn(async move { reader.recv::<Vec<u8>>().await.map(|v| v.len()) });
+    let e = broadcast::<Vec<u8>>(&mut ours, &large, Duration::from_secs(30))
+        .await
+        .unwrap_err();
+    let e = format!("{e:?}");
+    ensure!(
+        e.contains("failed to send to 1 of 2 messengers"),
+        format!("{e}")
+    );
+    ensure!(e.contains("messenger 1"), format!("{e}"));
+    ensure_eq!(read.await.stack()?.stack()?, 16 << 20);
+    ensure!(!ours[0].is_poisoned());
+    ensure!(ours[1].is_poisoned());
+    stuck.send::<u64>(&0).await.stack()?;
+    Ok(())
+}
+
+async fn datagrams() -> Result<()> {
+    let mut a = NetDatagram::bind("127.0.0.1:0").await.stack()?;
+    let mut b = NetDatagram::bind("127.0.0.1:0").await.stack()?;
+    let a_addr = a.local_addr().stack()?;
+    let b_addr = b.local_addr().stack()?;
+
+    let msg = Example {
+        name: "datagram".to_owned(),
+        values: vec![1, 2, 3],
+        nested: None,
+    };
+    a.send_to::<Example>(&msg, b_addr).await.stack()?;
+    let (received, from) = b.recv_from::<Example>().await.stack()?;
+    ensure_eq!(received, msg);
+    ensure_eq!(from, a_addr);
+    // replies go to the address it came from
+    b.send_to::<u64>(&7, from).await.stack()?;
+    ensure_eq!(a.recv_from_timeout::<u64>(TIMEOUT).await.stack()?.0, 7);
+
+    // the MTU is checked before sending
+    let mut a = a.mtu(100);
+    let e = a
+        .send_to::<Vec<u8>>(&vec![0u8; 100], b_addr)
+        .await
+        .unwrap_err();
+    ensure!(format!("{e:?}").contains("larger than the MTU of 100 bytes"));
+    a.send_to::<Vec<u8>>(&vec![0u8; 50], b_addr).await.stack()?;
+    ensure_eq!(b.recv_from::<Vec<u8>>().await.stack()?.0.len(), 50);
+
+    // a datagram of the wrong type is an error, but the next one is received
+    a.send_to::<u64>(&1, b_addr).await.stack()?;
+    a.send_to::<String>(&"next".to_owned(), b_addr)
+        .await
+        .stack()?;
+    let e = b.recv_from::<String>().await.unwrap_err();
+    ensure!(format!("{e:?}").contains("did not match the expected type"));
+    ensure_eq!(b.recv_from::<String>().await.stack()?.0, "next");
+
+    // and so is one that is too short
+    let raw = tokio::net::UdpSocket::bind("127.0.0.1:0").await.stack()?;
+    raw.send_to(&[1, 2, 3], b_addr).await.stack()?;
+    let e = b.recv_from::<u64>().await.unwrap_err();
+    ensure!(format!("{e:?}").contains("too short for the type ID"));
+
+    // JSON on both sides
+    let mut a = a.codec(Codec::Json);
+    let mut b = b.codec(Codec::Json);
+    a.send_to::<Example>(&msg, b_addr).await.stack()?;
+    ensure_eq!(b.recv_from::<Example>().await.stack()?.0, msg);
+
+    ensure!(b
+        .recv_from_timeout::<u64>(Duration::from_millis(50))
+        .await
+        .is_err());
+    Ok(())
+}
+
+async fn server() -> Result<()> {
+    let mut server = NetMessengerServer::bind("127.0.0.1:0").await.stack()?;
+    let addr = server.local_addr().stack()?.to_string();
+
+    // nobody connecting
+    ensure!(server.accept(Duration::from_millis(100)).await.is_err());
+
+    // connections accepted by an `accept_n` that times out are not lost
+    let mut clients = vec![];
+    for i in 0..3u64 {
+        let addr = addr.clone();
+        clients.push(tokio::spawn(async move {
+            let mut nm = NetMessenger::connect(10, Duration::from_millis(100), &addr)
+                .await
+                .stack()?;
+            nm.send::<u64>(&i).await.stack()?;
+            ensure_eq!(nm.recv::<u64>().await.stack()?, i * 10);
+            Ok::<(), stacked_errors::Error>(())
+        }));
+    }
+    let e = server
+        .accept_n(4, Duration::from_millis(300))
+        .await
+        .unwrap_err();
+    ensure!(format!("{e:?}").contains("accepted 3 of 4"));
+    let mut accepted = server.accept_n(3, TIMEOUT).await.stack()?;
+    ensure_eq!(accepted.len(), 3);
+    for (nm, _) in &mut accepted {
+        let i = nm.recv::<u64>().await.stack()?;
+        nm.send::<u64>(&(i * 10)).await.stack()?;
+    }
+    for client in clients {
+        client.await.stack()?.stack()?;
+    }
+
+    // a connection failing the handshake does not stop the server
+    let mut raw = TcpStream::connect(&addr).await.stack()?;
+    raw.write_all(b"GET / HTTP/1.1\r\n\r\n").await.stack()?;
+    let e = server.accept(TIMEOUT).await.unwrap_err();
+    ensure!(format!("{e:?}").contains("failed the handshake"));
+    let connect = tokio::spawn({
+        let addr = addr.clone();
+        async move { NetMessenger::connect(10, Duration::from_millis(100), &addr).await }
+    });
+    let (mut a, _) = server.accept(TIMEOUT).await.stack()?;
+    let mut b = connect.await.stack()?.stack()?;
+    b.send::<String>(&"after".to_owned()).await.stack()?;
+    ensure_eq!(a.recv::<String>().await.stack()?, "after");
+
+    // a handshake is not limited by the `accept` call it started in
+    let mut raw = TcpStream::connect(&addr).await.stack()?;
+    let e = server.accept(Duration::from_millis(100)).await.unwrap_err();
+    ensure!(e.is_timeout(), format!("{e:?}"));
+    raw.write_all(&raw_handshake(PROTOCOL_VERSION, "", 0))
+        .await
+        .stack()?;
+    server.accept(TIMEOUT).await.stack()?;
+
+    // but it has the connect timeout of the options
+    let mut server = NetMessengerServer::bind("127.0.0.1:0")
+        .await
+        .stack()?
+        .options(NetMessengerOptions::new().connect_timeout(Duration::from_millis(200)));
+    let _raw = TcpStream::connect(server.local_addr().stack()?)
+        .await
+        .stack()?;
+    let e = server.accept(TIMEOUT).await.unwrap_err();
+    ensure!(e.is_timeout() && format!("{e:?}").contains("failed the handshake"));
+    Ok(())
+}
+
+/// Returns the results of `listen_tls` and `connect_tls` to each other
+async fn tls_pair(
+    listen_tls: TlsConfig,
+    connect_tls: TlsConfig,
+) -> Result<(Result<NetMessenger>, Result<NetMessenger>)> {
+    let addr = free_addr().await.stack()?;
+    let listen = tokio::spawn({
+        let addr = addr.clone();
+        async move { NetMessenger::listen_tls(&addr, TIMEOUT, &listen_tls).await }
+    });
+    let (_, port) = addr.rsplit_once(':').stack()?;
+    let connected = NetMessenger::connect_tls(
+        10,
+        Duration::from_millis(100),
+        &format!("localhost:{port}"),
+        &connect_tls,
+    )
+    .await;
+    Ok((listen.await.stack()?, connected))
+}
+
+async fn tls() -> Result<()> {
+    let (cert, key) = TlsConfig::generate_self_signed(&["localhost"]).stack()?;
+    let tls = TlsConfig::pinned(cert.as_bytes(), key.as_bytes()).stack()?;
+
+    // a round trip with the same pinned certificate
+    let (a, b) = tls_pair(tls.clone(), tls.clone()).await.stack()?;
+    let (mut a, mut b) = (a.stack()?, b.stack()?);
+    a.send::<String>(&"hello".to_owned()).await.stack()?;
+    ensure_eq!(b.recv::<String>().await.stack()?, "hello");
+    let large: Vec<u8> = (0..(1 << 20)).map(|i: u32| i as u8).collect();
+    b.send::<Vec<u8>>(&large).await.stack()?;
+    ensure!(a.recv::<Vec<u8>>().await.stack()? == large);
+
+    // the server name is checked
+    let (a, b) = tls_pair(tls.clone(), tls.clone().server_name("other"))
+        .await
+        .stack()?;
+    ensure!(format!("{:?}", b.unwrap_err()).contains("the TLS handshake failed"));
+    ensure!(a.is_err());
+
+    // a different certificate is not trusted by either side
+    let (other_cert, other_key) = TlsConfig::generate_self_signed(&["localhost"]).stack()?;
+    let other = TlsConfig::pinned(other_cert.as_bytes(), other_key.as_bytes()).stack()?;
+    let (a, b) = tls_pair(tls.clone(), other).await.stack()?;
+    ensure!(format!("{:?}", b.unwrap_err()).contains("the TLS handshake failed"));
+    ensure!(format!("{:?}", a.unwrap_err()).contains("the TLS handshake failed"));
+
+    // the listening side trusts a client certificate signed by its CA, but not
+    // the other way around
+    let trusting =
+        TlsConfig::mutual(cert.as_bytes(), key.as_bytes(), other_cert.as_bytes()).stack()?;
+    let (a, b) = tls_pair(
+        trusting,
+        TlsConfig::pinned(other_cert.as_bytes(), other_key.as_bytes()).stack()?,
+    )
+    .await
+    .stack()?;
+    ensure!(format!("{:?}", b.unwrap_err()).contains("the TLS handshake failed"));
+    ensure!(a.is_err());
+
+    // a plaintext peer fails the TLS handshake instead of being misread
+    let addr = free_addr().await.stack()?;
+    let listen = tokio::spawn({
+        let addr = addr.clone();
+        let tls = tls.clone();
+        async move { NetMessenger::listen_tls(&addr, TIMEOUT, &tls).await }
+    });
+    let plain = NetMessenger::connect(10, Duration::from_millis(100), &addr).await;
+    let e = listen.await.stack()?.unwrap_err();
+    ensure!(format!("{e:?}").contains("the TLS handshake failed"));
+    ensure!(plain.is_err());
+
+    // nobody connecting is a timeout and not a TLS error
+    let addr = free_addr().await.stack()?;
+    let e = NetMessenger::listen_tls(&addr, Duration::from_millis(100), &tls)
+        .await
+        .unwrap_err();
+    ensure!(!format!("{e:?}").contains("TLS"));
+
+    // a peer that accepts but never answers times out in the TLS handshake
+    let listener = TcpListener::bind("127.0.0.1:0").await.stack()?;
+    let addr = listener.local_addr().stack()?.to_string();
+    let e = NetMessengerOptions::new()
+        .connect_timeout(Duration::from_millis(200))
+        .connect_tls(10, Duration::from_millis(100), &addr, &tls)
+        .await
+        .unwrap_err();
+    ensure!(e.is_timeout(), format!("{e:?}"));
+    ensure!(format!("{e:?}").contains("timed out in the TLS handshake"));
+    drop(listener);
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    round_trip().await.stack()?;
+    handshake().await.stack()?;
+    compression().await.stack()?;
+    json_interop().await.stack()?;
+    timeouts().await.stack()?;
+    cancel_safety().await.stack()?;
+    max_message_size().await.stack()?;
+    buffers().await.stack()?;
+    streams().await.stack()?;
+    tls().await.stack()?;
+    server().await.stack()?;
+    keepalive().await.stack()?;
+    requests().await.stack()?;
+    close().await.stack()?;
+    datagrams().await.stack()?;
+    broadcasts().await.stack()?;
+    Ok(())
+}
@@ -0,0 +1,80 @@
+//! Checks `create_network_api` and `get_or_create_network_api`
+
+use std::str::FromStr;
+
+use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::api_docker::{
+    create_network_api, get_or_create_network_api, NetworkOptions,
+};
+use tracing_subscriber::EnvFilter;
+
+async fn run_networks(name: &str) -> Result<()> {
+    // invalid IPAM options are rejected before anything is created
+    for (subnet, gateway) in [
+        (Some("172.28.0.5/16"), None),
+        (Some("172.28.0.0/33"), None),
+        (Some("172.28.0.0"), None),
+        (Some("172.28.0.0/16"), Some("172.29.0.1")),
+        (Some("172.28.0.0/16"), Some("fd00::1")),
+        (None, Some("172.28.0.1")),
+    ] {
+        ensure!(create_network_api(name, NetworkOptions {
+            subnet: subnet.map(|s| s.to_owned()),
+            gateway: gateway.map(|s| s.to_owned()),
+            ..Default::default()
+        })
+        .await
+        .is_err());
+    }
+
+    let network = create_network_api(name, NetworkOptions {
+        internal: true,
+        subnet: Some("172.28.0.0/16".to_owned()),
+        gateway: Some("172.28.0.1".to_owned()),
+        ..Default::default()
+    })
+    .await
+    .stack()?;
+    ensure!(network.uuid().is_some());
+
+    let err = create_network_api(name, NetworkOptions::default())
+        .await
+        .unwrap_err();
+    ensure!(format!("{err:?}").contains("already exists"));
+
+    let reused = get_or_create_network_api(name, NetworkOptions::default())
+        .await
+        .stack()?;
+    ensure_eq!(reused.id(), network.id());
+    ensure_eq!(reused.uuid(), network.uuid());
+
+    network.remove().await.stack()?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let name = super_orchestrator::random_name("test_network_api");
+    let res = run_networks(&name).await;
+    if res.is_err() {
+        let _ = get_or_create_network_api(&name, NetworkOptions::default())
+            .await
+            .stack()?
+            .remove()
+            .await;
+    }
+    res.stack()?;
+
+    // creates if it does not exist
+    let network = get_or_create_network_api(&name, NetworkOptions::default())
+        .await
+        .stack()?;
+    ensure_eq!(network.name(), name);
+    network.remove().await.stack()?;
+
+    Ok(())
+}
@@ -0,0 +1,104 @@
+//! Checks `SuperImage::export_oci_layout` by re-importing the layout
+
+use std::{path::Path, str::FromStr};
+
+use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::{
+    api_docker::{Dockerfile, SuperDockerfile, SuperImage, SuperRunOptions},
+    sh,
+};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+const LAYOUT: &str = "./logs/oci_layout";
+const REIMPORTED_LAYOUT: &str = "./logs/oci_layout_reimported";
+
+fn read_json(path: impl AsRef<Path>) -> Result<serde_json::Value> {
+    serde_json::from_slice(&std::fs::read(path.as_ref()).stack()?).stack()
+}
+
+fn blob_path(layout: &str, digest: &str) -> Result<String> {
+    let (algorithm, hex) = digest.split_once(':').stack()?;
+    Ok(format!("{layout}/blobs/{algorithm}/{hex}"))
+}
+
+/// Follows the index of the layout to the config digest of its image
+fn config_digest(layout: &str) -> Result<String> {
+    ensure_eq!(
+        read_json(format!("{layout}/oci-layout")).stack()?["imageLayoutVersion"],
+        "1.0.0"
+    );
+    let mut descriptor = read_json(format!("{layout}/index.json")).stack()?["manifests"][0].clone();
+    loop {
+        let digest = descriptor["digest"].as_str().stack()?;
+        let blob = read_json(blob_path(layout, digest).stack()?).stack()?;
+        // multi-platform images have nested indexes
+        if let Some(manifests) = blob["manifests"].as_array() {
+            descriptor = manifests.first().stack()?.clone();
+            continue
+        }
+        for layer in blob["layers"].as_array().stack()? {
+            ensure!(Path::new(&blob_path(layout, layer["digest"].as_str().stack()?)?).is_file());
+        }
+        return blob["config"]["digest"].as_str().stack().map(str::to_owned)
+    }
+}
+
+/// Loads the layout with `docker load` and returns the loaded image reference
+async fn reimport(layout: &str) -> Result<String> {
+    let tarball = format!("{layout}.tar");
+    let mut builder = tar::Builder::new(std::fs::File::create(&tarball).stack()?);
+    builder.append_dir_all(".", layout).stack()?;
+    builder.into_inner().stack()?;
+    let output = sh(["docker load -i", &tarball]).await.stack()?;
+    std::fs::remove_file(&tarball).stack()?;
+    output
+        .lines()
+        .find_map(|line| {
+            line.strip_prefix("Loaded image ID: ")
+                .or_else(|| line.strip_prefix("Loaded image: "))
+        })
+        .map(|reference| reference.trim().to_owned())
+        .stack_err_with(|| format!("unexpected `docker load` output: {output}"))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let image = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .append_dockerfile_instructions(["RUN echo exported > /exported"])
+        .build_image()
+        .await
+        .stack()?;
+
+    for layout in [LAYOUT, REIMPORTED_LAYOUT] {
+        let _ = std::fs::remove_dir_all(layout);
+    }
+    image.export_oci_layout(LAYOUT).await.stack()?;
+    let digest = config_digest(LAYOUT).stack()?;
+
+    let reimported = SuperImage::new(reimport(LAYOUT).await.stack()?);
+    reimported
+        .export_oci_layout(REIMPORTED_LAYOUT)
+        .await
+        .stack()?;
+    ensure_eq!(config_digest(REIMPORTED_LAYOUT).stack()?, digest);
+
+    let completed = reimported
+        .run_once(SuperRunOptions {
+            cmd: vec!["cat".to_owned(), "/exported".to_owned()],
+            ..Default::default()
+        })
+        .await
+        .stack()?;
+    ensure_eq!(completed.stdout_as_utf8().stack()?, "exported\n");
+
+    for layout in [LAYOUT, REIMPORTED_LAYOUT] {
+        std::fs::remove_dir_all(layout).stack()?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+//! Checks `CommandPipeline`s, this uses Unix programs
+
+use std::time::{Duration, Instant};
+
+use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::{Command, CommandPipeline};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // three stages, with the output of the pipeline in the last result
+    let res = Command::new("printf")
+        .arg("b\nc\na\n")
+        .pipe_to(Command::new("sort"))
+        .pipe_to(Command::new("tr a-z A-Z"))
+        .run_to_completion()
+        .await
+        .stack()?;
+    res.assert_success().stack()?;
+    ensure_eq!(res.results.len(), 3);
+    ensure_eq!(res.last().stdout_as_utf8().stack()?, "A\nB\nC\n");
+    // the stdout of the upstream stages went into the pipes
+    ensure!(res.results[0].stdout.is_empty());
+
+    // combined recording of an upstream stage does not take its stdout from the
+    // pipe
+    let res = Command::new("printf")
+        .arg("b\na\n")
+        .record_combined(true)
+        .pipe_to(Command::new("sort").record_combined(true))
+        .run_to_completion()
+        .await
+        .stack()?;
+    res.assert_success().stack()?;
+    ensure_eq!(res.last().stdout_as_utf8().stack()?, "a\nb\n");
+    ensure_eq!(res.last().combined_as_utf8_lossy(), "a\nb\n");
+    ensure!(res.results[0].combined_chunks.is_empty());
+
+    // the data streams through the pipe instead of being buffered
+    let res = Command::new("head -c 50000000 /dev/zero")
+        .pipe_to(Command::new("wc -c"))
+        .run_to_completion()
+        .await
+        .stack()?;
+    res.assert_success().stack()?;
+    ensure_eq!(res.last().stdout_as_utf8().stack()?.trim(), "50000000");
+
+    // a failing stage makes the pipeline unsuccessful, like `pipefail`
+    let res = CommandPipeline::new(vec![Command::new("false"), Command::new("cat")])
+        .run_to_completion()
+        .await
+        .stack()?;
+    ensure!(!res.successful());
+    ensure!(res.last().successful());
+    let e = format!("{:?}", res.assert_success().unwrap_err());
+    ensure!(e.contains("stage 0 was unsuccessful"));
+
+    // a downstream stage exiting early stops the upstream stage with a broken pipe
+    let res = Command::new("yes")
+        .pipe_to(Command::new("head -n 1"))
+        .run_to_completion()
+        .await
+        .stack()?;
+    ensure_eq!(res.last().stdout_as_utf8().stack()?, "y\n");
+    ensure!(!res.results[0].successful());
+
+    // an upstream stage that does not write is stopped after the grace period
+    let start = Instant::now();
+    let res = Command::new("sleep 30")
+        .pipe_to(Command::new("true"))
+        .upstream_grace(Duration::from_millis(200))
+        .run_to_completion()
+        .await
+        .stack()?;
+    ensure!(start.elapsed() < Duration::from_secs(10));
+    ensure!(res.last().successful());
+    ensure!(res.results[0].status.is_none() || res.results[0].terminated_gracefully);
+
+    // spawning failures are errors
+    let res = Command::new("sleep 30")
+        .pipe_to(Command::new("nonexistent_program_for_pipelines"))
+        .run_to_completion()
+        .await;
+    ensure!(res.is_err());
+
+    Ok(())
+}
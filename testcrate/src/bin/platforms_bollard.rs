@@ -0,0 +1,57 @@
+//! Checks building an image for multiple platforms with
+//! `SuperDockerfile::build_platform_images`
+
+use std::str::FromStr;
+
+use stacked_errors::{ensure_eq, ensure_ne, Result, StackableErr};
+use super_orchestrator::api_docker::{Dockerfile, SuperDockerfile};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    // no `RUN` instructions so that QEMU is not needed
+    let platforms = ["linux/amd64", "linux/arm64"];
+    let images = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .append_dockerfile_instructions(["ENV BUILT=yes"])
+        .with_platforms(platforms)
+        .build_platform_images()
+        .await
+        .stack()?;
+    ensure_eq!(
+        images
+            .iter()
+            .map(|(platform, _)| platform.as_str())
+            .collect::<Vec<_>>(),
+        platforms.to_vec()
+    );
+    ensure_ne!(images[0].1.get_image_id(), images[1].1.get_image_id());
+
+    // each platform gets its own tag
+    let images = SuperDockerfile::new(
+        Dockerfile::name_tag(BASE_CONTAINER),
+        Some("super_platforms:test".to_owned()),
+    )
+    .append_dockerfile_instructions(["ENV BUILT=yes"])
+    .with_platforms(platforms)
+    .build_platform_images()
+    .await
+    .stack()?;
+    ensure_eq!(
+        images
+            .iter()
+            .map(|(_, image)| image.reference())
+            .collect::<Vec<_>>(),
+        vec![
+            Some("super_platforms:test-linux-amd64"),
+            Some("super_platforms:test-linux-arm64")
+        ]
+    );
+
+    Ok(())
+}
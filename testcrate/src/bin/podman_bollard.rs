@@ -0,0 +1,72 @@
+//! Checks the podman compatibility of the API docker path. This only runs if
+//! the `SUPER_ORCHESTRATOR_PODMAN` environment variable is set and the docker
+//! API is served by podman (e.g. `DOCKER_HOST` pointing to the podman socket).
+
+use std::str::FromStr;
+
+use stacked_errors::{ensure, Result, StackableErr};
+use super_orchestrator::api_docker::{
+    docker_socket, AddContainerOptions, BuilderVersion, ContainerCreateOptions, ContainerNetwork,
+    Dockerfile, ExtraAddContainerOptions, ImageBuildOptions, NetworkCreateOptions, SuperDockerfile,
+};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    if std::env::var("SUPER_ORCHESTRATOR_PODMAN").is_err() {
+        eprintln!("skipping, `SUPER_ORCHESTRATOR_PODMAN` is not set");
+        return Ok(());
+    }
+
+    let engine_info = docker_socket::engine_info().await.stack()?;
+    tracing::info!("{engine_info:?}");
+    ensure!(engine_info.is_podman());
+
+    let mut cn = ContainerNetwork::create(NetworkCreateOptions {
+        name: "test_podman_bollard".to_string(),
+        overwrite_existing: true,
+        log_by_default: true,
+        ..Default::default()
+    })
+    .await
+    .stack()?;
+
+    // podman rejects BuildKit builds and container level MAC addresses, these
+    // should be adjusted with warnings
+    cn.add_container(
+        AddContainerOptions::DockerFile(
+            SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None).with_build_opts(
+                ImageBuildOptions {
+                    version: BuilderVersion::BuilderBuildKit,
+                    ..Default::default()
+                },
+            ),
+        ),
+        ExtraAddContainerOptions {
+            mac_address: Some("02:42:ac:11:00:02".to_string()),
+            ..Default::default()
+        },
+        ContainerCreateOptions {
+            name: super_orchestrator::random_name("podman"),
+            cmd: vec!["echo".to_string(), "hello from podman".to_string()],
+            important: true,
+            ..Default::default()
+        },
+    )
+    .await
+    .stack()?;
+
+    cn.teardown_on_ctrlc();
+    cn.start_all().await.stack()?;
+    let res = cn.wait_important().await.stack();
+    cn.teardown().await.stack()?;
+    res?;
+
+    Ok(())
+}
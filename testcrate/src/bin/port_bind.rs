@@ -0,0 +1,84 @@
+//! Checks the `docker run -p` rendering and validation of `PortBind`, this
+//! does not need docker to be running.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use stacked_errors::{ensure, ensure_eq, Result};
+use super_orchestrator::api_docker::{PortBind, PortBindProtocol};
+
+fn main() -> Result<()> {
+    ensure_eq!(PortBind::new(80).to_string(), "80:80/tcp");
+    ensure_eq!(PortBind::ephemeral(80).to_string(), "80/tcp");
+    ensure_eq!(
+        PortBind::new(80)
+            .with_host_port(8080)
+            .with_host_ip(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .with_protocol(PortBindProtocol::Udp)
+            .to_string(),
+        "127.0.0.1:8080:80/udp"
+    );
+
+    // every protocol x (no ip / v4 / v6) x (default / explicit host port)
+    let v4 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    let v6 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+    for (protocol, p) in [
+        (PortBindProtocol::Tcp, "tcp"),
+        (PortBindProtocol::Udp, "udp"),
+        (PortBindProtocol::Sctp, "sctp"),
+    ] {
+        for (host_ip, ip) in [
+            (None, ""),
+            (Some(v4), "10.0.0.1:"),
+            (Some(v6), "[2001:db8::1]:"),
+        ] {
+            for (host_port, host) in [(None, "80"), (Some(8080), "8080")] {
+                let mut pb = PortBind::new(80).with_protocol(protocol);
+                if let Some(host_ip) = host_ip {
+                    pb = pb.with_host_ip(host_ip);
+                }
+                if let Some(host_port) = host_port {
+                    pb = pb.with_host_port(host_port);
+                }
+                ensure_eq!(pb.to_string(), format!("{ip}{host}:80/{p}"));
+            }
+        }
+    }
+    ensure_eq!(
+        PortBind::ephemeral(80)
+            .with_host_ip(IpAddr::V6(Ipv6Addr::LOCALHOST))
+            .to_string(),
+        "[::1]::80/tcp"
+    );
+
+    // ranges
+    ensure_eq!(
+        PortBind::range(8000, 8002)?.to_string(),
+        "8000-8002:8000-8002/tcp"
+    );
+    ensure_eq!(
+        PortBind::range(8000, 8002)?
+            .with_host_port(9000)
+            .to_string(),
+        "9000-9002:8000-8002/tcp"
+    );
+    ensure_eq!(
+        PortBind::range(8000, 8002)?.with_host_port(0).to_string(),
+        "8000-8002/tcp"
+    );
+    ensure_eq!(PortBind::range(8000, 8000)?.to_string(), "8000:8000/tcp");
+    ensure!(PortBind::range(8001, 8000).is_err());
+    ensure_eq!(
+        PortBind::range(1, u16::MAX)?.to_string(),
+        format!("1-{}:1-{}/tcp", u16::MAX, u16::MAX)
+    );
+    // the overflowing host range that is rejected when creating the container is
+    // shown as is instead of being clamped
+    ensure_eq!(
+        PortBind::range(8000, 8010)?
+            .with_host_port(65530)
+            .to_string(),
+        "65530-65540:8000-8010/tcp"
+    );
+
+    Ok(())
+}
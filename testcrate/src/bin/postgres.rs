@@ -155,7 +155,7 @@ async fn container_runner(args: &Args) -> Result<()> {
 
 async fn test_runner() -> Result<()> {
     async fn postgres_health() -> Result<()> {
-        Command::new("psql --host=postgres -U postgres --command=\\l")
+        Command::new("psql --host=postgres -U postgres --command='\\l'")
             .env("PGPASSWORD", "root")
             .run_to_completion()
             .await
@@ -168,7 +168,7 @@ async fn test_runner() -> Result<()> {
         .await
         .stack()?;
 
-    Command::new("psql --host=postgres -U postgres --command=\\l")
+    Command::new("psql --host=postgres -U postgres --command='\\l'")
         .env("PGPASSWORD", "root")
         .debug(true)
         .run_to_completion()
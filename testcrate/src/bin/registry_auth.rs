@@ -0,0 +1,97 @@
+//! Checks reading registry credentials from a docker `config.json` with
+//! `RegistryAuth::from_docker_config` and splitting image references with
+//! `split_reference`, this does not need docker to be running.
+
+use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::api_docker::{split_reference, RegistryAuth};
+
+const CONFIG_DIR: &str = "./logs/registry_auth_docker_config";
+
+fn write_config(config: &str) -> Result<()> {
+    std::fs::write(format!("{CONFIG_DIR}/config.json"), config).stack()
+}
+
+fn main() -> Result<()> {
+    let _ = std::fs::remove_dir_all(CONFIG_DIR);
+    std::fs::create_dir_all(CONFIG_DIR).stack()?;
+    std::env::set_var("DOCKER_CONFIG", CONFIG_DIR);
+
+    // no config
+    ensure!(RegistryAuth::from_docker_config("ghcr.io")
+        .stack()?
+        .is_none());
+
+    // "user:pa:ss" in base64, the password can contain colons
+    write_config(
+        r#"{
+            "auths": {
+                "ghcr.io": { "auth": "dXNlcjpwYTpzcw==" },
+                "https://index.docker.io/v1/": { "auth": "dXNlcjpwYTpzcw==" },
+                "localhost:5000": { "identitytoken": "token" },
+                "bad.example.com": { "auth": "not base64" }
+            }
+        }"#,
+    )?;
+    let auth = RegistryAuth::from_docker_config("ghcr.io")
+        .stack()?
+        .stack()?;
+    ensure_eq!(auth.username.as_deref(), Some("user"));
+    ensure_eq!(auth.password.as_deref(), Some("pa:ss"));
+    ensure_eq!(auth.server_address.as_deref(), Some("ghcr.io"));
+    let auth = RegistryAuth::from_docker_config("docker.io")
+        .stack()?
+        .stack()?;
+    ensure_eq!(auth.username.as_deref(), Some("user"));
+    let auth = RegistryAuth::from_docker_config("localhost:5000")
+        .stack()?
+        .stack()?;
+    ensure_eq!(auth.identity_token.as_deref(), Some("token"));
+    ensure!(auth.username.is_none());
+    ensure!(RegistryAuth::from_docker_config("quay.io")
+        .stack()?
+        .is_none());
+    ensure!(RegistryAuth::from_docker_config("bad.example.com").is_err());
+    // the secrets are not printed
+    ensure!(!format!("{auth:?}").contains("token"));
+
+    // credentials in helpers cannot be read
+    write_config(
+        r#"{
+            "auths": { "ghcr.io": {} },
+            "credsStore": "desktop",
+            "credHelpers": { "gcr.io": "gcloud" }
+        }"#,
+    )?;
+    let err = RegistryAuth::from_docker_config("ghcr.io").unwrap_err();
+    ensure!(format!("{err:?}").contains("docker-credential-desktop"));
+    let err = RegistryAuth::from_docker_config("gcr.io").unwrap_err();
+    ensure!(format!("{err:?}").contains("docker-credential-gcloud"));
+    ensure!(RegistryAuth::from_docker_config("quay.io")
+        .stack()?
+        .is_none());
+
+    std::fs::remove_dir_all(CONFIG_DIR).stack()?;
+
+    // splitting references for the docker API
+    ensure_eq!(split_reference("alpine"), ("alpine", ""));
+    ensure_eq!(split_reference("alpine:3.21"), ("alpine", "3.21"));
+    ensure_eq!(
+        split_reference("localhost:5000/org/image"),
+        ("localhost:5000/org/image", "")
+    );
+    ensure_eq!(
+        split_reference("localhost:5000/org/image:tag"),
+        ("localhost:5000/org/image", "tag")
+    );
+    let digest = format!("sha256:{}", "ab".repeat(32));
+    ensure_eq!(
+        split_reference(&format!("alpine@{digest}")),
+        ("alpine", digest.as_str())
+    );
+    ensure_eq!(
+        split_reference(&format!("localhost:5000/image:tag@{digest}")),
+        ("localhost:5000/image", digest.as_str())
+    );
+
+    Ok(())
+}
@@ -0,0 +1,119 @@
+//! Checks the error classification and retrying of `RetryPolicy` with
+//! synthetic errors, this does not need docker to be running.
+
+use std::{
+    io::ErrorKind,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::api_docker::{BollardError, RetryPolicy};
+
+fn io_error(kind: ErrorKind) -> BollardError {
+    BollardError::IOError {
+        err: std::io::Error::from(kind),
+    }
+}
+
+fn server_error(status_code: u16) -> BollardError {
+    BollardError::DockerResponseServerError {
+        status_code,
+        message: "synthetic".to_owned(),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt().with_env_filter("debug").init();
+
+    // classification
+
+    for kind in [
+        ErrorKind::ConnectionReset,
+        ErrorKind::BrokenPipe,
+        ErrorKind::UnexpectedEof,
+    ] {
+        ensure!(RetryPolicy::is_transient(&io_error(kind)));
+    }
+    ensure!(RetryPolicy::is_transient(
+        &BollardError::RequestTimeoutError
+    ));
+    ensure!(RetryPolicy::is_transient(
+        &BollardError::DockerStreamError {
+            error: "unexpected EOF".to_owned()
+        }
+    ));
+    ensure!(!RetryPolicy::is_transient(&io_error(ErrorKind::NotFound)));
+    ensure!(!RetryPolicy::is_transient(
+        &BollardError::DockerStreamError {
+            error: "failed to solve: process did not complete successfully".to_owned()
+        }
+    ));
+    for status_code in [400, 404, 409, 500] {
+        ensure!(!RetryPolicy::is_transient(&server_error(status_code)));
+    }
+
+    // backoff
+
+    let retry_policy = RetryPolicy {
+        max_retries: 3,
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(25),
+    };
+    ensure_eq!(retry_policy.backoff(0), Duration::from_millis(10));
+    ensure_eq!(retry_policy.backoff(1), Duration::from_millis(20));
+    ensure_eq!(retry_policy.backoff(2), Duration::from_millis(25));
+    ensure_eq!(retry_policy.backoff(100), Duration::from_millis(25));
+
+    // retrying
+
+    let calls = AtomicU32::new(0);
+    let res = retry_policy
+        .retry("transient", || async {
+            if calls.fetch_add(1, Ordering::SeqCst) < 2 {
+                Err(io_error(ErrorKind::ConnectionReset))
+            } else {
+                Ok(7)
+            }
+        })
+        .await
+        .stack()?;
+    ensure_eq!(res, 7);
+    ensure_eq!(calls.load(Ordering::SeqCst), 3);
+
+    // gives up after `max_retries`
+    let calls = AtomicU32::new(0);
+    ensure!(retry_policy
+        .retry("always transient", || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>(io_error(ErrorKind::ConnectionReset))
+        })
+        .await
+        .is_err());
+    ensure_eq!(calls.load(Ordering::SeqCst), 4);
+
+    // daemon responses are never retried
+    let calls = AtomicU32::new(0);
+    ensure!(retry_policy
+        .retry("client error", || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>(server_error(404))
+        })
+        .await
+        .is_err());
+    ensure_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // the default does not retry
+    let calls = AtomicU32::new(0);
+    ensure!(RetryPolicy::default()
+        .retry("default", || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>(io_error(ErrorKind::ConnectionReset))
+        })
+        .await
+        .is_err());
+    ensure_eq!(calls.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
@@ -0,0 +1,61 @@
+//! Checks `SuperImage::run_once`, the API equivalent of `docker run --rm`
+
+use std::{str::FromStr, time::Duration};
+
+use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::api_docker::{Dockerfile, SuperDockerfile, SuperRunOptions};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let image = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .build_image()
+        .await
+        .stack()?;
+
+    let completed = image
+        .run_once(SuperRunOptions {
+            cmd: vec![
+                "sh".to_owned(),
+                "-c".to_owned(),
+                "echo \"out $VAR\"; echo err >&2; exit 3".to_owned(),
+            ],
+            env_vars: vec!["VAR=value".to_owned()],
+            timeout: Some(Duration::from_secs(60)),
+            ..Default::default()
+        })
+        .await
+        .stack()?;
+    ensure_eq!(completed.exit_code, 3);
+    ensure!(completed.assert_success().is_err());
+    ensure_eq!(completed.stdout_as_utf8().stack()?, "out value\n");
+    ensure_eq!(completed.stderr_as_utf8().stack()?, "err\n");
+
+    image
+        .run_once(SuperRunOptions {
+            cmd: vec!["true".to_owned()],
+            ..Default::default()
+        })
+        .await
+        .stack()?
+        .assert_success()
+        .stack()?;
+
+    let err = image
+        .run_once(SuperRunOptions {
+            cmd: vec!["sleep".to_owned(), "100".to_owned()],
+            timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    ensure!(err.is_timeout());
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+//! Checks `SuperNetwork`, the API equivalent of the CLI `ContainerNetwork`
+
+use std::{str::FromStr, time::Duration};
+
+use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::{
+    api_docker::{Dockerfile, SuperContainer, SuperDockerfile, SuperNetwork},
+    FileOptions,
+};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+const LOGS: &str = "./logs";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let image = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .build_image()
+        .await
+        .stack()?;
+
+    let mut sn = SuperNetwork::new_with_uuid("test_super_network", LOGS);
+    sn.add_container(
+        SuperContainer::new("server", image.clone())
+            .cmd(["sh", "-c", "echo hello from server; sleep 300"])
+            .log(true),
+    )
+    .stack()?;
+    // the unsuffixed name is reachable as a network alias
+    sn.add_container(
+        SuperContainer::new("client", image.clone())
+            .cmd(["sh", "-c", "ping -c 1 server && echo done"])
+            .log(true),
+    )
+    .stack()?;
+    sn.add_container(
+        SuperContainer::new("failing", image.clone())
+            .cmd(["sh", "-c", "echo \"Error: something failed\" >&2; exit 1"])
+            .log(true),
+    )
+    .stack()?;
+
+    sn.run(["server", "client"]).await.stack()?;
+    let res = sn
+        .wait_with_timeout(["client"], true, Duration::from_secs(60))
+        .await;
+    if res.is_err() {
+        sn.terminate_all().await;
+    }
+    res.stack()?;
+    ensure_eq!(sn.active_names(), vec!["server".to_owned()]);
+    let client_stdout = FileOptions::read2_to_string(LOGS, "client_stdout.log")
+        .await
+        .stack()?;
+    ensure_eq!(client_stdout.lines().last(), Some("done"));
+
+    // still running
+    let err = sn
+        .wait_with_timeout(["server"], false, Duration::from_millis(500))
+        .await
+        .unwrap_err();
+    ensure!(err.is_timeout());
+
+    // the failure is compiled from the logs and everything is terminated
+    sn.run(["failing"]).await.stack()?;
+    let err = sn
+        .wait_with_timeout(["failing"], true, Duration::from_secs(60))
+        .await
+        .unwrap_err();
+    ensure!(format!("{err:?}").contains("Error: something failed"));
+    ensure!(sn.active_names().is_empty());
+    ensure_eq!(
+        FileOptions::read2_to_string(LOGS, "server_stdout.log")
+            .await
+            .stack()?,
+        "hello from server\n"
+    );
+
+    sn.terminate_all().await;
+
+    Ok(())
+}
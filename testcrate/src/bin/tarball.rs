@@ -0,0 +1,557 @@
+//! Checks the `Tarball` and `SuperDockerfile` build context handling, this
+//! does not need docker to be running. Run from the repo root.
+
+use std::io::Read;
+
+use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::api_docker::{
+    BuildSecret, Dockerfile, HealthcheckOptions, ImageBuildOptions, OverwritePolicy,
+    SuperDockerfile, TarEntryOptions, Tarball, IMAGE_NAME_LABEL,
+};
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+// stands in for a bootstrapped binary
+const ENTRYPOINT: &str = "./dockerfiles/dockerfile_resources/example.txt";
+
+/// Returns the contents of all entries at `path` in the order they appear
+fn entries_at(tarball: &[u8], path: &str) -> Result<Vec<Vec<u8>>> {
+    let mut archive = tar::Archive::new(std::io::Cursor::new(tarball));
+    let mut res = vec![];
+    for entry in archive.entries().stack()? {
+        let mut entry = entry.stack()?;
+        if entry.path().stack()?.to_str() == Some(path) {
+            let mut content = vec![];
+            entry.read_to_end(&mut content).stack()?;
+            res.push(content);
+        }
+    }
+    Ok(res)
+}
+
+/// Returns the paths of all entries with their link names if they are symlinks
+fn entry_paths(tarball: &[u8]) -> Result<Vec<(String, Option<String>)>> {
+    let mut archive = tar::Archive::new(std::io::Cursor::new(tarball));
+    let mut res = vec![];
+    for entry in archive.entries().stack()? {
+        let entry = entry.stack()?;
+        let path = entry.path().stack()?.to_str().stack()?.to_owned();
+        let link_name = if entry.header().entry_type().is_symlink() {
+            Some(
+                entry
+                    .link_name()
+                    .stack()?
+                    .stack()?
+                    .to_str()
+                    .stack()?
+                    .to_owned(),
+            )
+        } else {
+            None
+        };
+        res.push((path, link_name));
+    }
+    Ok(res)
+}
+
+/// The entrypoint path colliding with an explicit copy of the same file
+async fn entrypoint_collision(overwrite_policy: OverwritePolicy) -> Result<Vec<u8>> {
+    let (_, tarball) = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .with_overwrite_policy(overwrite_policy)
+        .copying_from_paths([(ENTRYPOINT, "/entrypoint")])
+        .await
+        .stack()?
+        .with_entrypoint((ENTRYPOINT, "/entrypoint"), ["--entry-name", "container0"])
+        .await
+        .stack()?
+        .into_bollard_args()
+        .await
+        .stack()?;
+    Ok(tarball)
+}
+
+/// Builds with fixed entry metadata, sets the modification time of `file` to
+/// `mtime` first
+async fn fixed_metadata_build(file: &str, mtime: std::time::SystemTime) -> Result<Vec<u8>> {
+    std::fs::File::options()
+        .write(true)
+        .open(file)
+        .stack()?
+        .set_modified(mtime)
+        .stack()?;
+    let (_, tarball) = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .with_tar_entry_options(TarEntryOptions {
+            uid: Some(0),
+            gid: Some(0),
+            mtime: Some(1),
+            ..Default::default()
+        })
+        .copying_from_paths([(file, "/file.txt")])
+        .await
+        .stack()?
+        .copying_from_paths_with([(ENTRYPOINT, "/entrypoint", TarEntryOptions {
+            mode: Some(0o755),
+            uid: Some(1000),
+            ..Default::default()
+        })])
+        .await
+        .stack()?
+        .into_bollard_args()
+        .await
+        .stack()?;
+    Ok(tarball)
+}
+
+/// Builds a reproducible context from `dir` after setting the modification
+/// times and modes of its files, which differ between hosts
+async fn reproducible_build(dir: &str, mtime: std::time::SystemTime, mode: u32) -> Result<Vec<u8>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let files = [format!("{dir}/b.txt"), format!("{dir}/sub/a.txt")];
+    for file in &files {
+        std::fs::File::options()
+            .write(true)
+            .open(file)
+            .stack()?
+            .set_modified(mtime)
+            .stack()?;
+        std::fs::set_permissions(file, std::fs::Permissions::from_mode(mode)).stack()?;
+    }
+    let (_, tarball) = SuperDockerfile::new(
+        Dockerfile::contents(format!("FROM {BASE_CONTAINER}\r\nRUN echo hello\r\n")),
+        None,
+    )
+    .reproducible(true)
+    .copying_from_paths(files.iter().map(|file| (file.as_str(), "/files/")))
+    .await
+    .stack()?
+    .into_bollard_args()
+    .await
+    .stack()?;
+    Ok(tarball)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt().with_env_filter("debug").init();
+
+    let example = std::fs::read(ENTRYPOINT).stack()?;
+    let entry_path = ENTRYPOINT.trim_start_matches("./");
+
+    // overwrite policies
+
+    ensure!(entrypoint_collision(OverwritePolicy::Error).await.is_err());
+    for overwrite_policy in [OverwritePolicy::ReplaceLast, OverwritePolicy::KeepFirst] {
+        let tarball = entrypoint_collision(overwrite_policy).await.stack()?;
+        ensure_eq!(entries_at(&tarball, entry_path).stack()?, vec![
+            example.clone()
+        ]);
+    }
+
+    let mut tarball = Tarball::default();
+    tarball
+        .append_file_bytes("a.txt", 0o644, b"first")
+        .stack()?;
+    tarball
+        .append_file_bytes("b.txt", 0o644, b"other")
+        .stack()?;
+    tarball
+        .append_file_bytes("./a.txt", 0o644, b"second")
+        .stack()?;
+    // a leading root is not part of the path
+    ensure!(tarball.contains_path("/b.txt"));
+    ensure!(!tarball.contains_path("//b.txt/c"));
+    let tarball = tarball.into_tarball().stack()?;
+    ensure_eq!(entries_at(&tarball, "a.txt").stack()?, vec![
+        b"second".to_vec()
+    ]);
+    ensure_eq!(entries_at(&tarball, "b.txt").stack()?, vec![
+        b"other".to_vec()
+    ]);
+
+    let mut tarball = Tarball::default().with_overwrite_policy(OverwritePolicy::KeepFirst);
+    tarball
+        .append_file_bytes("a.txt", 0o644, b"first")
+        .stack()?;
+    tarball
+        .append_file_bytes("a.txt", 0o644, b"second")
+        .stack()?;
+    let tarball = tarball.into_tarball().stack()?;
+    ensure_eq!(entries_at(&tarball, "a.txt").stack()?, vec![
+        b"first".to_vec()
+    ]);
+
+    let mut tarball = Tarball::default().with_overwrite_policy(OverwritePolicy::Error);
+    tarball
+        .append_file_bytes("a.txt", 0o644, b"first")
+        .stack()?;
+    ensure!(tarball
+        .append_file_bytes("a.txt", 0o644, b"second")
+        .is_err());
+
+    // directories and symlinks
+
+    let dir = "./logs/tarball_dir";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(format!("{dir}/a")).stack()?;
+    std::fs::write(format!("{dir}/a/b.txt"), "b").stack()?;
+    std::fs::write(format!("{dir}/c.txt"), "c").stack()?;
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("c.txt", format!("{dir}/link")).stack()?;
+
+    let mut tarball = Tarball::default().with_follow_symlinks(false);
+    tarball.append_dir_all("dir", dir).stack()?;
+    // directories are not duplicated and the files are replaced
+    tarball.append_dir_all("dir", dir).stack()?;
+    let tarball = tarball.into_tarball().stack()?;
+    let mut expected = vec![
+        ("dir".to_owned(), None),
+        ("dir/a".to_owned(), None),
+        ("dir/a/b.txt".to_owned(), None),
+        ("dir/c.txt".to_owned(), None),
+    ];
+    #[cfg(unix)]
+    expected.push(("dir/link".to_owned(), Some("c.txt".to_owned())));
+    ensure_eq!(entry_paths(&tarball).stack()?, expected);
+    ensure_eq!(entries_at(&tarball, "dir/a/b.txt").stack()?, vec![
+        b"b".to_vec()
+    ]);
+
+    let mut tarball = Tarball::default();
+    tarball.append_dir_all("", dir).stack()?;
+    let tarball = tarball.into_tarball().stack()?;
+    #[cfg(unix)]
+    ensure_eq!(entries_at(&tarball, "link").stack()?, vec![b"c".to_vec()]);
+    ensure!(entry_paths(&tarball)
+        .stack()?
+        .iter()
+        .all(|(path, link_name)| !path.starts_with("dir") && link_name.is_none()));
+
+    std::fs::remove_dir_all(dir).stack()?;
+
+    // entry metadata
+
+    let file = "./logs/tarball_metadata.txt";
+    std::fs::write(file, "metadata").stack()?;
+    let now = std::time::SystemTime::now();
+    let earlier = now - std::time::Duration::from_secs(1000);
+    let tarball = fixed_metadata_build(file, now).await.stack()?;
+    ensure_eq!(tarball, fixed_metadata_build(file, earlier).await.stack()?);
+
+    let mut archive = tar::Archive::new(std::io::Cursor::new(&tarball));
+    for entry in archive.entries().stack()? {
+        let entry = entry.stack()?;
+        let header = entry.header();
+        ensure_eq!(header.mtime().stack()?, 1);
+        ensure_eq!(header.gid().stack()?, 0);
+        if entry.path().stack()?.to_str() == Some(entry_path) {
+            ensure_eq!(header.mode().stack()?, 0o755);
+            ensure_eq!(header.uid().stack()?, 1000);
+        } else {
+            ensure_eq!(header.uid().stack()?, 0);
+        }
+    }
+
+    let mut tarball = Tarball::default();
+    tarball
+        .append_file(file, &mut std::fs::File::open(file).stack()?)
+        .stack()?;
+    let mut archive = tar::Archive::new(std::io::Cursor::new(tarball.into_tarball().stack()?));
+    let mtime = archive
+        .entries()
+        .stack()?
+        .next()
+        .stack()?
+        .stack()?
+        .header()
+        .mtime()
+        .stack()?;
+    ensure_eq!(
+        mtime,
+        earlier
+            .duration_since(std::time::UNIX_EPOCH)
+            .stack()?
+            .as_secs()
+    );
+
+    std::fs::remove_file(file).stack()?;
+
+    // the dockerfile does not leave temporary files behind
+    let temp_dir_entries = || -> Result<std::collections::HashSet<_>> {
+        std::fs::read_dir(std::env::temp_dir())
+            .stack()?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<_>>()
+            .stack()
+    };
+    let before = temp_dir_entries().stack()?;
+    let (_, tarball) = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .append_dockerfile_instructions(["RUN echo hello"])
+        .into_bollard_args()
+        .await
+        .stack()?;
+    ensure_eq!(
+        temp_dir_entries()
+            .stack()?
+            .difference(&before)
+            .collect::<Vec<_>>(),
+        Vec::<&std::path::PathBuf>::new()
+    );
+    ensure_eq!(entries_at(&tarball, "super.dockerfile").stack()?, vec![
+        format!("FROM {BASE_CONTAINER}\nRUN echo hello").into_bytes()
+    ]);
+
+    let (_, tarball) = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .with_healthcheck(
+            HealthcheckOptions {
+                interval: Some(std::time::Duration::from_millis(1500)),
+                retries: Some(2),
+                ..Default::default()
+            },
+            ["sh", "-c", "test -f \"/ready\""],
+        )
+        .stack()?
+        .into_bollard_args()
+        .await
+        .stack()?;
+    ensure_eq!(entries_at(&tarball, "super.dockerfile").stack()?, vec![
+        format!(
+            "FROM {BASE_CONTAINER}\nHEALTHCHECK --interval=1500ms --retries=2 CMD \
+             [\"sh\",\"-c\",\"test -f \\\"/ready\\\"\"]"
+        )
+        .into_bytes()
+    ]);
+
+    // durations under a millisecond are not truncated
+    let (_, tarball) = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .with_healthcheck(
+            HealthcheckOptions {
+                interval: Some(std::time::Duration::from_micros(250)),
+                timeout: Some(std::time::Duration::from_nanos(1_000_001)),
+                ..Default::default()
+            },
+            ["true"],
+        )
+        .stack()?
+        .into_bollard_args()
+        .await
+        .stack()?;
+    ensure_eq!(entries_at(&tarball, "super.dockerfile").stack()?, vec![
+        format!(
+            "FROM {BASE_CONTAINER}\nHEALTHCHECK --interval=250us --timeout=1000001ns CMD \
+             [\"true\"]"
+        )
+        .into_bytes()
+    ]);
+
+    // secrets cannot go through the classic build arguments
+    let with_secret = || {
+        SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+            .with_secret("token", BuildSecret::Bytes(b"secret".to_vec()))
+    };
+    ensure!(with_secret().into_bollard_args().await.is_err());
+    // this crate does not enable the `buildkit` feature
+    ensure!(with_secret().build_image().await.is_err());
+
+    // the image name tags the image
+    let (opts, _) = SuperDockerfile::new(
+        Dockerfile::name_tag(BASE_CONTAINER),
+        Some("myapp:latest".to_owned()),
+    )
+    .into_bollard_args()
+    .await
+    .stack()?;
+    ensure_eq!(opts.t, "myapp:latest");
+    ensure_eq!(
+        opts.labels.get(IMAGE_NAME_LABEL).map(String::as_str),
+        Some("myapp:latest")
+    );
+    ensure!(!opts.labels.contains_key("myapp"));
+    ensure!(SuperDockerfile::new(
+        Dockerfile::name_tag(BASE_CONTAINER),
+        Some("myapp:latest".to_owned())
+    )
+    .with_build_opts(ImageBuildOptions {
+        t: "other:latest".to_owned(),
+        ..Default::default()
+    })
+    .into_bollard_args()
+    .await
+    .is_err());
+
+    // a single platform is passed through, multiple need separate builds
+    let (opts, _) = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .with_platforms(["linux/arm64"])
+        .into_bollard_args()
+        .await
+        .stack()?;
+    ensure_eq!(opts.platform, "linux/arm64");
+    ensure!(
+        SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+            .with_platforms(["linux/amd64", "linux/arm64"])
+            .into_bollard_args()
+            .await
+            .is_err()
+    );
+    ensure!(
+        SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+            .build_platform_images()
+            .await
+            .is_err()
+    );
+
+    // remote contexts are fetched by the daemon and cannot be mixed with local
+    // files
+    let url = "https://github.com/docker-library/hello-world.git#master:amd64/hello-world";
+    let (opts, tarball) = SuperDockerfile::from_remote_context(url, "Dockerfile")
+        .into_bollard_args()
+        .await
+        .stack()?;
+    ensure_eq!(opts.remote, url);
+    ensure_eq!(opts.dockerfile, "Dockerfile");
+    ensure!(tarball.is_empty());
+    ensure!(SuperDockerfile::from_remote_context(url, "Dockerfile")
+        .copying_from_contents([("/file.txt", None, b"content".to_vec())])
+        .await
+        .is_err());
+    ensure!(SuperDockerfile::from_remote_context(url, "Dockerfile")
+        .copying_from_paths([(ENTRYPOINT, "/entrypoint")])
+        .await
+        .is_err());
+    ensure!(SuperDockerfile::from_remote_context(url, "Dockerfile")
+        .append_dockerfile_instructions(["RUN echo hello"])
+        .into_bollard_args()
+        .await
+        .is_err());
+
+    // reproducible contexts only depend on the file contents
+    let dir = "./logs/tarball_reproducible";
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(format!("{dir}/sub")).stack()?;
+    std::fs::write(format!("{dir}/b.txt"), "b").stack()?;
+    std::fs::write(format!("{dir}/sub/a.txt"), "a").stack()?;
+    let tarball = reproducible_build(dir, now, 0o600).await.stack()?;
+    ensure_eq!(
+        tarball,
+        reproducible_build(dir, earlier, 0o664).await.stack()?
+    );
+    let paths: Vec<String> = entry_paths(&tarball)
+        .stack()?
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    let mut sorted = paths.clone();
+    sorted.sort();
+    ensure_eq!(paths, sorted);
+    let mut archive = tar::Archive::new(std::io::Cursor::new(&tarball));
+    for entry in archive.entries().stack()? {
+        let entry = entry.stack()?;
+        let header = entry.header();
+        ensure_eq!(header.mtime().stack()?, 0);
+        ensure_eq!(header.uid().stack()?, 0);
+        ensure_eq!(header.gid().stack()?, 0);
+    }
+    std::fs::remove_dir_all(dir).stack()?;
+
+    // compressed contexts decompress to the uncompressed context
+    let build = |compress_context| async move {
+        SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+            .reproducible(true)
+            .compress_context(compress_context)
+            .copying_from_paths([(ENTRYPOINT, "/entrypoint")])
+            .await
+            .stack()?
+            .into_bollard_args()
+            .await
+            .stack()
+    };
+    let (_, tarball) = build(false).await.stack()?;
+    let (_, compressed) = build(true).await.stack()?;
+    // the gzip magic number
+    ensure!(compressed.starts_with(&[0x1f, 0x8b]));
+    let mut decompressed = vec![];
+    flate2::read::GzDecoder::new(compressed.as_slice())
+        .read_to_end(&mut decompressed)
+        .stack()?;
+    ensure_eq!(decompressed, tarball);
+
+    // generated files get their parent directories
+    let (_, tarball) = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .copying_from_contents([("/etc/myapp/conf.d/app.toml", None, b"app".to_vec())])
+        .await
+        .stack()?
+        .copying_contents_into_dir("/etc/myapp/", [
+            ("a.toml", Some(0o644), b"a".to_vec()),
+            ("nested/b.toml", None, b"b".to_vec()),
+        ])
+        .await
+        .stack()?
+        .into_bollard_args()
+        .await
+        .stack()?;
+    let mut archive = tar::Archive::new(std::io::Cursor::new(&tarball));
+    let mut dirs = vec![];
+    for entry in archive.entries().stack()? {
+        let entry = entry.stack()?;
+        if entry.header().entry_type().is_dir() {
+            dirs.push(entry.path().stack()?.to_str().stack()?.to_owned());
+        }
+    }
+    ensure_eq!(dirs, [
+        "etc",
+        "etc/myapp",
+        "etc/myapp/conf.d",
+        "etc/myapp/nested"
+    ]);
+    ensure_eq!(entries_at(&tarball, "super.dockerfile").stack()?, vec![
+        format!(
+            "FROM {BASE_CONTAINER}\nCOPY etc/myapp/conf.d/app.toml \
+             /etc/myapp/conf.d/app.toml\nCOPY etc/myapp/ /etc/myapp/"
+        )
+        .into_bytes()
+    ]);
+    let sdf = || SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None);
+    for to in ["relative.txt", "/etc/../passwd", "/"] {
+        ensure!(sdf()
+            .copying_from_contents([(to, None, vec![])])
+            .await
+            .is_err());
+    }
+    ensure!(sdf()
+        .copying_contents_into_dir("relative", [("a", None, vec![])])
+        .await
+        .is_err());
+    ensure!(sdf()
+        .copying_contents_into_dir("/", [("a", None, vec![])])
+        .await
+        .is_err());
+    for relative in ["/a", "../a", "a/../../b", ""] {
+        ensure!(sdf()
+            .copying_contents_into_dir("/etc", [(relative, None, vec![])])
+            .await
+            .is_err());
+    }
+
+    // entrypoint arguments are JSON escaped
+    let (_, tarball) = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .with_entrypoint((ENTRYPOINT, "/entrypoint"), [
+            r#"{"json": "blob"}"#,
+            "back\\slash",
+            "multi\nline",
+            "$HOME",
+        ])
+        .await
+        .stack()?
+        .into_bollard_args()
+        .await
+        .stack()?;
+    ensure_eq!(entries_at(&tarball, "super.dockerfile").stack()?, vec![
+        format!(
+            "FROM {BASE_CONTAINER}\nCOPY {ENTRYPOINT} /entrypoint\nENTRYPOINT \
+             [\"/entrypoint\",\"{{\\\"json\\\": \
+             \\\"blob\\\"}}\",\"back\\\\slash\",\"multi\\nline\",\"$HOME\"]"
+        )
+        .into_bytes()
+    ]);
+
+    Ok(())
+}
@@ -0,0 +1,138 @@
+//! Checks that terminating a `CommandRunner` also kills the processes started
+//! by the command, which is the process group on Unix and the process tree on
+//! Windows (through `taskkill /T`). On Windows this uses PowerShell.
+//!
+//! `cargo r --bin terminate_tree -- --ctrl-c` is a manual check of the Ctrl-C
+//! path of `ContainerNetwork` that needs docker: press Ctrl-C while the
+//! network is waiting, afterwards there should be no container or `docker`
+//! process of the network left.
+
+use std::time::Duration;
+
+use clap::Parser;
+use stacked_errors::{ensure, Result, StackableErr};
+use super_orchestrator::{
+    cli_docker::{Container, ContainerNetwork, Dockerfile},
+    is_running, Command, OutputStream,
+};
+use tokio::time::sleep;
+use tracing::info;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Parser, Debug)]
+#[command(about)]
+struct Args {
+    /// Runs the manual Ctrl-C check of `ContainerNetwork` instead
+    #[arg(long)]
+    ctrl_c: bool,
+}
+
+/// A command that starts a background process, prints its ID, and waits
+fn tree_command() -> Command {
+    if cfg!(windows) {
+        Command::new("powershell -NoProfile -Command").arg(
+            "$p = Start-Process ping -ArgumentList '-n','1000','127.0.0.1' -PassThru -WindowStyle \
+             Hidden; Write-Output $p.Id; Wait-Process -Id $p.Id",
+        )
+    } else {
+        Command::shell("sleep 1000 & echo $!; wait")
+    }
+}
+
+/// Returns if the process with `pid` is running, not counting zombies that
+/// have not been reaped yet
+async fn is_alive(pid: u32) -> Result<bool> {
+    if cfg!(target_os = "linux") {
+        Ok(match std::fs::read_to_string(format!("/proc/{pid}/stat")) {
+            Ok(stat) => !stat.contains(") Z "),
+            Err(_) => false,
+        })
+    } else {
+        is_running(pid).await
+    }
+}
+
+async fn check_tree(graceful: bool) -> Result<()> {
+    let mut runner = tree_command().watch_lines(true).run().await.stack()?;
+    let pid: u32 = runner
+        .wait_for_line_matching(
+            OutputStream::Stdout,
+            |line| line.trim().parse::<u32>().is_ok(),
+            TIMEOUT,
+        )
+        .await
+        .stack()?
+        .trim()
+        .parse()
+        .stack()?;
+    ensure!(is_alive(pid).await.stack()?);
+    if graceful {
+        runner
+            .terminate_graceful(Duration::from_millis(300))
+            .await
+            .stack()?;
+    } else {
+        runner.terminate().await.stack()?;
+    }
+    let mut i = 0;
+    while is_alive(pid).await.stack()? {
+        ensure!(
+            i < 100,
+            format!("the background process {pid} is still running")
+        );
+        i += 1;
+        sleep(Duration::from_millis(50)).await;
+    }
+    Ok(())
+}
+
+async fn ctrl_c_network() -> Result<()> {
+    let mut cn = ContainerNetwork::new("terminate_tree", None, "./logs");
+    cn.add_container(
+        Container::new("sleeper", Dockerfile::name_tag(BASE_CONTAINER))
+            .entrypoint("/bin/sleep", ["1000"]),
+    )
+    .stack()?;
+    cn.run_all().await.stack()?;
+    let network_name = cn.network_name().to_owned();
+    info!("press Ctrl-C now");
+    let res = cn
+        .wait_with_timeout_all(true, Duration::from_secs(300))
+        .await;
+    ensure!(
+        res.is_err(),
+        "the network should have been stopped by Ctrl-C"
+    );
+    let left = Command::new("docker ps -a -q --filter")
+        .arg(format!("network={network_name}"))
+        .run_to_completion()
+        .await
+        .stack()?;
+    left.assert_success().stack()?;
+    ensure!(
+        left.stdout.is_empty(),
+        format!(
+            "containers of the network were left: {}",
+            left.stdout_as_utf8_lossy()
+        )
+    );
+    info!("no containers were left");
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .init();
+    let args = Args::parse();
+
+    if args.ctrl_c {
+        return ctrl_c_network().await
+    }
+    check_tree(false).await.stack()?;
+    check_tree(true).await.stack()?;
+    Ok(())
+}
@@ -0,0 +1,65 @@
+//! Checks `wait_container`, the API counterpart of
+//! `CommandRunner::wait_with_timeout`
+
+use std::{str::FromStr, time::Duration};
+
+use stacked_errors::{ensure, ensure_eq, Result, StackableErr};
+use super_orchestrator::api_docker::{
+    wait_container, ContainerExit, Dockerfile, SuperContainer, SuperDockerfile, SuperNetwork,
+};
+use tracing_subscriber::EnvFilter;
+
+const BASE_CONTAINER: &str = "alpine:3.21";
+
+async fn run_waits(sn: &SuperNetwork) -> Result<()> {
+    let sleeping = sn.docker_name("sleeping");
+    let err = wait_container(&sleeping, Duration::from_millis(500))
+        .await
+        .unwrap_err();
+    ensure!(err.is_timeout());
+
+    let exit = wait_container(&sn.docker_name("exiting"), Duration::from_secs(60))
+        .await
+        .stack()?;
+    ensure_eq!(exit, ContainerExit {
+        code: 3,
+        oom_killed: false,
+        error_msg: None
+    });
+    // returns immediately for exited containers
+    let exit = wait_container(&sn.docker_name("exiting"), Duration::ZERO)
+        .await
+        .stack()?;
+    ensure_eq!(exit.code, 3);
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_str("debug,bollard=warn,hyper_util=info").unwrap())
+        .init();
+
+    let image = SuperDockerfile::new(Dockerfile::name_tag(BASE_CONTAINER), None)
+        .build_image()
+        .await
+        .stack()?;
+
+    let mut sn = SuperNetwork::new_with_uuid("test_wait_container", "./logs");
+    sn.add_container(SuperContainer::new("sleeping", image.clone()).cmd(["sleep", "300"]))
+        .stack()?;
+    sn.add_container(
+        SuperContainer::new("exiting", image)
+            .cmd(["sh", "-c", "exit 3"])
+            .allow_unsuccessful(true),
+    )
+    .stack()?;
+    sn.run_all().await.stack()?;
+
+    let res = run_waits(&sn).await;
+    sn.terminate_all().await;
+    res.stack()?;
+
+    Ok(())
+}